@@ -0,0 +1,74 @@
+//! A diflouroborane plugin that only prints what it's told, to start your own from
+//!
+//! The types below have to match `src/modules/plugin.rs` of the version of diflouroborane the
+//! plugin is loaded into, which is what `FABACCESS_MODULE_ABI` is for.
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic;
+
+/// The module ABI this plugin is built against
+#[no_mangle]
+pub static FABACCESS_MODULE_ABI: u32 = 1;
+
+#[repr(C)]
+pub struct Vtable {
+    pub name: *const c_char,
+    pub state: *mut c_void,
+    pub init: extern "C" fn(state: *mut c_void, settings: *const c_char) -> c_int,
+    pub shutdown: extern "C" fn(state: *mut c_void),
+    pub handle_event: extern "C" fn(state: *mut c_void, event: *const c_char),
+}
+
+/// Whatever the plugin keeps around between calls
+struct State {
+    events: u64,
+}
+
+#[no_mangle]
+pub extern "C" fn fabaccess_module_init() -> *const Vtable {
+    let state = Box::new(State { events: 0 });
+    let vtable = Box::new(Vtable {
+        name: b"example\0".as_ptr() as *const c_char,
+        state: Box::into_raw(state) as *mut c_void,
+        init,
+        shutdown,
+        handle_event,
+    });
+    // The vtable is never freed, the plugin stays loaded for as long as diflouroborane runs
+    Box::into_raw(vtable)
+}
+
+/// Panics must not unwind into diflouroborane, so every call catches them
+fn guard<T, F: FnOnce() -> T + panic::UnwindSafe>(fallback: T, f: F) -> T {
+    panic::catch_unwind(f).unwrap_or(fallback)
+}
+
+fn string<'a>(s: *const c_char) -> &'a str {
+    // diflouroborane only ever hands us valid UTF-8
+    unsafe { CStr::from_ptr(s) }.to_str().unwrap_or("")
+}
+
+extern "C" fn init(_state: *mut c_void, settings: *const c_char) -> c_int {
+    guard(1, || {
+        eprintln!("example plugin starting with settings {}", string(settings));
+        0
+    })
+}
+
+extern "C" fn shutdown(state: *mut c_void) {
+    guard((), || {
+        let state = unsafe { Box::from_raw(state as *mut State) };
+        eprintln!("example plugin stopping after {} events", state.events);
+    })
+}
+
+extern "C" fn handle_event(state: *mut c_void, event: *const c_char) {
+    let state = state as *mut State;
+    guard((), || {
+        // Only ever called from one thread at a time
+        let state = unsafe { &mut *state };
+        state.events += 1;
+        eprintln!("example plugin got {}", string(event));
+    })
+}
@@ -8,50 +8,438 @@ use casbin::prelude::*;
 use futures_signals::signal::Mutable;
 
 use crate::api::api;
-use crate::config::Config;
+use crate::api::error;
+use crate::config::{Access, Config};
 use crate::auth::Authentication;
-use crate::error::Result;
+use crate::audit::{Audit, AuditEvent};
+use crate::error::{Error, Result, WithPath};
+use crate::events::{Events, ServerEvent};
+use crate::status::Status;
+
+use crate::machine::{self, unix_secs};
 
 use std::rc::Rc;
-use async_std::sync::{Arc, RwLock};
+use std::sync::{PoisonError, TryLockError};
+use std::time::{Duration, Instant, SystemTime};
+use async_std::future::timeout;
+use async_std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use async_std::task;
+
+use futures::task::{Spawn, SpawnExt};
+
+use capnp::capability::Promise;
 
 use std::ops::Deref;
 
+pub mod breaker;
+pub mod grants;
+pub mod reconcile;
+use breaker::{Admit, Breaker, Probe};
+use grants::{Grant, Grants};
+
+/// What access control needs from the policy, casbin's `Enforcer` outside of tests
+pub trait Enforce: Send + Sync {
+    fn enforce(&self, actor: &str, object: &str, action: &str) -> Result<bool>;
+    /// The rules as `[subject, object, action]`
+    fn policy(&self) -> Vec<Vec<String>>;
+    // casbin wants to be able to change its role manager for the role queries
+    fn roles(&mut self) -> Vec<String>;
+    fn members(&mut self, role: &str) -> Vec<String>;
+    fn roles_of(&mut self, user: &str) -> Vec<String>;
+}
+
+impl Enforce for Enforcer {
+    fn enforce(&self, actor: &str, object: &str, action: &str) -> Result<bool> {
+        Ok(CoreApi::enforce(self, vec![actor, object, action])?)
+    }
+
+    fn policy(&self) -> Vec<Vec<String>> {
+        self.get_policy()
+    }
+
+    fn roles(&mut self) -> Vec<String> {
+        self.get_all_roles()
+    }
+
+    fn members(&mut self, role: &str) -> Vec<String> {
+        self.get_users_for_role(role, None)
+    }
+
+    fn roles_of(&mut self, user: &str) -> Vec<String> {
+        self.get_implicit_roles_for_user(user, None)
+    }
+}
+
+/// The enforcer, shared with the checks still running on the thread pool
+type Shared = Arc<std::sync::RwLock<Box<dyn Enforce>>>;
+
 pub struct PermissionsProvider {
     log: Logger,
-    pdb: Enforcer,
+    /// Checks take this along to the thread pool instead of holding the lock around us, so one
+    /// stuck in the enforcer doesn't keep everybody else out. Reloading swaps in a new one.
+    pdb: Shared,
+    /// Permissions handed out for a while on top of the policy
+    grants: Grants,
+    /// Lives outside the lock around us, so it's there even when the lock isn't
+    breaker: Arc<Breaker>,
+    /// Where the policy is read from again
+    config: Access,
 }
 
 impl PermissionsProvider {
-    pub fn new(log: Logger, pdb: Enforcer) -> Self {
-        Self { log, pdb }
+    pub fn new(log: Logger, pdb: Box<dyn Enforce>, grants: Grants, breaker: Breaker,
+        config: &Access) -> Self
+    {
+        let pdb = Arc::new(std::sync::RwLock::new(pdb));
+        Self { log, pdb, grants, breaker: Arc::new(breaker), config: config.clone() }
     }
 
-    pub fn enforce(&self, actor: &str, object: &str, action: &str) -> Result<bool> {
-        let b = self.pdb.enforce(vec![actor, object, action])?;
-        if b {
-            trace!(self.log, "Granted {} on {} for {}", action, object, actor);
-        } else {
-            trace!(self.log, "Denied {} on {} for {}", action, object, actor);
+    pub fn breaker(&self) -> Arc<Breaker> {
+        self.breaker.clone()
+    }
+
+    /// `log` is the logger of the connection the check is done for
+    ///
+    /// Fails with `Error::AuthzUnavailable` instead of waiting if the enforcer is busy with a role
+    /// query, since this is done right on the executor.
+    pub fn enforce(&self, log: &Logger, actor: &str, object: &str, action: &str) -> Result<bool> {
+        if self.grants.allows(actor, object, action, now()) {
+            return Ok(decided(log, true, actor, object, action));
         }
-        Ok(b)
+        let pdb = match self.pdb.try_read() {
+            Ok(pdb) => pdb,
+            Err(TryLockError::Poisoned(p)) => p.into_inner(),
+            Err(TryLockError::WouldBlock) => return Err(Error::AuthzUnavailable),
+        };
+        let b = pdb.enforce(actor, object, action)?;
+        Ok(decided(log, b, actor, object, action))
+    }
+
+    /// The rules of the policy as `[subject, object, action]`, without temporary grants
+    pub fn policy(&self) -> Vec<Vec<String>> {
+        self.pdb.read().unwrap_or_else(PoisonError::into_inner).policy()
+    }
+
+    /// Read the policy from its file again, e.g. after an admin edited it
+    ///
+    /// Checks still running keep the enforcer they started with.
+    pub async fn reload_policy(&mut self) -> Result<()> {
+        let pdb = load(&self.config).await?;
+        self.pdb = Arc::new(std::sync::RwLock::new(Box::new(pdb)));
+        Ok(())
+    }
+
+    /// Drop temporary grants that expired, so the table doesn't grow forever
+    pub fn expire_grants(&mut self) -> Result<()> {
+        let expired = self.grants.expire(now())?;
+        if expired > 0 {
+            debug!(self.log, "Dropped {} expired temporary grants", expired);
+        }
+        Ok(())
+    }
+}
+
+fn decided(log: &Logger, b: bool, actor: &str, object: &str, action: &str) -> bool {
+    if b {
+        trace!(log, "Granted {} on {} for {}", action, object, actor);
+    } else {
+        trace!(log, "Denied {} on {} for {}", action, object, actor);
+    }
+    b
+}
+
+fn now() -> u64 {
+    unix_secs(SystemTime::now())
+}
+
+fn sorted(mut names: Vec<String>) -> Vec<String> {
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Names a listing returns if the client doesn't say how many
+const DEFAULT_PAGE: u32 = 100;
+/// Most names a single listing returns, so answers stay small however large the policy is
+const MAX_PAGE: u32 = 1000;
+
+/// The part of `all` a listing returns starting at `offset`, and how many there are in total
+fn page(all: &[String], offset: u32, limit: u32) -> (&[String], u32) {
+    let limit = match limit {
+        0 => DEFAULT_PAGE,
+        l => l.min(MAX_PAGE),
+    } as usize;
+    let start = (offset as usize).min(all.len());
+    let end = start.saturating_add(limit).min(all.len());
+    (&all[start..end], all.len() as u32)
+}
+
+fn fill_names(mut b: capnp::text_list::Builder<'_>, names: &[String]) {
+    for (i, name) in names.iter().enumerate() {
+        b.set(i as u32, name);
+    }
+}
+
+/// Read the policy again and tell clients that what their users may do could have changed
+pub async fn reload_policy(log: Logger, perm: Arc<RwLock<PermissionsProvider>>,
+    breaker: Arc<Breaker>, events: Events)
+{
+    let mut perm = match timeout(breaker.timeout(), perm.write()).await {
+        Ok(perm) => perm,
+        Err(_) => {
+            warn!(log, "Not reloading access policy, permissions were busy for more than {}s",
+                breaker.timeout().as_secs());
+            return;
+        },
+    };
+    match perm.reload_policy().await {
+        Ok(()) => {
+            info!(log, "Reloaded access policy");
+            events.publish(ServerEvent::PolicyReloaded);
+        },
+        Err(e) => error!(log, "Failed to reload access policy: {}", e),
+    }
+}
+
+/// How often expired temporary grants are cleaned up. They aren't honored anymore right away.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Clean up expired temporary grants every once in a while, for as long as we run
+///
+/// If the lock can't be had in time we try again next round, expired grants don't count anyway.
+pub async fn expire_grants(log: Logger, perm: Arc<RwLock<PermissionsProvider>>,
+    breaker: Arc<Breaker>)
+{
+    loop {
+        task::sleep(EXPIRE_INTERVAL).await;
+        match timeout(breaker.timeout(), perm.write()).await {
+            Ok(mut perm) => if let Err(e) = perm.expire_grants() {
+                error!(log, "Failed to save temporary grants: {}", e);
+            },
+            Err(_) => warn!(log, "Not cleaning up temporary grants, permissions were busy for \
+                more than {}s", breaker.timeout().as_secs()),
+        }
+    }
+}
+
+/// The error to send to a client whose permission check failed with `e`
+pub fn check_failed(log: &Logger, object: &str, action: &str, e: Error) -> capnp::Error {
+    match e {
+        Error::AuthzUnavailable => error::unavailable("Authorization system unavailable"),
+        e => {
+            error!(log, "Failed to check permission {} on {}: {}", action, object, e);
+            error::internal()
+        },
     }
 }
 
 #[derive(Clone)]
 pub struct Permissions {
+    log: Logger,
     inner: Arc<RwLock<PermissionsProvider>>,
+    breaker: Arc<Breaker>,
     auth: Rc<Authentication>,
+    audit: Audit,
+    /// Where checks run, so one that gets stuck doesn't hold up everything else
+    spawner: Rc<dyn Spawn>,
+    status: Arc<Status>,
 }
 
 impl Permissions {
-    pub fn new(inner: Arc<RwLock<PermissionsProvider>>, auth: Rc<Authentication>) -> Self {
-        Self { inner, auth }
+    pub fn new(log: Logger, inner: Arc<RwLock<PermissionsProvider>>, breaker: Arc<Breaker>,
+        auth: Rc<Authentication>, audit: Audit, spawner: Rc<dyn Spawn>, status: Arc<Status>)
+        -> Self
+    {
+        Self { log, inner, breaker, auth, audit, spawner, status }
+    }
+
+    /// Ask the enforcer whether `actor` may do `action` on `object`, giving up after the timeout
+    ///
+    /// Fails with `Error::AuthzUnavailable` if it took too long or the breaker is open and doesn't
+    /// let the check through.
+    pub async fn check(&self, actor: &str, object: &str, action: &str) -> Result<bool> {
+        let probe = match self.breaker.admit(action) {
+            Admit::Answer(true) => return Ok(true),
+            Admit::Answer(false) => return Err(Error::AuthzUnavailable),
+            Admit::Ask(probe) => probe,
+        };
+        let pdb = {
+            let inner = self.read(object, action).await?;
+            if inner.grants.allows(actor, object, action, now()) {
+                return Ok(decided(&self.log, true, actor, object, action));
+            }
+            inner.pdb.clone()
+        };
+
+        let log = self.log.clone();
+        let (a, o, x) = (actor.to_string(), object.to_string(), action.to_string());
+        self.ask(probe, object, action, move || {
+            let pdb = pdb.read().unwrap_or_else(PoisonError::into_inner);
+            // casbin's errors can't be sent between threads, so only their message comes back
+            let b = pdb.enforce(&a, &o, &x).map_err(|e| e.to_string())?;
+            Ok(decided(&log, b, &a, &o, &x))
+        }).await
+    }
+
+    /// `check` for many objects at once, answered in a single round and timeout
+    ///
+    /// For listing what somebody may do, which would otherwise mean one round to the thread pool
+    /// per object.
+    pub async fn check_all(&self, actor: &str, objects: Vec<String>, action: &str)
+        -> Result<Vec<bool>>
+    {
+        let probe = match self.breaker.admit(action) {
+            Admit::Answer(true) => return Ok(vec![true; objects.len()]),
+            Admit::Answer(false) => return Err(Error::AuthzUnavailable),
+            Admit::Ask(probe) => probe,
+        };
+        let (granted, pdb) = {
+            let inner = self.read("machines", action).await?;
+            let now = now();
+            let granted: Vec<bool> = objects.iter()
+                .map(|o| inner.grants.allows(actor, o, action, now))
+                .collect();
+            (granted, inner.pdb.clone())
+        };
+
+        let log = self.log.clone();
+        let (a, x) = (actor.to_string(), action.to_string());
+        self.ask(probe, "machines", action, move || {
+            let pdb = pdb.read().unwrap_or_else(PoisonError::into_inner);
+            objects.iter().zip(granted)
+                .map(|(o, granted)| {
+                    let b = granted || pdb.enforce(&a, o, &x).map_err(|e| e.to_string())?;
+                    Ok(decided(&log, b, &a, o, &x))
+                })
+                .collect()
+        }).await
+    }
+
+    /// Run a role query on the thread pool like a check, with the same timeout and breaker
+    ///
+    /// `what` names the query for the log.
+    async fn query<F>(&self, what: &str, f: F) -> Result<Vec<String>>
+        where F: FnOnce(&mut dyn Enforce) -> Vec<String> + Send + 'static
+    {
+        let probe = match self.breaker.admit("read") {
+            Admit::Answer(_) => return Err(Error::AuthzUnavailable),
+            Admit::Ask(probe) => probe,
+        };
+        let pdb = self.read("roles", what).await?.pdb.clone();
+        let deadline = Instant::now() + self.breaker.timeout();
+        self.ask(probe, "roles", what, move || {
+            // Waiting in line for the lock would keep every check after us waiting behind us
+            let mut pdb = loop {
+                match pdb.try_write() {
+                    Ok(pdb) => break pdb,
+                    Err(TryLockError::Poisoned(p)) => break p.into_inner(),
+                    Err(TryLockError::WouldBlock) if Instant::now() < deadline =>
+                        std::thread::sleep(Duration::from_millis(10)),
+                    Err(TryLockError::WouldBlock) => return Err("enforcer busy".to_string()),
+                }
+            };
+            Ok(sorted(f(&mut **pdb)))
+        }).await
+    }
+
+    /// Run `f` on the thread pool, giving up after the timeout and telling the breaker how it went
+    ///
+    /// `probe` goes along and is only dropped once `f` returns.
+    async fn ask<T, F>(&self, probe: Option<Probe>, object: &str, action: &str, f: F) -> Result<T>
+        where T: Send + 'static,
+              F: FnOnce() -> std::result::Result<T, String> + Send + 'static
+    {
+        let asked = self.spawner.spawn_with_handle(async move {
+            let r = f();
+            drop(probe);
+            r
+        }).map_err(|e| Error::Boxed(Box::new(e)))?;
+
+        match timeout(self.breaker.timeout(), asked).await {
+            Ok(r) => {
+                self.breaker.succeeded();
+                r.map_err(|e| Error::Boxed(e.into()))
+            },
+            Err(_) => {
+                self.breaker.timed_out(object, action);
+                Err(Error::AuthzUnavailable)
+            },
+        }
+    }
+
+    /// The lock around the provider, or `Error::AuthzUnavailable` if it can't be had in time
+    async fn read(&self, object: &str, action: &str)
+        -> Result<RwLockReadGuard<'_, PermissionsProvider>>
+    {
+        match timeout(self.breaker.timeout(), self.inner.read()).await {
+            Ok(inner) => Ok(inner),
+            Err(_) => {
+                self.breaker.timed_out(object, action);
+                Err(Error::AuthzUnavailable)
+            },
+        }
+    }
+
+    async fn write(&self, object: &str, action: &str)
+        -> Result<RwLockWriteGuard<'_, PermissionsProvider>>
+    {
+        match timeout(self.breaker.timeout(), self.inner.write()).await {
+            Ok(inner) => Ok(inner),
+            Err(_) => {
+                self.breaker.timed_out(object, action);
+                Err(Error::AuthzUnavailable)
+            },
+        }
+    }
+
+    /// The access control shared by all connections
+    pub fn provider(&self) -> Arc<RwLock<PermissionsProvider>> {
+        self.inner.clone()
+    }
+
+    /// Audit trail of this connection
+    pub fn audit(&self) -> &Audit {
+        &self.audit
+    }
+
+    /// The identity this connection is currently authenticated as, if any
+    pub async fn authzid(&self) -> Option<String> {
+        self.auth.state.read().await.clone()
+    }
+
+    /// Like `enforce`, but with the error to send to the client if the check fails
+    pub async fn require(&self, object: &str, action: &str)
+        -> std::result::Result<(), capnp::Error>
+    {
+        match self.enforce(object, action).await {
+            Ok(true) => Ok(()),
+            Ok(false) if self.auth.state.read().await.is_none() => Err(error::unauthenticated()),
+            Ok(false) => Err(error::unauthorized(object, action)),
+            Err(e) => Err(check_failed(&self.log, object, action, e)),
+        }
+    }
+
+    /// Whether the connection has `action` on `object`, without recording it if not
+    ///
+    /// For deciding what to show, not what to allow. Failed checks count as not having it.
+    pub async fn may(&self, object: &str, action: &str) -> bool {
+        let actor = match self.auth.state.read().await.deref() {
+            Some(a) => a.clone(),
+            None => return false,
+        };
+        self.check(&actor, object, action).await.unwrap_or_else(|e| {
+            error!(self.log, "Failed to check permission {} on {}: {}", action, object, e);
+            false
+        })
     }
 
     pub async fn enforce(&self, object: &str, action: &str) -> Result<bool> {
         if let Some(actor) = self.auth.state.read().await.deref() {
-            self.inner.read().await.enforce(&actor, object, action)
+            let b = self.check(actor, object, action).await?;
+            if !b {
+                self.audit.record(AuditEvent::PermissionDenied { authzid: &actor, object, action });
+            }
+            Ok(b)
         } else {
             Ok(false)
         }
@@ -59,15 +447,499 @@ impl Permissions {
 }
 
 impl api::permissions::Server for Permissions {
+    fn grant_temporary(&mut self,
+        params: api::permissions::GrantTemporaryParams,
+        _results: api::permissions::GrantTemporaryResults)
+        -> Promise<(), capnp::Error>
+    {
+        pry!(machine::writable(&self.status));
+        let this = self.clone();
+        let f = async move {
+            let params = params.get()?;
+            let user = params.get_user()?;
+            let object = params.get_object()?;
+            let action = params.get_action()?;
+            let duration = params.get_duration();
+            if user.is_empty() || duration == 0 {
+                return Err(error::invalid_argument("Needs a user and a duration"));
+            }
+            // Otherwise whoever got one could pass it on forever
+            if action == "manage" {
+                return Err(error::invalid_argument("`manage` can't be granted temporarily"));
+            }
+            this.require(object, "manage").await?;
+            let by = this.authzid().await.unwrap_or_default();
+
+            let granted = now();
+            let grant = Grant {
+                user: user.to_string(),
+                object: object.to_string(),
+                action: action.to_string(),
+                expires: granted.saturating_add(duration),
+                by: by.clone(),
+                granted,
+            };
+            let expires = grant.expires;
+            let mut inner = this.write(object, "manage").await
+                .map_err(|e| check_failed(&this.log, object, "manage", e))?;
+            if !inner.grants.enabled() {
+                return Err(error::unimplemented("Temporary grants are not enabled"));
+            }
+            if let Err(e) = inner.grants.add(grant) {
+                error!(this.log, "Failed to save temporary grants: {}", e);
+                return Err(error::internal());
+            }
+            drop(inner);
+
+            info!(this.log, "Granted {} on {} to {} until {}", action, object, user, expires);
+            this.audit.record(AuditEvent::PermissionGranted {
+                authzid: &by, user, object, action, expires,
+            });
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+
+    fn list_temporary_grants(&mut self,
+        _params: api::permissions::ListTemporaryGrantsParams,
+        mut results: api::permissions::ListTemporaryGrantsResults)
+        -> Promise<(), capnp::Error>
+    {
+        let this = self.clone();
+        let f = async move {
+            let actor = this.authzid().await.ok_or_else(error::unauthenticated)?;
+            let all: Vec<Grant> = this.read("grants", "list").await
+                .map_err(|e| check_failed(&this.log, "grants", "list", e))?
+                .grants.list(now()).cloned().collect();
+            // Only grants of permissions the caller could have granted themselves
+            let mut grants = Vec::new();
+            for g in all {
+                let manages = this.check(&actor, &g.object, "manage").await
+                    .map_err(|e| check_failed(&this.log, &g.object, "manage", e))?;
+                if manages {
+                    grants.push(g);
+                }
+            }
+
+            let mut b = results.get().init_grants(grants.len() as u32);
+            for (i, g) in grants.iter().enumerate() {
+                let mut e = b.reborrow().get(i as u32);
+                e.set_user(&g.user);
+                e.set_object(&g.object);
+                e.set_action(&g.action);
+                e.set_expires(g.expires);
+                e.set_granted_by(&g.by);
+                e.set_granted_at(g.granted);
+            }
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+
+    fn revoke_temporary(&mut self,
+        params: api::permissions::RevokeTemporaryParams,
+        _results: api::permissions::RevokeTemporaryResults)
+        -> Promise<(), capnp::Error>
+    {
+        pry!(machine::writable(&self.status));
+        let this = self.clone();
+        let f = async move {
+            let params = params.get()?;
+            let user = params.get_user()?;
+            let object = params.get_object()?;
+            let action = params.get_action()?;
+            this.require(object, "manage").await?;
+            let by = this.authzid().await.unwrap_or_default();
+
+            let mut inner = this.write(object, "manage").await
+                .map_err(|e| check_failed(&this.log, object, "manage", e))?;
+            let revoked = inner.grants.revoke(user, object, action, now())
+                .map_err(|e| {
+                    error!(this.log, "Failed to save temporary grants: {}", e);
+                    error::internal()
+                })?;
+            drop(inner);
+            if revoked.is_none() {
+                return Err(error::no_such_grant());
+            }
+
+            info!(this.log, "Revoked {} on {} from {}", action, object, user);
+            this.audit.record(AuditEvent::PermissionRevoked { authzid: &by, user, object, action });
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+
+    fn list_roles(&mut self,
+        params: api::permissions::ListRolesParams,
+        mut results: api::permissions::ListRolesResults)
+        -> Promise<(), capnp::Error>
+    {
+        let this = self.clone();
+        Promise::from_future(async move {
+            let params = params.get()?;
+            this.require("server", "admin").await?;
 
+            let roles = this.query("list_roles", |e| e.roles()).await
+                .map_err(|e| check_failed(&this.log, "roles", "list_roles", e))?;
+            let (roles, total) = page(&roles, params.get_offset(), params.get_limit());
+            let mut b = results.get();
+            b.set_total(total);
+            fill_names(b.init_roles(roles.len() as u32), roles);
+            Ok(())
+        })
+    }
+
+    fn list_members(&mut self,
+        params: api::permissions::ListMembersParams,
+        mut results: api::permissions::ListMembersResults)
+        -> Promise<(), capnp::Error>
+    {
+        let this = self.clone();
+        Promise::from_future(async move {
+            let params = params.get()?;
+            let role = params.get_role()?;
+            this.require("server", "admin").await?;
+
+            let r = role.to_string();
+            let members = this.query("list_members", move |e| e.members(&r)).await
+                .map_err(|e| check_failed(&this.log, "roles", "list_members", e))?;
+            let (members, total) = page(&members, params.get_offset(), params.get_limit());
+            let mut b = results.get();
+            b.set_total(total);
+            fill_names(b.init_members(members.len() as u32), members);
+            Ok(())
+        })
+    }
+
+    fn list_roles_of(&mut self,
+        params: api::permissions::ListRolesOfParams,
+        mut results: api::permissions::ListRolesOfResults)
+        -> Promise<(), capnp::Error>
+    {
+        let this = self.clone();
+        Promise::from_future(async move {
+            let params = params.get()?;
+            let caller = this.authzid().await.ok_or_else(error::unauthenticated)?;
+            let user = match params.get_user()? {
+                "" => caller.as_str(),
+                u => u,
+            };
+            // Anybody may know their own roles
+            if user != caller {
+                this.require("server", "admin").await?;
+            }
+
+            let u = user.to_string();
+            let roles = this.query("list_roles_of", move |e| e.roles_of(&u)).await
+                .map_err(|e| check_failed(&this.log, "roles", "list_roles_of", e))?;
+            let (roles, total) = page(&roles, params.get_offset(), params.get_limit());
+            let mut b = results.get();
+            b.set_total(total);
+            fill_names(b.init_roles(roles.len() as u32), roles);
+            Ok(())
+        })
+    }
 }
 
-/// This line documents init
-pub async fn init(log: Logger, config: &Config) -> std::result::Result<PermissionsProvider, Box<dyn std::error::Error>> {
-    let model = Model::from_file(config.access.model.clone()).await?;
-    let adapter = Box::new(FileAdapter::new(config.access.policy.clone()));
+/// Load the casbin model and policy configured in `[access]`
+pub async fn init(log: Logger, config: &Config, status: Arc<Status>)
+    -> Result<PermissionsProvider>
+{
+    let e = load_policy(config).await?;
+
+    let grants = match config.access.grants {
+        Some(ref path) => Grants::open(path, now()).with_path(path)?,
+        None => Grants::disabled(),
+    };
 
-    let e = Enforcer::new(model, adapter).await?;
+    let breaker = Breaker::new(log.clone(), &config.access, status);
+    return Ok(PermissionsProvider::new(log, Box::new(e), grants, breaker, &config.access));
+}
+
+/// Load the casbin model and policy without anything else access control needs
+pub async fn load_policy(config: &Config) -> Result<Enforcer> {
+    load(&config.access).await
+}
 
-    return Ok(PermissionsProvider::new(log, e));
+async fn load(config: &Access) -> Result<Enforcer> {
+    let model = Model::from_file(config.model.clone()).await.with_path(&config.model)?;
+    let adapter = Box::new(FileAdapter::new(config.policy.clone()));
+
+    Ok(Enforcer::new(model, adapter).await.with_path(&config.policy)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use futures::channel::oneshot;
+    use futures::executor::LocalPool;
+    use futures::task::LocalSpawnExt;
+
+    use crate::testing::rpc::{self, Server, PRINTER};
+
+    /// An enforcer that doesn't come back from anything until let go
+    struct Stuck(Arc<AtomicBool>);
+
+    impl Stuck {
+        fn wait(&self) {
+            while self.0.load(Ordering::SeqCst) {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+
+    impl Enforce for Stuck {
+        fn enforce(&self, _: &str, _: &str, _: &str) -> Result<bool> {
+            self.wait();
+            Ok(true)
+        }
+
+        fn policy(&self) -> Vec<Vec<String>> {
+            Vec::new()
+        }
+
+        fn roles(&mut self) -> Vec<String> {
+            self.wait();
+            Vec::new()
+        }
+
+        fn members(&mut self, _: &str) -> Vec<String> {
+            self.wait();
+            Vec::new()
+        }
+
+        fn roles_of(&mut self, _: &str) -> Vec<String> {
+            self.wait();
+            Vec::new()
+        }
+    }
+
+    #[test]
+    fn stuck_enforcer() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        let stuck = Arc::new(AtomicBool::new(true));
+        exec.run_until(async {
+            let server = Server::with_config(&spawner, |c| {
+                c.access.enforce_timeout = 1;
+                c.access.breaker_threshold = 2;
+            }).await;
+            let alice = server.connect(&spawner).await;
+            alice.login_as("alice").await;
+            let perm = server.api.permissions();
+            let enforcer: Box<dyn Enforce> = Box::new(Stuck(stuck.clone()));
+            perm.write().await.pdb = Arc::new(std::sync::RwLock::new(enforcer));
+
+            for _ in 0..2 {
+                let e = alice.use_machine(PRINTER).await.err().unwrap();
+                assert_eq!(rpc::code(&e), "unavailable");
+            }
+            assert!(server.api.breaker().is_open());
+            // The checks still stuck on the thread pool don't keep anybody else from the lock
+            assert!(timeout(Duration::from_millis(100), perm.write()).await.is_ok());
+
+            // The probe gets stuck as well, nothing else is let through until it's back
+            let e = alice.use_machine(PRINTER).await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unavailable");
+            let started = Instant::now();
+            let e = alice.use_machine(PRINTER).await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unavailable");
+            let perms = alice.bootstrap.permissions_request().send().pipeline.get_perm();
+            let e = perms.list_roles_of_request().send().promise.await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unavailable");
+            assert!(started.elapsed() < Duration::from_millis(500));
+
+            // Reloading doesn't have to wait for them either
+            assert!(timeout(Duration::from_millis(500), perm.write().await.reload_policy()).await
+                .unwrap().is_ok());
+        });
+        stuck.store(false, Ordering::SeqCst);
+    }
+
+    /// An enforcer allowing everything, but looking at anything takes until let go
+    struct SlowReads {
+        started: Arc<AtomicBool>,
+        held: Arc<AtomicBool>,
+    }
+
+    impl Enforce for SlowReads {
+        fn enforce(&self, _: &str, _: &str, action: &str) -> Result<bool> {
+            if action == "read" {
+                self.started.store(true, Ordering::SeqCst);
+                while self.held.load(Ordering::SeqCst) {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            }
+            Ok(true)
+        }
+
+        fn policy(&self) -> Vec<Vec<String>> {
+            Vec::new()
+        }
+
+        fn roles(&mut self) -> Vec<String> {
+            Vec::new()
+        }
+
+        fn members(&mut self, _: &str) -> Vec<String> {
+            Vec::new()
+        }
+
+        fn roles_of(&mut self, _: &str) -> Vec<String> {
+            Vec::new()
+        }
+    }
+
+    #[test]
+    fn slow_scans_dont_hold_up_uses() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        let started = Arc::new(AtomicBool::new(false));
+        let held = Arc::new(AtomicBool::new(true));
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let alice = server.connect(&spawner).await;
+            alice.login_as("alice").await;
+            let bob = server.connect(&spawner).await;
+            bob.login_as("bob").await;
+            let enforcer: Box<dyn Enforce> = Box::new(SlowReads {
+                started: started.clone(),
+                held: held.clone(),
+            });
+            server.api.permissions().write().await.pdb =
+                Arc::new(std::sync::RwLock::new(enforcer));
+
+            // The listing is slow in its checks, after it copied the machines and let go of them
+            let (tx, listed) = oneshot::channel();
+            spawner.spawn_local(async move {
+                let _ = tx.send(bob.list().await.map(|l| l.len()));
+            }).unwrap();
+            while !started.load(Ordering::SeqCst) {
+                task::sleep(Duration::from_millis(10)).await;
+            }
+
+            let before = Instant::now();
+            alice.use_machine(PRINTER).await.unwrap();
+            assert!(before.elapsed() < Duration::from_millis(500));
+
+            held.store(false, Ordering::SeqCst);
+            assert_eq!(listed.await.unwrap().unwrap(), 2);
+        });
+        held.store(false, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn role_queries_time_out() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        let stuck = Arc::new(AtomicBool::new(true));
+        exec.run_until(async {
+            let server = Server::with_config(&spawner, |c| c.access.enforce_timeout = 1).await;
+            let alice = server.connect(&spawner).await;
+            alice.login_as("alice").await;
+            let perm = server.api.permissions();
+            let enforcer: Box<dyn Enforce> = Box::new(Stuck(stuck.clone()));
+            perm.write().await.pdb = Arc::new(std::sync::RwLock::new(enforcer));
+
+            let perms = alice.bootstrap.permissions_request().send().pipeline.get_perm();
+            let started = Instant::now();
+            let e = perms.list_roles_of_request().send().promise.await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unavailable");
+            assert!(started.elapsed() < Duration::from_secs(2));
+        });
+        stuck.store(false, Ordering::SeqCst);
+    }
+
+    /// A page of the names `list` came back with, and the total
+    fn names(list: capnp::text_list::Reader, total: u32) -> (Vec<String>, u32) {
+        let mut names: Vec<String> = list.iter().map(|n| n.unwrap().to_string()).collect();
+        names.sort();
+        (names, total)
+    }
+
+    async fn roles(client: &rpc::Client, offset: u32, limit: u32)
+        -> std::result::Result<(Vec<String>, u32), capnp::Error>
+    {
+        let perms = client.bootstrap.permissions_request().send().pipeline.get_perm();
+        let mut req = perms.list_roles_request();
+        req.get().set_offset(offset);
+        req.get().set_limit(limit);
+        let reply = req.send().promise.await?;
+        Ok(names(reply.get()?.get_roles()?, reply.get()?.get_total()))
+    }
+
+    async fn members(client: &rpc::Client, role: &str, offset: u32, limit: u32)
+        -> std::result::Result<(Vec<String>, u32), capnp::Error>
+    {
+        let perms = client.bootstrap.permissions_request().send().pipeline.get_perm();
+        let mut req = perms.list_members_request();
+        req.get().set_role(role);
+        req.get().set_offset(offset);
+        req.get().set_limit(limit);
+        let reply = req.send().promise.await?;
+        Ok(names(reply.get()?.get_members()?, reply.get()?.get_total()))
+    }
+
+    async fn roles_of(client: &rpc::Client, user: &str)
+        -> std::result::Result<(Vec<String>, u32), capnp::Error>
+    {
+        let perms = client.bootstrap.permissions_request().send().pipeline.get_perm();
+        let mut req = perms.list_roles_of_request();
+        req.get().set_user(user);
+        let reply = req.send().promise.await?;
+        Ok(names(reply.get()?.get_roles()?, reply.get()?.get_total()))
+    }
+
+    fn strings(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn role_listings() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let admin = server.connect(&spawner).await;
+            admin.login_as("admin").await;
+
+            assert_eq!(roles(&admin, 0, 0).await.unwrap(),
+                (strings(&["role:admin", "role:member"]), 2));
+            assert_eq!(members(&admin, "role:member", 0, 0).await.unwrap(),
+                (strings(&["alice", "bob"]), 2));
+            assert_eq!(members(&admin, "role:nobody", 0, 0).await.unwrap(), (vec![], 0));
+            assert_eq!(roles_of(&admin, "bob").await.unwrap(), (strings(&["role:member"]), 1));
+
+            // Pages of one make up the whole list
+            let (first, total) = members(&admin, "role:member", 0, 1).await.unwrap();
+            let (second, _) = members(&admin, "role:member", 1, 1).await.unwrap();
+            assert_eq!((first.len(), second.len(), total), (1, 1, 2));
+            assert_ne!(first, second);
+            assert_eq!(members(&admin, "role:member", 2, 1).await.unwrap(), (vec![], 2));
+
+            // Members only get to know their own roles
+            let alice = server.connect(&spawner).await;
+            alice.login_as("alice").await;
+            assert_eq!(roles_of(&alice, "").await.unwrap(), (strings(&["role:member"]), 1));
+            assert_eq!(roles_of(&alice, "alice").await.unwrap(), (strings(&["role:member"]), 1));
+            for e in vec![
+                roles_of(&alice, "bob").await.err().unwrap(),
+                roles(&alice, 0, 0).await.err().unwrap(),
+                members(&alice, "role:member", 0, 0).await.err().unwrap(),
+            ] {
+                assert_eq!(rpc::code(&e), "unauthorized");
+            }
+
+            let anonymous = server.connect(&spawner).await;
+            let e = roles_of(&anonymous, "").await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unauthenticated");
+        });
+    }
 }
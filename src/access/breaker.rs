@@ -0,0 +1,180 @@
+//! Keeps an enforcer that stopped answering from taking every API call down with it
+//!
+//! Checks that take longer than the timeout count as failed. After enough of them in a row the
+//! breaker opens and checks aren't even tried anymore, except for one every `PROBE_INTERVAL` to
+//! find out whether the enforcer is back. The first check that succeeds closes it again.
+//!
+//! Only one such probe is out at a time. A probe that times out may still be stuck in the enforcer
+//! on a thread of the pool, and sending another one every interval would stick them all.
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use slog::Logger;
+
+use crate::config::Access;
+use crate::status::Status;
+
+/// How often a check is let through to the enforcer while the breaker is open
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct Breaker {
+    log: Logger,
+    timeout: Duration,
+    threshold: u32,
+    /// Allow `read` while open instead of denying everything
+    allow_read: bool,
+    /// Checks in a row that timed out
+    timeouts: AtomicU32,
+    /// When a check was last let through while open
+    last_probe: Mutex<Option<Instant>>,
+    probe_interval: Duration,
+    /// Whether the probe let through last hasn't come back from the enforcer yet
+    probing: Arc<AtomicBool>,
+    status: Arc<Status>,
+}
+
+/// What to do about a check, see `Breaker::admit`
+pub enum Admit {
+    /// Ask the enforcer, as the probe if there is one
+    Ask(Option<Probe>),
+    /// Don't, the breaker is open and this is the answer
+    Answer(bool),
+}
+
+/// The check let through while the breaker is open
+///
+/// Has to be kept until the enforcer returns, however long after the timeout that is. No other
+/// check is let through before it's dropped.
+pub struct Probe(Arc<AtomicBool>);
+
+impl Drop for Probe {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Breaker {
+    pub fn new(log: Logger, config: &Access, status: Arc<Status>) -> Self {
+        Self {
+            log, status,
+            timeout: Duration::from_secs(config.enforce_timeout),
+            threshold: config.breaker_threshold,
+            allow_read: config.breaker_allow_read,
+            timeouts: AtomicU32::new(0),
+            last_probe: Mutex::new(None),
+            probe_interval: PROBE_INTERVAL,
+            probing: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// How long a single check may take
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.timeouts.load(Ordering::Relaxed) >= self.threshold
+    }
+
+    /// Whether to ask the enforcer about `action` or what the answer is without asking
+    pub fn admit(&self, action: &str) -> Admit {
+        if !self.is_open() {
+            return Admit::Ask(None);
+        }
+        let mut last = self.last_probe.lock().unwrap();
+        let due = last.map_or(true, |t| t.elapsed() >= self.probe_interval);
+        if due && !self.probing.swap(true, Ordering::SeqCst) {
+            *last = Some(Instant::now());
+            return Admit::Ask(Some(Probe(self.probing.clone())));
+        }
+        Admit::Answer(self.allow_read && action == "read")
+    }
+
+    /// A check was answered in time
+    pub fn succeeded(&self) {
+        if self.timeouts.swap(0, Ordering::Relaxed) >= self.threshold {
+            info!(self.log, "Permission checks are answered again, closing the breaker");
+            self.status.set_authz_breaker(false);
+        }
+    }
+
+    /// A check of `action` on `object` wasn't answered in time
+    pub fn timed_out(&self, object: &str, action: &str) {
+        let n = self.timeouts.fetch_add(1, Ordering::Relaxed) + 1;
+        error!(self.log, "Checking permission {} on {} took longer than {}s, the enforcer may \
+            be stuck", action, object, self.timeout.as_secs(); "timeouts" => n);
+        if n == self.threshold {
+            error!(self.log, "{} permission checks in a row timed out, opening the breaker. \
+                Checks {} until one succeeds again.", n,
+                if self.allow_read { "deny all but read" } else { "deny everything" });
+            self.status.set_authz_breaker(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::logger;
+
+    fn breaker(allow_read: bool) -> Breaker {
+        let mut config = Access::default();
+        config.breaker_threshold = 2;
+        config.breaker_allow_read = allow_read;
+        let mut breaker = Breaker::new(logger(), &config, Status::new());
+        breaker.probe_interval = Duration::from_secs(0);
+        breaker
+    }
+
+    fn asks(admit: Admit) -> bool {
+        match admit {
+            Admit::Ask(_) => true,
+            Admit::Answer(_) => false,
+        }
+    }
+
+    #[test]
+    fn opens_after_timeouts_in_a_row() {
+        let b = breaker(false);
+        b.timed_out("lab.printer", "write");
+        b.succeeded();
+        b.timed_out("lab.printer", "write");
+        assert!(!b.is_open());
+        assert!(asks(b.admit("write")));
+        b.timed_out("lab.printer", "write");
+        assert!(b.is_open());
+
+        b.succeeded();
+        assert!(!b.is_open());
+    }
+
+    #[test]
+    fn one_probe_at_a_time() {
+        let b = breaker(false);
+        b.timed_out("lab.printer", "write");
+        b.timed_out("lab.printer", "write");
+
+        let probe = match b.admit("write") {
+            Admit::Ask(Some(p)) => p,
+            _ => panic!("no probe let through"),
+        };
+        // Due again right away, but the first one is still stuck
+        assert!(matches!(b.admit("write"), Admit::Answer(false)));
+        assert!(matches!(b.admit("write"), Admit::Answer(false)));
+        drop(probe);
+        assert!(matches!(b.admit("write"), Admit::Ask(Some(_))));
+    }
+
+    #[test]
+    fn allow_read_while_open() {
+        let b = breaker(true);
+        b.timed_out("lab.printer", "read");
+        b.timed_out("lab.printer", "read");
+        let _probe = b.admit("read");
+        assert!(matches!(b.admit("read"), Admit::Answer(true)));
+        assert!(matches!(b.admit("write"), Admit::Answer(false)));
+    }
+}
@@ -0,0 +1,165 @@
+//! Permissions granted for a limited time, e.g. by a trainer after an induction
+//!
+//! These are kept apart from the casbin policy so granting one doesn't mean rewriting the policy
+//! an admin maintains by hand. Expired grants are never consulted and dropped whenever the table is
+//! loaded or cleaned up, so they can't come back after a restart.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+use crate::error::{Result, WithPath};
+
+/// `user` may do `action` on `object` until `expires`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Grant {
+    pub user: String,
+    pub object: String,
+    pub action: String,
+    /// In seconds since the UNIX epoch, like `granted`
+    pub expires: u64,
+    /// Who granted it
+    pub by: String,
+    pub granted: u64,
+}
+
+impl Grant {
+    fn is(&self, user: &str, object: &str, action: &str) -> bool {
+        self.user == user && self.object == object && self.action == action
+    }
+}
+
+/// How the table is stored, as a TOML array of tables
+#[derive(Default, Serialize, Deserialize)]
+struct Table {
+    #[serde(default, rename = "grant")]
+    grants: Vec<Grant>,
+}
+
+/// All temporary grants, saved to `path` after every change
+pub struct Grants {
+    /// Without a path temporary grants are disabled
+    path: Option<PathBuf>,
+    grants: Vec<Grant>,
+}
+
+impl Grants {
+    /// Load the table at `path`, which is created on the first grant if it doesn't exist
+    pub fn open(path: &Path, now: u64) -> Result<Self> {
+        let grants = if path.exists() {
+            let content = fs::read_to_string(path)?;
+            let table: Table = toml::from_str(&content)?;
+            table.grants.into_iter().filter(|g| g.expires > now).collect()
+        } else {
+            Vec::new()
+        };
+        Ok(Self { path: Some(path.to_path_buf()), grants })
+    }
+
+    /// No table, for when temporary grants aren't configured
+    pub fn disabled() -> Self {
+        Self { path: None, grants: Vec::new() }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    /// Whether an unexpired grant allows `user` to do `action` on `object`
+    pub fn allows(&self, user: &str, object: &str, action: &str, now: u64) -> bool {
+        self.grants.iter().any(|g| g.is(user, object, action) && g.expires > now)
+    }
+
+    /// All grants that haven't expired as of `now`
+    pub fn list(&self, now: u64) -> impl Iterator<Item=&Grant> {
+        self.grants.iter().filter(move |g| g.expires > now)
+    }
+
+    /// Add `grant`, replacing any earlier grant of the same permission to the same user
+    pub fn add(&mut self, grant: Grant) -> Result<()> {
+        self.grants.retain(|g| !g.is(&grant.user, &grant.object, &grant.action));
+        self.grants.push(grant);
+        self.save()
+    }
+
+    /// Take back the grant of `action` on `object` from `user`, returning it if there was one
+    pub fn revoke(&mut self, user: &str, object: &str, action: &str, now: u64)
+        -> Result<Option<Grant>>
+    {
+        let i = match self.grants.iter().position(|g| g.is(user, object, action)) {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        let grant = self.grants.remove(i);
+        self.save()?;
+        // An expired grant was as good as gone already
+        Ok(Some(grant).filter(|g| g.expires > now))
+    }
+
+    /// Drop all grants that expired, returning how many there were
+    pub fn expire(&mut self, now: u64) -> Result<usize> {
+        let before = self.grants.len();
+        self.grants.retain(|g| g.expires > now);
+        let expired = before - self.grants.len();
+        if expired > 0 {
+            self.save()?;
+        }
+        Ok(expired)
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = match self.path {
+            Some(ref p) => p,
+            None => return Ok(()),
+        };
+        // Same dance as for the machine database, so a crash never leaves half a table behind.
+        // Whoever can change it can grant themselves anything, so it's only for us.
+        let tmp = path.with_extension("tmp");
+        {
+            let mut fp = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600)
+                .open(&tmp).with_path(&tmp)?;
+            let toml = toml::to_string(&Table { grants: self.grants.clone() })?;
+            fp.write_all(toml.as_bytes()).with_path(&tmp)?;
+            fp.sync_all().with_path(&tmp)?;
+        }
+        fs::rename(&tmp, path).with_path(path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn grant(user: &str, expires: u64) -> Grant {
+        Grant {
+            user: user.to_string(),
+            object: "lab.laser".to_string(),
+            action: "write".to_string(),
+            expires,
+            by: "trainer".to_string(),
+            granted: 1_000_000,
+        }
+    }
+
+    #[test]
+    fn saved_for_us_only() {
+        let dir = TempDir::new();
+        let path = dir.join("grants.toml");
+        let mut grants = Grants::open(&path, 1_000_000).unwrap();
+        grants.add(grant("alice", 1_003_600)).unwrap();
+        grants.add(grant("bob", 1_000_100)).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        // Expired ones don't come back
+        let grants = Grants::open(&path, 1_000_100).unwrap();
+        assert!(grants.allows("alice", "lab.laser", "write", 1_000_100));
+        assert_eq!(grants.list(1_000_100).collect::<Vec<_>>(), vec![&grant("alice", 1_003_600)]);
+    }
+}
@@ -0,0 +1,79 @@
+//! Finding machines nobody can use and policy rules for machines that are gone
+//!
+//! Both happen easily since the policy and the machine database are edited apart from each other.
+//! Objects are compared as they are written, a rule matching many machines through a pattern in
+//! the casbin model counts for none of them.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use slog::Logger;
+
+use uuid::Uuid;
+
+/// Objects the server checks that aren't machines
+const BUILTIN_OBJECTS: &[&str] = &["server", "machines"];
+
+/// Actions whose object is a user instead of a machine
+const USER_ACTIONS: &[&str] = &["su"];
+
+/// Where the policy and the machines don't fit together
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Machines whose perm appears in no rule, with that perm
+    pub unusable: Vec<(Uuid, String)>,
+    /// Objects of rules that are no machine's perm
+    pub stale: Vec<String>,
+}
+
+impl Report {
+    /// Compare the perms of `machines` against the objects of the `policy` rules
+    ///
+    /// Rules are `[subject, object, action]` as casbin has them.
+    pub fn new(machines: &[(Uuid, String)], policy: &[Vec<String>]) -> Self {
+        let objects: HashSet<&str> = policy.iter()
+            .filter(|rule| !rule.get(2).map_or(false, |a| USER_ACTIONS.contains(&a.as_str())))
+            .filter_map(|rule| rule.get(1))
+            .map(String::as_str)
+            .filter(|o| !BUILTIN_OBJECTS.contains(o))
+            .collect();
+        let perms: HashSet<&str> = machines.iter().map(|(_, p)| p.as_str()).collect();
+
+        let mut unusable: Vec<_> = machines.iter()
+            .filter(|(_, p)| !objects.contains(p.as_str()))
+            .cloned()
+            .collect();
+        unusable.sort_by(|a, b| a.1.cmp(&b.1));
+        let mut stale: Vec<_> = objects.difference(&perms).map(|o| o.to_string()).collect();
+        stale.sort();
+
+        Self { unusable, stale }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.unusable.is_empty() && self.stale.is_empty()
+    }
+
+    /// Warn about every mismatch on its own
+    pub fn log(&self, log: &Logger) {
+        for line in self.lines() {
+            warn!(log, "{}", line);
+        }
+    }
+
+    /// One line for every mismatch
+    pub fn lines(&self) -> Vec<String> {
+        let unusable = self.unusable.iter().map(|(uuid, perm)| format!("Machine {} can't be used \
+            by anybody, no policy rule is about its perm {}", uuid.to_hyphenated(), perm));
+        let stale = self.stale.iter()
+            .map(|o| format!("Policy rules are about {}, which is no machine's perm", o));
+        unusable.chain(stale).collect()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} machines nobody can use and {} policy objects without a machine",
+            self.unusable.len(), self.stale.len())
+    }
+}
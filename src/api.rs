@@ -4,11 +4,44 @@ pub mod api {
     include!(concat!(env!("OUT_DIR"), "/schema/api_capnp.rs"));
 }
 
-use std::default::Default;
-use async_std::net::TcpStream;
+pub mod admin;
+pub mod codes;
+pub mod error;
+pub mod ratelimit;
+
+/// Version of the API schema, see `getApiVersion` in schema/api.capnp for what the numbers mean
+///
+/// Bump these together with every change to the schema.
+pub const API_VERSION_MAJOR: u16 = 1;
+pub const API_VERSION_MINOR: u16 = 38;
+
+/// Seconds between warnings about clients sending messages over the limits
+const LIMIT_WARN_INTERVAL: u64 = 10;
+
+/// When we last warned about a message over the limits, in seconds since the UNIX epoch, and how
+/// many we kept quiet about since
+static LIMIT_WARNED: AtomicU64 = AtomicU64::new(0);
+static LIMIT_SUPPRESSED: AtomicU64 = AtomicU64::new(0);
+
+/// How long before closing an idle connection its server event subscriber is warned
+const SESSION_WARNING: Duration = Duration::from_secs(60);
+
+/// How long a server event subscriber has to answer before it's dropped
+const EVENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use async_std::future::timeout;
 
 use futures::task::Spawn;
-use futures::FutureExt;
+use futures::{Future, FutureExt};
+use futures::future::{AbortHandle, Abortable};
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::future;
+use futures::channel::mpsc;
+use futures::stream::{Stream, StreamExt};
+use async_std::task;
 use futures_signals::signal::Mutable;
 use casbin::Enforcer;
 use casbin::MgmtApi;
@@ -18,12 +51,28 @@ use slog::Logger;
 use std::rc::Rc;
 use async_std::sync::{Arc, RwLock};
 
-use crate::machine::{MachinesProvider, Machines};
-use crate::auth::{AuthenticationProvider, Authentication};
+use crate::machine::{self, lock, MachinesProvider, Machines, Grants};
+use crate::machine::watch::Channels;
+use crate::auth::{self, AuthenticationProvider, Authentication};
+use crate::auth::setup::{self, Setup};
 use crate::access::{PermissionsProvider, Permissions};
+use crate::access::breaker::Breaker;
+use crate::config::{self, IdleGrants};
+use crate::connection::{Activity, ClientInfo, Hello, Session, Sessions, Tracked};
+use crate::status::{Status, Bridge};
+use crate::audit::{Audit, AuditEvent};
+use crate::events::{Events, ServerEvent};
+use crate::stats::Stats;
+use crate::webhook::Deliveries;
+
+use error::RetryAfter;
+use ratelimit::{RateLimiter, Throttle, Limited};
+
+use uuid::Uuid;
 
 use capnp::{Error};
 use capnp::capability::Promise;
+use capnp::message::ReaderOptions;
 use capnp_rpc::RpcSystem;
 use capnp_rpc::twoparty::VatNetwork;
 use capnp_rpc::rpc_twoparty_capnp::Side;
@@ -37,47 +86,508 @@ pub struct API<S> {
     auth: Arc<RwLock<AuthenticationProvider>>,
     perm: Arc<RwLock<PermissionsProvider>>,
     mach: Arc<RwLock<MachinesProvider>>,
+    /// How long calls wait for `mach`, see `machine::lock`
+    lock_timeout: Duration,
+    breaker: Arc<Breaker>,
+
+    config: config::Api,
+    status: Arc<Status>,
+    audit: Audit,
+    /// Shared by all connections so limits per user hold across connections
+    limiter: Option<Arc<RateLimiter>>,
+    sessions: Sessions,
+    events: Events,
+    /// Webhook deliveries, if there are any hooks
+    deliveries: Option<Deliveries>,
+    /// Ways to reach users, for admins to test
+    channels: Channels,
+    stats: Option<Stats>,
+    setup: Setup,
 
     spawner: S,
 }
-impl<S: Spawn> API<S> {
+impl<S: Spawn + 'static> API<S> {
     pub fn new(auth: AuthenticationProvider, 
        perm: PermissionsProvider,
        mach: MachinesProvider,
+       config: config::Api,
+       status: Arc<Status>,
+       audit: Audit,
+       deliveries: Option<Deliveries>,
+       channels: Channels,
+       stats: Option<Stats>,
+       setup: Setup,
        spawner: S)
         -> Self
     {
         let auth = Arc::new(RwLock::new(auth));
+        let breaker = perm.breaker();
         let perm = Arc::new(RwLock::new(perm));
+        let lock_timeout = mach.lock_timeout();
         let mach = Arc::new(RwLock::new(mach));
+        let limiter = config.rate_limit.clone().map(RateLimiter::new);
+
+        Self { auth, perm, mach, lock_timeout, breaker, config, status, audit, limiter,
+            sessions: Sessions::new(), events: Events::new(), deliveries, channels, stats, setup,
+            spawner }
+    }
+
+    /// Where to publish events clients may subscribe to
+    pub fn events(&self) -> Events {
+        self.events.clone()
+    }
+
+    /// All connections being served
+    pub fn sessions(&self) -> Sessions {
+        self.sessions.clone()
+    }
 
-        Self { auth, perm, mach, spawner }
+    /// The machine state shared by all connections
+    pub fn machines(&self) -> Arc<RwLock<MachinesProvider>> {
+        self.mach.clone()
     }
 
-    pub fn into_connection(self) -> Bootstrap {
-        let auth = Rc::new(Authentication::new(self.auth));
-        let perm = Rc::new(Permissions::new(self.perm, auth.clone()));
-        let mach = Machines::new(self.mach, perm.clone());
+    /// Rate limiter shared by all connections, if calls are limited
+    pub fn limiter(&self) -> Option<Arc<RateLimiter>> {
+        self.limiter.clone()
+    }
+
+    /// The access control shared by all connections
+    pub fn permissions(&self) -> Arc<RwLock<PermissionsProvider>> {
+        self.perm.clone()
+    }
+
+    /// The breaker in front of the enforcer, also there while the lock around it isn't
+    pub fn breaker(&self) -> Arc<Breaker> {
+        self.breaker.clone()
+    }
+
+    /// Users, passwords and SASL mechanisms, shared by all connections
+    pub fn authentication(&self) -> Arc<RwLock<AuthenticationProvider>> {
+        self.auth.clone()
+    }
+
+    /// Write out all state that is kept in memory, waiting for the machines through `background`
+    pub async fn flush(&self, background: &lock::Background) -> crate::error::Result<()> {
+        background.write(&self.mach, "save it").await.flush()
+    }
+
+    /// Capabilities for a single connection, logging to that connection's `log`
+    ///
+    /// `peer` describes the other end of the connection for the audit trail, `local` says whether
+    /// it came in over loopback or a Unix socket.
+    pub fn into_connection(self, log: Logger, peer: String, local: bool) -> Bootstrap {
+        let audit = self.audit.for_peer(peer);
+        let spawner: Rc<dyn Spawn> = Rc::new(self.spawner);
+        let auth = Authentication::new(log.new(o!("system" => "authentication")), self.auth,
+            spawner.clone(), audit.clone(), self.status.clone());
+        // Local connections are as encrypted as whatever proxy they came through
+        let auth = Rc::new(if self.config.require_tls && !local {
+            auth.without_passwords()
+        } else {
+            auth
+        });
+        let perm = Rc::new(Permissions::new(log.new(o!("system" => "permissions")),
+            self.perm, self.breaker, auth.clone(), audit, spawner.clone(), self.status.clone()));
+        let mach = Machines::new(log.new(o!("system" => "machines")), self.mach, perm.clone(),
+            spawner, self.status.clone(), self.lock_timeout);
+        let throttle = self.limiter.map(|l| l.for_connection(log.clone(), auth.clone(), local));
         Bootstrap {
+            log: log,
             auth: auth,
             perm: perm,
             mach: mach,
+            status: self.status,
+            sessions: self.sessions,
+            deliveries: self.deliveries,
+            channels: self.channels,
+            stats: self.stats,
+            setup: self.setup,
+            require_auth: self.config.require_auth_for_bootstrap,
+            require_tls: self.config.require_tls,
+            throttle,
+            subscriber: Rc::new(RefCell::new(None)),
+            client: Rc::new(RefCell::new(None)),
         }
     }
 }
 
-pub async fn handle_connection<S: Spawn>(api: API<S>, log: Logger, socket: TcpStream) -> Result<(), Error> {
+/// Serve the API on a connection until it's closed
+///
+/// `stream` can be any transport; the capnp messages are read from and written to clones of it.
+/// `peer` describes the other end for the audit trail since not every transport has an address.
+/// `local` connections may be exempt from rate limiting. Admins know the connection by its `id`.
+///
+/// This has to run on the local executor, not the thread pool. capnp-rpc keeps its connection
+/// state and every client it hands out in `Rc`s, so neither the RPC system nor the capabilities
+/// we serve can move between threads, no matter what we keep in them. Connection state like
+/// `Sessions` relies on that as well. Anything expensive a call does is handed to the thread pool
+/// through the spawner instead, like checking passwords.
+pub async fn handle_connection<S, T>(api: API<S>, log: Logger, stream: T, peer: String,
+    local: bool, id: u64) -> Result<(), Error>
+    where S: Spawn + 'static,
+          T: AsyncRead + AsyncWrite + Clone + Unpin + 'static,
+{
     info!(log, "A new connection");
-    let client = api.into_connection();
+    let idle_timeout = Duration::from_secs(api.config.idle_timeout);
+    let dead_after = Duration::from_secs(api.config.dead_after);
+    let idle_grants = api.config.idle_grants;
+    let mach = api.mach.clone();
+    // Giving back machines of idle connections is up to us, nobody's waiting for that
+    let background = lock::Background::new(log.clone(), api.lock_timeout);
+    let sessions = api.sessions.clone();
+    let api_config = api.config.clone();
+    let events = api.events.subscribe();
+
+    let client = api.into_connection(log.clone(), peer.clone(), local);
+    let subscriber = client.subscriber.clone();
+    let hello = client.client.clone();
+    let grants = client.mach.grants();
+    let auth_state = client.auth.state.clone();
+    let audit = client.perm.audit().clone();
     let a = api::diflouroborane::ToClient::new(client).into_client::<capnp_rpc::Server>();
 
-    let netw = VatNetwork::new(socket.clone(), socket, Side::Server, Default::default());
+    // Every read or write on the stream counts as activity, including notifications we send out.
+    let activity = Activity::new();
+    let stream = Tracked::new(stream, activity.clone());
+    let netw = VatNetwork::new(stream.clone(), stream, Side::Server,
+        reader_options(&api_config));
+
+    let rpc_log = log.clone();
+    let rpc_peer = peer.clone();
+    let rpc = RpcSystem::new(Box::new(netw), Some(a.clone().client)).map(move |r| {
+        if let Err(e) = r {
+            rpc_failed(&rpc_log, &rpc_peer, &e);
+        }
+    });
+
+    // Listed for admins until we return, whichever way
+    let (abort, abort_registration) = AbortHandle::new_pair();
+    // Warnings about this connection being closed and machines its user waits for go only to its
+    // own subscriber
+    let (expiring, session_events) = mpsc::unbounded();
+
+    let _session = sessions.register(id, Session {
+        peer,
+        since: machine::unix_secs(SystemTime::now()),
+        authzid: auth_state.clone(),
+        grants: grants.clone(),
+        activity: activity.clone(),
+        abort,
+        events: expiring.clone(),
+        subscriber: subscriber.clone(),
+        client: hello.clone(),
+    });
+    let released = |uuid: &Uuid| {
+        // Whatever the connection authenticated as last is who had the machine
+        let user = auth_state.try_read().and_then(|s| s.clone()).unwrap_or_default();
+        audit.record(AuditEvent::MachineReleased { authzid: &user, machine: uuid });
+    };
+    let served = serve(rpc, &log, &activity, idle_timeout, idle_grants, mach, background, grants,
+        released, |secs| {
+        let _ = expiring.unbounded_send(ServerEvent::SessionExpiring { secs });
+    });
+    let events = futures::stream::select(events, session_events);
+    let delivered = deliver(&log, events, subscriber, &activity);
+    let delivered = future::select(delivered.boxed_local(),
+        watch_dead(&log, &activity, dead_after).boxed_local());
+    let served = future::select(served.boxed_local(), delivered.boxed_local())
+        .map(|e| match e {
+            future::Either::Left((r, _)) => r,
+            // Delivering never stops on its own, so this is a dead connection
+            future::Either::Right(_) => Ok(()),
+        });
+    let r = match Abortable::new(served, abort_registration).await {
+        Ok(r) => r,
+        Err(_) => {
+            info!(log, "Connection closed by an admin");
+            Ok(())
+        },
+    };
+
+    let (bytes_in, bytes_out) = activity.bytes();
+    let client = hello.borrow().as_ref().map_or(ClientInfo::UNKNOWN.to_string(),
+        |c| c.name().to_string());
+    info!(log, "Connection closed after {}s", activity.age().as_secs();
+        "bytes_in" => bytes_in, "bytes_out" => bytes_out, "client" => client);
+    r
+}
+
+/// Limits on what clients may send us, so nobody can make us chew on huge or deeply nested
+/// messages
+pub fn reader_options(config: &config::Api) -> ReaderOptions {
+    let mut options = ReaderOptions::new();
+    // The traversal limit also applies to the size of the whole message when it's read
+    options.traversal_limit_in_words(config.max_message_bytes / 8);
+    options.nesting_limit(config.max_nesting);
+    options
+}
+
+/// Log why the RPC system of a connection stopped, which also closes the connection
+///
+/// Clients running into the limits are warned about, but only every so often so a client doing
+/// so on purpose can't flood the log.
+fn rpc_failed(log: &Logger, peer: &str, e: &Error) {
+    // capnp only tells these apart from other errors by their description
+    let over_limit = ["too large", "traversal limit", "nesting limit"].iter()
+        .any(|s| e.description.contains(s));
+    if !over_limit {
+        debug!(log, "RPC failed: {}", e);
+        return;
+    }
+
+    let now = machine::unix_secs(SystemTime::now());
+    let last = LIMIT_WARNED.load(Ordering::Relaxed);
+    if now.saturating_sub(last) < LIMIT_WARN_INTERVAL
+        || LIMIT_WARNED.compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed).is_err()
+    {
+        LIMIT_SUPPRESSED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    let suppressed = LIMIT_SUPPRESSED.swap(0, Ordering::Relaxed);
+    warn!(log, "Dropping connection from {}, it sent a message over the limits: {}", peer, e;
+        "suppressed" => suppressed);
+}
+
+/// Hand server events to the subscriber of a connection as they come, for as long as it has one
+///
+/// A subscriber that fails or takes too long to answer is dropped. This never returns.
+async fn deliver(log: &Logger, mut events: impl Stream<Item = ServerEvent> + Unpin,
+    subscriber: Subscriber, activity: &Activity)
+{
+    while let Some(event) = events.next().await {
+        let client = match subscriber.borrow().clone() {
+            Some(c) => c,
+            None => continue,
+        };
+
+        // Warning about the connection being idle mustn't keep it open
+        let _quiet = match event {
+            ServerEvent::SessionExpiring { .. } => Some(activity.quiet()),
+            _ => None,
+        };
+        let mut req = client.event_request();
+        event.fill(req.get().init_event());
+        let why = match timeout(EVENT_TIMEOUT, req.send().promise).await {
+            Ok(Ok(_)) => continue,
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => format!("no answer within {}s", EVENT_TIMEOUT.as_secs()),
+        };
+        debug!(log, "Dropping server event subscriber: {}", why);
+        subscriber.borrow_mut().take();
+    }
+    future::pending().await
+}
+
+/// Return once the peer didn't send anything for `dead_after`, never if that's zero
+///
+/// Machines in use through the connection stay in use, like with any other connection that's
+/// gone. Whoever had them can reclaim them from a new connection.
+async fn watch_dead(log: &Logger, activity: &Activity, dead_after: Duration) {
+    if dead_after == Duration::from_secs(0) {
+        return future::pending().await;
+    }
+    loop {
+        let silent = activity.silent_for();
+        if silent >= dead_after {
+            break;
+        }
+        task::sleep(dead_after - silent).await;
+    }
+    info!(log, "Closing connection, the client sent nothing for {}s", dead_after.as_secs());
+}
+
+/// Run the RPC system of a connection until it's closed or was idle for too long
+///
+/// `gave_back` is called for every machine given back because of the idle timeout, `expiring`
+/// with the seconds left shortly before the connection is closed for being idle.
+async fn serve(rpc: impl Future<Output = ()>, log: &Logger, activity: &Activity,
+    idle_timeout: Duration, idle_grants: IdleGrants, mach: Arc<RwLock<MachinesProvider>>,
+    background: lock::Background, grants: Grants, gave_back: impl Fn(&Uuid),
+    expiring: impl Fn(u64)) -> Result<(), Error>
+{
+    if idle_timeout == Duration::from_secs(0) {
+        rpc.await;
+        return Ok(());
+    }
+
+    // Runs until the connection was idle for too long. Dropping the RpcSystem afterwards closes
+    // the connection.
+    let warn_at = idle_timeout - SESSION_WARNING.min(idle_timeout / 2);
+    let watchdog = async {
+        let mut warned = false;
+        loop {
+            let idle = activity.idle_for();
+            if idle < warn_at {
+                warned = false;
+                task::sleep(warn_at - idle).await;
+                continue;
+            }
+            if idle < idle_timeout {
+                // Connections that are kept open anyway have nothing to be warned about
+                let kept = idle_grants == IdleGrants::Exempt && !grants.borrow().is_empty();
+                if !warned && !kept {
+                    expiring((idle_timeout - idle).as_secs());
+                }
+                warned = true;
+                task::sleep(idle_timeout - idle).await;
+                continue;
+            }
+
+            let held: Vec<Uuid> = grants.borrow().iter().cloned().collect();
+            if held.is_empty() {
+                break;
+            }
+
+            match idle_grants {
+                IdleGrants::Exempt => {
+                    task::sleep(idle_timeout).await;
+                },
+                IdleGrants::GiveBack => {
+                    let mut mach = background.write(&mach, "give back machines of an idle \
+                        connection").await;
+                    for uuid in held.iter() {
+                        info!(log, "Giving back machine {} of idle connection", uuid);
+                        mach.give_back(log, uuid)?;
+                        gave_back(uuid);
+                    }
+                    grants.borrow_mut().clear();
+                    break;
+                },
+            }
+        }
+
+        info!(log, "Closing connection idle for {}s", idle_timeout.as_secs());
+        Ok::<(), Error>(())
+    };
+
+    match future::select(rpc.boxed_local(), watchdog.boxed_local()).await {
+        future::Either::Left(_) => Ok(()),
+        future::Either::Right((r, _)) => r,
+    }
+}
+
+/// How long a client of an overloaded server is given to read its error before we hang up
+const OVERLOADED_LINGER: Duration = Duration::from_secs(5);
+
+/// Serve a connection that arrived while the server is overloaded
+///
+/// The client gets a bootstrap capability failing every call with an `overloaded` error so it knows
+/// to come back later. The connection is closed shortly after no matter what the client does.
+pub async fn handle_overloaded<T>(log: Logger, stream: T, options: ReaderOptions,
+    retry: RetryAfter)
+    where T: AsyncRead + AsyncWrite + Clone + Unpin + 'static,
+{
+    let client = diflouroborane::ToClient::new(Overloaded { retry })
+        .into_client::<capnp_rpc::Server>();
+    let netw = VatNetwork::new(stream.clone(), stream, Side::Server, options);
+    let rpc = RpcSystem::new(Box::new(netw), Some(client.client));
+
+    if timeout(OVERLOADED_LINGER, rpc).await.is_err() {
+        trace!(log, "Closing connection to client of overloaded server");
+    }
+}
+
+/// Lightweight bootstrap handed out while the server is overloaded
+struct Overloaded {
+    /// How long the client should wait, fixed when it connected
+    retry: RetryAfter,
+}
+
+impl Overloaded {
+    fn err(&self) -> Promise<(), Error> {
+        Promise::err(error::overloaded_retry("Server overloaded", self.retry))
+    }
+}
+
+impl diflouroborane::Server for Overloaded {
+    fn authentication(&mut self,
+        _params: diflouroborane::AuthenticationParams,
+        _results: diflouroborane::AuthenticationResults)
+        -> Promise<(), Error>
+    {
+        self.err()
+    }
+
+    fn permissions(&mut self,
+        _params: diflouroborane::PermissionsParams,
+        _results: diflouroborane::PermissionsResults)
+        -> Promise<(), Error>
+    {
+        self.err()
+    }
+
+    fn machines(&mut self,
+        _params: diflouroborane::MachinesParams,
+        _results: diflouroborane::MachinesResults)
+        -> Promise<(), Error>
+    {
+        self.err()
+    }
+
+    fn get_server_info(&mut self,
+        _params: diflouroborane::GetServerInfoParams,
+        _results: diflouroborane::GetServerInfoResults)
+        -> Promise<(), Error>
+    {
+        self.err()
+    }
+
+    fn get_api_version(&mut self,
+        _params: diflouroborane::GetApiVersionParams,
+        _results: diflouroborane::GetApiVersionResults)
+        -> Promise<(), Error>
+    {
+        self.err()
+    }
 
-    let rpc = RpcSystem::new(Box::new(netw), Some(a.clone().client)).map(|_| ());
+    fn hello(&mut self,
+        _params: diflouroborane::HelloParams,
+        _results: diflouroborane::HelloResults)
+        -> Promise<(), Error>
+    {
+        self.err()
+    }
 
-    rpc.await;
-    
-    Ok(())
+    fn admin(&mut self,
+        _params: diflouroborane::AdminParams,
+        _results: diflouroborane::AdminResults)
+        -> Promise<(), Error>
+    {
+        self.err()
+    }
+
+    fn subscribe_server_events(&mut self,
+        _params: diflouroborane::SubscribeServerEventsParams,
+        _results: diflouroborane::SubscribeServerEventsResults)
+        -> Promise<(), Error>
+    {
+        self.err()
+    }
+
+    fn ping(&mut self,
+        _params: diflouroborane::PingParams,
+        _results: diflouroborane::PingResults)
+        -> Promise<(), Error>
+    {
+        self.err()
+    }
+
+    fn claim_instance(&mut self,
+        _params: diflouroborane::ClaimInstanceParams,
+        _results: diflouroborane::ClaimInstanceResults)
+        -> Promise<(), Error>
+    {
+        self.err()
+    }
+
+    fn get_capabilities(&mut self,
+        _params: diflouroborane::GetCapabilitiesParams,
+        _results: diflouroborane::GetCapabilitiesResults)
+        -> Promise<(), Error>
+    {
+        self.err()
+    }
 }
 
 /// Bootstrap capability of the Diflouroborane API
@@ -87,7 +597,38 @@ pub async fn handle_connection<S: Spawn>(api: API<S>, log: Logger, socket: TcpSt
 pub struct Bootstrap {
     auth: Rc<Authentication>,
     perm: Rc<Permissions>,
+    log: Logger,
     mach: Machines,
+    status: Arc<Status>,
+    sessions: Sessions,
+    deliveries: Option<Deliveries>,
+    channels: Channels,
+    stats: Option<Stats>,
+    setup: Setup,
+    /// Refuse handing out anything but `authentication` before the connection authenticated
+    require_auth: bool,
+    require_tls: bool,
+    /// Rate limiter for the machines and permissions subsystems, if configured
+    throttle: Option<Rc<Throttle>>,
+    subscriber: Subscriber,
+    client: Hello,
+}
+
+/// Who on a connection wants to hear about server events, if anybody
+pub type Subscriber = Rc<RefCell<Option<api::server_events::Client>>>;
+
+impl Bootstrap {
+    /// Fail with an error if the connection has to but didn't yet authenticate
+    async fn check_auth(log: Logger, auth: Rc<Authentication>, require_auth: bool, what: &str)
+        -> Result<(), Error>
+    {
+        if require_auth && auth.state.read().await.is_none() {
+            info!(log, "Refusing {} to unauthenticated connection", what);
+            Err(error::unauthenticated())
+        } else {
+            Ok(())
+        }
+    }
 }
 
 impl diflouroborane::Server for Bootstrap {
@@ -97,7 +638,8 @@ impl diflouroborane::Server for Bootstrap {
         -> Promise<(), Error>
     {
         let mut b = results.get();
-        let auth = api::authentication::ToClient::new(self.auth.deref().clone()).into_client::<capnp_rpc::Server>();
+        let auth = self.auth.deref().clone().with_permissions(self.perm.clone());
+        let auth = api::authentication::ToClient::new(auth).into_client::<capnp_rpc::Server>();
         b.set_auth(auth);
         Promise::ok(())
     }
@@ -107,20 +649,787 @@ impl diflouroborane::Server for Bootstrap {
         mut results: diflouroborane::PermissionsResults) 
         -> Promise<(), Error>
     {
-        //let mut b = results.get();
-        //let perm = api::permissions::ToClient::new(self.perm).into_client::<capnp_rpc::Server>();
-        //b.set_perm(perm);
-        Promise::ok(())
+        let auth = self.auth.clone();
+        let require_auth = self.require_auth;
+        let perm = Limited::new(self.perm.deref().clone(), self.throttle.clone());
+        let log = self.log.clone();
+        Promise::from_future(async move {
+            Self::check_auth(log, auth, require_auth, "permissions").await?;
+            let mut b = results.get();
+            let perm = api::permissions::ToClient::new(perm).into_client::<capnp_rpc::Server>();
+            b.set_perm(perm);
+            Ok(())
+        })
     }
 
     fn machines(&mut self,
         _params: diflouroborane::MachinesParams,
         mut results: diflouroborane::MachinesResults) 
         -> Promise<(), Error>
+    {
+        let auth = self.auth.clone();
+        let require_auth = self.require_auth;
+        let mach = Limited::new(self.mach.clone(), self.throttle.clone());
+        let log = self.log.clone();
+        Promise::from_future(async move {
+            Self::check_auth(log, auth, require_auth, "machines").await?;
+            let mut b = results.get();
+            let mach = api::machines::ToClient::new(mach).into_client::<capnp_rpc::Server>();
+            b.set_mach(mach);
+            Ok(())
+        })
+    }
+
+    fn get_server_info(&mut self,
+        _params: diflouroborane::GetServerInfoParams,
+        mut results: diflouroborane::GetServerInfoResults)
+        -> Promise<(), Error>
+    {
+        if !self.status.allow_query() {
+            return Promise::err(error::overloaded_retry("Too many status queries",
+                RetryAfter::for_depth(0)));
+        }
+
+        let mut b = results.get().init_info();
+        b.set_version(clap::crate_version!());
+        b.set_uptime(self.status.uptime().as_secs());
+        b.set_machines(self.status.machines() as u32);
+        b.set_connections(self.status.connections() as u32);
+        b.set_last_save_ok(self.status.last_save_ok());
+        b.set_mqtt(match self.status.mqtt() {
+            Bridge::Disabled => api::server_info::Bridge::Disabled,
+            Bridge::Down => api::server_info::Bridge::Down,
+            Bridge::Up => api::server_info::Bridge::Up,
+        });
+        b.set_dry_run(self.status.dry_run());
+        b.set_saturated_for(self.status.saturated().as_secs());
+        b.set_authorization_down(self.status.authz_breaker());
+        b.set_retry_after(self.status.retry_after());
+        b.set_writes(self.status.writes());
+        b.set_coalesced_writes(self.status.coalesced());
+        b.set_read_only(self.status.read_only());
+        let registries = self.status.registries();
+        let mut r = b.reborrow().init_registries(registries.len() as u32);
+        for (i, (name, n)) in registries.iter().enumerate() {
+            let mut e = r.reborrow().get(i as u32);
+            e.set_name(name);
+            e.set_entries(*n as u64);
+        }
+        let clients = self.sessions.clients();
+        let mut c = b.reborrow().init_clients(clients.len() as u32);
+        for (i, (name, n)) in clients.iter().enumerate() {
+            let mut e = c.reborrow().get(i as u32);
+            e.set_name(name);
+            e.set_connections(*n as u32);
+        }
+        b.set_setup_pending(self.setup.pending(machine::unix_secs(SystemTime::now())));
+        Promise::ok(())
+    }
+
+    fn get_api_version(&mut self,
+        _params: diflouroborane::GetApiVersionParams,
+        mut results: diflouroborane::GetApiVersionResults)
+        -> Promise<(), Error>
     {
         let mut b = results.get();
-        let mach = api::machines::ToClient::new(self.mach.clone()).into_client::<capnp_rpc::Server>();
-        b.set_mach(mach);
+        b.set_major(API_VERSION_MAJOR);
+        b.set_minor(API_VERSION_MINOR);
+        Promise::ok(())
+    }
+
+    fn get_capabilities(&mut self,
+        _params: diflouroborane::GetCapabilitiesParams,
+        mut results: diflouroborane::GetCapabilitiesResults)
+        -> Promise<(), Error>
+    {
+        let auth = self.auth.clone();
+        let require_auth = self.require_auth;
+        let require_tls = self.require_tls;
+        let mqtt = self.status.mqtt() != Bridge::Disabled;
+        Promise::from_future(async move {
+            let mechanisms = auth.mechanisms().await;
+            let mut b = results.get().init_capabilities();
+            b.set_tls_required(require_tls);
+            b.set_anonymous_allowed(!require_auth);
+            {
+                let mut list = b.reborrow().init_mechanisms(mechanisms.len() as u32);
+                for (i, mech) in mechanisms.iter().enumerate() {
+                    list.set(i as u32, mech);
+                }
+            }
+            b.set_api_major(API_VERSION_MAJOR);
+            b.set_api_minor(API_VERSION_MINOR);
+            b.set_server_events(true);
+            b.set_mqtt(mqtt);
+            b.set_password_resets(auth.password_resets().await);
+            Ok(())
+        })
+    }
+
+    fn hello(&mut self,
+        params: diflouroborane::HelloParams,
+        _results: diflouroborane::HelloResults)
+        -> Promise<(), Error>
+    {
+        let params = pry!(params.get());
+        // This is for finding out which clients are out there, not for trusting what they say
+        let client = ClientInfo::new(pry!(params.get_client_name()),
+            pry!(params.get_client_version()), pry!(params.get_platform()));
+        info!(self.log, "Client says hello"; "client" => client.name(),
+            "client_version" => &client.version, "platform" => &client.platform);
+        self.client.borrow_mut().replace(client);
+        Promise::ok(())
+    }
+
+    fn admin(&mut self,
+        _params: diflouroborane::AdminParams,
+        mut results: diflouroborane::AdminResults)
+        -> Promise<(), Error>
+    {
+        let perm = self.perm.clone();
+        let sessions = self.sessions.clone();
+        let deliveries = self.deliveries.clone();
+        let channels = self.channels.clone();
+        let stats = self.stats.clone();
+        let machines = self.mach.provider();
+        let timeout = self.mach.lock_timeout();
+        let auth = self.auth.provider();
+        let log = self.log.clone();
+        Promise::from_future(async move {
+            perm.require("server", "admin").await?;
+            let user = perm.authzid().await.unwrap_or_default();
+            let admin = admin::Admin::new(log.new(o!("system" => "admin")), sessions, deliveries,
+                machines, timeout, perm.provider(), auth, channels, stats, perm.audit().clone(),
+                user);
+            let admin = api::admin::ToClient::new(admin).into_client::<capnp_rpc::Server>();
+            results.get().set_admin(admin);
+            Ok(())
+        })
+    }
+
+    fn subscribe_server_events(&mut self,
+        params: diflouroborane::SubscribeServerEventsParams,
+        _results: diflouroborane::SubscribeServerEventsResults)
+        -> Promise<(), Error>
+    {
+        let auth = self.auth.clone();
+        let subscriber = self.subscriber.clone();
+        let log = self.log.clone();
+        Promise::from_future(async move {
+            // Some of the events are about the user's session, so there has to be one
+            Self::check_auth(log.clone(), auth, true, "server events").await?;
+            let client = params.get()?.get_subscriber()?;
+            *subscriber.borrow_mut() = Some(client);
+            debug!(log, "Subscribed to server events");
+            Ok(())
+        })
+    }
+
+    fn ping(&mut self,
+        _params: diflouroborane::PingParams,
+        mut results: diflouroborane::PingResults)
+        -> Promise<(), Error>
+    {
+        // Reading the call already counted as the client being alive
+        results.get().set_timestamp(self.status.uptime().as_millis() as u64);
         Promise::ok(())
     }
+
+    fn claim_instance(&mut self,
+        params: diflouroborane::ClaimInstanceParams,
+        _results: diflouroborane::ClaimInstanceResults)
+        -> Promise<(), Error>
+    {
+        pry!(machine::writable(&self.status));
+        let params = pry!(params.get());
+        let token = pry!(params.get_token()).to_string();
+        let name = pry!(params.get_admin_name()).to_string();
+        let password = pry!(params.get_admin_password()).to_string();
+        let setup = self.setup.clone();
+        let auth = self.auth.provider();
+        let perm = self.perm.provider();
+        let mach = self.mach.provider();
+        let timeout = self.mach.lock_timeout();
+        let audit = self.perm.audit().clone();
+        let log = self.log.clone();
+        Promise::from_future(async move {
+            if !setup.accepts(&token, machine::unix_secs(SystemTime::now())) {
+                warn!(log, "Refusing to claim the instance with a wrong or expired setup token");
+                return Err(error::unauthorized("instance", "claim"));
+            }
+            if !setup::valid_name(&name) {
+                return Err(error::invalid_argument(
+                    "Admin name must not be empty nor contain commas or whitespace"));
+            }
+            let violations = auth::policy::check(&setup.rules, &name, &password);
+            if !violations.is_empty() {
+                let violations: Vec<String> = violations.iter()
+                    .map(|v| format!("Password {}", v)).collect();
+                return Err(error::invalid_argument(violations.join(", ")));
+            }
+            // Before it's too late to try again if the machines are busy
+            let objects: Vec<String> = lock::read(&mach, timeout).await?.perms().into_iter()
+                .map(|(_, perm)| perm).collect();
+
+            // Nobody gets another try from here on, whatever happens below. If saving the admin
+            // or their rules fails we take back what was saved, so a restart offers a new token.
+            setup.close();
+            let claimed = async {
+                auth.write().await.add_user(&setup.passdb, &name, &password)?;
+                let rules = setup::admin_rules(&name, &objects);
+                if let Err(e) = setup::append_policy(&setup.policy, &rules) {
+                    // An admin without rights would be no use, and the install couldn't be
+                    // claimed again either
+                    if let Err(e) = auth.write().await.remove_user(&setup.passdb, &name) {
+                        error!(log, "Failed to remove {} again, remove them from {} by hand: {}",
+                            name, setup.passdb.display(), e);
+                    }
+                    return Err(e);
+                }
+                perm.write().await.reload_policy().await?;
+                auth.write().await.reload_policy().await
+            };
+            if let Err(e) = claimed.await {
+                error!(log, "Failed to claim the instance: {}", e);
+                return Err(error::internal());
+            }
+            audit.record(AuditEvent::InstanceClaimed { authzid: &name });
+            info!(log, "Instance claimed, {} is the admin now", name);
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::Duration;
+
+    use futures::executor::LocalPool;
+    use futures::future;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+    use async_std::future::timeout;
+    use async_std::net::TcpStream;
+    use async_std::task;
+
+    use capnp::Error;
+
+    use uuid::Uuid;
+
+    use crate::api::api::machines::Status;
+    use crate::auth;
+    use crate::machine::{api_from_uuid, uuid_from_api};
+    use crate::testing::rpc::{self, Server, PRINTER, SAW};
+
+    #[test]
+    fn use_and_give_back() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let client = server.connect(&spawner).await;
+            client.login_as("alice").await;
+
+            let listed = client.list().await.unwrap();
+            assert_eq!(listed, vec![(PRINTER, Status::Free), (SAW, Status::Free)]);
+
+            let giveback = client.use_machine(PRINTER).await.unwrap();
+            assert_eq!(client.status(PRINTER).await.unwrap(), Status::Occupied);
+            rpc::give_back(&giveback).await.unwrap();
+            assert_eq!(client.status(PRINTER).await.unwrap(), Status::Free);
+        });
+    }
+
+    /// Bumping the version has to be done on purpose, together with a change to the schema
+    #[test]
+    fn api_version() {
+        assert_eq!((super::API_VERSION_MAJOR, super::API_VERSION_MINOR), (1, 38));
+
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let client = server.connect(&spawner).await;
+            let reply = client.bootstrap.get_api_version_request().send().promise.await.unwrap();
+            let version = reply.get().unwrap();
+            assert_eq!((version.get_major(), version.get_minor()), (1, 38));
+        });
+    }
+
+    #[test]
+    fn any_transport() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let client = server.connect_pair(&spawner);
+            client.login_as("alice").await;
+            let giveback = client.use_machine(PRINTER).await.unwrap();
+            rpc::give_back(&giveback).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn wrong_password() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let client = server.connect(&spawner).await;
+            assert!(!client.login("alice", "bob's password").await.unwrap());
+            assert!(!client.login("mallory", "").await.unwrap());
+        });
+    }
+
+    #[test]
+    fn permission_denied() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+
+            let anonymous = server.connect(&spawner).await;
+            let e = anonymous.use_machine(PRINTER).await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unauthenticated");
+
+            // Members may look at the saw but not use it
+            let client = server.connect(&spawner).await;
+            client.login_as("alice").await;
+            let e = client.use_machine(SAW).await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unauthorized");
+            assert_eq!(client.status(SAW).await.unwrap(), Status::Free);
+        });
+    }
+
+    #[test]
+    fn blocked_and_missing_machines() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let admin = server.connect(&spawner).await;
+            admin.login_as("admin").await;
+            let mut req = admin.machines().manage_request();
+            api_from_uuid(PRINTER, req.get().init_uuid());
+            let manage = req.send().pipeline.get_manage();
+            let mut block = manage.set_blocked_request();
+            block.get().set_blocked(true);
+            block.send().promise.await.unwrap();
+
+            let client = server.connect(&spawner).await;
+            client.login_as("alice").await;
+            let e = client.use_machine(PRINTER).await.err().unwrap();
+            assert_eq!(rpc::code(&e), "blocked");
+            let e = client.status(Uuid::from_u128(0xdead)).await.err().unwrap();
+            assert_eq!(rpc::code(&e), "no-such-machine");
+        });
+    }
+
+    #[test]
+    fn bootstrap_open_by_default() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let anonymous = server.connect(&spawner).await;
+            // Nothing to see without being anybody, but asking is fine
+            assert_eq!(anonymous.list().await.unwrap(), vec![]);
+        });
+    }
+
+    #[test]
+    fn bootstrap_requiring_auth() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::with_config(&spawner,
+                |c| c.api.require_auth_for_bootstrap = true).await;
+            let client = server.connect(&spawner).await;
+
+            let e = client.list().await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unauthenticated");
+            let perms = client.bootstrap.permissions_request().send().pipeline.get_perm();
+            let e = perms.get_all_roles_request().send().promise.await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unauthenticated");
+
+            client.login_as("alice").await;
+            assert_eq!(client.list().await.unwrap().len(), 2);
+        });
+    }
+
+    #[test]
+    fn parallel_logins() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let mut clients = Vec::new();
+            for _ in 0..32 {
+                clients.push(server.connect(&spawner).await);
+            }
+
+            // Checking passwords happens on the thread pool, so these don't queue up behind each
+            // other on the connections' thread
+            let logins = clients.iter().enumerate().map(|(i, c)| {
+                let (user, password) = rpc::USERS[i % rpc::USERS.len()];
+                c.login(user, password)
+            });
+            for granted in future::join_all(logins).await {
+                assert!(granted.unwrap());
+            }
+        });
+    }
+
+    #[test]
+    fn concurrent_use() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let alice = server.connect(&spawner).await;
+            alice.login_as("alice").await;
+            let bob = server.connect(&spawner).await;
+            bob.login_as("bob").await;
+
+            // Exactly one of them gets the machine, whoever is first
+            let (a, b) = future::join(alice.use_machine(PRINTER), bob.use_machine(PRINTER)).await;
+            let (giveback, loser, e) = match (a, b) {
+                (Ok(g), Err(e)) => (g, &bob, e),
+                (Err(e), Ok(g)) => (g, &alice, e),
+                (a, b) => panic!("expected one use to fail, got {:?} and {:?}",
+                    a.err(), b.err()),
+            };
+            assert_eq!(rpc::code(&e), "occupied");
+
+            // Until the winner gives it back
+            rpc::give_back(&giveback).await.unwrap();
+            loser.use_machine(PRINTER).await.unwrap();
+        });
+    }
+
+    /// Id, user and machines in use of every connection the server lists, in order of their id
+    async fn connections(client: &rpc::Client) -> Result<Vec<(u64, String, Vec<Uuid>)>, Error> {
+        let reply = client.admin().list_connections_request().send().promise.await?;
+        let mut listed = Vec::new();
+        for c in reply.get()?.get_connections()?.iter() {
+            let machines = c.get_machines()?.iter().map(uuid_from_api).collect();
+            listed.push((c.get_id(), c.get_user()?.to_string(), machines));
+        }
+        listed.sort();
+        Ok(listed)
+    }
+
+    #[test]
+    fn list_and_close_connections() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let admin = server.connect(&spawner).await;
+            admin.login_as("admin").await;
+            let alice = server.connect(&spawner).await;
+            alice.login_as("alice").await;
+            let _giveback = alice.use_machine(PRINTER).await.unwrap();
+            let anonymous = server.connect(&spawner).await;
+            // Makes sure the server got around to accepting it
+            anonymous.list().await.unwrap();
+
+            assert_eq!(connections(&admin).await.unwrap(), vec![
+                (1, "admin".to_string(), vec![]),
+                (2, "alice".to_string(), vec![PRINTER]),
+                (3, String::new(), vec![]),
+            ]);
+
+            let mut req = admin.admin().disconnect_request();
+            req.get().set_id(3);
+            req.send().promise.await.unwrap();
+            assert!(anonymous.list().await.is_err());
+            assert_eq!(connections(&admin).await.unwrap().len(), 2);
+
+            let mut req = admin.admin().disconnect_request();
+            req.get().set_id(42);
+            let e = req.send().promise.await.err().unwrap();
+            assert_eq!(rpc::code(&e), "no-such-connection");
+
+            // Only admins may look
+            let e = connections(&alice).await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unauthorized");
+        });
+    }
+
+    #[test]
+    fn oversized_messages_drop_the_connection() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::with_config(&spawner, |c| c.api.max_message_bytes = 4096).await;
+            let client = server.connect(&spawner).await;
+            let password = "x".repeat(64 * 1024);
+            assert!(client.login("alice", &password).await.is_err());
+            assert!(client.list().await.is_err());
+
+            // Everybody else is fine
+            let other = server.connect(&spawner).await;
+            other.login_as("alice").await;
+        });
+    }
+
+    #[test]
+    fn huge_segments_are_not_waited_for() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let mut stream = TcpStream::connect(server.addr).await.unwrap();
+            // The segment table of a message with one segment of 1 GiB, which never follows. The
+            // server has to hang up on reading the table instead of setting aside memory for it.
+            let mut table = Vec::new();
+            table.extend_from_slice(&0u32.to_le_bytes());
+            table.extend_from_slice(&(1u32 << 27).to_le_bytes());
+            stream.write_all(&table).await.unwrap();
+
+            // It may say why first, or simply reset the connection
+            let mut rest = Vec::new();
+            let read = timeout(Duration::from_secs(5), stream.read_to_end(&mut rest)).await;
+            assert!(read.is_ok(), "the server kept the connection open");
+        });
+    }
+
+    #[test]
+    fn occupants_only_shown_to_managers() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let alice = server.connect(&spawner).await;
+            alice.login_as("alice").await;
+            let _giveback = alice.use_machine(PRINTER).await.unwrap();
+
+            let bob = server.connect(&spawner).await;
+            bob.login_as("bob").await;
+            let e = bob.use_machine(PRINTER).await.err().unwrap();
+            assert_eq!(rpc::code(&e), "occupied");
+            assert!(!e.description.contains("alice"), "{}", e.description);
+
+            let admin = server.connect(&spawner).await;
+            admin.login_as("admin").await;
+            let e = admin.use_machine(PRINTER).await.err().unwrap();
+            assert_eq!(rpc::code(&e), "occupied");
+            assert!(e.description.contains("\"occupant\":\"alice\""), "{}", e.description);
+        });
+    }
+
+    #[test]
+    fn responsive_under_a_flood_of_connections() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::with_config(&spawner, |c| c.api.max_connections = Some(4)).await;
+            let admin = server.connect(&spawner).await;
+            admin.login_as("admin").await;
+
+            // Way more than can be turned away, most of them have to wait in the backlog
+            let mut flood = Vec::new();
+            for _ in 0..100 {
+                flood.push(TcpStream::connect(server.addr).await.unwrap());
+            }
+            for _ in 0..5 {
+                let listed = timeout(Duration::from_secs(2), admin.list()).await
+                    .expect("the server stopped answering");
+                assert_eq!(listed.unwrap().len(), 2);
+            }
+            assert!(server.connections.open() <= 4);
+
+            // Once the flood is over the server catches up on its backlog, and has room again
+            drop(flood);
+            for _ in 0..100 {
+                if server.connections.open() == 1 {
+                    break;
+                }
+                task::sleep(Duration::from_millis(50)).await;
+            }
+            assert_eq!(server.connections.open(), 1);
+            let mut clients = Vec::new();
+            for _ in 0..3 {
+                let client = server.connect(&spawner).await;
+                client.login_as("alice").await;
+                clients.push(client);
+            }
+        });
+    }
+
+    #[test]
+    fn overloaded_clients_are_told_when_to_retry() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::with_config(&spawner, |c| c.api.max_connections = Some(1)).await;
+            let first = server.connect(&spawner).await;
+            first.list().await.unwrap();
+
+            let second = server.connect(&spawner).await;
+            let e = second.list().await.err().unwrap();
+            assert_eq!(rpc::code(&e), "overloaded");
+            assert!(e.description.contains("(retry-after: 1-2)"), "{}", e.description);
+            // Whoever got in first isn't bothered by it
+            first.list().await.unwrap();
+        });
+    }
+
+    /// The machines `client` may manage and who's using them
+    async fn manageable(client: &rpc::Client) -> Result<Vec<(Uuid, String)>, Error> {
+        let reply = client.machines().list_manageable_request().send().promise.await?;
+        let mut listed = Vec::new();
+        for m in reply.get()?.get_machines()?.iter() {
+            listed.push((uuid_from_api(m.get_info()?.get_uuid()?), m.get_occupant()?.to_string()));
+        }
+        listed.sort();
+        Ok(listed)
+    }
+
+    #[test]
+    fn list_manageable() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let alice = server.connect(&spawner).await;
+            alice.login_as("alice").await;
+            let _giveback = alice.use_machine(PRINTER).await.unwrap();
+            assert_eq!(manageable(&alice).await.unwrap(), vec![]);
+
+            let admin = server.connect(&spawner).await;
+            admin.login_as("admin").await;
+            assert_eq!(manageable(&admin).await.unwrap(), vec![
+                (PRINTER, "alice".to_string()),
+                (SAW, String::new()),
+            ]);
+
+            let anonymous = server.connect(&spawner).await;
+            let e = manageable(&anonymous).await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unauthenticated");
+        });
+    }
+
+    const PASSWORD: &str = "Tr0ubadour & horse";
+
+    async fn claim(client: &rpc::Client, token: &str, name: &str) -> Result<(), Error> {
+        let mut req = client.bootstrap.claim_instance_request();
+        req.get().set_token(token);
+        req.get().set_admin_name(name);
+        req.get().set_admin_password(PASSWORD);
+        req.send().promise.await?;
+        Ok(())
+    }
+
+    #[test]
+    fn claiming_an_instance() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let token = server.setup.offer(u64::MAX).unwrap();
+            assert_eq!(fs::read_to_string(&*server.setup.token_file).unwrap(),
+                format!("{}\n", token));
+
+            let client = server.connect(&spawner).await;
+            let e = claim(&client, "wrong", "carol").await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unauthorized");
+            // Neither uses the token up
+            let e = claim(&client, &token, "car ol").await.err().unwrap();
+            assert_eq!(rpc::code(&e), "invalid-argument");
+
+            claim(&client, &token, "carol").await.unwrap();
+            assert!(!server.setup.token_file.exists());
+            let carol = server.connect(&spawner).await;
+            assert!(carol.login("carol", PASSWORD).await.unwrap());
+            assert_eq!(manageable(&carol).await.unwrap(), vec![
+                (PRINTER, String::new()),
+                (SAW, String::new()),
+            ]);
+
+            let e = claim(&client, &token, "dave").await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unauthorized");
+        });
+    }
+
+    #[test]
+    fn failed_claims_leave_no_admin_behind() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let token = server.setup.offer(u64::MAX).unwrap();
+            // Nothing can be added to the policy anymore
+            let policy = &server.config.access.policy;
+            fs::remove_file(policy).unwrap();
+            fs::create_dir(policy).unwrap();
+
+            let client = server.connect(&spawner).await;
+            let e = claim(&client, &token, "carol").await.err().unwrap();
+            assert_eq!(rpc::code(&e), "internal");
+            assert!(!auth::open_passdb(&server.config.passdb).unwrap().contains_key("carol"));
+            assert!(!client.login("carol", PASSWORD).await.unwrap());
+
+            // The token is used up all the same
+            let e = claim(&client, &token, "carol").await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unauthorized");
+        });
+    }
+
+    /// Whether `client` is told TLS is required and anonymous use allowed, and its mechanisms
+    async fn capabilities(client: &rpc::Client) -> Result<(bool, bool, Vec<String>), Error> {
+        let reply = client.bootstrap.get_capabilities_request().send().promise.await?;
+        let caps = reply.get()?.get_capabilities()?;
+        assert_eq!((caps.get_api_major(), caps.get_api_minor()),
+            (super::API_VERSION_MAJOR, super::API_VERSION_MINOR));
+        let mut mechanisms = Vec::new();
+        for m in caps.get_mechanisms()?.iter() {
+            mechanisms.push(m?.to_string());
+        }
+        Ok((caps.get_tls_required(), caps.get_anonymous_allowed(), mechanisms))
+    }
+
+    #[test]
+    fn capabilities_by_default() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let client = server.connect(&spawner).await;
+            let (tls, anonymous, mechanisms) = capabilities(&client).await.unwrap();
+            assert!(!tls);
+            assert!(anonymous);
+            assert!(mechanisms.iter().any(|m| m == "PLAIN"), "{:?}", mechanisms);
+        });
+    }
+
+    #[test]
+    fn passwords_only_on_encrypted_connections() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::with_config(&spawner, |c| {
+                c.api.require_tls = true;
+                c.api.require_auth_for_bootstrap = true;
+            }).await;
+
+            let tcp = server.connect(&spawner).await;
+            let (tls, anonymous, mechanisms) = capabilities(&tcp).await.unwrap();
+            assert!(tls);
+            assert!(!anonymous);
+            assert!(!mechanisms.iter().any(|m| m == "PLAIN"), "{:?}", mechanisms);
+            // Not even trying gets anywhere
+            let e = tcp.login("alice", "alice's password").await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unimplemented");
+
+            // Local connections count as encrypted, whatever is in front of them
+            let local = server.connect_pair(&spawner);
+            let (tls, _, mechanisms) = capabilities(&local).await.unwrap();
+            assert!(tls);
+            assert!(mechanisms.iter().any(|m| m == "PLAIN"), "{:?}", mechanisms);
+            local.login_as("alice").await;
+        });
+    }
 }
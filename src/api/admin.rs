@@ -0,0 +1,413 @@
+//! Administration of the server itself
+
+use std::time::{Duration, SystemTime};
+
+use capnp::Error;
+use capnp::capability::Promise;
+
+use slog::Logger;
+
+use async_std::sync::{Arc, RwLock};
+
+use crate::access::PermissionsProvider;
+use crate::access::reconcile::Report;
+use crate::audit::{Audit, AuditEvent};
+use crate::auth::AuthenticationProvider;
+use crate::connection::{ClientInfo, Sessions};
+use crate::machine::{api_from_uuid, lock, unix_secs, MachinesProvider};
+use crate::machine::watch::{Channels, Notice, What};
+use crate::stats::{self, Stats};
+use crate::webhook::Deliveries;
+
+use super::api::admin;
+use super::error;
+
+/// Handed out to connections with the `admin` action on `server`
+pub struct Admin {
+    log: Logger,
+    sessions: Sessions,
+    /// Webhook deliveries, if there are any hooks
+    deliveries: Option<Deliveries>,
+    machines: Arc<RwLock<MachinesProvider>>,
+    /// How long calls wait for the lock on `machines`
+    lock_timeout: Duration,
+    permissions: Arc<RwLock<PermissionsProvider>>,
+    auth: Arc<RwLock<AuthenticationProvider>>,
+    channels: Channels,
+    /// If statistics are kept
+    stats: Option<Stats>,
+    audit: Audit,
+    /// Who is administrating
+    user: String,
+}
+
+impl Admin {
+    pub fn new(log: Logger, sessions: Sessions, deliveries: Option<Deliveries>,
+        machines: Arc<RwLock<MachinesProvider>>, lock_timeout: Duration,
+        permissions: Arc<RwLock<PermissionsProvider>>, auth: Arc<RwLock<AuthenticationProvider>>,
+        channels: Channels, stats: Option<Stats>, audit: Audit, user: String) -> Self
+    {
+        Self {
+            log, sessions, deliveries, machines, lock_timeout, permissions, auth, channels, stats,
+            audit, user,
+        }
+    }
+}
+
+impl admin::Server for Admin {
+    fn list_connections(&mut self,
+        _params: admin::ListConnectionsParams,
+        mut results: admin::ListConnectionsResults)
+        -> Promise<(), Error>
+    {
+        let sessions = self.sessions.list();
+        let mut b = results.get().init_connections(sessions.len() as u32);
+        for (i, s) in sessions.into_iter().enumerate() {
+            let mut c = b.reborrow().get(i as u32);
+            c.set_id(s.id);
+            c.set_peer(&s.peer);
+            c.set_user(s.authzid.as_deref().unwrap_or(""));
+            c.set_connected_at(s.since);
+            c.set_idle_for(s.idle_for.as_secs());
+            c.set_silent_for(s.silent_for.as_secs());
+            match s.client {
+                Some(ref client) => {
+                    c.set_client_name(client.name());
+                    c.set_client_version(&client.version);
+                    c.set_platform(&client.platform);
+                },
+                None => c.set_client_name(ClientInfo::UNKNOWN),
+            }
+            let mut machines = c.init_machines(s.machines.len() as u32);
+            for (j, uuid) in s.machines.into_iter().enumerate() {
+                api_from_uuid(uuid, machines.reborrow().get(j as u32));
+            }
+        }
+        Promise::ok(())
+    }
+
+    fn disconnect(&mut self,
+        params: admin::DisconnectParams,
+        _results: admin::DisconnectResults)
+        -> Promise<(), Error>
+    {
+        let id = pry!(params.get()).get_id();
+        if !self.sessions.disconnect(id) {
+            return Promise::err(error::no_such_connection());
+        }
+        info!(self.log, "Closing connection {} on behalf of {}", id, self.user);
+        Promise::ok(())
+    }
+
+    fn deliveries(&mut self,
+        params: admin::DeliveriesParams,
+        mut results: admin::DeliveriesResults)
+        -> Promise<(), Error>
+    {
+        let failed_only = pry!(params.get()).get_failed_only();
+        let deliveries: Vec<_> = self.deliveries.as_ref().map(|d| d.list()).unwrap_or_default()
+            .into_iter()
+            .filter(|d| d.failed || !failed_only)
+            .collect();
+
+        let mut b = results.get().init_deliveries(deliveries.len() as u32);
+        for (i, d) in deliveries.iter().enumerate() {
+            let mut e = b.reborrow().get(i as u32);
+            e.set_id(d.id);
+            e.set_url(&d.url);
+            e.set_event(&d.event);
+            api_from_uuid(d.machine, e.reborrow().init_machine());
+            e.set_user(&d.user);
+            e.set_at(d.at);
+            e.set_attempts(d.attempts);
+            e.set_next_attempt(d.next_attempt);
+            e.set_last_error(d.last_error.as_deref().unwrap_or(""));
+            e.set_failed(d.failed);
+        }
+        Promise::ok(())
+    }
+
+    fn policy_report(&mut self,
+        _params: admin::PolicyReportParams,
+        mut results: admin::PolicyReportResults)
+        -> Promise<(), Error>
+    {
+        let machines = self.machines.clone();
+        let timeout = self.lock_timeout;
+        let permissions = self.permissions.clone();
+        Promise::from_future(async move {
+            // Taken now instead of at startup since both may have changed since
+            let perms = lock::read(&machines, timeout).await?.perms();
+            let policy = permissions.read().await.policy();
+            let report = Report::new(&perms, &policy);
+
+            let mut b = results.get();
+            let mut unusable = b.reborrow().init_unusable(report.unusable.len() as u32);
+            for (i, (uuid, perm)) in report.unusable.iter().enumerate() {
+                let mut u = unusable.reborrow().get(i as u32);
+                api_from_uuid(*uuid, u.reborrow().init_machine());
+                u.set_perm(perm);
+            }
+            let mut stale = b.init_stale(report.stale.len() as u32);
+            for (i, object) in report.stale.iter().enumerate() {
+                stale.set(i as u32, object);
+            }
+            Ok(())
+        })
+    }
+
+    fn test_notification(&mut self,
+        params: admin::TestNotificationParams,
+        _results: admin::TestNotificationResults)
+        -> Promise<(), Error>
+    {
+        let params = pry!(params.get());
+        let name = pry!(params.get_channel()).to_string();
+        let user = pry!(params.get_user()).to_string();
+        let channel = match self.channels.get(&name) {
+            Some(c) => c,
+            None => return Promise::err(error::invalid_argument(
+                format!("there is no notification channel {}", name))),
+        };
+        let contact = match self.channels.contact(&user) {
+            Ok(c) => c,
+            Err(e) => {
+                error!(self.log, "Could not read the contact details of {}: {}", user, e);
+                return Promise::err(error::internal());
+            },
+        };
+        let log = self.log.clone();
+        let admin = self.user.clone();
+        Promise::from_future(async move {
+            let notice = Notice { user: &user, contact: &contact, what: What::Test };
+            match channel.send(&notice).await {
+                Ok(()) => {
+                    info!(log, "Sent {} a test notification on behalf of {}", user, admin;
+                        "channel" => name);
+                    Ok(())
+                },
+                Err(why) => Err(error::unavailable(why)),
+            }
+        })
+    }
+
+    fn get_statistics(&mut self,
+        params: admin::GetStatisticsParams,
+        mut results: admin::GetStatisticsResults)
+        -> Promise<(), Error>
+    {
+        let stats = match self.stats {
+            Some(ref s) => s,
+            None => return Promise::err(error::unavailable("statistics aren't kept")),
+        };
+        let params = pry!(params.get());
+        let from = pry!(stats::parse_day(pry!(params.get_from())).map_err(error::invalid_argument));
+        let to = pry!(stats::parse_day(pry!(params.get_to())).map_err(error::invalid_argument));
+        let totals = stats.totals(from.as_ref(), to.as_ref());
+
+        let mut b = results.get().init_statistics();
+        b.set_first(totals.first.as_deref().unwrap_or(""));
+        b.set_last(totals.last.as_deref().unwrap_or(""));
+        b.set_authentications(totals.authentications);
+        b.set_failed_authentications(totals.failed_authentications);
+        b.set_unique_users(totals.unique_users as u32);
+        let mut machines = b.init_machines(totals.machines.len() as u32);
+        for (i, (uuid, counts)) in totals.machines.iter().enumerate() {
+            let mut m = machines.reborrow().get(i as u32);
+            api_from_uuid(*uuid, m.reborrow().init_machine());
+            m.set_sessions(counts.sessions);
+            m.set_occupied_seconds(counts.occupied_secs);
+        }
+        Promise::ok(())
+    }
+
+    fn set_read_only(&mut self,
+        params: admin::SetReadOnlyParams,
+        mut results: admin::SetReadOnlyResults)
+        -> Promise<(), Error>
+    {
+        let on = pry!(params.get()).get_read_only();
+        let machines = self.machines.clone();
+        let timeout = self.lock_timeout;
+        let log = self.log.clone();
+        let audit = self.audit.clone();
+        let user = self.user.clone();
+        Promise::from_future(async move {
+            let changed = lock::write(&machines, timeout).await?.set_read_only(on);
+            if changed {
+                warn!(log, "{} read-only mode on behalf of {}", if on { "Entered" } else { "Left" },
+                    user);
+                audit.record(AuditEvent::ReadOnly { by: &user, read_only: on });
+            }
+            results.get().set_changed(changed);
+            Ok(())
+        })
+    }
+
+    fn create_password_reset(&mut self,
+        params: admin::CreatePasswordResetParams,
+        mut results: admin::CreatePasswordResetResults)
+        -> Promise<(), Error>
+    {
+        let user = pry!(pry!(params.get()).get_user()).to_string();
+        let machines = self.machines.clone();
+        let timeout = self.lock_timeout;
+        let auth = self.auth.clone();
+        let log = self.log.clone();
+        let audit = self.audit.clone();
+        let by = self.user.clone();
+        Promise::from_future(async move {
+            if lock::read(&machines, timeout).await?.read_only() {
+                return Err(error::read_only());
+            }
+            let now = unix_secs(SystemTime::now());
+            let mut auth = auth.write().await;
+            if !auth.resets.enabled() {
+                return Err(error::unimplemented("Password resets are not enabled"));
+            }
+            if !auth.has_user(&user) {
+                return Err(error::invalid_argument(format!("No user {}", user)));
+            }
+            let expires = now.saturating_add(auth.reset_expiry);
+            let token = auth.resets.create(&user, &by, now, expires).map_err(|e| {
+                error!(log, "Failed to save password reset tokens: {}", e);
+                error::internal()
+            })?;
+            drop(auth);
+
+            info!(log, "Issued a password reset token for {} on behalf of {}", user, by);
+            audit.record(AuditEvent::PasswordResetIssued { by: &by, user: &user, expires });
+            let mut b = results.get();
+            b.set_token(&token);
+            b.set_expires(expires);
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use futures::executor::LocalPool;
+
+    use capnp::Error;
+
+    use crate::api::api::machines::Status;
+    use crate::audit::Event;
+    use crate::machine::unix_secs;
+    use crate::testing::rpc::{self, Server, PRINTER};
+
+    async fn set_read_only(client: &rpc::Client, on: bool) -> Result<bool, Error> {
+        let mut req = client.admin().set_read_only_request();
+        req.get().set_read_only(on);
+        Ok(req.send().promise.await?.get()?.get_changed())
+    }
+
+    async fn read_only(client: &rpc::Client) -> bool {
+        let reply = client.bootstrap.get_server_info_request().send().promise.await.unwrap();
+        reply.get().unwrap().get_info().unwrap().get_read_only()
+    }
+
+    #[test]
+    fn read_only_mode() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let admin = server.connect(&spawner).await;
+            admin.login_as("admin").await;
+            let alice = server.connect(&spawner).await;
+            alice.login_as("alice").await;
+            let giveback = alice.use_machine(PRINTER).await.unwrap();
+
+            assert!(set_read_only(&admin, true).await.unwrap());
+            assert!(!set_read_only(&admin, true).await.unwrap());
+            assert!(read_only(&alice).await);
+            let e = set_read_only(&alice, false).await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unauthorized");
+
+            // Looking is fine, changing anything isn't
+            assert_eq!(alice.status(PRINTER).await.unwrap(), Status::Occupied);
+            let e = rpc::give_back(&giveback).await.err().unwrap();
+            assert_eq!(rpc::code(&e), "read-only");
+
+            assert!(set_read_only(&admin, false).await.unwrap());
+            assert!(!read_only(&alice).await);
+            rpc::give_back(&giveback).await.unwrap();
+        });
+    }
+
+    async fn create_reset(client: &rpc::Client, user: &str) -> Result<String, Error> {
+        let mut req = client.admin().create_password_reset_request();
+        req.get().set_user(user);
+        Ok(req.send().promise.await?.get()?.get_token()?.to_string())
+    }
+
+    async fn redeem(client: &rpc::Client, token: &str, password: &str) -> Result<String, Error> {
+        let auth = client.bootstrap.authentication_request().send().pipeline.get_auth();
+        let mut req = auth.redeem_password_reset_request();
+        req.get().set_token(token);
+        req.get().set_new_password(password);
+        Ok(req.send().promise.await?.get()?.get_user()?.to_string())
+    }
+
+    #[test]
+    fn password_resets() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::with_config(&spawner, |c| {
+                c.auth.resets = Some(c.machinedb.with_file_name("resets.toml"));
+            }).await;
+            let mut audit = server.audit.subscribe();
+            let admin = server.connect(&spawner).await;
+            admin.login_as("admin").await;
+            let alice = server.connect(&spawner).await;
+            alice.login_as("alice").await;
+            let e = create_reset(&alice, "bob").await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unauthorized");
+
+            // One that ran out before the others were issued
+            let now = unix_secs(SystemTime::now());
+            let expired = server.api.authentication().write().await.resets
+                .create("alice", "admin", now - 7200, now - 3600).unwrap();
+            let first = create_reset(&admin, "alice").await.unwrap();
+            let second = create_reset(&admin, "alice").await.unwrap();
+
+            // Nobody has to be logged in, but the password rules still hold
+            let anonymous = server.connect(&spawner).await;
+            let e = redeem(&anonymous, &first, "short").await.err().unwrap();
+            assert_eq!(rpc::code(&e), "invalid-argument");
+            assert_eq!(redeem(&anonymous, &first, "Violet-Lathe-42").await.unwrap(), "alice");
+
+            let e = redeem(&anonymous, &first, "Violet-Lathe-43").await.err().unwrap();
+            assert_eq!(rpc::code(&e), "token-used");
+            let e = redeem(&anonymous, &second, "Violet-Lathe-43").await.err().unwrap();
+            assert_eq!(rpc::code(&e), "token-used");
+            let e = redeem(&anonymous, &expired, "Violet-Lathe-43").await.err().unwrap();
+            assert_eq!(rpc::code(&e), "token-expired");
+            let e = redeem(&anonymous, "nope", "Violet-Lathe-43").await.err().unwrap();
+            assert_eq!(rpc::code(&e), "no-such-token");
+
+            let again = server.connect(&spawner).await;
+            assert!(again.login("alice", "Violet-Lathe-42").await.unwrap());
+
+            let mut recorded = Vec::new();
+            while let Ok(Some(r)) = audit.try_next() {
+                match r.event {
+                    Event::PasswordResetIssued { by, user, .. } =>
+                        recorded.push(format!("{} issued for {}", by, user)),
+                    Event::PasswordReset { user, by } =>
+                        recorded.push(format!("{} reset with {}'s token", user, by)),
+                    _ => {},
+                }
+            }
+            assert_eq!(recorded, vec![
+                "admin issued for alice",
+                "admin issued for alice",
+                "alice reset with admin's token",
+            ]);
+        });
+    }
+}
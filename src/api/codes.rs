@@ -0,0 +1,186 @@
+//! Every code an API error can carry and the parameters that go along with it
+//!
+//! Clients are meant to build their own messages from these, in whatever language their users
+//! speak, instead of showing or parsing our English descriptions. Errors can only be made from a
+//! `Code`, so there's no way to send one that isn't listed here.
+
+use std::fmt;
+
+use capnp::ErrorKind;
+
+/// Why a call failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Code {
+    /// The machine is in use by somebody else
+    Occupied,
+    /// The machine was blocked by an admin
+    Blocked,
+    /// The machine is held for the next user in its queue
+    Reserved,
+    /// The machine may not be used at this time of day
+    Closed,
+    NoSuchMachine,
+    NoSuchConnection,
+    NoSuchBlock,
+    NoSuchGrant,
+    /// The password reset token is not one we issued, or so long ago we forgot about it
+    NoSuchToken,
+    /// The password reset token was valid once but expired
+    TokenExpired,
+    /// The password reset token was redeemed already, it or another one for the same user
+    TokenUsed,
+    /// What was asked for clashes with something that's already there
+    Conflict,
+    /// An argument of the call makes no sense, e.g. a time window ending before it starts
+    InvalidArgument,
+    /// The call requires the connection to authenticate first
+    Unauthenticated,
+    /// The authenticated user lacks the permission for the call
+    Unauthorized,
+    /// The server can't handle the call right now, try again later
+    Overloaded,
+    /// The client made too many calls recently, slow down
+    Throttled,
+    /// A part of the server needed for the call doesn't work right now, try again later
+    Unavailable,
+    /// The server doesn't support what was asked for
+    Unimplemented,
+    /// The server is in read-only mode for maintenance, nothing can be changed until it's over
+    ReadOnly,
+    /// Something went wrong on our side
+    Internal,
+}
+
+impl Code {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Code::Occupied => "occupied",
+            Code::Blocked => "blocked",
+            Code::Reserved => "reserved",
+            Code::Closed => "closed",
+            Code::NoSuchMachine => "no-such-machine",
+            Code::NoSuchConnection => "no-such-connection",
+            Code::NoSuchBlock => "no-such-block",
+            Code::NoSuchGrant => "no-such-grant",
+            Code::NoSuchToken => "no-such-token",
+            Code::TokenExpired => "token-expired",
+            Code::TokenUsed => "token-used",
+            Code::Conflict => "conflict",
+            Code::InvalidArgument => "invalid-argument",
+            Code::Unauthenticated => "unauthenticated",
+            Code::Unauthorized => "unauthorized",
+            Code::Overloaded => "overloaded",
+            Code::Throttled => "throttled",
+            Code::Unavailable => "unavailable",
+            Code::Unimplemented => "unimplemented",
+            Code::ReadOnly => "read-only",
+            Code::Internal => "internal",
+        }
+    }
+
+    pub fn kind(self) -> ErrorKind {
+        match self {
+            Code::Overloaded | Code::Throttled | Code::Unavailable => ErrorKind::Overloaded,
+            Code::Unimplemented => ErrorKind::Unimplemented,
+            _ => ErrorKind::Failed,
+        }
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What an error may tell about itself besides its code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Param {
+    /// UUID of the machine the call was about
+    Machine,
+    /// Permission object and action that were missing
+    Object,
+    Action,
+    /// Who is using the machine, only if the caller may know
+    Occupant,
+    /// UNIX seconds, when the machine was taken into use
+    Since,
+    /// Why the machine was blocked, as given by whoever blocked it
+    Reason,
+    /// UNIX seconds, when a block or reservation ends
+    Until,
+    /// UNIX seconds, when the machine may be used next
+    Opens,
+    /// Seconds to wait before retrying, pick a random number between the two
+    RetryAfterMin,
+    RetryAfterMax,
+}
+
+impl Param {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Param::Machine => "machine",
+            Param::Object => "object",
+            Param::Action => "action",
+            Param::Occupant => "occupant",
+            Param::Since => "since",
+            Param::Reason => "reason",
+            Param::Until => "until",
+            Param::Opens => "opens",
+            Param::RetryAfterMin => "retry-after-min",
+            Param::RetryAfterMax => "retry-after-max",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    const CODES: &[Code] = &[
+        Code::Occupied, Code::Blocked, Code::Reserved, Code::Closed, Code::NoSuchMachine,
+        Code::NoSuchConnection, Code::NoSuchBlock, Code::NoSuchGrant, Code::NoSuchToken,
+        Code::TokenExpired, Code::TokenUsed, Code::Conflict, Code::InvalidArgument,
+        Code::Unauthenticated, Code::Unauthorized, Code::Overloaded, Code::Throttled,
+        Code::Unavailable, Code::Unimplemented, Code::ReadOnly, Code::Internal,
+    ];
+
+    const PARAMS: &[Param] = &[
+        Param::Machine, Param::Object, Param::Action, Param::Occupant, Param::Since, Param::Reason,
+        Param::Until, Param::Opens, Param::RetryAfterMin, Param::RetryAfterMax,
+    ];
+
+    /// Clients only know about what's in the schema
+    #[test]
+    fn documented() {
+        let schema = include_str!("../../schema/api.capnp");
+        for code in CODES {
+            assert!(schema.contains(&format!("`{}`", code)), "code {} is not in the schema", code);
+        }
+        for param in PARAMS {
+            assert!(schema.contains(&format!("`{}`", param.as_str())),
+                "parameter {} is not in the schema", param.as_str());
+        }
+    }
+
+    #[test]
+    fn distinct() {
+        let codes: HashSet<&str> = CODES.iter().map(|c| c.as_str()).collect();
+        assert_eq!(codes.len(), CODES.len());
+        let params: HashSet<&str> = PARAMS.iter().map(|p| p.as_str()).collect();
+        assert_eq!(params.len(), PARAMS.len());
+        // They're matched on, nothing that could be taken for the end of the code
+        assert!(codes.iter().all(|c| c.chars().all(|c| c.is_ascii_lowercase() || c == '-')));
+    }
+
+    #[test]
+    fn retryable() {
+        for code in CODES {
+            let retry = matches!(code, Code::Overloaded | Code::Throttled | Code::Unavailable);
+            assert_eq!(code.kind() == ErrorKind::Overloaded, retry, "{}", code);
+        }
+        assert_eq!(Code::Unimplemented.kind(), ErrorKind::Unimplemented);
+    }
+}
@@ -0,0 +1,228 @@
+//! Errors sent to clients
+//!
+//! Every error has a code clients can match on, see `codes`. It's the part of the description in
+//! front of the first colon, e.g. `occupied: Machine is occupied`; the rest of the first line is
+//! meant for humans. The capnp error kind is set to match as well, so `overloaded` errors can be
+//! retried and so on.
+//!
+//! Errors with parameters have them as a JSON object after the last line break, e.g.
+//! `occupied: Machine is occupied by alice\n{"occupant":"alice","since":1600000000}`. Errors
+//! telling a client to come back later also end the human part in a hint how long to wait, e.g.
+//! `throttled: Too many calls, slow down (retry-after: 2-4)`, for clients from before parameters.
+
+use std::fmt;
+
+use capnp::Error;
+
+use serde_json::{Map, Value};
+
+use uuid::Uuid;
+
+pub use super::codes::{Code, Param};
+
+/// Longest time in seconds we tell a client to wait before retrying
+const MAX_RETRY_AFTER: u64 = 60;
+
+/// How long a client should wait before retrying, in seconds
+///
+/// Clients are meant to pick a random time between `min` and `max`. If all of them came back after
+/// the same time they'd overload us again all at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryAfter {
+    pub min: u64,
+    pub max: u64,
+}
+
+impl RetryAfter {
+    /// Hint for being `depth` steps past what we can handle, where a step is whatever the caller
+    /// measures load in. Every step doubles the wait, up to `MAX_RETRY_AFTER`.
+    pub fn for_depth(depth: u32) -> Self {
+        let min = 1u64.checked_shl(depth).unwrap_or(u64::MAX).min(MAX_RETRY_AFTER);
+        // The window grows with the wait, the more clients there are the more they need spreading
+        Self { min, max: min * 2 }
+    }
+
+    /// Wait at least `secs`, e.g. because nothing can succeed before then anyway
+    pub fn at_least(self, secs: u64) -> Self {
+        let secs = secs.min(MAX_RETRY_AFTER);
+        if secs <= self.min {
+            self
+        } else {
+            Self { min: secs, max: secs + (self.max - self.min) }
+        }
+    }
+}
+
+impl fmt::Display for RetryAfter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "retry-after: {}-{}", self.min, self.max)
+    }
+}
+
+/// An error on its way to a client, with parameters to build a message from
+pub struct ApiError {
+    code: Code,
+    description: String,
+    params: Map<String, Value>,
+}
+
+impl ApiError {
+    pub fn new<D: fmt::Display>(code: Code, description: D) -> Self {
+        Self { code, description: description.to_string(), params: Map::new() }
+    }
+
+    pub fn with<V: Into<Value>>(mut self, param: Param, value: V) -> Self {
+        self.params.insert(param.as_str().to_string(), value.into());
+        self
+    }
+
+    fn retry(self, retry: RetryAfter) -> Self {
+        self.with(Param::RetryAfterMin, retry.min).with(Param::RetryAfterMax, retry.max)
+    }
+}
+
+impl From<ApiError> for Error {
+    fn from(e: ApiError) -> Self {
+        let mut description = format!("{}: {}", e.code, e.description);
+        if !e.params.is_empty() {
+            description.push('\n');
+            description.push_str(&Value::Object(e.params).to_string());
+        }
+        Error { kind: e.code.kind(), description }
+    }
+}
+
+/// An error with `code` and a description for humans
+pub fn error<D: fmt::Display>(code: Code, description: D) -> Error {
+    ApiError::new(code, description).into()
+}
+
+pub fn occupied() -> Error {
+    error(Code::Occupied, "Machine is occupied")
+}
+
+pub fn blocked() -> Error {
+    error(Code::Blocked, "Machine is blocked")
+}
+
+pub fn reserved() -> Error {
+    error(Code::Reserved, "Machine is reserved for the next user in the queue")
+}
+
+pub fn no_such_machine(uuid: &Uuid) -> Error {
+    ApiError::new(Code::NoSuchMachine, "No such machine")
+        .with(Param::Machine, uuid.to_hyphenated().to_string())
+        .into()
+}
+
+pub fn no_such_connection() -> Error {
+    error(Code::NoSuchConnection, "No such connection")
+}
+
+pub fn no_such_block() -> Error {
+    error(Code::NoSuchBlock, "No block scheduled at that time")
+}
+
+pub fn no_such_grant() -> Error {
+    error(Code::NoSuchGrant, "No such temporary grant")
+}
+
+pub fn no_such_token() -> Error {
+    error(Code::NoSuchToken, "No such password reset token")
+}
+
+pub fn token_expired() -> Error {
+    error(Code::TokenExpired, "Password reset token expired")
+}
+
+pub fn token_used() -> Error {
+    error(Code::TokenUsed, "Password reset token was used already")
+}
+
+pub fn conflict<D: fmt::Display>(description: D) -> Error {
+    error(Code::Conflict, description)
+}
+
+pub fn invalid_argument<D: fmt::Display>(description: D) -> Error {
+    error(Code::InvalidArgument, description)
+}
+
+pub fn unauthenticated() -> Error {
+    error(Code::Unauthenticated, "Authentication required")
+}
+
+/// Lacking `action` on `object`
+pub fn unauthorized(object: &str, action: &str) -> Error {
+    ApiError::new(Code::Unauthorized, "Permission denied")
+        .with(Param::Object, object)
+        .with(Param::Action, action)
+        .into()
+}
+
+pub fn overloaded<D: fmt::Display>(description: D) -> Error {
+    error(Code::Overloaded, description)
+}
+
+/// Like `overloaded`, telling the client how long to wait
+pub fn overloaded_retry<D: fmt::Display>(description: D, retry: RetryAfter) -> Error {
+    ApiError::new(Code::Overloaded, format_args!("{} ({})", description, retry)).retry(retry).into()
+}
+
+pub fn throttled(retry: RetryAfter) -> Error {
+    ApiError::new(Code::Throttled, format_args!("Too many calls, slow down ({})", retry))
+        .retry(retry)
+        .into()
+}
+
+pub fn unavailable<D: fmt::Display>(description: D) -> Error {
+    error(Code::Unavailable, description)
+}
+
+pub fn unimplemented<D: fmt::Display>(description: D) -> Error {
+    error(Code::Unimplemented, description)
+}
+
+pub fn read_only() -> Error {
+    error(Code::ReadOnly, "Server is in read-only mode")
+}
+
+/// Something that isn't the client's fault
+///
+/// Details stay in our log, clients only learn that something went wrong.
+pub fn internal() -> Error {
+    error(Code::Internal, "Internal server error")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use capnp::ErrorKind;
+
+    #[test]
+    fn codes_and_params() {
+        let e = occupied();
+        assert_eq!(e.description, "occupied: Machine is occupied");
+        assert_eq!(e.kind, ErrorKind::Failed);
+
+        let e = unauthorized("lab.saw", "write");
+        let (first, params) = e.description.split_at(e.description.find('\n').unwrap());
+        assert_eq!(first, "unauthorized: Permission denied");
+        let params: Value = serde_json::from_str(params).unwrap();
+        assert_eq!(params["object"], "lab.saw");
+        assert_eq!(params["action"], "write");
+    }
+
+    #[test]
+    fn retrying() {
+        let e = throttled(RetryAfter::for_depth(1));
+        let human = "throttled: Too many calls, slow down (retry-after: 2-4)\n";
+        assert!(e.description.starts_with(human), "{}", e.description);
+        assert_eq!(e.kind, ErrorKind::Overloaded);
+
+        assert_eq!(RetryAfter::for_depth(0), RetryAfter { min: 1, max: 2 });
+        assert_eq!(RetryAfter::for_depth(100), RetryAfter { min: 60, max: 120 });
+        assert_eq!(RetryAfter::for_depth(0).at_least(10), RetryAfter { min: 10, max: 11 });
+        assert_eq!(RetryAfter::for_depth(3).at_least(2), RetryAfter { min: 8, max: 16 });
+    }
+}
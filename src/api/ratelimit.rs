@@ -0,0 +1,594 @@
+//! Rate limiting of RPC calls
+//!
+//! Every connection draws from a token bucket of its own. Once it authenticated it also draws
+//! from one shared by all connections of that user, so opening more connections doesn't get a
+//! client around the limit.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::{self, FutureExt, LocalBoxFuture};
+
+use slog::Logger;
+
+use capnp::Error;
+use capnp::capability::Promise;
+
+use crate::access::Permissions;
+use crate::auth::Authentication;
+use crate::config::RateLimit;
+use crate::gc::Registry;
+use crate::machine::Machines;
+
+use super::api;
+use super::error::{self, RetryAfter};
+
+/// How often we log about the same connection or user running into the limit
+const WARN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Calls denied in a row after which a client is told to wait twice as long before retrying
+const DENIALS_PER_STEP: u32 = 10;
+
+/// Where the limiter gets the current time from
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// The actual time
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    /// When tokens were last added
+    last: Instant,
+    /// When we last logged about this bucket running dry
+    warned: Option<Instant>,
+    /// Calls denied since the last one that got through. Clients that keep hammering us are told
+    /// to wait longer.
+    denied: u32,
+}
+
+impl Bucket {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        Self { tokens: limit.burst as f64, last: now, warned: None, denied: 0 }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second as f64).min(limit.burst as f64);
+        self.last = now;
+    }
+
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            self.denied = 0;
+            true
+        } else {
+            self.denied = self.denied.saturating_add(1);
+            false
+        }
+    }
+
+    /// How long a client whose call was just denied should wait
+    fn retry_after(&self, limit: &RateLimit) -> RetryAfter {
+        let refill = ((1.0 - self.tokens) / limit.per_second as f64).ceil() as u64;
+        RetryAfter::for_depth(self.denied / DENIALS_PER_STEP).at_least(refill)
+    }
+
+    /// Whether it's time to log about this bucket running dry again
+    fn should_warn(&mut self, now: Instant) -> bool {
+        match self.warned {
+            Some(t) if now.saturating_duration_since(t) < WARN_INTERVAL => false,
+            _ => {
+                self.warned = Some(now);
+                true
+            },
+        }
+    }
+
+    /// A full bucket is no different from a new one, so there's no need to keep it around
+    fn is_full(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        self.refill(limit, now);
+        self.tokens >= limit.burst as f64
+    }
+}
+
+/// The configured limits and the buckets of all users
+pub struct RateLimiter {
+    limit: RateLimit,
+    clock: Box<dyn Clock + Send + Sync>,
+    users: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Arc<Self> {
+        Self::with_clock(limit, Box::new(SystemClock))
+    }
+
+    pub fn with_clock(limit: RateLimit, clock: Box<dyn Clock + Send + Sync>) -> Arc<Self> {
+        Arc::new(Self { limit, clock, users: Mutex::new(HashMap::new()) })
+    }
+
+    /// Limiter for a single connection
+    ///
+    /// `local` says whether the connection came in over loopback or a Unix socket.
+    pub fn for_connection(self: &Arc<Self>, log: Logger, auth: Rc<Authentication>, local: bool)
+        -> Rc<Throttle>
+    {
+        let conn = Bucket::new(&self.limit, self.clock.now());
+        Rc::new(Throttle {
+            limiter: self.clone(),
+            log,
+            auth,
+            exempt: local && self.limit.exempt_local,
+            conn: RefCell::new(conn),
+        })
+    }
+
+    /// Take a token from the bucket of `user`. If there's none left the error says whether to log
+    /// about it and how long to wait.
+    fn take_user(&self, user: &str, now: Instant) -> Result<(), (bool, RetryAfter)> {
+        let mut users = self.users.lock().unwrap();
+        if !users.contains_key(user) {
+            // Only clean up when the map grows, everybody else may still be using theirs
+            let limit = &self.limit;
+            users.retain(|_, b| !b.is_full(limit, now));
+            users.insert(user.to_string(), Bucket::new(limit, now));
+        }
+
+        let bucket = users.get_mut(user).unwrap();
+        if bucket.take(&self.limit, now) {
+            Ok(())
+        } else {
+            Err((bucket.should_warn(now), bucket.retry_after(&self.limit)))
+        }
+    }
+}
+
+impl Registry for RateLimiter {
+    fn len(&self) -> LocalBoxFuture<'_, usize> {
+        future::ready(self.users.lock().unwrap().len()).boxed_local()
+    }
+
+    /// Buckets of users who haven't made a call in a while, which are full again
+    fn prune(&self) -> LocalBoxFuture<'_, usize> {
+        let now = self.clock.now();
+        let mut users = self.users.lock().unwrap();
+        let before = users.len();
+        users.retain(|_, b| !b.is_full(&self.limit, now));
+        future::ready(before - users.len()).boxed_local()
+    }
+}
+
+/// Rate limiter of a single connection
+pub struct Throttle {
+    limiter: Arc<RateLimiter>,
+    log: Logger,
+    auth: Rc<Authentication>,
+    exempt: bool,
+    conn: RefCell<Bucket>,
+}
+
+impl Throttle {
+    /// Account for a call, failing with a `throttled` error if too many were made recently
+    pub fn check(&self) -> Result<(), Error> {
+        if self.exempt {
+            return Ok(());
+        }
+
+        let limit = &self.limiter.limit;
+        let user = self.auth.state.try_read().and_then(|s| s.clone());
+        if let Some(ref user) = user {
+            if limit.exempt_users.contains(user) {
+                return Ok(());
+            }
+        }
+
+        let now = self.limiter.clock.now();
+        {
+            let mut conn = self.conn.borrow_mut();
+            if !conn.take(limit, now) {
+                if conn.should_warn(now) {
+                    warn!(self.log, "Connection exceeds its rate limit, throttling");
+                }
+                return Err(error::throttled(conn.retry_after(limit)));
+            }
+        }
+
+        if let Some(user) = user {
+            if let Err((warn, retry)) = self.limiter.take_user(&user, now) {
+                if warn {
+                    warn!(self.log, "User {} exceeds their rate limit, throttling", user);
+                }
+                return Err(error::throttled(retry));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A capability whose calls are rate limited before being passed on to `inner`
+pub struct Limited<T> {
+    inner: T,
+    throttle: Option<Rc<Throttle>>,
+}
+
+impl<T> Limited<T> {
+    /// Without a throttle every call is passed on
+    pub fn new(inner: T, throttle: Option<Rc<Throttle>>) -> Self {
+        Self { inner, throttle }
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        match self.throttle {
+            Some(ref t) => t.check(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl api::machines::Server for Limited<Machines> {
+    fn manage(&mut self,
+        params: api::machines::ManageParams,
+        results: api::machines::ManageResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::machines::Server::manage(&mut self.inner, params, results)
+    }
+
+    fn use_(&mut self,
+        params: api::machines::UseParams,
+        results: api::machines::UseResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::machines::Server::use_(&mut self.inner, params, results)
+    }
+
+    fn get_info(&mut self,
+        params: api::machines::GetInfoParams,
+        results: api::machines::GetInfoResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::machines::Server::get_info(&mut self.inner, params, results)
+    }
+
+    fn list(&mut self,
+        params: api::machines::ListParams,
+        results: api::machines::ListResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::machines::Server::list(&mut self.inner, params, results)
+    }
+
+    fn enqueue(&mut self,
+        params: api::machines::EnqueueParams,
+        results: api::machines::EnqueueResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::machines::Server::enqueue(&mut self.inner, params, results)
+    }
+
+    fn leave_queue(&mut self,
+        params: api::machines::LeaveQueueParams,
+        results: api::machines::LeaveQueueResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::machines::Server::leave_queue(&mut self.inner, params, results)
+    }
+
+    fn reclaim(&mut self,
+        params: api::machines::ReclaimParams,
+        results: api::machines::ReclaimResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::machines::Server::reclaim(&mut self.inner, params, results)
+    }
+
+    fn set_blocked_bulk(&mut self,
+        params: api::machines::SetBlockedBulkParams,
+        results: api::machines::SetBlockedBulkResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::machines::Server::set_blocked_bulk(&mut self.inner, params, results)
+    }
+
+    fn list_locations(&mut self,
+        params: api::machines::ListLocationsParams,
+        results: api::machines::ListLocationsResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::machines::Server::list_locations(&mut self.inner, params, results)
+    }
+
+    fn list_by_status(&mut self,
+        params: api::machines::ListByStatusParams,
+        results: api::machines::ListByStatusResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::machines::Server::list_by_status(&mut self.inner, params, results)
+    }
+
+    fn last_denial(&mut self,
+        params: api::machines::LastDenialParams,
+        results: api::machines::LastDenialResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::machines::Server::last_denial(&mut self.inner, params, results)
+    }
+
+    fn get_my_grants(&mut self,
+        params: api::machines::GetMyGrantsParams,
+        results: api::machines::GetMyGrantsResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::machines::Server::get_my_grants(&mut self.inner, params, results)
+    }
+
+    fn get_my_history(&mut self,
+        params: api::machines::GetMyHistoryParams,
+        results: api::machines::GetMyHistoryResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::machines::Server::get_my_history(&mut self.inner, params, results)
+    }
+
+    fn list_manageable(&mut self,
+        params: api::machines::ListManageableParams,
+        results: api::machines::ListManageableResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::machines::Server::list_manageable(&mut self.inner, params, results)
+    }
+
+    fn notify_when_free(&mut self,
+        params: api::machines::NotifyWhenFreeParams,
+        results: api::machines::NotifyWhenFreeResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::machines::Server::notify_when_free(&mut self.inner, params, results)
+    }
+}
+
+impl api::permissions::Server for Limited<Permissions> {
+    fn get_all_subjects(&mut self,
+        params: api::permissions::GetAllSubjectsParams,
+        results: api::permissions::GetAllSubjectsResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::permissions::Server::get_all_subjects(&mut self.inner, params, results)
+    }
+
+    fn get_all_objects(&mut self,
+        params: api::permissions::GetAllObjectsParams,
+        results: api::permissions::GetAllObjectsResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::permissions::Server::get_all_objects(&mut self.inner, params, results)
+    }
+
+    fn get_all_action(&mut self,
+        params: api::permissions::GetAllActionParams,
+        results: api::permissions::GetAllActionResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::permissions::Server::get_all_action(&mut self.inner, params, results)
+    }
+
+    fn get_all_roles(&mut self,
+        params: api::permissions::GetAllRolesParams,
+        results: api::permissions::GetAllRolesResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::permissions::Server::get_all_roles(&mut self.inner, params, results)
+    }
+
+    fn list_roles(&mut self,
+        params: api::permissions::ListRolesParams,
+        results: api::permissions::ListRolesResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::permissions::Server::list_roles(&mut self.inner, params, results)
+    }
+
+    fn list_members(&mut self,
+        params: api::permissions::ListMembersParams,
+        results: api::permissions::ListMembersResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::permissions::Server::list_members(&mut self.inner, params, results)
+    }
+
+    fn list_roles_of(&mut self,
+        params: api::permissions::ListRolesOfParams,
+        results: api::permissions::ListRolesOfResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::permissions::Server::list_roles_of(&mut self.inner, params, results)
+    }
+
+    fn remove_policy(&mut self,
+        params: api::permissions::RemovePolicyParams,
+        results: api::permissions::RemovePolicyResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::permissions::Server::remove_policy(&mut self.inner, params, results)
+    }
+
+    fn add_policy(&mut self,
+        params: api::permissions::AddPolicyParams,
+        results: api::permissions::AddPolicyResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::permissions::Server::add_policy(&mut self.inner, params, results)
+    }
+
+    fn grant_temporary(&mut self,
+        params: api::permissions::GrantTemporaryParams,
+        results: api::permissions::GrantTemporaryResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::permissions::Server::grant_temporary(&mut self.inner, params, results)
+    }
+
+    fn list_temporary_grants(&mut self,
+        params: api::permissions::ListTemporaryGrantsParams,
+        results: api::permissions::ListTemporaryGrantsResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::permissions::Server::list_temporary_grants(&mut self.inner, params, results)
+    }
+
+    fn revoke_temporary(&mut self,
+        params: api::permissions::RevokeTemporaryParams,
+        results: api::permissions::RevokeTemporaryResults)
+        -> Promise<(), Error>
+    {
+        pry!(self.check());
+        api::permissions::Server::revoke_temporary(&mut self.inner, params, results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor::LocalPool;
+
+    use crate::config::RateLimit;
+    use crate::testing::rpc::{self, Server};
+
+    /// A clock that only moves when told to
+    #[derive(Clone)]
+    struct TestClock(Arc<Mutex<Instant>>);
+
+    impl TestClock {
+        fn new() -> Self {
+            TestClock(Arc::new(Mutex::new(Instant::now())))
+        }
+
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for TestClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn limit(per_second: u32, burst: u32) -> RateLimit {
+        RateLimit { per_second, burst, exempt_local: false, exempt_users: Vec::new() }
+    }
+
+    #[test]
+    fn bucket_refills() {
+        let limit = limit(10, 5);
+        let clock = TestClock::new();
+        let mut bucket = Bucket::new(&limit, clock.now());
+        for _ in 0..5 {
+            assert!(bucket.take(&limit, clock.now()));
+        }
+        assert!(!bucket.take(&limit, clock.now()));
+        assert_eq!(bucket.retry_after(&limit), RetryAfter { min: 1, max: 2 });
+
+        clock.advance(Duration::from_millis(150));
+        assert!(bucket.take(&limit, clock.now()));
+        assert!(!bucket.take(&limit, clock.now()));
+
+        // Never more than a burst, however long it's been
+        clock.advance(Duration::from_secs(3600));
+        assert!(bucket.is_full(&limit, clock.now()));
+        for _ in 0..5 {
+            assert!(bucket.take(&limit, clock.now()));
+        }
+        assert!(!bucket.take(&limit, clock.now()));
+    }
+
+    #[test]
+    fn hammering_backs_off() {
+        let limit = limit(1, 1);
+        let clock = TestClock::new();
+        let mut bucket = Bucket::new(&limit, clock.now());
+        assert!(bucket.take(&limit, clock.now()));
+        for _ in 0..DENIALS_PER_STEP * 2 {
+            assert!(!bucket.take(&limit, clock.now()));
+        }
+        assert_eq!(bucket.retry_after(&limit), RetryAfter { min: 4, max: 8 });
+    }
+
+    #[test]
+    fn users_share_a_bucket() {
+        let clock = TestClock::new();
+        let limiter = RateLimiter::with_clock(limit(1, 2), Box::new(clock.clone()));
+        let now = clock.now();
+        assert!(limiter.take_user("alice", now).is_ok());
+        assert!(limiter.take_user("alice", now).is_ok());
+
+        // Only the first denial within the interval is logged
+        let (warn, _) = limiter.take_user("alice", now).unwrap_err();
+        assert!(warn);
+        let (warn, _) = limiter.take_user("alice", now).unwrap_err();
+        assert!(!warn);
+        assert!(limiter.take_user("bob", now).is_ok());
+
+        clock.advance(Duration::from_secs(2));
+        let mut exec = LocalPool::new();
+        assert_eq!(exec.run_until(limiter.prune()), 2);
+        assert_eq!(exec.run_until(limiter.len()), 0);
+    }
+
+    #[test]
+    fn connections_get_throttled() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::with_config(&spawner,
+                |c| c.api.rate_limit = Some(limit(1, 3))).await;
+            let client = server.connect(&spawner).await;
+            for _ in 0..3 {
+                client.list().await.unwrap();
+            }
+            let e = client.list().await.err().unwrap();
+            assert_eq!(rpc::code(&e), "throttled");
+        });
+    }
+}
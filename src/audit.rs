@@ -0,0 +1,318 @@
+//! Audit trail of security-relevant events
+//!
+//! This is kept apart from the normal log so it's neither affected by log levels nor buried in
+//! debug output. Every event is written as a single line of JSON.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use futures::channel::mpsc;
+use futures::future::{self, FutureExt, LocalBoxFuture};
+
+use slog::{Drain, Logger, Discard, FnValue};
+
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::gc::Registry;
+
+pub mod history;
+
+/// Something that has to end up in the audit trail
+pub enum AuditEvent<'a> {
+    /// A SASL exchange finished
+    Authentication { authzid: &'a str, granted: bool },
+    /// A permission check failed
+    PermissionDenied { authzid: &'a str, object: &'a str, action: &'a str },
+    /// `note` is what the machine is used for, if `authzid` said
+    MachineUse { authzid: &'a str, machine: &'a Uuid, note: Option<&'a str> },
+    MachineGiveBack { authzid: &'a str, machine: &'a Uuid },
+    /// A machine was given back for somebody who didn't do so themselves, e.g. because their
+    /// connection was idle for too long
+    MachineReleased { authzid: &'a str, machine: &'a Uuid },
+    MachineBlocked { authzid: &'a str, machine: &'a Uuid, blocked: bool },
+    /// `authzid` allowed `user` to do `action` on `object` until `expires`, in UNIX seconds
+    PermissionGranted {
+        authzid: &'a str, user: &'a str, object: &'a str, action: &'a str, expires: u64,
+    },
+    /// `authzid` took a temporary grant back before it expired
+    PermissionRevoked { authzid: &'a str, user: &'a str, object: &'a str, action: &'a str },
+    /// The device powering a machine didn't confirm switching, so the machine was put into
+    /// `status` instead
+    ActorFailed { machine: &'a Uuid, power: bool, status: &'a str },
+    /// `authzid` switched the device powering a machine by hand, which confirmed it if `ok`
+    ActorOverridden { authzid: &'a str, machine: &'a Uuid, power: bool, ok: bool },
+    /// `real`, currently acting as `from`, asked to act as `to`
+    IdentitySwitched { real: &'a str, from: &'a str, to: &'a str, granted: bool },
+    /// `real` stopped acting as `from`
+    IdentityDropped { real: &'a str, from: &'a str },
+    /// A fresh install was claimed with the setup token, creating the admin `authzid`
+    InstanceClaimed { authzid: &'a str },
+    /// Read-only mode was entered or left on behalf of `by`, an admin or `SIGUSR1`
+    ReadOnly { by: &'a str, read_only: bool },
+    /// `by` issued a password reset token for `user`, working until `expires` in UNIX seconds
+    PasswordResetIssued { by: &'a str, user: &'a str, expires: u64 },
+    /// `user` set a new password with a token issued by `by`
+    PasswordReset { user: &'a str, by: &'a str },
+}
+
+impl AuditEvent<'_> {
+    fn to_event(&self) -> Event {
+        match *self {
+            AuditEvent::Authentication { authzid, granted } =>
+                Event::Authentication { authzid: authzid.to_string(), granted },
+            AuditEvent::PermissionDenied { authzid, object, action } =>
+                Event::PermissionDenied { authzid: authzid.to_string(),
+                    object: object.to_string(), action: action.to_string() },
+            AuditEvent::MachineUse { authzid, machine, note } =>
+                Event::MachineUse { authzid: authzid.to_string(), machine: *machine,
+                    note: note.map(str::to_string) },
+            AuditEvent::MachineGiveBack { authzid, machine } =>
+                Event::MachineGiveBack { authzid: authzid.to_string(), machine: *machine },
+            AuditEvent::MachineReleased { authzid, machine } =>
+                Event::MachineReleased { authzid: authzid.to_string(), machine: *machine },
+            AuditEvent::MachineBlocked { authzid, machine, blocked } =>
+                Event::MachineBlocked { authzid: authzid.to_string(), machine: *machine, blocked },
+            AuditEvent::PermissionGranted { authzid, user, object, action, expires } =>
+                Event::PermissionGranted { authzid: authzid.to_string(), user: user.to_string(),
+                    object: object.to_string(), action: action.to_string(), expires },
+            AuditEvent::PermissionRevoked { authzid, user, object, action } =>
+                Event::PermissionRevoked { authzid: authzid.to_string(), user: user.to_string(),
+                    object: object.to_string(), action: action.to_string() },
+            AuditEvent::ActorFailed { machine, power, status } =>
+                Event::ActorFailed { machine: *machine, power, status: status.to_string() },
+            AuditEvent::ActorOverridden { authzid, machine, power, ok } =>
+                Event::ActorOverridden { authzid: authzid.to_string(), machine: *machine, power,
+                    ok },
+            AuditEvent::IdentitySwitched { real, from, to, granted } =>
+                Event::IdentitySwitched { real: real.to_string(), from: from.to_string(),
+                    to: to.to_string(), granted },
+            AuditEvent::IdentityDropped { real, from } =>
+                Event::IdentityDropped { real: real.to_string(), from: from.to_string() },
+            AuditEvent::InstanceClaimed { authzid } =>
+                Event::InstanceClaimed { authzid: authzid.to_string() },
+            AuditEvent::ReadOnly { by, read_only } =>
+                Event::ReadOnly { by: by.to_string(), read_only },
+            AuditEvent::PasswordResetIssued { by, user, expires } =>
+                Event::PasswordResetIssued { by: by.to_string(), user: user.to_string(), expires },
+            AuditEvent::PasswordReset { user, by } =>
+                Event::PasswordReset { user: user.to_string(), by: by.to_string() },
+        }
+    }
+}
+
+/// An `AuditEvent` that can be sent to subscribers of the audit trail
+#[derive(Debug, Clone)]
+pub enum Event {
+    Authentication { authzid: String, granted: bool },
+    PermissionDenied { authzid: String, object: String, action: String },
+    MachineUse { authzid: String, machine: Uuid, note: Option<String> },
+    MachineGiveBack { authzid: String, machine: Uuid },
+    MachineReleased { authzid: String, machine: Uuid },
+    MachineBlocked { authzid: String, machine: Uuid, blocked: bool },
+    PermissionGranted {
+        authzid: String, user: String, object: String, action: String, expires: u64,
+    },
+    PermissionRevoked { authzid: String, user: String, object: String, action: String },
+    ActorFailed { machine: Uuid, power: bool, status: String },
+    ActorOverridden { authzid: String, machine: Uuid, power: bool, ok: bool },
+    IdentitySwitched { real: String, from: String, to: String, granted: bool },
+    IdentityDropped { real: String, from: String },
+    InstanceClaimed { authzid: String },
+    ReadOnly { by: String, read_only: bool },
+    PasswordResetIssued { by: String, user: String, expires: u64 },
+    PasswordReset { user: String, by: String },
+}
+
+/// An event as subscribers get it
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    /// Address of the peer that caused it, if it came from a connection
+    pub peer: Option<String>,
+    /// Who really caused it if they were acting as somebody else, see `Audit::set_real`
+    pub real: Option<String>,
+    pub event: Event,
+}
+
+/// Handle to the audit trail
+///
+/// Handles for single connections are derived with `for_peer` so every event carries the peer
+/// address.
+#[derive(Clone)]
+pub struct Audit {
+    log: Logger,
+    file: Option<AuditFile>,
+    /// Where `file` is, for reading it back
+    path: Option<PathBuf>,
+    peer: Option<String>,
+    /// Who the connection really is while it acts as somebody else. Events carry the identity it
+    /// acts as, this is added so it's clear who was behind them.
+    real: Arc<Mutex<Option<String>>>,
+    /// Modules that want to hear about events, whether there's an audit file or not
+    subscribers: Arc<Mutex<Vec<mpsc::UnboundedSender<Recorded>>>>,
+}
+
+impl Audit {
+    /// Open the audit trail configured in `[audit]`, if any. Without one events are discarded.
+    pub fn open(config: &Config) -> io::Result<Self> {
+        let subscribers = Arc::new(Mutex::new(Vec::new()));
+        let path = match config.audit.path {
+            Some(ref p) => p,
+            None => return Ok(Self {
+                log: Logger::root(Discard, o!()), file: None, path: None, peer: None,
+                real: Arc::new(Mutex::new(None)), subscribers,
+            }),
+        };
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let file = AuditFile(Arc::new(Mutex::new(file)));
+
+        // Events are written synchronously. There are few of them and we don't want to lose any
+        // sitting in a queue when we go down. A failing write must not take the server down with
+        // it though.
+        let drain = Mutex::new(slog_json::Json::default(file.clone())).ignore_res();
+
+        Ok(Self {
+            log: Logger::root(drain, o!()),
+            file: Some(file),
+            path: Some(path.clone()),
+            peer: None,
+            real: Arc::new(Mutex::new(None)),
+            subscribers,
+        })
+    }
+
+    /// Handle for events caused by the connection with `peer`
+    pub fn for_peer(&self, peer: String) -> Self {
+        let real = Arc::new(Mutex::new(None));
+        let r = real.clone();
+        Self {
+            log: self.log.new(o!("peer" => peer.clone(),
+                "real" => FnValue(move |_| r.lock().unwrap().clone()))),
+            file: self.file.clone(),
+            path: self.path.clone(),
+            peer: Some(peer),
+            real,
+            subscribers: self.subscribers.clone(),
+        }
+    }
+
+    /// The file events are written to, if there is one
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Record events as caused by `real` acting as whoever they name from now on, or as caused
+    /// by who they name if `None`
+    ///
+    /// Only for handles of a single connection, see `for_peer`.
+    pub fn set_real(&self, real: Option<String>) {
+        *self.real.lock().unwrap() = real;
+    }
+
+    /// Get every event recorded from now on
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<Recorded> {
+        let (tx, rx) = mpsc::unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        {
+            let mut subscribers = self.subscribers.lock().unwrap();
+            if !subscribers.is_empty() {
+                let recorded = Recorded {
+                    peer: self.peer.clone(),
+                    real: self.real.lock().unwrap().clone(),
+                    event: event.to_event(),
+                };
+                subscribers.retain(|s| s.unbounded_send(recorded.clone()).is_ok());
+            }
+        }
+
+        match event {
+            AuditEvent::Authentication { authzid, granted } =>
+                info!(self.log, "authentication";
+                    "authzid" => authzid, "granted" => granted),
+            AuditEvent::PermissionDenied { authzid, object, action } =>
+                info!(self.log, "permission denied";
+                    "authzid" => authzid, "object" => object, "action" => action),
+            AuditEvent::MachineUse { authzid, machine, note } =>
+                info!(self.log, "machine use";
+                    "authzid" => authzid, "machine" => %machine, "note" => note),
+            AuditEvent::MachineGiveBack { authzid, machine } =>
+                info!(self.log, "machine giveback";
+                    "authzid" => authzid, "machine" => %machine),
+            AuditEvent::MachineReleased { authzid, machine } =>
+                info!(self.log, "machine released";
+                    "authzid" => authzid, "machine" => %machine),
+            AuditEvent::MachineBlocked { authzid, machine, blocked } =>
+                info!(self.log, "machine blocked";
+                    "authzid" => authzid, "machine" => %machine, "blocked" => blocked),
+            AuditEvent::PermissionGranted { authzid, user, object, action, expires } =>
+                info!(self.log, "permission granted";
+                    "authzid" => authzid, "user" => user, "object" => object, "action" => action,
+                    "expires" => expires),
+            AuditEvent::PermissionRevoked { authzid, user, object, action } =>
+                info!(self.log, "permission revoked";
+                    "authzid" => authzid, "user" => user, "object" => object, "action" => action),
+            AuditEvent::ActorFailed { machine, power, status } =>
+                info!(self.log, "actor failed";
+                    "machine" => %machine, "power" => power, "status" => status),
+            AuditEvent::ActorOverridden { authzid, machine, power, ok } =>
+                info!(self.log, "actor overridden";
+                    "authzid" => authzid, "machine" => %machine, "power" => power, "ok" => ok),
+            AuditEvent::IdentitySwitched { real, from, to, granted } =>
+                info!(self.log, "identity switched";
+                    "authcid" => real, "from" => from, "to" => to, "granted" => granted),
+            AuditEvent::IdentityDropped { real, from } =>
+                info!(self.log, "identity dropped"; "authcid" => real, "from" => from),
+            AuditEvent::InstanceClaimed { authzid } =>
+                info!(self.log, "instance claimed"; "authzid" => authzid),
+            AuditEvent::ReadOnly { by, read_only } =>
+                info!(self.log, "read-only mode"; "by" => by, "read_only" => read_only),
+            AuditEvent::PasswordResetIssued { by, user, expires } =>
+                info!(self.log, "password reset issued";
+                    "authzid" => by, "user" => user, "expires" => expires),
+            AuditEvent::PasswordReset { user, by } =>
+                info!(self.log, "password reset"; "authzid" => user, "issued_by" => by),
+        }
+    }
+
+    /// Make sure everything recorded so far is on disk
+    pub fn sync(&self) -> io::Result<()> {
+        match self.file {
+            Some(ref f) => f.0.lock().unwrap().sync_data(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Registry for Audit {
+    fn len(&self) -> LocalBoxFuture<'_, usize> {
+        future::ready(self.subscribers.lock().unwrap().len()).boxed_local()
+    }
+
+    /// Subscribers that went away while nothing was recorded
+    fn prune(&self) -> LocalBoxFuture<'_, usize> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let before = subscribers.len();
+        subscribers.retain(|s| !s.is_closed());
+        future::ready(before - subscribers.len()).boxed_local()
+    }
+}
+
+#[derive(Clone)]
+struct AuditFile(Arc<Mutex<File>>);
+
+impl Write for AuditFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
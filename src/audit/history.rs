@@ -0,0 +1,118 @@
+//! Reading back who used which machine from the audit trail
+//!
+//! The trail is the only record of past uses we have, so this is what users get to see of their
+//! own history. It's read from the start on every call, which is fine for the sizes we expect but
+//! callers should keep it off the executor for large files.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use serde_json::Value;
+
+use uuid::Uuid;
+
+/// A single use of a machine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Usage {
+    pub machine: Uuid,
+    /// When the machine was taken into use, in UNIX seconds
+    pub start: u64,
+    /// When it was given back, in UNIX seconds. `None` if it still is in use or the trail doesn't
+    /// say, e.g. because we went down in the meantime.
+    pub end: Option<u64>,
+    /// What the machine was used for, if the user said
+    pub note: Option<String>,
+}
+
+/// The last `limit` uses of machines by `user` recorded in the audit trail at `path`, newest first
+pub fn read(path: &Path, user: &str, limit: usize) -> io::Result<Vec<Usage>> {
+    let reader = BufReader::new(File::open(path)?);
+
+    // Only the last `limit` uses are kept around. `dropped` counts those that fell off the front
+    // so uses still open can be found by their index in the whole trail.
+    let mut uses: VecDeque<Usage> = VecDeque::new();
+    let mut dropped = 0;
+    let mut open: HashMap<Uuid, usize> = HashMap::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        // The last line may be cut short if we went down while writing it
+        let event: Value = match serde_json::from_str(&line) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        let (msg, at, machine) = match (
+            event["msg"].as_str(),
+            event["ts"].as_str().and_then(parse_ts),
+            event["machine"].as_str().and_then(|m| Uuid::parse_str(m).ok()),
+        ) {
+            (Some(msg), Some(at), Some(machine)) => (msg, at, machine),
+            _ => continue,
+        };
+        let by_user = event["authzid"].as_str() == Some(user);
+
+        let ended = match msg {
+            "machine use" if by_user => {
+                let note = event["note"].as_str().map(str::to_string);
+                uses.push_back(Usage { machine, start: at, end: None, note });
+                open.insert(machine, dropped + uses.len() - 1);
+                if uses.len() > limit {
+                    uses.pop_front();
+                    dropped += 1;
+                }
+                false
+            },
+            "machine giveback" | "machine released" => by_user,
+            // Blocking a machine ends its use, no matter who did it
+            "machine blocked" => event["blocked"].as_bool().unwrap_or(false),
+            "actor failed" => true,
+            _ => false,
+        };
+        if ended {
+            if let Some(i) = open.remove(&machine) {
+                if i >= dropped {
+                    uses[i - dropped].end = Some(at);
+                }
+            }
+        }
+    }
+
+    Ok(uses.into_iter().rev().collect())
+}
+
+/// Seconds since the UNIX epoch of an RFC 3339 timestamp, as written by the JSON logger
+///
+/// Fractions of seconds are ignored.
+fn parse_ts(ts: &str) -> Option<u64> {
+    let num = |r: std::ops::Range<usize>| ts.get(r).and_then(|s| s.parse::<i64>().ok());
+    let (year, month, day) = (num(0..4)?, num(5..7)?, num(8..10)?);
+    let (hour, min, sec) = (num(11..13)?, num(14..16)?, num(17..19)?);
+
+    let rest = ts.get(19..)?.trim_start_matches(|c: char| c == '.' || c.is_ascii_digit());
+    let offset = match rest.chars().next()? {
+        'Z' | 'z' => 0,
+        sign @ '+' | sign @ '-' => {
+            let h: i64 = rest.get(1..3)?.parse().ok()?;
+            let m: i64 = rest.get(4..6)?.parse().ok()?;
+            let o = h * 3600 + m * 60;
+            if sign == '+' { o } else { -o }
+        },
+        _ => return None,
+    };
+
+    let secs = days_from_civil(year, month, day) * 86400 + hour * 3600 + min * 60 + sec - offset;
+    if secs < 0 { None } else { Some(secs as u64) }
+}
+
+/// Days between the UNIX epoch and a date of the proleptic Gregorian calendar
+// See http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
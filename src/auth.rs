@@ -6,30 +6,52 @@
 use std::collections::HashMap;
 use std::fmt;
 use std::error::Error;
-use std::path::Path;
-use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::fs::{self, File, OpenOptions};
 use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
 use std::ops::Deref;
+use std::rc::Rc;
 
 use async_std::sync::{Arc, RwLock};
 use capnp::capability::Promise;
 
+use futures::task::{Spawn, SpawnExt};
+
 use futures_signals::signal::Mutable;
 use casbin::{Enforcer, Model, FileAdapter};
 
 use slog::Logger;
 
-use crate::error::Result;
-use crate::config::Config;
+use crate::error::{Result, WithPath};
+use crate::config::{Config, PasswordPolicy};
+use crate::audit::{Audit, AuditEvent};
+use crate::machine::{self, unix_secs};
+use crate::status::Status;
+
+use reset::{Refused, Resets};
+use sasl::{Mechanism, Session, StepResult};
+
+pub mod policy;
+pub mod reset;
+pub mod sasl;
+pub mod setup;
+pub mod users;
 
 pub async fn init(log: Logger, config: Config) -> Result<AuthenticationProvider> {
-    let passdb = open_passdb(&config.passdb).unwrap();
+    let passdb = open_passdb(&config.passdb).with_path(&config.passdb)?;
 
-    let m = Model::from_file(&config.access.model).await?;
-    let a = FileAdapter::new(config.access.policy);
-    let enforcer = Enforcer::new(m, Box::new(a)).await?;
+    let m = Model::from_file(&config.access.model).await.with_path(&config.access.model)?;
+    let a = FileAdapter::new(config.access.policy.clone());
+    let enforcer = Enforcer::new(m, Box::new(a)).await.with_path(&config.access.policy)?;
 
-    Ok(AuthenticationProvider::new(passdb, enforcer))
+    let resets = match config.auth.resets {
+        Some(ref path) => Resets::open(path, unix_secs(SystemTime::now())).with_path(path)?,
+        None => Resets::disabled(),
+    };
+
+    Ok(AuthenticationProvider::new(passdb, enforcer, resets, &config))
 }
 
 #[derive(Debug)]
@@ -40,6 +62,8 @@ pub enum SASLError {
     BadChallenge,
     /// Enforcer Failure
     Enforcer,
+    /// The mechanism needs data the client didn't send
+    MissingData,
 }
 impl fmt::Display for SASLError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -48,108 +72,219 @@ impl fmt::Display for SASLError {
 }
 impl Error for SASLError {}
 
-type PassDB = HashMap<String, String>;
-pub fn open_passdb(path: &Path) -> Option<PassDB> {
+pub type PassDB = HashMap<String, String>;
+
+/// Read the password database at `path`, which is empty if it doesn't exist yet
+///
+/// A fresh install has no users at all then, see `setup` for how it gets its first.
+pub fn open_passdb(path: &Path) -> Result<PassDB> {
     if path.is_file() {
-        let mut fp = File::open(path).unwrap();
+        let mut fp = File::open(path)?;
         let mut content = String::new();
-        fp.read_to_string(&mut content).unwrap();
-        let map = toml::from_str(&content).ok()?;
-        return Some(map);
+        fp.read_to_string(&mut content)?;
+        let map = toml::from_str(&content)?;
+        return Ok(map);
     } else {
-        let mut map = HashMap::new();
-        map.insert("Testuser".to_string(), "Testpass".to_string());
-        let mut fp = File::create(&path).unwrap();
-        let toml = toml::to_string(&map).unwrap();
-        fp.write_all(&toml.as_bytes()).unwrap();
-        return Some(map);
+        return Ok(HashMap::new());
     }
 }
 
-pub struct Plain {
+/// Replace the password database at `path` with `passdb`
+///
+/// Like the machine database it's written next to the old one and renamed over it, and only its
+/// owner may read it.
+pub fn save_passdb(path: &Path, passdb: &PassDB) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut fp = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600)
+            .open(&tmp).with_path(&tmp)?;
+        let toml = toml::to_string(passdb)?;
+        fp.write_all(toml.as_bytes()).with_path(&tmp)?;
+        fp.sync_all().with_path(&tmp)?;
+    }
+    fs::rename(&tmp, path).with_path(path)?;
+    Ok(())
+}
+
+pub struct AuthenticationProvider {
     // FIXME: I don't want to store passwords.
     passdb: PassDB,
+    /// Decides who may act as whom
     enforcer: Enforcer,
+    /// Every mechanism clients may use, in the order they're offered
+    mechanisms: Vec<Arc<dyn Mechanism>>,
+    /// Tokens for setting a new password without knowing the old one
+    pub resets: Resets,
+    /// Seconds a password reset token works
+    pub reset_expiry: u64,
+    /// What new passwords have to look like
+    pub rules: PasswordPolicy,
+    /// Where the password database is saved to
+    passdb_path: PathBuf,
 }
 
-impl Plain {
-    pub fn step<'a>(&self, data: &'a [u8]) -> Result<(bool, &'a str)> {
-        let data = std::str::from_utf8(data).map_err(|_| SASLError::UTF8)?;
-        if let Some((authzid, authcid, passwd)) = split_nul(data) {
-
-            // Check if we know about that user
-            if let Some(pwd) = self.passdb.get(authcid) {
-                // Check the provided password
-                // FIXME: At least use hashes
-                if pwd == passwd {
-                    // authzid is the Identity the user wants to act as.
-                    // If that is unset, shortcut to Success
-                    if authzid == "" || authzid == authcid {
-                        return Ok((true, authcid));
-                    }
-
-                    if let Ok(b) = self.enforcer.enforce(vec![authcid, authzid, "su"]) {
-                        if b {
-                            return Ok((true, authzid));
-                        } else {
-                            return Ok((false, authzid));
-                        }
-                    } else {
-                        return Err(SASLError::Enforcer.into());
-                    }
+impl AuthenticationProvider {
+    pub fn new(passdb: PassDB, enforcer: Enforcer, resets: Resets, config: &Config) -> Self {
+        Self {
+            passdb,
+            enforcer,
+            mechanisms: vec![Arc::new(sasl::Plain)],
+            resets,
+            reset_expiry: config.auth.reset_expiry,
+            rules: config.auth.policy.clone(),
+            passdb_path: config.passdb.clone(),
+        }
+    }
 
-                }
-            }
-            Ok((false, authzid))
-        } else {
-            return Err(SASLError::BadChallenge.into())
+    /// Offer `mechanism` to clients, replacing the one with the same name if there is one
+    pub fn register(&mut self, mechanism: Arc<dyn Mechanism>) {
+        match self.mechanisms.iter_mut().find(|m| m.name() == mechanism.name()) {
+            Some(m) => *m = mechanism,
+            None => self.mechanisms.push(mechanism),
         }
     }
-}
 
-pub fn split_nul(string: &str) -> Option<(&str, &str, &str)> {
-    let mut i = string.split(|b| b == '\0');
+    /// The mechanism offered as `name`
+    pub fn mechanism(&self, name: &str) -> Option<Arc<dyn Mechanism>> {
+        self.mechanisms.iter().find(|m| m.name() == name).cloned()
+    }
 
-    let a = i.next()?;
-    let b = i.next()?;
-    let c = i.next()?;
+    /// Names of the mechanisms offered, leaving out those sending passwords unless `passwords`
+    pub fn mechs(&self, passwords: bool) -> Vec<&'static str> {
+        self.mechanisms.iter()
+            .filter(|m| passwords || !m.sends_password())
+            .map(|m| m.name())
+            .collect()
+    }
 
-    Some((a,b,c))
-}
+    /// Whether `user` exists and has `password`
+    pub fn check_password(&self, user: &str, password: &str) -> bool {
+        self.passdb.get(user).map(|p| p == password).unwrap_or(false)
+    }
 
+    /// Whether `authcid` may act as `authzid` right after authenticating
+    pub fn may_act_as(&self, authcid: &str, authzid: &str)
+        -> std::result::Result<bool, SASLError>
+    {
+        self.enforcer.enforce(vec![authcid, authzid, "su"]).map_err(|_| SASLError::Enforcer)
+    }
 
-pub struct AuthenticationProvider {
-    pub plain: Plain,
-}
+    pub fn has_users(&self) -> bool {
+        !self.passdb.is_empty()
+    }
 
-impl AuthenticationProvider {
-        pub fn new(passdb: PassDB, enforcer: Enforcer) -> Self {
-        Self {
-            plain: Plain { passdb, enforcer }
-        }
+    /// Add `user` with `password` and save the password database to `path`
+    pub fn add_user(&mut self, path: &Path, user: &str, password: &str) -> Result<()> {
+        let mut passdb = self.passdb.clone();
+        passdb.insert(user.to_string(), password.to_string());
+        save_passdb(path, &passdb)?;
+        self.passdb = passdb;
+        Ok(())
+    }
+
+    /// Remove `user` from the password database at `path` again
+    pub fn remove_user(&mut self, path: &Path, user: &str) -> Result<()> {
+        let mut passdb = self.passdb.clone();
+        passdb.remove(user);
+        save_passdb(path, &passdb)?;
+        self.passdb = passdb;
+        Ok(())
     }
 
-    pub fn mechs(&self) -> Vec<&'static str> {
-        vec!["PLAIN"]
+    pub fn has_user(&self, user: &str) -> bool {
+        self.passdb.contains_key(user)
+    }
+
+    /// Change the password of `user`, saving the password database
+    pub fn set_password(&mut self, user: &str, password: &str) -> Result<()> {
+        let path = self.passdb_path.clone();
+        self.add_user(&path, user, password)
+    }
+
+    /// Read the policy again, which decides who may act as whom
+    pub async fn reload_policy(&mut self) -> Result<()> {
+        self.enforcer.load_policy().await?;
+        Ok(())
     }
 }
 
 #[derive(Clone)]
 pub struct Authentication {
+    /// The identity the connection acts as
     pub state: Arc<RwLock<Option<String>>>,
+    /// Who authenticated, if the connection acts as somebody else
+    real: Arc<RwLock<Option<String>>>,
+    /// For checking `su` when switching identities. Only set on the capability handed to the
+    /// client, since permissions themselves need the authentication state.
+    perm: Option<Rc<Permissions>>,
     provider: Arc<RwLock<AuthenticationProvider>>,
+    /// Whether mechanisms sending passwords are offered, which they aren't on unencrypted
+    /// connections if `api.require_tls` is set
+    passwords: bool,
+    /// Where to run the expensive parts of an authentication exchange so they don't hold up every
+    /// other connection.
+    spawner: Rc<dyn Spawn>,
+    log: Logger,
+    audit: Audit,
+    /// For refusing password resets in read-only mode
+    status: Arc<Status>,
 }
 impl Authentication {
-    pub fn new(provider: Arc<RwLock<AuthenticationProvider>>) -> Self {
+    pub fn new(log: Logger, provider: Arc<RwLock<AuthenticationProvider>>, spawner: Rc<dyn Spawn>,
+        audit: Audit, status: Arc<Status>) -> Self
+    {
         Self {
             state: Arc::new(RwLock::new(None)),
+            real: Arc::new(RwLock::new(None)),
+            perm: None,
             provider: provider,
+            passwords: true,
+            spawner: spawner,
+            log: log,
+            audit: audit,
+            status: status,
+        }
+    }
+
+    /// Don't offer mechanisms sending passwords in the clear
+    pub fn without_passwords(mut self) -> Self {
+        self.passwords = false;
+        self
+    }
+
+    /// The mechanisms this connection may use
+    pub async fn mechanisms(&self) -> Vec<&'static str> {
+        self.provider.read().await.mechs(self.passwords)
+    }
+
+    /// Whether this connection can redeem password reset tokens
+    pub async fn password_resets(&self) -> bool {
+        self.passwords && self.provider.read().await.resets.enabled()
+    }
+
+    /// The same authentication state, able to switch identities using `perm`
+    pub fn with_permissions(mut self, perm: Rc<Permissions>) -> Self {
+        self.perm = Some(perm);
+        self
+    }
+
+    pub fn provider(&self) -> Arc<RwLock<AuthenticationProvider>> {
+        self.provider.clone()
+    }
+
+    /// Who authenticated, no matter who the connection acts as
+    pub async fn real_authzid(&self) -> Option<String> {
+        match self.real.read().await.clone() {
+            Some(r) => Some(r),
+            None => self.state.read().await.clone(),
         }
     }
 }
 
 
 use crate::api::api;
+use crate::api::error;
+use crate::access::{self, Permissions};
 
 impl api::authentication::Server for Authentication {
     fn available_mechanisms(&mut self,
@@ -157,9 +292,9 @@ impl api::authentication::Server for Authentication {
         mut results: api::authentication::AvailableMechanismsResults)
         -> ::capnp::capability::Promise<(), ::capnp::Error>
     {
-        let p = self.provider.clone();
+        let this = self.clone();
         let f = async move {
-            let m = p.read().await.mechs();
+            let m = this.mechanisms().await;
             let mut b = results.get()
                 .init_mechanisms(m.len() as u32);
             for (i, mech) in m.iter().enumerate() {
@@ -177,46 +312,21 @@ impl api::authentication::Server for Authentication {
         mut results: api::authentication::InitializeAuthenticationResults)
         -> ::capnp::capability::Promise<(), ::capnp::Error>
     {
-        let prov = self.provider.clone();
-        let stat = self.state.clone();
-
+        let this = self.clone();
         Promise::from_future(async move {
             let params = params.get()?;
-            let mechanism = params.get_mechanism()?;
-
-            match mechanism {
-                "PLAIN" => {
-                    use api::authentication::maybe_data::Which;
-
-                    let data = params.get_initial_data()?;
-                    if let Ok(Which::Some(data)) = data.which() {
-                        let data = data?;
-                        if let Ok((b, name)) = prov.read().await.plain.step(data) {
-                            // If login was successful set the authzid
-                            if b {
-                                stat.write().await.replace(name.to_string());
-                            }
-
-                            let outcome = Outcome::value(b);
-                            results
-                                .get()
-                                .init_response()
-                                .set_outcome(api::authentication::outcome::ToClient::new(outcome)
-                                    .into_client::<::capnp_rpc::Server>());
-                        }
-                        Ok(())
-                    } else {
-                        Err(::capnp::Error::unimplemented(
-                            "SASL PLAIN requires initial data set".to_string()))
-                    }
-                },
-                m => {
-                    Err(::capnp::Error::unimplemented(
-                            format!("SASL Mechanism {} is not implemented", m)
-                    ))
-                }
+            let name = params.get_mechanism()?;
 
+            let mechanism = this.provider.read().await.mechanism(name).ok_or_else(||
+                error::unimplemented(format!("SASL Mechanism {} is not implemented", name)))?;
+            if mechanism.sends_password() && !this.passwords {
+                return Err(error::unimplemented(format!(
+                    "SASL mechanism {} is only offered on encrypted connections", name)));
             }
+
+            let initial = maybe_data(params.get_initial_data()?)?;
+            this.step(mechanism, Session::new(), initial, true, results.get().init_response())
+                .await
         })
     }
 
@@ -226,18 +336,226 @@ impl api::authentication::Server for Authentication {
         -> ::capnp::capability::Promise<(), ::capnp::Error>
     {
         let state = self.state.clone();
+        let real = self.real.clone();
         let f = async move {
             if let Some(zid) = state.read().await.deref() {
                 results.get().set_authzid(&zid);
             } else {
                 results.get().set_authzid("");
             }
+            results.get().set_real_authzid(real.read().await.as_deref().unwrap_or(""));
 
             Ok(())
         };
 
         Promise::from_future(f)
     }
+
+    fn switch_identity(&mut self,
+        params: api::authentication::SwitchIdentityParams,
+        _results: api::authentication::SwitchIdentityResults)
+        -> ::capnp::capability::Promise<(), ::capnp::Error>
+    {
+        let this = self.clone();
+        Promise::from_future(async move {
+            let target = params.get()?.get_target()?.to_string();
+            let current = this.state.read().await.clone().ok_or_else(error::unauthenticated)?;
+            let real = this.real_authzid().await.unwrap_or_else(|| current.clone());
+            if target == current {
+                return Ok(());
+            }
+            // Going back to oneself needs no permission
+            if target == real {
+                return this.drop_to_real().await;
+            }
+
+            let perm = this.perm.as_ref()
+                .ok_or_else(|| error::unimplemented("Switching identities is not available"))?;
+            let granted = perm.check(&current, &target, "su").await
+                .map_err(|e| access::check_failed(&this.log, &target, "su", e))?;
+            this.audit.record(AuditEvent::IdentitySwitched {
+                real: &real, from: &current, to: &target, granted,
+            });
+            if !granted {
+                info!(this.log, "Denied {} acting as {}", current, target);
+                return Err(error::unauthorized(&target, "su"));
+            }
+
+            info!(this.log, "{} now acts as {}", real, target);
+            this.audit.set_real(Some(real.clone()));
+            *this.real.write().await = Some(real);
+            *this.state.write().await = Some(target);
+            Ok(())
+        })
+    }
+
+    fn drop_identity(&mut self,
+        _params: api::authentication::DropIdentityParams,
+        _results: api::authentication::DropIdentityResults)
+        -> ::capnp::capability::Promise<(), ::capnp::Error>
+    {
+        let this = self.clone();
+        Promise::from_future(async move { this.drop_to_real().await })
+    }
+
+    fn redeem_password_reset(&mut self,
+        params: api::authentication::RedeemPasswordResetParams,
+        mut results: api::authentication::RedeemPasswordResetResults)
+        -> ::capnp::capability::Promise<(), ::capnp::Error>
+    {
+        pry!(machine::writable(&self.status));
+        let this = self.clone();
+        Promise::from_future(async move {
+            let params = params.get()?;
+            let token = params.get_token()?;
+            let password = params.get_new_password()?;
+            let now = unix_secs(SystemTime::now());
+
+            // Same as for PLAIN, the new password would be sent in the clear
+            if !this.passwords {
+                return Err(error::unimplemented(
+                    "Passwords are only accepted on encrypted connections"));
+            }
+            // Held until the token is used up so it can't be redeemed twice at the same time
+            let mut prov = this.provider.write().await;
+            if !prov.resets.enabled() {
+                return Err(error::unimplemented("Password resets are not enabled"));
+            }
+            let user = match prov.resets.check(token, now) {
+                // A user removed since can't get their password back this way
+                Ok(r) if prov.has_user(&r.user) => r.user.clone(),
+                Ok(_) => return Err(Refused::Unknown.to_error()),
+                Err(e) => {
+                    info!(this.log, "Refused a password reset: {:?} token", e);
+                    return Err(e.to_error());
+                },
+            };
+            let violations = policy::check(&prov.rules, &user, password);
+            if !violations.is_empty() {
+                let violations: Vec<String> = violations.iter()
+                    .map(|v| format!("Password {}", v)).collect();
+                return Err(error::invalid_argument(violations.join(", ")));
+            }
+
+            // The token is used up first, so failing to save it can't leave it working for
+            // another try
+            let reset = match prov.resets.redeem(token, now) {
+                Ok(Ok(r)) => r,
+                // Checked under the same lock above
+                Ok(Err(e)) => return Err(e.to_error()),
+                Err(e) => {
+                    error!(this.log, "Failed to save password reset tokens: {}", e);
+                    return Err(error::internal());
+                },
+            };
+            if let Err(e) = prov.set_password(&user, password) {
+                error!(this.log, "Failed to save the new password of {}: {}", user, e);
+                return Err(error::internal());
+            }
+            drop(prov);
+
+            info!(this.log, "{} set a new password with a token issued by {}", user, reset.by);
+            this.audit.record(AuditEvent::PasswordReset { user: &user, by: &reset.by });
+            results.get().set_user(&user);
+            Ok(())
+        })
+    }
+}
+
+/// The data of a `MaybeData`, if there is any
+fn maybe_data(data: api::authentication::maybe_data::Reader)
+    -> std::result::Result<Option<Vec<u8>>, capnp::Error>
+{
+    use api::authentication::maybe_data::Which;
+    match data.which() {
+        Ok(Which::Some(data)) => Ok(Some(data?.to_vec())),
+        _ => Ok(None),
+    }
+}
+
+impl Authentication {
+    /// Take the exchange `session` with `mechanism` a step further with the client's `data`,
+    /// answering with the next challenge or the outcome
+    async fn step(&self, mechanism: Arc<dyn Mechanism>, mut session: Session,
+        data: Option<Vec<u8>>, start: bool,
+        mut response: api::authentication::step_result::Builder<'_>)
+        -> std::result::Result<(), capnp::Error>
+    {
+        // Checking the credentials is the expensive part so it runs on the thread pool. That needs
+        // its own copy of everything.
+        let prov = self.provider.clone();
+        let mech = mechanism.clone();
+        let step = self.spawner.spawn_with_handle(async move {
+            let prov = prov.read().await;
+            let result = if start {
+                mech.start(&prov, data.as_deref(), &mut session)
+            } else {
+                mech.step(&prov, data.as_deref(), &mut session)
+            };
+            (result, session)
+        }).map_err(error::overloaded)?;
+
+        match step.await {
+            (StepResult::Challenge(data), session) => {
+                let challenge = Challenge {
+                    auth: self.clone(),
+                    mechanism,
+                    session: Some(session),
+                    data,
+                };
+                response.set_challenge(api::authentication::challenge::ToClient::new(challenge)
+                    .into_client::<::capnp_rpc::Server>());
+            },
+            (StepResult::Done(granted), session) => {
+                self.finish(granted, session).await;
+                response.set_outcome(api::authentication::outcome::ToClient::new(
+                    Outcome::value(granted)).into_client::<::capnp_rpc::Server>());
+            },
+            (StepResult::Invalid(SASLError::MissingData), _) => {
+                return Err(error::unimplemented(format!("SASL {} requires initial data set",
+                    mechanism.name())));
+            },
+            // Garbage gets no response at all
+            (StepResult::Invalid(_), _) => {},
+        }
+        Ok(())
+    }
+
+    /// Record the outcome of an exchange, switching to its authzid if it was granted
+    async fn finish(&self, granted: bool, session: Session) {
+        let name = session.authzid.unwrap_or_default();
+        let authcid = session.authcid.unwrap_or_else(|| name.clone());
+        self.audit.record(AuditEvent::Authentication { authzid: &name, granted });
+        if !granted {
+            info!(self.log, "Failed authentication as {}", name);
+            return;
+        }
+
+        info!(self.log, "Authenticated as {}", name);
+        // Acting as somebody else right away is recorded like switching to them later
+        let acting = Some(authcid.clone()).filter(|a| *a != name);
+        if acting.is_some() {
+            self.audit.record(AuditEvent::IdentitySwitched {
+                real: &authcid, from: &authcid, to: &name, granted: true,
+            });
+        }
+        self.audit.set_real(acting.clone());
+        *self.real.write().await = acting;
+        self.state.write().await.replace(name);
+    }
+
+    /// Stop acting as somebody else, if the connection does
+    async fn drop_to_real(&self) -> std::result::Result<(), capnp::Error> {
+        let real = match self.real.write().await.take() {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        let from = self.state.write().await.replace(real.clone()).unwrap_or_default();
+        self.audit.set_real(None);
+        self.audit.record(AuditEvent::IdentityDropped { real: &real, from: &from });
+        info!(self.log, "{} stopped acting as {}", real, from);
+        Ok(())
+    }
 }
 
 struct Outcome {
@@ -260,3 +578,261 @@ impl api::authentication::outcome::Server for Outcome {
         ::capnp::capability::Promise::ok(())
     }
 }
+
+/// A mechanism waiting for the client to respond
+struct Challenge {
+    auth: Authentication,
+    mechanism: Arc<dyn Mechanism>,
+    /// Gone once the client responded, a challenge can only be answered once
+    session: Option<Session>,
+    data: Option<Vec<u8>>,
+}
+
+impl api::authentication::challenge::Server for Challenge {
+    fn read(&mut self,
+        _params: api::authentication::challenge::ReadParams,
+        mut results: api::authentication::challenge::ReadResults)
+        -> ::capnp::capability::Promise<(), ::capnp::Error>
+    {
+        let mut data = results.get().init_data();
+        match self.data {
+            Some(ref d) => data.set_some(d),
+            None => data.set_none(()),
+        }
+        ::capnp::capability::Promise::ok(())
+    }
+
+    fn respond(&mut self,
+        params: api::authentication::challenge::RespondParams,
+        mut results: api::authentication::challenge::RespondResults)
+        -> ::capnp::capability::Promise<(), ::capnp::Error>
+    {
+        let session = match self.session.take() {
+            Some(s) => s,
+            None => return Promise::err(error::conflict("The challenge was responded to already")),
+        };
+        let auth = self.auth.clone();
+        let mechanism = self.mechanism.clone();
+        Promise::from_future(async move {
+            let data = maybe_data(params.get()?.get_data()?)?;
+            auth.step(mechanism, session, data, false, results.get().init_response()).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::executor::LocalPool;
+
+    use crate::api::api;
+    use crate::audit::Event;
+    use crate::testing::rpc::{self, Client, Server, PRINTER};
+
+    use super::AuthenticationProvider;
+    use super::sasl::{Mechanism, Session, StepResult};
+
+    /// Who the connection acts as and who authenticated, if that's somebody else
+    async fn authzid(client: &Client) -> (String, String) {
+        let auth = client.bootstrap.authentication_request().send().pipeline.get_auth();
+        let reply = auth.get_authzid_request().send().promise.await.unwrap();
+        let r = reply.get().unwrap();
+        (r.get_authzid().unwrap().to_string(), r.get_real_authzid().unwrap().to_string())
+    }
+
+    async fn switch(client: &Client, target: &str) -> Result<(), capnp::Error> {
+        let auth = client.bootstrap.authentication_request().send().pipeline.get_auth();
+        let mut req = auth.switch_identity_request();
+        req.get().set_target(target);
+        req.send().promise.await?;
+        Ok(())
+    }
+
+    /// Authenticate with PLAIN sending `data` as is, returning whether it was granted
+    async fn plain(client: &Client, data: &[u8]) -> bool {
+        let auth = client.bootstrap.authentication_request().send().pipeline.get_auth();
+        let mut req = auth.initialize_authentication_request();
+        req.get().set_mechanism("PLAIN");
+        req.get().init_initial_data().set_some(data);
+        let reply = req.send().promise.await.unwrap();
+        let outcome = match reply.get().unwrap().get_response().unwrap().which().unwrap() {
+            api::authentication::step_result::Outcome(o) => o.unwrap(),
+            api::authentication::step_result::Challenge(_) => panic!("PLAIN took another step"),
+        };
+        let reply = outcome.value_request().send().promise.await.unwrap();
+        reply.get().unwrap().get_granted()
+    }
+
+    fn strings(a: &str, b: &str) -> (String, String) {
+        (a.to_string(), b.to_string())
+    }
+
+    #[test]
+    fn switching_identities() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let mut audit = server.audit.subscribe();
+            let alice = server.connect(&spawner).await;
+            alice.login_as("alice").await;
+            assert_eq!(authzid(&alice).await, strings("alice", ""));
+
+            let e = switch(&alice, "admin").await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unauthorized");
+            assert_eq!(authzid(&alice).await, strings("alice", ""));
+
+            switch(&alice, "bob").await.unwrap();
+            assert_eq!(authzid(&alice).await, strings("bob", "alice"));
+            let giveback = alice.use_machine(PRINTER).await.unwrap();
+            rpc::give_back(&giveback).await.unwrap();
+            // Going back needs no permission
+            switch(&alice, "alice").await.unwrap();
+            assert_eq!(authzid(&alice).await, strings("alice", ""));
+
+            // Not the other way around
+            let bob = server.connect(&spawner).await;
+            bob.login_as("bob").await;
+            let e = switch(&bob, "alice").await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unauthorized");
+
+            let mut recorded = Vec::new();
+            while let Ok(Some(r)) = audit.try_next() {
+                match r.event {
+                    Event::IdentitySwitched { real, from, to, granted } =>
+                        recorded.push(format!("{} as {} to {}: {}", real, from, to, granted)),
+                    Event::IdentityDropped { real, from } =>
+                        recorded.push(format!("{} dropped {}", real, from)),
+                    Event::MachineUse { authzid, .. } =>
+                        recorded.push(format!("{} (really {:?}) uses", authzid, r.real)),
+                    _ => {},
+                }
+            }
+            assert_eq!(recorded, vec![
+                "alice as alice to admin: false",
+                "alice as alice to bob: true",
+                "bob (really Some(\"alice\")) uses",
+                "alice dropped bob",
+                "bob as bob to alice: false",
+            ]);
+        });
+    }
+
+    #[test]
+    fn acting_as_somebody_else_right_away() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let alice = server.connect(&spawner).await;
+            assert!(plain(&alice, b"bob\0alice\0alice's password").await);
+            assert_eq!(authzid(&alice).await, strings("bob", "alice"));
+
+            switch(&alice, "alice").await.unwrap();
+            assert_eq!(authzid(&alice).await, strings("alice", ""));
+
+            // bob may not act as alice, so he can't log in as her either
+            let bob = server.connect(&spawner).await;
+            assert!(!plain(&bob, b"alice\0bob\0bob's password").await);
+            assert_eq!(authzid(&bob).await, strings("", ""));
+        });
+    }
+
+    /// Asks who the client is and believes whatever user it names
+    struct Ask;
+
+    impl Mechanism for Ask {
+        fn name(&self) -> &'static str {
+            "X-ASK"
+        }
+
+        fn start(&self, _provider: &AuthenticationProvider, _initial: Option<&[u8]>,
+            session: &mut Session) -> StepResult
+        {
+            session.state = Some(Box::new("asked"));
+            StepResult::Challenge(Some(b"who are you?".to_vec()))
+        }
+
+        fn step(&self, provider: &AuthenticationProvider, response: Option<&[u8]>,
+            session: &mut Session) -> StepResult
+        {
+            assert_eq!(session.state.take().unwrap().downcast_ref::<&str>(), Some(&"asked"));
+            let name = String::from_utf8(response.unwrap().to_vec()).unwrap();
+            let granted = provider.has_user(&name);
+            session.authzid = Some(name);
+            StepResult::Done(granted)
+        }
+    }
+
+    async fn mechanisms(client: &Client) -> Vec<String> {
+        let auth = client.bootstrap.authentication_request().send().pipeline.get_auth();
+        let reply = auth.available_mechanisms_request().send().promise.await.unwrap();
+        reply.get().unwrap().get_mechanisms().unwrap().iter()
+            .map(|m| m.unwrap().to_string())
+            .collect()
+    }
+
+    /// Start an exchange with `mechanism` and no initial data, returning the challenge
+    async fn challenge(client: &Client, mechanism: &str)
+        -> Result<api::authentication::challenge::Client, capnp::Error>
+    {
+        let auth = client.bootstrap.authentication_request().send().pipeline.get_auth();
+        let mut req = auth.initialize_authentication_request();
+        req.get().set_mechanism(mechanism);
+        req.get().init_initial_data().set_none(());
+        let reply = req.send().promise.await?;
+        match reply.get()?.get_response()?.which()? {
+            api::authentication::step_result::Challenge(c) => c,
+            api::authentication::step_result::Outcome(_) => panic!("no challenge"),
+        }
+    }
+
+    async fn respond(challenge: &api::authentication::challenge::Client, data: &[u8])
+        -> Result<bool, capnp::Error>
+    {
+        let mut req = challenge.respond_request();
+        req.get().init_data().set_some(data);
+        let reply = req.send().promise.await?;
+        let outcome = match reply.get()?.get_response()?.which()? {
+            api::authentication::step_result::Outcome(o) => o?,
+            api::authentication::step_result::Challenge(_) => panic!("another challenge"),
+        };
+        Ok(outcome.value_request().send().promise.await?.get()?.get_granted())
+    }
+
+    #[test]
+    fn registered_mechanisms() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let auth = server.api.authentication();
+            auth.write().await.register(Arc::new(Ask));
+            // Registering one under the same name again replaces it
+            auth.write().await.register(Arc::new(Ask));
+            let client = server.connect(&spawner).await;
+            assert_eq!(mechanisms(&client).await, vec!["PLAIN", "X-ASK"]);
+
+            let asked = challenge(&client, "X-ASK").await.unwrap();
+            let reply = asked.read_request().send().promise.await.unwrap();
+            match reply.get().unwrap().get_data().unwrap().which().unwrap() {
+                api::authentication::maybe_data::Some(d) => assert_eq!(d.unwrap(), b"who are you?"),
+                api::authentication::maybe_data::None(()) => panic!("no challenge data"),
+            }
+            assert!(respond(&asked, b"bob").await.unwrap());
+            assert_eq!(authzid(&client).await, strings("bob", ""));
+            // Every challenge can only be answered once
+            let e = respond(&asked, b"admin").await.err().unwrap();
+            assert_eq!(rpc::code(&e), "conflict");
+
+            let other = server.connect(&spawner).await;
+            let asked = challenge(&other, "X-ASK").await.unwrap();
+            assert!(!respond(&asked, b"mallory").await.unwrap());
+            assert_eq!(authzid(&other).await, strings("", ""));
+
+            let e = challenge(&other, "X-NONE").await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unimplemented");
+        });
+    }
+}
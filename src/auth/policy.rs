@@ -0,0 +1,133 @@
+//! Rules new passwords have to follow
+//!
+//! These are only checked when a password is set, never when somebody logs in, so existing
+//! passwords keep working after the rules got stricter.
+
+use std::fmt;
+
+use crate::config::PasswordPolicy;
+
+/// About a thousand of the most commonly used passwords, one per line and lowercase
+const COMMON: &str = include_str!("common-passwords.txt");
+
+/// A rule a password broke
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    TooShort { min: usize },
+    TooFewClasses { required: usize },
+    ContainsUsername,
+    Common,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::TooShort { min } => write!(f, "must be at least {} characters long", min),
+            Violation::TooFewClasses { required } => write!(f, "must use at least {} of \
+                lowercase letters, uppercase letters, digits and other characters", required),
+            Violation::ContainsUsername => write!(f, "must not contain the user name"),
+            Violation::Common => write!(f, "is too common"),
+        }
+    }
+}
+
+/// Every rule of `policy` that `password` for `user` breaks, empty if it's fine
+pub fn check(policy: &PasswordPolicy, user: &str, password: &str) -> Vec<Violation> {
+    let mut violations = Vec::new();
+
+    if password.chars().count() < policy.min_length {
+        violations.push(Violation::TooShort { min: policy.min_length });
+    }
+
+    let classes = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_numeric()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ];
+    if classes.iter().filter(|c| **c).count() < policy.require_classes {
+        violations.push(Violation::TooFewClasses { required: policy.require_classes });
+    }
+
+    let lower = password.to_lowercase();
+    if policy.deny_username && !user.is_empty() && lower.contains(&user.to_lowercase()) {
+        violations.push(Violation::ContainsUsername);
+    }
+    if policy.deny_common && COMMON.lines().any(|l| l == lower) {
+        violations.push(Violation::Common);
+    }
+
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> PasswordPolicy {
+        PasswordPolicy::default()
+    }
+
+    #[test]
+    fn good_passwords_pass() {
+        assert_eq!(check(&policy(), "alice", "Correct horse battery"), vec![]);
+        assert_eq!(check(&policy(), "alice", "correct-horse-battery"), vec![]);
+    }
+
+    #[test]
+    fn length() {
+        let v = check(&policy(), "alice", "Sh0rt!");
+        assert_eq!(v, vec![Violation::TooShort { min: 10 }]);
+        // Characters count, not bytes
+        assert_eq!(check(&policy(), "alice", "Grüße, Jürgen"), vec![]);
+        assert_eq!(check(&policy(), "alice", "Größe: äöü"), vec![]);
+        let v = check(&policy(), "alice", "äöüÄÖÜ1");
+        assert!(v.contains(&Violation::TooShort { min: 10 }));
+    }
+
+    #[test]
+    fn classes() {
+        let required = Violation::TooFewClasses { required: 2 };
+        assert_eq!(check(&policy(), "alice", "onlylowercaseletters"), vec![required]);
+        assert_eq!(check(&policy(), "alice", "ONLYUPPERCASELETTERS"), vec![required]);
+        assert_eq!(check(&policy(), "alice", "9182736450918"), vec![required]);
+
+        let mut strict = policy();
+        strict.require_classes = 4;
+        let v = check(&strict, "alice", "Lower and Upper");
+        assert_eq!(v, vec![Violation::TooFewClasses { required: 4 }]);
+        assert_eq!(check(&strict, "alice", "Lower, Upper and 1"), vec![]);
+    }
+
+    #[test]
+    fn username() {
+        let v = check(&policy(), "alice", "I am ALICE, 2020");
+        assert_eq!(v, vec![Violation::ContainsUsername]);
+
+        let mut lax = policy();
+        lax.deny_username = false;
+        assert_eq!(check(&lax, "alice", "I am ALICE, 2020"), vec![]);
+        // Nobody in particular
+        assert_eq!(check(&policy(), "", "Setting up, 2020"), vec![]);
+    }
+
+    #[test]
+    fn common() {
+        assert_eq!(check(&policy(), "alice", "Password123"), vec![Violation::Common]);
+        assert_eq!(check(&policy(), "alice", "Q1W2E3R4T5"), vec![Violation::Common]);
+
+        let mut lax = policy();
+        lax.deny_common = false;
+        assert_eq!(check(&lax, "alice", "Password123"), vec![]);
+    }
+
+    #[test]
+    fn every_violation_at_once() {
+        assert_eq!(check(&policy(), "1234", "1234"), vec![
+            Violation::TooShort { min: 10 },
+            Violation::TooFewClasses { required: 2 },
+            Violation::ContainsUsername,
+            Violation::Common,
+        ]);
+    }
+}
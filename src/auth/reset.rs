@@ -0,0 +1,220 @@
+//! Tokens an admin hands to a member who forgot their password, so they can set a new one
+//!
+//! Only a hash of each token is kept, so whoever gets to read the table can't use them. A token
+//! works once and only until it expires, and using one makes all others for the same user useless.
+//! Used and expired tokens are remembered for a while longer so clients can tell their users why
+//! a token didn't work, instead of just that it didn't.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+use sha2::{Digest, Sha256};
+
+use crate::api::error;
+use crate::error::{Result, WithPath};
+
+use super::setup;
+
+/// Seconds used and expired tokens are remembered after they expired
+const RETAIN: u64 = 7 * 24 * 3600;
+
+/// A token issued for `user`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reset {
+    /// SHA-256 of the token, in hex
+    hash: String,
+    pub user: String,
+    /// The admin who issued it
+    pub by: String,
+    /// In seconds since the UNIX epoch, like `expires`
+    pub issued: u64,
+    pub expires: u64,
+    /// Redeemed, or made useless by redeeming another token for the same user
+    #[serde(default)]
+    pub used: bool,
+}
+
+/// Why a token can't be redeemed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refused {
+    Unknown,
+    Expired,
+    Used,
+}
+
+impl Refused {
+    pub fn to_error(self) -> capnp::Error {
+        match self {
+            Refused::Unknown => error::no_such_token(),
+            Refused::Expired => error::token_expired(),
+            Refused::Used => error::token_used(),
+        }
+    }
+}
+
+/// How the table is stored, as a TOML array of tables
+#[derive(Default, Serialize, Deserialize)]
+struct Table {
+    #[serde(default, rename = "reset")]
+    resets: Vec<Reset>,
+}
+
+/// All tokens issued, saved to `path` after every change
+pub struct Resets {
+    /// Without a path password resets are disabled
+    path: Option<PathBuf>,
+    resets: Vec<Reset>,
+}
+
+impl Resets {
+    /// Load the table at `path`, which is created on the first token if it doesn't exist
+    pub fn open(path: &Path, now: u64) -> Result<Self> {
+        let resets = if path.exists() {
+            let content = fs::read_to_string(path)?;
+            let table: Table = toml::from_str(&content)?;
+            table.resets.into_iter().filter(|r| r.expires.saturating_add(RETAIN) > now).collect()
+        } else {
+            Vec::new()
+        };
+        Ok(Self { path: Some(path.to_path_buf()), resets })
+    }
+
+    /// No table, for when password resets aren't configured
+    pub fn disabled() -> Self {
+        Self { path: None, resets: Vec::new() }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    /// Issue a token letting `user` set a new password until `expires`, returning the token
+    pub fn create(&mut self, user: &str, by: &str, now: u64, expires: u64) -> Result<String> {
+        let token = setup::token()?;
+        self.forget(now);
+        self.resets.push(Reset {
+            hash: hash(&token),
+            user: user.to_string(),
+            by: by.to_string(),
+            issued: now,
+            expires,
+            used: false,
+        });
+        self.save()?;
+        Ok(token)
+    }
+
+    /// The reset `token` is for, if it can still be redeemed at `now`
+    pub fn check(&self, token: &str, now: u64) -> std::result::Result<&Reset, Refused> {
+        let hash = hash(token);
+        let reset = self.resets.iter().find(|r| r.hash == hash).ok_or(Refused::Unknown)?;
+        if reset.used {
+            Err(Refused::Used)
+        } else if reset.expires <= now {
+            Err(Refused::Expired)
+        } else {
+            Ok(reset)
+        }
+    }
+
+    /// Use up `token` and every other one for the same user, returning the reset it was for
+    ///
+    /// Checks the token again, so callers can check it first and only redeem it once whatever it
+    /// was for worked out. Tokens that expired already stay that way, so whoever tries one later
+    /// hears it expired rather than that it was used.
+    pub fn redeem(&mut self, token: &str, now: u64)
+        -> Result<std::result::Result<Reset, Refused>>
+    {
+        let reset = match self.check(token, now) {
+            Ok(r) => r.clone(),
+            Err(e) => return Ok(Err(e)),
+        };
+        for r in self.resets.iter_mut().filter(|r| r.user == reset.user && r.expires > now) {
+            r.used = true;
+        }
+        self.forget(now);
+        self.save()?;
+        Ok(Ok(reset))
+    }
+
+    /// Drop tokens that expired long enough ago nobody needs to be told anymore
+    fn forget(&mut self, now: u64) {
+        self.resets.retain(|r| r.expires.saturating_add(RETAIN) > now);
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = match self.path {
+            Some(ref p) => p,
+            None => return Ok(()),
+        };
+        // Same dance as for the temporary grants, so a crash never leaves half a table behind
+        let tmp = path.with_extension("tmp");
+        {
+            let mut fp = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600)
+                .open(&tmp).with_path(&tmp)?;
+            let toml = toml::to_string(&Table { resets: self.resets.clone() })?;
+            fp.write_all(toml.as_bytes()).with_path(&tmp)?;
+            fp.sync_all().with_path(&tmp)?;
+        }
+        fs::rename(&tmp, path).with_path(path)?;
+        Ok(())
+    }
+}
+
+/// SHA-256 of `token`, in hex
+fn hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn saved_for_us_only() {
+        let dir = TempDir::new();
+        let path = dir.join("resets.toml");
+        let mut resets = Resets::open(&path, 1_000_000).unwrap();
+        let token = resets.create("alice", "admin", 1_000_000, 1_003_600).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        // Only the hash is written
+        assert!(!fs::read_to_string(&path).unwrap().contains(&token));
+
+        let mut resets = Resets::open(&path, 1_000_100).unwrap();
+        assert_eq!(resets.redeem(&token, 1_000_100).unwrap().unwrap().user, "alice");
+        assert_eq!(resets.check(&token, 1_000_100), Err(Refused::Used));
+    }
+
+    #[test]
+    fn redeeming_cancels_the_others() {
+        let dir = TempDir::new();
+        let mut resets = Resets::open(&dir.join("resets.toml"), 1_000_000).unwrap();
+        let expired = resets.create("alice", "admin", 990_000, 999_000).unwrap();
+        let first = resets.create("alice", "admin", 1_000_000, 1_003_600).unwrap();
+        let second = resets.create("alice", "admin", 1_000_000, 1_003_600).unwrap();
+        let bob = resets.create("bob", "admin", 1_000_000, 1_003_600).unwrap();
+
+        assert_eq!(resets.check("nope", 1_000_100), Err(Refused::Unknown));
+        assert_eq!(resets.check(&expired, 1_000_100), Err(Refused::Expired));
+        let reset = resets.redeem(&second, 1_000_100).unwrap().unwrap();
+        assert_eq!((reset.user.as_str(), reset.by.as_str()), ("alice", "admin"));
+        assert_eq!(resets.check(&first, 1_000_100), Err(Refused::Used));
+        assert_eq!(resets.redeem(&second, 1_000_100).unwrap(), Err(Refused::Used));
+        assert_eq!(resets.check(&expired, 1_000_100), Err(Refused::Expired));
+        assert!(resets.check(&bob, 1_000_100).is_ok());
+        assert_eq!(resets.check(&bob, 1_003_600), Err(Refused::Expired));
+
+        // Told apart for a week after they expired, then forgotten
+        let mut resets = Resets::open(&dir.join("resets.toml"), 1_003_600 + RETAIN).unwrap();
+        assert_eq!(resets.check(&first, 1_003_600 + RETAIN), Err(Refused::Unknown));
+        resets.create("bob", "admin", 1_003_600 + RETAIN, 1_007_200 + RETAIN).unwrap();
+        assert_eq!(resets.resets.len(), 1);
+    }
+}
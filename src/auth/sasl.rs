@@ -0,0 +1,129 @@
+//! SASL mechanisms and the state of an exchange using one
+//!
+//! Every mechanism implements `Mechanism` and is registered with the `AuthenticationProvider`,
+//! which is what `availableMechanisms` lists and `initializeAuthentication` looks mechanisms up
+//! in. PLAIN is registered by default, modules can register more from their `init`.
+//!
+//! Mechanisms are shared by all connections, so whatever an exchange needs to remember between
+//! two steps goes into its `Session` instead of the mechanism.
+
+use std::any::Any;
+
+use super::{AuthenticationProvider, SASLError};
+
+/// The state of one exchange
+#[derive(Default)]
+pub struct Session {
+    /// The identity to act as, once the mechanism knows it
+    pub authzid: Option<String>,
+    /// The identity that authenticated, if that's not the same as `authzid`
+    pub authcid: Option<String>,
+    /// Whatever the mechanism needs between two steps
+    pub state: Option<Box<dyn Any + Send>>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Where an exchange is at after a step
+pub enum StepResult {
+    /// The client has to respond to this before the exchange can continue
+    Challenge(Option<Vec<u8>>),
+    /// The exchange is over, authenticating as the `authzid` of the session if granted
+    Done(bool),
+    /// The client sent something that makes no sense for the mechanism
+    Invalid(SASLError),
+}
+
+/// A way for clients to authenticate
+///
+/// Both steps run on the thread pool, so checking credentials may take its time.
+pub trait Mechanism: Send + Sync {
+    /// Name the mechanism is offered under, e.g. "PLAIN"
+    fn name(&self) -> &'static str;
+
+    /// Whether the client sends its password, readable by anyone listening on the connection. Such
+    /// mechanisms aren't offered on unencrypted connections if `api.require_tls` is set.
+    fn sends_password(&self) -> bool {
+        false
+    }
+
+    /// Start an exchange with the data the client sent along, if any
+    fn start(&self, provider: &AuthenticationProvider, initial: Option<&[u8]>,
+        session: &mut Session) -> StepResult;
+
+    /// Continue an exchange with the client's response to the last challenge
+    fn step(&self, provider: &AuthenticationProvider, response: Option<&[u8]>,
+        session: &mut Session) -> StepResult;
+}
+
+/// RFC 4616, the password sent in the clear
+pub struct Plain;
+
+impl Plain {
+    /// Whether the exchange succeeded, the identity to act as and the one that authenticated
+    fn check<'a>(provider: &AuthenticationProvider, data: &'a [u8])
+        -> Result<(bool, &'a str, &'a str), SASLError>
+    {
+        let data = std::str::from_utf8(data).map_err(|_| SASLError::UTF8)?;
+        let (authzid, authcid, passwd) = split_nul(data).ok_or(SASLError::BadChallenge)?;
+
+        // FIXME: At least use hashes
+        if !provider.check_password(authcid, passwd) {
+            return Ok((false, authzid, authcid));
+        }
+        // authzid is the Identity the user wants to act as. If that is unset, shortcut to Success
+        if authzid == "" || authzid == authcid {
+            return Ok((true, authcid, authcid));
+        }
+        let granted = provider.may_act_as(authcid, authzid)?;
+        Ok((granted, authzid, authcid))
+    }
+}
+
+impl Mechanism for Plain {
+    fn name(&self) -> &'static str {
+        "PLAIN"
+    }
+
+    fn sends_password(&self) -> bool {
+        true
+    }
+
+    fn start(&self, provider: &AuthenticationProvider, initial: Option<&[u8]>,
+        session: &mut Session) -> StepResult
+    {
+        let data = match initial {
+            Some(d) => d,
+            None => return StepResult::Invalid(SASLError::MissingData),
+        };
+        match Self::check(provider, data) {
+            Ok((granted, authzid, authcid)) => {
+                session.authzid = Some(authzid.to_string());
+                session.authcid = Some(authcid.to_string());
+                StepResult::Done(granted)
+            },
+            Err(e) => StepResult::Invalid(e),
+        }
+    }
+
+    fn step(&self, _provider: &AuthenticationProvider, _response: Option<&[u8]>,
+        _session: &mut Session) -> StepResult
+    {
+        // Everything is in the initial data, there's never a challenge to respond to
+        StepResult::Invalid(SASLError::BadChallenge)
+    }
+}
+
+pub fn split_nul(string: &str) -> Option<(&str, &str, &str)> {
+    let mut i = string.split(|b| b == '\0');
+
+    let a = i.next()?;
+    let b = i.next()?;
+    let c = i.next()?;
+
+    Some((a,b,c))
+}
@@ -0,0 +1,204 @@
+//! Setting up a fresh install over the API, before anybody could log in
+//!
+//! Without users and policy rules nobody can log in, let alone configure anything. So if both are
+//! empty when we start we make up a token and log it, and whoever brings it may create the first
+//! admin with `claimInstance`. It's also written to `setup-token` next to the password database,
+//! for when the log goes somewhere only we can read yet. The token works once and only for
+//! `auth.setup_expiry` seconds, a restart makes a new one. Once there's an admin there are users
+//! and rules, so later starts don't offer any of this anymore.
+
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::config::{Config, PasswordPolicy};
+use crate::error::{Result, WithPath};
+
+/// Bytes of randomness in a token
+const TOKEN_BYTES: usize = 16;
+
+struct Pending {
+    token: String,
+    /// In seconds since the UNIX epoch
+    expires: u64,
+}
+
+/// Whether an install can still be claimed, shared by all connections
+#[derive(Clone)]
+pub struct Setup {
+    pending: Rc<RefCell<Option<Pending>>>,
+    /// Where the first admin is written to
+    pub passdb: Rc<PathBuf>,
+    pub policy: Rc<PathBuf>,
+    /// Where the token is written to while it works
+    pub token_file: Rc<PathBuf>,
+    /// What their password has to look like
+    pub rules: Rc<PasswordPolicy>,
+}
+
+impl Setup {
+    /// Nothing to claim until `offer` is called
+    pub fn new(config: &Config) -> Self {
+        Self {
+            pending: Rc::new(RefCell::new(None)),
+            passdb: Rc::new(config.passdb.clone()),
+            policy: Rc::new(config.access.policy.clone()),
+            token_file: Rc::new(config.passdb.with_file_name("setup-token")),
+            rules: Rc::new(config.auth.policy.clone()),
+        }
+    }
+
+    /// Let the install be claimed with a new token until `expires`, returning the token
+    ///
+    /// The token is written to `token_file`, which only we may read.
+    pub fn offer(&self, expires: u64) -> io::Result<String> {
+        let token = token()?;
+        let mut fp = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600)
+            .open(&*self.token_file)?;
+        fp.write_all(format!("{}\n", token).as_bytes())?;
+        *self.pending.borrow_mut() = Some(Pending { token: token.clone(), expires });
+        Ok(token)
+    }
+
+    /// Whether the install can be claimed at `now`
+    pub fn pending(&self, now: u64) -> bool {
+        self.pending.borrow().as_ref().map_or(false, |p| p.expires > now)
+    }
+
+    /// Whether `token` would claim the install at `now`
+    pub fn accepts(&self, token: &str, now: u64) -> bool {
+        match *self.pending.borrow() {
+            Some(ref p) => p.expires > now && same(p.token.as_bytes(), token.as_bytes()),
+            None => false,
+        }
+    }
+
+    /// Use the token up, so nobody can claim the install again
+    pub fn close(&self) {
+        if self.pending.borrow_mut().take().is_some() {
+            // It's no good to anybody anymore, so failing to remove it is fine
+            let _ = fs::remove_file(&*self.token_file);
+        }
+    }
+}
+
+/// Compare without giving away through timing how much of `b` was right
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |d, (x, y)| d | (x ^ y)) == 0
+}
+
+/// A new random token, in hex
+pub fn token() -> io::Result<String> {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Whether `name` can be used as a user name. It ends up in the policy, which is CSV.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(|c: char| c == ',' || c.is_whitespace() || c.is_control())
+}
+
+/// Rules giving `admin` full rights on the server and on the machines with the perms `objects`
+///
+/// Machines added later need rules of their own, same as for everybody else.
+pub fn admin_rules(admin: &str, objects: &[String]) -> Vec<[String; 3]> {
+    let rule = |object: &str, action: &str| [admin.to_string(), object.to_string(),
+        action.to_string()];
+    let mut rules = vec![rule("server", "admin"), rule("machines", "admin")];
+    for object in objects {
+        for action in &["read", "write", "manage"] {
+            rules.push(rule(object, action));
+        }
+    }
+    rules
+}
+
+/// Add `rules` to the end of the policy at `path`, which is created if it doesn't exist
+pub fn append_policy(path: &Path, rules: &[[String; 3]]) -> Result<()> {
+    let lines: Vec<String> = rules.iter()
+        .map(|[subject, object, action]| format!("p, {}, {}, {}", subject, object, action))
+        .collect();
+    append_lines(path, &lines)
+}
+
+/// Assign roles to users at the end of the policy at `path`, as `[user, role]`
+pub fn append_roles(path: &Path, roles: &[[String; 2]]) -> Result<()> {
+    let lines: Vec<String> = roles.iter()
+        .map(|[user, role]| format!("g, {}, {}", user, role))
+        .collect();
+    append_lines(path, &lines)
+}
+
+fn append_lines(path: &Path, lines: &[String]) -> Result<()> {
+    let mut policy = if path.exists() {
+        fs::read_to_string(path).with_path(path)?
+    } else {
+        String::new()
+    };
+    if !policy.is_empty() && !policy.ends_with('\n') {
+        policy.push('\n');
+    }
+    for line in lines {
+        policy.push_str(line);
+        policy.push('\n');
+    }
+
+    // Same dance as for the machine database, so a crash never leaves half a policy behind. A
+    // new policy is only for us, an existing one keeps whatever mode the admin gave it.
+    let tmp = path.with_extension("tmp");
+    {
+        let mut fp = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600)
+            .open(&tmp).with_path(&tmp)?;
+        if let Ok(meta) = fs::metadata(path) {
+            let mode = meta.permissions().mode() & 0o7777;
+            fp.set_permissions(fs::Permissions::from_mode(mode)).with_path(&tmp)?;
+        }
+        fp.write_all(policy.as_bytes()).with_path(&tmp)?;
+        fp.sync_all().with_path(&tmp)?;
+    }
+    fs::rename(&tmp, path).with_path(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn new_files_are_only_for_us() {
+        let dir = TempDir::new();
+        let mut config = Config::default();
+        config.passdb = dir.join("passwd.toml");
+        config.access.policy = dir.join("policy.csv");
+        let setup = Setup::new(&config);
+
+        let token = setup.offer(u64::MAX).unwrap();
+        assert_eq!(fs::read_to_string(&*setup.token_file).unwrap(), format!("{}\n", token));
+        assert_eq!(mode(&setup.token_file), 0o600);
+
+        append_policy(&config.access.policy, &admin_rules("admin", &[])).unwrap();
+        assert_eq!(mode(&config.access.policy), 0o600);
+    }
+
+    #[test]
+    fn appending_keeps_the_mode() {
+        let dir = TempDir::new();
+        let policy = dir.join("policy.csv");
+        fs::write(&policy, "p, role:admin, server, admin").unwrap();
+        fs::set_permissions(&policy, fs::Permissions::from_mode(0o640)).unwrap();
+
+        append_roles(&policy, &[["alice".to_string(), "role:admin".to_string()]]).unwrap();
+        assert_eq!(mode(&policy), 0o640);
+        assert_eq!(fs::read_to_string(&policy).unwrap(),
+            "p, role:admin, server, admin\ng, alice, role:admin\n");
+    }
+}
@@ -0,0 +1,64 @@
+//! What we know about users besides their password, like how to reach them
+//!
+//! Kept apart from the password database so reading contact details never means handling
+//! passwords. Users don't have to have an entry, and a missing file is as good as an empty one.
+
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use serde::{Serialize, Deserialize};
+
+use crate::error::{Result, WithPath};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    /// Matrix user id, like `@alice:example.org`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matrix: Option<String>,
+}
+
+impl UserInfo {
+    pub fn is_empty(&self) -> bool {
+        self.email.is_none() && self.matrix.is_none()
+    }
+}
+
+/// Users by the name they log in with
+pub type UserDB = BTreeMap<String, UserInfo>;
+
+pub fn open_userdb(path: &Path) -> Result<UserDB> {
+    if !path.exists() {
+        return Ok(UserDB::new());
+    }
+    let content = fs::read_to_string(path).with_path(path)?;
+    Ok(toml::from_str(&content)?)
+}
+
+/// What we know about `user`, nothing if there is no database or it has no entry for them
+pub fn lookup(path: Option<&Path>, user: &str) -> Result<UserInfo> {
+    match path {
+        Some(path) => Ok(open_userdb(path)?.remove(user).unwrap_or_default()),
+        None => Ok(UserInfo::default()),
+    }
+}
+
+/// Replace the database at `path` with `userdb`
+///
+/// Written like the password database, since contact details aren't for everybody either.
+pub fn save_userdb(path: &Path, userdb: &UserDB) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut fp = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600)
+            .open(&tmp).with_path(&tmp)?;
+        let toml = toml::to_string(userdb)?;
+        fp.write_all(toml.as_bytes()).with_path(&tmp)?;
+        fp.sync_all().with_path(&tmp)?;
+    }
+    fs::rename(&tmp, path).with_path(path)?;
+    Ok(())
+}
@@ -0,0 +1,52 @@
+//! Cards members identify themselves with at card readers
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+
+/// Which card belongs to whom
+pub struct Cards {
+    /// User by normalized UID
+    users: HashMap<String, String>,
+}
+
+impl Cards {
+    /// Load the card store, a TOML file mapping UIDs to user names
+    pub fn open(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let map: HashMap<String, String> = toml::from_str(&content)?;
+
+        let mut users = HashMap::new();
+        for (uid, user) in map {
+            match normalize(&uid) {
+                Some(n) => { users.insert(n, user); },
+                None => return Err(Error::Config(format!("{} is not a valid card UID", uid))),
+            }
+        }
+        Ok(Self { users })
+    }
+
+    /// The user a card belongs to
+    pub fn lookup(&self, uid: &str) -> Option<&str> {
+        normalize(uid).and_then(|n| self.users.get(&n)).map(|u| u.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+}
+
+/// Readers differ in how they write UIDs, `04:a2:24` and `04A224` are the same card
+fn normalize(uid: &str) -> Option<String> {
+    let n: String = uid.chars()
+        .filter(|c| !matches!(c, ':' | '-' | ' '))
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if !n.is_empty() && n.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(n)
+    } else {
+        None
+    }
+}
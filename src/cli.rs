@@ -0,0 +1,616 @@
+//! Offline administration subcommands
+//!
+//! These operate directly on the configured database files instead of going through the API, so
+//! they are useful for bootstrapping a fresh install.
+
+use std::io::{self, Write};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use clap::ArgMatches;
+
+use futures::FutureExt;
+use futures::executor::{LocalPool, LocalSpawner};
+use futures::task::LocalSpawnExt;
+
+use async_std::future::timeout;
+use async_std::net::TcpStream;
+use async_std::os::unix::net::UnixStream;
+
+use capnp_rpc::RpcSystem;
+use capnp_rpc::twoparty::VatNetwork;
+use capnp_rpc::rpc_twoparty_capnp::Side;
+
+use uuid::Uuid;
+
+use casbin::MgmtApi;
+
+use crate::access::{self, reconcile::Report};
+use crate::api::api::{diflouroborane, server_info};
+use crate::auth;
+use crate::config::{self, Config, Listen, ListenKind};
+use crate::error::{Result, WithPath};
+use crate::listen::Socket;
+use crate::machine::{self, Machine, MachineDB, Entries};
+use crate::machine::location::{self, Location};
+use crate::machine::store::MachineStore;
+use crate::privileges;
+use crate::stats;
+
+mod import;
+mod status;
+pub use import::column_arg;
+pub use status::{status, Format};
+
+/// Load and validate the config file at `path`, printing every problem found
+///
+/// Exits with 0 if the config is fine and 1 otherwise. Files others could tamper with count as
+/// problems too, unless `insecure_ok`.
+pub fn check(path: &Path, insecure_ok: bool) {
+    let config = match config::read(path) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("{}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let problems = config.validate();
+    for p in problems.iter() {
+        println!("{}: {}", path.display(), p);
+    }
+    for key in config.unknown.iter() {
+        println!("{}: {}: unknown key, ignored", path.display(), key);
+    }
+    let exposed = privileges::check_files(&config, path);
+    for e in exposed.iter() {
+        println!("{}: {}", path.display(), e);
+    }
+    let exposed = !insecure_ok && exposed.iter().any(|e| e.fatal);
+    // Derived perms are easy to get wrong without noticing, so show what they came out as
+    for (uuid, perm) in config.derived_perms() {
+        println!("{}: machine {} uses derived perm {}", path.display(), uuid.to_hyphenated(), perm);
+    }
+
+    // Only worth looking at the policy with a config that points at the right files
+    let mut mismatched = false;
+    if problems.is_empty() {
+        match futures::executor::block_on(policy_report(&config)) {
+            Ok(report) => {
+                for line in report.lines() {
+                    println!("{}: {}", path.display(), line);
+                }
+                mismatched = config.access.strict && !report.is_empty();
+            },
+            Err(e) => println!("{}: could not compare the policy with the machines: {}",
+                path.display(), e),
+        }
+    }
+
+    if problems.is_empty() && !mismatched && !exposed {
+        println!("{}: OK", path.display());
+        std::process::exit(0);
+    } else {
+        std::process::exit(1);
+    }
+}
+
+/// Compare the configured policy with the machines in the database and the config file
+async fn policy_report(config: &Config) -> Result<Report> {
+    let mdb = machine::store::open(config)?;
+    let template = config.machines.perm_template.as_deref();
+    let mut perms: BTreeMap<Uuid, String> = mdb.iter()
+        .map(|(uuid, m)| {
+            let mut m = m.clone();
+            m.derive_perm(uuid, template);
+            (*uuid, m.perm().to_string())
+        })
+        .collect();
+    // Merged into the database on startup, overriding what it has
+    for m in config.inline_machines.iter().filter(|m| !m.perm.is_empty()) {
+        perms.insert(m.uuid, m.perm.clone());
+    }
+    perms.extend(config.derived_perms());
+
+    let pdb = access::load_policy(config).await?;
+    let perms: Vec<_> = perms.into_iter().collect();
+    Ok(Report::new(&perms, &pdb.get_policy()))
+}
+
+/// How long the health check waits for an answer before declaring the server unhealthy
+const HEALTHCHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Take the lock a running daemon holds on the machine database, exiting if it's taken
+///
+/// Refuse to touch the databases while somebody else -- most likely a running daemon -- holds it.
+/// Otherwise one of us would overwrite the changes of the other.
+fn lock(config: &Config) -> Result<File> {
+    match machine::lock(&config.machinedb) {
+        Ok(l) => Ok(l),
+        Err(crate::error::Error::IO(e)) if e.kind() == io::ErrorKind::WouldBlock => {
+            eprintln!("Machine database {} is locked. Is the daemon running?",
+                config.machinedb.display());
+            std::process::exit(1);
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Dispatch the `machine` subcommand
+pub fn machine(config: &Config, matches: &ArgMatches) -> Result<()> {
+    let _lock = lock(config)?;
+
+    let mut mdb = machine::store::open(config)?;
+    if let Some(upgrade) = mdb.upgraded_from() {
+        eprintln!("Upgrading machine database from format version {} to {}, the original is kept \
+            as {}", upgrade.from, upgrade.to, upgrade.backup.display());
+    }
+    // Uses and givebacks the daemon didn't get to save before it stopped
+    let journal = machine::journal::path(&config.machinedb);
+    let replay = machine::journal::recover(mdb.as_mut(), &journal, true)?;
+    if !replay.transitions.is_empty() {
+        eprintln!("Saved {} machine state changes left in the journal {}",
+            replay.transitions.len(), journal.display());
+    }
+
+    match matches.subcommand() {
+        ("add", Some(m)) => {
+            // Both are required arguments so clap already made sure they're there
+            let name = m.value_of("name").unwrap().to_string();
+            let location = m.value_of("location").unwrap().to_string();
+            let perm = m.value_of("perm").unwrap_or_default().to_string();
+            if perm.is_empty() && config.machines.perm_template.is_none() {
+                eprintln!("--perm is required unless machines.perm_template is set");
+                std::process::exit(1);
+            }
+
+            let (location, added) = location::adopt(mdb.locations_mut(), &location);
+            if added {
+                eprintln!("Added location {}", location);
+            }
+            let uuid = Uuid::new_v4();
+            mdb.insert(uuid, Machine::new(name, location, perm));
+            mdb.flush()?;
+
+            println!("{}", uuid.to_hyphenated());
+        },
+        ("list", Some(_)) => {
+            let mut machines: Vec<_> = mdb.iter().collect();
+            machines.sort_by(|(_, a), (_, b)| a.name.cmp(&b.name));
+
+            let template = config.machines.perm_template.as_deref();
+            for (uuid, m) in machines {
+                let location = mdb.locations().get(&m.location).map_or(&m.location, |l| &l.name);
+                let mut m = m.clone();
+                m.derive_perm(uuid, template);
+                println!("{}\t{}\t{}\t{}\t{:?}", uuid.to_hyphenated(), m.name, location,
+                    m.perm(), m.status);
+            }
+        },
+        ("location", Some(m)) => set_location(mdb.as_mut(), m)?,
+        ("remove", Some(m)) => {
+            // The validator on the argument already ensures this is a valid UUID
+            let uuid = Uuid::parse_str(m.value_of("uuid").unwrap()).unwrap();
+
+            if let Some(m) = mdb.remove(&uuid) {
+                mdb.flush()?;
+                println!("Removed machine {} ({})", uuid.to_hyphenated(), m.name);
+            } else {
+                eprintln!("No machine with UUID {}", uuid.to_hyphenated());
+                std::process::exit(1);
+            }
+        },
+        ("export", Some(m)) => {
+            let encoded = export(mdb.iter(), m.value_of("format").unwrap())?;
+
+            let stdout = io::stdout();
+            let mut handle = stdout.lock();
+            handle.write_all(encoded.as_bytes())?;
+        },
+        ("import", Some(m)) => {
+            let content = fs::read_to_string(m.value_of("file").unwrap())?;
+            let imported = parse_export(&content, m.value_of("format").unwrap())?;
+            let count = imported.len();
+
+            if m.is_present("replace") {
+                mdb.clear();
+            }
+            for (uuid, machine) in imported {
+                mdb.insert(uuid, machine);
+            }
+            // Exports only have the ids of locations, or names if they're older than locations
+            machine::store::adopt_locations(mdb.as_mut());
+            // Merging may still produce e.g. duplicate names between old and new entries. Since we
+            // haven't flushed yet bailing out here leaves the database on disk untouched.
+            machine::validate(mdb.iter())?;
+
+            mdb.flush()?;
+            println!("Imported {} machines, database now contains {}", count, mdb.len());
+        },
+        ("migrate", Some(m)) => {
+            // The TOML loader validates the old database the same way as an import
+            let (old, locations, _) = machine::load(Path::new(m.value_of("file").unwrap()))?;
+            let count = old.len();
+
+            for (id, location) in locations {
+                mdb.locations_mut().entry(id).or_insert(location);
+            }
+            for (uuid, machine) in old {
+                mdb.insert(uuid, machine);
+            }
+            machine::store::adopt_locations(mdb.as_mut());
+            machine::validate(mdb.iter())?;
+
+            mdb.flush()?;
+            println!("Migrated {} machines into {}", count, config.machinedb.display());
+        },
+        // clap enforces that one of the subcommands above is given
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+/// `machines` as `format`, either "json" or "toml"
+fn export<'a>(machines: impl Iterator<Item=(&'a Uuid, &'a Machine)>, format: &str)
+    -> Result<String>
+{
+    // Sort by UUID so repeated exports of the same database are identical
+    let sorted: BTreeMap<_, _> = machines.collect();
+
+    Ok(match format {
+        "json" => serde_json::to_string_pretty(&sorted)?,
+        _ => toml::to_string(&sorted)?,
+    })
+}
+
+/// Read machines written by `export`
+fn parse_export(content: &str, format: &str) -> Result<MachineDB> {
+    // Parsing into `Entries` runs the same validation the normal loader does
+    let entries: Entries = match format {
+        "json" => serde_json::from_str(content)?,
+        _ => toml::from_str(content)?,
+    };
+    Ok(entries.into_db()?)
+}
+
+/// Add or change the location given by `m`, or list all of them
+fn set_location(mdb: &mut dyn MachineStore, m: &ArgMatches) -> Result<()> {
+    let id = match m.value_of("id") {
+        Some(id) => id,
+        None => {
+            for (id, l) in mdb.locations().iter() {
+                let machines = mdb.iter().filter(|(_, m)| &m.location == id).count();
+                println!("{}\t{}\t{}\t{} machines", id, l.name, l.perm.as_deref().unwrap_or("-"),
+                    machines);
+            }
+            return Ok(());
+        }
+    };
+    if location::id(id) != id {
+        eprintln!("Location ids may only contain lowercase letters, digits, '-' and '_'");
+        std::process::exit(1);
+    }
+    let perm = match m.value_of("perm") {
+        Some("") => Some(None),
+        Some(p) if machine::valid_perm(p) => Some(Some(p.to_string())),
+        Some(p) => {
+            eprintln!("\"{}\" is not a valid permission object", p);
+            std::process::exit(1);
+        },
+        None => None,
+    };
+
+    let location = mdb.locations_mut().entry(id.to_string())
+        .or_insert_with(|| Location { name: id.to_string(), perm: None });
+    if let Some(name) = m.value_of("name") {
+        location.name = name.trim().to_string();
+    }
+    if let Some(perm) = perm {
+        location.perm = perm;
+    }
+    println!("{}\t{}\t{}", id, location.name, location.perm.as_deref().unwrap_or("-"));
+    mdb.flush()
+}
+
+/// Dispatch the `user` subcommand
+///
+/// Passwords are read from the first line of stdin so they don't show up in the process list. A
+/// running daemon only sees the change after a restart.
+pub fn user(config: &Config, matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        ("contact", Some(m)) => return contact(config, m),
+        ("import", Some(m)) => return import::users(config, m),
+        _ => {},
+    }
+
+    let mut passdb = if config.passdb.exists() {
+        auth::open_passdb(&config.passdb).with_path(&config.passdb)?
+    } else {
+        auth::PassDB::new()
+    };
+
+    let (name, m) = matches.subcommand();
+    // The name is a required argument of all subcommands
+    let user = m.and_then(|m| m.value_of("name")).unwrap_or_default().to_string();
+    match name {
+        "add" if passdb.contains_key(&user) => {
+            eprintln!("User {} exists already", user);
+            std::process::exit(1);
+        },
+        "passwd" if !passdb.contains_key(&user) => {
+            eprintln!("No user {}", user);
+            std::process::exit(1);
+        },
+        "add" | "passwd" => {},
+        _ => unreachable!(),
+    }
+
+    let mut password = String::new();
+    io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(|c| c == '\n' || c == '\r');
+
+    let violations = auth::policy::check(&config.auth.policy, &user, password);
+    if !violations.is_empty() {
+        for v in violations {
+            eprintln!("Password {}", v);
+        }
+        std::process::exit(1);
+    }
+
+    passdb.insert(user, password.to_string());
+    auth::save_passdb(&config.passdb, &passdb)
+}
+
+/// Set the contact details of a user in `auth.users`
+fn contact(config: &Config, m: &ArgMatches) -> Result<()> {
+    let path = match config.auth.users {
+        Some(ref p) => p,
+        None => {
+            eprintln!("auth.users is not set");
+            std::process::exit(1);
+        }
+    };
+    // A required argument
+    let user = m.value_of("name").unwrap_or_default();
+    // Opening a missing password database would create one
+    let known = config.passdb.exists()
+        && auth::open_passdb(&config.passdb).with_path(&config.passdb)?.contains_key(user);
+    if !known {
+        eprintln!("No user {}", user);
+        std::process::exit(1);
+    }
+
+    let mut userdb = auth::users::open_userdb(path)?;
+    let info = userdb.entry(user.to_string()).or_default();
+    let set = |field: &mut Option<String>, value: Option<&str>| match value {
+        Some("") => *field = None,
+        Some(v) => *field = Some(v.to_string()),
+        None => {},
+    };
+    set(&mut info.email, m.value_of("email"));
+    set(&mut info.matrix, m.value_of("matrix"));
+    if info.is_empty() {
+        userdb.remove(user);
+    }
+    auth::users::save_userdb(path, &userdb)
+}
+
+/// Print the statistics kept in `stats.path`
+pub fn stats(config: &Config, m: &ArgMatches) -> Result<()> {
+    let path = match config.stats.path {
+        Some(ref p) => p,
+        None => {
+            eprintln!("stats.path is not set");
+            std::process::exit(1);
+        }
+    };
+    let day = |name: &str| match stats::parse_day(m.value_of(name).unwrap_or_default()) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let (from, to) = (day("from"), day("to"));
+    let totals = stats::Store::open(path)?.totals(from.as_ref(), to.as_ref());
+
+    if m.value_of("format") == Some("json") {
+        let machines: Vec<_> = totals.machines.iter().map(|(uuid, c)| serde_json::json!({
+            "uuid": uuid.to_hyphenated().to_string(),
+            "sessions": c.sessions,
+            "occupied_secs": c.occupied_secs,
+        })).collect();
+        println!("{}", serde_json::json!({
+            "first": totals.first,
+            "last": totals.last,
+            "authentications": totals.authentications,
+            "failed_authentications": totals.failed_authentications,
+            "unique_users": totals.unique_users,
+            "machines": machines,
+        }));
+        return Ok(());
+    }
+
+    match (&totals.first, &totals.last) {
+        (Some(first), Some(last)) => println!("{} to {}", first, last),
+        _ => println!("Nothing counted"),
+    }
+    println!("{} logins, {} failed", totals.authentications, totals.failed_authentications);
+    println!("{} users used machines", totals.unique_users);
+    // Busiest first. Names are left out, the machine database may be held by the running server.
+    for (uuid, c) in totals.machines.iter() {
+        println!("{}  {:>6} uses  {:>8.1}h", uuid.to_hyphenated(), c.sessions,
+            c.occupied_secs as f64 / 3600.0);
+    }
+    Ok(())
+}
+
+/// Send a test mail to `to` with the settings in `[modules.email]`
+#[cfg(feature = "email")]
+pub fn test_email(config: &Config, to: &str) -> Result<()> {
+    futures::executor::block_on(crate::modules::email::send_test(config, to))?;
+    println!("Sent a test mail to {}", to);
+    Ok(())
+}
+
+#[cfg(not(feature = "email"))]
+pub fn test_email(_config: &Config, _to: &str) -> Result<()> {
+    eprintln!("diflouroborane was built without the email feature");
+    std::process::exit(1);
+}
+
+/// Ask the daemon listening on the first configured address how it's doing
+///
+/// WebSocket addresses are skipped, we only speak the raw protocol here. Exits with 0 if the
+/// server answered and the last database save worked, 1 otherwise.
+pub fn healthcheck(config: &Config) -> Result<()> {
+    let listen = match raw_listen(config) {
+        Some(l) => l,
+        None => {
+            eprintln!("No listen address configured");
+            std::process::exit(1);
+        }
+    };
+
+    let mut exec = LocalPool::new();
+    let spawner = exec.spawner();
+
+    let check = async {
+        let client = bootstrap(listen, &spawner).await?;
+
+        let reply = client.get_server_info_request().send().promise.await?;
+        let info = reply.get()?.get_info()?;
+
+        let mqtt = match info.get_mqtt()? {
+            server_info::Bridge::Disabled => "disabled",
+            server_info::Bridge::Down => "DOWN",
+            server_info::Bridge::Up => "up",
+        };
+        println!("version {}, up {}s, {} machines, {} connections, last save {}, mqtt {}",
+            info.get_version()?, info.get_uptime(), info.get_machines(), info.get_connections(),
+            if info.get_last_save_ok() { "ok" } else { "FAILED" }, mqtt);
+
+        Ok::<_, Box<dyn std::error::Error>>(info.get_last_save_ok())
+    };
+
+    let healthy = match exec.run_until(timeout(HEALTHCHECK_TIMEOUT, check)) {
+        Ok(Ok(healthy)) => healthy,
+        Ok(Err(e)) => {
+            eprintln!("Health check on {} failed: {}", listen, e);
+            false
+        },
+        Err(_) => {
+            eprintln!("Health check on {} timed out", listen);
+            false
+        },
+    };
+
+    std::process::exit(if healthy { 0 } else { 1 });
+}
+
+/// The first configured address we can talk to the server on
+///
+/// WebSocket addresses are skipped, we only speak the raw protocol here.
+fn raw_listen(config: &Config) -> Option<&Listen> {
+    let raw = |l: &&Listen| match l {
+        Listen::Tcp { kind, .. } => *kind == ListenKind::Raw,
+        Listen::Unix { .. } => true,
+    };
+    config.listen.iter().find(raw)
+}
+
+/// Connect to the server at `listen`, running the RPC system on `spawner`
+async fn bootstrap(listen: &Listen, spawner: &LocalSpawner)
+    -> std::result::Result<diflouroborane::Client, Box<dyn std::error::Error>>
+{
+    let socket = connect(listen).await?;
+    let netw = VatNetwork::new(socket.clone(), socket, Side::Client, Default::default());
+    let mut rpc = RpcSystem::new(Box::new(netw), None);
+    let client: diflouroborane::Client = rpc.bootstrap(Side::Server);
+    spawner.spawn_local(rpc.map(|_| ()))?;
+    Ok(client)
+}
+
+/// Open a connection to a configured listen address
+async fn connect(listen: &Listen) -> io::Result<Socket> {
+    match listen {
+        Listen::Tcp { address, port, .. } => {
+            let port = port.unwrap_or(config::DEFAULT_PORT);
+            // A server listening on all addresses is reachable via loopback
+            let address = match address.parse::<IpAddr>() {
+                Ok(IpAddr::V4(a)) if a.is_unspecified() => "127.0.0.1".to_string(),
+                Ok(IpAddr::V6(a)) if a.is_unspecified() => "::1".to_string(),
+                _ => address.clone(),
+            };
+            Ok(Socket::Tcp(TcpStream::connect((address.as_str(), port)).await?))
+        },
+        Listen::Unix { path, .. } => Ok(Socket::Unix(UnixStream::connect(path).await?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::machine::{ScheduledBlock, Status};
+
+    /// A machine with every optional field set
+    fn full_machine() -> Machine {
+        let mut m = Machine::new("Laser Cutter".to_string(), "wood-workshop".to_string(),
+            "lab.laser".to_string());
+        m.status = Status::Occupied;
+        m.since = Some(1_600_000_000);
+        m.occupant = Some("alice".to_string());
+        m.grant = Some(Uuid::from_u128(7));
+        m.note = Some("parts for the door sensor".to_string());
+        m.block_reason = Some("waiting for a new lens".to_string());
+        m.giveback_at_close = true;
+        m.allowed_hours = vec![serde_json::from_value(serde_json::json!({
+            "days": ["Mon-Fri", "Sun"], "from": "10:00", "to": "22:00",
+        })).unwrap()];
+        m.schedule = vec![ScheduledBlock {
+            start: 1_700_000_000,
+            end: 1_700_003_600,
+            reason: "maintenance".to_string(),
+            by: "admin".to_string(),
+            applied: true,
+        }];
+        m
+    }
+
+    fn round_trip(format: &str) {
+        let mut mdb = MachineDB::new();
+        mdb.insert(Uuid::from_u128(1), full_machine());
+        // And one with none of them set
+        mdb.insert(Uuid::from_u128(2),
+            Machine::new("Saw".to_string(), String::new(), "lab.saw".to_string()));
+
+        let exported = export(mdb.iter(), format).unwrap();
+        assert_eq!(parse_export(&exported, format).unwrap(), mdb);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        round_trip("json");
+    }
+
+    #[test]
+    fn toml_round_trip() {
+        round_trip("toml");
+    }
+
+    #[test]
+    fn export_is_stable() {
+        let mut mdb = MachineDB::new();
+        for i in 0..16 {
+            mdb.insert(Uuid::from_u128(i), Machine::new(format!("Machine {}", i), String::new(),
+                format!("lab.m{}", i)));
+        }
+        let copy: MachineDB = mdb.clone().into_iter().rev().collect();
+        assert_eq!(export(mdb.iter(), "json").unwrap(), export(copy.iter(), "json").unwrap());
+    }
+}
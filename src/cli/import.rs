@@ -0,0 +1,299 @@
+//! Creating users in bulk from a CSV file, like the export of a membership management tool
+//!
+//! Rows naming users that don't exist yet create them with a random password, rows naming existing
+//! ones update what we know about them. Either way they get the role given, if any. Nobody is told
+//! the random passwords unless asked for with `--show-passwords`, so members need somebody to set
+//! them one with `user passwd` before they can log in.
+//!
+//! Malformed rows are reported with their line number and skipped, so one typo in a few hundred
+//! rows doesn't hold up the rest. With `--strict` nothing is imported instead.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read};
+
+use clap::ArgMatches;
+
+use casbin::MgmtApi;
+
+use crate::access;
+use crate::auth::{self, setup};
+use crate::config::Config;
+use crate::error::{Result, WithPath};
+
+/// Length of the random passwords of created users, unless the password policy wants longer ones
+const PASSWORD_LENGTH: usize = 20;
+/// What random passwords are made of. 64 characters, so every random byte maps to one without
+/// favouring any.
+const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789-_";
+/// Random passwords drawn before giving up on finding one the password policy accepts
+const PASSWORD_TRIES: usize = 100;
+
+/// Check a column argument, which counts from 1
+pub fn column_arg(s: String) -> std::result::Result<(), String> {
+    match s.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err("must be a column number, counting from 1".to_string()),
+    }
+}
+
+/// A user as a row of the file describes them
+struct Row {
+    name: String,
+    email: Option<String>,
+}
+
+/// What importing a row changes
+struct Change<'a> {
+    row: &'a Row,
+    create: bool,
+    /// The address we knew before, if the row changes it
+    email: Option<Option<String>>,
+    role: bool,
+}
+
+/// Import the users in the file given by `m`, see the module docs
+pub fn users(config: &Config, m: &ArgMatches) -> Result<()> {
+    // Required or defaulted arguments, with validators making sure they parse
+    let path = m.value_of("csv").unwrap_or_default();
+    let column = |name| m.value_of(name).and_then(|c| c.parse::<usize>().ok());
+    let name_col = column("column name").unwrap_or(1) - 1;
+    let email_col = column("column email").map(|c| c - 1);
+    let delimiter = m.value_of("delimiter").and_then(|d| d.chars().next()).unwrap_or(',');
+    let role = m.value_of("role");
+    let dry_run = m.is_present("dry run");
+
+    let userdb_path = config.auth.users.as_ref();
+    if email_col.is_some() && userdb_path.is_none() {
+        eprintln!("--column-email needs auth.users to be set");
+        std::process::exit(1);
+    }
+    if let Some(role) = role {
+        if !setup::valid_name(role) {
+            eprintln!("Role must not be empty nor contain commas or whitespace");
+            std::process::exit(1);
+        }
+    }
+
+    let content = fs::read_to_string(path).with_path(path)?;
+    let (rows, malformed) = parse(&content, delimiter, m.is_present("header"), name_col,
+        email_col);
+    for (line, problem) in malformed.iter() {
+        eprintln!("{}:{}: {}", path, line, problem);
+    }
+    if !malformed.is_empty() && m.is_present("strict") {
+        eprintln!("{} malformed rows, nothing was imported", malformed.len());
+        std::process::exit(1);
+    }
+
+    // Dry runs only read, like those of the daemon
+    let _lock = if dry_run { None } else { Some(super::lock(config)?) };
+
+    let mut passdb = auth::open_passdb(&config.passdb).with_path(&config.passdb)?;
+    let mut userdb = match userdb_path {
+        Some(path) => auth::users::open_userdb(path)?,
+        None => auth::users::UserDB::new(),
+    };
+    // Users that have the role already
+    let assigned: HashSet<String> = match role {
+        Some(role) if config.access.policy.exists() => {
+            let pdb = futures::executor::block_on(access::load_policy(config))?;
+            pdb.get_grouping_policy().into_iter()
+                .filter(|g| g.len() >= 2 && g[1] == role)
+                .map(|g| g[0].clone())
+                .collect()
+        },
+        _ => HashSet::new(),
+    };
+
+    let mut changes = Vec::new();
+    let mut unchanged = 0;
+    for row in rows.iter() {
+        let create = !passdb.contains_key(&row.name);
+        let known = userdb.get(&row.name).and_then(|i| i.email.clone());
+        let email = match row.email {
+            Some(ref e) if known.as_ref() != Some(e) => Some(known),
+            _ => None,
+        };
+        let role = role.is_some() && !assigned.contains(&row.name);
+        if create || email.is_some() || role {
+            changes.push(Change { row, create, email, role });
+        } else {
+            unchanged += 1;
+        }
+    }
+
+    let show_passwords = m.is_present("show passwords");
+    let mut roles = Vec::new();
+    for c in changes.iter() {
+        let mut what = Vec::new();
+        if let Some(ref old) = c.email {
+            let new = c.row.email.as_deref().unwrap_or_default();
+            what.push(match old {
+                Some(old) if !c.create => format!("email {} -> {}", old, new),
+                _ => format!("email {}", new),
+            });
+        }
+        if c.role {
+            what.push(format!("role {}", role.unwrap_or_default()));
+        }
+
+        let mut password = None;
+        if c.create && !dry_run {
+            let p = random_password(config, &c.row.name)?;
+            passdb.insert(c.row.name.clone(), p.clone());
+            password = Some(p).filter(|_| show_passwords);
+        }
+        if c.email.is_some() {
+            userdb.entry(c.row.name.clone()).or_default().email = c.row.email.clone();
+        }
+        if c.role {
+            roles.push([c.row.name.clone(), role.unwrap_or_default().to_string()]);
+        }
+
+        print!("{} {}", if c.create { "+" } else { "~" }, c.row.name);
+        if !what.is_empty() {
+            print!(" ({})", what.join(", "));
+        }
+        match password {
+            Some(p) => println!("\tpassword {}", p),
+            None => println!(),
+        }
+    }
+
+    let created = changes.iter().filter(|c| c.create).count();
+    if !dry_run {
+        if created > 0 {
+            auth::save_passdb(&config.passdb, &passdb)?;
+        }
+        if let Some(path) = userdb_path {
+            if changes.iter().any(|c| c.email.is_some()) {
+                auth::users::save_userdb(path, &userdb)?;
+            }
+        }
+        if !roles.is_empty() {
+            setup::append_roles(&config.access.policy, &roles)?;
+        }
+    }
+
+    println!("{} created, {} updated, {} unchanged, {} malformed rows skipped{}", created,
+        changes.len() - created, unchanged, malformed.len(),
+        if dry_run { " (dry run, nothing was changed)" } else { "" });
+    Ok(())
+}
+
+/// The rows of `content` and the problems with those that couldn't be read, by line number
+fn parse(content: &str, delimiter: char, header: bool, name_col: usize, email_col: Option<usize>)
+    -> (Vec<Row>, Vec<(usize, String)>)
+{
+    let mut rows = Vec::new();
+    let mut malformed = Vec::new();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+
+    // Spreadsheets like to start their exports with a byte order mark
+    let content = content.trim_start_matches('\u{feff}');
+    let lines = content.lines().enumerate().skip(if header { 1 } else { 0 });
+    for (idx, text) in lines {
+        let line = idx + 1;
+        if text.trim().is_empty() {
+            continue;
+        }
+        let fields = match split(text, delimiter) {
+            Ok(f) => f,
+            Err(e) => {
+                malformed.push((line, e));
+                continue;
+            }
+        };
+        let field = |col: usize| fields.get(col).map(|f| f.trim());
+
+        let name = match field(name_col) {
+            Some(n) if setup::valid_name(n) => n.to_string(),
+            Some(n) => {
+                malformed.push((line, format!("\"{}\" can't be a user name", n)));
+                continue;
+            },
+            None => {
+                malformed.push((line, format!("has only {} columns", fields.len())));
+                continue;
+            },
+        };
+        let email = match email_col.map(field) {
+            None | Some(Some("")) => None,
+            Some(Some(e)) if valid_email(e) => Some(e.to_string()),
+            Some(Some(e)) => {
+                malformed.push((line, format!("\"{}\" isn't an email address", e)));
+                continue;
+            },
+            Some(None) => {
+                malformed.push((line, format!("has only {} columns", fields.len())));
+                continue;
+            },
+        };
+        if let Some(first) = seen.get(&name) {
+            malformed.push((line, format!("{} already appeared on line {}", name, first)));
+            continue;
+        }
+        seen.insert(name.clone(), line);
+        rows.push(Row { name, email });
+    }
+
+    (rows, malformed)
+}
+
+/// Split a line of CSV into its fields
+///
+/// Fields may be quoted, with quotes inside doubled. Quoted fields spanning lines aren't
+/// supported, nobody's name or address does that.
+fn split(line: &str, delimiter: char) -> std::result::Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            },
+            '"' if quoted => quoted = false,
+            '"' if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            },
+            c if c == delimiter && !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err("has a quote that isn't closed".to_string());
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+/// Close enough to tell addresses from other things that ended up in the column
+fn valid_email(email: &str) -> bool {
+    match email.find('@') {
+        Some(at) => at > 0 && at < email.len() - 1
+            && !email.contains(|c: char| c.is_whitespace() || c.is_control()),
+        None => false,
+    }
+}
+
+/// A password for `user` nobody knows, following the password policy
+fn random_password(config: &Config, user: &str) -> io::Result<String> {
+    let policy = &config.auth.policy;
+    let len = PASSWORD_LENGTH.max(policy.min_length);
+    let mut bytes = vec![0u8; len];
+    let mut urandom = File::open("/dev/urandom")?;
+    for _ in 0..PASSWORD_TRIES {
+        urandom.read_exact(&mut bytes)?;
+        let password: String = bytes.iter().map(|b| ALPHABET[*b as usize % 64] as char).collect();
+        if auth::policy::check(policy, user, &password).is_empty() {
+            return Ok(password);
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::Other,
+        "the password policy refused every random password, it may be impossible to follow"))
+}
@@ -0,0 +1,290 @@
+//! A snapshot of the running server for scripts, e.g. a cron job feeding a metrics collector
+//!
+//! Unlike the other subcommands this goes through the API of the running daemon, the same way
+//! `--healthcheck` does, since only it knows which machines are in use right now.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use futures::executor::LocalPool;
+
+use async_std::future::timeout;
+
+use serde_json::json;
+
+use uuid::Uuid;
+
+use crate::api::api::{authentication, diflouroborane, machines, server_info};
+use crate::config::{Config, StatusClient};
+use crate::error::Result;
+use crate::machine::uuid_from_api;
+
+use super::{bootstrap, raw_listen};
+
+/// How long the server has to answer everything together
+const STATUS_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+    OpenMetrics,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "openmetrics" => Ok(Format::OpenMetrics),
+            _ => Err(format!("unknown format {}", s)),
+        }
+    }
+}
+
+/// What the server told us
+struct Snapshot {
+    version: String,
+    uptime: u64,
+    connections: u32,
+    last_save_ok: bool,
+    mqtt: &'static str,
+    dry_run: bool,
+    authorization_down: bool,
+    read_only: bool,
+    /// Entries in each registry kept in memory, by name
+    registries: Vec<(String, u64)>,
+    /// Open connections by the name of their client
+    clients: Vec<(String, u32)>,
+    /// `None` if we didn't log in, so we can't tell
+    machines: Option<Vec<MachineState>>,
+}
+
+struct MachineState {
+    uuid: Uuid,
+    name: String,
+    status: &'static str,
+    /// In UNIX seconds, 0 if not in use
+    since: u64,
+}
+
+const STATUSES: &[&str] = &["free", "occupied", "blocked"];
+
+type BoxError = Box<dyn std::error::Error>;
+
+/// Print the state of the server at the first raw listen address in `format`
+///
+/// Exits with 0 if the server answered and 1 if it couldn't be reached, didn't answer in time or
+/// didn't let us log in. For OpenMetrics a snapshot saying the server is down is printed then, so
+/// collectors see that instead of stale values.
+pub fn status(config: &Config, format: Format) -> Result<()> {
+    let listen = match raw_listen(config) {
+        Some(l) => l,
+        None => {
+            eprintln!("No listen address configured");
+            std::process::exit(1);
+        }
+    };
+
+    let mut exec = LocalPool::new();
+    let spawner = exec.spawner();
+    let snapshot = async {
+        let client = bootstrap(listen, &spawner).await?;
+        take_snapshot(client, config.status.as_ref()).await
+    };
+
+    let why = match exec.run_until(timeout(STATUS_TIMEOUT, snapshot)) {
+        Ok(Ok(snapshot)) => {
+            print(&snapshot, format);
+            std::process::exit(0);
+        },
+        Ok(Err(e)) => format!("Could not get the status from {}: {}", listen, e),
+        Err(_) => format!("{} didn't answer within {}s", listen, STATUS_TIMEOUT.as_secs()),
+    };
+    eprintln!("{}", why);
+    if format == Format::OpenMetrics {
+        println!("# TYPE diflouroborane_up gauge");
+        println!("diflouroborane_up 0");
+        println!("# EOF");
+    }
+    std::process::exit(1);
+}
+
+async fn take_snapshot(client: diflouroborane::Client, login: Option<&StatusClient>)
+    -> std::result::Result<Snapshot, BoxError>
+{
+    let reply = client.get_server_info_request().send().promise.await?;
+    let info = reply.get()?.get_info()?;
+    let mqtt = match info.get_mqtt()? {
+        server_info::Bridge::Disabled => "disabled",
+        server_info::Bridge::Down => "down",
+        server_info::Bridge::Up => "up",
+    };
+    let mut snapshot = Snapshot {
+        version: info.get_version()?.to_string(),
+        uptime: info.get_uptime(),
+        connections: info.get_connections(),
+        last_save_ok: info.get_last_save_ok(),
+        mqtt,
+        dry_run: info.get_dry_run(),
+        authorization_down: info.get_authorization_down(),
+        read_only: info.get_read_only(),
+        registries: Vec::new(),
+        clients: Vec::new(),
+        machines: None,
+    };
+    for r in info.get_registries()?.iter() {
+        snapshot.registries.push((r.get_name()?.to_string(), r.get_entries()));
+    }
+    for c in info.get_clients()?.iter() {
+        snapshot.clients.push((c.get_name()?.to_string(), c.get_connections()));
+    }
+
+    if let Some(login) = login {
+        log_in(&client, login).await?;
+        snapshot.machines = Some(list_machines(&client).await?);
+    }
+    Ok(snapshot)
+}
+
+/// Authenticate with SASL PLAIN
+async fn log_in(client: &diflouroborane::Client, login: &StatusClient)
+    -> std::result::Result<(), BoxError>
+{
+    let auth = client.authentication_request().send().pipeline.get_auth();
+    let mut req = auth.initialize_authentication_request();
+    req.get().set_mechanism("PLAIN");
+    let data = format!("\0{}\0{}", login.user, login.token.expose());
+    req.get().init_initial_data().set_some(data.as_bytes());
+    let reply = req.send().promise.await?;
+
+    let outcome = match reply.get()?.get_response()?.which()? {
+        authentication::step_result::Outcome(o) => o?,
+        authentication::step_result::Challenge(_) =>
+            return Err("PLAIN wants more than one step".into()),
+    };
+    let granted = outcome.value_request().send().promise.await?.get()?.get_granted();
+    if !granted {
+        return Err(format!("Logging in as {} failed", login.user).into());
+    }
+    Ok(())
+}
+
+async fn list_machines(client: &diflouroborane::Client)
+    -> std::result::Result<Vec<MachineState>, BoxError>
+{
+    let mach = client.machines_request().send().pipeline.get_mach();
+    let reply = mach.list_request().send().promise.await?;
+    let mut machines = Vec::new();
+    for m in reply.get()?.get_machines()?.iter() {
+        let status = match m.get_status()? {
+            machines::Status::Free => "free",
+            machines::Status::Occupied => "occupied",
+            machines::Status::Blocked => "blocked",
+        };
+        machines.push(MachineState {
+            uuid: uuid_from_api(m.get_uuid()?),
+            name: m.get_name()?.to_string(),
+            status,
+            since: m.get_in_use_since(),
+        });
+    }
+    machines.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(machines)
+}
+
+fn print(s: &Snapshot, format: Format) {
+    match format {
+        Format::Text => print_text(s),
+        Format::Json => println!("{}", to_json(s)),
+        Format::OpenMetrics => print_openmetrics(s),
+    }
+}
+
+fn print_text(s: &Snapshot) {
+    println!("version {}, up {}s, {} connections, last save {}, mqtt {}{}{}{}", s.version,
+        s.uptime, s.connections, if s.last_save_ok { "ok" } else { "FAILED" }, s.mqtt,
+        if s.dry_run { ", dry run" } else { "" },
+        if s.authorization_down { ", authorization DOWN" } else { "" },
+        if s.read_only { ", read-only" } else { "" });
+    for m in s.machines.iter().flatten() {
+        println!("{}  {:<8}  {}", m.uuid.to_hyphenated(), m.status, m.name);
+    }
+}
+
+fn to_json(s: &Snapshot) -> serde_json::Value {
+    let machines = s.machines.as_ref().map(|ms| ms.iter().map(|m| json!({
+        "uuid": m.uuid.to_hyphenated().to_string(),
+        "name": m.name,
+        "status": m.status,
+        "since": if m.since == 0 { None } else { Some(m.since) },
+    })).collect::<Vec<_>>());
+    json!({
+        "version": s.version,
+        "uptime": s.uptime,
+        "connections": s.connections,
+        "last_save_ok": s.last_save_ok,
+        "mqtt": s.mqtt,
+        "dry_run": s.dry_run,
+        "authorization_down": s.authorization_down,
+        "read_only": s.read_only,
+        "registries": s.registries.iter().map(|(name, n)| (name.clone(), json!(n)))
+            .collect::<serde_json::Map<_, _>>(),
+        "clients": s.clients.iter().map(|(name, n)| (name.clone(), json!(n)))
+            .collect::<serde_json::Map<_, _>>(),
+        "machines": machines,
+    })
+}
+
+fn print_openmetrics(s: &Snapshot) {
+    let gauge = |name: &str, help: &str, value: u64| {
+        println!("# TYPE diflouroborane_{} gauge", name);
+        println!("# HELP diflouroborane_{} {}", name, help);
+        println!("diflouroborane_{} {}", name, value);
+    };
+    gauge("up", "Whether the server answered", 1);
+    gauge("uptime_seconds", "Seconds since the server was started", s.uptime);
+    gauge("connections", "Open connections", s.connections as u64);
+    gauge("last_save_ok", "Whether the last save of the machine database worked",
+        s.last_save_ok as u64);
+    gauge("mqtt_up", "Whether the MQTT bridge is connected", (s.mqtt == "up") as u64);
+    gauge("authorization_down", "Whether permission checks are given up on",
+        s.authorization_down as u64);
+    gauge("read_only", "Whether the server is in read-only mode", s.read_only as u64);
+
+    if !s.registries.is_empty() {
+        println!("# TYPE diflouroborane_registry_entries gauge");
+        println!("# HELP diflouroborane_registry_entries Entries of a registry kept in memory");
+        for (name, n) in s.registries.iter() {
+            println!("diflouroborane_registry_entries{{registry=\"{}\"}} {}", escape(name), n);
+        }
+    }
+
+    if !s.clients.is_empty() {
+        println!("# TYPE diflouroborane_client_connections gauge");
+        println!("# HELP diflouroborane_client_connections Open connections by client name");
+        for (name, n) in s.clients.iter() {
+            println!("diflouroborane_client_connections{{client=\"{}\"}} {}", escape(name), n);
+        }
+    }
+
+    if let Some(ref machines) = s.machines {
+        println!("# TYPE diflouroborane_machine_status stateset");
+        println!("# HELP diflouroborane_machine_status Status of the machine");
+        for m in machines.iter() {
+            for status in STATUSES {
+                println!("diflouroborane_machine_status{{uuid=\"{}\",name=\"{}\",\
+                    diflouroborane_machine_status=\"{}\"}} {}", m.uuid.to_hyphenated(),
+                    escape(&m.name), status, (m.status == *status) as u8);
+            }
+        }
+    }
+    println!("# EOF");
+}
+
+/// Label values are quoted, so quotes, backslashes and newlines in them have to be escaped
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -1,62 +1,2418 @@
 use std::str::FromStr;
-use std::path::{Path, PathBuf};
-use serde::{Serialize, Deserialize};
-use std::io::Read;
-use std::fs::File;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Component, Path, PathBuf};
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Error as _;
+use std::io::{Read, Write};
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::fmt;
+use std::env;
+use std::net::{IpAddr, SocketAddr};
 
-use crate::error::Result;
+use toml::Value;
+
+use uuid::Uuid;
+use toml::map::{Entry, Map};
+
+use slog::Level;
+
+use crate::error::{Error, Result, WithPath};
+use crate::gc;
+use crate::listen::filter::Cidr;
+use crate::machine::{self, Machine};
 
 use std::default::Default;
 
+/// The default config with every key explained
+///
+/// Keep this in sync with `Config::default()` and the documentation of the fields below.
+const DEFAULT_CONFIG: &str = include_str!("default.toml");
+
+/// The default config as commented TOML
+///
+/// This fails if the template doesn't describe the same config as `Config::default()`, so it can't
+/// silently go out of date.
+pub fn default_annotated() -> Result<&'static str> {
+    let parsed: Config = toml::from_str(DEFAULT_CONFIG)?;
+    if Value::try_from(&parsed)? != Value::try_from(&Config::default())? {
+        return Err(Error::Config("the default config template does not match the built-in \
+            defaults".to_string()));
+    }
+    Ok(DEFAULT_CONFIG)
+}
+
+/// The default config in `format`
+///
+/// TOML comes with explanations for every key, the other formats only have the bare values.
+pub fn default_as(format: FileFormat) -> Result<String> {
+    // Going through `Value` leaves out unset optional values, which would be `null` otherwise and
+    // couldn't be read back in.
+    let value = Value::try_from(&Config::default())?;
+    match format {
+        FileFormat::Toml => default_annotated().map(str::to_string),
+        FileFormat::Yaml => Ok(serde_yaml::to_string(&value)?),
+        FileFormat::Json => Ok(serde_json::to_string_pretty(&value)?),
+    }
+}
+
+/// Write the default config in `format` to `path`, readable by its owner only
+///
+/// Configs can hold secrets once filled in, so an existing file is made private as well.
+pub fn write_default(path: &Path, format: FileFormat) -> Result<()> {
+    let config = default_as(format)?;
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600)
+        .open(path)?;
+    // The mode only applies to new files
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(config.as_bytes())?;
+    Ok(())
+}
+
+/// Formats config files can be written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl FileFormat {
+    /// The format of the file at `path` going by its extension, if it's one we know
+    pub fn of(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(FileFormat::Toml),
+            "yaml" | "yml" => Some(FileFormat::Yaml),
+            "json" => Some(FileFormat::Json),
+            _ => None,
+        }
+    }
+
+    /// Parse the settings in `contents`
+    ///
+    /// Everything is turned into TOML values so the rest of the loading works the same
+    /// regardless of the format.
+    fn parse(self, contents: &str) -> Result<Map<String, Value>> {
+        let value: Value = match self {
+            FileFormat::Toml => toml::from_str(contents)?,
+            FileFormat::Yaml => serde_yaml::from_str(contents)?,
+            FileFormat::Json => serde_json::from_str(contents)?,
+        };
+        match value {
+            Value::Table(t) => Ok(t),
+            _ => Err(Error::Config("expected a map of settings at the top level".to_string())),
+        }
+    }
+}
+
+impl FromStr for FileFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "toml" => Ok(FileFormat::Toml),
+            "yaml" | "yml" => Ok(FileFormat::Yaml),
+            "json" => Ok(FileFormat::Json),
+            _ => Err(format!("unknown config format \"{}\"", s)),
+        }
+    }
+}
+
+/// Prefix of environment variables overriding values from the config file
+const ENV_PREFIX: &str = "DIFLOUROBORANE_";
+
+/// Read the config file at `path` and apply overrides from the environment
+///
+/// Values are taken from, in order of precedence: command line flags, `DIFLOUROBORANE_*`
+/// environment variables, the config file and finally the defaults. If the config file sets
+/// `include`, every config file in that directory is merged into it in lexical order, later files
+/// taking precedence.
+///
+/// The format is picked by the file extension: `.yaml` and `.yml` are read as YAML, `.json` as JSON
+/// and everything else as TOML.
+///
+/// Keys we don't know are collected in `unknown`, or make this fail with `strict_config`.
 pub fn read(path: &Path) -> Result<Config> {
     let mut fp = File::open(path)?;
     let mut contents = String::new();
     fp.read_to_string(&mut contents)?;
 
-    let config = toml::from_str(&contents)?;
+    let format = FileFormat::of(path).unwrap_or(FileFormat::Toml);
+    let mut tree = Value::Table(format.parse(&contents)?);
+
+    // Relative paths are meant relative to the config file, not to wherever we were started from
+    // -- which for a service is usually `/`.
+    let dir = path.canonicalize()?.parent().map(Path::to_path_buf).unwrap_or_default();
+
+    let mut overrides = Vec::new();
+    let include = tree.get("include").and_then(Value::as_str).map(PathBuf::from);
+    if let Some(include) = include {
+        let include = resolve(&dir, &include);
+        for (file, format) in included_files(&include)? {
+            let mut contents = String::new();
+            File::open(&file).and_then(|mut f| f.read_to_string(&mut contents)).with_path(&file)?;
+            let t = format.parse(&contents).with_path(&file)?;
+
+            if t.contains_key("include") {
+                return Err(Error::Config("include can only be set in the main config file"
+                    .to_string()).with_path(&file));
+            }
+            merge(tree.as_table_mut().unwrap(), t, "", &file, &mut overrides);
+        }
+    }
+
+    // Keys we don't know are most likely misspelled ones, which would otherwise silently leave
+    // their default in place
+    let mut unknown = Vec::new();
+    let config = serde_ignored::deserialize(tree, |path| unknown.push(path.to_string()))?;
+
+    // Variables that aren't valid unicode can't be meant for us
+    let vars = env::vars_os()
+        .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)));
+    let mut config = apply_env(config, vars)?;
+
+    if config.strict_config && !unknown.is_empty() {
+        return Err(Error::Config(format!("unknown keys {}", unknown.join(", "))));
+    }
+    config.resolve_paths(&dir);
+    config.overrides = overrides;
+    config.unknown = unknown;
+
+    Ok(config)
+}
+
+/// All config files in the include directory with their format, in the order they are merged in
+fn included_files(dir: &Path) -> Result<Vec<(PathBuf, FileFormat)>> {
+    let mut files = Vec::new();
+    let entries = fs::read_dir(dir).with_path(dir)?;
+    for entry in entries {
+        let path = entry?.path();
+        if let Some(format) = FileFormat::of(&path) {
+            if path.is_file() {
+                files.push((path, format));
+            }
+        }
+    }
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(files)
+}
+
+/// Merge the config file `file` with the contents `from` into what was read so far
+///
+/// Tables are merged key by key and entries in `listen` and `machine` are added to the existing
+/// ones. Any other value replaces the one set before, which is noted in `overrides`.
+fn merge(into: &mut Map<String, Value>, from: Map<String, Value>, prefix: &str, file: &Path,
+    overrides: &mut Vec<Override>)
+{
+    for (k, v) in from {
+        let key = if prefix.is_empty() { k.clone() } else { format!("{}.{}", prefix, k) };
+        match into.entry(k) {
+            Entry::Vacant(e) => { e.insert(v); },
+            Entry::Occupied(mut e) => match (e.get_mut(), v) {
+                (Value::Table(a), Value::Table(b)) => merge(a, b, &key, file, overrides),
+                (Value::Array(a), Value::Array(b))
+                    if key == "listen" || key == "machine" || key == "notifier" => a.extend(b),
+                (old, v) => {
+                    if *old != v {
+                        overrides.push(Override { key, file: file.to_path_buf() });
+                    }
+                    *old = v;
+                },
+            },
+        }
+    }
+}
+
+/// A value from the main config file replaced by one from an included file
+#[derive(Debug, Clone)]
+pub struct Override {
+    /// Path of the key in TOML notation
+    pub key: String,
+    /// The included file that won
+    pub file: PathBuf,
+}
+
+/// Override config values from `DIFLOUROBORANE_*` environment variables
+///
+/// The rest of the variable name is the key in lowercase with `__` separating nested keys, e.g.
+/// `DIFLOUROBORANE_DAEMON__SHUTDOWN_GRACE=30` sets `shutdown_grace` in `[daemon]`.
+/// `DIFLOUROBORANE_LISTEN` takes a comma-separated list of `address[:port]` or socket paths and
+/// replaces all configured listen entries.
+fn apply_env<I: Iterator<Item=(String, String)>>(config: Config, vars: I) -> Result<Config> {
+    let mut config = config;
+
+    for (var, val) in vars {
+        let key = match var.strip_prefix(ENV_PREFIX) {
+            Some(k) => k.to_lowercase(),
+            None => continue,
+        };
+
+        if key == "listen" {
+            config.listen = parse_listen_env(&var, &val)?;
+            continue;
+        }
+
+        // Going through `Value` lets us treat every key the same, at the price of only finding
+        // out if the value has the right type by deserializing the whole thing again.
+        let tree = Value::try_from(&config)?;
+        let path: Vec<&str> = key.split("__").collect();
+
+        // Keys that are currently unset have no type to go by so we try what the value looks like
+        // first and fall back to a string.
+        let candidates = match lookup(&tree, &path) {
+            Some(Value::Integer(_)) => vec![val.parse().map(Value::Integer).map_err(|_|
+                Error::Config(format!("{}: expected a number, got \"{}\"", var, val)))?],
+            Some(Value::Boolean(_)) => vec![val.parse().map(Value::Boolean).map_err(|_|
+                Error::Config(format!("{}: expected true or false, got \"{}\"", var, val)))?],
+            Some(Value::String(_)) => vec![Value::String(val.clone())],
+            Some(_) => return Err(Error::Config(
+                format!("{}: this key can't be set from the environment", var))),
+            None => {
+                let mut c = Vec::new();
+                if let Ok(i) = val.parse() {
+                    c.push(Value::Integer(i));
+                }
+                if let Ok(b) = val.parse() {
+                    c.push(Value::Boolean(b));
+                }
+                c.push(Value::String(val.clone()));
+                c
+            }
+        };
+
+        let mut error = None;
+        for candidate in candidates {
+            let mut t = tree.clone();
+            if !insert(&mut t, &path, candidate) {
+                return Err(Error::Config(
+                    format!("{}: this key can't be set from the environment", var)));
+            }
+            match t.try_into() {
+                Ok(c) => {
+                    config = c;
+                    error = None;
+                    break;
+                }
+                Err(e) => if error.is_none() {
+                    error = Some(e);
+                },
+            }
+        }
+        if let Some(e) = error {
+            return Err(Error::Config(format!("{}: {}", var, e)));
+        }
+    }
+
     Ok(config)
 }
 
+fn lookup<'a>(tree: &'a Value, path: &[&str]) -> Option<&'a Value> {
+    path.iter().try_fold(tree, |v, k| v.get(*k))
+}
+
+/// Set the value at `path`, creating tables on the way. Fails if something else is in the way.
+fn insert(tree: &mut Value, path: &[&str], value: Value) -> bool {
+    let (last, parents) = match path.split_last() {
+        Some(p) => p,
+        None => return false,
+    };
+
+    let mut table = match tree {
+        Value::Table(t) => t,
+        _ => return false,
+    };
+    for p in parents {
+        table = match table.entry(p.to_string())
+            .or_insert_with(|| Value::Table(toml::map::Map::new()))
+        {
+            Value::Table(t) => t,
+            _ => return false,
+        };
+    }
+    table.insert(last.to_string(), value);
+    true
+}
+
+fn parse_listen_env(var: &str, val: &str) -> Result<Box<[Listen]>> {
+    let mut listen = Vec::new();
+    for entry in val.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let l = if entry.starts_with('/') {
+            Listen::Unix { path: PathBuf::from(entry), mode: None, owner: None }
+        } else if let Ok(addr) = entry.parse::<SocketAddr>() {
+            Listen::tcp(addr.ip().to_string(), Some(addr.port()))
+        } else if let Ok(addr) = entry.parse::<IpAddr>() {
+            Listen::tcp(addr.to_string(), None)
+        } else {
+            // Host names, possibly with a port
+            match entry.rfind(':') {
+                Some(i) => {
+                    let port = entry[i+1..].parse().map_err(|_| Error::Config(
+                        format!("{}: invalid port \"{}\"", var, &entry[i+1..])))?;
+                    Listen::tcp(entry[..i].to_string(), Some(port))
+                }
+                None => Listen::tcp(entry.to_string(), None),
+            }
+        };
+        listen.push(l);
+    }
+
+    if listen.is_empty() {
+        return Err(Error::Config(format!("{}: no listen addresses given", var)));
+    }
+
+    Ok(listen.into_boxed_slice())
+}
+
+/// Everything missing from the config file is taken from `Config::default()`, so config files
+/// written for older versions keep working when keys are added.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
+    /// Directory with more config files to merge into this one
+    pub include: Option<PathBuf>,
+    /// Refuse config files with keys we don't know instead of only warning about them
+    pub strict_config: bool,
     pub machinedb: PathBuf,
     pub passdb: PathBuf,
+    /// Card UIDs and the users they belong to, for card readers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cards: Option<PathBuf>,
     pub(crate) access: Access,
+    pub auth: Auth,
     pub listen: Box<[Listen]>,
+    pub machines: Machines,
+    /// Machines defined right here instead of in `machinedb`
+    #[serde(rename = "machine", skip_serializing_if = "Vec::is_empty")]
+    pub inline_machines: Vec<InlineMachine>,
+    pub api: Api,
+    pub daemon: Daemon,
+    pub log: Log,
+    pub audit: Audit,
+    pub stats: Stats,
+    pub gc: Gc,
+    /// Broker to bridge to. Without one the bridge is disabled.
+    pub mqtt: Option<Mqtt>,
+    /// Who to tell about machines changing their state
+    #[serde(rename = "notifier", skip_serializing_if = "Vec::is_empty")]
+    pub notifiers: Vec<Notifier>,
+    pub webhooks: Webhooks,
+    /// Who `status` logs in as to see all machines. It only shows the server's health without.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<StatusClient>,
+    pub modules: Modules,
+    /// Values that included files changed, so we can tell the user where they came from
+    #[serde(skip)]
+    pub overrides: Vec<Override>,
+    /// Keys of the config files that aren't ours, e.g. misspelled ones, which were ignored
+    #[serde(skip)]
+    pub unknown: Vec<String>,
+    /// Set by `--dry-run`, to the directory that files are written to instead
+    #[serde(skip)]
+    pub dry_run: Option<PathBuf>,
+}
+
+impl Config {
+    /// Make all relative file paths relative to `dir` instead
+    fn resolve_paths(&mut self, dir: &Path) {
+        for path in vec![&mut self.machinedb, &mut self.passdb, &mut self.access.model,
+            &mut self.access.policy]
+        {
+            *path = resolve(dir, path);
+        }
+        if let Some(ref mut file) = self.log.file {
+            *file = resolve(dir, file);
+        }
+        if let Some(ref mut path) = self.audit.path {
+            *path = resolve(dir, path);
+        }
+        if let Some(ref mut path) = self.stats.path {
+            *path = resolve(dir, path);
+        }
+        if let Some(ref mut path) = self.cards {
+            *path = resolve(dir, path);
+        }
+        if let Some(ref mut path) = self.access.grants {
+            *path = resolve(dir, path);
+        }
+        if let Some(ref mut path) = self.auth.users {
+            *path = resolve(dir, path);
+        }
+        if let Some(ref mut path) = self.auth.resets {
+            *path = resolve(dir, path);
+        }
+        if let Some(ref mut path) = self.machines.watches {
+            *path = resolve(dir, path);
+        }
+        for actor in self.machines.actors.values_mut() {
+            if let Some(ref mut path) = actor.simulate {
+                *path = resolve(dir, path);
+            }
+        }
+        if let Some(ref mut path) = self.modules.plugin_dir {
+            *path = resolve(dir, path);
+        }
+        if let Some(ref mut path) = self.webhooks.outbox {
+            *path = resolve(dir, path);
+        }
+    }
+
+    /// Check for mistakes that parsing the config can't catch, like missing files or listen
+    /// addresses that can never be bound
+    ///
+    /// Every problem found is returned, not just the first one.
+    pub fn validate(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+
+        match self.machines.backend {
+            MachineBackend::Toml if self.machinedb.is_dir() =>
+                problems.push(Problem::new("machinedb", "is a directory, expected a file")),
+            MachineBackend::Sled if self.machinedb.exists() && !self.machinedb.is_dir() =>
+                problems.push(Problem::new("machinedb", "is not a directory as the sled backend \
+                    requires")),
+            _ => {},
+        }
+        check_parent(&mut problems, "machinedb", &self.machinedb);
+
+        // A missing password database is created on startup
+        if self.passdb.exists() {
+            check_file(&mut problems, "passdb", &self.passdb);
+        } else {
+            check_parent(&mut problems, "passdb", &self.passdb);
+        }
+
+        if self.access.enforce_timeout == 0 {
+            problems.push(Problem::new("access.enforce_timeout", "must be at least 1"));
+        }
+        if self.access.breaker_threshold == 0 {
+            problems.push(Problem::new("access.breaker_threshold", "must be at least 1"));
+        }
+        if self.auth.policy.require_classes > 4 {
+            problems.push(Problem::new("auth.policy.require_classes",
+                "there are only 4 classes of characters"));
+        }
+
+        check_file(&mut problems, "access.model", &self.access.model);
+        check_file(&mut problems, "access.policy", &self.access.policy);
+        // Like the password database the table of grants is created when it's first needed
+        if let Some(ref grants) = self.access.grants {
+            if grants.exists() {
+                check_file(&mut problems, "access.grants", grants);
+            } else {
+                check_parent(&mut problems, "access.grants", grants);
+            }
+        }
+
+        if let Some(ref users) = self.auth.users {
+            if users.exists() {
+                check_file(&mut problems, "auth.users", users);
+            } else {
+                check_parent(&mut problems, "auth.users", users);
+            }
+        }
+        if let Some(ref resets) = self.auth.resets {
+            if resets.exists() {
+                check_file(&mut problems, "auth.resets", resets);
+            } else {
+                check_parent(&mut problems, "auth.resets", resets);
+            }
+            if self.auth.reset_expiry == 0 {
+                problems.push(Problem::new("auth.reset_expiry", "must be at least 1"));
+            }
+        }
+        if let Some(ref watches) = self.machines.watches {
+            if watches.exists() {
+                check_file(&mut problems, "machines.watches", watches);
+            } else {
+                check_parent(&mut problems, "machines.watches", watches);
+            }
+        }
+        if self.machines.watch_expiry == 0 {
+            problems.push(Problem::new("machines.watch_expiry", "must be at least 1"));
+        }
+        if self.machines.journal_sync == 0 {
+            problems.push(Problem::new("machines.journal_sync", "must be at least 1"));
+        }
+        if self.machines.lock_timeout == 0 {
+            problems.push(Problem::new("machines.lock_timeout", "must be at least 1"));
+        }
+        if self.log.dedup_threshold == 0 {
+            problems.push(Problem::new("log.dedup_threshold", "must be at least 1"));
+        }
+        for (i, channel) in self.machines.notify_channels.iter().enumerate() {
+            if !NOTIFY_CHANNELS.contains(&channel.as_str()) {
+                problems.push(Problem::new(format!("machines.notify_channels[{}]", i),
+                    format!("unknown channel \"{}\", expected one of {}", channel,
+                        NOTIFY_CHANNELS.join(", "))));
+            }
+        }
+
+        if self.machines.timezone.parse::<chrono_tz::Tz>().is_err() {
+            problems.push(Problem::new("machines.timezone",
+                format!("unknown time zone {}, expected a name like Europe/Berlin",
+                    self.machines.timezone)));
+        }
+        let template = self.machines.perm_template.as_deref();
+        if let Some(t) = template {
+            if let Err(e) = machine::check_perm_template(t) {
+                problems.push(Problem::new("machines.perm_template", e));
+            }
+        }
+
+        // Inline machines have to follow the same rules as those in the machine database
+        let machines: Vec<(Uuid, Machine)> = self.inline_machines.iter()
+            .map(|m| {
+                let mut machine = m.to_machine();
+                machine.derive_perm(&m.uuid, template);
+                (m.uuid, machine)
+            })
+            .collect();
+        for (i, (_, m)) in machines.iter().enumerate() {
+            if m.perm().is_empty() {
+                problems.push(Problem::new(format!("machine[{}].perm", i),
+                    "must be set unless machines.perm_template is"));
+            }
+        }
+        if let Err(e) = machine::validate(machines.iter().map(|(u, m)| (u, m))) {
+            problems.push(Problem::new("machine", e.to_string()));
+        }
+
+        if self.listen.is_empty() {
+            problems.push(Problem::new("listen", "no addresses to listen on"));
+        }
+        for (i, l) in self.listen.iter().enumerate() {
+            match l {
+                Listen::Tcp { address, port, kind, origins, allow, deny } => {
+                    if address.parse::<IpAddr>().is_err() && !is_hostname(address) {
+                        problems.push(Problem::new(format!("listen[{}].address", i),
+                            format!("\"{}\" is neither an IP address nor a host name", address)));
+                    }
+                    if *port == Some(0) {
+                        problems.push(Problem::new(format!("listen[{}].port", i),
+                            "must be between 1 and 65535"));
+                    }
+                    if *kind == ListenKind::Raw && !origins.is_empty() {
+                        problems.push(Problem::new(format!("listen[{}].origins", i),
+                            "only makes sense with kind = \"websocket\""));
+                    }
+                    // Overlaps are fine, deny wins, but denying all that's allowed isn't
+                    if !allow.is_empty() && allow.iter().all(|a| deny.iter().any(|d| d.covers(a))) {
+                        problems.push(Problem::new(format!("listen[{}].deny", i),
+                            "denies every peer `allow` lets in, nobody could connect"));
+                    }
+                },
+                Listen::Unix { path, .. } =>
+                    check_parent(&mut problems, &format!("listen[{}].path", i), path),
+            }
+            if self.listen[..i].iter().any(|other| same_listen(l, other)) {
+                problems.push(Problem::new(format!("listen[{}]", i),
+                    format!("{} is listed more than once", l)));
+            }
+        }
+
+        if let Some(ref user) = self.daemon.user {
+            if users::get_user_by_name(user).is_none() {
+                problems.push(Problem::new("daemon.user", format!("no such user {}", user)));
+            }
+        }
+        if let Some(ref group) = self.daemon.group {
+            if users::get_group_by_name(group).is_none() {
+                problems.push(Problem::new("daemon.group", format!("no such group {}", group)));
+            }
+        }
+        if self.api.max_message_bytes < 1024 {
+            problems.push(Problem::new("api.max_message_bytes", "must be at least 1024"));
+        }
+        if self.api.max_nesting < 8 {
+            problems.push(Problem::new("api.max_nesting", "must be at least 8"));
+        }
+        if let Some(ref k) = self.api.tcp_keepalive {
+            for (key, value) in [("idle", k.idle), ("interval", k.interval), ("count", k.count)]
+                .iter()
+            {
+                if *value == 0 {
+                    problems.push(Problem::new(format!("api.tcp_keepalive.{}", key),
+                        "must be at least 1"));
+                }
+            }
+        }
+        if let Some(ref limit) = self.api.rate_limit {
+            if limit.per_second == 0 {
+                problems.push(Problem::new("api.rate_limit.per_second", "must be at least 1"));
+            }
+            if limit.burst == 0 {
+                problems.push(Problem::new("api.rate_limit.burst", "must be at least 1"));
+            }
+        }
+
+        for (uuid, actor) in self.machines.actors.iter() {
+            let key = format!("machines.actors.{}", uuid);
+            if actor.kind.needs_mqtt() && self.mqtt.is_none() {
+                problems.push(Problem::new(format!("{}.type", key),
+                    "requires a broker configured in [mqtt]"));
+            }
+            match actor.kind {
+                ActorKind::Tasmota => match actor.topic {
+                    None => problems.push(Problem::new(format!("{}.topic", key),
+                        "must be set for tasmota actors")),
+                    Some(ref topic) => {
+                        if actor.stat_topic.is_none() && !topic.starts_with("cmnd/") {
+                            problems.push(Problem::new(format!("{}.stat_topic", key),
+                                "must be set if topic doesn't start with cmnd/"));
+                        }
+                        if topic.contains(|c| c == '#' || c == '+') {
+                            problems.push(Problem::new(format!("{}.topic", key),
+                                "must not contain # or +"));
+                        }
+                    },
+                },
+                ActorKind::Shelly => match actor.device {
+                    None => problems.push(Problem::new(format!("{}.device", key),
+                        "must be set for shelly actors")),
+                    Some(ref d) if d.is_empty() || d.contains(|c| c == '#' || c == '+') =>
+                        problems.push(Problem::new(format!("{}.device", key),
+                            "must not be empty or contain # or +")),
+                    Some(_) => {},
+                },
+                ActorKind::Exec => if actor.command.first().map(|c| c.is_empty()).unwrap_or(true) {
+                    problems.push(Problem::new(format!("{}.command", key),
+                        "must name a program for exec actors"));
+                },
+                ActorKind::Http => match actor.url {
+                    None => problems.push(Problem::new(format!("{}.url", key),
+                        "must be set for http actors")),
+                    Some(ref url) =>
+                        check_http(&mut problems, &key, url, &actor.method, &actor.headers),
+                },
+                ActorKind::Gpio => {
+                    if actor.line.is_none() {
+                        problems.push(Problem::new(format!("{}.line", key),
+                            "must be set for gpio actors"));
+                    }
+                    if actor.simulate.is_none() && !cfg!(feature = "gpio") {
+                        problems.push(Problem::new(format!("{}.type", key),
+                            "gpio actors need diflouroborane built with the gpio feature"));
+                    }
+                },
+                ActorKind::Dummy => {},
+            }
+            if actor.timeout == 0 {
+                problems.push(Problem::new(format!("{}.timeout", key), "must be at least 1"));
+            }
+        }
+
+        for (i, notifier) in self.notifiers.iter().enumerate() {
+            let key = format!("notifier[{}]", i);
+            match notifier.kind {
+                NotifierKind::Http => check_http(&mut problems, &key, &notifier.url,
+                    &notifier.method, &notifier.headers),
+            }
+            if notifier.timeout == 0 {
+                problems.push(Problem::new(format!("{}.timeout", key), "must be at least 1"));
+            }
+        }
+
+        if !self.webhooks.hooks.is_empty() {
+            match self.webhooks.outbox {
+                Some(ref path) => check_parent(&mut problems, "webhooks.outbox", path),
+                None => problems.push(Problem::new("webhooks.outbox",
+                    "must be set, deliveries have to survive restarts")),
+            }
+            if self.webhooks.max_attempts == 0 {
+                problems.push(Problem::new("webhooks.max_attempts", "must be at least 1"));
+            }
+        }
+        for (i, hook) in self.webhooks.hooks.iter().enumerate() {
+            let key = format!("webhooks.hook[{}]", i);
+            check_http(&mut problems, &key, &hook.url, "POST", &BTreeMap::new());
+            if hook.secret.is_none() && self.webhooks.secret.is_none() {
+                problems.push(Problem::new(format!("{}.secret", key),
+                    "must be set if webhooks.secret isn't"));
+            }
+            if hook.timeout == 0 {
+                problems.push(Problem::new(format!("{}.timeout", key), "must be at least 1"));
+            }
+        }
+
+        if let Some(ref mqtt) = self.mqtt {
+            if mqtt.host.is_empty() {
+                problems.push(Problem::new("mqtt.host", "must not be empty"));
+            }
+            if mqtt.port == 0 {
+                problems.push(Problem::new("mqtt.port", "must be between 1 and 65535"));
+            }
+            if mqtt.keep_alive == 0 {
+                problems.push(Problem::new("mqtt.keep_alive", "must be at least 1"));
+            }
+            if mqtt.password.is_some() && mqtt.username.is_none() {
+                problems.push(Problem::new("mqtt.password", "requires a username"));
+            }
+            // Those are the only characters MQTT reserves in topic names
+            if mqtt.command_prefix.contains(|c| c == '#' || c == '+') {
+                problems.push(Problem::new("mqtt.command_prefix", "must not contain # or +"));
+            }
+            if mqtt.availability_topic.contains(|c| c == '#' || c == '+') {
+                problems.push(Problem::new("mqtt.availability_topic", "must not contain # or +"));
+            }
+            if mqtt.machine_prefix.contains(|c| c == '#' || c == '+') {
+                problems.push(Problem::new("mqtt.machine_prefix", "must not contain # or +"));
+            }
+            if mqtt.state.qos > 1 {
+                problems.push(Problem::new("mqtt.state.qos", "must be 0 or 1"));
+            }
+            if mqtt.events.qos > 1 {
+                problems.push(Problem::new("mqtt.events.qos", "must be 0 or 1"));
+            }
+            if mqtt.reader_prefix.contains(|c| c == '#' || c == '+') {
+                problems.push(Problem::new("mqtt.reader_prefix", "must not contain # or +"));
+            }
+            if mqtt.hass_prefix.is_empty() || mqtt.hass_prefix.contains(|c| c == '#' || c == '+') {
+                problems.push(Problem::new("mqtt.hass_prefix",
+                    "must not be empty or contain # or +"));
+            }
+            for id in mqtt.readers.keys() {
+                if id.is_empty() || id.contains(|c| c == '#' || c == '+' || c == '/') {
+                    problems.push(Problem::new(format!("mqtt.readers.{}", id),
+                        "id must not be empty or contain #, + or /"));
+                }
+            }
+            if !mqtt.readers.is_empty() && self.cards.is_none() {
+                problems.push(Problem::new("mqtt.readers", "require a card store set in `cards`"));
+            }
+        }
+        if let Some(ref cards) = self.cards {
+            check_file(&mut problems, "cards", cards);
+        }
+
+        if let Some(ref dir) = self.modules.plugin_dir {
+            if !cfg!(feature = "plugins") {
+                problems.push(Problem::new("modules.plugin_dir",
+                    "plugins need diflouroborane built with the plugins feature"));
+            } else if !dir.is_dir() {
+                problems.push(Problem::new("modules.plugin_dir",
+                    format!("{} is not a directory", dir.display())));
+            }
+        }
+
+        if self.daemon.worker_threads == Some(0) {
+            problems.push(Problem::new("daemon.worker_threads", "must be at least 1"));
+        }
+
+        if Level::from_str(&self.log.level).is_err() {
+            problems.push(Problem::new("log.level",
+                format!("unknown log level \"{}\"", self.log.level)));
+        }
+        for (system, level) in self.log.levels.iter() {
+            if Level::from_str(level).is_err() {
+                problems.push(Problem::new(format!("log.levels.{}", system),
+                    format!("unknown log level \"{}\"", level)));
+            }
+        }
+        if let Some(ref file) = self.log.file {
+            check_parent(&mut problems, "log.file", file);
+        }
+        if let Some(ref path) = self.audit.path {
+            check_parent(&mut problems, "audit.path", path);
+        }
+        if let Some(ref path) = self.stats.path {
+            if path.exists() {
+                check_file(&mut problems, "stats.path", path);
+            } else {
+                check_parent(&mut problems, "stats.path", path);
+            }
+        }
+        if self.stats.flush_interval == 0 {
+            problems.push(Problem::new("stats.flush_interval", "must be at least 1"));
+        }
+        for name in self.gc.caps.keys().filter(|n| !gc::NAMES.contains(&n.as_str())) {
+            problems.push(Problem::new(format!("gc.caps.{}", name), format!(
+                "there is no such registry, expected one of {}", gc::NAMES.join(", "))));
+        }
+
+        problems
+    }
+
+    /// The inline machines that don't set a perm with the one they get from the template
+    pub fn derived_perms(&self) -> Vec<(Uuid, String)> {
+        let template = match self.machines.perm_template {
+            Some(ref t) => t,
+            None => return Vec::new(),
+        };
+        self.inline_machines.iter()
+            .filter(|m| m.perm.is_empty())
+            .map(|m| (m.uuid, machine::derive_perm(template, &m.uuid, &m.name, &m.location)))
+            .collect()
+    }
+}
+
+/// Join a relative `path` onto `dir`, leaving absolute ones alone
+///
+/// `..` is resolved by dropping the previous component instead of asking the filesystem, since
+/// the file doesn't have to exist yet.
+fn resolve(dir: &Path, path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        return path.to_path_buf();
+    }
+
+    let mut resolved = dir.to_path_buf();
+    for c in path.components() {
+        match c {
+            Component::CurDir => {},
+            Component::ParentDir => { resolved.pop(); },
+            c => resolved.push(c),
+        }
+    }
+    resolved
+}
+
+/// A mistake in the config found by `Config::validate`
+#[derive(Debug, Clone)]
+pub struct Problem {
+    /// Path of the offending key in TOML notation, e.g. `listen[1].address`
+    pub key: String,
+    pub msg: String,
+}
+
+impl Problem {
+    fn new<K: Into<String>, M: Into<String>>(key: K, msg: M) -> Self {
+        Problem { key: key.into(), msg: msg.into() }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.msg)
+    }
+}
+
+/// The file at `path` has to exist and be readable
+fn check_file(problems: &mut Vec<Problem>, key: &str, path: &Path) {
+    if path.is_dir() {
+        problems.push(Problem::new(key, format!("{} is a directory, expected a file",
+            path.display())));
+    } else if let Err(e) = File::open(path) {
+        problems.push(Problem::new(key, format!("can not read {}: {}", path.display(), e)));
+    }
+}
+
+/// The directory `path` would be created in has to exist
+fn check_parent(problems: &mut Vec<Problem>, key: &str, path: &Path) {
+    // Relative paths without a directory part live in the current one
+    let dir = match path.parent() {
+        Some(d) if d != Path::new("") => d,
+        _ => return,
+    };
+    if !dir.is_dir() {
+        problems.push(Problem::new(key, format!("directory {} does not exist", dir.display())));
+    }
+}
+
+/// A webhook has to have a URL we can send to and a method and headers that can't break the
+/// request
+fn check_http(problems: &mut Vec<Problem>, key: &str, url: &str, method: &str,
+    headers: &BTreeMap<String, Secret>)
+{
+    match url::Url::parse(url) {
+        Ok(u) if u.scheme() != "http" && u.scheme() != "https" =>
+            problems.push(Problem::new(format!("{}.url", key), "must be an http or https URL")),
+        Ok(u) if u.host_str().is_none() =>
+            problems.push(Problem::new(format!("{}.url", key), "must have a host")),
+        Ok(_) => {},
+        Err(e) => problems.push(Problem::new(format!("{}.url", key), format!("is invalid: {}", e))),
+    }
+    if method.is_empty() || !method.chars().all(|c| c.is_ascii_alphabetic()) {
+        problems.push(Problem::new(format!("{}.method", key), "must be a word like POST"));
+    }
+    for (name, value) in headers.iter() {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            problems.push(Problem::new(format!("{}.headers", key),
+                format!("{} is not a valid header name", name)));
+        }
+        if value.expose().contains(|c| c == '\r' || c == '\n') {
+            problems.push(Problem::new(format!("{}.headers.{}", key, name),
+                "must not contain line breaks"));
+        }
+    }
+}
+
+fn is_hostname(s: &str) -> bool {
+    !s.is_empty() && s.len() <= 253 && s.split('.').all(|label| !label.is_empty()
+        && label.len() <= 63
+        && !label.starts_with('-')
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+}
+
+fn same_listen(a: &Listen, b: &Listen) -> bool {
+    match (a, b) {
+        // The kind doesn't matter, the second one would fail to bind either way
+        (Listen::Tcp { address: a, port: pa, .. }, Listen::Tcp { address: b, port: pb, .. }) => {
+            let same_address = match (a.parse::<IpAddr>(), b.parse::<IpAddr>()) {
+                (Ok(a), Ok(b)) => a == b,
+                _ => a.eq_ignore_ascii_case(b),
+            };
+            same_address && pa.unwrap_or(DEFAULT_PORT) == pb.unwrap_or(DEFAULT_PORT)
+        },
+        (Listen::Unix { path: a, .. }, Listen::Unix { path: b, .. }) => a == b,
+        _ => false,
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Access {
     pub(crate) model: PathBuf,
-    pub(crate) policy: PathBuf
+    pub(crate) policy: PathBuf,
+    /// Where permissions granted for a limited time are kept. Without it they can't be granted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) grants: Option<PathBuf>,
+    /// Seconds a permission check may take before it's given up on
+    pub(crate) enforce_timeout: u64,
+    /// Checks in a row timing out after which they aren't even tried anymore
+    pub(crate) breaker_threshold: u32,
+    /// Allow `read` while checks aren't tried instead of denying everything
+    pub(crate) breaker_allow_read: bool,
+    /// Refuse to start if machines and policy rules don't fit together instead of only warning
+    pub(crate) strict: bool,
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Self {
+            model: PathBuf::from("/tmp/model.conf"),
+            policy: PathBuf::from("/tmp/policy.csv"),
+            grants: None,
+            enforce_timeout: 2,
+            breaker_threshold: 3,
+            breaker_allow_read: false,
+            strict: false,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Listen {
-    pub address: String,
-    pub port: Option<u16>,
+pub struct Auth {
+    /// What new passwords have to look like
+    #[serde(default)]
+    pub policy: PasswordPolicy,
+    /// Contact details and whatever else we know about users besides their password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub users: Option<PathBuf>,
+    /// Seconds the token for claiming a fresh install works after starting
+    #[serde(default = "default_setup_expiry")]
+    pub setup_expiry: u64,
+    /// Where password reset tokens are kept, hashed. Without it admins can't issue any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resets: Option<PathBuf>,
+    /// Seconds a password reset token works after it was issued
+    #[serde(default = "default_reset_expiry")]
+    pub reset_expiry: u64,
 }
 
-impl Default for Config {
+impl Default for Auth {
     fn default() -> Self {
-        Config {
-            machinedb: PathBuf::from_str("/tmp/machines.db").unwrap(),
-            access: Access {
-                model: PathBuf::from_str("/tmp/model.conf").unwrap(),
-                policy: PathBuf::from_str("/tmp/policy.csv").unwrap(),
-            },
-            passdb: PathBuf::from_str("/tmp/passwd.db").unwrap(),
-            listen: Box::new([Listen {
-                    address: "127.0.0.1".to_string(),
-                    port: Some(DEFAULT_PORT)
-                },
-                Listen {
-                    address: "::1".to_string(),
-                    port: Some(DEFAULT_PORT)
-            }]),
+        Auth {
+            policy: PasswordPolicy::default(),
+            users: None,
+            setup_expiry: default_setup_expiry(),
+            resets: None,
+            reset_expiry: default_reset_expiry(),
         }
     }
 }
 
-// The default port in the non-assignable i.e. free-use area
-pub const DEFAULT_PORT: u16 = 59661;
+fn default_setup_expiry() -> u64 {
+    3600
+}
+
+fn default_reset_expiry() -> u64 {
+    24 * 3600
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasswordPolicy {
+    /// In characters
+    #[serde(default = "default_min_length")]
+    pub min_length: usize,
+    /// How many of lowercase letters, uppercase letters, digits and everything else a password has
+    /// to contain
+    #[serde(default = "default_require_classes")]
+    pub require_classes: usize,
+    /// Refuse passwords containing the name of the user
+    #[serde(default = "default_true")]
+    pub deny_username: bool,
+    /// Refuse passwords on the list of common passwords built in
+    #[serde(default = "default_true")]
+    pub deny_common: bool,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: default_min_length(),
+            require_classes: default_require_classes(),
+            deny_username: true,
+            deny_common: true,
+        }
+    }
+}
+
+fn default_min_length() -> usize {
+    10
+}
+
+fn default_require_classes() -> usize {
+    2
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Machines {
+    /// How the machine database at `machinedb` is stored
+    #[serde(default)]
+    pub backend: MachineBackend,
+    /// How many seconds a freed machine is reserved for the head of its queue
+    #[serde(default = "default_queue_hold")]
+    pub queue_hold: u64,
+    /// How many seconds a machine may be in use at most. Unlimited if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_use: Option<u64>,
+    /// What the perm of machines that don't set one is derived from, e.g. `machines.{name}`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub perm_template: Option<String>,
+    /// Time zone opening hours of machines are in, e.g. `Europe/Berlin`
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    /// Where users waiting for machines to become free are kept. Without it nobody can wait.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watches: Option<PathBuf>,
+    /// Seconds after which users waiting for a machine that didn't become free stop waiting
+    #[serde(default = "default_watch_expiry")]
+    pub watch_expiry: u64,
+    /// How users are told about machines becoming free, each tried after the one before failed
+    #[serde(default = "default_notify_channels")]
+    pub notify_channels: Vec<String>,
+    /// Seconds machines being used and given back may wait to be saved, so many of them at once
+    /// are saved together. 0 saves every change right away.
+    #[serde(default = "default_write_interval")]
+    pub write_interval: u64,
+    /// Whether machines being used and given back go to a journal next to `machinedb` first, so
+    /// they survive a crash while waiting to be saved. Only used with a `write_interval`.
+    #[serde(default = "default_journal")]
+    pub journal: bool,
+    /// Records appended to the journal between two syncs to disk
+    #[serde(default = "default_journal_sync")]
+    pub journal_sync: u32,
+    /// Seconds API calls wait for the machine database while something else holds it before
+    /// telling the client to retry. Background tasks keep waiting but warn after as long.
+    #[serde(default = "default_lock_timeout")]
+    pub lock_timeout: u64,
+    /// Devices switching the power of machines, by machine UUID
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub actors: BTreeMap<Uuid, Actor>,
+}
+
+impl Default for Machines {
+    fn default() -> Self {
+        Machines {
+            backend: MachineBackend::default(),
+            queue_hold: default_queue_hold(),
+            max_use: None,
+            perm_template: None,
+            timezone: default_timezone(),
+            watches: None,
+            watch_expiry: default_watch_expiry(),
+            notify_channels: default_notify_channels(),
+            write_interval: default_write_interval(),
+            journal: default_journal(),
+            journal_sync: default_journal_sync(),
+            lock_timeout: default_lock_timeout(),
+            actors: BTreeMap::new(),
+        }
+    }
+}
+
+fn default_queue_hold() -> u64 {
+    300
+}
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+fn default_watch_expiry() -> u64 {
+    24 * 3600
+}
+
+fn default_write_interval() -> u64 {
+    2
+}
+
+fn default_journal() -> bool {
+    true
+}
+
+fn default_journal_sync() -> u32 {
+    1
+}
+
+fn default_lock_timeout() -> u64 {
+    2
+}
+
+/// Ways of telling users about machines becoming free there are
+const NOTIFY_CHANNELS: &[&str] = &["event", "matrix", "email"];
+
+fn default_notify_channels() -> Vec<String> {
+    vec!["event".to_string()]
+}
+
+/// A device that powers a machine on while it's in use and off otherwise
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Actor {
+    #[serde(rename = "type")]
+    pub kind: ActorKind,
+    /// Tasmota: MQTT topic to send the commands to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// Tasmota: MQTT topic the device reports its state on. Defaults to `topic` with `cmnd/`
+    /// replaced by `stat/`, which is where Tasmota reports.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stat_topic: Option<String>,
+    /// Shelly: topic prefix of the device, which is its device id unless changed on the device
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// Shelly: which switch of the device powers the machine
+    #[serde(default)]
+    pub channel: u8,
+    /// Exec: program and arguments to run. `{uuid}`, `{name}`, `{state}`, `{occupant}` and
+    /// `{power}` in them are replaced with the machine's.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub command: Vec<String>,
+    /// Http: where to send requests to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Http: request method
+    #[serde(default = "default_http_method")]
+    pub method: String,
+    /// Http: additional request headers, e.g. for authorization
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, Secret>,
+    /// Http: JSON body with the same placeholders as `command`. Everything we know about the
+    /// machine if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// Http: whether to check the certificate of https URLs
+    #[serde(default = "default_true")]
+    pub verify_tls: bool,
+    /// Gpio: character device of the GPIO chip the relay is wired to
+    #[serde(default = "default_gpio_chip")]
+    pub chip: PathBuf,
+    /// Gpio: offset of the line switching the relay on the chip
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<u32>,
+    /// Gpio: offset of a line reading back whether the machine has power, to confirm switching
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub feedback_line: Option<u32>,
+    /// Gpio: whether the lines are low for power on, which is the case for most relay boards.
+    /// Applies to the feedback line as well.
+    #[serde(default)]
+    pub active_low: bool,
+    /// Gpio: keep the line level in this file instead of using the chip, for trying out a config
+    /// on a machine without GPIOs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub simulate: Option<PathBuf>,
+    /// Seconds the device has to confirm it switched, or the command has to finish, before we
+    /// consider it failed
+    #[serde(default = "default_actor_timeout")]
+    pub timeout: u64,
+    /// How often a failed switch is tried again before giving up on the device
+    #[serde(default = "default_actor_retries")]
+    pub retries: u32,
+    /// Block the machine once we gave up on the device, instead of only giving it back if it
+    /// couldn't be switched on
+    #[serde(default)]
+    pub block_offline: bool,
+}
+
+fn default_actor_timeout() -> u64 {
+    5
+}
+
+fn default_actor_retries() -> u32 {
+    3
+}
+
+fn default_gpio_chip() -> PathBuf {
+    PathBuf::from("/dev/gpiochip0")
+}
+
+fn default_http_method() -> String {
+    "POST".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Actor {
+    /// Request headers with their values exposed
+    pub fn headers(&self) -> Vec<(String, String)> {
+        expose_headers(&self.headers)
+    }
+}
+
+fn expose_headers(headers: &BTreeMap<String, Secret>) -> Vec<(String, String)> {
+    headers.iter().map(|(k, v)| (k.clone(), v.expose().to_string())).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActorKind {
+    /// A relay running Tasmota, switched with ON and OFF on its POWER topic
+    Tasmota,
+    /// A Shelly Gen2 device, switched with `Switch.Set` RPC calls
+    Shelly,
+    /// Runs a command on every change, for devices driven by scripts or vendor tools
+    Exec,
+    /// Calls a webhook on every change, e.g. of a door controller
+    Http,
+    /// A relay on a GPIO line of the machine we run on, e.g. a Raspberry Pi
+    Gpio,
+    /// Only logs what it would do, for trying out a config without any devices attached
+    Dummy,
+}
+
+impl ActorKind {
+    /// Whether the device is switched through the MQTT broker
+    pub fn needs_mqtt(&self) -> bool {
+        match self {
+            ActorKind::Tasmota | ActorKind::Shelly => true,
+            ActorKind::Exec | ActorKind::Http | ActorKind::Gpio | ActorKind::Dummy => false,
+        }
+    }
+}
+
+/// Somebody to tell about machines changing their state, e.g. a chat
+///
+/// Unlike actors, notifiers failing has no effect on the machines. They're only logged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notifier {
+    #[serde(rename = "type")]
+    pub kind: NotifierKind,
+    pub url: String,
+    #[serde(default = "default_http_method")]
+    pub method: String,
+    /// Additional request headers, e.g. for authorization
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, Secret>,
+    /// JSON body with the same placeholders as the command of exec actors. Everything we know
+    /// about the machine if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// Whether to check the certificate of https URLs
+    #[serde(default = "default_true")]
+    pub verify_tls: bool,
+    /// Seconds the endpoint has to answer
+    #[serde(default = "default_actor_timeout")]
+    pub timeout: u64,
+    /// How often a failed request is tried again before giving up on it
+    #[serde(default = "default_actor_retries")]
+    pub retries: u32,
+    /// States of machines worth telling about, all of them if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<MachineEvent>,
+    /// Machines worth telling about, all of them if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub machines: Vec<Uuid>,
+}
+
+impl Notifier {
+    /// Request headers with their values exposed
+    pub fn headers(&self) -> Vec<(String, String)> {
+        expose_headers(&self.headers)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifierKind {
+    /// Calls a webhook
+    Http,
+}
+
+/// HTTP callbacks about machines being used and given back, e.g. for a booking system
+///
+/// Unlike notifiers these don't miss events. Deliveries are kept in `outbox` until they succeeded,
+/// across restarts, and retried with growing delays.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhooks {
+    /// Where deliveries not made yet are kept. Required if there are any hooks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outbox: Option<PathBuf>,
+    /// Key requests are signed with, for hooks without their own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<Secret>,
+    /// How often a delivery is tried before giving up on it
+    #[serde(default = "default_webhook_attempts")]
+    pub max_attempts: u32,
+    #[serde(default, rename = "hook", skip_serializing_if = "Vec::is_empty")]
+    pub hooks: Vec<Webhook>,
+}
+
+impl Default for Webhooks {
+    fn default() -> Self {
+        Self {
+            outbox: None,
+            secret: None,
+            max_attempts: default_webhook_attempts(),
+            hooks: Vec::new(),
+        }
+    }
+}
+
+fn default_webhook_attempts() -> u32 {
+    20
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub url: String,
+    /// Key requests to this hook are signed with instead of `webhooks.secret`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<Secret>,
+    /// Machines to call the hook for, all of them if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub machines: Vec<Uuid>,
+    /// Whether to check the certificate of https URLs
+    #[serde(default = "default_true")]
+    pub verify_tls: bool,
+    /// Seconds the endpoint has to answer
+    #[serde(default = "default_actor_timeout")]
+    pub timeout: u64,
+}
+
+/// Credentials of a user set aside for monitoring, from the password database
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatusClient {
+    pub user: String,
+    pub token: Secret,
+}
+
+/// A machine entering a state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MachineEvent {
+    Free,
+    Occupied,
+    Blocked,
+}
+
+/// A machine defined in the config file
+///
+/// Its metadata is authoritative and replaces whatever the machine database has for the same
+/// UUID. Only its status is kept in the machine database.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InlineMachine {
+    pub uuid: Uuid,
+    pub name: String,
+    pub location: String,
+    /// Derived from `machines.perm_template` if not set
+    #[serde(default)]
+    pub perm: String,
+}
+
+impl InlineMachine {
+    /// A fresh machine record with this metadata
+    pub fn to_machine(&self) -> Machine {
+        Machine::new(self.name.clone(), self.location.clone(), self.perm.clone())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MachineBackend {
+    /// A single TOML file
+    Toml,
+    /// A sled database directory
+    Sled,
+}
+
+impl Default for MachineBackend {
+    fn default() -> Self {
+        MachineBackend::Toml
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Api {
+    /// Only hand out the machines and permissions subsystems to authenticated connections
+    #[serde(default)]
+    pub require_auth_for_bootstrap: bool,
+    /// Only offer sending passwords on connections that are encrypted. We don't speak TLS
+    /// ourselves, so that's those over a Unix socket or loopback, e.g. from a proxy doing TLS.
+    #[serde(default)]
+    pub require_tls: bool,
+    /// Maximum number of concurrently open connections. Further clients are told the server is
+    /// overloaded.
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// Maximum number of concurrently open connections from a single address. Further connections
+    /// from it are refused.
+    #[serde(default)]
+    pub max_connections_per_peer: Option<usize>,
+    /// Close connections that were idle for this many seconds. 0 disables the timeout.
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// What to do with idle connections that still have machines in use
+    #[serde(default)]
+    pub idle_grants: IdleGrants,
+    /// Close connections the client sent nothing over for this many seconds, not even a ping.
+    /// Unlike the idle timeout this ignores what we sent. 0 disables it.
+    #[serde(default)]
+    pub dead_after: u64,
+    /// Have the kernel probe TCP connections that are silent, so dead ones are noticed even if
+    /// neither side sends anything
+    #[serde(default)]
+    pub tcp_keepalive: Option<TcpKeepalive>,
+    /// Limit how many calls to the machines and permissions subsystems clients can make
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    /// Largest message a client may send. Also limits how much of a message is read in total,
+    /// so messages pointing to the same data over and over don't get around it.
+    #[serde(default = "default_max_message_bytes")]
+    pub max_message_bytes: u64,
+    /// How deep structs and lists in a message may be nested
+    #[serde(default = "default_max_nesting")]
+    pub max_nesting: i32,
+}
+
+impl Default for Api {
+    fn default() -> Self {
+        Api {
+            require_auth_for_bootstrap: false,
+            require_tls: false,
+            max_connections: None,
+            max_connections_per_peer: None,
+            idle_timeout: default_idle_timeout(),
+            idle_grants: IdleGrants::default(),
+            dead_after: 0,
+            tcp_keepalive: None,
+            rate_limit: None,
+            max_message_bytes: default_max_message_bytes(),
+            max_nesting: default_max_nesting(),
+        }
+    }
+}
+
+fn default_idle_timeout() -> u64 {
+    15 * 60
+}
+
+fn default_max_message_bytes() -> u64 {
+    // None of our messages come anywhere close
+    1024 * 1024
+}
+
+fn default_max_nesting() -> i32 {
+    64
+}
+
+/// When and how often the kernel probes silent TCP connections
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TcpKeepalive {
+    /// Seconds a connection has to be silent before the first probe
+    pub idle: u32,
+    /// Seconds between probes
+    pub interval: u32,
+    /// Unanswered probes after which the connection is dropped
+    pub count: u32,
+}
+
+/// Token bucket parameters, applied to every connection and every user on their own
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// Calls allowed per second on average
+    pub per_second: u32,
+    /// Calls allowed in a row before the average kicks in
+    pub burst: u32,
+    /// Don't limit connections over loopback or Unix sockets
+    #[serde(default)]
+    pub exempt_local: bool,
+    /// Users that are never limited, e.g. admins running scripts
+    #[serde(default)]
+    pub exempt_users: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdleGrants {
+    /// Keep connections that have machines in use open regardless of the idle timeout
+    Exempt,
+    /// Give back all machines the connection has in use and close it
+    GiveBack,
+}
+
+impl Default for IdleGrants {
+    fn default() -> Self {
+        IdleGrants::Exempt
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Daemon {
+    /// How many seconds to wait for open connections to finish when shutting down
+    #[serde(default = "default_shutdown_grace")]
+    pub shutdown_grace: u64,
+    /// User to switch to after binding the listening sockets
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Group to switch to after binding the listening sockets. Defaults to the primary group of
+    /// `user`.
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Seconds to wait before trying again to bind listen addresses that failed at startup, 0 to
+    /// not retry. The delay doubles after every failed attempt.
+    ///
+    /// This lives here since `listen` is the list of addresses.
+    #[serde(default)]
+    pub bind_retry: u64,
+    /// Number of threads for CPU-heavy work like checking passwords. Defaults to the number of
+    /// CPUs.
+    #[serde(default)]
+    pub worker_threads: Option<usize>,
+    /// Start in read-only mode, see `MachinesProvider::set_read_only`
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+impl Default for Daemon {
+    fn default() -> Self {
+        Daemon {
+            shutdown_grace: default_shutdown_grace(),
+            user: None,
+            group: None,
+            bind_retry: 0,
+            worker_threads: None,
+            read_only: false,
+        }
+    }
+}
+
+fn default_shutdown_grace() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Log {
+    /// Write the log to this file instead of the terminal. The file is reopened on SIGHUP.
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Least severe level to log, one of "trace", "debug", "info", "warn", "error" and
+    /// "critical"
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    /// Levels for single subsystems, overriding `level`. Keys are the names of the subsystems as
+    /// they appear in the `system` field of log messages.
+    #[serde(default)]
+    pub levels: HashMap<String, String>,
+    /// Seconds identical messages are counted in. Past `dedup_threshold` of them in that time the
+    /// rest are only counted, and logged as one summary once it's over. 0 logs every one of them.
+    #[serde(default = "default_dedup_window")]
+    pub dedup_window: u64,
+    #[serde(default = "default_dedup_threshold")]
+    pub dedup_threshold: u32,
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Log {
+            file: None,
+            format: LogFormat::default(),
+            level: default_log_level(),
+            levels: HashMap::new(),
+            dedup_window: default_dedup_window(),
+            dedup_threshold: default_dedup_threshold(),
+        }
+    }
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_dedup_window() -> u64 {
+    60
+}
+
+fn default_dedup_threshold() -> u32 {
+    5
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Audit {
+    /// File to append the audit trail to. Without one no audit trail is kept.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Stats {
+    /// File to keep daily counts in. Without one nothing is counted.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Seconds between writes of the counts, which is how many of them a crash may lose
+    #[serde(default = "default_flush_interval")]
+    pub flush_interval: u64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self { path: None, flush_interval: default_flush_interval() }
+    }
+}
+
+fn default_flush_interval() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Gc {
+    /// Seconds between prunings of the in-memory registries, 0 to only prune those over their cap
+    #[serde(default = "default_gc_interval")]
+    pub interval: u64,
+    /// Entries a registry may hold before it's pruned without waiting for `interval`
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    /// `max_entries` of single registries, by their name in `gc::NAMES`
+    #[serde(default)]
+    pub caps: BTreeMap<String, usize>,
+}
+
+impl Default for Gc {
+    fn default() -> Self {
+        Self { interval: default_gc_interval(), max_entries: default_max_entries(),
+            caps: BTreeMap::new() }
+    }
+}
+
+fn default_gc_interval() -> u64 {
+    300
+}
+
+fn default_max_entries() -> usize {
+    10000
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Modules {
+    /// What to do if a module fails to start
+    #[serde(default)]
+    pub on_failure: OnFailure,
+    /// Directory to load plugin modules from, every shared object in it is one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plugin_dir: Option<PathBuf>,
+    /// The `[modules.<name>]` tables, which each module reads itself
+    #[serde(flatten)]
+    pub sections: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnFailure {
+    /// Log it and keep running without the module
+    Warn,
+    /// Refuse to start
+    Fatal,
+}
+
+impl Default for OnFailure {
+    fn default() -> Self {
+        OnFailure::Warn
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mqtt {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    /// Connect using TLS, verifying the broker's certificate against the usual CAs
+    #[serde(default)]
+    pub tls: bool,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<Secret>,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    /// Seconds between keep alive pings
+    #[serde(default = "default_mqtt_keep_alive")]
+    pub keep_alive: u16,
+    /// Retained topic we publish "online" to while connected and the broker "offline" after.
+    /// Machine states must be taken as stale while it's "offline".
+    #[serde(default = "default_mqtt_availability_topic")]
+    pub availability_topic: String,
+    /// How machine states are published. Also used for the availability and Home Assistant
+    /// announcements, which are always retained though.
+    #[serde(default = "default_mqtt_state_publication")]
+    pub state: Publication,
+    /// How results for card readers are published
+    #[serde(default)]
+    pub events: Publication,
+    /// We subscribe to everything below this
+    #[serde(default = "default_mqtt_command_prefix")]
+    pub command_prefix: String,
+    /// The state of every machine is published, retained, to `<machine_prefix>/<uuid>/state`
+    #[serde(default = "default_mqtt_machine_prefix")]
+    pub machine_prefix: String,
+    /// Include the note given with a use in machine states. Anybody subscribed to them gets to
+    /// read what members are working on, so this is off unless asked for.
+    #[serde(default)]
+    pub publish_notes: bool,
+    /// Card readers publish swipes to `<reader_prefix>/<id>/swipe` and get the result on
+    /// `<reader_prefix>/<id>/result`
+    #[serde(default = "default_mqtt_reader_prefix")]
+    pub reader_prefix: String,
+    /// Card readers by id
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub readers: BTreeMap<String, Reader>,
+    /// Announce machines to Home Assistant through MQTT discovery
+    #[serde(default)]
+    pub hass_discovery: bool,
+    /// Discovery prefix Home Assistant listens on
+    #[serde(default = "default_mqtt_hass_prefix")]
+    pub hass_prefix: String,
+    /// Also announce a switch showing the power of every machine with an actor
+    #[serde(default)]
+    pub hass_switches: bool,
+}
+
+/// QoS and retain flag for messages of one kind
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Publication {
+    /// 0 or 1, we don't do exactly once delivery
+    #[serde(default)]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+}
+
+/// A card reader mounted at a machine, letting members use it by swiping their card
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reader {
+    pub machine: Uuid,
+    /// Sent along with every swipe so we know it's from the reader. Without one anybody allowed
+    /// to publish on the swipe topic can use the machine in any member's name, so the broker has
+    /// to make sure only the reader can.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<Secret>,
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "diflouroborane".to_string()
+}
+
+fn default_mqtt_keep_alive() -> u16 {
+    30
+}
+
+fn default_mqtt_availability_topic() -> String {
+    "fabaccess/status".to_string()
+}
+
+fn default_mqtt_state_publication() -> Publication {
+    Publication { qos: 1, retain: true }
+}
+
+fn default_mqtt_command_prefix() -> String {
+    "fabaccess/command".to_string()
+}
+
+fn default_mqtt_machine_prefix() -> String {
+    "fabaccess/machines".to_string()
+}
+
+fn default_mqtt_reader_prefix() -> String {
+    "fabaccess/readers".to_string()
+}
+
+fn default_mqtt_hass_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    Term,
+    /// One JSON object per line
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Term
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Listen {
+    Tcp {
+        address: String,
+        port: Option<u16>,
+        /// What is spoken on the socket
+        #[serde(default, skip_serializing_if = "ListenKind::is_raw")]
+        kind: ListenKind,
+        /// Values of the `Origin` header a WebSocket upgrade is accepted from. Requests without
+        /// one, i.e. not coming from a browser, are always accepted.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        origins: Vec<String>,
+        /// Peers connections are accepted from, everybody if empty
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        allow: Vec<Cidr>,
+        /// Peers connections are never accepted from, even if they're in `allow`
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        deny: Vec<Cidr>,
+    },
+    Unix {
+        path: PathBuf,
+        /// Permission bits to set on the socket file, e.g. `0o660`
+        mode: Option<u32>,
+        /// Owner to set on the socket file, as `user` or `user:group`
+        owner: Option<String>,
+    },
+}
+
+impl Listen {
+    /// A plain TCP listener
+    pub fn tcp(address: String, port: Option<u16>) -> Self {
+        Listen::Tcp { address, port, kind: ListenKind::Raw, origins: Vec::new(), allow: Vec::new(),
+            deny: Vec::new() }
+    }
+}
+
+/// Protocol spoken on a TCP listener
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenKind {
+    /// The capnp RPC protocol directly on the socket
+    Raw,
+    /// The capnp RPC protocol in binary WebSocket messages, for browsers
+    WebSocket,
+}
+
+impl ListenKind {
+    fn is_raw(&self) -> bool {
+        *self == ListenKind::Raw
+    }
+}
+
+impl Default for ListenKind {
+    fn default() -> Self {
+        ListenKind::Raw
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Listen::Tcp { address, port, kind: ListenKind::WebSocket, .. } =>
+                write!(f, "{} port {} (websocket)", address, port.unwrap_or(DEFAULT_PORT)),
+            Listen::Tcp { address, port, .. } =>
+                write!(f, "{} port {}", address, port.unwrap_or(DEFAULT_PORT)),
+            Listen::Unix { path, .. } => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            machinedb: PathBuf::from_str("/tmp/machines.db").unwrap(),
+            access: Access::default(),
+            auth: Auth::default(),
+            passdb: PathBuf::from_str("/tmp/passwd.db").unwrap(),
+            cards: None,
+            listen: Box::new([
+                Listen::tcp("127.0.0.1".to_string(), Some(DEFAULT_PORT)),
+                Listen::tcp("::1".to_string(), Some(DEFAULT_PORT)),
+            ]),
+            machines: Machines::default(),
+            inline_machines: Vec::new(),
+            api: Api::default(),
+            daemon: Daemon::default(),
+            log: Log::default(),
+            audit: Audit::default(),
+            stats: Stats::default(),
+            gc: Gc::default(),
+            mqtt: None,
+            notifiers: Vec::new(),
+            webhooks: Webhooks::default(),
+            status: None,
+            modules: Modules::default(),
+            include: None,
+            strict_config: false,
+            overrides: Vec::new(),
+            unknown: Vec::new(),
+            dry_run: None,
+        }
+    }
+}
+
+/// A config value that shouldn't be written into the config file itself, like a password
+///
+/// It can be given either as a plain string, as `{ file = "/run/secrets/x" }` to read it from a
+/// file or as `{ env = "VAR" }` to take it from an environment variable. Files and variables are
+/// read while loading the config. The value never shows up in debug output so it can't end up in
+/// the log by accident.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(<redacted>)")
+    }
+}
+
+/// Serializes to the plain value, since the config is serialized to apply overrides from the
+/// environment.
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Source {
+            Literal(String),
+            File { file: PathBuf },
+            Env { env: String },
+        }
+
+        match Source::deserialize(deserializer)? {
+            Source::Literal(s) => Ok(Secret(s)),
+            Source::File { file } => {
+                let s = fs::read_to_string(&file).map_err(|e| D::Error::custom(
+                    format!("could not read secret from {}: {}", file.display(), e)))?;
+                // Files written with an editor or `echo` end in a newline that isn't meant to be
+                // part of the secret.
+                Ok(Secret(s.trim_end_matches(|c| c == '\n' || c == '\r').to_string()))
+            },
+            Source::Env { env } => env::var(&env).map(Secret).map_err(|e| D::Error::custom(
+                format!("could not read secret from ${}: {}", env, e))),
+        }
+    }
+}
+
+// The default port in the non-assignable i.e. free-use area
+pub const DEFAULT_PORT: u16 = 59661;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::TempDir;
+
+    fn env(vars: &[(&str, &str)]) -> Result<Config> {
+        apply_env(Config::default(),
+            vars.iter().map(|(k, v)| (k.to_string(), v.to_string())))
+    }
+
+    #[test]
+    fn env_overrides() {
+        let config = env(&[
+            ("DIFLOUROBORANE_MACHINEDB", "/data/machines.toml"),
+            ("DIFLOUROBORANE_DAEMON__SHUTDOWN_GRACE", "30"),
+            ("DIFLOUROBORANE_STRICT_CONFIG", "true"),
+            // Not ours
+            ("PATH", "/bin"),
+        ]).unwrap();
+        assert_eq!(config.machinedb, PathBuf::from("/data/machines.toml"));
+        assert_eq!(config.daemon.shutdown_grace, 30);
+        assert!(config.strict_config);
+    }
+
+    #[test]
+    fn env_listen() {
+        let config = env(&[("DIFLOUROBORANE_LISTEN", "0.0.0.0:59661, ::1, /run/bffh.sock")])
+            .unwrap();
+        assert_eq!(config.listen.to_vec(), vec![
+            Listen::tcp("0.0.0.0".to_string(), Some(59661)),
+            Listen::tcp("::1".to_string(), None),
+            Listen::Unix { path: PathBuf::from("/run/bffh.sock"), mode: None, owner: None },
+        ]);
+    }
+
+    #[test]
+    fn env_errors_name_the_variable() {
+        for (var, val) in &[
+            ("DIFLOUROBORANE_DAEMON__SHUTDOWN_GRACE", "soon"),
+            ("DIFLOUROBORANE_STRICT_CONFIG", "yes"),
+            ("DIFLOUROBORANE_LISTEN", "localhost:http"),
+            ("DIFLOUROBORANE_LISTEN", ","),
+            ("DIFLOUROBORANE_DAEMON", "on"),
+        ] {
+            let e = env(&[(var, val)]).err()
+                .unwrap_or_else(|| panic!("{}={} was accepted", var, val));
+            assert!(e.to_string().contains(var), "\"{}\" doesn't name {}", e, var);
+        }
+    }
+
+    #[test]
+    fn resolving_paths() {
+        let dir = Path::new("/etc/diflouroborane");
+        assert_eq!(resolve(dir, Path::new("/var/lib/machines.toml")),
+            PathBuf::from("/var/lib/machines.toml"));
+        assert_eq!(resolve(dir, Path::new("machines.toml")),
+            PathBuf::from("/etc/diflouroborane/machines.toml"));
+        assert_eq!(resolve(dir, Path::new("./access/model.conf")),
+            PathBuf::from("/etc/diflouroborane/access/model.conf"));
+        assert_eq!(resolve(dir, Path::new("../../var/lib/./passdb.toml")),
+            PathBuf::from("/var/lib/passdb.toml"));
+    }
+
+    #[test]
+    fn read_resolves_relative_to_the_file() {
+        let dir = TempDir::new();
+        fs::create_dir(dir.join("etc")).unwrap();
+        let path = dir.join("etc/config.toml");
+        fs::write(&path, r#"
+            machinedb = "machines.toml"
+            passdb = "/var/lib/diflouroborane/passdb.toml"
+
+            [access]
+            model = "./access/model.conf"
+            policy = "../shared/policy.csv"
+        "#).unwrap();
+
+        let config = read(&path).unwrap();
+        let base = dir.path().canonicalize().unwrap();
+        assert_eq!(config.machinedb, base.join("etc/machines.toml"));
+        assert_eq!(config.passdb, PathBuf::from("/var/lib/diflouroborane/passdb.toml"));
+        assert_eq!(config.access.model, base.join("etc/access/model.conf"));
+        assert_eq!(config.access.policy, base.join("shared/policy.csv"));
+    }
+
+    #[test]
+    fn default_template_reads_back() {
+        let config: Config = toml::from_str(default_annotated().unwrap()).unwrap();
+        assert_eq!(Value::try_from(&config).unwrap(), Value::try_from(&Config::default()).unwrap());
+    }
+
+    #[test]
+    fn default_written_privately() {
+        let dir = TempDir::new();
+        let path = dir.join("config.toml");
+        // Even over a file anybody could read before
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_default(&path, FileFormat::Toml).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_to_string(&path).unwrap(), DEFAULT_CONFIG);
+    }
+
+    /// A main config including `conf.d` with `files` in it, returning the main config's path
+    fn with_includes(dir: &TempDir, files: &[(&str, &str)]) -> PathBuf {
+        let path = dir.join("config.toml");
+        fs::write(&path, r#"
+            include = "conf.d"
+            machinedb = "/var/lib/machines.toml"
+            listen = [{ address = "127.0.0.1" }]
+
+            [daemon]
+            shutdown_grace = 10
+        "#).unwrap();
+        fs::create_dir(dir.join("conf.d")).unwrap();
+        for (name, contents) in files {
+            fs::write(dir.join("conf.d").join(name), contents).unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn includes_merge_in_order() {
+        let dir = TempDir::new();
+        let path = with_includes(&dir, &[
+            ("20-site.toml", r#"
+                machinedb = "/srv/machines.toml"
+                listen = [{ address = "::1" }]
+            "#),
+            ("10-base.json", r#"{
+                "machinedb": "/opt/machines.toml",
+                "daemon": { "bind_retry": 5 }
+            }"#),
+            // Neither a config file nor a directory to look into
+            ("README", "machinedb = \"/nowhere\""),
+        ]);
+
+        let config = read(&path).unwrap();
+        assert_eq!(config.machinedb, PathBuf::from("/srv/machines.toml"));
+        // Tables are merged, not replaced
+        assert_eq!(config.daemon.shutdown_grace, 10);
+        assert_eq!(config.daemon.bind_retry, 5);
+        assert_eq!(config.listen.to_vec(), vec![
+            Listen::tcp("127.0.0.1".to_string(), None),
+            Listen::tcp("::1".to_string(), None),
+        ]);
+
+        let overrides: Vec<_> = config.overrides.iter()
+            .map(|o| (o.key.as_str(), o.file.file_name().unwrap().to_str().unwrap()))
+            .collect();
+        assert_eq!(overrides, vec![("machinedb", "10-base.json"), ("machinedb", "20-site.toml")]);
+    }
+
+    #[test]
+    fn includes_may_be_empty() {
+        let dir = TempDir::new();
+        let config = read(&with_includes(&dir, &[])).unwrap();
+        assert_eq!(config.machinedb, PathBuf::from("/var/lib/machines.toml"));
+        assert!(config.overrides.is_empty());
+    }
+
+    #[test]
+    fn broken_include_fails() {
+        let dir = TempDir::new();
+        let path = with_includes(&dir, &[
+            ("10-good.toml", "machinedb = \"/srv/machines.toml\""),
+            ("20-broken.toml", "machinedb = "),
+        ]);
+        let e = read(&path).err().unwrap();
+        assert!(e.to_string().contains("20-broken.toml"), "\"{}\" doesn't name the file", e);
+
+        let dir = TempDir::new();
+        let path = with_includes(&dir, &[("10-nested.toml", "include = \"more.d\"")]);
+        assert!(read(&path).is_err());
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct WithSecret {
+        secret: Secret,
+    }
+
+    fn secret(toml: &str) -> std::result::Result<Secret, toml::de::Error> {
+        toml::from_str::<WithSecret>(toml).map(|w| w.secret)
+    }
+
+    #[test]
+    fn secret_sources() {
+        assert_eq!(secret(r#"secret = "hunter2""#).unwrap().expose(), "hunter2");
+
+        let dir = TempDir::new();
+        let path = dir.join("secret");
+        fs::write(&path, "from a file\n").unwrap();
+        let toml = format!("secret = {{ file = {:?} }}", path.to_str().unwrap());
+        assert_eq!(secret(&toml).unwrap().expose(), "from a file");
+
+        env::set_var("BFFH_TEST_SECRET", "from the environment");
+        let s = secret(r#"secret = { env = "BFFH_TEST_SECRET" }"#).unwrap();
+        assert_eq!(s.expose(), "from the environment");
+    }
+
+    #[test]
+    fn missing_secrets() {
+        let e = secret(r#"secret = { file = "/nonexistent/secret" }"#).unwrap_err();
+        assert!(e.to_string().contains("/nonexistent/secret"), "{}", e);
+        let e = secret(r#"secret = { env = "BFFH_TEST_UNSET_SECRET" }"#).unwrap_err();
+        assert!(e.to_string().contains("$BFFH_TEST_UNSET_SECRET"), "{}", e);
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        let s = secret(r#"secret = "hunter2""#).unwrap();
+        let debug = format!("{:?}", WithSecret { secret: s });
+        assert!(!debug.contains("hunter2"), "{}", debug);
+    }
+
+    #[test]
+    fn default_round_trips_in_every_format() {
+        let default = Value::try_from(&Config::default()).unwrap();
+        for (format, ext) in &[(FileFormat::Toml, "toml"), (FileFormat::Yaml, "yaml"),
+            (FileFormat::Json, "json")]
+        {
+            let dir = TempDir::new();
+            let path = dir.join(format!("config.{}", ext));
+            fs::write(&path, default_as(*format).unwrap()).unwrap();
+
+            let config = read(&path).unwrap();
+            assert!(config.unknown.is_empty(), "{:?}: unknown keys {:?}", format, config.unknown);
+            assert_eq!(Value::try_from(&config).unwrap(), default, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn empty_config_is_the_default() {
+        let dir = TempDir::new();
+        let path = dir.join("config.toml");
+        fs::write(&path, "").unwrap();
+        let config = read(&path).unwrap();
+        assert!(config.unknown.is_empty());
+        assert_eq!(Value::try_from(&config).unwrap(), Value::try_from(&Config::default()).unwrap());
+
+        // Same for a section that's there but empty
+        fs::write(&path, "[access]\n[machines]\n").unwrap();
+        let config = read(&path).unwrap();
+        assert_eq!(Value::try_from(&config).unwrap(), Value::try_from(&Config::default()).unwrap());
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let dir = TempDir::new();
+        let path = dir.join("config.toml");
+        fs::write(&path, r#"
+            machinedbb = "machines.toml"
+
+            [api]
+            max_conections = 10
+            idle_timeout = 60
+        "#).unwrap();
+        let mut config = read(&path).unwrap();
+        config.unknown.sort();
+        assert_eq!(config.unknown, vec!["api.max_conections", "machinedbb"]);
+        // Everything else is still read
+        assert_eq!(config.api.idle_timeout, 60);
+        assert_eq!(config.machinedb, Config::default().machinedb);
+
+        let mut strict = fs::read_to_string(&path).unwrap();
+        strict.insert_str(0, "strict_config = true\n");
+        fs::write(&path, strict).unwrap();
+        let e = read(&path).err().unwrap().to_string();
+        assert!(e.contains("machinedbb") && e.contains("api.max_conections"), "{}", e);
+    }
+
+    /// What `validate` finds wrong with a config that's fine apart from what `change` did to it
+    fn problems(change: impl FnOnce(&mut Config, &TempDir)) -> Vec<String> {
+        let dir = TempDir::new();
+        let mut config = Config::default();
+        config.machinedb = dir.join("machines.toml");
+        config.passdb = dir.join("passwd.toml");
+        config.access.model = dir.join("model.conf");
+        config.access.policy = dir.join("policy.csv");
+        fs::write(&config.access.model, "").unwrap();
+        fs::write(&config.access.policy, "").unwrap();
+        change(&mut config, &dir);
+        config.validate().iter().map(|p| p.key.clone()).collect()
+    }
+
+    #[test]
+    fn valid_config() {
+        assert_eq!(problems(|_, _| {}), Vec::<String>::new());
+    }
+
+    #[test]
+    fn too_little() {
+        let rules: Vec<(&str, Box<dyn Fn(&mut Config)>)> = vec![
+            ("access.enforce_timeout", Box::new(|c| c.access.enforce_timeout = 0)),
+            ("access.breaker_threshold", Box::new(|c| c.access.breaker_threshold = 0)),
+            ("machines.watch_expiry", Box::new(|c| c.machines.watch_expiry = 0)),
+            ("machines.journal_sync", Box::new(|c| c.machines.journal_sync = 0)),
+            ("machines.lock_timeout", Box::new(|c| c.machines.lock_timeout = 0)),
+            ("log.dedup_threshold", Box::new(|c| c.log.dedup_threshold = 0)),
+            ("stats.flush_interval", Box::new(|c| c.stats.flush_interval = 0)),
+            ("daemon.worker_threads", Box::new(|c| c.daemon.worker_threads = Some(0))),
+            ("api.max_message_bytes", Box::new(|c| c.api.max_message_bytes = 1000)),
+            ("api.max_nesting", Box::new(|c| c.api.max_nesting = 4)),
+            ("auth.policy.require_classes", Box::new(|c| c.auth.policy.require_classes = 5)),
+        ];
+        for (key, change) in rules {
+            assert_eq!(problems(|c, _| change(c)), vec![key.to_string()]);
+        }
+    }
+
+    #[test]
+    fn unknown_names() {
+        assert_eq!(problems(|c, _| c.machines.timezone = "Mars/Olympus_Mons".to_string()),
+            vec!["machines.timezone"]);
+        assert_eq!(problems(|c, _| c.log.level = "loud".to_string()), vec!["log.level"]);
+        assert_eq!(problems(|c, _| {
+            c.log.levels.insert("api".to_string(), "quiet".to_string());
+        }), vec!["log.levels.api"]);
+        assert_eq!(problems(|c, _| c.machines.notify_channels = vec!["pigeon".to_string()]),
+            vec!["machines.notify_channels[0]"]);
+    }
+
+    #[test]
+    fn files() {
+        assert_eq!(problems(|c, d| c.access.model = d.join("missing.conf")),
+            vec!["access.model"]);
+        assert_eq!(problems(|c, d| c.access.policy = d.path().to_path_buf()),
+            vec!["access.policy"]);
+        assert_eq!(problems(|c, d| c.machinedb = d.path().to_path_buf()), vec!["machinedb"]);
+        // Created when needed, but only in a directory that's there
+        assert_eq!(problems(|c, d| c.passdb = d.join("missing/passwd.toml")), vec!["passdb"]);
+        assert_eq!(problems(|c, d| c.auth.users = Some(d.join("users.toml"))),
+            Vec::<String>::new());
+        assert_eq!(problems(|c, d| c.auth.users = Some(d.join("missing/users.toml"))),
+            vec!["auth.users"]);
+        assert_eq!(problems(|c, d| c.log.file = Some(d.join("missing/bffh.log"))),
+            vec!["log.file"]);
+    }
+
+    #[test]
+    fn listen_problems() {
+        assert_eq!(problems(|c, _| c.listen = Box::new([])), vec!["listen"]);
+        assert_eq!(problems(|c, _| {
+            c.listen = Box::new([
+                Listen::tcp("not an address!".to_string(), None),
+                Listen::tcp("::1".to_string(), Some(0)),
+                Listen::tcp("::1".to_string(), None),
+                // The same address spelled differently
+                Listen::tcp("0:0:0:0:0:0:0:1".to_string(), Some(DEFAULT_PORT)),
+            ]);
+        }), vec!["listen[0].address", "listen[1].port", "listen[3]"]);
+    }
+
+    /// Problems of a config with one actor set up as in `actor`
+    fn actor_problems(actor: &str, mqtt: bool) -> Vec<String> {
+        let actor: Actor = toml::from_str(actor).unwrap();
+        let key = format!("machines.actors.{}.", Uuid::nil());
+        problems(|c, _| {
+            c.machines.actors.insert(Uuid::nil(), actor);
+            if mqtt {
+                c.mqtt = Some(toml::from_str("host = \"localhost\"").unwrap());
+            }
+        }).into_iter().map(|k| k.trim_start_matches(&key).to_string()).collect()
+    }
+
+    #[test]
+    fn actors() {
+        assert_eq!(actor_problems("type = \"dummy\"", false), Vec::<String>::new());
+        assert_eq!(actor_problems("type = \"tasmota\"", false), vec!["type", "topic"]);
+        assert_eq!(actor_problems("type = \"tasmota\"\ntopic = \"laser\"", true),
+            vec!["stat_topic"]);
+        assert_eq!(actor_problems("type = \"tasmota\"\ntopic = \"cmnd/#\"", true),
+            vec!["topic"]);
+        assert_eq!(actor_problems("type = \"shelly\"", true), vec!["device"]);
+        assert_eq!(actor_problems("type = \"shelly\"\ndevice = \"+\"", true), vec!["device"]);
+        assert_eq!(actor_problems("type = \"exec\"", false), vec!["command"]);
+        assert_eq!(actor_problems("type = \"http\"", false), vec!["url"]);
+        assert_eq!(actor_problems("type = \"dummy\"\ntimeout = 0", false), vec!["timeout"]);
+        let gpio = actor_problems("type = \"gpio\"\nsimulate = \"/tmp/line\"", false);
+        assert_eq!(gpio, vec!["line"]);
+    }
+
+    #[test]
+    fn mqtt_problems() {
+        let mqtt = |s: &str| problems(|c, _| c.mqtt = Some(toml::from_str(s).unwrap()));
+        assert_eq!(mqtt("host = \"localhost\""), Vec::<String>::new());
+        assert_eq!(mqtt("host = \"\"\nport = 0\nkeep_alive = 0"),
+            vec!["mqtt.host", "mqtt.port", "mqtt.keep_alive"]);
+        assert_eq!(mqtt("host = \"localhost\"\npassword = \"hunter2\""), vec!["mqtt.password"]);
+        assert_eq!(mqtt("host = \"localhost\"\ncommand_prefix = \"fab/#\""),
+            vec!["mqtt.command_prefix"]);
+    }
+}
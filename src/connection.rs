@@ -0,0 +1,696 @@
+//! Tracking of open connections
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc;
+use futures::future::{self, AbortHandle, Future, FutureExt, LocalBoxFuture};
+use futures::io::{AsyncRead, AsyncWrite};
+
+use async_std::sync::RwLock;
+
+use slog::Logger;
+
+use uuid::Uuid;
+
+use crate::api::Subscriber;
+use crate::api::error::RetryAfter;
+use crate::events::ServerEvent;
+use crate::gc::Registry;
+use crate::machine::Grants;
+use crate::machine::watch::{Channel, Notice, What};
+use crate::status::Status;
+
+/// Why a connection was not accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refused {
+    /// The server as a whole is at its connection limit
+    Overloaded,
+    /// The peer has too many connections open already
+    PeerLimit,
+}
+
+/// How many connections beyond the limit are told to come back later at the same time
+///
+/// Each of them lingers for a few seconds, so with a flood of connections we'd have more and more
+/// of them. Once this many are waiting for their answer we stop accepting instead, leaving further
+/// connections in the kernel's backlog until a slot frees up.
+const MAX_TURNED_AWAY: usize = 32;
+
+/// Connections being turned away at the same time after which they're told to wait twice as long
+const TURNED_AWAY_PER_STEP: usize = 4;
+
+/// Count of open connections and the overload state derived from it
+pub struct Connections {
+    log: Logger,
+    open: AtomicUsize,
+    /// Hard cap of concurrent connections. Any connection beyond that is turned away.
+    max: Option<usize>,
+    overloaded: AtomicBool,
+    /// Connections currently being told we're overloaded
+    turned_away: AtomicUsize,
+    /// Since when we stopped accepting connections, if we did
+    saturated_since: Mutex<Option<Instant>>,
+    /// The accept loop waiting for `capacity`
+    waiter: Mutex<Option<Waker>>,
+
+    /// Open connections per peer address
+    per_peer: Mutex<HashMap<IpAddr, usize>>,
+    max_per_peer: Option<usize>,
+
+    status: Arc<Status>,
+}
+
+impl Connections {
+    pub fn new(log: Logger, max: Option<usize>, max_per_peer: Option<usize>, status: Arc<Status>)
+        -> Arc<Self>
+    {
+        Arc::new(Self {
+            log,
+            open: AtomicUsize::new(0),
+            max,
+            overloaded: AtomicBool::new(false),
+            turned_away: AtomicUsize::new(0),
+            saturated_since: Mutex::new(None),
+            waiter: Mutex::new(None),
+            per_peer: Mutex::new(HashMap::new()),
+            max_per_peer,
+            status,
+        })
+    }
+
+    /// Number of connections currently open
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::SeqCst)
+    }
+
+    /// Register a new connection from `peer`
+    ///
+    /// Fails if either the server or the peer is at its connection limit. The connection counts as
+    /// open for as long as the returned guard is alive.
+    pub fn try_acquire(self: &Arc<Self>, peer: Option<IpAddr>)
+        -> Result<ConnectionGuard, Refused>
+    {
+        if let (Some(addr), Some(max)) = (peer, self.max_per_peer) {
+            let mut per_peer = self.per_peer.lock().unwrap();
+            let count = per_peer.entry(addr).or_insert(0);
+            if *count >= max {
+                return Err(Refused::PeerLimit);
+            }
+            *count += 1;
+        }
+
+        let open = self.open.fetch_add(1, Ordering::SeqCst);
+
+        if let Some(max) = self.max {
+            if open >= max {
+                self.open.fetch_sub(1, Ordering::SeqCst);
+                self.release_peer(peer);
+                if !self.overloaded.swap(true, Ordering::SeqCst) {
+                    warn!(self.log, "Entering overload with {} open connections", open);
+                }
+                return Err(Refused::Overloaded);
+            }
+        }
+
+        self.status.set_connections(open + 1);
+        Ok(ConnectionGuard { conns: self.clone(), peer })
+    }
+
+    /// Account for a connection we tell to come back later, unless there are too many already
+    ///
+    /// It counts for as long as the returned guard is alive.
+    pub fn try_turn_away(self: &Arc<Self>) -> Option<TurnAwayGuard> {
+        let turned_away = self.turned_away.fetch_add(1, Ordering::SeqCst);
+        if turned_away >= MAX_TURNED_AWAY {
+            self.turned_away.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        let retry = retry_after(turned_away + 1);
+        self.status.set_retry_after(retry.min);
+        Some(TurnAwayGuard { conns: self.clone(), retry })
+    }
+
+    /// Whether we can't even turn more connections away right now
+    fn saturated(&self) -> bool {
+        match self.max {
+            Some(max) => self.open() >= max
+                && self.turned_away.load(Ordering::SeqCst) >= MAX_TURNED_AWAY,
+            None => false,
+        }
+    }
+
+    /// Wait until accepting another connection makes sense
+    ///
+    /// The accept loop waits for this after every connection, so while we're saturated further
+    /// connections stay in the kernel's backlog instead of piling up as tasks.
+    pub fn capacity(self: &Arc<Self>) -> Capacity {
+        Capacity { conns: self.clone() }
+    }
+
+    fn wake(&self) {
+        if let Some(w) = self.waiter.lock().unwrap().take() {
+            w.wake();
+        }
+    }
+
+    fn release_peer(&self, peer: Option<IpAddr>) {
+        if let (Some(addr), Some(_)) = (peer, self.max_per_peer) {
+            let mut per_peer = self.per_peer.lock().unwrap();
+            if let Some(count) = per_peer.get_mut(&addr) {
+                *count -= 1;
+                // Don't keep entries for peers that aren't connected anymore around forever
+                if *count == 0 {
+                    per_peer.remove(&addr);
+                }
+            }
+        }
+    }
+
+    fn release(&self, peer: Option<IpAddr>) {
+        self.release_peer(peer);
+
+        let open = self.open.fetch_sub(1, Ordering::SeqCst) - 1;
+        self.status.set_connections(open);
+
+        if let Some(max) = self.max {
+            if open < max && self.overloaded.swap(false, Ordering::SeqCst) {
+                info!(self.log, "Leaving overload with {} open connections", open);
+                self.status.set_retry_after(0);
+            }
+        }
+        self.wake();
+    }
+}
+
+/// Resolves once we're not saturated, see `Connections::capacity`
+pub struct Capacity {
+    conns: Arc<Connections>,
+}
+
+impl Future for Capacity {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let conns = &self.conns;
+        if conns.saturated() {
+            // Registered before checking again so a slot freed in between isn't missed
+            *conns.waiter.lock().unwrap() = Some(cx.waker().clone());
+            if conns.saturated() {
+                let mut since = conns.saturated_since.lock().unwrap();
+                if since.is_none() {
+                    warn!(conns.log, "Too many connections, not accepting any until one closes";
+                        "open" => conns.open());
+                    *since = Some(Instant::now());
+                }
+                return Poll::Pending;
+            }
+        }
+
+        if let Some(since) = conns.saturated_since.lock().unwrap().take() {
+            let d = since.elapsed();
+            info!(conns.log, "Accepting connections again after {:.1}s", d.as_secs_f32());
+            conns.status.add_saturated(d);
+        }
+        Poll::Ready(())
+    }
+}
+
+/// How long to tell clients to wait while `turned_away` connections are being turned away
+fn retry_after(turned_away: usize) -> RetryAfter {
+    RetryAfter::for_depth((turned_away / TURNED_AWAY_PER_STEP) as u32)
+}
+
+/// Counts a connection as being turned away for as long as it's alive
+pub struct TurnAwayGuard {
+    conns: Arc<Connections>,
+    retry: RetryAfter,
+}
+
+impl TurnAwayGuard {
+    /// How long the client should wait before trying again
+    pub fn retry_after(&self) -> RetryAfter {
+        self.retry
+    }
+}
+
+impl Drop for TurnAwayGuard {
+    fn drop(&mut self) {
+        let turned_away = self.conns.turned_away.fetch_sub(1, Ordering::SeqCst) - 1;
+        if self.conns.overloaded.load(Ordering::SeqCst) {
+            self.conns.status.set_retry_after(retry_after(turned_away).min);
+        }
+        self.conns.wake();
+    }
+}
+
+/// Counts a connection as open for as long as it's alive
+pub struct ConnectionGuard {
+    conns: Arc<Connections>,
+    peer: Option<IpAddr>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.conns.release(self.peer);
+    }
+}
+
+/// Longest client name, version or platform we keep, anything after that is cut off
+const MAX_CLIENT_INFO: usize = 64;
+
+/// What a client says it is, see `hello`
+///
+/// Clients can claim to be anything, so this is only ever shown to admins and counted, never
+/// used to decide what a connection may do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub name: String,
+    pub version: String,
+    pub platform: String,
+}
+
+impl ClientInfo {
+    /// What to call clients that never said hello, or said it without a name
+    pub const UNKNOWN: &'static str = "unknown";
+
+    /// Take what the client sent, without control characters and cut short so nobody can flood
+    /// logs and listings with it
+    pub fn new(name: &str, version: &str, platform: &str) -> Self {
+        Self { name: sanitize(name), version: sanitize(version), platform: sanitize(platform) }
+    }
+
+    /// Name of the client, `UNKNOWN` if it didn't give one
+    pub fn name(&self) -> &str {
+        if self.name.is_empty() { Self::UNKNOWN } else { &self.name }
+    }
+}
+
+fn sanitize(s: &str) -> String {
+    s.chars().filter(|c| !c.is_control()).collect::<String>().trim()
+        .chars().take(MAX_CLIENT_INFO).collect()
+}
+
+/// What the client on a connection said it is, if it said anything. Saying hello again replaces
+/// it.
+pub type Hello = Rc<RefCell<Option<ClientInfo>>>;
+
+/// What admins get to see of an open connection
+pub struct Session {
+    pub peer: String,
+    /// When the connection was opened, in seconds since the UNIX epoch
+    pub since: u64,
+    pub authzid: Arc<RwLock<Option<String>>>,
+    pub grants: Grants,
+    pub activity: Arc<Activity>,
+    /// Closes the connection
+    pub abort: AbortHandle,
+    /// Server events for this connection alone
+    pub events: mpsc::UnboundedSender<ServerEvent>,
+    /// Who on the connection hears about server events, if anybody
+    pub subscriber: Subscriber,
+    pub client: Hello,
+}
+
+/// A session as listed to admins
+pub struct SessionInfo {
+    pub id: u64,
+    pub peer: String,
+    pub since: u64,
+    pub authzid: Option<String>,
+    pub idle_for: Duration,
+    pub silent_for: Duration,
+    pub machines: Vec<Uuid>,
+    pub client: Option<ClientInfo>,
+}
+
+/// All connections being served, by their id
+///
+/// Only ever used from the thread serving connections, so it doesn't need to be `Send`.
+#[derive(Clone)]
+pub struct Sessions {
+    inner: Rc<RefCell<BTreeMap<u64, Session>>>,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Self { inner: Rc::new(RefCell::new(BTreeMap::new())) }
+    }
+
+    /// Add the session of connection `id`, which is listed for as long as the guard is alive
+    pub fn register(&self, id: u64, session: Session) -> SessionGuard {
+        self.inner.borrow_mut().insert(id, session);
+        SessionGuard { sessions: self.clone(), id }
+    }
+
+    pub fn list(&self) -> Vec<SessionInfo> {
+        self.inner.borrow().iter().map(|(id, s)| {
+            let mut machines: Vec<Uuid> = s.grants.borrow().iter().cloned().collect();
+            machines.sort();
+            SessionInfo {
+                id: *id,
+                peer: s.peer.clone(),
+                since: s.since,
+                // Only ever written while authenticating, which is over in an instant
+                authzid: s.authzid.try_read().and_then(|a| a.clone()),
+                idle_for: s.activity.idle_for(),
+                silent_for: s.activity.silent_for(),
+                machines,
+                client: s.client.borrow().clone(),
+            }
+        }).collect()
+    }
+
+    /// Open connections by the name of their client
+    pub fn clients(&self) -> BTreeMap<String, usize> {
+        let mut clients = BTreeMap::new();
+        for s in self.inner.borrow().values() {
+            let name = match *s.client.borrow() {
+                Some(ref c) => c.name().to_string(),
+                None => ClientInfo::UNKNOWN.to_string(),
+            };
+            *clients.entry(name).or_insert(0) += 1;
+        }
+        clients
+    }
+
+    /// Send `event` to every connection of `user` subscribed to server events, returning how many
+    /// that were
+    pub fn notify(&self, user: &str, event: ServerEvent) -> usize {
+        self.inner.borrow().values()
+            .filter(|s| s.authzid.try_read().map_or(false, |a| a.as_deref() == Some(user)))
+            .filter(|s| s.subscriber.borrow().is_some())
+            .filter(|s| s.events.unbounded_send(event).is_ok())
+            .count()
+    }
+
+    /// Close connection `id`. Returns whether there was such a connection.
+    pub fn disconnect(&self, id: u64) -> bool {
+        match self.inner.borrow().get(&id) {
+            Some(s) => {
+                s.abort.abort();
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+impl Registry for Sessions {
+    fn len(&self) -> LocalBoxFuture<'_, usize> {
+        future::ready(self.inner.borrow().len()).boxed_local()
+    }
+
+    /// Sessions of connections that went away without their guard taking them along
+    fn prune(&self) -> LocalBoxFuture<'_, usize> {
+        let mut inner = self.inner.borrow_mut();
+        let gone: Vec<u64> = inner.iter()
+            .filter(|(_, s)| s.events.is_closed())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in gone.iter() {
+            inner.remove(id);
+        }
+        future::ready(gone.len()).boxed_local()
+    }
+}
+
+/// Telling users through the connections they have open, the `event` notification channel
+pub struct EventChannel {
+    sessions: Sessions,
+}
+
+impl EventChannel {
+    pub fn new(sessions: Sessions) -> Self {
+        Self { sessions }
+    }
+}
+
+impl Channel for EventChannel {
+    fn name(&self) -> &'static str {
+        "event"
+    }
+
+    fn send<'a>(&'a self, notice: &'a Notice<'a>)
+        -> LocalBoxFuture<'a, std::result::Result<(), String>>
+    {
+        let event = match notice.what {
+            What::Free { machine, .. } => ServerEvent::MachineFree { uuid: machine },
+            What::Ready { machine, until, .. } =>
+                ServerEvent::MachineReady { uuid: machine, until },
+            // There's no event for it, clients wouldn't know what to make of one
+            What::Test => return future::ready(Err("server events can't be tested".to_string()))
+                .boxed_local(),
+        };
+        let r = if self.sessions.notify(notice.user, event) > 0 {
+            Ok(())
+        } else {
+            Err("no connection subscribed to server events".to_string())
+        };
+        future::ready(r).boxed_local()
+    }
+}
+
+/// Lists a session for as long as it's alive
+pub struct SessionGuard {
+    sessions: Sessions,
+    id: u64,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.sessions.inner.borrow_mut().remove(&self.id);
+    }
+}
+
+/// Time of the last activity on a connection and how much data went over it
+pub struct Activity {
+    start: Instant,
+    /// Milliseconds since `start`
+    last: AtomicU64,
+    /// When the peer last sent anything, in milliseconds since `start`
+    last_in: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    /// Number of `Quiet` guards alive
+    quiet: AtomicUsize,
+}
+
+impl Activity {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            last_in: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            quiet: AtomicUsize::new(0),
+        })
+    }
+
+    /// How long ago the connection was opened
+    pub fn age(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Bytes received and sent so far
+    pub fn bytes(&self) -> (u64, u64) {
+        (self.bytes_in.load(Ordering::Relaxed), self.bytes_out.load(Ordering::Relaxed))
+    }
+
+    /// Mark the connection as active right now, unless it's kept quiet
+    pub fn touch(&self) {
+        if self.quiet.load(Ordering::Relaxed) == 0 {
+            self.last.store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Don't count anything going over the connection as activity while the guard is alive, for
+    /// traffic of our own making
+    pub fn quiet(&self) -> Quiet<'_> {
+        self.quiet.fetch_add(1, Ordering::Relaxed);
+        Quiet { activity: self }
+    }
+
+    /// How long ago the last activity was
+    pub fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.start.elapsed().checked_sub(last).unwrap_or_default()
+    }
+
+    /// How long ago the peer last sent anything
+    ///
+    /// Writes succeeding don't tell us anybody is still listening, only what we read does. Quiet
+    /// guards don't apply since reads are never of our own making.
+    pub fn silent_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last_in.load(Ordering::Relaxed));
+        self.start.elapsed().checked_sub(last).unwrap_or_default()
+    }
+}
+
+pub struct Quiet<'a> {
+    activity: &'a Activity,
+}
+
+impl Drop for Quiet<'_> {
+    fn drop(&mut self) {
+        self.activity.quiet.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A stream that records every successful read or write as activity
+#[derive(Clone)]
+pub struct Tracked<S> {
+    inner: S,
+    activity: Arc<Activity>,
+}
+
+impl<S> Tracked<S> {
+    pub fn new(inner: S, activity: Arc<Activity>) -> Self {
+        Self { inner, activity }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+        -> Poll<io::Result<usize>>
+    {
+        let r = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = r {
+            if n > 0 {
+                self.activity.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+                self.activity.last_in.store(self.activity.start.elapsed().as_millis() as u64,
+                    Ordering::Relaxed);
+                self.activity.touch();
+            }
+        }
+        r
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        let r = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = r {
+            if n > 0 {
+                self.activity.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+                self.activity.touch();
+            }
+        }
+        r
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::logger;
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn peer_limit() {
+        let conns = Connections::new(logger(), None, Some(2), Status::new());
+        let a = conns.try_acquire(ip("192.0.2.1")).unwrap();
+        let _b = conns.try_acquire(ip("192.0.2.1")).unwrap();
+        assert_eq!(conns.try_acquire(ip("192.0.2.1")).err(), Some(Refused::PeerLimit));
+        // Others aren't affected by it
+        let _c = conns.try_acquire(ip("192.0.2.2")).unwrap();
+        assert_eq!(conns.open(), 3);
+
+        drop(a);
+        conns.try_acquire(ip("192.0.2.1")).unwrap();
+    }
+
+    #[test]
+    fn global_limit() {
+        let conns = Connections::new(logger(), Some(2), Some(2), Status::new());
+        let a = conns.try_acquire(ip("192.0.2.1")).unwrap();
+        let _b = conns.try_acquire(ip("192.0.2.2")).unwrap();
+        assert_eq!(conns.try_acquire(ip("192.0.2.3")).err(), Some(Refused::Overloaded));
+        // Being refused doesn't count against the peer
+        assert_eq!(conns.per_peer.lock().unwrap().get(&ip("192.0.2.3").unwrap()), None);
+        assert_eq!(conns.open(), 2);
+
+        drop(a);
+        conns.try_acquire(ip("192.0.2.3")).unwrap();
+    }
+
+    #[test]
+    fn turned_away_are_capped() {
+        let conns = Connections::new(logger(), Some(0), None, Status::new());
+        let guards: Vec<_> = (0..MAX_TURNED_AWAY).map(|_| conns.try_turn_away().unwrap()).collect();
+        assert!(conns.try_turn_away().is_none());
+        assert!(conns.saturated());
+        drop(guards);
+        assert!(conns.try_turn_away().is_some());
+    }
+
+    #[test]
+    fn dropped_connections_are_released() {
+        let conns = Connections::new(logger(), Some(1), Some(1), Status::new());
+        let guard = conns.try_acquire(ip("192.0.2.1")).unwrap();
+        // A connection whose task is dropped without ever finishing, e.g. after its RPC system
+        // failed, still stops counting
+        let task = async move {
+            future::pending::<()>().await;
+            drop(guard);
+        };
+        drop(task);
+        assert_eq!(conns.open(), 0);
+        conns.try_acquire(ip("192.0.2.1")).unwrap();
+    }
+
+    #[test]
+    fn saturated_until_a_slot_frees_up() {
+        let status = Status::new();
+        let conns = Connections::new(logger(), Some(1), None, status.clone());
+        let mut capacity = conns.capacity();
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let _open = conns.try_acquire(None).unwrap();
+        let mut guards: Vec<_> = (0..MAX_TURNED_AWAY).map(|_| conns.try_turn_away().unwrap())
+            .collect();
+        assert_eq!(Pin::new(&mut capacity).poll(&mut cx), Poll::Pending);
+        assert!(conns.waiter.lock().unwrap().is_some());
+
+        std::thread::sleep(Duration::from_millis(20));
+        guards.pop();
+        // Woken up by the guard
+        assert!(conns.waiter.lock().unwrap().is_none());
+        assert_eq!(Pin::new(&mut capacity).poll(&mut cx), Poll::Ready(()));
+        assert!(status.saturated() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn turned_away_wait_longer_the_more_there_are() {
+        let conns = Connections::new(logger(), Some(0), None, Status::new());
+        let guards: Vec<_> = (0..TURNED_AWAY_PER_STEP).map(|_| conns.try_turn_away().unwrap())
+            .collect();
+        assert_eq!(guards[0].retry_after(), RetryAfter::for_depth(0));
+        assert_eq!(guards[TURNED_AWAY_PER_STEP - 2].retry_after(), RetryAfter::for_depth(0));
+        assert_eq!(guards[TURNED_AWAY_PER_STEP - 1].retry_after(), RetryAfter::for_depth(1));
+    }
+}
@@ -0,0 +1,129 @@
+//! Running as a traditional Unix daemon: pidfiles and detaching from the terminal
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use nix::errno::Errno;
+use nix::fcntl::{self, OFlag};
+use nix::sys::signal::kill;
+use nix::sys::stat::Mode;
+use nix::unistd::{self, ForkResult, Pid};
+
+fn to_io(e: nix::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// A file containing our PID, removed again when dropped
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+impl Pidfile {
+    /// Make sure no other live process owns the pidfile at `path`
+    ///
+    /// A pidfile left over by a process that is gone is fine and will be overwritten.
+    pub fn check(path: &Path) -> io::Result<()> {
+        let contents = match fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let pid = match contents.trim().parse::<i32>() {
+            Ok(pid) if pid > 0 => pid,
+            // Garbage in the pidfile doesn't point at any process
+            _ => return Ok(()),
+        };
+
+        // Signal 0 only checks if the process exists and we may signal it. EPERM means it exists
+        // but belongs to somebody else, which still counts as alive.
+        match kill(Pid::from_raw(pid), None) {
+            Err(nix::Error::Sys(Errno::ESRCH)) => Ok(()),
+            _ => Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                format!("Pidfile {} is owned by running process {}", path.display(), pid))),
+        }
+    }
+
+    /// Write our PID to `path`
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Self::check(path)?;
+
+        let mut file = File::create(path)?;
+        writeln!(file, "{}", std::process::id())?;
+
+        Ok(Self { path: path.to_path_buf() })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Handle to tell the original process waiting in the foreground that startup succeeded
+pub struct Ready {
+    fd: RawFd,
+}
+
+impl Ready {
+    /// Let the original process exit successfully
+    pub fn notify(self) {
+        let _ = unistd::write(self.fd, &[1]);
+    }
+}
+
+impl Drop for Ready {
+    fn drop(&mut self) {
+        let _ = unistd::close(self.fd);
+    }
+}
+
+/// Fork into the background and detach from the controlling terminal
+///
+/// The original process stays around until the daemon either calls `Ready::notify` or exits, so
+/// whoever started us gets a meaningful exit code. This must be called before any threads are
+/// started since only the calling thread survives a fork.
+pub fn daemonize() -> io::Result<Ready> {
+    let (rx, tx) = unistd::pipe().map_err(to_io)?;
+
+    match unistd::fork().map_err(to_io)? {
+        ForkResult::Parent { .. } => {
+            let _ = unistd::close(tx);
+            // If the daemon dies before it's ready the pipe is closed without anything written to
+            // it.
+            let mut rx = unsafe { File::from_raw_fd(rx) };
+            let mut buf = [0u8; 1];
+            let code = match rx.read(&mut buf) {
+                Ok(1) => 0,
+                _ => 1,
+            };
+            std::process::exit(code);
+        },
+        ForkResult::Child => {
+            let _ = unistd::close(rx);
+        },
+    }
+
+    // New session without a controlling terminal
+    unistd::setsid().map_err(to_io)?;
+
+    // Fork again so the daemon isn't a session leader and can never acquire a terminal again
+    if let ForkResult::Parent { .. } = unistd::fork().map_err(to_io)? {
+        std::process::exit(0);
+    }
+
+    // The working directory is kept since relative paths in the config are resolved against it.
+
+    let null = fcntl::open("/dev/null", OFlag::O_RDWR, Mode::empty()).map_err(to_io)?;
+    for fd in 0..3 {
+        unistd::dup2(null, fd).map_err(to_io)?;
+    }
+    if null > 2 {
+        let _ = unistd::close(null);
+    }
+
+    Ok(Ready { fd: tx })
+}
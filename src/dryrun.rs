@@ -0,0 +1,61 @@
+//! Running against a production config without changing anything, for `--dry-run`
+//!
+//! Instead of asking whether this is a dry run all over the place we swap out whatever has an
+//! effect outside of the process: files we'd write go to a temporary directory, the machine
+//! database only pretends to save (see `machine::store::DryRun`) and actors only log what they'd
+//! do. Modules talking to the outside aren't started at all.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+
+use crate::config::Config;
+
+/// Point everything we'd write in `config` into a fresh temporary directory, which is returned
+///
+/// The password database, the policy, temporary grants, password reset tokens and statistics are
+/// copied there since they are written to. The audit trail starts out empty.
+pub fn prepare(config: &mut Config) -> io::Result<PathBuf> {
+    let dir = std::env::temp_dir().join(format!("diflouroborane-dry-run-{}", process::id()));
+    fs::create_dir_all(&dir)?;
+
+    let passdb = dir.join("passwd.db");
+    if config.passdb.is_file() {
+        fs::copy(&config.passdb, &passdb)?;
+    }
+    config.passdb = passdb;
+    // Claiming a fresh install writes to the policy
+    let policy = dir.join("policy.csv");
+    if config.access.policy.is_file() {
+        fs::copy(&config.access.policy, &policy)?;
+    }
+    config.access.policy = policy;
+    if let Some(ref grants) = config.access.grants {
+        let copy = dir.join("grants.toml");
+        if grants.is_file() {
+            fs::copy(grants, &copy)?;
+        }
+        config.access.grants = Some(copy);
+    }
+    if let Some(ref resets) = config.auth.resets {
+        let copy = dir.join("resets.toml");
+        if resets.is_file() {
+            fs::copy(resets, &copy)?;
+        }
+        config.auth.resets = Some(copy);
+    }
+    if config.audit.path.is_some() {
+        config.audit.path = Some(dir.join("audit.log"));
+    }
+    if let Some(ref stats) = config.stats.path {
+        let copy = dir.join("stats.json");
+        if stats.is_file() {
+            fs::copy(stats, &copy)?;
+        }
+        config.stats.path = Some(copy);
+    }
+
+    config.dry_run = Some(dir.clone());
+    Ok(dir)
+}
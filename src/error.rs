@@ -1,15 +1,97 @@
 use std::io;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use toml;
 
 use crate::auth::SASLError;
+use crate::machine::MachineDBError;
 
 #[derive(Debug)]
 pub enum Error {
     TomlDe(toml::de::Error),
     TomlSer(toml::ser::Error),
+    Json(serde_json::Error),
+    Yaml(serde_yaml::Error),
+    MachineDB(MachineDBError),
+    Sled(sled::Error),
+    Bincode(bincode::Error),
+    UUID(uuid::Error),
     SASL(SASLError),
     IO(io::Error),
+    Capnp(capnp::Error),
+    /// Errors from casbin, which only hands out boxed errors
     Boxed(Box<dyn std::error::Error>),
+    /// A config value that doesn't make sense, with the key or variable it came from
+    Config(String),
+    /// Something went wrong with the file at that path
+    Path(PathBuf, Box<Error>),
+    /// Permission checks time out, see `access::breaker`
+    AuthzUnavailable,
+    /// Nothing may be written while the server is in read-only mode
+    ReadOnly,
+}
+
+impl Error {
+    /// Add the file the error happened with, so the user knows where to look
+    pub fn with_path<P: AsRef<Path>>(self, path: P) -> Error {
+        Error::Path(path.as_ref().to_path_buf(), Box::new(self))
+    }
+}
+
+/// `Error::with_path` for any result that can be turned into ours
+pub trait WithPath<T> {
+    fn with_path<P: AsRef<Path>>(self, path: P) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> WithPath<T> for std::result::Result<T, E> {
+    fn with_path<P: AsRef<Path>>(self, path: P) -> Result<T> {
+        self.map_err(|e| e.into().with_path(path))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::TomlDe(e) => write!(f, "Invalid TOML: {}", e),
+            Error::TomlSer(e) => write!(f, "Could not encode TOML: {}", e),
+            Error::Json(e) => write!(f, "Invalid JSON: {}", e),
+            Error::Yaml(e) => write!(f, "Invalid YAML: {}", e),
+            Error::MachineDB(e) => write!(f, "Invalid machine database: {}", e),
+            Error::Sled(e) => write!(f, "Database error: {}", e),
+            Error::Bincode(e) => write!(f, "Corrupt database record: {}", e),
+            Error::UUID(e) => write!(f, "Invalid UUID: {}", e),
+            Error::SASL(e) => write!(f, "{}", e),
+            Error::IO(e) => write!(f, "{}", e),
+            Error::Capnp(e) => write!(f, "RPC error: {}", e),
+            Error::Boxed(e) => write!(f, "{}", e),
+            Error::Config(e) => write!(f, "{}", e),
+            Error::Path(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::AuthzUnavailable => write!(f, "Authorization system unavailable"),
+            Error::ReadOnly => write!(f, "Server is in read-only mode"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::TomlDe(e) => Some(e),
+            Error::TomlSer(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::Yaml(e) => Some(e),
+            Error::MachineDB(e) => Some(e),
+            Error::Sled(e) => Some(e),
+            Error::Bincode(e) => Some(e),
+            Error::UUID(e) => Some(e),
+            Error::SASL(e) => Some(e),
+            Error::IO(e) => Some(e),
+            Error::Capnp(e) => Some(e),
+            Error::Boxed(e) => Some(e.as_ref()),
+            Error::Config(_) => None,
+            Error::Path(_, e) => Some(e.as_ref()),
+            Error::AuthzUnavailable | Error::ReadOnly => None,
+        }
+    }
 }
 
 impl From<SASLError> for Error {
@@ -18,12 +100,54 @@ impl From<SASLError> for Error {
     }
 }
 
+impl From<MachineDBError> for Error {
+    fn from(e: MachineDBError) -> Error {
+        Error::MachineDB(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
+
+impl From<serde_yaml::Error> for Error {
+    fn from(e: serde_yaml::Error) -> Error {
+        Error::Yaml(e)
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(e: sled::Error) -> Error {
+        Error::Sled(e)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Error {
+        Error::Bincode(e)
+    }
+}
+
+impl From<uuid::Error> for Error {
+    fn from(e: uuid::Error) -> Error {
+        Error::UUID(e)
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Error {
         Error::IO(e)
     }
 }
 
+impl From<capnp::Error> for Error {
+    fn from(e: capnp::Error) -> Error {
+        Error::Capnp(e)
+    }
+}
+
 impl From<toml::de::Error> for Error {
     fn from(e: toml::de::Error) -> Error {
         Error::TomlDe(e)
@@ -43,3 +167,36 @@ impl From<Box<dyn std::error::Error>> for Error {
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::error::Error as _;
+
+    #[test]
+    fn display() {
+        let e = Error::from(io::Error::new(io::ErrorKind::NotFound, "No such file or directory"));
+        assert_eq!(e.to_string(), "No such file or directory");
+        assert_eq!(Error::ReadOnly.to_string(), "Server is in read-only mode");
+        assert_eq!(Error::Config("listen: no addresses".to_string()).to_string(),
+            "listen: no addresses");
+
+        let toml: toml::de::Error = toml::from_str::<toml::Value>("a = ").unwrap_err();
+        assert!(Error::from(toml).to_string().starts_with("Invalid TOML: "));
+    }
+
+    #[test]
+    fn paths() {
+        let r: std::result::Result<(), io::Error> =
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, "Permission denied"));
+        let e = r.with_path("/etc/diflouroborane.toml").unwrap_err();
+        assert_eq!(e.to_string(), "/etc/diflouroborane.toml: Permission denied");
+
+        // The error with the path leads to the original one
+        let inner = e.source().unwrap();
+        assert_eq!(inner.to_string(), "Permission denied");
+        let io = inner.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(io.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
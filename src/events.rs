@@ -0,0 +1,87 @@
+//! Events about the server itself that clients may want to react to
+//!
+//! Machine state changes have their own subscriptions in `MachinesProvider`. These are the rest,
+//! e.g. telling kiosks we're about to go down so they can show a banner.
+
+use std::sync::{Arc, Mutex};
+
+use futures::channel::mpsc;
+use futures::future::{self, FutureExt, LocalBoxFuture};
+
+use uuid::Uuid;
+
+use crate::api::api;
+use crate::gc::Registry;
+use crate::machine::api_from_uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerEvent {
+    /// We're shutting down and close all connections in `grace` seconds at the latest
+    ShutdownImminent { grace: u64 },
+    /// The access policy was loaded again, so what a user may do could have changed
+    PolicyReloaded,
+    /// The connection is closed in `secs` seconds unless it does something
+    SessionExpiring { secs: u64 },
+    /// A machine the user of the connection waits for became free
+    MachineFree { uuid: Uuid },
+    /// A machine the user of the connection was first in the queue for is reserved for them until
+    /// `until`, in seconds since the UNIX epoch
+    MachineReady { uuid: Uuid, until: u64 },
+}
+
+impl ServerEvent {
+    pub fn fill(&self, mut b: api::server_events::event::Builder) {
+        match *self {
+            ServerEvent::ShutdownImminent { grace } =>
+                b.init_shutdown_imminent().set_grace_seconds(grace),
+            ServerEvent::PolicyReloaded => b.set_policy_reloaded(()),
+            ServerEvent::SessionExpiring { secs } =>
+                b.init_session_expiring().set_in_seconds(secs),
+            ServerEvent::MachineFree { uuid } => api_from_uuid(uuid, b.init_machine_free()),
+            ServerEvent::MachineReady { uuid, until } => {
+                let mut ready = b.init_machine_ready();
+                api_from_uuid(uuid, ready.reborrow().init_machine());
+                ready.set_until(until);
+            },
+        }
+    }
+}
+
+/// Hands every event published to everybody who subscribed
+///
+/// Publishing never waits for subscribers. Each has its own unbounded queue, and those that went
+/// away are dropped the next time something is published.
+#[derive(Clone)]
+pub struct Events {
+    subscribers: Arc<Mutex<Vec<mpsc::UnboundedSender<ServerEvent>>>>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self { subscribers: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<ServerEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub fn publish(&self, event: ServerEvent) {
+        self.subscribers.lock().unwrap().retain(|s| s.unbounded_send(event).is_ok());
+    }
+}
+
+impl Registry for Events {
+    fn len(&self) -> LocalBoxFuture<'_, usize> {
+        future::ready(self.subscribers.lock().unwrap().len()).boxed_local()
+    }
+
+    /// Subscribers that went away while nothing was published
+    fn prune(&self) -> LocalBoxFuture<'_, usize> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let before = subscribers.len();
+        subscribers.retain(|s| !s.is_closed());
+        future::ready(before - subscribers.len()).boxed_local()
+    }
+}
@@ -0,0 +1,276 @@
+//! Keeping the in-memory registries from piling up entries nobody needs anymore
+//!
+//! Sessions, subscriptions, rate limit buckets and queues mostly clean up after themselves, but a
+//! server running for months shouldn't depend on every last path doing so. Every `gc.interval`
+//! seconds all of them are pruned, and one growing past its cap in between is pruned right away.
+//! How many entries each holds is published to `Status` for monitoring.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::future::LocalBoxFuture;
+
+use async_std::task;
+
+use slog::Logger;
+
+use crate::config::Gc;
+use crate::status::Status;
+
+/// Names registries go by in logs, metrics and `gc.caps`
+pub const SESSIONS: &str = "sessions";
+pub const MACHINE_SUBSCRIBERS: &str = "machine_subscribers";
+pub const EVENT_SUBSCRIBERS: &str = "event_subscribers";
+pub const AUDIT_SUBSCRIBERS: &str = "audit_subscribers";
+pub const RATE_LIMIT_BUCKETS: &str = "rate_limit_buckets";
+pub const QUEUES: &str = "queues";
+
+pub const NAMES: &[&str] = &[SESSIONS, MACHINE_SUBSCRIBERS, EVENT_SUBSCRIBERS, AUDIT_SUBSCRIBERS,
+    RATE_LIMIT_BUCKETS, QUEUES];
+
+/// Longest time between two looks at how large the registries are
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Something holding entries that can outlive their use
+pub trait Registry {
+    /// Entries held right now
+    fn len(&self) -> LocalBoxFuture<'_, usize>;
+
+    /// Drop the entries nobody needs anymore, returning how many that were
+    fn prune(&self) -> LocalBoxFuture<'_, usize>;
+}
+
+impl<R: Registry + ?Sized> Registry for Arc<R> {
+    fn len(&self) -> LocalBoxFuture<'_, usize> {
+        (**self).len()
+    }
+
+    fn prune(&self) -> LocalBoxFuture<'_, usize> {
+        (**self).prune()
+    }
+}
+
+struct Entry {
+    name: &'static str,
+    cap: usize,
+    registry: Box<dyn Registry>,
+}
+
+/// All registries to keep small
+pub struct Collector {
+    log: Logger,
+    status: Arc<Status>,
+    config: Gc,
+    registries: Vec<Entry>,
+}
+
+impl Collector {
+    pub fn new(log: Logger, status: Arc<Status>, config: Gc) -> Self {
+        Self { log, status, config, registries: Vec::new() }
+    }
+
+    /// Keep `registry` small from now on. `name` should be one of `NAMES`, so it can be given a
+    /// cap of its own.
+    pub fn register<R: Registry + 'static>(&mut self, name: &'static str, registry: R) {
+        let cap = self.config.caps.get(name).cloned().unwrap_or(self.config.max_entries);
+        self.registries.push(Entry { name, cap, registry: Box::new(registry) });
+    }
+
+    /// Prune for as long as we run
+    pub async fn run(self) {
+        let interval = Duration::from_secs(self.config.interval);
+        let tick = if interval == Duration::from_secs(0) {
+            CHECK_INTERVAL
+        } else {
+            interval.min(CHECK_INTERVAL)
+        };
+        let mut last = Instant::now();
+        // Registries we warned about being over their cap even after pruning, so we don't warn
+        // again every tick
+        let mut over = HashSet::new();
+
+        loop {
+            task::sleep(tick).await;
+            let due = interval > Duration::from_secs(0) && last.elapsed() >= interval;
+            if due {
+                last = Instant::now();
+            }
+            self.collect(due, &mut over).await;
+        }
+    }
+
+    /// Look at every registry once, pruning all of them if `due` and otherwise those over their
+    /// cap. `over` are those that were over their cap even after pruning last time.
+    async fn collect(&self, due: bool, over: &mut HashSet<&'static str>) {
+        let mut pruned = Vec::new();
+        for e in self.registries.iter() {
+            let mut len = e.registry.len().await;
+            if due || len > e.cap {
+                let n = e.registry.prune().await;
+                len = len.saturating_sub(n);
+                if n > 0 {
+                    pruned.push((e.name, n));
+                }
+            }
+
+            if len > e.cap {
+                if over.insert(e.name) {
+                    warn!(self.log, "Registry {} holds {} entries even after pruning, more than \
+                        its cap of {}", e.name, len, e.cap);
+                }
+            } else {
+                over.remove(e.name);
+            }
+            self.status.set_registry(e.name, len);
+        }
+
+        if !pruned.is_empty() {
+            let total: usize = pruned.iter().map(|(_, n)| n).sum();
+            let each: Vec<String> = pruned.iter()
+                .map(|(name, n)| format!("{} {}", name, n))
+                .collect();
+            info!(self.log, "Reclaimed {} registry entries: {}", total, each.join(", "));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use futures::executor::{block_on, LocalPool};
+    use futures::future::{self, FutureExt};
+
+    use super::*;
+    use crate::config;
+    use crate::machine::{self, lock};
+    use crate::testing::{logger, rpc};
+
+    /// `held` entries of which `stale` can go
+    struct Fake {
+        held: Cell<usize>,
+        stale: Cell<usize>,
+        pruned: Cell<bool>,
+    }
+
+    impl Fake {
+        fn new(held: usize, stale: usize) -> Arc<Self> {
+            Arc::new(Self { held: Cell::new(held), stale: Cell::new(stale),
+                pruned: Cell::new(false) })
+        }
+
+        /// Whether it was pruned since the last time this was asked
+        fn pruned(&self) -> bool {
+            self.pruned.replace(false)
+        }
+    }
+
+    impl Registry for Fake {
+        fn len(&self) -> LocalBoxFuture<'_, usize> {
+            future::ready(self.held.get()).boxed_local()
+        }
+
+        fn prune(&self) -> LocalBoxFuture<'_, usize> {
+            self.pruned.set(true);
+            let n = self.stale.replace(0);
+            self.held.set(self.held.get() - n);
+            future::ready(n).boxed_local()
+        }
+    }
+
+    #[test]
+    fn pruning() {
+        let status = Status::new();
+        let mut config = Gc::default();
+        config.max_entries = 10;
+        config.caps.insert(QUEUES.to_string(), 2);
+        let mut collector = Collector::new(logger(), status.clone(), config);
+        let sessions = Fake::new(5, 1);
+        let queues = Fake::new(2, 1);
+        collector.register(SESSIONS, sessions.clone());
+        collector.register(QUEUES, queues.clone());
+        let mut over = HashSet::new();
+
+        // Nothing over its cap and nothing due
+        block_on(collector.collect(false, &mut over));
+        assert!(!sessions.pruned() && !queues.pruned());
+        assert_eq!(status.registries().into_iter().collect::<HashSet<_>>(),
+            vec![(SESSIONS, 5), (QUEUES, 2)].into_iter().collect());
+
+        // Only those over their cap are pruned in between
+        queues.held.set(4);
+        queues.stale.set(1);
+        block_on(collector.collect(false, &mut over));
+        assert!(!sessions.pruned() && queues.pruned());
+        assert_eq!(queues.held.get(), 3);
+        // Still over it, which is only warned about once
+        assert!(over.contains(QUEUES));
+
+        block_on(collector.collect(true, &mut over));
+        assert!(sessions.pruned() && queues.pruned());
+        assert_eq!(status.registries().into_iter().collect::<HashSet<_>>(),
+            vec![(SESSIONS, 4), (QUEUES, 3)].into_iter().collect());
+
+        queues.stale.set(2);
+        block_on(collector.collect(false, &mut over));
+        assert!(over.is_empty());
+    }
+
+    #[test]
+    fn short_connections_leave_nothing_behind() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = rpc::Server::with_config(&spawner, |c| {
+                c.api.rate_limit = Some(config::RateLimit { per_second: 100_000, burst: 100_000,
+                    exempt_local: false, exempt_users: vec![] });
+            }).await;
+
+            // Everything main keeps small
+            let status = Status::new();
+            let background = lock::Background::new(logger(), lock::DEFAULT_TIMEOUT);
+            let mut collector = Collector::new(logger(), status.clone(), Gc::default());
+            collector.register(SESSIONS, server.api.sessions());
+            collector.register(MACHINE_SUBSCRIBERS, machine::Subscribers(server.api.machines(),
+                background.clone()));
+            collector.register(EVENT_SUBSCRIBERS, server.api.events());
+            collector.register(AUDIT_SUBSCRIBERS, server.audit.clone());
+            collector.register(RATE_LIMIT_BUCKETS, server.api.limiter().unwrap());
+            collector.register(QUEUES, machine::Queues(server.api.machines(), background));
+            let mut over = HashSet::new();
+            collector.collect(true, &mut over).await;
+            let baseline = status.registries();
+
+            // Clients that log in, look around and hang up, a couple hundred at a time
+            let sessions = server.api.sessions();
+            for _ in 0..10 {
+                let mut batch = Vec::new();
+                for _ in 0..200 {
+                    let bob = server.connect(&spawner).await;
+                    bob.login_as("bob").await;
+                    bob.list().await.unwrap();
+                    bob.status(rpc::PRINTER).await.unwrap();
+                    batch.push(bob);
+                }
+                assert!(sessions.len().await >= 200);
+                for bob in batch {
+                    bob.close();
+                }
+            }
+
+            // The server only notices once it reads from the connection again
+            let start = Instant::now();
+            while sessions.len().await > 0 {
+                assert!(start.elapsed() < Duration::from_secs(10), "connections stay open");
+                task::sleep(Duration::from_millis(10)).await;
+            }
+            // Let bob's bucket fill up again
+            task::sleep(Duration::from_millis(100)).await;
+
+            collector.collect(true, &mut over).await;
+            assert_eq!(status.registries(), baseline);
+            assert!(over.is_empty(), "{:?}", over);
+        });
+    }
+}
@@ -0,0 +1,502 @@
+//! Sockets we accept connections on
+
+pub mod filter;
+
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::channel::{mpsc, oneshot};
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::stream::{self, LocalBoxStream, Stream, StreamExt};
+
+use async_std::task;
+
+use slog::Logger;
+
+use async_std::net::{TcpListener, TcpStream};
+use async_std::os::unix::net::{UnixListener, UnixStream};
+
+use nix::libc;
+use nix::unistd::{chown, Uid, Gid};
+
+use crate::config::{self, Listen, ListenKind, TcpKeepalive};
+use crate::websocket::{self, WsStream};
+
+use self::filter::PeerFilter;
+
+/// A bound listening socket
+pub enum Listener {
+    /// With the peers it accepts
+    Tcp(TcpListener, Rc<PeerFilter>),
+    /// TCP speaking WebSocket, with the origins browsers may connect from
+    WebSocket(TcpListener, Rc<[String]>, Rc<PeerFilter>),
+    Unix(UnixSocket),
+}
+
+/// A connection as it comes in, with the peers the listener it came in on accepts
+pub type Accepted = (Socket, Rc<PeerFilter>);
+
+/// A listening Unix domain socket that is unlinked again when dropped
+pub struct UnixSocket {
+    listener: UnixListener,
+    /// Path to remove on drop, if the socket file is ours to clean up
+    path: Option<PathBuf>,
+}
+
+impl UnixSocket {
+    /// A socket that somebody else created for us and is responsible for cleaning up
+    #[cfg(feature = "systemd")]
+    pub fn adopted(listener: UnixListener) -> Self {
+        Self { listener, path: None }
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        if let Some(ref path) = self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl Listener {
+    /// Stream of connections accepted on this listener
+    ///
+    /// The stream owns the listener, so dropping it closes the socket.
+    pub fn into_incoming(self) -> LocalBoxStream<'static, io::Result<Accepted>> {
+        match self {
+            Listener::Tcp(l, f) => stream::unfold((l, f), |(l, f)| async move {
+                let r = l.accept().await.map(|(s, _)| (Socket::Tcp(s), f.clone()));
+                Some((r, (l, f)))
+            }).boxed_local(),
+            // The handshake is left to the connection's own task so a slow client can't hold up
+            // accepting others
+            Listener::WebSocket(l, o, f) => stream::unfold((l, o, f), |(l, o, f)| async move {
+                let r = l.accept().await.map(|(s, _)| (Socket::Upgrade(s, o.clone()), f.clone()));
+                Some((r, (l, o, f)))
+            }).boxed_local(),
+            // Unix sockets are kept away from strangers by their permissions instead
+            Listener::Unix(u) => stream::unfold(u, |u| async move {
+                let open = Rc::new(PeerFilter::default());
+                let r = u.listener.accept().await.map(|(s, _)| (Socket::Unix(s), open));
+                Some((r, u))
+            }).boxed_local(),
+        }
+    }
+}
+
+/// The `[[listen]]` entries we are supposed to be listening on right now
+pub type Wanted = Rc<RefCell<Vec<Listen>>>;
+
+/// A change to the set of listeners `Incoming` accepts on
+pub enum Change {
+    /// Start accepting on a freshly bound listener
+    Add(Listen, Listener),
+    /// Stop accepting on the listener bound for this entry and close it. Connections accepted on
+    /// it before stay open. The sender is dropped once the socket is closed.
+    Remove(Listen, oneshot::Sender<()>),
+}
+
+/// Listeners with the entry they were bound for. Sockets passed by the service manager have none
+/// and stay for as long as we run.
+type Active = Vec<(Option<Listen>, LocalBoxStream<'static, io::Result<Accepted>>)>;
+
+/// Connections accepted on any of a set of listeners that can change while it's being polled
+pub struct Incoming {
+    active: Rc<RefCell<Active>>,
+    changes: Option<mpsc::UnboundedReceiver<Change>>,
+    /// Set by `Closer`, after which no listener is added anymore
+    closed: Rc<Cell<bool>>,
+    /// Which listener to poll first next time, so a busy one can't starve the others
+    next: usize,
+}
+
+impl Incoming {
+    /// Accept on `listeners` and apply changes sent through the returned channel later
+    pub fn new(listeners: Vec<(Option<Listen>, Listener)>)
+        -> (Self, mpsc::UnboundedSender<Change>)
+    {
+        let (tx, rx) = mpsc::unbounded();
+        let active = listeners.into_iter().map(|(l, s)| (l, s.into_incoming())).collect();
+        let incoming = Self {
+            active: Rc::new(RefCell::new(active)),
+            changes: Some(rx),
+            closed: Rc::new(Cell::new(false)),
+            next: 0,
+        };
+        (incoming, tx)
+    }
+
+    /// A way to close all listeners, whether we're still polling them or not
+    pub fn closer(&self) -> Closer {
+        Closer { active: self.active.clone(), closed: self.closed.clone() }
+    }
+
+    fn apply(&mut self, change: Change) {
+        match change {
+            Change::Add(_, _) if self.closed.get() => {},
+            Change::Add(l, s) => self.active.borrow_mut().push((Some(l), s.into_incoming())),
+            // Dropping the stream closes the socket, only then we can tell the sender it's gone.
+            Change::Remove(l, done) => {
+                self.active.borrow_mut().retain(|(a, _)| a.as_ref() != Some(&l));
+                drop(done);
+            },
+        }
+    }
+}
+
+/// Closes the listeners of an `Incoming`, see `Incoming::closer`
+pub struct Closer {
+    active: Rc<RefCell<Active>>,
+    closed: Rc<Cell<bool>>,
+}
+
+impl Closer {
+    /// Close all listeners, connections accepted on them before stay open. Listeners that are
+    /// still being bound are dropped once they are.
+    pub fn close(&self) {
+        self.closed.set(true);
+        self.active.borrow_mut().clear();
+    }
+}
+
+impl Stream for Incoming {
+    type Item = io::Result<Accepted>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        let mut closed = false;
+        if let Some(ref mut changes) = this.changes {
+            let mut applied = Vec::new();
+            loop {
+                match Pin::new(&mut *changes).poll_next(cx) {
+                    Poll::Ready(Some(c)) => applied.push(c),
+                    Poll::Ready(None) => { closed = true; break },
+                    Poll::Pending => break,
+                }
+            }
+            for c in applied {
+                this.apply(c);
+            }
+        }
+        if closed {
+            this.changes = None;
+        }
+
+        let mut active = this.active.borrow_mut();
+        let n = active.len();
+        for i in 0..n {
+            let idx = (this.next + i) % n;
+            match active[idx].1.as_mut().poll_next(cx) {
+                Poll::Ready(Some(r)) => {
+                    this.next = (idx + 1) % n;
+                    return Poll::Ready(Some(r));
+                },
+                // Accepting never ends on its own but if it does there's no use in keeping it
+                Poll::Ready(None) => {
+                    active.remove(idx);
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                },
+                Poll::Pending => {},
+            }
+        }
+
+        // Having no listeners right now doesn't mean we're done if more may still come in
+        if active.is_empty() && this.changes.is_none() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Upper bound for the delay between bind attempts
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// Keep trying to bind `failed` in the background, handing every listener that succeeds to `tx`
+///
+/// The delay starts out at `delay` and doubles after every round. Entries that are removed from
+/// `wanted` in the meantime are given up on. Keep in mind that by the time this runs privileges
+/// may have been dropped already, so this can't help with e.g. low ports.
+pub async fn retry(log: Logger, mut failed: Vec<Listen>, mut delay: Duration,
+    tx: mpsc::UnboundedSender<Change>, wanted: Wanted)
+{
+    while !failed.is_empty() {
+        task::sleep(delay).await;
+        delay = std::cmp::min(delay * 2, MAX_RETRY_DELAY);
+
+        let mut still_failed = Vec::new();
+        for l in failed {
+            if !wanted.borrow().contains(&l) {
+                continue;
+            }
+            match bind(&l).await {
+                Ok(listener) => {
+                    info!(log, "Bound socket on {} after retrying", l);
+                    // The receiver is gone if we're shutting down, no point in going on then.
+                    if tx.unbounded_send(Change::Add(l, listener)).is_err() {
+                        return;
+                    }
+                },
+                Err(e) => {
+                    warn!(log, "Could still not setup socket on {}: {}, retrying in {}s", l, e,
+                        delay.as_secs());
+                    still_failed.push(l);
+                },
+            }
+        }
+        failed = still_failed;
+    }
+}
+
+/// Bring the listeners in line with the `[[listen]]` entries of a reloaded config
+///
+/// Entries that are gone are closed first so their addresses are free to be bound again, then
+/// new ones are bound. Entries present in both are left alone. New entries that fail to bind are
+/// retried like at startup if `retry_delay` isn't zero.
+pub async fn reload(log: Logger, new: Vec<Listen>, wanted: Wanted, retry_delay: Duration,
+    tx: mpsc::UnboundedSender<Change>)
+{
+    let old = wanted.replace(new.clone());
+
+    for l in old.iter().filter(|l| !new.contains(l)) {
+        let (done, closed) = oneshot::channel();
+        if tx.unbounded_send(Change::Remove(l.clone(), done)).is_err() {
+            return;
+        }
+        // We only get `Canceled` back, which is exactly what we're waiting for
+        let _ = closed.await;
+        info!(log, "Stopped listening on {}", l);
+    }
+
+    let mut failed = Vec::new();
+    for l in new.into_iter().filter(|l| !old.contains(l)) {
+        match bind(&l).await {
+            Ok(listener) => {
+                info!(log, "Listening on {}", l);
+                if tx.unbounded_send(Change::Add(l, listener)).is_err() {
+                    return;
+                }
+            },
+            Err(e) => {
+                error!(log, "Could not setup socket on {}: {}", l, e);
+                failed.push(l);
+            },
+        }
+    }
+
+    if !failed.is_empty() && retry_delay > Duration::from_secs(0) {
+        retry(log, failed, retry_delay, tx, wanted).await;
+    }
+}
+
+/// Bind a socket as described by a `[[listen]]` entry
+pub async fn bind(l: &Listen) -> io::Result<Listener> {
+    match l {
+        Listen::Tcp { address, port, kind, origins, allow, deny } => {
+            let port = port.unwrap_or(config::DEFAULT_PORT);
+            let listener = TcpListener::bind((address.as_str(), port)).await?;
+            let filter = Rc::new(PeerFilter::new(allow, deny));
+            match kind {
+                ListenKind::Raw => Ok(Listener::Tcp(listener, filter)),
+                ListenKind::WebSocket =>
+                    Ok(Listener::WebSocket(listener, origins.clone().into(), filter)),
+            }
+        },
+        Listen::Unix { path, mode, owner } => {
+            remove_stale(path)?;
+            let listener = UnixListener::bind(path).await?;
+            // From here on the socket file is cleaned up even if setting it up fails
+            let socket = UnixSocket { listener, path: Some(path.clone()) };
+
+            if let Some(mode) = mode {
+                fs::set_permissions(path, fs::Permissions::from_mode(*mode))?;
+            }
+            if let Some(owner) = owner {
+                let (uid, gid) = lookup_owner(owner)?;
+                chown(path.as_path(), uid, gid)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            }
+
+            Ok(Listener::Unix(socket))
+        },
+    }
+}
+
+/// Remove a socket file left over by a previous instance that didn't shut down cleanly
+///
+/// If somebody is still listening on the socket it's left alone and binding will fail later.
+fn remove_stale(path: &Path) -> io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+
+    match std::os::unix::net::UnixStream::connect(path) {
+        // There is somebody on the other end, leave it alone
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+/// Resolve an owner specification of the form `user` or `user:group`
+fn lookup_owner(owner: &str) -> io::Result<(Option<Uid>, Option<Gid>)> {
+    let mut parts = owner.splitn(2, ':');
+    let user = parts.next().unwrap_or("");
+    let group = parts.next();
+
+    let not_found = |what: &str, name: &str| io::Error::new(io::ErrorKind::NotFound,
+        format!("No such {}: {}", what, name));
+
+    let uid = if user.is_empty() {
+        None
+    } else {
+        let u = users::get_user_by_name(user).ok_or_else(|| not_found("user", user))?;
+        Some(Uid::from_raw(u.uid()))
+    };
+    let gid = match group {
+        Some(g) if !g.is_empty() => {
+            let g = users::get_group_by_name(g).ok_or_else(|| not_found("group", g))?;
+            Some(Gid::from_raw(g.gid()))
+        },
+        _ => None,
+    };
+
+    Ok((uid, gid))
+}
+
+/// Set an integer socket option, nix doesn't know all of the keepalive ones
+fn setsockopt(fd: RawFd, level: libc::c_int, name: libc::c_int, value: libc::c_int)
+    -> io::Result<()>
+{
+    let r = unsafe {
+        libc::setsockopt(fd, level, name, &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if r == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+/// A connection accepted on any kind of listener
+#[derive(Clone)]
+pub enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    /// Accepted on a WebSocket listener, but the handshake hasn't happened yet. Reading and
+    /// writing goes to the bare TCP connection, call `ready` first.
+    Upgrade(TcpStream, Rc<[String]>),
+    /// The WebSocket doesn't tell us about the peer anymore so we keep its address around
+    WebSocket(WsStream, Option<SocketAddr>),
+}
+
+impl Socket {
+    /// Do whatever needs to be done before the API can be spoken on this socket
+    pub async fn ready(self) -> io::Result<Socket> {
+        match self {
+            Socket::Upgrade(s, origins) => {
+                let peer = s.peer_addr().ok();
+                let ws = websocket::accept(s, &origins).await?;
+                Ok(Socket::WebSocket(ws, peer))
+            },
+            s => Ok(s),
+        }
+    }
+
+    /// Address of the peer if it has one
+    pub fn peer_ip(&self) -> Option<IpAddr> {
+        match self {
+            Socket::Tcp(s) | Socket::Upgrade(s, _) => s.peer_addr().ok().map(|a| a.ip()),
+            Socket::Unix(_) => None,
+            Socket::WebSocket(_, peer) => peer.map(|a| a.ip()),
+        }
+    }
+
+    /// Whether the peer is on this host
+    pub fn is_local(&self) -> bool {
+        match self {
+            Socket::Unix(_) => true,
+            _ => self.peer_ip().map_or(false, |ip| ip.is_loopback()),
+        }
+    }
+
+    /// Have the kernel probe the connection when it's silent, if it's TCP
+    ///
+    /// Has to happen before `ready`, the socket of a WebSocket is out of reach afterwards.
+    pub fn set_keepalive(&self, k: &TcpKeepalive) -> io::Result<()> {
+        let fd = match self {
+            Socket::Tcp(s) | Socket::Upgrade(s, _) => s.as_raw_fd(),
+            _ => return Ok(()),
+        };
+        setsockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+        setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, k.idle as libc::c_int)?;
+        setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, k.interval as libc::c_int)?;
+        setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, k.count as libc::c_int)
+    }
+
+    /// Human readable description of the peer for logging
+    pub fn peer_name(&self) -> String {
+        match self {
+            Socket::Tcp(s) | Socket::Upgrade(s, _) => s.peer_addr()
+                .map(|a| a.to_string())
+                .unwrap_or_else(|_| "unknown".to_string()),
+            Socket::Unix(_) => "unix".to_string(),
+            Socket::WebSocket(_, peer) => peer
+                .map(|a| a.to_string())
+                .unwrap_or_else(|| "unknown".to_string()),
+        }
+    }
+}
+
+impl AsyncRead for Socket {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+        -> Poll<io::Result<usize>>
+    {
+        match self.get_mut() {
+            Socket::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            Socket::Unix(s) => Pin::new(s).poll_read(cx, buf),
+            Socket::Upgrade(s, _) => Pin::new(s).poll_read(cx, buf),
+            Socket::WebSocket(s, _) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Socket {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        match self.get_mut() {
+            Socket::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            Socket::Unix(s) => Pin::new(s).poll_write(cx, buf),
+            Socket::Upgrade(s, _) => Pin::new(s).poll_write(cx, buf),
+            Socket::WebSocket(s, _) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(s) => Pin::new(s).poll_flush(cx),
+            Socket::Unix(s) => Pin::new(s).poll_flush(cx),
+            Socket::Upgrade(s, _) => Pin::new(s).poll_flush(cx),
+            Socket::WebSocket(s, _) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Socket::Tcp(s) => Pin::new(s).poll_close(cx),
+            Socket::Unix(s) => Pin::new(s).poll_close(cx),
+            Socket::Upgrade(s, _) => Pin::new(s).poll_close(cx),
+            Socket::WebSocket(s, _) => Pin::new(s).poll_close(cx),
+        }
+    }
+}
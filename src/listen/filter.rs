@@ -0,0 +1,222 @@
+//! Which peers a TCP listener accepts connections from, by their address
+//!
+//! This happens right after `accept`, before a peer gets to say anything, so it keeps e.g. the
+//! whole internet away from authentication. It's no replacement for a firewall: the port is still
+//! open, we just hang up on whoever isn't welcome.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use serde::{Serialize, Deserialize};
+
+/// A range of addresses like `10.0.0.0/8` or `2001:db8::/32`, or a single one without the length
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    /// With the bits past `len` cleared
+    addr: IpAddr,
+    len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, unmap(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) =>
+                mask(u32::from(ip) as u128, 32, self.len) == u32::from(net) as u128,
+            (IpAddr::V6(net), IpAddr::V6(ip)) =>
+                mask(u128::from(ip), 128, self.len) == u128::from(net),
+            _ => false,
+        }
+    }
+
+    /// Whether every address in `other` is in here as well
+    pub fn covers(&self, other: &Cidr) -> bool {
+        self.len <= other.len && self.contains(&other.addr)
+    }
+}
+
+/// `bits` of which only the first `len` of `width` are kept
+fn mask(bits: u128, width: u8, len: u8) -> u128 {
+    if len == 0 {
+        0
+    } else {
+        bits & (!0u128 << (width - len)) & (!0u128 >> (128 - width))
+    }
+}
+
+/// IPv4 peers of a socket bound to `::` show up as e.g. `::ffff:10.0.0.1`, which should be treated
+/// like `10.0.0.1`
+fn unmap(ip: &IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] =>
+                IpAddr::V4(Ipv4Addr::from((hi as u32) << 16 | lo as u32)),
+            _ => IpAddr::V6(*v6),
+        },
+        v4 => *v4,
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (addr, len) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse()
+            .map_err(|_| format!("\"{}\" doesn't start with an IP address", s))?;
+        // A range of mapped addresses is a range of IPv4 ones
+        let (addr, shift) = match (addr, unmap(&addr)) {
+            (IpAddr::V6(_), v4 @ IpAddr::V4(_)) => (v4, 96),
+            _ => (addr, 0),
+        };
+        let width = if addr.is_ipv4() { 32 } else { 128 };
+        let len = match len {
+            Some(l) => l.parse::<u8>().ok()
+                .and_then(|l| l.checked_sub(shift))
+                .filter(|l| *l <= width)
+                .ok_or_else(|| format!("\"{}\" has an invalid prefix length", s))?,
+            None => width,
+        };
+        let addr = match addr {
+            IpAddr::V4(a) =>
+                IpAddr::V4(Ipv4Addr::from(mask(u32::from(a) as u128, 32, len) as u32)),
+            IpAddr::V6(a) => IpAddr::V6(Ipv6Addr::from(mask(u128::from(a), 128, len))),
+        };
+        Ok(Self { addr, len })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        s.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(c: Cidr) -> Self {
+        c.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+/// The `allow` and `deny` lists of a listener
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerFilter {
+    /// Empty allows everybody not denied
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl PeerFilter {
+    pub fn new(allow: &[Cidr], deny: &[Cidr]) -> Self {
+        Self { allow: allow.to_vec(), deny: deny.to_vec() }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Whether a peer at `ip` may connect. Being denied wins over being allowed, so a range can be
+    /// allowed except for a part of it. Peers whose address we can't tell only get in if there
+    /// are no lists at all.
+    pub fn allows(&self, ip: Option<IpAddr>) -> bool {
+        let ip = match ip {
+            Some(ip) => ip,
+            None => return self.is_open(),
+        };
+        if self.deny.iter().any(|c| c.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|c| c.contains(&ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidrs(s: &[&str]) -> Vec<Cidr> {
+        s.iter().map(|c| c.parse().unwrap()).collect()
+    }
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!("10.1.2.3/8".parse::<Cidr>().unwrap().to_string(), "10.0.0.0/8");
+        assert_eq!("10.1.2.3".parse::<Cidr>().unwrap().to_string(), "10.1.2.3/32");
+        assert_eq!("2001:db8::1/32".parse::<Cidr>().unwrap().to_string(), "2001:db8::/32");
+        assert_eq!("0.0.0.0/0".parse::<Cidr>().unwrap().to_string(), "0.0.0.0/0");
+        // Mapped ranges are IPv4 ones
+        assert_eq!("::ffff:10.1.2.3/104".parse::<Cidr>().unwrap().to_string(), "10.0.0.0/8");
+
+        for bad in &["10.0.0.0/33", "::/129", "::ffff:10.0.0.0/95", "10.0.0/8", "10.0.0.0/x", ""] {
+            assert!(bad.parse::<Cidr>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn contains() {
+        let lan: Cidr = "192.168.0.0/16".parse().unwrap();
+        assert!(lan.contains(&"192.168.255.1".parse().unwrap()));
+        assert!(!lan.contains(&"192.169.0.1".parse().unwrap()));
+        assert!(lan.contains(&"::ffff:192.168.1.1".parse().unwrap()));
+        assert!(!lan.contains(&"::1".parse().unwrap()));
+
+        let v6: Cidr = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(&"fd12:3456::1".parse().unwrap()));
+        assert!(!v6.contains(&"fe80::1".parse().unwrap()));
+        assert!(!v6.contains(&"10.0.0.1".parse().unwrap()));
+
+        assert!(lan.covers(&"192.168.3.0/24".parse().unwrap()));
+        assert!(!lan.covers(&"192.0.0.0/8".parse().unwrap()));
+    }
+
+    #[test]
+    fn deny_beats_allow() {
+        let filter = PeerFilter::new(&cidrs(&["10.0.0.0/8"]), &cidrs(&["10.0.5.0/24"]));
+        assert!(filter.allows(ip("10.0.4.1")));
+        assert!(!filter.allows(ip("10.0.5.1")));
+        assert!(!filter.allows(ip("172.16.0.1")));
+
+        // Peers of a listener on `::` come in mapped, and are still told apart
+        assert!(filter.allows(ip("::ffff:10.0.4.1")));
+        assert!(!filter.allows(ip("::ffff:10.0.5.1")));
+        assert!(!filter.allows(ip("::ffff:172.16.0.1")));
+
+        // The same for lists of mapped addresses
+        let filter = PeerFilter::new(&cidrs(&["::ffff:10.0.0.0/104"]),
+            &cidrs(&["::ffff:10.0.5.0/120"]));
+        assert!(filter.allows(ip("10.0.4.1")));
+        assert!(!filter.allows(ip("10.0.5.1")));
+        assert!(!filter.allows(ip("::ffff:10.0.5.1")));
+    }
+
+    #[test]
+    fn empty_lists() {
+        let open = PeerFilter::default();
+        assert!(open.is_open());
+        assert!(open.allows(ip("203.0.113.1")));
+        assert!(open.allows(None));
+
+        let deny_only = PeerFilter::new(&[], &cidrs(&["203.0.113.0/24"]));
+        assert!(!deny_only.allows(ip("203.0.113.1")));
+        assert!(deny_only.allows(ip("198.51.100.1")));
+        // Without an address we can't tell whether a peer is denied
+        assert!(!deny_only.allows(None));
+    }
+}
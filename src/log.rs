@@ -1,12 +1,462 @@
-use slog::{Drain, Logger};
-use slog_async;
-use slog_term::{TermDecorator, FullFormat};
-use crate::config::Config;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
-pub fn init(_config: &Config) -> Logger {
-    let decorator = TermDecorator::new().build();
-    let drain = FullFormat::new(decorator).build().fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
+use slog::{Drain, Logger, Level, Record, RecordLocation, RecordStatic, OwnedKVList, Key, KV, Never,
+    Fuse};
+use slog_async::Async;
+use slog_term::{TermDecorator, PlainSyncDecorator, FullFormat};
+use crate::config::{Config, LogFormat};
 
-    return slog::Logger::root(drain, o!());
+/// Set up the root logger
+///
+/// `level` overrides the level set in the config, e.g. from command line flags. If logging goes
+/// to a file the handle to reopen it is returned as well.
+pub fn init(config: &Config, level: Option<Level>) -> io::Result<(Logger, Option<LogFile>)> {
+    let level = match level {
+        Some(l) => l,
+        None => parse_level(&config.log.level)?,
+    };
+    let mut levels = HashMap::new();
+    for (system, l) in config.log.levels.iter() {
+        levels.insert(system.clone(), parse_level(l)?);
+    }
+
+    // A daemon has no terminal to write to, so it has to log to a file instead.
+    let file = match config.log.file {
+        Some(ref path) => Some(LogFile::open(path)?),
+        None => None,
+    };
+
+    // Writing out happens on a separate thread in every case so logging never blocks the RPC path
+    let drain = match (config.log.format, file.clone()) {
+        (LogFormat::Term, Some(f)) => async_drain(FullFormat::new(PlainSyncDecorator::new(f))
+            .build().fuse()),
+        (LogFormat::Term, None) => async_drain(FullFormat::new(TermDecorator::new().build())
+            .build().fuse()),
+        (LogFormat::Json, Some(f)) => async_drain(slog_json::Json::default(f).fuse()),
+        (LogFormat::Json, None) => async_drain(slog_json::Json::default(io::stdout()).fuse()),
+    };
+    let drain = Dedup::new(drain, Duration::from_secs(config.log.dedup_window),
+        config.log.dedup_threshold);
+    let drain = SystemFilter { drain, level, levels };
+
+    return Ok((slog::Logger::root(drain, o!()), file));
+}
+
+fn async_drain<D: Drain<Ok = (), Err = Never> + Send + 'static>(drain: D) -> Fuse<Async> {
+    Async::new(drain).build().fuse()
+}
+
+/// A log file that can be reopened, e.g. after logrotate moved it away
+#[derive(Clone)]
+pub struct LogFile {
+    path: PathBuf,
+    file: Arc<Mutex<File>>,
+}
+
+impl LogFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { path: path.to_path_buf(), file: Arc::new(Mutex::new(file)) })
+    }
+
+    /// Continue logging to a fresh file at the same path
+    pub fn reopen(&self) -> io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        *self.file.lock().unwrap() = file;
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.lock().unwrap().flush()
+    }
+}
+
+fn parse_level(s: &str) -> io::Result<Level> {
+    Level::from_str(s).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput,
+        format!("Unknown log level \"{}\"", s)))
+}
+
+/// Drops records less severe than the level configured for the subsystem they come from
+///
+/// This sits in front of the async drain so filtered records never have to cross threads.
+struct SystemFilter<D> {
+    drain: D,
+    level: Level,
+    /// Overrides by the value of the `system` key subsystem loggers carry
+    levels: HashMap<String, Level>,
+}
+
+impl<D: Drain<Ok = ()>> Drain for SystemFilter<D> {
+    type Ok = ();
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), D::Err> {
+        let level = if self.levels.is_empty() {
+            self.level
+        } else {
+            let mut system = FindSystem(None);
+            let _ = values.serialize(record, &mut system);
+            system.0
+                .and_then(|s| self.levels.get(&s).cloned())
+                .unwrap_or(self.level)
+        };
+
+        if record.level().is_at_least(level) {
+            self.drain.log(record, values)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Picks the `system` value out of a logger's key-value pairs
+///
+/// Values of child loggers are serialized first, so the most specific one wins.
+struct FindSystem(Option<String>);
+
+impl slog::Serializer for FindSystem {
+    fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments) -> slog::Result {
+        if key == "system" && self.0.is_none() {
+            self.0 = Some(val.to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Subsystems whose messages are never held back, since every single one of them may matter
+const DEDUP_EXEMPT: &[&str] = &["admin", "authentication", "permissions"];
+/// The table of recent messages is split up so threads logging different things rarely wait for
+/// each other
+const DEDUP_SHARDS: usize = 16;
+/// Messages tracked per shard. Beyond that new ones are logged without being tracked.
+const DEDUP_TRACKED: usize = 256;
+
+/// Holds back messages repeating one logged shortly before
+///
+/// Messages are the same if their level, text and values are. Of those in `window` the first
+/// `threshold` are logged, the rest only counted. Once the window is over the count is logged in
+/// place of them. This sits behind `SystemFilter`, so messages filtered out anyway aren't counted.
+struct Dedup<D: Drain<Ok = ()>> {
+    drain: D,
+    window: Duration,
+    threshold: u32,
+    start: Instant,
+    /// Milliseconds after `start` the table was last swept for windows that are over
+    swept: AtomicU64,
+    /// Locks are only ever held for a lookup, never while logging
+    shards: Vec<Mutex<HashMap<u64, Repeats>>>,
+}
+
+/// A message seen in the current window, with what's needed to log its summary
+struct Repeats {
+    since: Instant,
+    seen: u32,
+    level: Level,
+    location: RecordLocation,
+    msg: String,
+    values: OwnedKVList,
+}
+
+impl<D: Drain<Ok = ()>> Dedup<D> {
+    fn new(drain: D, window: Duration, threshold: u32) -> Self {
+        let shards = (0..DEDUP_SHARDS).map(|_| Mutex::new(HashMap::new())).collect();
+        Self { drain, window, threshold: threshold.max(1), start: Instant::now(),
+            swept: AtomicU64::new(0), shards }
+    }
+
+    /// Log how many times the message of `r` was held back, if it was at all
+    fn summarize(&self, r: &Repeats) -> Result<(), D::Err> {
+        if r.seen <= self.threshold {
+            return Ok(());
+        }
+        let held = r.seen - self.threshold;
+        let rs = RecordStatic { location: &r.location, tag: "", level: r.level };
+        self.drain.log(&Record::new(&rs,
+            &format_args!("previous message repeated {} times: {}", held, r.msg),
+            b!("repeated" => held)), &r.values)
+    }
+
+    /// Summarize and forget every message whose window is over at `now`
+    fn sweep(&self, now: Instant) -> Result<(), D::Err> {
+        let mut over = Vec::new();
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            let keys: Vec<u64> = shard.iter()
+                .filter(|(_, r)| now.duration_since(r.since) >= self.window)
+                .map(|(k, _)| *k)
+                .collect();
+            over.extend(keys.iter().filter_map(|k| shard.remove(k)));
+        }
+        for r in over.iter() {
+            self.summarize(r)?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: Drain<Ok = ()>> Drain for Dedup<D> {
+    type Ok = ();
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), D::Err> {
+        if self.window == Duration::from_secs(0) {
+            return self.drain.log(record, values);
+        }
+
+        let mut print = Fingerprint { hasher: DefaultHasher::new(), system: None };
+        record.level().as_usize().hash(&mut print.hasher);
+        let _ = fmt::write(&mut print, *record.msg());
+        let _ = record.kv().serialize(record, &mut print);
+        let _ = values.serialize(record, &mut print);
+        if print.system.map(|s| DEDUP_EXEMPT.contains(&s.as_str())).unwrap_or(false) {
+            return self.drain.log(record, values);
+        }
+
+        let key = print.hasher.finish();
+        let now = Instant::now();
+        let (log, over) = {
+            let mut shard = self.shards[key as usize % DEDUP_SHARDS].lock().unwrap();
+            match shard.get_mut(&key) {
+                Some(r) if now.duration_since(r.since) < self.window => {
+                    r.seen += 1;
+                    (r.seen <= self.threshold, None)
+                },
+                _ => {
+                    let over = shard.remove(&key);
+                    if shard.len() < DEDUP_TRACKED {
+                        let l = record.location();
+                        shard.insert(key, Repeats {
+                            since: now,
+                            seen: 1,
+                            level: record.level(),
+                            location: RecordLocation { file: l.file, line: l.line,
+                                column: l.column, function: l.function, module: l.module },
+                            msg: record.msg().to_string(),
+                            values: values.clone(),
+                        });
+                    }
+                    (true, over)
+                },
+            }
+        };
+
+        // The summary of the last window goes before the first message of the next
+        if let Some(r) = over {
+            self.summarize(&r)?;
+        }
+        if log {
+            self.drain.log(record, values)?;
+        }
+
+        // Messages that stopped repeating are summarized whenever anything is logged after their
+        // window, with only one thread at a time looking for them
+        let elapsed = now.duration_since(self.start).as_millis() as u64;
+        let swept = self.swept.load(Ordering::Relaxed);
+        if elapsed.saturating_sub(swept) >= self.window.as_millis() as u64
+            && self.swept.compare_exchange(swept, elapsed, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.sweep(now)?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: Drain<Ok = ()>> Drop for Dedup<D> {
+    fn drop(&mut self) {
+        // Don't take the counts of the last windows with us
+        if let Some(end) = Instant::now().checked_add(self.window) {
+            let _ = self.sweep(end);
+        }
+    }
+}
+
+/// Hashes a message's text and values, picking out the `system` value on the way
+struct Fingerprint {
+    hasher: DefaultHasher,
+    system: Option<String>,
+}
+
+impl fmt::Write for Fingerprint {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.hasher.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl slog::Serializer for Fingerprint {
+    fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments) -> slog::Result {
+        if key == "system" && self.system.is_none() {
+            self.system = Some(val.to_string());
+        }
+        key.hash(&mut self.hasher);
+        let _ = fmt::write(self, *val);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keeps the level and text of everything logged
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<(Level, String)>>>);
+
+    impl Capture {
+        fn take(&self) -> Vec<(Level, String)> {
+            std::mem::take(&mut *self.0.lock().unwrap())
+        }
+    }
+
+    impl Drain for Capture {
+        type Ok = ();
+        type Err = Never;
+
+        fn log(&self, record: &Record, _: &OwnedKVList) -> Result<(), Never> {
+            self.0.lock().unwrap().push((record.level(), record.msg().to_string()));
+            Ok(())
+        }
+    }
+
+    fn filtered(level: Level, levels: &[(&str, Level)]) -> (Logger, Capture) {
+        let capture = Capture::default();
+        let levels = levels.iter().map(|(s, l)| (s.to_string(), *l)).collect();
+        let log = Logger::root(SystemFilter { drain: capture.clone(), level, levels }, o!());
+        (log, capture)
+    }
+
+    #[test]
+    fn filters_by_level() {
+        let (log, capture) = filtered(Level::Info, &[]);
+        debug!(log, "debug");
+        info!(log, "info");
+        error!(log, "error");
+        assert_eq!(capture.take(), vec![
+            (Level::Info, "info".to_string()),
+            (Level::Error, "error".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn filters_by_system() {
+        let (log, capture) = filtered(Level::Info, &[("machines", Level::Trace),
+            ("api", Level::Error)]);
+        let machines = log.new(o!("system" => "machines"));
+        let api = log.new(o!("system" => "api"));
+        let other = log.new(o!("system" => "audit"));
+
+        trace!(machines, "machines trace");
+        warn!(api, "api warning");
+        error!(api, "api error");
+        debug!(other, "audit debug");
+        info!(other, "audit info");
+        assert_eq!(capture.take(), vec![
+            (Level::Trace, "machines trace".to_string()),
+            (Level::Error, "api error".to_string()),
+            (Level::Info, "audit info".to_string()),
+        ]);
+
+        // The innermost system is the one that counts
+        let nested = machines.new(o!("system" => "api"));
+        trace!(nested, "nested trace");
+        assert_eq!(capture.take(), vec![]);
+    }
+
+    #[test]
+    fn levels_parse() {
+        assert_eq!(parse_level("debug").unwrap(), Level::Debug);
+        assert_eq!(parse_level("TRACE").unwrap(), Level::Trace);
+        let e = parse_level("loud").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(e.to_string().contains("\"loud\""));
+    }
+
+    fn deduped(window: Duration, threshold: u32) -> (Logger, Capture) {
+        let capture = Capture::default();
+        let log = Logger::root(Dedup::new(capture.clone(), window, threshold), o!());
+        (log, capture)
+    }
+
+    fn texts(capture: &Capture) -> Vec<String> {
+        capture.take().into_iter().map(|(_, msg)| msg).collect()
+    }
+
+    #[test]
+    fn holds_back_repeats() {
+        let (log, capture) = deduped(Duration::from_secs(60), 2);
+        for _ in 0..5 {
+            warn!(log, "disk full"; "path" => "/var/lib/bffh");
+        }
+        // Other values or another level make another message
+        warn!(log, "disk full"; "path" => "/tmp");
+        error!(log, "disk full"; "path" => "/var/lib/bffh");
+        assert_eq!(texts(&capture), vec!["disk full"; 4]);
+
+        // Whatever was held back is counted once we're gone
+        drop(log);
+        assert_eq!(capture.take(), vec![
+            (Level::Warning, "previous message repeated 3 times: disk full".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn summaries_after_the_window() {
+        let window = Duration::from_millis(50);
+        let (log, capture) = deduped(window, 1);
+        for _ in 0..3 {
+            info!(log, "tick");
+        }
+        std::thread::sleep(window * 2);
+
+        // The summary of the last window goes right before the first message of the next one
+        info!(log, "tick");
+        assert_eq!(texts(&capture), vec!["tick", "previous message repeated 2 times: tick",
+            "tick"]);
+
+        // Messages that stopped repeating are summarized once anything else is logged
+        info!(log, "tock");
+        info!(log, "tock");
+        std::thread::sleep(window * 2);
+        info!(log, "something else");
+        assert_eq!(texts(&capture), vec!["tock", "something else",
+            "previous message repeated 1 times: tock"]);
+
+        // Nothing that wasn't held back gets a summary
+        drop(log);
+        assert_eq!(texts(&capture), Vec::<String>::new());
+    }
+
+    #[test]
+    fn exempt_systems_and_no_window() {
+        let (log, capture) = deduped(Duration::from_secs(60), 1);
+        let auth = log.new(o!("system" => "authentication"));
+        for _ in 0..3 {
+            info!(auth, "login failed"; "user" => "alice");
+        }
+        assert_eq!(texts(&capture), vec!["login failed"; 3]);
+
+        let (log, capture) = deduped(Duration::from_secs(0), 1);
+        for _ in 0..3 {
+            info!(log, "tick");
+        }
+        drop(log);
+        assert_eq!(texts(&capture), vec!["tick"; 3]);
+    }
 }
@@ -1,21 +1,32 @@
-use std::collections::HashMap;
-use std::fs::File;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
 use std::path::Path;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use slog::Logger;
 
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, Deserializer};
+use serde::de::{Visitor, MapAccess};
 use toml;
 
+use futures::channel::{mpsc, oneshot};
+use futures::future::{FutureExt, LocalBoxFuture};
+use futures::task::{Spawn, SpawnExt};
 use futures_signals::signal::Mutable;
 
-use crate::error::Result;
+use crate::error::{Result, WithPath};
 use crate::config::Config;
 use crate::api::api;
-use crate::access::Permissions;
+use crate::api::error;
+use crate::access::{self, Permissions};
+use crate::audit::{self, Audit, AuditEvent};
+use crate::gc::Registry;
+use crate::status::Status as ServerStatus;
 
 use std::rc::Rc;
+use std::cell::RefCell;
 use async_std::sync::{Arc, RwLock};
 
 use capnp::capability::Promise;
@@ -25,8 +36,27 @@ use capnp_rpc::Server;
 use uuid::Uuid;
 use std::ops::DerefMut;
 
+use fs2::FileExt;
+
+use chrono_tz::Tz;
+
+pub mod store;
+use store::MachineStore;
+pub mod deny;
+use deny::DenyReason;
+pub mod format;
+pub mod hours;
+pub mod journal;
+pub mod location;
+pub mod lock;
+pub mod watch;
+use hours::OpeningHours;
+use journal::{Journal, Transition};
+use location::{Location, Locations};
+use watch::{Watch, Watches};
+
 /// Status of a Machine
-#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum Status {
     /// Not currently used by anybody
     Free,
@@ -36,281 +66,3148 @@ pub enum Status {
     Blocked,
 }
 
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Free => "free",
+            Status::Occupied => "occupied",
+            Status::Blocked => "blocked",
+        }
+    }
+}
+
+impl From<api::machines::Status> for Status {
+    fn from(s: api::machines::Status) -> Self {
+        match s {
+            api::machines::Status::Free => Status::Free,
+            api::machines::Status::Occupied => Status::Occupied,
+            api::machines::Status::Blocked => Status::Blocked,
+        }
+    }
+}
+
+impl From<Status> for api::machines::Status {
+    fn from(s: Status) -> Self {
+        match s {
+            Status::Free => api::machines::Status::Free,
+            Status::Occupied => api::machines::Status::Occupied,
+            Status::Blocked => api::machines::Status::Blocked,
+        }
+    }
+}
+
+/// Source of the current time
+///
+/// Exists so the timekeeping of the machine state can be driven by something other than the system
+/// clock, e.g. in tests.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The regular system clock
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Convert a point in time to seconds since the UNIX epoch
+///
+/// Times before the epoch are clamped to it.
+pub fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// The state of a machine after it changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateChange {
+    pub uuid: Uuid,
+    pub name: String,
+    pub status: Status,
+    /// Who is using the machine, if we know
+    pub occupant: Option<String>,
+    /// When the machine was taken into use, in seconds since the UNIX epoch
+    pub since: Option<u64>,
+    /// What the occupant is working on, if they said
+    pub note: Option<String>,
+}
+
+/// How the actor of a machine has been doing
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ActorStatus {
+    /// When it was last told to switch and whether on, in seconds since the UNIX epoch
+    pub last_command: Option<(u64, bool)>,
+    /// When it last carried out a command, in seconds since the UNIX epoch
+    pub last_success: Option<u64>,
+    /// When it last failed to carry out a command and why, in seconds since the UNIX epoch.
+    /// Cleared once a command succeeds.
+    pub last_error: Option<(u64, String)>,
+}
+
+impl ActorStatus {
+    /// Whether the device does what it's told, as far as we know
+    pub fn online(&self) -> bool {
+        self.last_error.is_none()
+    }
+}
+
+/// Switching the device powering a machine by hand, answered once the device confirmed it did
+pub struct ActorOverride {
+    pub power: bool,
+    pub reply: oneshot::Sender<io::Result<()>>,
+}
+
+/// A freed machine reserved for the user that was first in its queue
+struct Hold {
+    user: String,
+    /// Until when the reservation lasts, in UNIX seconds
+    until: u64,
+}
+
 pub struct MachinesProvider {
     log: Logger,
-    mdb: MachineDB,
+    mdb: Box<dyn MachineStore>,
+    clock: Box<dyn Clock>,
+
+    /// Users waiting for a machine, in order.
+    ///
+    /// Queues only live in memory and are kept separate from the database so they are not affected
+    /// by it being reloaded or replaced.
+    queues: HashMap<Uuid, VecDeque<String>>,
+    holds: HashMap<Uuid, Hold>,
+    /// How long a hold lasts, in seconds
+    queue_hold: u64,
+    /// How long machines may be in use at most, in seconds
+    max_use: Option<u64>,
+    /// What perms of machines without one are derived from
+    perm_template: Option<String>,
+    /// What opening hours of machines are in
+    timezone: Tz,
+    /// How the actors of machines that have one are doing
+    actors: HashMap<Uuid, ActorStatus>,
+    /// Where to send overrides to the actors of machines
+    overrides: HashMap<Uuid, mpsc::UnboundedSender<ActorOverride>>,
+    /// Machines in use by each user, so finding somebody's doesn't take a look at every machine
+    by_occupant: HashMap<String, HashSet<Uuid>>,
+    /// Users who want to be told when a machine becomes free
+    watches: Watches,
+    /// How long they wait at most, in seconds
+    watch_expiry: u64,
+    /// Seconds routine changes may wait to be saved, 0 if they're saved right away
+    write_interval: u64,
+    /// Whether there are changes waiting for `write_pending`
+    pending: bool,
+    /// Where uses and givebacks go before they're applied while they wait, if anywhere
+    journal: Option<Journal>,
+    /// How long calls wait for the lock on all of this, see `lock`
+    lock_timeout: Duration,
+
+    status: Arc<ServerStatus>,
+    /// Everybody who wants to hear about machines changing their state
+    subscribers: Vec<mpsc::UnboundedSender<StateChange>>,
 }
 
 impl MachinesProvider {
-    pub fn new(log: Logger, mdb: MachineDB) -> Self {
-        Self { log, mdb }
+    pub fn new(log: Logger, mdb: Box<dyn MachineStore>, queue_hold: u64,
+        perm_template: Option<String>, timezone: Tz, status: Arc<ServerStatus>) -> Self
+    {
+        Self::with_clock(log, mdb, queue_hold, perm_template, timezone, status,
+            Box::new(SystemClock))
     }
 
-    pub fn use_(&mut self, uuid: &Uuid) -> std::result::Result<(), capnp::Error> {
-        if let Some(m) = self.mdb.get_mut(uuid) {
-            match m.status {
-                Status::Free => {
-                    trace!(self.log, "Granted use on machine {}", uuid);
-
-                    m.status = Status::Occupied;
-
-                    Ok(())
-                },
-                Status::Occupied => {
-                    info!(self.log, "Attempted use on an occupied machine {}", uuid);
-                    Err(Error::failed("Machine is occupied".to_string()))
-                },
-                Status::Blocked => {
-                    info!(self.log, "Attempted use on a blocked machine {}", uuid);
-                    Err(Error::failed("Machine is blocked".to_string()))
-                }
+    pub fn with_clock(log: Logger, mdb: Box<dyn MachineStore>, queue_hold: u64,
+        perm_template: Option<String>, timezone: Tz, status: Arc<ServerStatus>,
+        clock: Box<dyn Clock>) -> Self
+    {
+        status.set_machines(mdb.len());
+        // Uses survive restarts, so there may be some already
+        let mut by_occupant: HashMap<String, HashSet<Uuid>> = HashMap::new();
+        for (uuid, m) in mdb.iter() {
+            if let (Status::Occupied, Some(o)) = (m.status, m.occupant.as_ref()) {
+                by_occupant.entry(o.clone()).or_default().insert(uuid.clone());
             }
-        } else {
-            info!(self.log, "Attempted use on invalid machine {}", uuid);
-            Err(Error::failed("No such machine".to_string()))
+        }
+        Self {
+            log, mdb, clock, queue_hold, perm_template, timezone, status, by_occupant,
+            queues: HashMap::new(),
+            holds: HashMap::new(),
+            actors: HashMap::new(),
+            overrides: HashMap::new(),
+            watches: Watches::disabled(),
+            watch_expiry: 0,
+            max_use: None,
+            write_interval: 0,
+            pending: false,
+            journal: None,
+            lock_timeout: lock::DEFAULT_TIMEOUT,
+            subscribers: Vec::new(),
         }
     }
 
-    pub fn give_back(&mut self, uuid: &Uuid) -> std::result::Result<(), capnp::Error> {
-        if let Some(m) = self.mdb.get_mut(uuid) {
-            m.status = Status::Free;
-        } else {
-            warn!(self.log, "A giveback was issued for a unknown machine {}", uuid);
-        }
+    /// Let users wait for machines to become free, for up to `expiry` seconds
+    pub fn set_watches(&mut self, watches: Watches, expiry: u64) {
+        self.watches = watches;
+        self.watch_expiry = expiry;
+    }
 
-        Ok(())
+    /// Give back machines that have been in use for `max_use` seconds, see `expire_uses`
+    pub fn set_max_use(&mut self, max_use: Option<u64>) {
+        self.max_use = max_use;
     }
 
-    pub fn get_perm_req(&self, uuid: &Uuid) -> Option<String> {
-        self.mdb.get(uuid).map(|m| m.perm.clone())
+    /// Let machines being used and given back wait up to `secs` to be saved, see `persist_soon`
+    pub fn set_write_interval(&mut self, secs: u64) {
+        self.write_interval = secs;
     }
 
-    pub fn set_blocked(&mut self, uuid: &Uuid, blocked: bool) -> std::result::Result<(), capnp::Error> {
-        // If the value can not be found map doesn't run and ok_or changes it into a Err with the
-        // given error value
-        self.mdb.get_mut(uuid).map(|m| m.set_blocked(blocked))
-            .ok_or(capnp::Error::failed("No such machine".to_string()))
+    /// Let calls wait up to `secs` for the lock on the machines before giving up, see `lock`
+    pub fn set_lock_timeout(&mut self, secs: u64) {
+        self.lock_timeout = Duration::from_secs(secs);
     }
-}
 
-#[derive(Clone)]
-pub struct Machines {
-    inner: Arc<RwLock<MachinesProvider>>,
-    perm: Rc<Permissions>,
-}
-impl Machines {
-    pub fn new(inner: Arc<RwLock<MachinesProvider>>, perm: Rc<Permissions>) -> Self {
-        Self { inner, perm }
+    /// How long calls wait for the lock on the machines
+    pub fn lock_timeout(&self) -> Duration {
+        self.lock_timeout
     }
-}
-impl api::machines::Server for Machines {
-    fn manage(&mut self,
-        params: api::machines::ManageParams,
-        mut results: api::machines::ManageResults)
-        -> Promise<(), Error>
-    {
-        let params = pry!(params.get());
-        let uuid_s = pry!(params.get_uuid());
-        let uuid = uuid_from_api(uuid_s);
 
-        // We need to copy the Arc here because we don't have access to it from within the closure
-        // witout moving it out of self.
-        let i = self.inner.clone();
-        let p = self.perm.clone();
+    /// Append uses and givebacks to `journal` before applying them, see `journal`
+    pub fn set_journal(&mut self, journal: Journal) {
+        self.journal = Some(journal);
+    }
 
-        let f = async move {
-            // We only need a read lock at first there's no reason to aquire a write lock.
-            let i_lock = i.read().await;
-
-            if let Some(ps) = i_lock.get_perm_req(&uuid) {
-                // drop the lock as soon as possible to prevent locking as much as possible
-                drop(i_lock);
-                if let Ok(true) = p.enforce(&ps, "manage").await {
-                    // We're here and have not returned an error yet - that means we're free to
-                    // send a successful manage back.
-                    let mut b = results.get();
-
-                    // Magic incantation to get a capability to send
-                    // Also since we move i in here we at this point *must* have dropped
-                    // all locks we may still have on it.
-                    b.set_manage(api::machines::manage::ToClient::new(
-                            MachineManager::new(uuid, i)).into_client::<Server>());
+    /// Get told about every change to the state of a machine from now on
+    ///
+    /// Changes are queued without bound and never wait for the receiver, so it has to keep up.
+    pub fn subscribe(&mut self) -> mpsc::UnboundedReceiver<StateChange> {
+        let (tx, rx) = mpsc::unbounded();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    /// Drop subscribers that went away while no machine changed
+    pub fn prune_subscribers(&mut self) -> usize {
+        let before = self.subscribers.len();
+        self.subscribers.retain(|s| !s.is_closed());
+        before - self.subscribers.len()
+    }
+
+    /// Current state of a machine
+    pub fn state(&self, uuid: &Uuid) -> Option<StateChange> {
+        self.mdb.get(uuid).map(|m| StateChange {
+            uuid: uuid.clone(),
+            name: m.name.clone(),
+            status: m.status,
+            occupant: m.occupant.clone(),
+            since: m.since,
+            note: m.note.clone(),
+        })
+    }
+
+    /// Who is using a machine, if we know
+    pub fn occupant(&self, uuid: &Uuid) -> Option<&str> {
+        self.mdb.get(uuid).and_then(|m| m.occupant.as_deref())
+    }
+
+    /// Machines `user` is currently using
+    pub fn occupied_by(&self, user: &str) -> Vec<(Uuid, Machine)> {
+        self.by_occupant.get(user).into_iter().flatten()
+            .filter_map(|uuid| self.get(uuid).map(|m| (uuid.clone(), m)))
+            .collect()
+    }
+
+    /// Forget that `occupant`, if known, was using a machine
+    fn vacate(&mut self, uuid: &Uuid, occupant: Option<String>) {
+        if let Some(o) = occupant {
+            if let Some(machines) = self.by_occupant.get_mut(&o) {
+                machines.remove(uuid);
+                if machines.is_empty() {
+                    self.by_occupant.remove(&o);
                 }
             }
-            Ok(())
-        };
+        }
+    }
 
-        Promise::from_future(f)
+    /// Current state of all machines
+    pub fn states(&self) -> Vec<StateChange> {
+        self.mdb.iter().filter_map(|(uuid, _)| self.state(uuid)).collect()
     }
 
-    fn use_(&mut self,
-        params: api::machines::UseParams,
-        mut results: api::machines::UseResults)
-        -> Promise<(), capnp::Error>
+    /// Put a machine into a safe state after the device powering it failed to follow `change`
+    ///
+    /// A machine that couldn't be switched on is given back, one that couldn't be switched off is
+    /// blocked since it may still be running. With a `block_reason` it's blocked either way, so
+    /// nobody tries to use it until the device works again. If the machine changed state again in
+    /// the meantime that change is what counts now and nothing is done. Returns the status the
+    /// machine was put in, if any.
+    pub fn actor_failed(&mut self, log: &Logger, change: &StateChange,
+        block_reason: Option<String>) -> std::result::Result<Option<Status>, capnp::Error>
     {
-        let params = pry!(params.get());
-        let uuid_s = pry!(params.get_uuid());
-        let uuid = uuid_from_api(uuid_s);
+        if self.state(&change.uuid).as_ref() != Some(change) {
+            return Ok(None);
+        }
 
-        // We need to copy the Arc here because we don't have access to it from within the closure
-        // witout moving it out of self.
-        let i = self.inner.clone();
-        let p = self.perm.clone();
+        match change.status {
+            // Can't get any safer than that
+            Status::Blocked => Ok(None),
+            _ if block_reason.is_some() => {
+                self.block(log, &change.uuid, true, block_reason)?;
+                Ok(Some(Status::Blocked))
+            },
+            Status::Occupied => {
+                self.give_back(log, &change.uuid)?;
+                Ok(Some(Status::Free))
+            },
+            Status::Free => {
+                self.set_blocked(log, &change.uuid, true)?;
+                Ok(Some(Status::Blocked))
+            },
+        }
+    }
 
-        let f = async move {
-            // We only need a read lock at first there's no reason to aquire a write lock.
-            let i_lock = i.read().await;
-
-            if let Some(ps) = i_lock.get_perm_req(&uuid) {
-                // drop the lock as soon as possible to prevent locking as much as possible
-                drop(i_lock);
-                if let Ok(true) = p.enforce(&ps, "write").await {
-                    {
-                        // If use_() returns an error that is our error. If it doesn't that means we can use
-                        // the machine
-                        // Using a subscope to again make the time the lock is valid as short as
-                        // possible. Less locking == more good
-                        let mut i_lock = i.write().await;
-                        i_lock.use_(&uuid)?;
-                    }
+    /// How the actor of a machine is doing, `None` if it has none
+    pub fn actor_status(&self, uuid: &Uuid) -> Option<&ActorStatus> {
+        self.actors.get(uuid)
+    }
+
+    pub fn set_actor_status(&mut self, uuid: &Uuid, status: ActorStatus) {
+        self.actors.insert(uuid.clone(), status);
+    }
+
+    /// Send overrides of the actor of `uuid` to `tx`
+    pub fn set_actor_control(&mut self, uuid: &Uuid, tx: mpsc::UnboundedSender<ActorOverride>) {
+        self.overrides.insert(uuid.clone(), tx);
+    }
+
+    /// Switch the device powering `uuid` without changing the state of the machine
+    ///
+    /// `None` if the machine has no actor running. Otherwise the receiver resolves once the
+    /// actor is done trying.
+    pub fn override_actor(&self, uuid: &Uuid, power: bool)
+        -> Option<oneshot::Receiver<io::Result<()>>>
+    {
+        let tx = self.overrides.get(uuid)?;
+        let (reply, rx) = oneshot::channel();
+        tx.unbounded_send(ActorOverride { power, reply }).ok()?;
+        Some(rx)
+    }
 
-                    // We're here and have not returned an error yet - that means we're free to
-                    // send a successful use back.
-                    let mut b = results.get();
+    /// Tell all subscribers about the current state of a machine
+    fn notify(&mut self, uuid: &Uuid) {
+        if let Some(change) = self.state(uuid) {
+            // Subscribers that went away don't need to hear about anything anymore
+            self.subscribers.retain(|s| s.unbounded_send(change.clone()).is_ok());
+        }
+    }
+
+    /// Current time in seconds since the UNIX epoch according to our clock
+    pub fn now(&self) -> u64 {
+        unix_secs(self.clock.now())
+    }
 
-                    // Magic incantation to get a capability to send
-                    // Also since we move i in here we at this point *must* have dropped
-                    // all locks we may still have on it.
-                    b.set_giveback(api::machines::give_back::ToClient::new(
-                            GiveBack::new(i, uuid)).into_client::<Server>());
+    /// Write all pending changes to the machine database to its backend
+    ///
+    /// Fails with `Error::ReadOnly` in read-only mode, the changes are kept until it's over.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.status.read_only() {
+            return Err(crate::error::Error::ReadOnly);
+        }
+        let r = self.mdb.flush();
+        self.status.saved(r.is_ok());
+        if r.is_ok() {
+            self.pending = false;
+            // Everything in the journal was saved with the rest now
+            if let Some(ref mut journal) = self.journal {
+                if let Err(e) = journal.truncate() {
+                    error!(self.log, "Failed to empty machine journal {}: {}",
+                        journal.path().display(), e);
                 }
             }
-            Ok(())
-        };
+        }
+        r
+    }
 
-        Promise::from_future(f)
+    /// Save the changes waiting for it, if there are any
+    pub fn flush_pending(&mut self) {
+        if self.pending {
+            self.persist();
+        }
     }
-}
 
-#[derive(Clone)]
-pub struct GiveBack {
-    mdb: Arc<RwLock<MachinesProvider>>,
-    uuid: Uuid,
-}
-impl GiveBack {
-    pub fn new(mdb: Arc<RwLock<MachinesProvider>>, uuid: Uuid) -> Self {
-        Self { mdb, uuid }
+    /// Write changes to the machine database out to its backend
+    ///
+    /// Failing to save is logged but otherwise not fatal; the in-memory state is still correct
+    /// and the next successful flush will contain the changes.
+    fn persist(&mut self) {
+        if self.status.read_only() {
+            self.pending = true;
+            return;
+        }
+        if let Err(e) = self.flush() {
+            error!(self.log, "Failed to save machine database: {}", e);
+        }
     }
-}
 
-impl api::machines::give_back::Server for GiveBack {
-    fn giveback(&mut self,
-        _params: api::machines::give_back::GivebackParams,
-        _results: api::machines::give_back::GivebackResults)
-        -> Promise<(), Error>
-    {
-        let mdb = self.mdb.clone();
-        let uuid = self.uuid.clone();
-        let f = async move {
-            mdb.write().await.give_back(&uuid)
+    /// Like `persist`, for routine changes that may wait for the next run of `write_pending`
+    ///
+    /// A class arriving at once would otherwise mean a write for every student. Whatever waits is
+    /// lost if we crash unless it went to the journal, so without one this is only for changes
+    /// that are cheap to lose: at worst a machine comes back as used by whoever had it
+    /// `write_interval` seconds earlier.
+    fn persist_soon(&mut self) {
+        if self.write_interval == 0 {
+            return self.persist();
+        }
+        self.pending = true;
+        self.status.add_coalesced();
+    }
+
+    /// Put machine `uuid` into the state of `t`, appending it to the journal first
+    ///
+    /// The change is saved right away if it couldn't be journaled, otherwise it may wait.
+    fn transition(&mut self, t: Transition) {
+        let uuid = t.uuid;
+        // Also in read-only mode, where the change is only saved once it's over. The machine
+        // database is left alone until then, but the change still survives us crashing.
+        let journaled = match self.journal {
+            Some(ref mut journal) => match journal.append(&t) {
+                Ok(()) => true,
+                Err(e) => {
+                    error!(self.log, "Failed to append to machine journal {}, saving right \
+                        away: {}", journal.path().display(), e);
+                    false
+                },
+            },
+            None => true,
         };
+        if let Some(m) = self.mdb.get_mut(&uuid) {
+            t.apply(m);
+        }
+        if journaled {
+            self.persist_soon();
+        } else {
+            self.persist();
+        }
+    }
 
-        Promise::from_future(f)
+    pub fn read_only(&self) -> bool {
+        self.status.read_only()
     }
-}
 
-fn uuid_from_api(uuid: api::u_u_i_d::Reader) -> Uuid {
-    let uuid0 = uuid.get_uuid0() as u128;
-    let uuid1 = uuid.get_uuid1() as u128;
-    let num: u128 = (uuid1 << 64) + uuid0;
-    Uuid::from_u128(num)
-}
-fn api_from_uuid(uuid: Uuid, mut wr: api::u_u_i_d::Builder) {
-    let num = uuid.to_u128_le();
-    let uuid0 = num as u64;
-    let uuid1 = (num >> 64) as u64;
-    wr.set_uuid0(uuid0);
-    wr.set_uuid1(uuid1);
-}
+    /// Enter or leave read-only mode, returning whether that changed anything
+    ///
+    /// Clients are refused changes from then on, see `writable`. Calls that made it past that
+    /// before still complete once they get the lock, e.g. a giveback waiting for it, but what
+    /// they and we ourselves change while in read-only mode is only saved once it's over, uses and
+    /// givebacks only go to the journal until then. What was changed before is saved on entering,
+    /// so the machine database is complete for e.g. a backup.
+    pub fn set_read_only(&mut self, on: bool) -> bool {
+        if self.status.read_only() == on {
+            return false;
+        }
+        if on {
+            self.flush_pending();
+            self.status.set_read_only(true);
+        } else {
+            self.status.set_read_only(false);
+            self.flush_pending();
+        }
+        true
+    }
 
-#[derive(Clone)]
-pub struct MachineManager {
-    mdb: Arc<RwLock<MachinesProvider>>,
-    uuid: Uuid,
-}
+    /// `log` is the logger of the connection on whose behalf this happens
+    ///
+    /// Returns the id of the new grant. Denials carry the occupant of the machine, callers have to
+    /// hide it from users who may not see it. Outside of its opening hours a machine can only be
+    /// used `anytime`, which is for those allowed to manage it. `note` has to be sanitized already.
+    pub fn use_(&mut self, log: &Logger, uuid: &Uuid, user: &str, anytime: bool,
+        note: Option<String>)
+        -> std::result::Result<Uuid, DenyReason>
+    {
+        let now = self.now();
+        let timezone = self.timezone;
 
-impl MachineManager {
-    pub fn new(uuid: Uuid, mdb: Arc<RwLock<MachinesProvider>>) -> Self {
-        Self { mdb, uuid }
+        // While a machine is held for the head of its queue nobody else may use it. Once the hold
+        // expired it's free for all again.
+        if let Some(hold) = self.holds.get(uuid) {
+            if hold.until > now && hold.user != user {
+                info!(log, "Attempted use on machine {} reserved for {}", uuid, hold.user);
+                return Err(DenyReason::Reserved { until: hold.until });
+            }
+        }
+
+        let grant = Uuid::new_v4();
+        if let Some(m) = self.mdb.get(uuid) {
+            match m.status {
+                Status::Free if !anytime && !hours::allows(&m.allowed_hours, timezone, now) => {
+                    info!(log, "Attempted use on machine {} outside of its opening hours", uuid);
+                    return Err(DenyReason::Closed {
+                        opens: hours::next_open(&m.allowed_hours, timezone, now),
+                    });
+                },
+                Status::Free => {
+                    trace!(log, "Granted use on machine {}", uuid);
+                },
+                Status::Occupied => {
+                    info!(log, "Attempted use on an occupied machine {}", uuid);
+                    return Err(DenyReason::Occupied {
+                        occupant: m.occupant.clone(),
+                        since: m.since,
+                    });
+                },
+                Status::Blocked => {
+                    info!(log, "Attempted use on a blocked machine {}", uuid);
+                    // Blocked by hand we only know why if a reason was given, and never for how
+                    // long
+                    let scheduled = m.schedule.iter().find(|b| b.applied);
+                    let reason = match scheduled {
+                        Some(b) => Some(b.reason.clone()),
+                        None => m.block_reason.clone(),
+                    };
+                    return Err(DenyReason::Blocked {
+                        reason: reason.filter(|r| !r.is_empty()),
+                        until: scheduled.map(|b| b.end),
+                    });
+                }
+            }
+        } else {
+            info!(log, "Attempted use on invalid machine {}", uuid);
+            return Err(DenyReason::NoSuchMachine);
+        }
+
+        // Whoever got the machine doesn't need to wait for it anymore, and a hold has served its
+        // purpose either way.
+        self.holds.remove(uuid);
+        self.leave_queue(uuid, user);
+        self.by_occupant.entry(user.to_string()).or_default().insert(uuid.clone());
+
+        self.transition(Transition {
+            uuid: uuid.clone(),
+            status: Status::Occupied,
+            since: Some(now),
+            occupant: Some(user.to_string()),
+            grant: Some(grant),
+            note,
+        });
+        self.notify(uuid);
+        Ok(grant)
     }
-}
 
-impl api::machines::manage::Server for MachineManager {
-    fn set_blocked(&mut self,
-        params: api::machines::manage::SetBlockedParams,
-        results: api::machines::manage::SetBlockedResults)
-        -> Promise<(), Error>
-    {
-        let uuid = self.uuid.clone();
-        let mdb = self.mdb.clone();
-        let f = async move {
-            let params = params.get()?;
-            let blocked = params.get_blocked();
-            mdb.write().await.set_blocked(&uuid, blocked)?;
-            Ok(())
-        };
+    /// The grant `user` holds on a machine, for handing out a new `GiveBack` after reconnecting
+    pub fn reclaim(&self, uuid: &Uuid, user: &str) -> std::result::Result<Uuid, capnp::Error> {
+        let m = self.mdb.get(uuid).ok_or_else(|| error::no_such_machine(uuid))?;
+        match (m.status, m.occupant.as_deref(), m.grant) {
+            (Status::Occupied, Some(o), Some(grant)) if o == user => Ok(grant),
+            _ => Err(error::error(error::Code::NoSuchGrant, "Not using this machine")),
+        }
+    }
 
-        Promise::from_future(f)
+    /// Id of the grant a machine is currently in use under
+    pub fn grant(&self, uuid: &Uuid) -> Option<Uuid> {
+        self.mdb.get(uuid).and_then(|m| m.grant)
     }
 
-}
+    /// Give back machines that have been in use for `max_use` seconds, returning them and who was
+    /// using them
+    ///
+    /// Machines in use are restored as they were saved when we start, with their occupants, so this
+    /// also ends those that were forgotten about while we were down. `run_schedule` takes care of
+    /// it while we run.
+    pub fn expire_uses(&mut self) -> Vec<(Uuid, String)> {
+        let max_use = match self.max_use {
+            Some(max_use) => max_use,
+            None => return Vec::new(),
+        };
+        let now = self.now();
+        let expired: Vec<(Uuid, String)> = self.mdb.iter()
+            .filter(|(_, m)| m.status == Status::Occupied
+                && m.since.map(|s| now.saturating_sub(s) >= max_use).unwrap_or(false))
+            .map(|(u, m)| (u.clone(), m.occupant.clone().unwrap_or_default()))
+            .collect();
 
-#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
-pub struct Machine {
-    pub name: String,
-    pub location: String,
-    pub status: Status,
-    pub perm: String,
-}
+        let log = self.log.clone();
+        for (uuid, occupant) in expired.iter() {
+            info!(log, "Machine {} in use for {}s, giving it back", uuid, max_use;
+                "occupant" => occupant);
+            // Can't fail for machines that exist
+            let _ = self.give_back(&log, uuid);
+        }
+        expired
+    }
 
-impl Machine {
-    pub fn new(name: String, location: String, perm: String) -> Machine {
-        Machine {
-            name: name,
-            location: location,
-            status: Status::Free,
-            perm: perm,
+    /// When `m` is given back by us if it's still in use then, in UNIX seconds
+    ///
+    /// That's after `max_use` or at the end of its opening hours if it's given back then,
+    /// whichever comes first.
+    fn giveback_deadline(&self, m: &Machine) -> Option<u64> {
+        let since = m.since.filter(|_| m.status == Status::Occupied)?;
+        let expiry = self.max_use.map(|max_use| since.saturating_add(max_use));
+        let closing = if m.giveback_at_close {
+            hours::closes_at(&m.allowed_hours, self.timezone, since)
+        } else {
+            None
+        };
+        match (expiry, closing) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
         }
     }
 
-    pub fn set_blocked(&mut self, blocked: bool) {
-        if blocked {
-            self.status = Status::Blocked;
+    pub fn give_back(&mut self, log: &Logger, uuid: &Uuid) -> std::result::Result<(), capnp::Error> {
+        if let Some(m) = self.mdb.get(uuid) {
+            trace!(log, "Machine {} given back", uuid);
+            let occupant = m.occupant.clone();
+            self.transition(Transition {
+                uuid: uuid.clone(),
+                status: Status::Free,
+                since: None,
+                occupant: None,
+                grant: None,
+                note: None,
+            });
+            self.vacate(uuid, occupant);
+            self.notify(uuid);
+            self.advance_queue(uuid);
         } else {
-            self.status = Status::Free;
+            warn!(log, "A giveback was issued for a unknown machine {}", uuid);
         }
+
+        Ok(())
     }
-}
 
-pub type MachineDB = HashMap<Uuid, Machine>;
+    /// Add `user` to the end of the queue for a machine, returning their position
+    ///
+    /// Users that are already waiting keep their place.
+    pub fn enqueue(&mut self, log: &Logger, uuid: &Uuid, user: &str)
+        -> std::result::Result<u32, capnp::Error>
+    {
+        if self.mdb.get(uuid).is_none() {
+            return Err(error::no_such_machine(uuid));
+        }
 
-pub async fn init(log: Logger, config: &Config) -> Result<MachinesProvider> {
-    let mdb = if config.machinedb.is_file() {
-        let mut fp = File::open(&config.machinedb)?;
-        let mut content = String::new();
-        fp.read_to_string(&mut content)?;
-        let map = toml::from_str(&content)?;
-        map
-    } else {
-        HashMap::new()
-    };
+        let queue = self.queues.entry(uuid.clone()).or_insert_with(VecDeque::new);
+        if let Some(pos) = queue.iter().position(|u| u == user) {
+            return Ok(pos as u32 + 1);
+        }
 
-    Ok(MachinesProvider::new(log, mdb))
-}
+        queue.push_back(user.to_string());
+        trace!(log, "{} is waiting for machine {} at position {}", user, uuid, queue.len());
+        Ok(queue.len() as u32)
+    }
 
-pub fn save(config: &Config, mdb: &MachineDB) -> Result<()> {
-    let mut fp = File::create(&config.machinedb)?;
-    let toml = toml::to_string(mdb)?;
-    fp.write_all(&toml.as_bytes())?;
-    Ok(())
+    /// Remove `user` from the queue of a machine, dropping any hold they have on it
+    pub fn leave_queue(&mut self, uuid: &Uuid, user: &str) {
+        if let Some(queue) = self.queues.get_mut(uuid) {
+            queue.retain(|u| u != user);
+            if queue.is_empty() {
+                self.queues.remove(uuid);
+            }
+        }
+
+        if self.holds.get(uuid).map(|h| h.user == user).unwrap_or(false) {
+            self.holds.remove(uuid);
+        }
+    }
+
+    /// Tell `user` once the machine is free again, returning until when they wait at most
+    ///
+    /// Returns `None` if the machine is free already, so there's nothing to wait for.
+    pub fn watch(&mut self, uuid: &Uuid, user: &str)
+        -> std::result::Result<Option<u64>, capnp::Error>
+    {
+        let status = self.mdb.get(uuid).map(|m| m.status)
+            .ok_or_else(|| error::no_such_machine(uuid))?;
+        if !self.watches.enabled() {
+            return Err(error::unimplemented("Waiting for machines to become free is not enabled"));
+        }
+        if status == Status::Free {
+            return Ok(None);
+        }
+
+        let now = self.now();
+        let expires = now.saturating_add(self.watch_expiry);
+        let watch = Watch { user: user.to_string(), machine: uuid.clone(), expires };
+        if let Err(e) = self.watches.add(watch, now) {
+            error!(self.log, "Failed to save who waits for machines: {}", e);
+            return Err(error::internal());
+        }
+        Ok(Some(expires))
+    }
+
+    /// Forget who waits for a machine, returning them so they can be told it's free
+    pub fn take_watches(&mut self, uuid: &Uuid) -> Result<Vec<Watch>> {
+        let now = self.now();
+        self.watches.take(uuid, now)
+    }
+
+    /// Position of `user` in the queue for a machine starting at 1, 0 if they're not waiting
+    pub fn queue_position(&self, uuid: &Uuid, user: &str) -> u32 {
+        self.queues.get(uuid)
+            .and_then(|q| q.iter().position(|u| u == user))
+            .map(|p| p as u32 + 1)
+            .unwrap_or(0)
+    }
+
+    /// Who a machine is reserved for and until when in UNIX seconds, if it's reserved
+    pub fn hold(&self, uuid: &Uuid) -> Option<(String, u64)> {
+        let now = self.now();
+        self.holds.get(uuid).filter(|h| h.until > now).map(|h| (h.user.clone(), h.until))
+    }
+
+    /// Users waiting in all queues and holds, together
+    pub fn queued(&self) -> usize {
+        self.queues.values().map(|q| q.len()).sum::<usize>() + self.holds.len()
+    }
+
+    /// Drop holds that expired and queues nobody has to wait in anymore, returning how many
+    /// entries that were
+    ///
+    /// Machines that were removed can't be waited for. For free ones that aren't held anymore
+    /// waiting is over, whoever waits may use them right away.
+    pub fn prune_queues(&mut self) -> usize {
+        let now = self.now();
+        let before = self.queued();
+        self.holds.retain(|_, h| h.until > now);
+        let (mdb, holds) = (&self.mdb, &self.holds);
+        self.queues.retain(|uuid, q| {
+            let waiting = match mdb.get(uuid) {
+                Some(m) => m.status != Status::Free || holds.contains_key(uuid),
+                None => false,
+            };
+            waiting && !q.is_empty()
+        });
+        before - self.queued()
+    }
+
+    /// Reserve a freshly freed machine for the head of its queue
+    ///
+    /// They are told by `watch::deliver`, which looks at the hold once the machine is free.
+    fn advance_queue(&mut self, uuid: &Uuid) {
+        if self.queue_hold == 0 {
+            return;
+        }
+
+        let next = self.queues.get_mut(uuid).and_then(|q| q.pop_front());
+        if let Some(user) = next {
+            if self.queues.get(uuid).map(|q| q.is_empty()).unwrap_or(false) {
+                self.queues.remove(uuid);
+            }
+
+            info!(self.log, "Machine {} is ready and reserved for {} for {}s", uuid, user,
+                self.queue_hold);
+
+            let until = self.now() + self.queue_hold;
+            self.holds.insert(uuid.clone(), Hold { user, until });
+        }
+    }
+
+    pub fn get_perm_req(&self, uuid: &Uuid) -> Option<String> {
+        self.get(uuid).map(|m| m.perm().to_string())
+    }
+
+    /// Whether a machine can only be used at some times, so using it may need `manage`
+    pub fn has_hours(&self, uuid: &Uuid) -> bool {
+        self.mdb.get(uuid).map_or(false, |m| !m.allowed_hours.is_empty())
+    }
+
+    /// A copy of the machine's current state
+    pub fn get(&self, uuid: &Uuid) -> Option<Machine> {
+        self.mdb.get(uuid).map(|m| self.with_perm(uuid, m))
+    }
+
+    /// A copy of the current state of all machines
+    pub fn list(&self) -> Vec<(Uuid, Machine)> {
+        self.mdb.iter().map(|(u, m)| (u.clone(), self.with_perm(u, m))).collect()
+    }
+
+    /// The perm of every machine, derived ones included
+    pub fn perms(&self) -> Vec<(Uuid, String)> {
+        self.mdb.iter().map(|(u, m)| (u.clone(), self.with_perm(u, m).perm().to_string())).collect()
+    }
+
+    /// A copy of `m` with its perm derived if it has none, the name of its location and when it's
+    /// given back
+    fn with_perm(&self, uuid: &Uuid, m: &Machine) -> Machine {
+        let mut m = m.clone();
+        m.derive_perm(uuid, self.perm_template.as_deref());
+        m.location_name = self.mdb.locations().get(&m.location).map(|l| l.name.clone());
+        m.giveback_deadline = self.giveback_deadline(&m);
+        m
+    }
+
+    /// All locations, by id
+    pub fn locations(&self) -> Locations {
+        self.mdb.locations().clone()
+    }
+
+    /// The permission object needed to see anything at the location of the machine, if any
+    pub fn location_perm(&self, uuid: &Uuid) -> Option<String> {
+        let m = self.mdb.get(uuid)?;
+        self.mdb.locations().get(&m.location).and_then(|l| l.perm.clone())
+    }
+
+    pub fn set_blocked(&mut self, log: &Logger, uuid: &Uuid, blocked: bool)
+        -> std::result::Result<(), capnp::Error>
+    {
+        self.block(log, uuid, blocked, None)
+    }
+
+    /// `set_blocked`, saying why
+    fn block(&mut self, log: &Logger, uuid: &Uuid, blocked: bool, reason: Option<String>)
+        -> std::result::Result<(), capnp::Error>
+    {
+        // If the value can not be found map doesn't run and ok_or changes it into a Err with the
+        // given error value
+        let occupant = self.mdb.get_mut(uuid).map(|m| {
+            let occupant = m.occupant.take();
+            m.set_blocked(blocked);
+            m.block_reason = reason;
+            // Unblocking by hand ends a scheduled block early
+            if !blocked {
+                m.schedule.retain(|b| !b.applied);
+            }
+            occupant
+        }).ok_or_else(|| error::no_such_machine(uuid))?;
+        self.vacate(uuid, occupant);
+        info!(log, "Machine {} {}", uuid, if blocked { "blocked" } else { "unblocked" });
+        self.blocked_changed(uuid);
+        self.persist();
+        Ok(())
+    }
+
+    /// Block or unblock many machines at once, e.g. when closing the space for a holiday
+    ///
+    /// Unlike `set_blocked` machines in use are left alone, nobody should be cut off in the middle
+    /// of a job by a change to the whole space. All changes are saved together, so the database
+    /// either has all or none of them.
+    pub fn set_blocked_bulk(&mut self, log: &Logger, uuids: &[Uuid], blocked: bool,
+        reason: Option<&str>) -> Vec<(Uuid, BulkResult)>
+    {
+        let target = if blocked { Status::Blocked } else { Status::Free };
+        let mut results = Vec::with_capacity(uuids.len());
+        let mut changed = Vec::new();
+        for uuid in uuids {
+            let r = match self.mdb.get_mut(uuid) {
+                None => BulkResult::NoSuchMachine,
+                Some(m) if m.status == Status::Occupied => BulkResult::Occupied,
+                Some(m) if m.status == target => {
+                    // Blocking again still updates the reason
+                    if blocked {
+                        m.block_reason = reason.map(str::to_string);
+                    }
+                    BulkResult::Unchanged
+                },
+                Some(m) => {
+                    m.set_blocked(blocked);
+                    if blocked {
+                        m.block_reason = reason.map(str::to_string);
+                    } else {
+                        m.schedule.retain(|b| !b.applied);
+                    }
+                    changed.push(uuid.clone());
+                    BulkResult::Changed
+                },
+            };
+            results.push((uuid.clone(), r));
+        }
+
+        info!(log, "{} {} of {} machines", if blocked { "Blocked" } else { "Unblocked" },
+            changed.len(), uuids.len());
+        self.persist();
+        for uuid in changed.iter() {
+            self.blocked_changed(uuid);
+        }
+        results
+    }
+
+    /// Forget about everything that doesn't make sense after a machine was blocked or unblocked
+    fn blocked_changed(&mut self, uuid: &Uuid) {
+        // A blocked machine can't be reserved for anybody
+        self.holds.remove(uuid);
+        self.notify(uuid);
+    }
+
+    /// Plan to block a machine from `block.start` until `block.end`
+    ///
+    /// Windows overlapping one that is planned already are rejected instead of merged, somebody
+    /// would have to look at the reasons of both anyway.
+    pub fn schedule_block(&mut self, log: &Logger, uuid: &Uuid, block: ScheduledBlock)
+        -> std::result::Result<(), capnp::Error>
+    {
+        if block.end <= block.start {
+            return Err(error::invalid_argument("The block has to end after it starts"));
+        }
+        if block.end <= self.now() {
+            return Err(error::invalid_argument("The block would be over already"));
+        }
+        let m = self.mdb.get(uuid).ok_or_else(|| error::no_such_machine(uuid))?;
+        if let Some(other) = m.schedule.iter().find(|b| b.overlaps(&block)) {
+            return Err(error::conflict(format_args!(
+                "Overlaps the block from {} to {}", other.start, other.end)));
+        }
+
+        info!(log, "Machine {} scheduled to be blocked", uuid;
+            "start" => block.start, "end" => block.end, "reason" => &block.reason);
+        if let Some(m) = self.mdb.get_mut(uuid) {
+            let i = m.schedule.iter().position(|b| b.start > block.start)
+                .unwrap_or(m.schedule.len());
+            m.schedule.insert(i, block);
+        }
+        self.persist();
+        Ok(())
+    }
+
+    /// Cancel the block of a machine starting at `start`, unblocking it if the block is in effect
+    ///
+    /// Returns whether the machine was unblocked.
+    pub fn cancel_scheduled_block(&mut self, log: &Logger, uuid: &Uuid, start: u64)
+        -> std::result::Result<bool, capnp::Error>
+    {
+        let m = self.mdb.get_mut(uuid).ok_or_else(|| error::no_such_machine(uuid))?;
+        let i = m.schedule.iter().position(|b| b.start == start)
+            .ok_or_else(error::no_such_block)?;
+        let block = m.schedule.remove(i);
+        let unblock = block.applied && m.status == Status::Blocked;
+        if unblock {
+            m.set_blocked(false);
+        }
+
+        info!(log, "Scheduled block of machine {} cancelled", uuid;
+            "start" => block.start, "end" => block.end, "unblocked" => unblock);
+        if unblock {
+            self.blocked_changed(uuid);
+        }
+        self.persist();
+        Ok(unblock)
+    }
+
+    /// Apply scheduled blocks that started and lift those that ended
+    ///
+    /// Blocks that ended while we weren't running are lifted now, so this is also how they are
+    /// caught up on after a restart.
+    pub fn apply_schedule(&mut self) -> Vec<ScheduleChange> {
+        let now = self.now();
+        let due: Vec<Uuid> = self.mdb.iter()
+            .filter(|(_, m)| m.schedule.iter().any(|b| b.due(now)))
+            .map(|(u, _)| u.clone())
+            .collect();
+        if due.is_empty() {
+            return Vec::new();
+        }
+
+        let mut changes = Vec::new();
+        let mut vacated = Vec::new();
+        for uuid in due {
+            let m = match self.mdb.get_mut(&uuid) {
+                Some(m) => m,
+                None => continue,
+            };
+            let was_blocked = m.status == Status::Blocked;
+
+            let (ended, planned) = std::mem::take(&mut m.schedule).into_iter()
+                .partition::<Vec<_>, _>(|b| b.end <= now);
+            m.schedule = planned;
+            let lifted = ended.into_iter().filter(|b| b.applied).last();
+            let mut blocked = was_blocked && lifted.is_none();
+
+            // Blocks never overlap, so at most one can have started
+            let mut applied = None;
+            if let Some(i) = m.schedule.iter().position(|b| !b.applied && b.start <= now) {
+                if blocked {
+                    // Whoever blocked it is going to unblock it too, we'd only get in their way
+                    let b = m.schedule.remove(i);
+                    info!(self.log, "Machine {} is blocked already, dropping scheduled block", uuid;
+                        "start" => b.start, "end" => b.end);
+                } else {
+                    m.schedule[i].applied = true;
+                    applied = Some(m.schedule[i].by.clone());
+                    blocked = true;
+                }
+            }
+
+            if blocked == was_blocked {
+                continue;
+            }
+            if let Some(o) = m.occupant.take() {
+                vacated.push((uuid.clone(), o));
+            }
+            m.set_blocked(blocked);
+            let by = match (applied, lifted) {
+                (Some(by), _) => by,
+                (None, Some(b)) => b.by,
+                (None, None) => continue,
+            };
+            info!(self.log, "Machine {} {} as scheduled", uuid,
+                if blocked { "blocked" } else { "unblocked" }; "by" => &by);
+            changes.push(ScheduleChange { uuid, by, blocked });
+        }
+
+        for (uuid, occupant) in vacated {
+            self.vacate(&uuid, Some(occupant));
+        }
+        for change in changes.iter() {
+            self.blocked_changed(&change.uuid);
+        }
+        self.persist();
+        changes
+    }
+
+    /// When `apply_schedule` has to run next, in UNIX seconds
+    pub fn next_scheduled(&self) -> Option<u64> {
+        self.mdb.iter()
+            .flat_map(|(_, m)| m.schedule.iter())
+            .map(|b| if b.applied { b.end } else { b.start })
+            .min()
+    }
+
+    /// Give back machines that are to be given back when their opening hours end and did
+    ///
+    /// Only uses that started within opening hours end, those allowed to use a machine anytime
+    /// did so on purpose. Returns the machines given back and who had them.
+    pub fn close_hours(&mut self) -> Vec<(Uuid, String)> {
+        let (now, timezone) = (self.now(), self.timezone);
+        let closed: Vec<(Uuid, String)> = self.mdb.iter()
+            .filter(|(_, m)| m.giveback_at_close && m.status == Status::Occupied)
+            .filter(|(_, m)| match m.since {
+                Some(since) => hours::closes_at(&m.allowed_hours, timezone, since)
+                    .map_or(false, |close| close <= now),
+                None => false,
+            })
+            .map(|(u, m)| (u.clone(), m.occupant.clone().unwrap_or_default()))
+            .collect();
+
+        let log = self.log.clone();
+        for (uuid, occupant) in closed.iter() {
+            info!(log, "Opening hours of machine {} are over, giving it back", uuid;
+                "occupant" => occupant);
+            // Can't fail for machines that exist
+            let _ = self.give_back(&log, uuid);
+        }
+        closed
+    }
+
+    /// When `close_hours` or `expire_uses` have to run next, in UNIX seconds
+    pub fn next_giveback(&self) -> Option<u64> {
+        self.mdb.iter().filter_map(|(_, m)| self.giveback_deadline(m)).min()
+    }
+}
+
+/// Apply and lift scheduled blocks as their windows start and end, for as long as we run
+///
+/// Blocks are recorded in the audit trail in the name of whoever scheduled them. Machines are
+/// given back at the end of their opening hours and after `max_use` here as well.
+pub async fn run_schedule(log: Logger, mdb: Arc<RwLock<MachinesProvider>>, audit: Audit,
+    background: lock::Background)
+{
+    loop {
+        let (changes, closed, now, next) = {
+            let mut mdb = background.write(&mdb, "look at the schedule").await;
+            let changes = mdb.apply_schedule();
+            let mut closed = mdb.close_hours();
+            closed.extend(mdb.expire_uses());
+            let next = match (mdb.next_scheduled(), mdb.next_giveback()) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            (changes, closed, mdb.now(), next)
+        };
+        for c in changes.iter() {
+            audit.record(AuditEvent::MachineBlocked {
+                authzid: &c.by, machine: &c.uuid, blocked: c.blocked,
+            });
+        }
+        for (uuid, occupant) in closed.iter() {
+            audit.record(AuditEvent::MachineReleased { authzid: occupant, machine: uuid });
+        }
+
+        // Blocks scheduled in the meantime are picked up by waking up regularly
+        let wait = next.map(|n| n.saturating_sub(now)).unwrap_or(SCHEDULE_INTERVAL)
+            .min(SCHEDULE_INTERVAL).max(1);
+        trace!(log, "Next look at the schedule in {}s", wait);
+        async_std::task::sleep(Duration::from_secs(wait)).await;
+    }
+}
+
+/// Longest time in seconds between two looks at the schedule
+const SCHEDULE_INTERVAL: u64 = 60;
+
+/// Save changes that wait for it every `interval`, for as long as we run
+pub async fn write_pending(mdb: Arc<RwLock<MachinesProvider>>, interval: Duration,
+    background: lock::Background)
+{
+    loop {
+        async_std::task::sleep(interval).await;
+        background.write(&mdb, "save pending changes").await.flush_pending();
+    }
+}
+
+/// Subscribers to machine state changes, as a registry to keep small
+pub struct Subscribers(pub Arc<RwLock<MachinesProvider>>, pub lock::Background);
+
+impl Registry for Subscribers {
+    fn len(&self) -> LocalBoxFuture<'_, usize> {
+        async move { self.1.read(&self.0, "count subscribers").await.subscribers.len() }
+            .boxed_local()
+    }
+
+    fn prune(&self) -> LocalBoxFuture<'_, usize> {
+        async move { self.1.write(&self.0, "prune subscribers").await.prune_subscribers() }
+            .boxed_local()
+    }
+}
+
+/// Queues of all machines and their holds, as a registry to keep small
+pub struct Queues(pub Arc<RwLock<MachinesProvider>>, pub lock::Background);
+
+impl Registry for Queues {
+    fn len(&self) -> LocalBoxFuture<'_, usize> {
+        async move { self.1.read(&self.0, "count queued users").await.queued() }.boxed_local()
+    }
+
+    fn prune(&self) -> LocalBoxFuture<'_, usize> {
+        async move { self.1.write(&self.0, "prune queues").await.prune_queues() }.boxed_local()
+    }
+}
+
+#[derive(Clone)]
+pub struct Machines {
+    log: Logger,
+    inner: Arc<RwLock<MachinesProvider>>,
+    perm: Rc<Permissions>,
+    grants: Grants,
+    /// Why the last `use` through this connection was denied and of which machine
+    last_denial: Rc<RefCell<Option<(Uuid, DenyReason)>>>,
+    /// Where large audit trails are read, so that doesn't hold up everything else
+    spawner: Rc<dyn Spawn>,
+    status: Arc<ServerStatus>,
+    /// How long calls wait for `inner`, see `lock`
+    lock_timeout: Duration,
+}
+impl Machines {
+    pub fn new(log: Logger, inner: Arc<RwLock<MachinesProvider>>, perm: Rc<Permissions>,
+        spawner: Rc<dyn Spawn>, status: Arc<ServerStatus>, lock_timeout: Duration) -> Self
+    {
+        Self {
+            log, inner, perm, spawner, status, lock_timeout,
+            grants: Rc::new(RefCell::new(HashSet::new())),
+            last_denial: Rc::new(RefCell::new(None)),
+        }
+    }
+
+    /// The machine state shared by all connections
+    pub fn provider(&self) -> Arc<RwLock<MachinesProvider>> {
+        self.inner.clone()
+    }
+
+    /// How long calls wait for the lock on `provider`, see `lock`
+    pub fn lock_timeout(&self) -> Duration {
+        self.lock_timeout
+    }
+
+    /// Machines currently in use through this connection
+    pub fn grants(&self) -> Grants {
+        self.grants.clone()
+    }
+}
+
+/// How many uses `getMyHistory` returns if the client doesn't say
+const DEFAULT_HISTORY: usize = 20;
+/// Most uses `getMyHistory` returns
+const MAX_HISTORY: usize = 1000;
+/// Audit trails larger than this, in bytes, are read on the thread pool
+const LARGE_AUDIT_TRAIL: u64 = 1024 * 1024;
+/// Longest note a use may carry, in characters
+pub const MAX_NOTE: usize = 200;
+
+/// Set of machines a single connection currently has in use
+pub type Grants = Rc<RefCell<HashSet<Uuid>>>;
+
+/// Refuse a change while the server is in read-only mode
+///
+/// This is checked as soon as a call arrives instead of once it has the lock, so calls that came
+/// in before the mode was entered still go through.
+pub fn writable(status: &ServerStatus) -> std::result::Result<(), Error> {
+    if status.read_only() {
+        Err(error::read_only())
+    } else {
+        Ok(())
+    }
+}
+
+/// Clean up the note given with a use
+///
+/// Control characters are dropped so a note can't mess up logs or terminals, and one that's empty
+/// afterwards is no note at all. Notes longer than `MAX_NOTE` are refused rather than cut short.
+pub fn sanitize_note(note: &str) -> std::result::Result<Option<String>, Error> {
+    let note: String = note.chars().filter(|c| !c.is_control()).collect();
+    let note = note.trim();
+    if note.chars().count() > MAX_NOTE {
+        return Err(error::invalid_argument(
+            format!("Notes can be at most {} characters long", MAX_NOTE)));
+    }
+    Ok(if note.is_empty() { None } else { Some(note.to_string()) })
+}
+
+/// Remember why a connection may not use a machine, returning the error to send it
+fn deny(last: &RefCell<Option<(Uuid, DenyReason)>>, uuid: Uuid, reason: DenyReason) -> Error {
+    let e = reason.to_error();
+    *last.borrow_mut() = Some((uuid, reason));
+    e
+}
+impl api::machines::Server for Machines {
+    fn manage(&mut self,
+        params: api::machines::ManageParams,
+        mut results: api::machines::ManageResults)
+        -> Promise<(), Error>
+    {
+        let params = pry!(params.get());
+        let uuid_s = pry!(params.get_uuid());
+        let uuid = uuid_from_api(uuid_s);
+
+        // We need to copy the Arc here because we don't have access to it from within the closure
+        // witout moving it out of self.
+        let i = self.inner.clone();
+        let timeout = self.lock_timeout;
+        let p = self.perm.clone();
+        let log = self.log.clone();
+        let status = self.status.clone();
+
+        let f = async move {
+            // We only need a read lock at first there's no reason to aquire a write lock.
+            let i_lock = lock::read(&i, timeout).await?;
+
+            let ps = i_lock.get_perm_req(&uuid).ok_or_else(|| error::no_such_machine(&uuid))?;
+            // drop the lock as soon as possible to prevent locking as much as possible
+            drop(i_lock);
+            p.require(&ps, "manage").await?;
+
+            let user = p.authzid().await.unwrap_or_default();
+            let audit = p.audit().clone();
+
+            // We're here and have not returned an error yet - that means we're free to
+            // send a successful manage back.
+            let mut b = results.get();
+
+            // Magic incantation to get a capability to send
+            // Also since we move i in here we at this point *must* have dropped
+            // all locks we may still have on it.
+            b.set_manage(api::machines::manage::ToClient::new(
+                    MachineManager::new(log, audit, user, uuid, i, status, timeout))
+                .into_client::<Server>());
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+
+    fn use_(&mut self,
+        params: api::machines::UseParams,
+        mut results: api::machines::UseResults)
+        -> Promise<(), capnp::Error>
+    {
+        let params = pry!(params.get());
+        let uuid_s = pry!(params.get_uuid());
+        let uuid = uuid_from_api(uuid_s);
+        pry!(writable(&self.status));
+        let note = pry!(sanitize_note(pry!(params.get_note())));
+
+        // We need to copy the Arc here because we don't have access to it from within the closure
+        // witout moving it out of self.
+        let i = self.inner.clone();
+        let timeout = self.lock_timeout;
+        let p = self.perm.clone();
+        let grants = self.grants.clone();
+        let log = self.log.clone();
+        let status = self.status.clone();
+
+        let last_denial = self.last_denial.clone();
+
+        let f = async move {
+            // We only need a read lock at first there's no reason to aquire a write lock.
+            let i_lock = lock::read(&i, timeout).await?;
+
+            let ps = match i_lock.get_perm_req(&uuid) {
+                Some(ps) => ps,
+                None => return Err(deny(&last_denial, uuid, DenyReason::NoSuchMachine)),
+            };
+            // drop the lock as soon as possible to prevent locking as much as possible
+            drop(i_lock);
+            match p.enforce(&ps, "write").await {
+                Ok(true) => {},
+                Ok(false) => {
+                    let reason = if p.authzid().await.is_none() {
+                        DenyReason::Unauthenticated
+                    } else {
+                        DenyReason::Unauthorized { object: ps, action: "write".to_string() }
+                    };
+                    return Err(deny(&last_denial, uuid, reason));
+                },
+                Err(e) => return Err(access::check_failed(&log, &ps, "write", e)),
+            }
+
+            // Permissions can only be granted to authenticated connections
+            let user = p.authzid().await.unwrap_or_default();
+            // Only asked for when it matters, it's another check on every use otherwise
+            // The lock has to go before the check, a temporary would be kept until it's done
+            let hours = lock::read(&i, timeout).await?.has_hours(&uuid);
+            let anytime = hours && p.may(&ps, "manage").await;
+            // If use_() returns an error that is our error. If it doesn't that means we can use
+            // the machine
+            let r = lock::write(&i, timeout).await?.use_(&log, &uuid, &user, anytime, note.clone());
+            let grant = match r {
+                Ok(grant) => grant,
+                Err(mut reason) => {
+                    // Who is using a machine is only for those managing it to know
+                    if !p.may(&ps, "manage").await {
+                        reason.hide_occupant();
+                    }
+                    return Err(deny(&last_denial, uuid, reason));
+                },
+            };
+            p.audit().record(AuditEvent::MachineUse {
+                authzid: &user,
+                machine: &uuid,
+                note: note.as_deref(),
+            });
+            grants.borrow_mut().insert(uuid.clone());
+
+            // We're here and have not returned an error yet - that means we're free to
+            // send a successful use back.
+            let mut b = results.get();
+
+            // Magic incantation to get a capability to send
+            // Also since we move i in here we at this point *must* have dropped
+            // all locks we may still have on it.
+            b.set_giveback(api::machines::give_back::ToClient::new(
+                    GiveBack::new(log, p.audit().clone(), user, i, uuid, grant, grants, status,
+                        timeout))
+                .into_client::<Server>());
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+
+    fn get_info(&mut self,
+        params: api::machines::GetInfoParams,
+        mut results: api::machines::GetInfoResults)
+        -> Promise<(), capnp::Error>
+    {
+        let params = pry!(params.get());
+        let uuid_s = pry!(params.get_uuid());
+        let uuid = uuid_from_api(uuid_s);
+
+        let i = self.inner.clone();
+        let timeout = self.lock_timeout;
+        let p = self.perm.clone();
+
+        let f = async move {
+            let user = p.authzid().await.unwrap_or_default();
+            let i_lock = lock::read(&i, timeout).await?;
+            let now = i_lock.now();
+            let pos = i_lock.queue_position(&uuid, &user);
+
+            let m = i_lock.get(&uuid).ok_or_else(|| error::no_such_machine(&uuid))?;
+            let location_perm = i_lock.location_perm(&uuid);
+            drop(i_lock);
+            if let Some(perm) = location_perm {
+                p.require(&perm, "read").await?;
+            }
+            p.require(m.perm(), "read").await?;
+
+            let mut b = results.get().init_info();
+            fill_info(b.reborrow(), &uuid, &m, now, pos);
+            // What somebody is working on is for them and those managing the machine to know
+            if let Some(ref note) = m.note {
+                if m.occupant.as_deref() == Some(user.as_str()) || p.may(m.perm(), "manage").await {
+                    b.set_note(note);
+                }
+            }
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+
+    fn list(&mut self,
+        params: api::machines::ListParams,
+        mut results: api::machines::ListResults)
+        -> Promise<(), capnp::Error>
+    {
+        let location = pry!(pry!(params.get()).get_location()).to_string();
+        let i = self.inner.clone();
+        let timeout = self.lock_timeout;
+        let p = self.perm.clone();
+        let log = self.log.clone();
+
+        let f = async move {
+            let user = p.authzid().await.unwrap_or_default();
+
+            // Take a snapshot so we don't hold the lock while checking permissions
+            let (machines, locations, now) = {
+                let i_lock = lock::read(&i, timeout).await?;
+                let machines: Vec<_> = i_lock.list().into_iter()
+                    .filter(|(_, m)| location.is_empty() || m.location == location)
+                    .map(|(uuid, m)| {
+                        let pos = i_lock.queue_position(&uuid, &user);
+                        (uuid, m, pos)
+                    })
+                    .collect();
+                (machines, i_lock.locations(), i_lock.now())
+            };
+
+            // Locations with a perm of their own are checked first, once each, so a whole room
+            // the caller may not see costs a single check instead of one for every machine in it
+            let mut hidden = HashSet::new();
+            for (id, l) in locations.iter() {
+                let perm = match l.perm {
+                    Some(ref perm) if machines.iter().any(|(_, m, _)| &m.location == id) => perm,
+                    _ => continue,
+                };
+                let allowed = match p.enforce(perm, "read").await {
+                    Ok(allowed) => allowed,
+                    Err(e) => {
+                        error!(log, "Failed to check permission read on {}: {}", perm, e);
+                        false
+                    },
+                };
+                if !allowed {
+                    hidden.insert(id.clone());
+                }
+            }
+
+            let mut visible = Vec::new();
+            for (uuid, m, pos) in machines {
+                if hidden.contains(&m.location) {
+                    continue;
+                }
+                match p.enforce(m.perm(), "read").await {
+                    Ok(true) => visible.push((uuid, m, pos)),
+                    Ok(false) => {},
+                    // Leaving the machine out is all we can do, but somebody should know why
+                    Err(e) => error!(log, "Failed to check permission read on {}: {}", m.perm(), e),
+                }
+            }
+
+            let mut b = results.get().init_machines(visible.len() as u32);
+            for (idx, (uuid, m, pos)) in visible.iter().enumerate() {
+                fill_info(b.reborrow().get(idx as u32), uuid, m, now, *pos);
+            }
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+
+    fn enqueue(&mut self,
+        params: api::machines::EnqueueParams,
+        mut results: api::machines::EnqueueResults)
+        -> Promise<(), capnp::Error>
+    {
+        let params = pry!(params.get());
+        let uuid_s = pry!(params.get_uuid());
+        let uuid = uuid_from_api(uuid_s);
+        pry!(writable(&self.status));
+
+        let i = self.inner.clone();
+        let timeout = self.lock_timeout;
+        let p = self.perm.clone();
+        let log = self.log.clone();
+
+        let f = async move {
+            let i_lock = lock::read(&i, timeout).await?;
+
+            let ps = i_lock.get_perm_req(&uuid).ok_or_else(|| error::no_such_machine(&uuid))?;
+            drop(i_lock);
+            // Waiting for a machine only makes sense if one is allowed to use it afterwards
+            p.require(&ps, "write").await?;
+
+            let user = p.authzid().await.unwrap_or_default();
+            let pos = lock::write(&i, timeout).await?.enqueue(&log, &uuid, &user)?;
+            results.get().set_position(pos);
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+
+    fn leave_queue(&mut self,
+        params: api::machines::LeaveQueueParams,
+        _results: api::machines::LeaveQueueResults)
+        -> Promise<(), capnp::Error>
+    {
+        let params = pry!(params.get());
+        let uuid_s = pry!(params.get_uuid());
+        let uuid = uuid_from_api(uuid_s);
+
+        let i = self.inner.clone();
+        let timeout = self.lock_timeout;
+        let p = self.perm.clone();
+
+        let f = async move {
+            if let Some(user) = p.authzid().await {
+                lock::write(&i, timeout).await?.leave_queue(&uuid, &user);
+            }
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+
+    fn reclaim(&mut self,
+        params: api::machines::ReclaimParams,
+        mut results: api::machines::ReclaimResults)
+        -> Promise<(), capnp::Error>
+    {
+        let params = pry!(params.get());
+        let uuid = uuid_from_api(pry!(params.get_uuid()));
+
+        let i = self.inner.clone();
+        let timeout = self.lock_timeout;
+        let p = self.perm.clone();
+        let grants = self.grants.clone();
+        let log = self.log.clone();
+        let status = self.status.clone();
+
+        let f = async move {
+            let user = p.authzid().await.ok_or_else(error::unauthenticated)?;
+            let grant = lock::read(&i, timeout).await?.reclaim(&uuid, &user)?;
+            info!(log, "Reclaimed machine {}", uuid);
+            grants.borrow_mut().insert(uuid.clone());
+
+            results.get().set_giveback(api::machines::give_back::ToClient::new(
+                    GiveBack::new(log, p.audit().clone(), user, i, uuid, grant, grants, status,
+                        timeout))
+                .into_client::<Server>());
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+
+    fn last_denial(&mut self,
+        _params: api::machines::LastDenialParams,
+        mut results: api::machines::LastDenialResults)
+        -> Promise<(), capnp::Error>
+    {
+        let mut b = results.get();
+        match *self.last_denial.borrow() {
+            Some((uuid, ref reason)) => {
+                api_from_uuid(uuid, b.reborrow().init_uuid());
+                reason.fill(b.init_reason());
+            },
+            None => b.init_reason().set_none(()),
+        }
+        Promise::ok(())
+    }
+
+    fn set_blocked_bulk(&mut self,
+        params: api::machines::SetBlockedBulkParams,
+        mut results: api::machines::SetBlockedBulkResults)
+        -> Promise<(), capnp::Error>
+    {
+        pry!(writable(&self.status));
+        let i = self.inner.clone();
+        let timeout = self.lock_timeout;
+        let p = self.perm.clone();
+        let log = self.log.clone();
+
+        let f = async move {
+            p.require("machines", "admin").await?;
+            let user = p.authzid().await.unwrap_or_default();
+
+            let params = params.get()?;
+            let uuids: Vec<Uuid> = params.get_uuids()?.iter().map(uuid_from_api).collect();
+            let blocked = params.get_blocked();
+            let reason = Some(params.get_reason()?).filter(|r| !r.is_empty());
+
+            // One write lock for all of them so nobody sees the space half closed
+            let changes = lock::write(&i, timeout).await?
+                .set_blocked_bulk(&log, &uuids, blocked, reason);
+
+            let audit = p.audit();
+            let mut b = results.get().init_results(changes.len() as u32);
+            for (idx, (uuid, r)) in changes.iter().enumerate() {
+                if *r == BulkResult::Changed {
+                    audit.record(AuditEvent::MachineBlocked {
+                        authzid: &user,
+                        machine: uuid,
+                        blocked,
+                    });
+                }
+                let mut e = b.reborrow().get(idx as u32);
+                api_from_uuid(uuid.clone(), e.reborrow().init_uuid());
+                match r {
+                    BulkResult::Changed | BulkResult::Unchanged => e.set_ok(()),
+                    BulkResult::NoSuchMachine => e.set_no_such_machine(()),
+                    BulkResult::Occupied => e.set_occupied(()),
+                }
+            }
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+
+    fn list_locations(&mut self,
+        _params: api::machines::ListLocationsParams,
+        mut results: api::machines::ListLocationsResults)
+        -> Promise<(), capnp::Error>
+    {
+        let i = self.inner.clone();
+        let timeout = self.lock_timeout;
+        let p = self.perm.clone();
+        let log = self.log.clone();
+
+        let f = async move {
+            let locations = lock::read(&i, timeout).await?.locations();
+
+            let mut visible: Vec<(String, Location)> = Vec::new();
+            for (id, l) in locations {
+                let perm = match l.perm {
+                    Some(ref perm) => perm,
+                    None => {
+                        visible.push((id, l));
+                        continue;
+                    },
+                };
+                match p.enforce(perm, "read").await {
+                    Ok(true) => visible.push((id, l)),
+                    Ok(false) => {},
+                    Err(e) => error!(log, "Failed to check permission read on {}: {}", perm, e),
+                }
+            }
+
+            let mut b = results.get().init_locations(visible.len() as u32);
+            for (idx, (id, l)) in visible.iter().enumerate() {
+                let mut e = b.reborrow().get(idx as u32);
+                e.set_id(id);
+                e.set_name(&l.name);
+                e.set_perm(l.perm.as_deref().unwrap_or(""));
+            }
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+
+    fn list_by_status(&mut self,
+        params: api::machines::ListByStatusParams,
+        mut results: api::machines::ListByStatusResults)
+        -> Promise<(), capnp::Error>
+    {
+        let i = self.inner.clone();
+        let timeout = self.lock_timeout;
+        let p = self.perm.clone();
+
+        let f = async move {
+            p.require("machines", "admin").await?;
+            let status: Status = params.get()?.get_status()?.into();
+
+            let (machines, now) = {
+                let i_lock = lock::read(&i, timeout).await?;
+                let machines: Vec<_> = i_lock.list().into_iter()
+                    .filter(|(_, m)| m.status == status)
+                    .collect();
+                (machines, i_lock.now())
+            };
+
+            let mut b = results.get().init_machines(machines.len() as u32);
+            for (idx, (uuid, m)) in machines.iter().enumerate() {
+                // Admins aren't in queues on behalf of anybody
+                fill_info(b.reborrow().get(idx as u32), uuid, m, now, 0);
+            }
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+
+    fn get_my_grants(&mut self,
+        _params: api::machines::GetMyGrantsParams,
+        mut results: api::machines::GetMyGrantsResults)
+        -> Promise<(), capnp::Error>
+    {
+        let i = self.inner.clone();
+        let timeout = self.lock_timeout;
+        let p = self.perm.clone();
+
+        let f = async move {
+            let user = p.authzid().await.ok_or_else(error::unauthenticated)?;
+            let (machines, now) = {
+                let i_lock = lock::read(&i, timeout).await?;
+                (i_lock.occupied_by(&user), i_lock.now())
+            };
+
+            let mut b = results.get().init_machines(machines.len() as u32);
+            for (idx, (uuid, m)) in machines.iter().enumerate() {
+                let mut e = b.reborrow().get(idx as u32);
+                // Nobody waits for a machine they're using
+                fill_info(e.reborrow(), uuid, m, now, 0);
+                e.set_note(m.note.as_deref().unwrap_or(""));
+            }
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+
+    fn list_manageable(&mut self,
+        _params: api::machines::ListManageableParams,
+        mut results: api::machines::ListManageableResults)
+        -> Promise<(), capnp::Error>
+    {
+        let i = self.inner.clone();
+        let timeout = self.lock_timeout;
+        let p = self.perm.clone();
+        let log = self.log.clone();
+
+        let f = async move {
+            let user = p.authzid().await.ok_or_else(error::unauthenticated)?;
+            let (machines, now) = {
+                let i_lock = lock::read(&i, timeout).await?;
+                (i_lock.list(), i_lock.now())
+            };
+
+            // Machines often share perms, each is only checked once
+            let mut perms: Vec<String> = machines.iter().map(|(_, m)| m.perm().to_string())
+                .collect();
+            perms.sort();
+            perms.dedup();
+            let allowed = p.check_all(&user, perms.clone(), "manage").await
+                .map_err(|e| access::check_failed(&log, "machines", "manage", e))?;
+            let manageable: Vec<_> = machines.into_iter()
+                .filter(|(_, m)| perms.binary_search_by(|s| s.as_str().cmp(m.perm()))
+                    .map_or(false, |idx| allowed[idx]))
+                .collect();
+
+            let mut b = results.get().init_machines(manageable.len() as u32);
+            for (idx, (uuid, m)) in manageable.iter().enumerate() {
+                let mut e = b.reborrow().get(idx as u32);
+                // Managers aren't in queues on behalf of anybody either
+                let mut info = e.reborrow().init_info();
+                fill_info(info.reborrow(), uuid, m, now, 0);
+                info.set_note(m.note.as_deref().unwrap_or(""));
+                e.set_occupant(m.occupant.as_deref().unwrap_or(""));
+            }
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+
+    fn notify_when_free(&mut self,
+        params: api::machines::NotifyWhenFreeParams,
+        mut results: api::machines::NotifyWhenFreeResults)
+        -> Promise<(), capnp::Error>
+    {
+        let uuid = uuid_from_api(pry!(pry!(params.get()).get_uuid()));
+        // Who waits is saved to disk
+        pry!(writable(&self.status));
+        let i = self.inner.clone();
+        let timeout = self.lock_timeout;
+        let p = self.perm.clone();
+        let log = self.log.clone();
+
+        let f = async move {
+            let user = p.authzid().await.ok_or_else(error::unauthenticated)?;
+            let ps = lock::read(&i, timeout).await?.get_perm_req(&uuid)
+                .ok_or_else(|| error::no_such_machine(&uuid))?;
+            // Whoever may see a machine may see it become free
+            p.require(&ps, "read").await?;
+
+            if let Some(expires) = lock::write(&i, timeout).await?.watch(&uuid, &user)? {
+                debug!(log, "{} waits for machine {} to become free", user, uuid);
+                results.get().set_expires(expires);
+            }
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+
+    fn get_my_history(&mut self,
+        params: api::machines::GetMyHistoryParams,
+        mut results: api::machines::GetMyHistoryResults)
+        -> Promise<(), capnp::Error>
+    {
+        let limit = match pry!(params.get()).get_limit() as usize {
+            0 => DEFAULT_HISTORY,
+            l => l.min(MAX_HISTORY),
+        };
+        let p = self.perm.clone();
+        let spawner = self.spawner.clone();
+        let log = self.log.clone();
+
+        let f = async move {
+            let user = p.authzid().await.ok_or_else(error::unauthenticated)?;
+            let path = match p.audit().path() {
+                Some(path) => path.to_path_buf(),
+                // Without an audit trail we don't remember any uses
+                None => return Ok(()),
+            };
+
+            let large = fs::metadata(&path).map(|m| m.len() > LARGE_AUDIT_TRAIL).unwrap_or(false);
+            let r = if large {
+                spawner.spawn_with_handle(async move {
+                    audit::history::read(&path, &user, limit)
+                }).map_err(error::overloaded)?.await
+            } else {
+                audit::history::read(&path, &user, limit)
+            };
+            let uses = r.map_err(|e| {
+                error!(log, "Failed to read the audit trail: {}", e);
+                error::internal()
+            })?;
+
+            let mut b = results.get().init_uses(uses.len() as u32);
+            for (idx, u) in uses.iter().enumerate() {
+                let mut e = b.reborrow().get(idx as u32);
+                api_from_uuid(u.machine, e.reborrow().init_uuid());
+                e.set_start(u.start);
+                e.set_end(u.end.unwrap_or(0));
+                e.set_note(u.note.as_deref().unwrap_or(""));
+            }
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+}
+
+/// Fill in the API representation of a machine
+///
+/// `now` is the current time in UNIX seconds and `pos` the caller's position in the machine's queue.
+fn fill_info(mut b: api::machines::machine_info::Builder, uuid: &Uuid, m: &Machine, now: u64,
+    pos: u32)
+{
+    api_from_uuid(uuid.clone(), b.reborrow().init_uuid());
+    b.set_name(&m.name);
+    b.set_location(m.location_name.as_deref().unwrap_or(&m.location));
+    b.set_location_id(&m.location);
+    b.set_status(m.status.into());
+    if let Some(since) = m.since {
+        b.set_in_use_since(since);
+        b.set_occupied_for(now.saturating_sub(since));
+    }
+    b.set_giveback_at(m.giveback_deadline.unwrap_or(0));
+    b.set_queue_position(pos);
+    b.set_perm(m.perm());
+    b.set_perm_derived(m.perm.is_empty());
+    fill_schedule(b.init_scheduled_blocks(m.schedule.len() as u32), &m.schedule);
+}
+
+fn fill_schedule(
+    mut b: capnp::struct_list::Builder<'_, api::machines::scheduled_block::Owned>,
+    schedule: &[ScheduledBlock])
+{
+    for (i, block) in schedule.iter().enumerate() {
+        let mut e = b.reborrow().get(i as u32);
+        e.set_start(block.start);
+        e.set_end(block.end);
+        e.set_reason(&block.reason);
+        e.set_scheduled_by(&block.by);
+        e.set_active(block.applied);
+    }
+}
+
+#[derive(Clone)]
+pub struct GiveBack {
+    log: Logger,
+    audit: Audit,
+    /// Who is using the machine
+    user: String,
+    mdb: Arc<RwLock<MachinesProvider>>,
+    uuid: Uuid,
+    /// The use this gives back
+    grant: Uuid,
+    grants: Grants,
+    status: Arc<ServerStatus>,
+    lock_timeout: Duration,
+}
+impl GiveBack {
+    pub fn new(log: Logger, audit: Audit, user: String, mdb: Arc<RwLock<MachinesProvider>>,
+        uuid: Uuid, grant: Uuid, grants: Grants, status: Arc<ServerStatus>,
+        lock_timeout: Duration) -> Self
+    {
+        Self { log, audit, user, mdb, uuid, grant, grants, status, lock_timeout }
+    }
+}
+
+impl api::machines::give_back::Server for GiveBack {
+    fn giveback(&mut self,
+        _params: api::machines::give_back::GivebackParams,
+        _results: api::machines::give_back::GivebackResults)
+        -> Promise<(), Error>
+    {
+        pry!(writable(&self.status));
+        let mdb = self.mdb.clone();
+        let timeout = self.lock_timeout;
+        let uuid = self.uuid.clone();
+        let grants = self.grants.clone();
+        let log = self.log.clone();
+        let audit = self.audit.clone();
+        let user = self.user.clone();
+        let grant = self.grant.clone();
+        let f = async move {
+            // Still ours if we can't get to the machine, so it's given back when we disconnect
+            let mut mdb = lock::write(&mdb, timeout).await?;
+            grants.borrow_mut().remove(&uuid);
+            // The use ended some other way already, e.g. because the machine was blocked
+            if mdb.grant(&uuid) != Some(grant) {
+                debug!(log, "Machine {} was given back already", uuid);
+                return Ok(());
+            }
+            mdb.give_back(&log, &uuid)?;
+            drop(mdb);
+            audit.record(AuditEvent::MachineGiveBack { authzid: &user, machine: &uuid });
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+}
+
+pub(crate) fn uuid_from_api(uuid: api::u_u_i_d::Reader) -> Uuid {
+    let uuid0 = uuid.get_uuid0() as u128;
+    let uuid1 = uuid.get_uuid1() as u128;
+    let num: u128 = (uuid1 << 64) + uuid0;
+    Uuid::from_u128(num)
+}
+pub(crate) fn api_from_uuid(uuid: Uuid, mut wr: api::u_u_i_d::Builder) {
+    // Has to be the exact inverse of `uuid_from_api`
+    let num = uuid.as_u128();
+    let uuid0 = num as u64;
+    let uuid1 = (num >> 64) as u64;
+    wr.set_uuid0(uuid0);
+    wr.set_uuid1(uuid1);
+}
+
+#[derive(Clone)]
+pub struct MachineManager {
+    log: Logger,
+    audit: Audit,
+    /// Who is managing the machine
+    user: String,
+    mdb: Arc<RwLock<MachinesProvider>>,
+    uuid: Uuid,
+    status: Arc<ServerStatus>,
+    lock_timeout: Duration,
+}
+
+impl MachineManager {
+    pub fn new(log: Logger, audit: Audit, user: String, uuid: Uuid,
+        mdb: Arc<RwLock<MachinesProvider>>, status: Arc<ServerStatus>, lock_timeout: Duration)
+        -> Self
+    {
+        Self { log, audit, user, mdb, uuid, status, lock_timeout }
+    }
+}
+
+impl api::machines::manage::Server for MachineManager {
+    fn set_blocked(&mut self,
+        params: api::machines::manage::SetBlockedParams,
+        results: api::machines::manage::SetBlockedResults)
+        -> Promise<(), Error>
+    {
+        pry!(writable(&self.status));
+        let uuid = self.uuid.clone();
+        let mdb = self.mdb.clone();
+        let timeout = self.lock_timeout;
+        let log = self.log.clone();
+        let audit = self.audit.clone();
+        let user = self.user.clone();
+        let f = async move {
+            let params = params.get()?;
+            let blocked = params.get_blocked();
+            lock::write(&mdb, timeout).await?.set_blocked(&log, &uuid, blocked)?;
+            audit.record(AuditEvent::MachineBlocked { authzid: &user, machine: &uuid, blocked });
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+
+    fn get_actor_status(&mut self,
+        _params: api::machines::manage::GetActorStatusParams,
+        mut results: api::machines::manage::GetActorStatusResults)
+        -> Promise<(), Error>
+    {
+        let uuid = self.uuid.clone();
+        let mdb = self.mdb.clone();
+        let timeout = self.lock_timeout;
+        let f = async move {
+            let actor = lock::read(&mdb, timeout).await?.actor_status(&uuid).cloned();
+            let mut b = results.get().init_status();
+            if let Some(actor) = actor {
+                b.set_has_actor(true);
+                b.set_online(actor.online());
+                b.set_last_success(actor.last_success.unwrap_or(0));
+                if let Some((at, ref msg)) = actor.last_error {
+                    b.set_last_failure(at);
+                    b.set_last_error(msg);
+                }
+                if let Some((at, on)) = actor.last_command {
+                    b.set_last_command(at);
+                    b.set_last_command_on(on);
+                }
+            }
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+
+    fn override_actor_state(&mut self,
+        params: api::machines::manage::OverrideActorStateParams,
+        _results: api::machines::manage::OverrideActorStateResults)
+        -> Promise<(), Error>
+    {
+        let this = self.clone();
+        Promise::from_future(async move {
+            let power = params.get()?.get_on();
+            let reply = lock::read(&this.mdb, this.lock_timeout).await?
+                .override_actor(&this.uuid, power)
+                .ok_or_else(|| error::unimplemented("The machine has no actor"))?;
+            let r = match reply.await {
+                Ok(r) => r,
+                // The actor went away, we're shutting down
+                Err(_) => return Err(error::unavailable("The actor is not running")),
+            };
+
+            this.audit.record(AuditEvent::ActorOverridden {
+                authzid: &this.user, machine: &this.uuid, power, ok: r.is_ok(),
+            });
+            let power = if power { "on" } else { "off" };
+            match r {
+                Ok(()) => {
+                    info!(this.log, "{} switched the actor of machine {} {} by hand", this.user,
+                        this.uuid, power);
+                    Ok(())
+                },
+                Err(e) => {
+                    warn!(this.log, "{} could not switch the actor of machine {} {}: {}",
+                        this.user, this.uuid, power, e);
+                    Err(error::unavailable(e))
+                },
+            }
+        })
+    }
+
+    fn schedule_block(&mut self,
+        params: api::machines::manage::ScheduleBlockParams,
+        _results: api::machines::manage::ScheduleBlockResults)
+        -> Promise<(), Error>
+    {
+        pry!(writable(&self.status));
+        let uuid = self.uuid.clone();
+        let mdb = self.mdb.clone();
+        let timeout = self.lock_timeout;
+        let log = self.log.clone();
+        let audit = self.audit.clone();
+        let user = self.user.clone();
+        let f = async move {
+            let params = params.get()?;
+            let block = ScheduledBlock {
+                start: params.get_start(),
+                end: params.get_end(),
+                reason: params.get_reason()?.to_string(),
+                by: user.clone(),
+                applied: false,
+            };
+            let mut mdb = lock::write(&mdb, timeout).await?;
+            mdb.schedule_block(&log, &uuid, block)?;
+            // A block starting right away shouldn't wait for the next look at the schedule
+            let changes = mdb.apply_schedule();
+            drop(mdb);
+            for c in changes.iter() {
+                audit.record(AuditEvent::MachineBlocked {
+                    authzid: &c.by, machine: &c.uuid, blocked: c.blocked,
+                });
+            }
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+
+    fn list_scheduled_blocks(&mut self,
+        _params: api::machines::manage::ListScheduledBlocksParams,
+        mut results: api::machines::manage::ListScheduledBlocksResults)
+        -> Promise<(), Error>
+    {
+        let uuid = self.uuid.clone();
+        let mdb = self.mdb.clone();
+        let timeout = self.lock_timeout;
+        let f = async move {
+            let m = lock::read(&mdb, timeout).await?.get(&uuid)
+                .ok_or_else(|| error::no_such_machine(&uuid))?;
+            fill_schedule(results.get().init_blocks(m.schedule.len() as u32), &m.schedule);
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+
+    fn cancel_scheduled_block(&mut self,
+        params: api::machines::manage::CancelScheduledBlockParams,
+        _results: api::machines::manage::CancelScheduledBlockResults)
+        -> Promise<(), Error>
+    {
+        pry!(writable(&self.status));
+        let uuid = self.uuid.clone();
+        let mdb = self.mdb.clone();
+        let timeout = self.lock_timeout;
+        let log = self.log.clone();
+        let audit = self.audit.clone();
+        let user = self.user.clone();
+        let f = async move {
+            let start = params.get()?.get_start();
+            let unblocked = lock::write(&mdb, timeout).await?
+                .cancel_scheduled_block(&log, &uuid, start)?;
+            if unblocked {
+                audit.record(AuditEvent::MachineBlocked {
+                    authzid: &user, machine: &uuid, blocked: false,
+                });
+            }
+            Ok(())
+        };
+
+        Promise::from_future(f)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct Machine {
+    pub name: String,
+    /// Id of the location the machine is at, empty if it isn't at any. See `location`.
+    pub location: String,
+    pub status: Status,
+    /// Permission object required to use the machine. Empty if it's derived from
+    /// `machines.perm_template`, see `perm()`.
+    #[serde(default)]
+    pub perm: String,
+    /// When the machine was taken into use, in seconds since the UNIX epoch
+    #[serde(default)]
+    pub since: Option<u64>,
+    /// Who is using the machine. Unknown for machines taken into use before this was saved.
+    #[serde(default)]
+    pub occupant: Option<String>,
+    /// Identifies the current use, so a `GiveBack` of an earlier one can't end it
+    #[serde(default)]
+    pub grant: Option<Uuid>,
+    /// What the occupant said they're working on, see `sanitize_note`
+    #[serde(default)]
+    pub note: Option<String>,
+    /// Why the machine was blocked by hand, if whoever did it said so
+    #[serde(default)]
+    pub block_reason: Option<String>,
+    /// Give the machine back when its opening hours end
+    #[serde(default)]
+    pub giveback_at_close: bool,
+    /// When the machine may be used, any time if empty. Those allowed to manage it always may.
+    #[serde(default)]
+    pub allowed_hours: Vec<OpeningHours>,
+    /// Blocks planned ahead, ordered by start and never overlapping
+    // Has to stay last, TOML wants tables after plain values
+    #[serde(default)]
+    pub schedule: Vec<ScheduledBlock>,
+    /// What `perm` was derived as if it isn't set. Never saved, so changing the template applies to
+    /// all machines without a perm of their own.
+    #[serde(skip)]
+    pub derived_perm: Option<String>,
+    /// Name of the location, looked up when handing out copies. Never saved either.
+    #[serde(skip)]
+    pub location_name: Option<String>,
+    /// When the machine is given back by the server unless that happens before, in UNIX seconds.
+    /// Also looked up when handing out copies, see `MachinesProvider::giveback_deadline`.
+    #[serde(skip)]
+    pub giveback_deadline: Option<u64>,
+}
+
+impl Machine {
+    pub fn new(name: String, location: String, perm: String) -> Machine {
+        Machine {
+            name: name,
+            location: location,
+            status: Status::Free,
+            perm: perm,
+            since: None,
+            occupant: None,
+            grant: None,
+            note: None,
+            block_reason: None,
+            giveback_at_close: false,
+            allowed_hours: Vec::new(),
+            schedule: Vec::new(),
+            derived_perm: None,
+            location_name: None,
+            giveback_deadline: None,
+        }
+    }
+
+    /// The permission object required to use the machine, set or derived
+    pub fn perm(&self) -> &str {
+        match self.derived_perm {
+            Some(ref p) if self.perm.is_empty() => p,
+            _ => &self.perm,
+        }
+    }
+
+    /// Derive the perm from `template` if the machine doesn't set one
+    pub fn derive_perm(&mut self, uuid: &Uuid, template: Option<&str>) {
+        self.derived_perm = match template {
+            Some(t) if self.perm.is_empty() =>
+                Some(derive_perm(t, uuid, &self.name, &self.location)),
+            _ => None,
+        };
+    }
+
+    pub fn set_blocked(&mut self, blocked: bool) {
+        if blocked {
+            self.status = Status::Blocked;
+        } else {
+            self.status = Status::Free;
+        }
+        self.since = None;
+        self.occupant = None;
+        self.grant = None;
+        self.note = None;
+        self.block_reason = None;
+    }
+}
+
+/// What changing a single machine as part of a bulk operation did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BulkResult {
+    Changed,
+    /// The machine already was in the requested state
+    Unchanged,
+    NoSuchMachine,
+    /// Somebody is using the machine, so it was left alone
+    Occupied,
+}
+
+/// A window in which a machine is blocked, e.g. for maintenance
+///
+/// Blocks are identified by their start since they never overlap.
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledBlock {
+    /// In seconds since the UNIX epoch, like `end`
+    pub start: u64,
+    pub end: u64,
+    pub reason: String,
+    /// Who scheduled the block
+    pub by: String,
+    /// Whether we blocked the machine for it already
+    #[serde(default)]
+    pub applied: bool,
+}
+
+impl ScheduledBlock {
+    fn overlaps(&self, other: &ScheduledBlock) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    /// Whether the block has to be applied or lifted at `now`
+    fn due(&self, now: u64) -> bool {
+        self.end <= now || (!self.applied && self.start <= now)
+    }
+}
+
+/// A scheduled block that was applied or lifted
+pub struct ScheduleChange {
+    pub uuid: Uuid,
+    /// Who scheduled the block
+    pub by: String,
+    pub blocked: bool,
+}
+
+pub type MachineDB = HashMap<Uuid, Machine>;
+
+#[derive(Debug)]
+pub enum MachineDBError {
+    /// The same UUID was used for more than one machine
+    DuplicateUUID(Uuid),
+    /// Two machines share a name
+    DuplicateName(String),
+    /// The perm string of the machine with that UUID is malformed
+    InvalidPerm(Uuid, String),
+    /// Neither the machine nor `machines.perm_template` sets a perm
+    NoPerm(Uuid),
+    /// `format_version` isn't a version number
+    InvalidVersion(String),
+    /// Written by a newer version of us, in a format we don't know yet. Also says which is the
+    /// latest we know.
+    TooNew(u32, u32),
+    /// Upgrading to the version failed
+    Migration(u32, String),
+}
+impl fmt::Display for MachineDBError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MachineDBError::DuplicateUUID(uuid) =>
+                write!(f, "UUID {} is used by more than one machine", uuid),
+            MachineDBError::DuplicateName(name) =>
+                write!(f, "Machine name \"{}\" is used more than once", name),
+            MachineDBError::InvalidPerm(uuid, perm) =>
+                write!(f, "Machine {} has an invalid perm \"{}\"", uuid, perm),
+            MachineDBError::NoPerm(uuid) => write!(f, "Machine {} has no perm and no \
+                machines.perm_template is set to derive one", uuid),
+            MachineDBError::InvalidVersion(v) =>
+                write!(f, "{} {} is not a version number", format::VERSION_KEY, v),
+            MachineDBError::TooNew(v, latest) => write!(f, "Format version {} is newer than \
+                version {} which is the latest this build understands. Upgrade to a release that \
+                knows it or restore the backup left next to the database when it was upgraded.", v,
+                latest),
+            MachineDBError::Migration(v, e) =>
+                write!(f, "Could not upgrade to format version {}: {}", v, e),
+        }
+    }
+}
+impl std::error::Error for MachineDBError {}
+
+/// Check a perm string for validity
+///
+/// Perms are a dot-separated path of non-empty segments consisting of alphanumerics, `-` and `_`,
+/// e.g. `machine.laser` or `room_2.printer-1`.
+pub fn valid_perm(perm: &str) -> bool {
+    perm.split('.').all(|seg| {
+        !seg.is_empty() && seg.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    })
+}
+
+/// Placeholders allowed in `machines.perm_template`
+const PERM_PLACEHOLDERS: &[&str] = &["name", "location", "uuid"];
+
+/// The perm `template` gives a machine
+///
+/// The placeholders are replaced by the values of the machine, lowercased and with everything that
+/// can't be part of a perm replaced by `_`, e.g. "Laser Cutter" becomes `laser_cutter`.
+pub fn derive_perm(template: &str, uuid: &Uuid, name: &str, location: &str) -> String {
+    template
+        .replace("{name}", &perm_segment(name))
+        .replace("{location}", &perm_segment(location))
+        .replace("{uuid}", &uuid.to_hyphenated().to_string())
+}
+
+fn perm_segment(s: &str) -> String {
+    let mut seg = String::new();
+    for c in s.trim().chars().flat_map(char::to_lowercase) {
+        if c.is_ascii_alphanumeric() || c == '-' {
+            seg.push(c);
+        } else if !seg.ends_with('_') {
+            // Runs of spaces and the like become a single one
+            seg.push('_');
+        }
+    }
+    if seg.is_empty() {
+        seg.push('_');
+    }
+    seg
+}
+
+/// Check that `template` only uses known placeholders and gives valid perms
+pub fn check_perm_template(template: &str) -> std::result::Result<(), String> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..].find('}').ok_or("has an unclosed `{`")? + start;
+        let name = &rest[start + 1..end];
+        if !PERM_PLACEHOLDERS.contains(&name) {
+            return Err(format!("has unknown placeholder `{{{}}}`, known are {}", name,
+                PERM_PLACEHOLDERS.iter().map(|p| format!("{{{}}}", p)).collect::<Vec<_>>()
+                    .join(", ")));
+        }
+        rest = &rest[end + 1..];
+    }
+
+    let sample = derive_perm(template, &Uuid::nil(), "name", "location");
+    if !valid_perm(&sample) {
+        return Err(format!("gives invalid perms like \"{}\"", sample));
+    }
+    Ok(())
+}
+
+/// Make sure every machine has a valid perm, set or derived
+fn check_perms(machines: &[(Uuid, Machine)]) -> std::result::Result<(), MachineDBError> {
+    for (uuid, m) in machines.iter() {
+        if m.perm().is_empty() {
+            return Err(MachineDBError::NoPerm(uuid.clone()));
+        }
+        if !valid_perm(m.perm()) {
+            return Err(MachineDBError::InvalidPerm(uuid.clone(), m.perm().to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Check a set of machines for consistency
+///
+/// This is what every way of loading machines has to pass; it's done on entries instead of a
+/// `MachineDB` so that duplicate UUIDs can be detected before they are silently merged by the
+/// map.
+pub fn validate<'a, I>(entries: I) -> std::result::Result<(), MachineDBError>
+    where I: IntoIterator<Item=(&'a Uuid, &'a Machine)>
+{
+    let mut uuids = HashSet::new();
+    let mut names = HashSet::new();
+
+    for (uuid, machine) in entries {
+        if !uuids.insert(uuid) {
+            return Err(MachineDBError::DuplicateUUID(uuid.clone()));
+        }
+        if !names.insert(machine.name.as_str()) {
+            return Err(MachineDBError::DuplicateName(machine.name.clone()));
+        }
+        // Machines without a perm get theirs derived later, see `check_perms`
+        if !machine.perm().is_empty() && !valid_perm(machine.perm()) {
+            return Err(MachineDBError::InvalidPerm(uuid.clone(), machine.perm().to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+/// All entries of a serialized machine database in the order they appeared
+///
+/// Deserializing straight into a `MachineDB` would make a later entry silently replace an earlier
+/// one with the same UUID which we want to report instead.
+pub struct Entries(pub Vec<(Uuid, Machine)>);
+
+impl<'de> Deserialize<'de> for Entries {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct EntriesVisitor;
+        impl<'de> Visitor<'de> for EntriesVisitor {
+            type Value = Entries;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a map of UUIDs to machines")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A)
+                -> std::result::Result<Entries, A::Error>
+            {
+                let mut v = Vec::with_capacity(map.size_hint().unwrap_or(0));
+                while let Some(entry) = map.next_entry()? {
+                    v.push(entry);
+                }
+                Ok(Entries(v))
+            }
+        }
+
+        deserializer.deserialize_map(EntriesVisitor)
+    }
+}
+
+impl Entries {
+    /// Validate the entries and turn them into a `MachineDB`
+    pub fn into_db(self) -> std::result::Result<MachineDB, MachineDBError> {
+        validate(self.0.iter().map(|(u, m)| (u, m)))?;
+        Ok(self.0.into_iter().collect())
+    }
+}
+
+pub async fn init(log: Logger, config: &Config, status: Arc<ServerStatus>)
+    -> Result<MachinesProvider>
+{
+    let mut mdb = store::open(config)?;
+    if let Some(ref dir) = config.dry_run {
+        mdb = Box::new(store::DryRun::new(log.clone(), mdb, dir.join("machines.toml")));
+    }
+    if let Some(upgrade) = mdb.upgraded_from() {
+        warn!(log, "Upgraded machine database from format version {} to {}, the original is kept \
+            as {}", upgrade.from, upgrade.to, upgrade.backup.display());
+        // Saving it right away means an older version of us refuses the database up front instead
+        // of at some point later on
+        mdb.flush()?;
+    }
+    merge_inline(&log, mdb.as_mut(), config)?;
+
+    // Even with the journal turned off now there may be one left from before. Nothing is written
+    // in dry runs and read-only mode, what's replayed is only saved once something else is.
+    let journal_path = journal::path(&config.machinedb);
+    let save = config.dry_run.is_none() && !config.daemon.read_only;
+    let replay = journal::recover(mdb.as_mut(), &journal_path, save)?;
+    if replay.skipped > 0 {
+        warn!(log, "Skipped {} bytes at the end of machine journal {} that aren't a complete \
+            record, most likely it was being written to while crashing", replay.skipped,
+            journal_path.display());
+    }
+    if !replay.transitions.is_empty() {
+        info!(log, "Replayed {} machine state changes from the journal", replay.transitions.len());
+    }
+
+    // Checked with the rest of the config
+    let timezone = config.machines.timezone.parse().unwrap_or(Tz::UTC);
+    let mut provider = MachinesProvider::new(log.clone(), mdb, config.machines.queue_hold,
+        config.machines.perm_template.clone(), timezone, status);
+    provider.set_write_interval(config.machines.write_interval);
+    provider.set_lock_timeout(config.machines.lock_timeout);
+    if config.daemon.read_only {
+        warn!(log, "Starting in read-only mode, nothing is saved until it's left");
+        provider.set_read_only(true);
+    }
+    if !replay.transitions.is_empty() && !save {
+        provider.pending = true;
+    }
+    if config.machines.journal && config.machines.write_interval > 0 && config.dry_run.is_none() {
+        let journal = Journal::open(&journal_path, config.machines.journal_sync)?;
+        provider.set_journal(journal);
+    }
+    let machines = provider.list();
+    check_perms(&machines)?;
+    for (uuid, m) in machines.iter().filter(|(_, m)| m.perm.is_empty()) {
+        debug!(log, "Machine {} uses derived perm {}", uuid, m.perm());
+    }
+    provider.set_max_use(config.machines.max_use);
+    provider.expire_uses();
+    if let Some(ref path) = config.machines.watches {
+        let watches = Watches::open(path, provider.now()).with_path(path)?;
+        provider.set_watches(watches, config.machines.watch_expiry);
+    }
+    let in_use = provider.states().iter().filter(|s| s.status == Status::Occupied).count();
+    if in_use > 0 {
+        info!(log, "Restored {} machines in use", in_use);
+    }
+    Ok(provider)
+}
+
+/// Add the machines defined in the config file to the store
+///
+/// Machines already in the store keep their status but get the metadata from the config.
+fn merge_inline(log: &Logger, mdb: &mut dyn MachineStore, config: &Config) -> Result<()> {
+    if config.inline_machines.is_empty() {
+        return Ok(());
+    }
+
+    for inline in config.inline_machines.iter() {
+        // The config may name the location by its id or by what it's called
+        let (location, _) = location::adopt(mdb.locations_mut(), &inline.location);
+        match mdb.get(&inline.uuid) {
+            None => {
+                let mut m = inline.to_machine();
+                m.location = location;
+                mdb.insert(inline.uuid, m);
+            },
+            Some(m) if m.name != inline.name || m.location != location
+                || m.perm != inline.perm =>
+            {
+                warn!(log, "Machine {} is defined in the config and the machine database, \
+                    using the definition from the config", inline.uuid);
+                let m = mdb.get_mut(&inline.uuid).unwrap();
+                m.name = inline.name.clone();
+                m.location = location;
+                m.perm = inline.perm.clone();
+            },
+            Some(_) => {},
+        }
+    }
+
+    // Inline machines are checked on their own already but could still clash with names in the
+    // database.
+    validate(mdb.iter())?;
+
+    mdb.flush()
+}
+
+/// Read the machine database at `path` with its locations
+///
+/// A missing file is not an error but simply an empty database. Databases in an older format are
+/// upgraded, see `format`, returning the version they were in.
+pub fn load(path: &Path) -> Result<(MachineDB, Locations, Option<u32>)> {
+    if path.is_file() {
+        let mut fp = File::open(path).with_path(path)?;
+        let mut content = String::new();
+        fp.read_to_string(&mut content).with_path(path)?;
+        let mut table: toml::value::Table = toml::from_str(&content).with_path(path)?;
+        let upgraded = format::upgrade(path, &mut table).with_path(path)?;
+        let locations: Locations = match table.remove(format::LOCATIONS_KEY) {
+            Some(l) => l.try_into().with_path(path)?,
+            None => Locations::new(),
+        };
+        let entries: Entries = toml::Value::Table(table).try_into().with_path(path)?;
+        Ok((entries.into_db().with_path(path)?, locations, upgraded))
+    } else {
+        Ok((HashMap::new(), Locations::new(), None))
+    }
+}
+
+pub fn save(path: &Path, mdb: &MachineDB, locations: &Locations) -> Result<()> {
+    // Write the new content into a file next to the database and then rename it over the old one.
+    // Renames are atomic so the database is either fully the old or fully the new version, never
+    // something half-written.
+    let tmp = path.with_extension("tmp");
+    {
+        let mut fp = File::create(&tmp).with_path(&tmp)?;
+        let mut db = toml::Value::try_from(mdb)?;
+        if let toml::Value::Table(ref mut t) = db {
+            t.insert(format::LOCATIONS_KEY.to_string(), toml::Value::try_from(locations)?);
+        }
+        let toml = toml::to_string(&format::versioned(db))?;
+        fp.write_all(&toml.as_bytes()).with_path(&tmp)?;
+        fp.sync_all().with_path(&tmp)?;
+    }
+    fs::rename(&tmp, path).with_path(path)?;
+    Ok(())
+}
+
+/// Take the advisory lock on the machine database at `path`
+///
+/// The lock is held for as long as the returned `File` is alive. Since `save` replaces the
+/// database file itself the lock is taken on a separate `.lock` file next to it.
+/// Fails with `WouldBlock` if somebody else -- usually a running daemon -- already holds it.
+pub fn lock(path: &Path) -> Result<File> {
+    let mut lockpath = path.as_os_str().to_owned();
+    lockpath.push(".lock");
+
+    let fp = OpenOptions::new().create(true).write(true).open(&lockpath).with_path(&lockpath)?;
+    // Not adding the path here, callers check for `WouldBlock`
+    fp.try_lock_exclusive()?;
+    Ok(fp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::StreamExt;
+    use futures::executor::LocalPool;
+    use futures::task::LocalSpawnExt;
+
+    use crate::testing::{logger, machines, reopen, rpc, TempDir, TestClock, LASER};
+
+    #[test]
+    fn uses_expire_after_max_use() {
+        let dir = TempDir::new();
+        let clock = TestClock::at(1_000_000);
+        let mut mdb = machines(&dir, &clock);
+        mdb.set_max_use(Some(3600));
+
+        mdb.use_(&logger(), &LASER, "alice", false, None).unwrap();
+        assert_eq!(mdb.get(&LASER).unwrap().giveback_deadline, Some(1_003_600));
+        assert_eq!(mdb.next_giveback(), Some(1_003_600));
+
+        clock.advance(3599);
+        assert!(mdb.expire_uses().is_empty());
+        assert_eq!(mdb.get(&LASER).unwrap().status, Status::Occupied);
+
+        clock.advance(1);
+        assert_eq!(mdb.expire_uses(), vec![(LASER, "alice".to_string())]);
+        let m = mdb.get(&LASER).unwrap();
+        assert_eq!(m.status, Status::Free);
+        assert_eq!(m.giveback_deadline, None);
+        assert_eq!(mdb.next_giveback(), None);
+    }
+
+    #[test]
+    fn head_of_queue_gets_hold() {
+        let dir = TempDir::new();
+        let clock = TestClock::at(1_000_000);
+        let mut mdb = machines(&dir, &clock);
+        let log = logger();
+
+        mdb.use_(&log, &LASER, "alice", false, None).unwrap();
+        assert_eq!(mdb.enqueue(&log, &LASER, "bob").unwrap(), 1);
+        assert_eq!(mdb.enqueue(&log, &LASER, "carol").unwrap(), 2);
+        assert_eq!(mdb.hold(&LASER), None);
+
+        mdb.give_back(&log, &LASER).unwrap();
+        assert_eq!(mdb.hold(&LASER), Some(("bob".to_string(), 1_000_300)));
+        assert_eq!(mdb.queue_position(&LASER, "carol"), 1);
+        assert!(mdb.use_(&log, &LASER, "carol", false, None).is_err());
+
+        clock.advance(300);
+        assert_eq!(mdb.hold(&LASER), None);
+        mdb.use_(&log, &LASER, "carol", false, None).unwrap();
+    }
+
+    #[test]
+    fn no_deadline_without_max_use() {
+        let dir = TempDir::new();
+        let clock = TestClock::at(1_000_000);
+        let mut mdb = machines(&dir, &clock);
+
+        mdb.use_(&logger(), &LASER, "alice", false, None).unwrap();
+        clock.advance(365 * 24 * 3600);
+        assert!(mdb.expire_uses().is_empty());
+        assert_eq!(mdb.get(&LASER).unwrap().giveback_deadline, None);
+        assert_eq!(mdb.next_giveback(), None);
+    }
+
+    fn block(start: u64, end: u64) -> ScheduledBlock {
+        ScheduledBlock {
+            start,
+            end,
+            reason: "Replacing the lens".to_string(),
+            by: "admin".to_string(),
+            applied: false,
+        }
+    }
+
+    #[test]
+    fn overlapping_blocks_are_rejected() {
+        let dir = TempDir::new();
+        let clock = TestClock::at(1_000_000);
+        let mut mdb = machines(&dir, &clock);
+        let log = logger();
+
+        mdb.schedule_block(&log, &LASER, block(1_003_600, 1_007_200)).unwrap();
+        for (start, end) in &[(1_000_000, 1_003_601), (1_007_199, 1_010_000),
+            (1_004_000, 1_005_000), (1_000_000, 1_010_000)]
+        {
+            let e = mdb.schedule_block(&log, &LASER, block(*start, *end)).err().unwrap();
+            assert!(e.description.starts_with("conflict"), "{}", e.description);
+        }
+        // Touching is fine
+        mdb.schedule_block(&log, &LASER, block(1_007_200, 1_010_000)).unwrap();
+        mdb.schedule_block(&log, &LASER, block(1_000_100, 1_003_600)).unwrap();
+
+        let starts: Vec<u64> = mdb.get(&LASER).unwrap().schedule.iter().map(|b| b.start).collect();
+        assert_eq!(starts, vec![1_000_100, 1_003_600, 1_007_200]);
+    }
+
+    #[test]
+    fn pointless_blocks_are_rejected() {
+        let dir = TempDir::new();
+        let clock = TestClock::at(1_000_000);
+        let mut mdb = machines(&dir, &clock);
+        let log = logger();
+
+        assert!(mdb.schedule_block(&log, &LASER, block(1_003_600, 1_003_600)).is_err());
+        assert!(mdb.schedule_block(&log, &LASER, block(900_000, 1_000_000)).is_err());
+        let e = mdb.schedule_block(&log, &Uuid::from_u128(0xdead), block(1_003_600, 1_007_200))
+            .err().unwrap();
+        assert!(e.description.starts_with("no-such-machine"), "{}", e.description);
+    }
+
+    #[test]
+    fn blocks_apply_and_lift() {
+        let dir = TempDir::new();
+        let clock = TestClock::at(1_000_000);
+        let mut mdb = machines(&dir, &clock);
+        let log = logger();
+
+        mdb.schedule_block(&log, &LASER, block(1_003_600, 1_007_200)).unwrap();
+        assert_eq!(mdb.next_scheduled(), Some(1_003_600));
+        assert!(mdb.apply_schedule().is_empty());
+
+        // Whoever is using it when the block starts has to stop
+        mdb.use_(&log, &LASER, "alice", false, None).unwrap();
+        clock.advance(3600);
+        let changes = mdb.apply_schedule();
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].blocked);
+        assert_eq!(changes[0].by, "admin");
+        let m = mdb.get(&LASER).unwrap();
+        assert_eq!(m.status, Status::Blocked);
+        assert_eq!(m.occupant, None);
+        assert!(m.schedule[0].applied);
+        assert!(mdb.occupied_by("alice").is_empty());
+        assert_eq!(mdb.next_scheduled(), Some(1_007_200));
+
+        clock.advance(3600);
+        let changes = mdb.apply_schedule();
+        assert_eq!(changes.len(), 1);
+        assert!(!changes[0].blocked);
+        let m = mdb.get(&LASER).unwrap();
+        assert_eq!(m.status, Status::Free);
+        assert!(m.schedule.is_empty());
+        assert_eq!(mdb.next_scheduled(), None);
+    }
+
+    #[test]
+    fn blocks_survive_restarts() {
+        let dir = TempDir::new();
+        let clock = TestClock::at(1_000_000);
+        let mut mdb = machines(&dir, &clock);
+        mdb.schedule_block(&logger(), &LASER, block(1_003_600, 1_007_200)).unwrap();
+        mdb.schedule_block(&logger(), &LASER, block(1_010_000, 1_020_000)).unwrap();
+        drop(mdb);
+
+        let mut mdb = reopen(&dir, &clock);
+        assert_eq!(mdb.get(&LASER).unwrap().schedule,
+            vec![block(1_003_600, 1_007_200), block(1_010_000, 1_020_000)]);
+
+        clock.advance(3600);
+        mdb.apply_schedule();
+        drop(mdb);
+
+        // Down for the whole first block, and back in the middle of the second
+        clock.advance(10_000);
+        let mut mdb = reopen(&dir, &clock);
+        assert_eq!(mdb.get(&LASER).unwrap().status, Status::Blocked);
+        let changes = mdb.apply_schedule();
+        let m = mdb.get(&LASER).unwrap();
+        assert_eq!(m.status, Status::Blocked);
+        assert_eq!(m.schedule.len(), 1);
+        assert!(m.schedule[0].applied);
+        // Still blocked, just for another reason
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn cancelling_lifts_blocks_in_effect() {
+        let dir = TempDir::new();
+        let clock = TestClock::at(1_000_000);
+        let mut mdb = machines(&dir, &clock);
+        let log = logger();
+
+        mdb.schedule_block(&log, &LASER, block(1_000_000, 1_007_200)).unwrap();
+        mdb.schedule_block(&log, &LASER, block(1_010_000, 1_020_000)).unwrap();
+        mdb.apply_schedule();
+        assert_eq!(mdb.get(&LASER).unwrap().status, Status::Blocked);
+
+        assert!(!mdb.cancel_scheduled_block(&log, &LASER, 1_010_000).unwrap());
+        assert_eq!(mdb.get(&LASER).unwrap().status, Status::Blocked);
+        assert!(mdb.cancel_scheduled_block(&log, &LASER, 1_000_000).unwrap());
+        assert_eq!(mdb.get(&LASER).unwrap().status, Status::Free);
+        let e = mdb.cancel_scheduled_block(&log, &LASER, 1_000_000).err().unwrap();
+        assert!(e.description.starts_with("no-such-block"), "{}", e.description);
+    }
+
+    #[test]
+    fn deny_reasons() {
+        let dir = TempDir::new();
+        let clock = TestClock::at(1_000_000);
+        let mut mdb = machines(&dir, &clock);
+        let log = logger();
+
+        assert_eq!(mdb.use_(&log, &Uuid::from_u128(0xdead), "alice", false, None),
+            Err(DenyReason::NoSuchMachine));
+
+        mdb.use_(&log, &LASER, "alice", false, None).unwrap();
+        assert_eq!(mdb.use_(&log, &LASER, "bob", false, None), Err(DenyReason::Occupied {
+            occupant: Some("alice".to_string()),
+            since: Some(1_000_000),
+        }));
+
+        mdb.enqueue(&log, &LASER, "bob").unwrap();
+        mdb.give_back(&log, &LASER).unwrap();
+        assert_eq!(mdb.use_(&log, &LASER, "carol", false, None),
+            Err(DenyReason::Reserved { until: 1_000_300 }));
+
+        clock.advance(300);
+        mdb.block(&log, &LASER, true, Some("broken lens".to_string())).unwrap();
+        assert_eq!(mdb.use_(&log, &LASER, "carol", false, None), Err(DenyReason::Blocked {
+            reason: Some("broken lens".to_string()),
+            until: None,
+        }));
+        mdb.block(&log, &LASER, true, Some(String::new())).unwrap();
+        assert_eq!(mdb.use_(&log, &LASER, "carol", false, None),
+            Err(DenyReason::Blocked { reason: None, until: None }));
+        mdb.set_blocked(&log, &LASER, false).unwrap();
+
+        // Scheduled blocks say when they're over
+        mdb.schedule_block(&log, &LASER, block(1_000_300, 1_003_600)).unwrap();
+        mdb.apply_schedule();
+        assert_eq!(mdb.use_(&log, &LASER, "carol", false, None), Err(DenyReason::Blocked {
+            reason: Some("Replacing the lens".to_string()),
+            until: Some(1_003_600),
+        }));
+    }
+
+    #[test]
+    fn uses_wait_for_write_pending() {
+        let dir = TempDir::new();
+        let clock = TestClock::at(1_000_000);
+        let log = logger();
+        let mut mdb = machines(&dir, &clock);
+        mdb.set_write_interval(60);
+
+        mdb.use_(&log, &LASER, "alice", false, None).unwrap();
+        assert_eq!(mdb.status.coalesced(), 1);
+        assert_eq!(reopen(&dir, &clock).get(&LASER).unwrap().status, Status::Free);
+
+        mdb.flush_pending();
+        let m = reopen(&dir, &clock).get(&LASER).unwrap();
+        assert_eq!(m.status, Status::Occupied);
+        assert_eq!(m.occupant.as_deref(), Some("alice"));
+
+        // Blocks are saved right away, and take what was waiting with them
+        mdb.give_back(&log, &LASER).unwrap();
+        mdb.block(&log, &LASER, true, None).unwrap();
+        assert_eq!(reopen(&dir, &clock).get(&LASER).unwrap().status, Status::Blocked);
+
+        // Without an interval nothing waits
+        mdb.set_write_interval(0);
+        mdb.set_blocked(&log, &LASER, false).unwrap();
+        mdb.use_(&log, &LASER, "bob", false, None).unwrap();
+        let m = reopen(&dir, &clock).get(&LASER).unwrap();
+        assert_eq!(m.occupant.as_deref(), Some("bob"));
+        assert_eq!(mdb.status.coalesced(), 2);
+    }
+
+    #[test]
+    fn read_only_mode_saves_once_over() {
+        let dir = TempDir::new();
+        let clock = TestClock::at(1_000_000);
+        let log = logger();
+        let mut mdb = machines(&dir, &clock);
+        mdb.set_write_interval(60);
+        mdb.use_(&log, &LASER, "alice", false, None).unwrap();
+
+        // What was waiting is saved on entering, so the files are complete
+        assert!(mdb.set_read_only(true));
+        assert!(!mdb.set_read_only(true));
+        assert_eq!(reopen(&dir, &clock).get(&LASER).unwrap().status, Status::Occupied);
+
+        // Not even blocks are saved until it's over
+        mdb.give_back(&log, &LASER).unwrap();
+        mdb.block(&log, &LASER, true, None).unwrap();
+        assert_eq!(reopen(&dir, &clock).get(&LASER).unwrap().status, Status::Occupied);
+
+        assert!(mdb.set_read_only(false));
+        assert_eq!(reopen(&dir, &clock).get(&LASER).unwrap().status, Status::Blocked);
+    }
+
+    #[test]
+    fn pruning_registries() {
+        let dir = TempDir::new();
+        let clock = TestClock::at(1_000_000);
+        let log = logger();
+        let mut mdb = machines(&dir, &clock);
+
+        let kept = mdb.subscribe();
+        drop(mdb.subscribe());
+        assert_eq!(mdb.prune_subscribers(), 1);
+        assert_eq!(mdb.subscribers.len(), 1);
+        drop(kept);
+
+        // Waiting for a machine in use is still worth something
+        mdb.use_(&log, &LASER, "alice", false, None).unwrap();
+        mdb.enqueue(&log, &LASER, "bob").unwrap();
+        assert_eq!(mdb.queued(), 1);
+        assert_eq!(mdb.prune_queues(), 0);
+
+        // Bob's hold runs out without him coming by
+        mdb.give_back(&log, &LASER).unwrap();
+        assert!(mdb.queued() > 0);
+        clock.advance(300);
+        assert!(mdb.prune_queues() > 0);
+        assert_eq!(mdb.queued(), 0);
+    }
+
+    #[test]
+    fn notes() {
+        assert_eq!(sanitize_note("").unwrap(), None);
+        assert_eq!(sanitize_note(" \t\n ").unwrap(), None);
+        assert_eq!(sanitize_note(" door\u{1b}[2J sensor\n").unwrap(),
+            Some("door[2J sensor".to_string()));
+        assert_eq!(sanitize_note(&"ä".repeat(MAX_NOTE)).unwrap(), Some("ä".repeat(MAX_NOTE)));
+        assert!(sanitize_note(&"a".repeat(MAX_NOTE + 1)).is_err());
+    }
+
+    async fn use_with_note(client: &rpc::Client, uuid: Uuid, note: &str)
+        -> std::result::Result<api::machines::give_back::Client, Error>
+    {
+        let mut req = client.machines().use_request();
+        api_from_uuid(uuid, req.get().init_uuid());
+        req.get().set_note(note);
+        req.send().promise.await?.get()?.get_giveback()
+    }
+
+    async fn note(client: &rpc::Client, uuid: Uuid) -> String {
+        let mut req = client.machines().get_info_request();
+        api_from_uuid(uuid, req.get().init_uuid());
+        let reply = req.send().promise.await.unwrap();
+        reply.get().unwrap().get_info().unwrap().get_note().unwrap().to_string()
+    }
+
+    #[test]
+    fn notes_only_for_the_occupant_and_managers() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = rpc::Server::start(&spawner).await;
+            let alice = server.connect(&spawner).await;
+            alice.login_as("alice").await;
+            let bob = server.connect(&spawner).await;
+            bob.login_as("bob").await;
+            let admin = server.connect(&spawner).await;
+            admin.login_as("admin").await;
+
+            let e = use_with_note(&alice, rpc::PRINTER, &"a".repeat(MAX_NOTE + 1)).await;
+            assert_eq!(rpc::code(&e.err().unwrap()), "invalid-argument");
+
+            let giveback = use_with_note(&alice, rpc::PRINTER, "parts for the\ndoor sensor").await
+                .unwrap();
+            assert_eq!(note(&alice, rpc::PRINTER).await, "parts for thedoor sensor");
+            assert_eq!(note(&admin, rpc::PRINTER).await, "parts for thedoor sensor");
+            assert_eq!(note(&bob, rpc::PRINTER).await, "");
+
+            rpc::give_back(&giveback).await.unwrap();
+            assert_eq!(note(&admin, rpc::PRINTER).await, "");
+        });
+    }
+
+    async fn locations(client: &rpc::Client) -> Vec<(String, String)> {
+        let reply = client.machines().list_locations_request().send().promise.await.unwrap();
+        reply.get().unwrap().get_locations().unwrap().iter()
+            .map(|l| (l.get_id().unwrap().to_string(), l.get_name().unwrap().to_string()))
+            .collect()
+    }
+
+    async fn listed_at(client: &rpc::Client, location: &str) -> Vec<(Uuid, String)> {
+        let mut req = client.machines().list_request();
+        req.get().set_location(location);
+        let reply = req.send().promise.await.unwrap();
+        let mut listed: Vec<_> = reply.get().unwrap().get_machines().unwrap().iter()
+            .map(|m| (uuid_from_api(m.get_uuid().unwrap()),
+                m.get_location().unwrap().to_string()))
+            .collect();
+        listed.sort();
+        listed
+    }
+
+    #[test]
+    fn locations_with_a_perm_of_their_own() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = rpc::Server::start(&spawner).await;
+            {
+                let mach = server.api.machines();
+                let mut mach = mach.write().await;
+                let locations = mach.mdb.locations_mut();
+                locations.insert("hall".to_string(),
+                    Location { name: "Hall".to_string(), perm: None });
+                locations.insert("workshop".to_string(),
+                    Location { name: "Wood Workshop".to_string(),
+                        perm: Some("lab.workshop".to_string()) });
+                mach.mdb.get_mut(&rpc::PRINTER).unwrap().location = "hall".to_string();
+                mach.mdb.get_mut(&rpc::SAW).unwrap().location = "workshop".to_string();
+            }
+            // Only admins may go into the workshop
+            let policy = &server.config.access.policy;
+            let mut rules = std::fs::read_to_string(policy).unwrap();
+            rules.push_str("p, role:admin, lab.workshop, read\n");
+            std::fs::write(policy, rules).unwrap();
+            server.api.permissions().write().await.reload_policy().await.unwrap();
+
+            let admin = server.connect(&spawner).await;
+            admin.login_as("admin").await;
+            assert_eq!(locations(&admin).await, vec![
+                ("hall".to_string(), "Hall".to_string()),
+                ("workshop".to_string(), "Wood Workshop".to_string()),
+            ]);
+            assert_eq!(listed_at(&admin, "").await, vec![
+                (rpc::PRINTER, "Hall".to_string()),
+                (rpc::SAW, "Wood Workshop".to_string()),
+            ]);
+            assert_eq!(listed_at(&admin, "workshop").await,
+                vec![(rpc::SAW, "Wood Workshop".to_string())]);
+
+            // Alice may read the saw itself, but not anything in the workshop
+            let alice = server.connect(&spawner).await;
+            alice.login_as("alice").await;
+            assert_eq!(locations(&alice).await, vec![("hall".to_string(), "Hall".to_string())]);
+            assert_eq!(listed_at(&alice, "").await, vec![(rpc::PRINTER, "Hall".to_string())]);
+            assert_eq!(listed_at(&alice, "workshop").await, vec![]);
+            let e = alice.status(rpc::SAW).await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unauthorized");
+        });
+    }
+
+    #[test]
+    fn journaled_in_read_only_mode() {
+        let dir = TempDir::new();
+        let clock = TestClock::at(1_000_000);
+        let log = logger();
+        let mut mdb = machines(&dir, &clock);
+        mdb.set_write_interval(60);
+        let path = journal::path(&dir.join("machines.toml"));
+        mdb.set_journal(Journal::open(&path, 1).unwrap());
+
+        mdb.set_read_only(true);
+        mdb.use_(&log, &LASER, "alice", false, None).unwrap();
+        assert_eq!(reopen(&dir, &clock).get(&LASER).unwrap().status, Status::Free);
+        // Crashing now loses nothing
+        let replay = journal::read(&path).unwrap();
+        assert_eq!(replay.transitions.len(), 1);
+        assert_eq!(replay.transitions[0].occupant.as_deref(), Some("alice"));
+
+        // Leaving saves it, and the journal isn't needed anymore
+        mdb.set_read_only(false);
+        assert_eq!(reopen(&dir, &clock).get(&LASER).unwrap().status, Status::Occupied);
+        assert!(journal::read(&path).unwrap().transitions.is_empty());
+    }
+
+    async fn actor_status(manage: &api::machines::manage::Client)
+        -> (bool, bool, u64, String)
+    {
+        let reply = manage.get_actor_status_request().send().promise.await.unwrap();
+        let status = reply.get().unwrap().get_status().unwrap();
+        (status.get_has_actor(), status.get_online(), status.get_last_failure(),
+            status.get_last_error().unwrap().to_string())
+    }
+
+    async fn override_actor(manage: &api::machines::manage::Client, on: bool)
+        -> std::result::Result<(), Error>
+    {
+        let mut req = manage.override_actor_state_request();
+        req.get().set_on(on);
+        req.send().promise.await?;
+        Ok(())
+    }
+
+    #[test]
+    fn actor_status_and_overrides() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = rpc::Server::start(&spawner).await;
+            let mut audit = server.audit.subscribe();
+            let admin = server.connect(&spawner).await;
+            admin.login_as("admin").await;
+            let mut req = admin.machines().manage_request();
+            api_from_uuid(rpc::PRINTER, req.get().init_uuid());
+            let manage = req.send().pipeline.get_manage();
+
+            assert_eq!(actor_status(&manage).await, (false, false, 0, String::new()));
+            let e = override_actor(&manage, true).await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unimplemented");
+
+            // Stand in for the actor, whose relay works once and then goes offline
+            let mdb = server.api.machines();
+            let (tx, mut rx) = mpsc::unbounded::<ActorOverride>();
+            mdb.write().await.set_actor_control(&rpc::PRINTER, tx);
+            let actor = mdb.clone();
+            spawner.spawn_local(async move {
+                let mut ok = true;
+                while let Some(o) = rx.next().await {
+                    let r = if ok {
+                        Ok(())
+                    } else {
+                        actor.write().await.set_actor_status(&rpc::PRINTER, ActorStatus {
+                            last_command: Some((1000, o.power)),
+                            last_success: None,
+                            last_error: Some((1000, "relay unreachable".to_string())),
+                        });
+                        Err(io::Error::new(io::ErrorKind::Other, "relay unreachable"))
+                    };
+                    ok = false;
+                    let _ = o.reply.send(r);
+                }
+            }).unwrap();
+
+            override_actor(&manage, true).await.unwrap();
+            let e = override_actor(&manage, false).await.err().unwrap();
+            assert_eq!(rpc::code(&e), "unavailable");
+            assert!(e.description.contains("relay unreachable"));
+            assert_eq!(actor_status(&manage).await,
+                (true, false, 1000, "relay unreachable".to_string()));
+            // Overriding doesn't touch the machine itself
+            assert_eq!(admin.status(rpc::PRINTER).await.unwrap(), api::machines::Status::Free);
+
+            let mut recorded = Vec::new();
+            while let Ok(Some(r)) = audit.try_next() {
+                if let audit::Event::ActorOverridden { authzid, machine, power, ok } = r.event {
+                    assert_eq!(machine, rpc::PRINTER);
+                    recorded.push((authzid, power, ok));
+                }
+            }
+            assert_eq!(recorded, vec![("admin".to_string(), true, true),
+                ("admin".to_string(), false, false)]);
+        });
+    }
 }
@@ -0,0 +1,198 @@
+//! Why somebody may not use a machine, in enough detail for clients to tell them what to do next
+
+use std::fmt;
+
+use crate::api::api;
+use crate::api::error::{ApiError, Code, Param};
+
+/// Why a `use` was denied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DenyReason {
+    /// The connection didn't authenticate, so it has no permissions at all
+    Unauthenticated,
+    /// The user lacks `action` on `object`
+    Unauthorized { object: String, action: String },
+    NoSuchMachine,
+    /// Somebody else is using the machine since `since`, in UNIX seconds. Who that is may only be
+    /// seen by those allowed to manage the machine, see `hide_occupant`.
+    Occupied { occupant: Option<String>, since: Option<u64> },
+    /// Blocked by an admin or by a scheduled block with a `reason` lasting `until`
+    Blocked { reason: Option<String>, until: Option<u64> },
+    /// Held for the next user in its queue until `until`, in UNIX seconds
+    Reserved { until: u64 },
+    /// Outside of the opening hours of the machine, which next allow using it at `opens`
+    Closed { opens: Option<u64> },
+}
+
+impl DenyReason {
+    pub fn code(&self) -> Code {
+        match self {
+            DenyReason::Unauthenticated => Code::Unauthenticated,
+            DenyReason::Unauthorized { .. } => Code::Unauthorized,
+            DenyReason::NoSuchMachine => Code::NoSuchMachine,
+            DenyReason::Occupied { .. } => Code::Occupied,
+            DenyReason::Blocked { .. } => Code::Blocked,
+            DenyReason::Reserved { .. } => Code::Reserved,
+            DenyReason::Closed { .. } => Code::Closed,
+        }
+    }
+
+    /// Forget who is using the machine, for callers who may not know
+    pub fn hide_occupant(&mut self) {
+        if let DenyReason::Occupied { ref mut occupant, .. } = self {
+            *occupant = None;
+        }
+    }
+
+    /// The error to send to the client, with the details as parameters
+    pub fn to_error(&self) -> capnp::Error {
+        let e = ApiError::new(self.code(), self);
+        let e = match self {
+            DenyReason::Unauthenticated | DenyReason::NoSuchMachine => e,
+            DenyReason::Unauthorized { object, action } =>
+                e.with(Param::Object, object.as_str()).with(Param::Action, action.as_str()),
+            DenyReason::Occupied { occupant, since } => {
+                let e = match occupant {
+                    Some(o) => e.with(Param::Occupant, o.as_str()),
+                    None => e,
+                };
+                match since {
+                    Some(s) => e.with(Param::Since, *s),
+                    None => e,
+                }
+            },
+            DenyReason::Blocked { reason, until } => {
+                let e = match reason {
+                    Some(r) => e.with(Param::Reason, r.as_str()),
+                    None => e,
+                };
+                match until {
+                    Some(u) => e.with(Param::Until, *u),
+                    None => e,
+                }
+            },
+            DenyReason::Reserved { until } => e.with(Param::Until, *until),
+            DenyReason::Closed { opens: Some(o) } => e.with(Param::Opens, *o),
+            DenyReason::Closed { opens: None } => e,
+        };
+        e.into()
+    }
+
+    pub fn fill(&self, mut b: api::machines::deny_reason::Builder) {
+        match self {
+            DenyReason::Unauthenticated => b.set_unauthenticated(()),
+            DenyReason::Unauthorized { object, action } => {
+                let mut u = b.init_unauthorized();
+                u.set_object(object);
+                u.set_action(action);
+            },
+            DenyReason::NoSuchMachine => b.set_no_such_machine(()),
+            DenyReason::Occupied { occupant, since } => {
+                let mut o = b.init_occupied();
+                if let Some(occupant) = occupant {
+                    o.set_occupant(occupant);
+                }
+                o.set_since(since.unwrap_or(0));
+            },
+            DenyReason::Blocked { reason, until } => {
+                let mut bl = b.init_blocked();
+                if let Some(reason) = reason {
+                    bl.set_reason(reason);
+                }
+                bl.set_until(until.unwrap_or(0));
+            },
+            DenyReason::Reserved { until } => b.init_reserved().set_until(*until),
+            DenyReason::Closed { opens } => b.init_closed().set_opens(opens.unwrap_or(0)),
+        }
+    }
+}
+
+impl fmt::Display for DenyReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DenyReason::Unauthenticated => write!(f, "Authentication required"),
+            DenyReason::Unauthorized { object, action } =>
+                write!(f, "Permission denied, requires {} on {}", action, object),
+            DenyReason::NoSuchMachine => write!(f, "No such machine"),
+            DenyReason::Occupied { occupant: Some(o), .. } =>
+                write!(f, "Machine is occupied by {}", o),
+            DenyReason::Occupied { occupant: None, .. } => write!(f, "Machine is occupied"),
+            DenyReason::Blocked { reason: Some(r), .. } => write!(f, "Machine is blocked: {}", r),
+            DenyReason::Blocked { reason: None, .. } => write!(f, "Machine is blocked"),
+            DenyReason::Reserved { .. } =>
+                write!(f, "Machine is reserved for the next user in the queue"),
+            DenyReason::Closed { .. } => write!(f, "Machine is outside of its opening hours"),
+        }
+    }
+}
+
+impl From<DenyReason> for capnp::Error {
+    fn from(r: DenyReason) -> Self {
+        r.to_error()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::{json, Value};
+
+    /// First line and parameters of the error sent for `reason`
+    fn sent(reason: DenyReason) -> (String, Value) {
+        let e = reason.to_error();
+        match e.description.find('\n') {
+            Some(i) => (e.description[..i].to_string(),
+                serde_json::from_str(&e.description[i + 1..]).unwrap()),
+            None => (e.description, json!({})),
+        }
+    }
+
+    #[test]
+    fn every_cause() {
+        let causes = vec![
+            (DenyReason::Unauthenticated,
+                "unauthenticated: Authentication required", json!({})),
+            (DenyReason::Unauthorized { object: "lab.laser".into(), action: "write".into() },
+                "unauthorized: Permission denied, requires write on lab.laser",
+                json!({ "object": "lab.laser", "action": "write" })),
+            (DenyReason::NoSuchMachine, "no-such-machine: No such machine", json!({})),
+            (DenyReason::Occupied { occupant: Some("alice".into()), since: Some(1_000_000) },
+                "occupied: Machine is occupied by alice",
+                json!({ "occupant": "alice", "since": 1_000_000 })),
+            (DenyReason::Occupied { occupant: None, since: None },
+                "occupied: Machine is occupied", json!({})),
+            (DenyReason::Blocked { reason: Some("broken lens".into()), until: Some(1_003_600) },
+                "blocked: Machine is blocked: broken lens",
+                json!({ "reason": "broken lens", "until": 1_003_600 })),
+            (DenyReason::Blocked { reason: None, until: None },
+                "blocked: Machine is blocked", json!({})),
+            (DenyReason::Reserved { until: 1_000_300 },
+                "reserved: Machine is reserved for the next user in the queue",
+                json!({ "until": 1_000_300 })),
+            (DenyReason::Closed { opens: Some(1_030_000) },
+                "closed: Machine is outside of its opening hours", json!({ "opens": 1_030_000 })),
+            (DenyReason::Closed { opens: None },
+                "closed: Machine is outside of its opening hours", json!({})),
+        ];
+        for (reason, first, params) in causes {
+            assert_eq!(sent(reason.clone()), (first.to_string(), params), "{:?}", reason);
+        }
+    }
+
+    #[test]
+    fn hiding_the_occupant() {
+        let mut reason = DenyReason::Occupied { occupant: Some("alice".into()), since: Some(1) };
+        reason.hide_occupant();
+        assert_eq!(reason, DenyReason::Occupied { occupant: None, since: Some(1) });
+        assert_eq!(sent(reason), ("occupied: Machine is occupied".to_string(),
+            json!({ "since": 1 })));
+
+        // Nothing else to hide
+        let mut reason = DenyReason::Blocked { reason: Some("alice broke it".into()), until: None };
+        reason.hide_occupant();
+        assert_eq!(reason.code(), Code::Blocked);
+        assert_eq!(reason, DenyReason::Blocked { reason: Some("alice broke it".into()),
+            until: None });
+    }
+}
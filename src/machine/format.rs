@@ -0,0 +1,244 @@
+//! Versions of the TOML machine database and how to get from one to the next
+//!
+//! The database carries its version in a top-level `format_version` key, databases written before
+//! there was one are version 0. Older databases are upgraded one version at a time when loaded, so
+//! every migration only has to know about the version right before it. After any of them ran the
+//! original file is kept as a backup next to the database since an older daemon can't read the
+//! upgraded one anymore.
+//!
+//! Adding a version means appending a migration to `MIGRATIONS`. Migrations work on the plain
+//! TOML value instead of `Machine`, which only knows the latest version.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use toml::value::Table;
+use toml::Value;
+
+use crate::error::{Result, WithPath};
+
+use super::MachineDBError;
+use super::location;
+
+/// Key holding the version, next to the machines
+pub const VERSION_KEY: &str = "format_version";
+/// Key holding the locations by id, next to the machines. Can't be mistaken for a machine since
+/// it isn't a UUID.
+pub const LOCATIONS_KEY: &str = "locations";
+
+/// `MIGRATIONS[n]` upgrades a database from version `n` to `n + 1`
+const MIGRATIONS: &[fn(&mut Table) -> std::result::Result<(), String>] = &[
+    v0_to_v1,
+    v1_to_v2,
+    v2_to_v3,
+];
+
+/// The version databases are written in
+pub const CURRENT: u32 = MIGRATIONS.len() as u32;
+
+/// Version 1 only introduced `format_version` itself, but everything else has to be a machine so
+/// the key can't be mistaken for one.
+fn v0_to_v1(db: &mut Table) -> std::result::Result<(), String> {
+    for (key, value) in db.iter() {
+        if !value.is_table() {
+            return Err(format!("entry \"{}\" is not a machine", key));
+        }
+    }
+    Ok(())
+}
+
+/// Version 2 added opening hours, which machines of older versions don't have. Older versions of
+/// us would drop them on saving, so this only keeps them from reading the database.
+fn v1_to_v2(_db: &mut Table) -> std::result::Result<(), String> {
+    Ok(())
+}
+
+/// Version 3 moved locations into a table of their own, with machines referring to them by id.
+/// Every free-text location becomes one, see `location`.
+fn v2_to_v3(db: &mut Table) -> std::result::Result<(), String> {
+    let mut locations = Table::new();
+    for (key, value) in db.iter_mut() {
+        let machine = value.as_table_mut()
+            .ok_or_else(|| format!("entry \"{}\" is not a machine", key))?;
+        let name = match machine.get("location") {
+            Some(Value::String(s)) => s.trim().to_string(),
+            Some(_) => return Err(format!("location of machine {} is not a string", key)),
+            None => continue,
+        };
+        let id = location::id(&name);
+        if !id.is_empty() && !locations.contains_key(&id) {
+            let mut location = Table::new();
+            location.insert("name".to_string(), Value::String(name));
+            locations.insert(id.clone(), Value::Table(location));
+        }
+        machine.insert("location".to_string(), Value::String(id));
+    }
+    db.insert(LOCATIONS_KEY.to_string(), Value::Table(locations));
+    Ok(())
+}
+
+/// Where the database at `path` is kept as it was before upgrading it from `version`
+pub fn backup_path(path: &Path, version: u32) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".v{}.bak", version));
+    PathBuf::from(backup)
+}
+
+/// Bring the database at `path` with the content `db` to the current version
+///
+/// Returns the version it was in if it had to be upgraded, after backing up the file. Nothing is
+/// written to `path` itself, that happens with the next save.
+pub fn upgrade(path: &Path, db: &mut Table) -> Result<Option<u32>> {
+    let version = match db.remove(VERSION_KEY) {
+        None => 0,
+        Some(Value::Integer(v)) if v >= 0 && v <= u32::MAX as i64 => v as u32,
+        Some(v) => return Err(MachineDBError::InvalidVersion(v.to_string()).into()),
+    };
+    if version > CURRENT {
+        return Err(MachineDBError::TooNew(version, CURRENT).into());
+    }
+    if version == CURRENT {
+        return Ok(None);
+    }
+
+    // Written before any migration so a failing one can't leave us without the original
+    let backup = backup_path(path, version);
+    fs::copy(path, &backup).with_path(&backup)?;
+
+    for (from, migrate) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migrate(db).map_err(|e| MachineDBError::Migration(from as u32 + 1, e))?;
+    }
+    Ok(Some(version))
+}
+
+/// `db` as it is saved, with the current version
+pub fn versioned(db: Value) -> Value {
+    match db {
+        Value::Table(mut t) => {
+            t.insert(VERSION_KEY.to_string(), Value::Integer(CURRENT as i64));
+            Value::Table(t)
+        },
+        v => v,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uuid::Uuid;
+
+    use crate::machine;
+    use crate::testing::TempDir;
+
+    /// As written before there were versions, with both machines in the same room
+    const V0: &str = r#"
+[00000000-0000-0000-0000-00001a5e0001]
+name = "Laser"
+location = "Wood Shop"
+status = "Free"
+perm = "lab.laser"
+
+[00000000-0000-0000-0000-00001a5e0002]
+name = "Saw"
+location = " Wood  Shop "
+status = "Free"
+perm = "lab.saw"
+"#;
+
+    const LASER: Uuid = Uuid::from_u128(0x1a5e_0001);
+
+    fn table(toml: &str) -> Table {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn location<'a>(db: &'a Table, machine: &str) -> Option<&'a str> {
+        db[machine].get("location").and_then(Value::as_str)
+    }
+
+    #[test]
+    fn v0_to_v1() {
+        let mut db = table(V0);
+        assert_eq!(super::v0_to_v1(&mut db), Ok(()));
+        assert_eq!(db, table(V0));
+
+        let mut db = table(&format!("stray = 1\n{}", V0));
+        assert_eq!(super::v0_to_v1(&mut db), Err("entry \"stray\" is not a machine".to_string()));
+    }
+
+    #[test]
+    fn v1_to_v2() {
+        let mut db = table(V0);
+        assert_eq!(super::v1_to_v2(&mut db), Ok(()));
+        assert_eq!(db, table(V0));
+    }
+
+    #[test]
+    fn v2_to_v3() {
+        let nowhere = r#"
+[00000000-0000-0000-0000-00001a5e0003]
+name = "Nowhere"
+status = "Free"
+"#;
+        let mut db = table(&format!("{}{}", V0, nowhere));
+        assert_eq!(super::v2_to_v3(&mut db), Ok(()));
+        assert_eq!(db[LOCATIONS_KEY], Value::Table(table("wood_shop = { name = \"Wood Shop\" }")));
+        assert_eq!(location(&db, "00000000-0000-0000-0000-00001a5e0001"), Some("wood_shop"));
+        assert_eq!(location(&db, "00000000-0000-0000-0000-00001a5e0002"), Some("wood_shop"));
+        assert_eq!(location(&db, "00000000-0000-0000-0000-00001a5e0003"), None);
+
+        let mut db = table("[00000000-0000-0000-0000-00001a5e0001]\nlocation = 3\n");
+        assert!(super::v2_to_v3(&mut db).unwrap_err().contains("not a string"));
+    }
+
+    #[test]
+    fn upgrading_keeps_a_backup() {
+        let dir = TempDir::new();
+        let path = dir.join("machines.toml");
+        fs::write(&path, V0).unwrap();
+        let mut db = table(V0);
+        assert_eq!(upgrade(&path, &mut db).unwrap(), Some(0));
+        assert_eq!(fs::read_to_string(backup_path(&path, 0)).unwrap(), V0);
+        assert!(!db.contains_key(VERSION_KEY));
+        assert!(db.contains_key(LOCATIONS_KEY));
+
+        // Once saved it's current and left alone
+        let mut db = match versioned(Value::Table(db)) {
+            Value::Table(t) => t,
+            _ => unreachable!(),
+        };
+        let upgraded = db.clone();
+        fs::remove_file(backup_path(&path, 0)).unwrap();
+        assert_eq!(upgrade(&path, &mut db).unwrap(), None);
+        db.insert(VERSION_KEY.to_string(), Value::Integer(CURRENT as i64));
+        assert_eq!(db, upgraded);
+        assert!(!backup_path(&path, 0).exists());
+    }
+
+    #[test]
+    fn unknown_versions() {
+        let dir = TempDir::new();
+        let path = dir.join("machines.toml");
+        let mut db = table(&format!("{} = {}\n", VERSION_KEY, CURRENT + 1));
+        assert!(upgrade(&path, &mut db).unwrap_err().to_string().contains("is newer than"));
+        let mut db = table(&format!("{} = \"three\"\n", VERSION_KEY));
+        assert!(upgrade(&path, &mut db).unwrap_err().to_string()
+            .contains("is not a version number"));
+    }
+
+    #[test]
+    fn loading_an_old_database() {
+        let dir = TempDir::new();
+        let path = dir.join("machines.toml");
+        fs::write(&path, V0).unwrap();
+        let (mdb, locations, upgraded) = machine::load(&path).unwrap();
+        assert_eq!(upgraded, Some(0));
+        assert_eq!(mdb[&LASER].location, "wood_shop");
+        assert_eq!(locations["wood_shop"].name, "Wood Shop");
+
+        machine::save(&path, &mdb, &locations).unwrap();
+        let (reloaded, _, upgraded) = machine::load(&path).unwrap();
+        assert_eq!(upgraded, None);
+        assert_eq!(reloaded, mdb);
+    }
+}
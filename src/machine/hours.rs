@@ -0,0 +1,348 @@
+//! When machines may be used, e.g. only during supervised hours
+//!
+//! Opening hours are given in local time of `machines.timezone`, so they keep meaning the same
+//! wall clock times across daylight saving changes. Everything here works on UNIX seconds handed
+//! in by the caller, which gets them from the clock of `MachinesProvider`.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, TimeZone, Timelike, Weekday};
+use chrono::offset::LocalResult;
+use chrono_tz::Tz;
+
+use serde::{Serialize, Deserialize};
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// In the order of the bits of `OpeningHours::days`
+const WEEKDAYS: [Weekday; 7] = [
+    Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri, Weekday::Sat,
+    Weekday::Sun,
+];
+
+/// A time window on some days of the week, e.g. Monday to Friday from 10:00 to 22:00
+///
+/// Windows end on the day they start. Opening past midnight takes a second window starting at
+/// 00:00 the day after.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "RawHours", into = "RawHours")]
+pub struct OpeningHours {
+    /// Bit 0 is Monday
+    days: u8,
+    /// Minutes after midnight
+    from: u32,
+    /// Minutes after midnight, up to a whole day
+    to: u32,
+}
+
+/// How opening hours are written down, e.g.
+/// `{ days = ["Mon-Fri", "Sun"], from = "10:00", to = "22:00" }`
+#[derive(Serialize, Deserialize)]
+struct RawHours {
+    days: Vec<String>,
+    from: String,
+    to: String,
+}
+
+impl TryFrom<RawHours> for OpeningHours {
+    type Error = String;
+
+    fn try_from(raw: RawHours) -> Result<Self, String> {
+        let mut days = 0;
+        for d in raw.days.iter() {
+            days |= parse_days(d)?;
+        }
+        if days == 0 {
+            return Err("opening hours apply to no day at all".to_string());
+        }
+        let from = parse_time(&raw.from)?;
+        let to = parse_time(&raw.to)?;
+        if from >= to {
+            return Err(format!("opening hours from {} to {} end before they start, use a second \
+                window for times after midnight", raw.from, raw.to));
+        }
+        Ok(Self { days, from, to })
+    }
+}
+
+impl From<OpeningHours> for RawHours {
+    fn from(h: OpeningHours) -> Self {
+        let days = WEEKDAYS.iter().enumerate()
+            .filter(|(i, _)| h.days & (1 << i) != 0)
+            .map(|(_, d)| format!("{:?}", d))
+            .collect();
+        RawHours { days, from: format_time(h.from), to: format_time(h.to) }
+    }
+}
+
+/// `Mon`, `monday` or a range like `Mon-Fri`, which may wrap around like `Sat-Mon`
+fn parse_days(s: &str) -> Result<u8, String> {
+    let day = |d: &str| Weekday::from_str(d.trim())
+        .map(|d| d.num_days_from_monday())
+        .map_err(|_| format!("unknown day \"{}\"", d));
+    let (first, last) = match s.find('-') {
+        Some(i) => (day(&s[..i])?, day(&s[i + 1..])?),
+        None => (day(s)?, day(s)?),
+    };
+    let mut days = 0;
+    let mut d = first;
+    loop {
+        days |= 1 << d;
+        if d == last {
+            return Ok(days);
+        }
+        d = (d + 1) % 7;
+    }
+}
+
+/// `HH:MM`, in minutes after midnight. `24:00` is the end of the day.
+fn parse_time(s: &str) -> Result<u32, String> {
+    let invalid = || format!("invalid time \"{}\", expected HH:MM", s);
+    let mut parts = s.splitn(2, ':');
+    let h: u32 = parts.next().and_then(|h| h.parse().ok()).ok_or_else(invalid)?;
+    let m: u32 = parts.next().and_then(|m| m.parse().ok()).ok_or_else(invalid)?;
+    if m >= 60 || h * 60 + m > MINUTES_PER_DAY {
+        return Err(invalid());
+    }
+    Ok(h * 60 + m)
+}
+
+fn format_time(minutes: u32) -> String {
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+impl fmt::Display for OpeningHours {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let raw = RawHours::from(self.clone());
+        write!(f, "{} {}-{}", raw.days.join(","), raw.from, raw.to)
+    }
+}
+
+impl OpeningHours {
+    fn on(&self, day: Weekday) -> bool {
+        self.days & (1 << day.num_days_from_monday()) != 0
+    }
+}
+
+/// Whether `hours` allow using a machine at `at`, in UNIX seconds. No hours at all allow any time.
+pub fn allows(hours: &[OpeningHours], tz: Tz, at: u64) -> bool {
+    if hours.is_empty() {
+        return true;
+    }
+    let local = tz.timestamp(at as i64, 0);
+    let minute = local.hour() * 60 + local.minute();
+    hours.iter().any(|h| h.on(local.weekday()) && h.from <= minute && minute < h.to)
+}
+
+/// When `hours` next allow using a machine, the earliest time from `at` on
+///
+/// `None` if they never do, which can't happen for hours that passed validation.
+pub fn next_open(hours: &[OpeningHours], tz: Tz, at: u64) -> Option<u64> {
+    if allows(hours, tz, at) {
+        return Some(at);
+    }
+    let today = tz.timestamp(at as i64, 0).date().naive_local();
+    // A week and a day covers every window, including one that started today before `at`
+    (0..=7).map(|d| today + Duration::days(d))
+        .flat_map(|date| hours.iter().filter(move |h| h.on(date.weekday()))
+            .flat_map(move |h| vec![
+                (resolve(tz, date, h.from), resolve(tz, date, h.to)),
+                // Windows in the hour repeated when daylight saving time ends open again in it
+                (resolve_late(tz, date, h.from), resolve_late(tz, date, h.to)),
+            ]))
+        .filter(|&(_, end)| end > at)
+        .map(|(start, _)| start.max(at))
+        .min()
+}
+
+/// When the window `at` is in ends, following on into windows that start right when it ends
+///
+/// `None` if `hours` don't allow using the machine at `at` or are empty.
+pub fn closes_at(hours: &[OpeningHours], tz: Tz, at: u64) -> Option<u64> {
+    if hours.is_empty() {
+        return None;
+    }
+    let mut end = window_end(hours, tz, at)?;
+    // Each step is at least a minute, a week of them covers windows going all around the clock
+    for _ in 0..(7 * MINUTES_PER_DAY) {
+        match window_end(hours, tz, end) {
+            Some(e) if e > end => end = e,
+            _ => break,
+        }
+    }
+    Some(end)
+}
+
+/// The latest end of the windows `at` is in
+fn window_end(hours: &[OpeningHours], tz: Tz, at: u64) -> Option<u64> {
+    let local = tz.timestamp(at as i64, 0);
+    let minute = local.hour() * 60 + local.minute();
+    let date = local.date().naive_local();
+    hours.iter()
+        .filter(|h| h.on(local.weekday()) && h.from <= minute && minute < h.to)
+        .filter_map(|h| {
+            // In the hour repeated when daylight saving time ends it's the end still to come
+            let ends = [resolve(tz, date, h.to), resolve_late(tz, date, h.to)];
+            ends.iter().copied().filter(|&end| end > at).min()
+        })
+        .max()
+}
+
+/// The UNIX time `minute` minutes after midnight of `date` in `tz`
+///
+/// Times falling into the gap of a change to daylight saving time are moved on by the length of
+/// the gap, like the clocks are. Times that happen twice when it ends are the earlier one, see
+/// `resolve_late` for the other.
+fn resolve(tz: Tz, date: NaiveDate, minute: u32) -> u64 {
+    let mut local: NaiveDateTime = date.and_hms(0, 0, 0) + Duration::minutes(minute as i64);
+    // Offsets of all time zones are multiples of a quarter hour, and no gap is longer than a day
+    for _ in 0..(24 * 4) {
+        match tz.from_local_datetime(&local) {
+            LocalResult::Single(t) | LocalResult::Ambiguous(t, _) => return t.timestamp() as u64,
+            LocalResult::None => local = local + Duration::minutes(15),
+        }
+    }
+    // Not a time zone that exists on this planet
+    local.timestamp().max(0) as u64
+}
+
+/// Like `resolve`, but the later of times that happen twice
+fn resolve_late(tz: Tz, date: NaiveDate, minute: u32) -> u64 {
+    let local = date.and_hms(0, 0, 0) + Duration::minutes(minute as i64);
+    match tz.from_local_datetime(&local) {
+        LocalResult::Ambiguous(_, t) => t.timestamp() as u64,
+        _ => resolve(tz, date, minute),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Opening hours as written in the config, e.g. `"Mon-Fri", "10:00", "22:00"`
+    fn hours(days: &str, from: &str, to: &str) -> Result<OpeningHours, String> {
+        let raw = RawHours {
+            days: days.split(',').map(str::to_string).collect(),
+            from: from.to_string(),
+            to: to.to_string(),
+        };
+        OpeningHours::try_from(raw)
+    }
+
+    fn berlin() -> Tz {
+        "Europe/Berlin".parse().unwrap()
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(hours("Mon-Fri", "10:00", "22:00").unwrap().to_string(),
+            "Mon,Tue,Wed,Thu,Fri 10:00-22:00");
+        assert_eq!(hours("Sat-Mon,wednesday", "00:00", "24:00").unwrap().to_string(),
+            "Mon,Wed,Sat,Sun 00:00-24:00");
+
+        assert!(hours("Mon", "22:00", "02:00").unwrap_err().contains("end before they start"));
+        assert!(hours("Mon", "10:00", "10:00").is_err());
+        assert_eq!(hours("Someday", "10:00", "12:00"), Err("unknown day \"Someday\"".to_string()));
+        assert!(hours("Mon", "10:00", "24:01").is_err());
+        assert!(hours("Mon", "10:60", "12:00").is_err());
+        assert!(hours("Mon", "10", "12:00").is_err());
+        assert!(hours("", "10:00", "12:00").is_err());
+    }
+
+    #[test]
+    fn weekdays() {
+        let weekdays = [hours("Mon-Fri", "10:00", "22:00").unwrap()];
+        // Monday, 2021-06-07
+        let monday_9 = 1623056400;
+        assert!(!allows(&weekdays, Tz::UTC, monday_9));
+        assert!(allows(&weekdays, Tz::UTC, monday_9 + 3600));
+        assert!(!allows(&weekdays, Tz::UTC, monday_9 + 13 * 3600));
+        assert_eq!(next_open(&weekdays, Tz::UTC, monday_9), Some(monday_9 + 3600));
+        assert_eq!(next_open(&weekdays, Tz::UTC, monday_9 + 7200), Some(monday_9 + 7200));
+        assert_eq!(closes_at(&weekdays, Tz::UTC, monday_9 + 3600), Some(monday_9 + 13 * 3600));
+        assert_eq!(closes_at(&weekdays, Tz::UTC, monday_9), None);
+
+        // Saturday morning it's Monday again
+        let saturday_9 = monday_9 + 5 * 24 * 3600;
+        let next_monday_10 = monday_9 + 7 * 24 * 3600 + 3600;
+        assert_eq!(next_open(&weekdays, Tz::UTC, saturday_9), Some(next_monday_10));
+
+        // No hours, no restrictions
+        assert!(allows(&[], Tz::UTC, saturday_9));
+        assert_eq!(next_open(&[], Tz::UTC, saturday_9), Some(saturday_9));
+        assert_eq!(closes_at(&[], Tz::UTC, saturday_9), None);
+    }
+
+    #[test]
+    fn open_past_midnight() {
+        let late = [
+            hours("Mon", "20:00", "24:00").unwrap(),
+            hours("Tue", "00:00", "02:00").unwrap(),
+        ];
+        let monday_21 = 1623099600;
+        assert_eq!(closes_at(&late, Tz::UTC, monday_21), Some(monday_21 + 5 * 3600));
+    }
+
+    #[test]
+    fn start_of_daylight_saving_time() {
+        let tz = berlin();
+        // Sunday, 2021-03-28. Clocks go from 02:00 straight to 03:00, which is 01:00 UTC.
+        let at_1_utc = 1616893200;
+        let saturday_evening = at_1_utc - 5 * 3600;
+
+        // Half of it never happens, it opens when the clocks say 03:00
+        let night = [hours("Sun", "02:30", "04:00").unwrap()];
+        assert_eq!(next_open(&night, tz, saturday_evening), Some(at_1_utc));
+        assert!(allows(&night, tz, at_1_utc + 1800));
+        assert_eq!(closes_at(&night, tz, at_1_utc + 1800), Some(at_1_utc + 3600));
+
+        // A window across the change is an hour shorter, from 23:00 to 04:00 UTC
+        let early = [hours("Sun", "00:00", "06:00").unwrap()];
+        let midnight = at_1_utc - 2 * 3600;
+        assert!(!allows(&early, tz, midnight - 1));
+        assert_eq!(next_open(&early, tz, saturday_evening), Some(midnight));
+        assert_eq!(closes_at(&early, tz, midnight), Some(midnight + 5 * 3600));
+
+        // One that starts after the change is as long as ever, the clocks just say CEST
+        let day = [hours("Sun", "10:00", "22:00").unwrap()];
+        let at_10 = at_1_utc + 7 * 3600;
+        assert!(!allows(&day, tz, at_10 - 1));
+        assert!(allows(&day, tz, at_10));
+        assert_eq!(closes_at(&day, tz, at_10), Some(at_10 + 12 * 3600));
+    }
+
+    #[test]
+    fn end_of_daylight_saving_time() {
+        let tz = berlin();
+        // Sunday, 2021-10-31. Clocks go from 03:00 back to 02:00 at 01:00 UTC, so 02:00 to 03:00
+        // happens twice.
+        let first = 1635638400;
+        let second = first + 3600;
+
+        // The window is open both times, and closes at the end of the one it's open in
+        let night = [hours("Sun", "02:00", "02:45").unwrap()];
+        assert!(allows(&night, tz, first + 1800));
+        assert_eq!(closes_at(&night, tz, first + 1800), Some(first + 2700));
+        assert!(!allows(&night, tz, first + 3000));
+        assert_eq!(next_open(&night, tz, first + 3000), Some(second));
+        assert!(allows(&night, tz, second + 1800));
+        assert_eq!(closes_at(&night, tz, second + 1800), Some(second + 2700));
+
+        // A window across the change is an hour longer, from 22:00 to 05:00 UTC
+        let early = [hours("Sun", "00:00", "06:00").unwrap()];
+        let midnight = first - 2 * 3600;
+        assert!(!allows(&early, tz, midnight - 1));
+        assert!(allows(&early, tz, midnight));
+        assert!(allows(&early, tz, second + 3600));
+        assert_eq!(closes_at(&early, tz, midnight), Some(midnight + 7 * 3600));
+
+        // One that starts after the change is as long as ever
+        let day = [hours("Sun", "10:00", "22:00").unwrap()];
+        let at_10 = first + 9 * 3600;
+        assert!(allows(&day, tz, at_10));
+        assert!(!allows(&day, tz, at_10 - 1));
+        assert_eq!(closes_at(&day, tz, at_10), Some(at_10 + 12 * 3600));
+    }
+}
@@ -0,0 +1,285 @@
+//! Write-ahead journal of machines being used and given back
+//!
+//! With `machines.write_interval` uses and givebacks are only saved every few seconds, so a crash
+//! would lose the last of them, and with them who used which machine for how long. Each of them is
+//! appended to a journal next to the machine database before it's applied instead. On startup the
+//! journal is replayed over the database, which is then saved and the journal emptied. The same
+//! happens every time the database is saved, so the journal only ever holds what isn't in it yet.
+//!
+//! Every record is the length of its payload and the CRC-32 of it, both as little-endian `u32`,
+//! followed by the payload, a `Transition` as JSON. A crash while appending leaves a torn record
+//! at the end which is shorter than its length says or fails its CRC. It and everything after it
+//! are skipped.
+
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+use uuid::Uuid;
+
+use crate::error::{Result, WithPath};
+
+use super::{Machine, Status};
+use super::store::MachineStore;
+
+/// Bytes before the payload of a record, its length and CRC
+const HEADER: usize = 8;
+/// Longest payload we believe a length for. A transition is a few hundred bytes at most, anything
+/// longer is garbage.
+const MAX_RECORD: usize = 64 * 1024;
+
+/// Where the journal of the machine database at `machinedb` is kept
+pub fn path(machinedb: &Path) -> PathBuf {
+    let mut path = machinedb.as_os_str().to_owned();
+    path.push(".journal");
+    PathBuf::from(path)
+}
+
+/// The state a machine was put in, everything that changes when it's used or given back
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transition {
+    pub uuid: Uuid,
+    pub status: Status,
+    pub since: Option<u64>,
+    pub occupant: Option<String>,
+    pub grant: Option<Uuid>,
+    pub note: Option<String>,
+}
+
+impl Transition {
+    pub fn apply(self, m: &mut Machine) {
+        m.status = self.status;
+        m.since = self.since;
+        m.occupant = self.occupant;
+        m.grant = self.grant;
+        m.note = self.note;
+    }
+}
+
+/// What was found in a journal
+pub struct Replay {
+    pub transitions: Vec<Transition>,
+    /// Bytes at the end that weren't a complete record
+    pub skipped: usize,
+}
+
+/// Read the journal at `path`, which is empty if it doesn't exist
+pub fn read(path: &Path) -> Result<Replay> {
+    let data = match fs::read(path) {
+        Ok(d) => d,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e).with_path(path),
+    };
+
+    let mut transitions = Vec::new();
+    let mut pos = 0;
+    while data.len() - pos >= HEADER {
+        let len = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(data[pos + 4..pos + HEADER].try_into().unwrap());
+        let start = pos + HEADER;
+        if len > MAX_RECORD || data.len() - start < len {
+            break;
+        }
+        let payload = &data[start..start + len];
+        if crc32(payload) != crc {
+            break;
+        }
+        match serde_json::from_slice(payload) {
+            Ok(t) => transitions.push(t),
+            // Written by a newer version of us or mangled in a way the CRC didn't catch. Nothing
+            // after it can be trusted to be what it looks like either.
+            Err(_) => break,
+        }
+        pos = start + len;
+    }
+
+    Ok(Replay { transitions, skipped: data.len() - pos })
+}
+
+/// Apply what's in the journal at `path` to `mdb`
+///
+/// If `save` is set the database is saved afterwards and the journal emptied. Transitions of
+/// machines that don't exist anymore are ignored.
+pub fn recover(mdb: &mut dyn MachineStore, path: &Path, save: bool) -> Result<Replay> {
+    let replay = read(path)?;
+    for t in replay.transitions.iter() {
+        if let Some(m) = mdb.get_mut(&t.uuid) {
+            t.clone().apply(m);
+        }
+    }
+    if save && (!replay.transitions.is_empty() || replay.skipped > 0) {
+        mdb.flush()?;
+        let fp = OpenOptions::new().write(true).open(path).with_path(path)?;
+        fp.set_len(0).with_path(path)?;
+        fp.sync_all().with_path(path)?;
+    }
+    Ok(replay)
+}
+
+/// The journal, open for appending
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    /// Where the last complete record ends
+    len: u64,
+    /// Records appended between two syncs to disk
+    sync_every: u32,
+    unsynced: u32,
+}
+
+impl Journal {
+    /// Open the journal at `path` for appending, creating it if it doesn't exist
+    ///
+    /// Whatever is in it has to be recovered first.
+    pub fn open(path: &Path, sync_every: u32) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path).with_path(path)?;
+        let len = file.metadata().with_path(path)?.len();
+        Ok(Self { path: path.to_path_buf(), file, len, sync_every: sync_every.max(1),
+            unsynced: 0 })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append `t`, syncing it to disk if it completes a batch
+    ///
+    /// Once this returned the record survives us crashing. Only with a batch size of 1 does it
+    /// also survive the machine losing power. If it fails the journal is cut back to where it
+    /// was, so a record that was only partly written can't hide the ones appended after it.
+    pub fn append(&mut self, t: &Transition) -> io::Result<()> {
+        let payload = serde_json::to_vec(t)?;
+        let mut record = Vec::with_capacity(HEADER + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+
+        if let Err(e) = self.write(&record) {
+            // If this fails too the torn record stays and replaying stops at it. The caller saves
+            // the database right away, which empties the journal.
+            let _ = self.file.set_len(self.len);
+            return Err(e);
+        }
+        self.len += record.len() as u64;
+        Ok(())
+    }
+
+    fn write(&mut self, record: &[u8]) -> io::Result<()> {
+        // A single write, so a crash can only ever tear the last record
+        self.file.write_all(record)?;
+        self.unsynced += 1;
+        if self.unsynced >= self.sync_every {
+            self.file.sync_data()?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    /// Forget everything appended so far, once the database it was for was saved
+    pub fn truncate(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.len = 0;
+        self.file.sync_all()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+}
+
+/// CRC-32 as used by zlib and Ethernet
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn transition(n: u128, user: &str) -> Transition {
+        Transition {
+            uuid: Uuid::from_u128(n),
+            status: Status::Occupied,
+            since: Some(1_000_000),
+            occupant: Some(user.to_string()),
+            grant: Some(Uuid::from_u128(n + 100)),
+            note: None,
+        }
+    }
+
+    #[test]
+    fn torn_records_are_skipped() {
+        let dir = TempDir::new();
+        let path = dir.join("machines.toml.journal");
+        let written = vec![transition(1, "alice"), transition(2, "bob"), transition(3, "carol")];
+        let mut journal = Journal::open(&path, 1).unwrap();
+        let mut ends = Vec::new();
+        for t in written.iter() {
+            journal.append(t).unwrap();
+            ends.push(fs::metadata(&path).unwrap().len());
+        }
+        drop(journal);
+        let replay = read(&path).unwrap();
+        assert_eq!(replay.transitions, written);
+        assert_eq!(replay.skipped, 0);
+
+        // Crashing anywhere in the last record, even in its header, leaves the others
+        let data = fs::read(&path).unwrap();
+        for torn in &[ends[1] + 1, ends[1] + HEADER as u64, ends[2] - 1] {
+            fs::write(&path, &data[..*torn as usize]).unwrap();
+            let replay = read(&path).unwrap();
+            assert_eq!(replay.transitions, written[..2].to_vec());
+            assert_eq!(replay.skipped as u64, torn - ends[1]);
+        }
+
+        // A flipped bit fails the CRC, which hides everything after it too
+        let mut data = data[..ends[1] as usize].to_vec();
+        data[ends[0] as usize + HEADER + 2] ^= 1;
+        fs::write(&path, &data).unwrap();
+        let replay = read(&path).unwrap();
+        assert_eq!(replay.transitions, written[..1].to_vec());
+        assert_eq!(replay.skipped as u64, ends[1] - ends[0]);
+    }
+
+    #[test]
+    fn appending_after_recovering() {
+        let dir = TempDir::new();
+        let path = dir.join("machines.toml.journal");
+        let mut journal = Journal::open(&path, 3).unwrap();
+        journal.append(&transition(1, "alice")).unwrap();
+        drop(journal);
+
+        // Picks up where the file ends, and truncating empties it
+        let mut journal = Journal::open(&path, 3).unwrap();
+        journal.append(&transition(2, "bob")).unwrap();
+        assert_eq!(read(&path).unwrap().transitions,
+            vec![transition(1, "alice"), transition(2, "bob")]);
+        journal.truncate().unwrap();
+        journal.append(&transition(3, "carol")).unwrap();
+        assert_eq!(read(&path).unwrap().transitions, vec![transition(3, "carol")]);
+    }
+
+    #[test]
+    fn failed_appends_are_taken_back() {
+        // Every write to it fails for lack of space
+        let mut journal = Journal::open(Path::new("/dev/full"), 1).unwrap();
+        assert!(journal.append(&transition(1, "alice")).is_err());
+        assert_eq!(journal.len, 0);
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
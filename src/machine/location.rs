@@ -0,0 +1,76 @@
+//! Rooms and other places machines are grouped by
+//!
+//! Machines refer to their location by its id, which is what their perm template sees as
+//! `{location}`. Locations used to be a free-text string on every machine instead; the ids were
+//! chosen so that the string turned into a perm segment is the id of the location it became, so
+//! derived perms stay the same. Strings that only differed in case or punctuation become the same
+//! location, named after one of them.
+
+use std::collections::BTreeMap;
+
+use serde::{Serialize, Deserialize};
+
+/// A place machines are at, e.g. a room of the space
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct Location {
+    /// What users see, e.g. "Wood Workshop"
+    pub name: String,
+    /// Permission object whose `read` is needed to see any machine at the location. Usually the
+    /// prefix the perms of those machines share. Without one every machine is checked on its own.
+    #[serde(default)]
+    pub perm: Option<String>,
+}
+
+/// All locations, by id
+pub type Locations = BTreeMap<String, Location>;
+
+/// The id a location named `name` gets. Empty for no location at all.
+pub fn id(name: &str) -> String {
+    if name.trim().is_empty() {
+        String::new()
+    } else {
+        super::perm_segment(name)
+    }
+}
+
+/// The id of the location `location` refers to, adding one named by it if there is none
+///
+/// `location` may be the id of a location or the free-text name machines had before. Returns
+/// whether a location had to be added.
+pub fn adopt(locations: &mut Locations, location: &str) -> (String, bool) {
+    if location.is_empty() || locations.contains_key(location) {
+        return (location.to_string(), false);
+    }
+    let id = id(location);
+    if id.is_empty() || locations.contains_key(&id) {
+        return (id, false);
+    }
+    locations.insert(id.clone(), Location { name: location.trim().to_string(), perm: None });
+    (id, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids() {
+        assert_eq!(id("Wood Workshop"), "wood_workshop");
+        assert_eq!(id("  Wood   workshop "), "wood_workshop");
+        assert_eq!(id("3D-Druck / Elektronik"), "3d-druck_elektronik");
+        assert_eq!(id(" "), "");
+    }
+
+    #[test]
+    fn adopting() {
+        let mut locations = Locations::new();
+        assert_eq!(adopt(&mut locations, ""), (String::new(), false));
+        assert_eq!(adopt(&mut locations, "Wood Workshop"), ("wood_workshop".to_string(), true));
+        // By its id, and by a name that comes out as the same id
+        assert_eq!(adopt(&mut locations, "wood_workshop"), ("wood_workshop".to_string(), false));
+        assert_eq!(adopt(&mut locations, "Wood  Workshop"), ("wood_workshop".to_string(), false));
+        assert_eq!(locations.len(), 1);
+        assert_eq!(locations["wood_workshop"],
+            Location { name: "Wood Workshop".to_string(), perm: None });
+    }
+}
@@ -0,0 +1,154 @@
+//! Taking the lock on the machine state, giving up or at least complaining if it takes too long
+//!
+//! All machines share one lock. Nothing holds it while checking permissions or waiting on anything
+//! else outside of it, scans copy what they need and let go again, so it's normally only ever held
+//! for a moment. A lock per machine wouldn't buy much over that: the database is saved as a whole
+//! and who's using what, the queues and the journal all span machines.
+//!
+//! Saving the database to a slow disk or a pile of writers can still keep it for a while though,
+//! and without a deadline every call on every connection would queue up behind that. Calls give up
+//! after `machines.lock_timeout` instead and tell the client to retry shortly, with code
+//! `overloaded`.
+//!
+//! Background tasks like the schedule don't answer to anybody and keep waiting for the lock, see
+//! `Background`.
+
+use std::time::{Duration, Instant};
+
+use slog::Logger;
+
+use async_std::future;
+use async_std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::api::error::{self, RetryAfter};
+
+/// How long a call waits for the lock unless configured otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub async fn read<T>(lock: &RwLock<T>, timeout: Duration)
+    -> Result<RwLockReadGuard<'_, T>, capnp::Error>
+{
+    future::timeout(timeout, lock.read()).await.map_err(|_| busy())
+}
+
+pub async fn write<T>(lock: &RwLock<T>, timeout: Duration)
+    -> Result<RwLockWriteGuard<'_, T>, capnp::Error>
+{
+    future::timeout(timeout, lock.write()).await.map_err(|_| busy())
+}
+
+/// The lock couldn't be had in time. Whatever held it is usually done within a second.
+fn busy() -> capnp::Error {
+    error::overloaded_retry("The machine database is busy", RetryAfter::for_depth(0))
+}
+
+/// Taking the lock for a background task, which waits for as long as it takes
+///
+/// Waiting longer than calls would is logged though, since calls give up in the meantime.
+#[derive(Clone)]
+pub struct Background {
+    log: Logger,
+    timeout: Duration,
+}
+
+impl Background {
+    pub fn new(log: Logger, timeout: Duration) -> Self {
+        Self { log, timeout }
+    }
+
+    /// Take the read lock in order to `what`, e.g. "look at the schedule"
+    pub async fn read<'a, T>(&self, lock: &'a RwLock<T>, what: &str) -> RwLockReadGuard<'a, T> {
+        let start = Instant::now();
+        match future::timeout(self.timeout, lock.read()).await {
+            Ok(guard) => guard,
+            Err(_) => {
+                self.waiting(what);
+                let guard = lock.read().await;
+                self.waited(what, start);
+                guard
+            },
+        }
+    }
+
+    /// Take the write lock in order to `what`
+    pub async fn write<'a, T>(&self, lock: &'a RwLock<T>, what: &str) -> RwLockWriteGuard<'a, T> {
+        let start = Instant::now();
+        match future::timeout(self.timeout, lock.write()).await {
+            Ok(guard) => guard,
+            Err(_) => {
+                self.waiting(what);
+                let guard = lock.write().await;
+                self.waited(what, start);
+                guard
+            },
+        }
+    }
+
+    fn waiting(&self, what: &str) {
+        warn!(self.log, "Waiting more than {}s for the machine database to {}, calls are failing \
+            in the meantime", self.timeout.as_secs(), what);
+    }
+
+    fn waited(&self, what: &str, start: Instant) {
+        info!(self.log, "Got the machine database to {} after {}ms", what,
+            start.elapsed().as_millis());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_std::sync::Arc;
+    use async_std::task;
+    use futures::channel::oneshot;
+    use futures::executor::LocalPool;
+    use futures::task::LocalSpawnExt;
+
+    use crate::testing::{logger, rpc};
+
+    #[test]
+    fn calls_give_up_on_slow_holders() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = rpc::Server::with_config(&spawner, |c| c.machines.lock_timeout = 1).await;
+            let alice = server.connect(&spawner).await;
+            alice.login_as("alice").await;
+
+            // Like a save to a disk that takes its time
+            let mdb = server.api.machines();
+            let held = mdb.write().await;
+            let e = alice.status(rpc::PRINTER).await.err().unwrap();
+            assert_eq!(rpc::code(&e), "overloaded");
+            drop(held);
+            assert!(alice.status(rpc::PRINTER).await.is_ok());
+        });
+    }
+
+    #[test]
+    fn background_tasks_wait() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let lock = Arc::new(RwLock::new(0));
+            let holder = lock.clone();
+            let (tx, taken) = oneshot::channel();
+            spawner.spawn_local(async move {
+                let mut held = holder.write().await;
+                tx.send(()).unwrap();
+                task::sleep(Duration::from_millis(200)).await;
+                *held += 1;
+            }).unwrap();
+            taken.await.unwrap();
+
+            let start = Instant::now();
+            let e = read(&lock, Duration::from_millis(20)).await.err().unwrap();
+            assert_eq!(rpc::code(&e), "overloaded");
+            let background = Background::new(logger(), Duration::from_millis(20));
+            *background.write(&lock, "count").await += 1;
+            assert!(start.elapsed() >= Duration::from_millis(150));
+            assert_eq!(*read(&lock, DEFAULT_TIMEOUT).await.unwrap(), 2);
+        });
+    }
+}
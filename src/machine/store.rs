@@ -0,0 +1,656 @@
+//! Storage backends for the machine database
+
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use slog::Logger;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use uuid::Uuid;
+
+use crate::config::{Config, MachineBackend};
+use crate::error::{Result, WithPath};
+
+use super::{format, Machine, MachineDB, MachineDBError, ScheduledBlock, Status};
+use super::hours::OpeningHours;
+use super::location::{self, Location, Locations};
+
+/// Storage of machine records
+///
+/// All backends keep the full set of machines in memory, so reads never touch the disk. Changes
+/// made through `get_mut`, `insert` and `remove` are only guaranteed to be durable once `flush`
+/// returned successfully.
+pub trait MachineStore: Send + Sync {
+    fn get(&self, uuid: &Uuid) -> Option<&Machine>;
+    fn get_mut(&mut self, uuid: &Uuid) -> Option<&mut Machine>;
+    fn insert(&mut self, uuid: Uuid, machine: Machine) -> Option<Machine>;
+    fn remove(&mut self, uuid: &Uuid) -> Option<Machine>;
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item=(&'a Uuid, &'a Machine)> + 'a>;
+    fn len(&self) -> usize;
+
+    /// All locations machines can be at, by id
+    fn locations(&self) -> &Locations;
+    fn locations_mut(&mut self) -> &mut Locations;
+
+    /// Write all changes since the last flush to disk
+    fn flush(&mut self) -> Result<()>;
+
+    /// How the database was upgraded when opening it, if it had to be
+    fn upgraded_from(&self) -> Option<Upgrade> {
+        None
+    }
+
+    /// Remove all machines
+    fn clear(&mut self) {
+        let uuids: Vec<Uuid> = self.iter().map(|(u, _)| u.clone()).collect();
+        for uuid in uuids.iter() {
+            self.remove(uuid);
+        }
+    }
+}
+
+/// A database that was in an older format when it was opened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upgrade {
+    pub from: u32,
+    pub to: u32,
+    /// Where the database is kept as it was before
+    pub backup: PathBuf,
+}
+
+/// Open the machine store configured in `config`
+pub fn open(config: &Config) -> Result<Box<dyn MachineStore>> {
+    let mut store: Box<dyn MachineStore> = match config.machines.backend {
+        MachineBackend::Toml => Box::new(FileStore::open(&config.machinedb)?),
+        MachineBackend::Sled => Box::new(SledStore::open(&config.machinedb)?),
+    };
+    adopt_locations(store.as_mut());
+    Ok(store)
+}
+
+/// Make every machine refer to its location by id, adding locations for those that don't
+///
+/// TOML databases of older versions get their locations when they're upgraded, see `format`. This
+/// is for sled databases, whose versions only concern the encoding, and for machines with a
+/// location that was typed in by hand. Returns how many machines had to be changed.
+pub fn adopt_locations(store: &mut dyn MachineStore) -> usize {
+    let machines: Vec<(Uuid, String)> = store.iter()
+        .filter(|(_, m)| !m.location.is_empty() && !store.locations().contains_key(&m.location))
+        .map(|(u, m)| (u.clone(), m.location.clone()))
+        .collect();
+    for (uuid, name) in machines.iter() {
+        let (id, _) = location::adopt(store.locations_mut(), name);
+        if let Some(m) = store.get_mut(uuid) {
+            m.location = id;
+        }
+    }
+    machines.len()
+}
+
+/// The whole database as a single TOML file that is rewritten on every flush
+pub struct FileStore {
+    path: PathBuf,
+    db: MachineDB,
+    locations: Locations,
+    dirty: bool,
+    upgraded_from: Option<u32>,
+}
+
+impl FileStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        let (db, locations, upgraded_from) = super::load(path)?;
+        // An upgraded database is only in the new format once saved
+        let dirty = upgraded_from.is_some();
+        Ok(Self { path: path.to_path_buf(), db, locations, dirty, upgraded_from })
+    }
+}
+
+impl MachineStore for FileStore {
+    fn get(&self, uuid: &Uuid) -> Option<&Machine> {
+        self.db.get(uuid)
+    }
+
+    fn get_mut(&mut self, uuid: &Uuid) -> Option<&mut Machine> {
+        // We can't know if the caller actually changes anything so assume they do
+        self.dirty = true;
+        self.db.get_mut(uuid)
+    }
+
+    fn insert(&mut self, uuid: Uuid, machine: Machine) -> Option<Machine> {
+        self.dirty = true;
+        self.db.insert(uuid, machine)
+    }
+
+    fn remove(&mut self, uuid: &Uuid) -> Option<Machine> {
+        self.dirty = true;
+        self.db.remove(uuid)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item=(&'a Uuid, &'a Machine)> + 'a> {
+        Box::new(self.db.iter())
+    }
+
+    fn len(&self) -> usize {
+        self.db.len()
+    }
+
+    fn locations(&self) -> &Locations {
+        &self.locations
+    }
+
+    fn locations_mut(&mut self) -> &mut Locations {
+        self.dirty = true;
+        &mut self.locations
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.dirty {
+            super::save(&self.path, &self.db, &self.locations)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn upgraded_from(&self) -> Option<Upgrade> {
+        self.upgraded_from.map(|from| Upgrade {
+            from,
+            to: format::CURRENT,
+            backup: format::backup_path(&self.path, from),
+        })
+    }
+
+    fn clear(&mut self) {
+        self.dirty = true;
+        self.db.clear();
+    }
+}
+
+/// Another store that's never written to, for dry runs
+///
+/// Instead of saving, every flush logs which machines would have been saved and writes all of
+/// them to `path`, so what would have ended up in the database can be looked at.
+pub struct DryRun {
+    log: Logger,
+    inner: Box<dyn MachineStore>,
+    path: PathBuf,
+    changed: BTreeSet<Uuid>,
+    locations_changed: bool,
+}
+
+impl DryRun {
+    pub fn new(log: Logger, inner: Box<dyn MachineStore>, path: PathBuf) -> Self {
+        Self { log, inner, path, changed: BTreeSet::new(), locations_changed: false }
+    }
+}
+
+impl MachineStore for DryRun {
+    fn get(&self, uuid: &Uuid) -> Option<&Machine> {
+        self.inner.get(uuid)
+    }
+
+    fn get_mut(&mut self, uuid: &Uuid) -> Option<&mut Machine> {
+        self.changed.insert(uuid.clone());
+        self.inner.get_mut(uuid)
+    }
+
+    fn insert(&mut self, uuid: Uuid, machine: Machine) -> Option<Machine> {
+        self.changed.insert(uuid.clone());
+        self.inner.insert(uuid, machine)
+    }
+
+    fn remove(&mut self, uuid: &Uuid) -> Option<Machine> {
+        self.changed.insert(uuid.clone());
+        self.inner.remove(uuid)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item=(&'a Uuid, &'a Machine)> + 'a> {
+        self.inner.iter()
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn locations(&self) -> &Locations {
+        self.inner.locations()
+    }
+
+    fn locations_mut(&mut self) -> &mut Locations {
+        self.locations_changed = true;
+        self.inner.locations_mut()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.changed.is_empty() && !self.locations_changed {
+            return Ok(());
+        }
+        if std::mem::take(&mut self.locations_changed) {
+            info!(self.log, "Dry run, would save {} locations", self.inner.locations().len());
+        }
+        for uuid in std::mem::take(&mut self.changed) {
+            match self.inner.get(&uuid) {
+                Some(m) => info!(self.log, "Dry run, would save machine {}", uuid;
+                    "name" => m.name.as_str(), "status" => m.status.as_str()),
+                None => info!(self.log, "Dry run, would remove machine {}", uuid),
+            }
+        }
+        let db = self.inner.iter().map(|(u, m)| (u.clone(), m.clone())).collect();
+        super::save(&self.path, &db, self.inner.locations())
+    }
+
+    fn upgraded_from(&self) -> Option<Upgrade> {
+        self.inner.upgraded_from()
+    }
+}
+
+/// Name of the sled tree holding the locations, next to the default one holding the machines
+const LOCATIONS_TREE: &str = "locations";
+
+/// Key holding the version, next to the machines. Can't be mistaken for one since it isn't 16
+/// bytes long like a UUID.
+const VERSION_KEY: &[u8] = b"format_version";
+
+/// The version sled databases are written in
+///
+/// Version 0 had no version key and encoded records with bincode, which can't tell a field is
+/// missing and so broke the database whenever `Machine` got a new one. Since version 1 records are
+/// JSON, so like in the TOML database a field with a default can be added without a migration.
+/// Anything else, like moving data around, gets a version of its own.
+pub const SLED_CURRENT: u32 = 1;
+
+/// Machines stored as individual JSON records in a sled database
+///
+/// Flushing only writes the records that actually changed so this stays cheap with many machines.
+/// Locations are kept in a tree of their own, by id, and are all rewritten when one changed since
+/// there are only ever a few of them.
+pub struct SledStore {
+    path: PathBuf,
+    db: sled::Db,
+    cache: MachineDB,
+    dirty: HashSet<Uuid>,
+    locations_tree: sled::Tree,
+    locations: Locations,
+    locations_dirty: bool,
+    /// Whether the version key is written already
+    versioned: bool,
+    upgraded_from: Option<u32>,
+}
+
+impl SledStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = sled::open(path).with_path(path)?;
+        let locations_tree = db.open_tree(LOCATIONS_TREE)?;
+
+        let stored = db.get(VERSION_KEY)?;
+        let versioned = stored.is_some();
+        let version = match stored {
+            Some(v) => serde_json::from_slice(&v).map_err(|_|
+                MachineDBError::InvalidVersion(String::from_utf8_lossy(&v).into_owned()))?,
+            // Databases that were just created are written in the current version right away
+            None if db.is_empty() && locations_tree.is_empty() => SLED_CURRENT,
+            None => 0,
+        };
+        if version > SLED_CURRENT {
+            return Err(MachineDBError::TooNew(version, SLED_CURRENT).into());
+        }
+
+        let mut cache = MachineDB::new();
+        for r in db.iter() {
+            let (k, v) = r?;
+            if k.as_ref() == VERSION_KEY {
+                continue;
+            }
+            let uuid = Uuid::from_slice(&k)?;
+            let machine = if version == 0 {
+                // Upgrading may have been interrupted after some records were rewritten already
+                serde_json::from_slice(&v).ok().or_else(|| v0::machine(&v)).ok_or_else(||
+                    MachineDBError::Migration(1, format!("machine {} is in none of the formats \
+                        bincode was used for", uuid)))?
+            } else {
+                serde_json::from_slice(&v)?
+            };
+            cache.insert(uuid, machine);
+        }
+        super::validate(cache.iter())?;
+
+        let mut locations = Locations::new();
+        for r in locations_tree.iter() {
+            let (k, v) = r?;
+            let location: Location = if version == 0 {
+                match serde_json::from_slice(&v) {
+                    Ok(l) => l,
+                    Err(_) => bincode::deserialize(&v)?,
+                }
+            } else {
+                serde_json::from_slice(&v)?
+            };
+            locations.insert(String::from_utf8_lossy(&k).into_owned(), location);
+        }
+
+        // Every record is rewritten on the next flush, until then older versions of us can still
+        // read the database but they wouldn't anymore afterwards. The version is written last, so
+        // the upgrade is simply done again if it doesn't finish.
+        let upgraded_from = if version < SLED_CURRENT { Some(version) } else { None };
+        let dirty = match upgraded_from {
+            Some(from) => {
+                // Unless that's the second try, and what's there already is the actual original
+                let backup = format::backup_path(path, from);
+                if !backup.exists() {
+                    db.flush()?;
+                    copy_dir(path, &backup).with_path(&backup)?;
+                }
+                cache.keys().cloned().collect()
+            },
+            None => HashSet::new(),
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            db,
+            cache,
+            dirty,
+            locations_tree,
+            locations,
+            locations_dirty: upgraded_from.is_some(),
+            versioned: versioned && upgraded_from.is_none(),
+            upgraded_from,
+        })
+    }
+}
+
+/// Copy the directory `from` with everything in it to `to`
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Records of version 0, bincode-encoded in whichever layout `Machine` had when they were written
+mod v0 {
+    use super::*;
+
+    /// Decode a machine record, trying the layouts from the newest to the oldest
+    ///
+    /// bincode only writes the fields one after the other, so an older record runs out of bytes
+    /// long before a newer layout is complete and a newer one has bytes left over in an older
+    /// layout. Only a layout that uses up exactly the whole record is taken.
+    pub fn machine(record: &[u8]) -> Option<Machine> {
+        if let Some(m) = exactly::<Machine>(record) {
+            return Some(m);
+        }
+        // Before notes
+        if let Some((name, location, status, perm, since, occupant, grant, block_reason,
+            giveback_at_close, allowed_hours, schedule)) = exactly::<(String, String, Status,
+            String, Option<u64>, Option<String>, Option<Uuid>, Option<String>, bool,
+            Vec<OpeningHours>, Vec<ScheduledBlock>)>(record)
+        {
+            let mut m = base(name, location, status, perm);
+            m.since = since;
+            m.occupant = occupant;
+            m.grant = grant;
+            m.block_reason = block_reason;
+            m.giveback_at_close = giveback_at_close;
+            m.allowed_hours = allowed_hours;
+            m.schedule = schedule;
+            return Some(m);
+        }
+        // Before opening hours
+        if let Some((name, location, status, perm, since, occupant, grant, block_reason,
+            schedule)) = exactly::<(String, String, Status, String, Option<u64>, Option<String>,
+            Option<Uuid>, Option<String>, Vec<ScheduledBlock>)>(record)
+        {
+            let mut m = base(name, location, status, perm);
+            m.since = since;
+            m.occupant = occupant;
+            m.grant = grant;
+            m.block_reason = block_reason;
+            m.schedule = schedule;
+            return Some(m);
+        }
+        // Before block reasons
+        if let Some((name, location, status, perm, since, occupant, grant, schedule)) =
+            exactly::<(String, String, Status, String, Option<u64>, Option<String>, Option<Uuid>,
+            Vec<ScheduledBlock>)>(record)
+        {
+            let mut m = base(name, location, status, perm);
+            m.since = since;
+            m.occupant = occupant;
+            m.grant = grant;
+            m.schedule = schedule;
+            return Some(m);
+        }
+        // Before occupants were kept
+        if let Some((name, location, status, perm, since, schedule)) = exactly::<(String, String,
+            Status, String, Option<u64>, Vec<ScheduledBlock>)>(record)
+        {
+            let mut m = base(name, location, status, perm);
+            m.since = since;
+            m.schedule = schedule;
+            return Some(m);
+        }
+        // Before schedules
+        if let Some((name, location, status, perm, since)) = exactly::<(String, String, Status,
+            String, Option<u64>)>(record)
+        {
+            let mut m = base(name, location, status, perm);
+            m.since = since;
+            return Some(m);
+        }
+        // As the sled backend started out
+        exactly::<(String, String, Status, String)>(record)
+            .map(|(name, location, status, perm)| base(name, location, status, perm))
+    }
+
+    fn base(name: String, location: String, status: Status, perm: String) -> Machine {
+        let mut m = Machine::new(name, location, perm);
+        m.status = status;
+        m
+    }
+
+    fn exactly<T: Serialize + DeserializeOwned>(record: &[u8]) -> Option<T> {
+        let t: T = bincode::deserialize(record).ok()?;
+        match bincode::serialized_size(&t) {
+            Ok(n) if n == record.len() as u64 => Some(t),
+            _ => None,
+        }
+    }
+}
+
+impl MachineStore for SledStore {
+    fn get(&self, uuid: &Uuid) -> Option<&Machine> {
+        self.cache.get(uuid)
+    }
+
+    fn get_mut(&mut self, uuid: &Uuid) -> Option<&mut Machine> {
+        let m = self.cache.get_mut(uuid);
+        if m.is_some() {
+            self.dirty.insert(uuid.clone());
+        }
+        m
+    }
+
+    fn insert(&mut self, uuid: Uuid, machine: Machine) -> Option<Machine> {
+        self.dirty.insert(uuid.clone());
+        self.cache.insert(uuid, machine)
+    }
+
+    fn remove(&mut self, uuid: &Uuid) -> Option<Machine> {
+        // A dirty UUID that is not in the cache anymore is removed from disk on flush
+        self.dirty.insert(uuid.clone());
+        self.cache.remove(uuid)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item=(&'a Uuid, &'a Machine)> + 'a> {
+        Box::new(self.cache.iter())
+    }
+
+    fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    fn locations(&self) -> &Locations {
+        &self.locations
+    }
+
+    fn locations_mut(&mut self) -> &mut Locations {
+        self.locations_dirty = true;
+        &mut self.locations
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        // All records go in one batch so a failure never leaves only some of them written, and we
+        // only forget about the dirty ones once it was applied so a failed flush can be retried.
+        let mut batch = sled::Batch::default();
+        for uuid in self.dirty.iter() {
+            if let Some(machine) = self.cache.get(uuid) {
+                batch.insert(&uuid.as_bytes()[..], serde_json::to_vec(machine)?);
+            } else {
+                batch.remove(&uuid.as_bytes()[..]);
+            }
+        }
+        self.db.apply_batch(batch)?;
+        self.dirty.clear();
+
+        if self.locations_dirty {
+            let mut batch = sled::Batch::default();
+            for r in self.locations_tree.iter().keys() {
+                let id = r?;
+                if !self.locations.contains_key(String::from_utf8_lossy(&id).as_ref()) {
+                    batch.remove(id);
+                }
+            }
+            for (id, location) in self.locations.iter() {
+                batch.insert(id.as_bytes(), serde_json::to_vec(location)?);
+            }
+            self.locations_tree.apply_batch(batch)?;
+            self.locations_dirty = false;
+            self.locations_tree.flush()?;
+        }
+        // Only once everything is written in it, see `open`
+        if !self.versioned {
+            self.db.insert(VERSION_KEY, serde_json::to_vec(&SLED_CURRENT)?)?;
+            self.versioned = true;
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn upgraded_from(&self) -> Option<Upgrade> {
+        self.upgraded_from.map(|from| Upgrade {
+            from,
+            to: SLED_CURRENT,
+            backup: format::backup_path(&self.path, from),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::{TempDir, LASER};
+
+    const SAW: Uuid = Uuid::from_u128(0x1a5e_0002);
+
+    #[test]
+    fn version_0_layouts() {
+        // Before notes
+        let record = bincode::serialize(&("Laser".to_string(), "wood_shop".to_string(),
+            Status::Occupied, "lab.laser".to_string(), Some(5u64), Some("alice".to_string()),
+            Some(SAW), None::<String>, true, Vec::<OpeningHours>::new(),
+            Vec::<ScheduledBlock>::new())).unwrap();
+        let m = v0::machine(&record).unwrap();
+        assert_eq!((m.status, m.since, m.occupant.as_deref(), m.grant, m.note, m.giveback_at_close),
+            (Status::Occupied, Some(5), Some("alice"), Some(SAW), None, true));
+
+        // As the sled backend started out
+        let record = bincode::serialize(&("Saw".to_string(), String::new(), Status::Blocked,
+            "lab.saw".to_string())).unwrap();
+        let mut saw = Machine::new("Saw".to_string(), String::new(), "lab.saw".to_string());
+        saw.status = Status::Blocked;
+        assert_eq!(v0::machine(&record), Some(saw.clone()));
+
+        // The latest one
+        saw.note = Some("sharpening".to_string());
+        assert_eq!(v0::machine(&bincode::serialize(&saw).unwrap()), Some(saw));
+
+        assert_eq!(v0::machine(b"garbage"), None);
+    }
+
+    #[test]
+    fn upgrading_from_bincode() {
+        let dir = TempDir::new();
+        let path = dir.join("machines.db");
+        {
+            let db = sled::open(&path).unwrap();
+            db.insert(LASER.as_bytes(), bincode::serialize(&("Laser".to_string(),
+                "wood_shop".to_string(), Status::Free, "lab.laser".to_string(), None::<u64>,
+                Vec::<ScheduledBlock>::new())).unwrap()).unwrap();
+            db.insert(SAW.as_bytes(), bincode::serialize(&("Saw".to_string(),
+                "wood_shop".to_string(), Status::Free, "lab.saw".to_string())).unwrap()).unwrap();
+            let location = Location { name: "Wood Shop".to_string(), perm: None };
+            db.open_tree(LOCATIONS_TREE).unwrap()
+                .insert("wood_shop", bincode::serialize(&location).unwrap()).unwrap();
+            db.flush().unwrap();
+        }
+
+        let backup = format::backup_path(&path, 0);
+        let mut store = SledStore::open(&path).unwrap();
+        assert_eq!(store.upgraded_from(), Some(Upgrade { from: 0, to: SLED_CURRENT,
+            backup: backup.clone() }));
+        assert!(backup.join("conf").exists());
+        assert_eq!(store.get(&LASER).unwrap().name, "Laser");
+        assert_eq!(store.get(&SAW).unwrap().perm, "lab.saw");
+        assert_eq!(store.locations()["wood_shop"].name, "Wood Shop");
+        store.flush().unwrap();
+        drop(store);
+
+        let store = SledStore::open(&path).unwrap();
+        assert_eq!(store.upgraded_from(), None);
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.locations().len(), 1);
+    }
+
+    #[test]
+    fn records_without_newer_fields() {
+        let dir = TempDir::new();
+        let path = dir.join("machines.db");
+        let mut store = SledStore::open(&path).unwrap();
+        assert_eq!(store.upgraded_from(), None);
+        let mut laser = Machine::new("Laser".to_string(), String::new(), "lab.laser".to_string());
+        laser.note = Some("engraving".to_string());
+        store.insert(LASER, laser);
+        store.flush().unwrap();
+        drop(store);
+
+        // As written before there were notes or opening hours
+        {
+            let db = sled::open(&path).unwrap();
+            let mut record: serde_json::Value =
+                serde_json::from_slice(&db.get(LASER.as_bytes()).unwrap().unwrap()).unwrap();
+            let fields = record.as_object_mut().unwrap();
+            for field in ["note", "giveback_at_close", "allowed_hours"].iter() {
+                assert!(fields.remove(*field).is_some(), "{} isn't saved", field);
+            }
+            db.insert(LASER.as_bytes(), serde_json::to_vec(&record).unwrap()).unwrap();
+            db.flush().unwrap();
+        }
+
+        let store = SledStore::open(&path).unwrap();
+        assert_eq!(store.upgraded_from(), None);
+        let laser = store.get(&LASER).unwrap();
+        assert_eq!((laser.note.as_deref(), laser.allowed_hours.len()), (None, 0));
+    }
+}
@@ -0,0 +1,271 @@
+//! Telling users that a machine they wait for became free, e.g. by a Matrix mention
+//!
+//! Unlike the queue this doesn't reserve anything, whoever comes first gets the machine. Users
+//! asking are kept in a table that survives restarts, until they were told once or they waited for
+//! longer than `machines.watch_expiry`.
+//!
+//! How they are told is up to channels, which modules register. They are tried in the order of
+//! `machines.notify_channels` until one of them reaches the user. The same goes for telling the
+//! head of a machine's queue that it's reserved for them now.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use futures::future::LocalBoxFuture;
+use futures::stream::StreamExt;
+
+use async_std::sync::{Arc, RwLock};
+
+use serde::{Serialize, Deserialize};
+
+use slog::Logger;
+
+use uuid::Uuid;
+
+use crate::auth::users::{self, UserInfo};
+use crate::error::{Result, WithPath};
+
+use super::{lock, MachinesProvider, Status};
+
+/// `user` wants to know when `machine` is free, until `expires`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Watch {
+    pub user: String,
+    pub machine: Uuid,
+    /// In seconds since the UNIX epoch
+    pub expires: u64,
+}
+
+/// How the table is stored, as a TOML array of tables
+#[derive(Default, Serialize, Deserialize)]
+struct Table {
+    #[serde(default, rename = "watch")]
+    watches: Vec<Watch>,
+}
+
+/// All users waiting for machines, saved to `path` after every change
+pub struct Watches {
+    /// Without a path nobody can wait
+    path: Option<PathBuf>,
+    watches: Vec<Watch>,
+}
+
+impl Watches {
+    /// Load the table at `path`, which is created on the first watch if it doesn't exist
+    pub fn open(path: &Path, now: u64) -> Result<Self> {
+        let watches = if path.exists() {
+            let content = fs::read_to_string(path)?;
+            let table: Table = toml::from_str(&content)?;
+            table.watches.into_iter().filter(|w| w.expires > now).collect()
+        } else {
+            Vec::new()
+        };
+        Ok(Self { path: Some(path.to_path_buf()), watches })
+    }
+
+    /// No table, for when it isn't configured
+    pub fn disabled() -> Self {
+        Self { path: None, watches: Vec::new() }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    /// Add `watch`, replacing an earlier one of the same user for the same machine
+    ///
+    /// Expired watches are dropped along the way, so the table doesn't grow forever.
+    pub fn add(&mut self, watch: Watch, now: u64) -> Result<()> {
+        self.watches.retain(|w| w.expires > now
+            && !(w.user == watch.user && w.machine == watch.machine));
+        self.watches.push(watch);
+        self.save()
+    }
+
+    /// Remove and return the unexpired watches for `machine`
+    pub fn take(&mut self, machine: &Uuid, now: u64) -> Result<Vec<Watch>> {
+        if !self.watches.iter().any(|w| w.machine == *machine) {
+            return Ok(Vec::new());
+        }
+        let (taken, kept): (Vec<Watch>, Vec<Watch>) = self.watches.drain(..)
+            .partition(|w| w.machine == *machine);
+        self.watches = kept;
+        self.save()?;
+        Ok(taken.into_iter().filter(|w| w.expires > now).collect())
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = match self.path {
+            Some(ref p) => p,
+            None => return Ok(()),
+        };
+        // Same dance as for the machine database, so a crash never leaves half a table behind
+        let tmp = path.with_extension("tmp");
+        {
+            let mut fp = File::create(&tmp).with_path(&tmp)?;
+            let toml = toml::to_string(&Table { watches: self.watches.clone() })?;
+            fp.write_all(toml.as_bytes()).with_path(&tmp)?;
+            fp.sync_all().with_path(&tmp)?;
+        }
+        fs::rename(&tmp, path).with_path(path)?;
+        Ok(())
+    }
+}
+
+/// What a user is told
+pub struct Notice<'a> {
+    pub user: &'a str,
+    pub contact: &'a UserInfo,
+    pub what: What<'a>,
+}
+
+pub enum What<'a> {
+    /// A machine they wait for became free
+    Free { machine: Uuid, name: &'a str },
+    /// A machine they were first in the queue for became free and is reserved for them until
+    /// `until`, in seconds since the UNIX epoch
+    Ready { machine: Uuid, name: &'a str, until: u64 },
+    /// Nothing, an admin wants to know whether the channel works
+    Test,
+}
+
+impl fmt::Display for Notice<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.what {
+            What::Free { machine, .. } => write!(f, "that machine {} is free", machine),
+            What::Ready { machine, until, .. } =>
+                write!(f, "that machine {} is reserved for them until {}", machine, until),
+            What::Test => write!(f, "about nothing, for testing"),
+        }
+    }
+}
+
+/// A way to reach users, like a chat
+pub trait Channel {
+    /// What `machines.notify_channels` calls it
+    fn name(&self) -> &'static str;
+
+    /// Tell the user of `notice` about it, failing with why if they couldn't be reached
+    fn send<'a>(&'a self, notice: &'a Notice<'a>)
+        -> LocalBoxFuture<'a, std::result::Result<(), String>>;
+}
+
+/// All channels there are, registered by whatever provides them while starting up
+#[derive(Clone)]
+pub struct Channels {
+    inner: Rc<RefCell<Vec<Rc<dyn Channel>>>>,
+    /// Where contact details of users are read from
+    users: Option<Rc<PathBuf>>,
+}
+
+impl Channels {
+    pub fn new(users: Option<PathBuf>) -> Self {
+        Self { inner: Rc::new(RefCell::new(Vec::new())), users: users.map(Rc::new) }
+    }
+
+    /// How `user` can be reached
+    ///
+    /// Read again every time, so changes to the contact details don't need a restart.
+    pub fn contact(&self, user: &str) -> Result<UserInfo> {
+        users::lookup(self.users.as_deref().map(PathBuf::as_path), user)
+    }
+
+    /// Add `channel`, replacing one with the same name
+    pub fn register(&self, channel: Rc<dyn Channel>) {
+        let mut inner = self.inner.borrow_mut();
+        inner.retain(|c| c.name() != channel.name());
+        inner.push(channel);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Rc<dyn Channel>> {
+        self.inner.borrow().iter().find(|c| c.name() == name).cloned()
+    }
+}
+
+/// Tell the users waiting for machines once they become free, for as long as we run
+///
+/// Besides everybody who asked, that's whoever the machine is reserved for now because they were
+/// first in its queue. `order` are the names of the channels to try.
+pub async fn deliver(log: Logger, mach: Arc<RwLock<MachinesProvider>>,
+    background: lock::Background, channels: Channels, order: Vec<String>)
+{
+    let mut changes = background.write(&mach, "subscribe to changes").await.subscribe();
+    while let Some(change) = changes.next().await {
+        if change.status != Status::Free {
+            continue;
+        }
+        let (watches, hold) = {
+            let mut mach = background.write(&mach, "tell users it's free").await;
+            let watches = match mach.take_watches(&change.uuid) {
+                Ok(w) => w,
+                Err(e) => {
+                    error!(log, "Failed to save who waits for machines: {}", e);
+                    Vec::new()
+                },
+            };
+            (watches, mach.hold(&change.uuid))
+        };
+
+        if let Some((user, until)) = hold {
+            let contact = contact(&log, &channels, &user);
+            let notice = Notice {
+                user: &user,
+                contact: &contact,
+                what: What::Ready { machine: change.uuid, name: &change.name, until },
+            };
+            if !notify(&log, &channels, &order, &notice).await {
+                warn!(log, "Could not tell {} that machine {} is reserved for them, no channel \
+                    reached them", user, change.uuid);
+            }
+        }
+
+        for watch in watches {
+            let contact = contact(&log, &channels, &watch.user);
+            let notice = Notice {
+                user: &watch.user,
+                contact: &contact,
+                what: What::Free { machine: change.uuid, name: &change.name },
+            };
+            if !notify(&log, &channels, &order, &notice).await {
+                warn!(log, "Could not tell {} that machine {} is free, no channel reached them",
+                    watch.user, change.uuid);
+            }
+        }
+    }
+}
+
+/// How `user` can be reached, as far as we know
+fn contact(log: &Logger, channels: &Channels, user: &str) -> UserInfo {
+    channels.contact(user).unwrap_or_else(|e| {
+        warn!(log, "Could not read the contact details of {}: {}", user, e);
+        UserInfo::default()
+    })
+}
+
+/// Try the channels in `order` until one reaches the user, returning whether one did
+async fn notify(log: &Logger, channels: &Channels, order: &[String], notice: &Notice<'_>)
+    -> bool
+{
+    for name in order {
+        let channel = match channels.get(name) {
+            Some(c) => c,
+            None => {
+                debug!(log, "Skipping notification channel {}, it isn't set up", name);
+                continue;
+            },
+        };
+        match channel.send(notice).await {
+            Ok(()) => {
+                info!(log, "Told {} {}", notice.user, notice; "channel" => name);
+                return true;
+            },
+            Err(e) => info!(log, "Could not tell {} {}: {}", notice.user, notice, e;
+                "channel" => name),
+        }
+    }
+    false
+}
@@ -12,10 +12,29 @@ mod api;
 mod config;
 mod error;
 mod machine;
+mod cli;
+mod connection;
+mod listen;
+#[cfg(feature = "systemd")]
+mod systemd;
+mod privileges;
+mod daemon;
+mod dryrun;
+mod status;
+mod audit;
+mod events;
+mod webhook;
+mod stats;
+mod shutdown;
+mod gc;
+mod cards;
+mod websocket;
+#[cfg(test)]
+mod testing;
 
 use signal_hook::iterator::Signals;
 
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand, AppSettings};
 
 use api::api as api_capnp;
 
@@ -28,29 +47,61 @@ use futures::task::{SpawnExt, LocalSpawn};
 use capnp_rpc::twoparty::{VatNetwork, VatId};
 use capnp_rpc::rpc_twoparty_capnp::Side;
 
-use async_std::net::{TcpListener, TcpStream};
 
+use std::fmt;
 use std::io;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::mem::drop;
 
 use std::sync::Arc;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::time::Duration;
 
-use error::Error;
+use async_std::task;
 
 use api::API;
 
-// Returning a `Result` from `main` allows us to use the `?` shorthand.
-// In the case of an Err it will be printed using `fmt::Debug`
-fn main() -> Result<(), Error> {
-    // Initialize signal handler.
-    // Specifically, this is a Stream of c_int representing received signals
-    // We currently only care about Ctrl-C so SIGINT it is.
-    // TODO: Make this do SIGHUP and a few others too.
-    let signals = Signals::new(&[signal_hook::SIGINT])?.into_async()?;
+/// Exit code for failures not covered by one of the more specific codes below
+const EXIT_FAILURE: i32 = 1;
+/// The config file could not be loaded
+const EXIT_CONFIG: i32 = 2;
+/// No listening socket could be set up
+const EXIT_BIND: i32 = 3;
+/// The machine database could not be opened
+const EXIT_DATABASE: i32 = 4;
+/// Authentication, access control or dropping privileges could not be set up
+const EXIT_ACCESS: i32 = 5;
+
+/// Why starting up failed and the exit code to report it with
+struct Failure {
+    code: i32,
+    msg: String,
+}
+
+/// Attach an exit code and a description of what we were trying to do to an error
+trait OrFail<T> {
+    fn or_fail(self, code: i32, what: impl fmt::Display) -> Result<T, Failure>;
+}
+
+impl<T, E: fmt::Display> OrFail<T> for Result<T, E> {
+    fn or_fail(self, code: i32, what: impl fmt::Display) -> Result<T, Failure> {
+        self.map_err(|e| Failure { code, msg: format!("{}: {}", what, e) })
+    }
+}
+
+fn main() {
+    // Whatever went wrong gets a single line on stderr instead of a Debug dump, and an exit code
+    // service managers and scripts can tell apart.
+    if let Err(f) = run() {
+        eprintln!("{}", f.msg);
+        std::process::exit(f.code);
+    }
+}
 
+fn run() -> Result<(), Failure> {
     use clap::{crate_version, crate_description, crate_name};
 
     // Argument parsing
@@ -58,29 +109,329 @@ fn main() -> Result<(), Error> {
     let matches = App::new(crate_name!())
         .about(crate_description!())
         .version(crate_version!())
+        .after_help("EXIT CODES:\n    \
+            1    Any failure not listed below\n    \
+            2    The config file could not be loaded\n    \
+            3    No listening socket could be set up\n    \
+            4    The machine database could not be opened\n    \
+            5    Authentication, access control or dropping privileges could not be set up")
         .arg(Arg::with_name("config")
-            .help("Path to the config file to use")
+            .help("Path to the config file to use. Values in it can be overridden with \
+                DIFLOUROBORANE_<KEY> environment variables, using __ to separate nested keys.")
             .long("config")
             .short("c")
             .takes_value(true)
         )
         .arg(Arg::with_name("print default")
-            .help("Print a default config to stdout instead of running")
+            .help("Print a default config with explanations to stdout instead of running")
             .long("print-default")
         )
+        .arg(Arg::with_name("output")
+            .help("Write the default config to this file instead of stdout. It is only readable by \
+                its owner since it may contain secrets.")
+            .long("output")
+            .short("o")
+            .takes_value(true)
+            .requires("print default")
+        )
+        .arg(Arg::with_name("config format")
+            .help("Format to print the default config in, TOML by default. Only TOML comes with \
+                explanations.")
+            .long("format")
+            .takes_value(true)
+            .possible_values(&["toml", "yaml", "json"])
+            .requires("print default")
+        )
+        .arg(Arg::with_name("verbose")
+            .help("Log more. Given once shows debug messages, twice shows everything")
+            .short("v")
+            .multiple(true)
+            .conflicts_with("quiet")
+        )
+        .arg(Arg::with_name("quiet")
+            .help("Only log warnings and errors")
+            .short("q")
+        )
+        .arg(Arg::with_name("check")
+            .help("Check the config file for problems, exiting with 0 if there are none and 1 \
+                otherwise")
+            .long("check")
+        )
+        .arg(Arg::with_name("insecure permissions ok")
+            .help("Start even if others than root and the daemon user could change the config, \
+                the password database, the policy, the temporary grants or the machine database")
+            .long("insecure-permissions-ok")
+        )
+        .arg(Arg::with_name("pidfile")
+            .help("Write the PID to this file once started")
+            .long("pidfile")
+            .takes_value(true)
+        )
+        .arg(Arg::with_name("healthcheck")
+            .help("Check if the server is up and healthy, exiting with 0 if it is and 1 otherwise")
+            .long("healthcheck")
+        )
+        .arg(Arg::with_name("daemonize")
+            .help("Fork into the background. Requires a log file to be configured.")
+            .long("daemonize")
+        )
+        .arg(Arg::with_name("dry run")
+            .help("Run without switching any device or saving anything, for trying out a config. \
+                Files are written to a temporary directory instead, the MQTT bridge, notifiers \
+                and plugins aren't started.")
+            .long("dry-run")
+        )
+        .subcommand(SubCommand::with_name("status")
+            .about("Print the state of all machines and the health of the running server, exiting \
+                with 0 if it could be reached and 1 otherwise")
+            .arg(Arg::with_name("format")
+                .help("Format to print in")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["text", "json", "openmetrics"])
+                .default_value("text")
+            )
+        )
+        .subcommand(SubCommand::with_name("stats")
+            .about("Print the statistics in stats.path. Counts the running server didn't write out \
+                yet are missing.")
+            .arg(Arg::with_name("from")
+                .help("First day to count, as YYYY-MM-DD")
+                .long("from")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("to")
+                .help("Last day to count, as YYYY-MM-DD")
+                .long("to")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("format")
+                .help("Format to print in")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text")
+            )
+        )
+        .subcommand(SubCommand::with_name("test-email")
+            .about("Send a test mail with the settings in [modules.email]")
+            .arg(Arg::with_name("address")
+                .help("Where to send it")
+                .required(true)
+            )
+        )
+        .subcommand(SubCommand::with_name("machine")
+            .about("Manage the machine database")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("add")
+                .about("Add a new machine and print its generated UUID")
+                .arg(Arg::with_name("name")
+                    .help("Display name of the machine")
+                    .long("name")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("location")
+                    .help("Where the machine can be found, the id or name of a location. New names \
+                        add a location.")
+                    .long("location")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("perm")
+                    .help("Permission object required to use the machine, derived from \
+                        machines.perm_template if not given")
+                    .long("perm")
+                    .takes_value(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("list")
+                .about("List all known machines")
+            )
+            .subcommand(SubCommand::with_name("location")
+                .about("Add or change a location, or list all of them if no id is given")
+                .arg(Arg::with_name("id")
+                    .help("Id of the location, lowercase letters, digits, '-' and '_'")
+                )
+                .arg(Arg::with_name("name")
+                    .help("What users see, the id for new locations if not given")
+                    .long("name")
+                    .takes_value(true)
+                    .requires("id")
+                )
+                .arg(Arg::with_name("perm")
+                    .help("Permission object whose read is needed to see any machine at the \
+                        location, empty for none")
+                    .long("perm")
+                    .takes_value(true)
+                    .empty_values(true)
+                    .requires("id")
+                )
+            )
+            .subcommand(SubCommand::with_name("remove")
+                .about("Remove a machine")
+                .arg(Arg::with_name("uuid")
+                    .help("UUID of the machine to remove")
+                    .required(true)
+                    .validator(|s| uuid::Uuid::parse_str(&s)
+                        .map(|_| ())
+                        .map_err(|e| e.to_string()))
+                )
+            )
+            .subcommand(SubCommand::with_name("export")
+                .about("Write the machine database to stdout")
+                .arg(Arg::with_name("format")
+                    .help("Format to export in")
+                    .long("format")
+                    .takes_value(true)
+                    .possible_values(&["json", "toml"])
+                    .default_value("json")
+                )
+            )
+            .subcommand(SubCommand::with_name("import")
+                .about("Import machines from a file")
+                .arg(Arg::with_name("format")
+                    .help("Format of the file to import")
+                    .long("format")
+                    .takes_value(true)
+                    .possible_values(&["json", "toml"])
+                    .default_value("json")
+                )
+                .arg(Arg::with_name("merge")
+                    .help("Add to the existing machines, replacing those with the same UUID (default)")
+                    .long("merge")
+                    .conflicts_with("replace")
+                )
+                .arg(Arg::with_name("replace")
+                    .help("Replace the entire database with the imported machines")
+                    .long("replace")
+                )
+                .arg(Arg::with_name("file")
+                    .help("File to import from")
+                    .required(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("migrate")
+                .about("Copy all machines from a TOML machine database into the configured backend")
+                .arg(Arg::with_name("file")
+                    .help("TOML machine database to migrate from")
+                    .required(true)
+                )
+            )
+        )
+        .subcommand(SubCommand::with_name("user")
+            .about("Manage the password database. Passwords are read from stdin and have to follow \
+                the rules in [auth.policy].")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("add")
+                .about("Add a new user")
+                .arg(Arg::with_name("name")
+                    .help("Name the user logs in with")
+                    .required(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("passwd")
+                .about("Change the password of a user")
+                .arg(Arg::with_name("name")
+                    .help("Name the user logs in with")
+                    .required(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("contact")
+                .about("Set how a user can be reached, in auth.users. An empty value removes it.")
+                .arg(Arg::with_name("name")
+                    .help("Name the user logs in with")
+                    .required(true)
+                )
+                .arg(Arg::with_name("email")
+                    .help("Email address")
+                    .long("email")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("matrix")
+                    .help("Matrix user id, like @alice:example.org")
+                    .long("matrix")
+                    .takes_value(true)
+                )
+            )
+            .subcommand(SubCommand::with_name("import")
+                .about("Create and update users from a CSV file, e.g. a membership export. Users \
+                    that don't exist yet get a random password. Prints what changed for every \
+                    user and a summary.")
+                .arg(Arg::with_name("csv")
+                    .help("CSV file to import from")
+                    .long("csv")
+                    .takes_value(true)
+                    .required(true)
+                )
+                .arg(Arg::with_name("column name")
+                    .help("Column holding the name users log in with, counting from 1")
+                    .long("column-name")
+                    .takes_value(true)
+                    .required(true)
+                    .validator(cli::column_arg)
+                )
+                .arg(Arg::with_name("column email")
+                    .help("Column holding the email address, stored in auth.users")
+                    .long("column-email")
+                    .takes_value(true)
+                    .validator(cli::column_arg)
+                )
+                .arg(Arg::with_name("role")
+                    .help("Role every imported user is assigned in the policy")
+                    .long("role")
+                    .takes_value(true)
+                )
+                .arg(Arg::with_name("header")
+                    .help("Skip the first line, it names the columns")
+                    .long("header")
+                )
+                .arg(Arg::with_name("delimiter")
+                    .help("Character separating the columns")
+                    .long("delimiter")
+                    .takes_value(true)
+                    .default_value(",")
+                    .validator(|s| if s.chars().count() == 1 && s != "\"" {
+                        Ok(())
+                    } else {
+                        Err("must be a single character other than \"".to_string())
+                    })
+                )
+                .arg(Arg::with_name("dry run")
+                    .help("Only print what would change")
+                    .long("dry-run")
+                )
+                .arg(Arg::with_name("strict")
+                    .help("Change nothing if any row is malformed instead of skipping those")
+                    .long("strict")
+                )
+                .arg(Arg::with_name("show passwords")
+                    .help("Print the random passwords of created users")
+                    .long("show-passwords")
+                    .conflicts_with("dry run")
+                )
+            )
+        )
         .get_matches();
 
     // Check for the --print-default option first because we don't need to do anything else in that
     // case.
     if matches.is_present("print default") {
-        let config = config::Config::default();
-        let encoded = toml::to_vec(&config)?;
-
-        // Direct writing to fd 1 is faster but also prevents any print-formatting that could
-        // invalidate the generated TOML
-        let stdout = io::stdout();
-        let mut handle = stdout.lock();
-        handle.write_all(&encoded)?;
+        // clap already made sure it's one of the formats we know
+        let format = matches.value_of("config format").unwrap_or("toml")
+            .parse::<config::FileFormat>().unwrap();
+        if let Some(path) = matches.value_of("output") {
+            config::write_default(Path::new(path), format)
+                .or_fail(EXIT_FAILURE, format!("Could not write {}", path))?;
+        } else {
+            let config = config::default_as(format)
+                .or_fail(EXIT_FAILURE, "Could not generate default config")?;
+            // Direct writing to fd 1 is faster but also prevents any print-formatting that could
+            // invalidate the generated TOML
+            let stdout = io::stdout();
+            let mut handle = stdout.lock();
+            handle.write_all(config.as_bytes()).or_fail(EXIT_FAILURE, "Could not write config")?;
+        }
 
         // Early return to exit.
         return Ok(())
@@ -88,14 +439,137 @@ fn main() -> Result<(), Error> {
 
     // If no `config` option is given use a preset default.
     let configpath = matches.value_of("config").unwrap_or("/etc/diflouroborane.toml");
-    let config = config::read(&PathBuf::from_str(configpath).unwrap())?;
+    if matches.is_present("check") {
+        cli::check(&PathBuf::from_str(configpath).unwrap(),
+            matches.is_present("insecure permissions ok"));
+    }
+    let mut config = config::read(&PathBuf::from_str(configpath).unwrap())
+        .or_fail(EXIT_CONFIG, format!("Could not load config file {}", configpath))?;
+
+    // Subcommands work on the databases directly and exit afterwards instead of starting the
+    // server.
+    if let Some(m) = matches.subcommand_matches("machine") {
+        return cli::machine(&config, m).or_fail(EXIT_DATABASE,
+            format!("Could not update machine database {}", config.machinedb.display()));
+    }
+    if let Some(m) = matches.subcommand_matches("user") {
+        return cli::user(&config, m).or_fail(EXIT_DATABASE,
+            format!("Could not update password database {}", config.passdb.display()));
+    }
+    if matches.is_present("healthcheck") {
+        return cli::healthcheck(&config).or_fail(EXIT_FAILURE, "Health check failed");
+    }
+    if let Some(m) = matches.subcommand_matches("status") {
+        // clap already made sure it's one of the formats we know
+        let format = m.value_of("format").unwrap_or("text").parse().unwrap();
+        return cli::status(&config, format).or_fail(EXIT_FAILURE, "Status failed");
+    }
+    if let Some(m) = matches.subcommand_matches("stats") {
+        return cli::stats(&config, m).or_fail(EXIT_DATABASE, "Could not read statistics");
+    }
+    if let Some(m) = matches.subcommand_matches("test-email") {
+        // A required argument
+        let to = m.value_of("address").unwrap_or_default();
+        return cli::test_email(&config, to).or_fail(EXIT_FAILURE, "Could not send test mail");
+    }
+
+    // Catch mistakes in the config now instead of halfway through starting up
+    let problems = config.validate();
+    if !problems.is_empty() {
+        let problems: Vec<String> = problems.iter().map(ToString::to_string).collect();
+        return Err(Failure { code: EXIT_CONFIG,
+            msg: format!("Invalid config file {}: {}", configpath, problems.join("; ")) });
+    }
+
+    // Anybody who can write the policy can grant themselves everything, so don't start like that
+    let exposed = privileges::check_files(&config, Path::new(configpath));
+    let insecure_ok = matches.is_present("insecure permissions ok");
+    if !insecure_ok {
+        let fatal: Vec<String> = exposed.iter().filter(|e| e.fatal).map(ToString::to_string)
+            .collect();
+        if !fatal.is_empty() {
+            return Err(Failure { code: EXIT_ACCESS, msg: format!("Refusing to start: {}. \
+                Fix the permissions or pass --insecure-permissions-ok.", fatal.join("; ")) });
+        }
+    }
+
+    let dry_run = if matches.is_present("dry run") {
+        Some(dryrun::prepare(&mut config).or_fail(EXIT_FAILURE, "Could not prepare dry run")?)
+    } else {
+        None
+    };
+
+    // Hold the lock on the machine database for as long as we're running so offline tools don't
+    // modify it behind our back. Dry runs don't modify it and are usually run next to the daemon
+    // using it for real.
+    let _mdb_lock = match dry_run {
+        Some(_) => None,
+        None => Some(machine::lock(&config.machinedb).or_fail(EXIT_DATABASE,
+            format!("Could not lock machine database {} (is the daemon already running?)",
+                config.machinedb.display()))?),
+    };
+
+    // Check for somebody else owning the pidfile now so the error still ends up on the terminal.
+    let pidfile = matches.value_of("pidfile").map(PathBuf::from);
+    if let Some(ref path) = pidfile {
+        daemon::Pidfile::check(path).or_fail(EXIT_FAILURE, "Could not use pidfile")?;
+    }
+
+    // Forking has to happen before anything starts a thread. The original process sticks around
+    // until we're ready so its exit code tells whether startup worked.
+    let ready = if matches.is_present("daemonize") {
+        if config.log.file.is_none() {
+            return Err(Failure { code: EXIT_CONFIG, msg: "--daemonize requires a log file to be \
+                set in the [log] section of the config".to_string() });
+        }
+        Some(daemon::daemonize().or_fail(EXIT_FAILURE, "Could not daemonize")?)
+    } else {
+        None
+    };
+
+    // Initialize signal handler.
+    // Specifically, this is a Stream of c_int representing received signals
+    // SIGINT for Ctrl-C, SIGTERM for init systems and supervisors, SIGHUP to reopen the log file,
+    // SIGUSR1 to toggle read-only mode.
+    let signals = Signals::new(&[signal_hook::SIGINT, signal_hook::SIGTERM, signal_hook::SIGHUP,
+            signal_hook::SIGUSR1])
+        .and_then(|s| s.into_async())
+        .or_fail(EXIT_FAILURE, "Could not set up signal handling")?;
 
     // Initialize the logging subsystem first to be able to better document the progress from now
     // on.
-    // TODO: Now would be a really good time to close stdin/out and move logging to syslog
     // Log is in an Arc so we can do very cheap clones in closures.
-    let log = Arc::new(log::init(&config));
-    info!(log, "Starting");
+    // Flags on the command line take precedence over the level set in the config
+    let level = match matches.occurrences_of("verbose") {
+        0 if matches.is_present("quiet") => Some(slog::Level::Warning),
+        0 => None,
+        1 => Some(slog::Level::Debug),
+        _ => Some(slog::Level::Trace),
+    };
+    let (log, logfile) = log::init(&config, level).or_fail(EXIT_CONFIG, "Could not set up logging")?;
+    let log = Arc::new(log);
+    info!(log, "Starting";
+        "config" => configpath,
+        "machinedb" => %config.machinedb.display(),
+        "passdb" => %config.passdb.display(),
+        "model" => %config.access.model.display(),
+        "policy" => %config.access.policy.display());
+    for o in config.overrides.iter() {
+        info!(log, "{} was overridden by {}", o.key, o.file.display());
+    }
+    if !config.unknown.is_empty() {
+        warn!(log, "Ignoring unknown config keys {}", config.unknown.join(", "));
+    }
+    for e in exposed.iter() {
+        warn!(log, "{}", e);
+    }
+    if let Some(ref dir) = dry_run {
+        warn!(log, "Dry run, nothing is switched or saved. Files are written to {} instead.",
+            dir.display());
+    }
+
+    // The audit trail is opened now, while we may still have the privileges to do so.
+    let audit = audit::Audit::open(&config).or_fail(EXIT_CONFIG, "Could not open audit trail")?;
 
     // Kick up an executor
     // Most initializations from now on do some amount of IO and are much better done in an
@@ -105,32 +579,46 @@ fn main() -> Result<(), Error> {
     // Start loading the machine database, authentication system and permission system
     // All of those get a custom logger so the source of a log message can be better traced and
     // filtered
-    let machinedb_f = machine::init(log.new(o!("system" => "machines")), &config);
-    let permission_f = access::init(log.new(o!("system" => "permissions")), &config);
+    // Subsystems keep their part of the server status up to date in here.
+    let status = status::Status::new();
+    status.set_dry_run(dry_run.is_some());
+
+    let machinedb_f = machine::init(log.new(o!("system" => "machines")), &config, status.clone());
+    let permission_f = access::init(log.new(o!("system" => "permissions")), &config,
+        status.clone());
     let authentication_f = auth::init(log.new(o!("system" => "authentication")), config.clone());
 
     // Bind to each address in config.listen.
     // This is a Stream over Futures so it will do absolutely nothing unless polled to completion
-    let listeners_s: futures::stream::Collect<_, Vec<TcpListener>> 
+    // Addresses that failed to bind are kept so we can retry them later.
+    let listeners_s: futures::stream::Collect<_,
+            Vec<Result<(config::Listen, listen::Listener), config::Listen>>>
         = stream::iter((&config).listen.iter())
         .map(|l| {
-            let addr = l.address.clone();
-            let port = l.port.unwrap_or(config::DEFAULT_PORT);
-            TcpListener::bind((l.address.as_str(), port))
-                // If the bind errors, include the address so we can log it
-                // Since this closure is lazy we need to have a cloned addr
-                .map_err(move |e| { (addr, port, e) })
+            listen::bind(l)
+                // Keep the entry around either way, to log errors and to know which listener to
+                // close when it is removed from the config.
+                .map_ok(move |s| (l.clone(), s))
+                .map_err(move |e| { (l, e) })
         })
-        .filter_map(|f| async {
+        .then(|f| async {
             match f.await {
-                Ok(l) => Some(l),
-                Err((addr, port, e)) => {
-                    error!(&log, "Could not setup socket on {} port {}: {}", addr, port, e);
-                    None
+                Ok(l) => Ok(l),
+                Err((l, e)) => {
+                    error!(&log, "Could not setup socket on {}: {}", l, e);
+                    Err(l.clone())
                 }
             }
         }).collect();
 
+    // When started with socket activation use the sockets systemd passed us instead of binding
+    // our own.
+    #[cfg(feature = "systemd")]
+    let activated = systemd::listeners()
+        .or_fail(EXIT_BIND, "Could not use sockets passed by the service manager")?;
+    #[cfg(not(feature = "systemd"))]
+    let activated: Vec<listen::Listener> = Vec::new();
+
     let (mach, pdb, auth) = exec.run_until(async {
         // Rull all futures to completion in parallel.
         // This will block until all three are done starting up.
@@ -138,9 +626,28 @@ fn main() -> Result<(), Error> {
     });
 
     // Error out if any of the subsystems failed to start.
-    let mach = mach?;
-    let pdb = pdb.unwrap();
-    let auth = auth?;
+    let mach = mach.or_fail(EXIT_DATABASE, "Could not open machine database")?;
+    let pdb = pdb.or_fail(EXIT_ACCESS, "Could not set up access control")?;
+    let auth = auth.or_fail(EXIT_ACCESS, "Could not set up authentication")?;
+
+    // Machines nobody may use and rules about removed machines are easy to miss otherwise
+    let report = access::reconcile::Report::new(&mach.perms(), &pdb.policy());
+    report.log(&log);
+    if config.access.strict && !report.is_empty() {
+        return Err(Failure { code: EXIT_ACCESS,
+            msg: format!("Policy doesn't fit the machines: {}", report) });
+    }
+
+    // Nobody could ever log in to a fresh install, so whoever can read our log may claim it
+    let setup = auth::setup::Setup::new(&config);
+    if !auth.has_users() && pdb.policy().is_empty() && config.auth.setup_expiry > 0 {
+        let expires = machine::unix_secs(std::time::SystemTime::now()) + config.auth.setup_expiry;
+        let token = setup.offer(expires)
+            .or_fail(EXIT_FAILURE, "Could not make up a setup token")?;
+        warn!(log, "No users and no policy yet. Create the first admin with claimInstance and \
+            setup token {} within {}s. It's also in {}.", token, config.auth.setup_expiry,
+            setup.token_file.display());
+    }
 
     // Since the below closures will happen at a much later time we need to make sure all pointers
     // are still valid. Thus, Arc.
@@ -148,61 +655,363 @@ fn main() -> Result<(), Error> {
     let stop_log = log.clone();
 
     // Create a thread pool to run tasks on
-    let pool = ThreadPool::builder()
+    let mut builder = ThreadPool::builder();
+    if let Some(n) = config.daemon.worker_threads {
+        builder.pool_size(n);
+    }
+    let pool = builder
         .after_start(move |i| {
             info!(start_log.new(o!("system" => "threadpool")), "Starting Thread <{}>", i)
         })
         .before_stop(move |i| {
             info!(stop_log.new(o!("system" => "threadpool")), "Stopping Thread <{}>", i)
         })
-        .create()?;
+        .create()
+        .or_fail(EXIT_FAILURE, "Could not start thread pool")?;
     let local_spawn = exec.spawner();
 
+    // If the service manager wants to hear from us regularly, do so for as long as the executor
+    // is running.
+    #[cfg(feature = "systemd")]
+    {
+        if let Some(interval) = systemd::watchdog_interval() {
+            let wlog = log.clone();
+            let f = async move {
+                loop {
+                    if let Err(e) = systemd::notify("WATCHDOG=1") {
+                        warn!(wlog, "Failed to send watchdog ping: {}", e);
+                    }
+                    task::sleep(interval / 2).await;
+                }
+            };
+            if let Err(e) = local_spawn.spawn_local_obj(Box::new(f).into()) {
+                error!(log, "Failed to start watchdog pings: {}", e);
+            }
+        }
+    }
+
 
     // The API has access to all subsystems it needs and the Threadpool as capability to spawn new
     // tasks for CPU-intensive work
-    let api = API::new(auth, pdb, mach, pool);
+    let webhooks = webhook::open(log.new(o!("system" => "webhooks")), &config)
+        .or_fail(EXIT_CONFIG, "Could not set up webhooks")?;
+    let stats = stats::open(&config)
+        .or_fail(EXIT_DATABASE, "Could not open statistics")?;
+    let channels = machine::watch::Channels::new(config.auth.users.clone());
+    let api = API::new(auth, pdb, mach, config.api.clone(), status.clone(), audit.clone(),
+        webhooks.as_ref().map(|w| w.deliveries()), channels.clone(), stats.clone(), setup,
+        pool.clone());
+
+    // Connected clients can always be told about machines becoming free, modules add other ways
+    channels.register(Rc::new(connection::EventChannel::new(api.sessions())));
+
+    // Modules come last since they build on all of the above
+    let mut modules = modules::Modules::builtin(log.new(o!("system" => "modules")), &config);
+    exec.run_until(modules.init(&config, status.clone(), api.machines(), api.permissions(),
+        api.authentication(), audit.clone(), channels.clone(), &pool, &local_spawn))
+        .or_fail(EXIT_FAILURE, "Could not start modules")?;
+
+    // Background tasks wait for the machines for as long as it takes, but say so once calls would
+    // have given up
+    let background = machine::lock::Background::new(log.new(o!("system" => "machines")),
+        Duration::from_secs(config.machines.lock_timeout));
+
+    // Nobody may notice a dry run, so users waiting for machines aren't told either. Those in
+    // queues wait with holds only.
+    let waiting = config.machines.watches.is_some() || config.machines.queue_hold > 0;
+    if waiting && dry_run.is_none() {
+        let f = machine::watch::deliver(log.new(o!("system" => "machines")), api.machines(),
+            background.clone(), channels, config.machines.notify_channels.clone());
+        if let Err(e) = local_spawn.spawn_local_obj(Box::new(f).into()) {
+            error!(log, "Failed to start telling users about free machines: {}", e);
+        }
+    }
+
+    // Events are written out right away but only periodically forced onto the disk
+    {
+        let sync_audit = audit.clone();
+        let sync_log = log.clone();
+        let f = async move {
+            loop {
+                task::sleep(AUDIT_SYNC_INTERVAL).await;
+                if let Err(e) = sync_audit.sync() {
+                    error!(sync_log, "Failed to sync audit trail: {}", e);
+                }
+            }
+        };
+        if let Err(e) = local_spawn.spawn_local_obj(Box::new(f).into()) {
+            error!(log, "Failed to start syncing the audit trail: {}", e);
+        }
+    }
+
+    // Scheduled blocks are applied and lifted in the background, including those whose window
+    // started or ended while we were down
+    {
+        let f = machine::run_schedule(log.new(o!("system" => "machines")), api.machines(),
+            audit.clone(), background.clone());
+        if let Err(e) = local_spawn.spawn_local_obj(Box::new(f).into()) {
+            error!(log, "Failed to start applying scheduled blocks: {}", e);
+        }
+    }
+
+    // Machines being used and given back are saved a little later, many at once
+    if config.machines.write_interval > 0 {
+        let f = machine::write_pending(api.machines(),
+            Duration::from_secs(config.machines.write_interval), background.clone());
+        if let Err(e) = local_spawn.spawn_local_obj(Box::new(f).into()) {
+            error!(log, "Failed to start saving machines: {}", e);
+        }
+    }
+
+    // Hooks hear about machines through the audit trail, so they're fed whether it's kept or not
+    if let Some(webhooks) = webhooks {
+        let f = webhooks.run(audit.clone());
+        if let Err(e) = local_spawn.spawn_local_obj(Box::new(f).into()) {
+            error!(log, "Failed to start delivering webhooks: {}", e);
+        }
+    }
+
+    // Statistics are counted from the audit trail too and only written out from time to time
+    if let Some(ref stats) = stats {
+        let f = stats.clone().run(log.new(o!("system" => "stats")), audit.subscribe(),
+            api.machines(), background.clone(), Duration::from_secs(config.stats.flush_interval));
+        if let Err(e) = local_spawn.spawn_local_obj(Box::new(f).into()) {
+            error!(log, "Failed to start counting statistics: {}", e);
+        }
+    }
+
+    // Everything that collects entries while we run, so long uptimes don't mean ever more memory
+    {
+        let mut collector = gc::Collector::new(log.new(o!("system" => "gc")), status.clone(),
+            config.gc.clone());
+        collector.register(gc::SESSIONS, api.sessions());
+        collector.register(gc::MACHINE_SUBSCRIBERS, machine::Subscribers(api.machines(),
+            background.clone()));
+        collector.register(gc::EVENT_SUBSCRIBERS, api.events());
+        collector.register(gc::AUDIT_SUBSCRIBERS, audit.clone());
+        if let Some(limiter) = api.limiter() {
+            collector.register(gc::RATE_LIMIT_BUCKETS, limiter);
+        }
+        collector.register(gc::QUEUES, machine::Queues(api.machines(), background.clone()));
+        if let Err(e) = local_spawn.spawn_local_obj(Box::new(collector.run()).into()) {
+            error!(log, "Failed to start pruning registries: {}", e);
+        }
+    }
+
+    // Expired temporary grants are ignored right away but only removed from time to time
+    {
+        let f = access::expire_grants(log.new(o!("system" => "permissions")), api.permissions(),
+            api.breaker());
+        if let Err(e) = local_spawn.spawn_local_obj(Box::new(f).into()) {
+            error!(log, "Failed to start cleaning up temporary grants: {}", e);
+        }
+    }
 
     // Closure inefficiencies. Lucky cloning an Arc is pretty cheap.
     let inner_log = log.clone();
     let loop_log = log.clone();
 
-    exec.run_until(async move {
-        // Generate a stream of TcpStreams appearing on any of the interfaces we listen to
-        let listeners = listeners_s.await;
-        let incoming = stream::select_all(listeners.iter().map(|l| l.incoming()));
+    // Currently open connections, used both to limit them and so the shutdown can wait for them
+    // to finish
+    let connections = connection::Connections::new(log.new(o!("system" => "connections")),
+        config.api.max_connections, config.api.max_connections_per_peer, status);
+    let conn_counter = connections.clone();
+
+    // Whatever needs doing when we stop is registered here, see the end of the main loop
+    let hooks = shutdown::Registry::new();
+    {
+        let api = api.clone();
+        let background = background.clone();
+        hooks.register("machine database", shutdown::SAVE, SAVE_TIMEOUT, move || async move {
+            api.flush(&background).await.map_err(|e| e.to_string())
+        });
+    }
+    if let Some(ref stats) = stats {
+        let stats = stats.clone();
+        hooks.register("statistics", shutdown::SAVE, SAVE_TIMEOUT, move || async move {
+            stats.flush().map_err(|e| e.to_string())
+        });
+    }
+    {
+        let audit = audit.clone();
+        hooks.register("audit trail", shutdown::SYNC, SAVE_TIMEOUT, move || async move {
+            audit.sync().map_err(|e| e.to_string())
+        });
+    }
+
+    let events = api.events();
+    let shutdown_events = events.clone();
+    let reload_perm = api.permissions();
+    let reload_breaker = api.breaker();
+    let hup_machines = api.machines();
+    let hup_background = background.clone();
+    let usr1_audit = audit.clone();
+    let grace = Duration::from_secs(config.daemon.shutdown_grace);
+    let bind_retry = Duration::from_secs(config.daemon.bind_retry);
+
+    let outcome = exec.run_until(async move {
+        let socket_activated = !activated.is_empty();
+        let (listeners, failed) = if !socket_activated {
+            let mut listeners = Vec::new();
+            let mut failed = Vec::new();
+            for r in listeners_s.await {
+                match r {
+                    Ok((l, s)) => listeners.push((Some(l), s)),
+                    Err(l) => failed.push(l),
+                }
+            }
+            (listeners, failed)
+        } else {
+            info!(loop_log, "Using {} sockets passed by the service manager", activated.len());
+            (activated.into_iter().map(|s| (None, s)).collect(), Vec::new())
+        };
+
+        // Sitting around without any way for clients to reach us helps nobody, unless we were
+        // told to wait for the network to come up.
+        if listeners.is_empty() {
+            if failed.is_empty() || bind_retry == Duration::from_secs(0) {
+                error!(loop_log, "No listening socket could be set up");
+                return Err(Failure { code: EXIT_BIND,
+                    msg: "No listening socket could be set up".to_string() });
+            }
+            warn!(loop_log, "No listening socket could be set up yet, retrying in {}s",
+                bind_retry.as_secs());
+        }
+
+        // Startup is done at this point. The pidfile is removed again when this block returns.
+        let _pidfile = match pidfile {
+            Some(ref path) => Some(daemon::Pidfile::create(path)
+                .or_fail(EXIT_FAILURE, "Could not write pidfile")?),
+            None => None,
+        };
+
+        // Everything that needs privileges is done now, so get rid of them before talking to
+        // anybody.
+        privileges::drop(&loop_log, &config).or_fail(EXIT_ACCESS, "Could not drop privileges")?;
+
+        if let Some(ready) = ready {
+            ready.notify();
+        }
+
+        #[cfg(feature = "systemd")]
+        {
+            if let Err(e) = systemd::notify("READY=1") {
+                warn!(loop_log, "Failed to notify service manager of readiness: {}", e);
+            }
+        }
+
+        // Generate a stream of sockets appearing on any of the interfaces we listen to. The set of
+        // interfaces can change later on, when binding is retried or the config is reloaded.
+        let (incoming, listen_changes) = listen::Incoming::new(listeners);
+        let closer = incoming.closer();
+        hooks.register("listeners", shutdown::LISTENERS, Duration::from_secs(1),
+            move || async move {
+                closer.close();
+                Ok(())
+            });
+        let wanted: listen::Wanted = Rc::new(RefCell::new(
+            if socket_activated { Vec::new() } else { config.listen.to_vec() }));
+        let listen_log = loop_log.new(o!("system" => "listen"));
+        if !failed.is_empty() && bind_retry > Duration::from_secs(0) {
+            let f = listen::retry(listen_log.clone(), failed, bind_retry, listen_changes.clone(),
+                wanted.clone());
+            if let Err(e) = local_spawn.spawn_local_obj(Box::new(f).into()) {
+                error!(loop_log, "Failed to start retrying to bind: {}", e);
+            }
+        }
 
-        // For each incoming connection start a new task to handle it and throw it on the thread
-        // pool
-        let handle_sockets = incoming.map(|socket| {
+        // For each incoming connection start a new task to handle it. They all run on this
+        // thread, see `api::handle_connection` for why.
+        let mut next_conn: u64 = 0;
+        let reader_options = api::reader_options(&config.api);
+        let keepalive = config.api.tcp_keepalive.clone();
+        // Somebody scanning the network would otherwise fill the log with hang ups
+        let mut filtered_logged: Option<std::time::Instant> = None;
+        let mut filtered_suppressed: u64 = 0;
+        let mut accept = |socket: io::Result<listen::Accepted>| {
             // incoming.next() is an error when the underlying `accept` call yielded an error
             // In POSIX those are protocol errors we can't really handle, so we just log the error
             // and the move on
             match socket {
-                Ok(socket) => {
-                    // If we have it available add the peer's address to all log messages
-                    let log =
-                        if let Ok(addr) = socket.peer_addr() {
-                            inner_log.new(o!("address" => addr))
+                Ok((socket, filter)) => {
+                    // Dropping the socket closes it, before the peer got to say anything
+                    if !filter.allows(socket.peer_ip()) {
+                        if filtered_logged.map_or(false, |t| t.elapsed() < FILTERED_LOG_INTERVAL) {
+                            filtered_suppressed += 1;
                         } else {
-                            inner_log.new(o!())
-                        };
+                            info!(inner_log, "Hung up on {}, not allowed on this listener",
+                                socket.peer_name(); "suppressed" => filtered_suppressed);
+                            filtered_logged = Some(std::time::Instant::now());
+                            filtered_suppressed = 0;
+                        }
+                        return LoopResult::Continue;
+                    }
+
+                    // Add the peer's address and a unique id to all log messages so concurrent
+                    // connections can be told apart
+                    next_conn += 1;
+                    let peer = socket.peer_name();
+                    let local = socket.is_local();
+                    let log = inner_log.new(o!("address" => peer.clone(), "conn" => next_conn));
+                    if let Some(ref k) = keepalive {
+                        if let Err(e) = socket.set_keepalive(k) {
+                            warn!(log, "Failed to enable TCP keepalive: {}", e);
+                        }
+                    }
 
                     // Clone a log for potential error handling
                     let elog = log.clone();
 
-                    // We handle the error using map_err, `let _` is used to quiet the compiler
-                    // warning
-                    let f = api::handle_connection(api.clone(), log.clone(), socket)
-                        .map_err(move |e| {
-                            error!(log, "Error occured during protocol handling: {}", e);
-                        })
-                        // Void any and all results since pool.spawn allows no return value.
-                        .map(|_| ());
+                    let f = match conn_counter.try_acquire(socket.peer_ip()) {
+                        Ok(guard) => {
+                            let api = api.clone();
+                            async move {
+                                // The WebSocket handshake happens in here so it doesn't hold up
+                                // accepting other connections
+                                match socket.ready().await {
+                                    Ok(socket) => {
+                                        let r = api::handle_connection(api, log.clone(), socket,
+                                            peer, local, next_conn).await;
+                                        if let Err(e) = r {
+                                            error!(log,
+                                                "Error occured during protocol handling: {}", e);
+                                        }
+                                    },
+                                    Err(e) => warn!(log, "WebSocket handshake failed: {}", e),
+                                }
+                                // The guard is moved in here so the connection stops counting as
+                                // open however the future ends, even if it's dropped without ever
+                                // completing.
+                                drop(guard);
+                            }.boxed_local()
+                        },
+                        // We're at the connection limit. Instead of just dropping the connection
+                        // tell the client to come back later.
+                        Err(connection::Refused::Overloaded) => match conn_counter.try_turn_away() {
+                            Some(guard) => async move {
+                                match socket.ready().await {
+                                    Ok(socket) => api::handle_overloaded(log, socket,
+                                        reader_options, guard.retry_after()).await,
+                                    Err(e) => warn!(log, "WebSocket handshake failed: {}", e),
+                                }
+                                drop(guard);
+                            }.boxed_local(),
+                            // Only the connection accepted right before we stopped accepting
+                            None => {
+                                debug!(log, "Dropping connection, turning away too many already");
+                                return LoopResult::Continue;
+                            },
+                        },
+                        // A single peer hogging connections doesn't get the courtesy.
+                        Err(connection::Refused::PeerLimit) => {
+                            warn!(log, "Refusing connection, peer is at its connection limit");
+                            return LoopResult::Continue;
+                        },
+                    };
 
                     // In this case only the error is relevant since the Value is always ()
                     // The future is Boxed to make it the `LocalFutureObj` that LocalSpawn expects
-                    if let Err(e) = local_spawn.spawn_local_obj(Box::new(f).into()) {
+                    if let Err(e) = local_spawn.spawn_local_obj(f.into()) {
                         error!(elog, "Failed to spawn connection handler: {}", e);
                         // Failing to spawn a handler means we are most likely overloaded
                         return LoopResult::Overloaded;
@@ -215,14 +1024,97 @@ fn main() -> Result<(), Error> {
 
             // Unless we are overloaded we just want to keep going.
             return LoopResult::Continue;
+        };
+        // Only take the next connection once there's room for it, so a flood of connections waits
+        // in the kernel's backlog instead of piling up here
+        let capacity = conn_counter.clone();
+        let handle_sockets = incoming.then(|socket| {
+            let r = accept(socket);
+            capacity.capacity().map(move |()| r)
         });
 
         // Check each signal as it arrives
         // signals is a futures-0.1 stream, compat() makes it a futures-0.3 (which we use) stream
-        let handle_signals = signals.compat().map(|_signal| {
-            // _signal is the signal c_int.
-            // But since we only listen for SIGINT at the moment we don't really need to look at
-            // it.
+        // It's only borrowed here since we still need it during shutdown.
+        let mut signals = signals.compat();
+        let signal_log = loop_log.clone();
+        let reload_spawn = local_spawn.clone();
+        let handle_signals = signals.by_ref().map(move |signal| {
+            // signal is the signal c_int.
+            // SIGUSR1 starts or ends a maintenance window, see `MachinesProvider::set_read_only`
+            if let Ok(signal_hook::SIGUSR1) = signal {
+                let m = hup_machines.clone();
+                let b = hup_background.clone();
+                let l = signal_log.clone();
+                let trail = usr1_audit.clone();
+                let f = async move {
+                    let mut m = b.write(&m, "toggle read-only mode").await;
+                    let on = !m.read_only();
+                    m.set_read_only(on);
+                    drop(m);
+                    warn!(l, "{} read-only mode on SIGUSR1", if on { "Entered" } else { "Left" });
+                    trail.record(audit::AuditEvent::ReadOnly { by: "SIGUSR1", read_only: on });
+                };
+                if let Err(e) = reload_spawn.spawn_local_obj(Box::new(f).into()) {
+                    error!(signal_log, "Failed to toggle read-only mode: {}", e);
+                }
+                return LoopResult::Continue;
+            }
+
+            // SIGHUP means somebody moved the log file away, e.g. logrotate, or changed the
+            // config or the policy. Everything else means stop.
+            if let Ok(signal_hook::SIGHUP) = signal {
+                if let Some(ref f) = logfile {
+                    match f.reopen() {
+                        Ok(()) => info!(signal_log, "Reopened log file"),
+                        Err(e) => error!(signal_log, "Failed to reopen log file: {}", e),
+                    }
+                }
+
+                // Whatever waits to be saved is saved now, e.g. before a backup is taken
+                let m = hup_machines.clone();
+                let b = hup_background.clone();
+                let l = signal_log.clone();
+                let f = async move {
+                    if let Err(e) = b.write(&m, "save it").await.flush() {
+                        error!(l, "Failed to save machine database: {}", e);
+                    }
+                };
+                if let Err(e) = reload_spawn.spawn_local_obj(Box::new(f).into()) {
+                    error!(signal_log, "Failed to save machine database: {}", e);
+                }
+
+                // The access policy is read again as is, from the file it was read from at start
+                let f = access::reload_policy(signal_log.new(o!("system" => "permissions")),
+                    reload_perm.clone(), reload_breaker.clone(), events.clone());
+                if let Err(e) = reload_spawn.spawn_local_obj(Box::new(f).into()) {
+                    error!(signal_log, "Failed to reload access policy: {}", e);
+                }
+
+                // Only the listen entries can change without a restart so far.
+                match config::read(&PathBuf::from(configpath)) {
+                    Err(e) => error!(signal_log, "Could not reload config file {}: {}",
+                        configpath, e),
+                    Ok(_) if socket_activated => warn!(signal_log, "Not reloading listen \
+                        addresses, the sockets are managed by the service manager"),
+                    Ok(new) => {
+                        let problems = new.validate();
+                        if problems.is_empty() {
+                            let f = listen::reload(listen_log.clone(), new.listen.to_vec(),
+                                wanted.clone(), bind_retry, listen_changes.clone());
+                            if let Err(e) = reload_spawn.spawn_local_obj(Box::new(f).into()) {
+                                error!(signal_log, "Failed to reload listen addresses: {}", e);
+                            }
+                        } else {
+                            for p in problems {
+                                error!(signal_log, "Not reloading config file {}: {}",
+                                    configpath, p);
+                            }
+                        }
+                    },
+                }
+                return LoopResult::Continue;
+            }
             return LoopResult::Stop;
         });
 
@@ -233,11 +1125,9 @@ fn main() -> Result<(), Error> {
                 // When the result says to continue, do exactly that
                 Some(LoopResult::Continue) => {}
                 Some(LoopResult::Overloaded) => {
-                    // In case over server overload we should install a replacement handler that
-                    // would instead just return `overloaded` for all connections until the
-                    // situation is remedied.
-                    //
-                    // For now, just log the overload and keep going.
+                    // The executor refused the task so there's nothing we can run the lightweight
+                    // overloaded handler on either. Connections over the configured limit are
+                    // handled above; this is the case of the executor itself giving up.
                     error!(loop_log, "Server overloaded");
                 }
                 // None should never be returned because it would mean all sockets were closed and
@@ -245,17 +1135,94 @@ fn main() -> Result<(), Error> {
                 // anyway, the only reason this could happen are some heavy bugs in the runtime
                 Some(LoopResult::Stop) | None => {
                     warn!(loop_log, "Stopping server");
+                    #[cfg(feature = "systemd")]
+                    {
+                        let _ = systemd::notify("STOPPING=1");
+                    }
                     break;
                 }
             }
         }
+
+        // Clients get the whole grace period to show a banner or give back their machines
+        shutdown_events.publish(events::ServerEvent::ShutdownImminent { grace: grace.as_secs() });
+
+        // Stop accepting new connections, among others. The signals are still needed, so let
+        // go of them.
+        hooks.run_below(&loop_log, shutdown::DRAIN).await;
+        drop(combined);
+
+        let open = connections.open();
+        if open > 0 {
+            info!(loop_log, "Waiting up to {}s for {} open connections to finish. Interrupt again to \
+                force shutdown.", grace.as_secs(), open);
+        }
+
+        let drained = async {
+            while connections.open() > 0 {
+                task::sleep(Duration::from_millis(100)).await;
+            }
+        };
+        let timeout = task::sleep(grace);
+        // A SIGHUP for the log file is no reason to stop right away, neither is SIGUSR1
+        let mut stop_signals = signals.filter(|s| future::ready(
+            !matches!(s, Ok(signal_hook::SIGHUP) | Ok(signal_hook::SIGUSR1))));
+        let interrupted = stop_signals.next();
+
+        // Whichever comes first: All connections closed, the grace period ran out or somebody
+        // really wants us to stop *now*.
+        match future::select(drained.boxed_local(),
+                future::select(timeout.boxed_local(), interrupted)).await
+        {
+            future::Either::Left(_) => {},
+            future::Either::Right((future::Either::Left(_), _)) => {
+                warn!(loop_log, "Grace period expired, dropping {} connections",
+                    connections.open());
+            },
+            future::Either::Right((future::Either::Right(_), _)) => {
+                return Ok(Shutdown::Forced);
+            },
+        }
+
+        // Modules are stopped before saving since they may change state up until then
+        modules.shutdown().await;
+
+        // Now nobody can change state anymore so make sure everything is on disk.
+        hooks.run(&loop_log).await;
+
+        Ok::<_, Failure>(Shutdown::Clean)
     });
 
-    // TODO: Run actual shut down code here
-    info!(log, "Shutting down...");
+    match outcome? {
+        Shutdown::Clean => {
+            info!(log, "Shutdown complete");
+            // Returning () is an implicit success so this will properly set the exit code as well
+            Ok(())
+        },
+        Shutdown::Forced => {
+            error!(log, "Forced shutdown, state may not have been saved");
+            // The log drain is asynchronous so give it the chance to write out the last messages.
+            drop(log);
+            std::process::exit(1);
+        },
+    }
+}
+
+/// How often the audit trail is synced to disk
+const AUDIT_SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long saving one thing during shutdown may take
+const SAVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often we log peers being hung up on for the `allow` and `deny` of a listener at most
+const FILTERED_LOG_INTERVAL: Duration = Duration::from_secs(60);
 
-    // Returning () is an implicit success so this will properly set the exit code as well
-    Ok(())
+/// How the server shut down
+enum Shutdown {
+    /// All state was saved
+    Clean,
+    /// Shutdown was forced before everything could be saved
+    Forced,
 }
 
 /// The result of one iteration of the core loop
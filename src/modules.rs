@@ -1,16 +1,246 @@
 //! Indpendent Communication modules
 //!
-//! This is where dynamic modules are implemented later on using libloading / abi_stable_crates et
-//! al.
+//! With the `plugins` feature modules can also be loaded from shared objects, see `plugin`.
 //! Additionally, FFI modules to other languages (Python/Lua/...) make the most sense in here as
 //! well.
+//!
+//! Every module implements `Module`. They're started once the core subsystems are up and stopped
+//! again, in reverse, during shutdown.
 
+mod actor;
+#[cfg(feature = "email")]
+pub mod email;
+pub mod http;
+#[cfg(feature = "matrix")]
+mod matrix;
 mod mqtt;
+mod notify;
+#[cfg(feature = "plugins")]
+mod plugin;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::executor::{LocalSpawner, ThreadPool};
+use futures::future::LocalBoxFuture;
+
+use async_std::sync::RwLock;
+
+use serde::de::DeserializeOwned;
 
 use slog::Logger;
 
-pub fn init(log: Logger) {
-    info!(log, "Initializing submodules");
-    mqtt::init(log.new(o!()));
-    info!(log, "Finished initializing submodules");
+use crate::access::PermissionsProvider;
+use crate::audit::Audit;
+use crate::auth::AuthenticationProvider;
+use crate::config::{Config, OnFailure};
+use crate::error::Result;
+use crate::machine::{lock, MachinesProvider};
+use crate::machine::watch::Channels;
+use crate::status::Status;
+
+/// Something running alongside the core, like the MQTT bridge
+pub trait Module {
+    /// Name of the module, which is also where its settings go: `[modules.<name>]`
+    fn name(&self) -> &'static str;
+
+    /// Start doing whatever the module does, usually by spawning a task on `ctx.spawner`
+    fn init<'a>(&'a mut self, ctx: ModuleContext<'a>) -> LocalBoxFuture<'a, Result<()>>;
+
+    /// Stop again, leaving things in a state that's fine to come back to
+    fn shutdown(&mut self) -> LocalBoxFuture<'_, ()>;
+}
+
+/// Everything a module gets to work with
+pub struct ModuleContext<'a> {
+    pub log: Logger,
+    /// The module's `[modules.<name>]` table, if there is one
+    pub section: Option<toml::Value>,
+    pub config: &'a Config,
+    pub status: Arc<Status>,
+    pub mach: Arc<RwLock<MachinesProvider>>,
+    pub perm: Arc<RwLock<PermissionsProvider>>,
+    /// Where to register SASL mechanisms, see `auth::sasl`
+    pub auth: Arc<RwLock<AuthenticationProvider>>,
+    pub audit: Audit,
+    /// Where to register ways to reach users
+    pub channels: Channels,
+    /// For blocking or CPU-heavy work
+    pub pool: ThreadPool,
+    /// For everything else
+    pub spawner: LocalSpawner,
+}
+
+impl ModuleContext<'_> {
+    /// The module's settings, as if its table was empty if it has none
+    pub fn settings<T: DeserializeOwned>(&self) -> Result<T> {
+        let section = self.section.clone()
+            .unwrap_or_else(|| toml::Value::Table(toml::value::Table::new()));
+        Ok(section.try_into()?)
+    }
+
+    /// For the module's tasks to take the lock on `mach` with, see `machine::lock`
+    pub fn background(&self) -> lock::Background {
+        lock::Background::new(self.log.clone(),
+            Duration::from_secs(self.config.machines.lock_timeout))
+    }
+}
+
+/// All modules we know of
+pub struct Modules {
+    log: Logger,
+    modules: Vec<Box<dyn Module>>,
+    /// Indices into `modules` of those that started, in the order they did
+    started: Vec<usize>,
+}
+
+impl Modules {
+    pub fn new(log: Logger) -> Self {
+        Self { log, modules: Vec::new(), started: Vec::new() }
+    }
+
+    /// The modules built in, as far as they're configured
+    pub fn builtin(log: Logger, config: &Config) -> Self {
+        let mut modules = Self::new(log);
+
+        // Nobody may notice a dry run, so only actors run and they only log
+        if config.dry_run.is_some() {
+            if !config.machines.actors.is_empty() {
+                modules.register(Box::new(actor::Module::new(None)));
+            }
+            return modules;
+        }
+
+        let bridge = config.mqtt.as_ref().map(|_| mqtt::Module::new());
+
+        // Actors watch the topics their devices report on, which has to happen before the bridge
+        // connects and subscribes to them
+        if !config.machines.actors.is_empty() {
+            let handle = bridge.as_ref().map(|b| b.handle());
+            modules.register(Box::new(actor::Module::new(handle)));
+        }
+        if let Some(bridge) = bridge {
+            modules.register(Box::new(bridge));
+        }
+        if !config.notifiers.is_empty() {
+            modules.register(Box::new(notify::Module::new()));
+        }
+        #[cfg(feature = "matrix")]
+        {
+            if config.modules.sections.contains_key("matrix") {
+                modules.register(Box::new(matrix::Module::new()));
+            }
+        }
+        #[cfg(feature = "email")]
+        {
+            if config.modules.sections.contains_key("email") {
+                modules.register(Box::new(email::Module::new()));
+            }
+        }
+
+        modules
+    }
+
+    /// Add a module, which is started after all modules added before it
+    pub fn register(&mut self, module: Box<dyn Module>) {
+        self.modules.push(module);
+    }
+
+    /// Start all modules
+    ///
+    /// A module failing to start only fails this if `modules.on_failure` says so. Plugins aren't
+    /// loaded for dry runs.
+    pub async fn init(&mut self, config: &Config, status: Arc<Status>,
+        mach: Arc<RwLock<MachinesProvider>>, perm: Arc<RwLock<PermissionsProvider>>,
+        auth: Arc<RwLock<AuthenticationProvider>>, audit: Audit, channels: Channels,
+        pool: &ThreadPool, spawner: &LocalSpawner)
+        -> Result<()>
+    {
+        info!(self.log, "Initializing submodules");
+
+        #[cfg(feature = "plugins")]
+        {
+            if let (Some(dir), None) = (&config.modules.plugin_dir, &config.dry_run) {
+                self.load_plugins(dir, config.modules.on_failure)?;
+            }
+        }
+
+        // Dry runs leave out most modules on purpose
+        if config.dry_run.is_none() {
+            for name in config.modules.sections.keys() {
+                if !self.modules.iter().any(|m| m.name() == name) {
+                    warn!(self.log, "Ignoring [modules.{}], there is no such module", name);
+                }
+            }
+        }
+
+        for (i, module) in self.modules.iter_mut().enumerate() {
+            let name = module.name();
+            let ctx = ModuleContext {
+                log: self.log.new(o!("module" => name)),
+                section: config.modules.sections.get(name).cloned(),
+                config,
+                status: status.clone(),
+                mach: mach.clone(),
+                perm: perm.clone(),
+                auth: auth.clone(),
+                audit: audit.clone(),
+                channels: channels.clone(),
+                pool: pool.clone(),
+                spawner: spawner.clone(),
+            };
+
+            match module.init(ctx).await {
+                Ok(()) => {
+                    debug!(self.log, "Started module {}", name);
+                    self.started.push(i);
+                },
+                Err(e) if config.modules.on_failure == OnFailure::Fatal => {
+                    error!(self.log, "Module {} failed to start: {}", name, e);
+                    return Err(e);
+                },
+                Err(e) => error!(self.log, "Module {} failed to start, continuing without it: {}",
+                    name, e),
+            }
+        }
+
+        info!(self.log, "Finished initializing submodules"; "started" => self.started.len());
+        Ok(())
+    }
+
+    /// Register every plugin in `dir`
+    #[cfg(feature = "plugins")]
+    fn load_plugins(&mut self, dir: &std::path::Path, on_failure: OnFailure) -> Result<()> {
+        use crate::error::WithPath;
+
+        for path in plugin::find(dir).with_path(dir)? {
+            let r = plugin::Plugin::load(&self.log, &path).and_then(|p| {
+                if self.modules.iter().any(|m| m.name() == p.name()) {
+                    Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists,
+                        format!("there already is a module named {}", p.name())))
+                } else {
+                    Ok(p)
+                }
+            });
+            match r {
+                Ok(p) => {
+                    info!(self.log, "Loaded plugin {} from {}", p.name(), path.display());
+                    self.register(Box::new(p));
+                },
+                Err(e) if on_failure == OnFailure::Fatal => return Err(e).with_path(&path),
+                Err(e) => error!(self.log, "Could not load plugin {}, continuing without it: {}",
+                    path.display(), e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop all started modules, the last one started first
+    pub async fn shutdown(&mut self) {
+        while let Some(i) = self.started.pop() {
+            let module = &mut self.modules[i];
+            debug!(self.log, "Stopping module {}", module.name());
+            module.shutdown().await;
+        }
+    }
 }
@@ -0,0 +1,751 @@
+//! Devices carrying out the side effects of machine state changes, like switching their power
+//!
+//! Every machine can have one actor. Whenever the state of the machine changes its actor is told
+//! what to do; if it fails even after a few retries the machine is put into a safe state. Trainers
+//! can also switch an actor by hand through the manage capability, to bring a device back in line
+//! with its machine after it was offline.
+
+mod exec;
+mod gpio;
+mod http;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use futures::executor::ThreadPool;
+use futures::future::{self, FutureExt, LocalBoxFuture, RemoteHandle};
+use futures::stream::StreamExt;
+use futures::task::LocalSpawnExt;
+
+use async_std::sync::RwLock;
+use async_std::task;
+
+use slog::Logger;
+
+use uuid::Uuid;
+
+use crate::audit::{Audit, AuditEvent};
+use crate::config::{self, ActorKind};
+use crate::error::{Error, Result};
+use crate::machine::{self, lock, ActorOverride, ActorStatus, MachinesProvider, StateChange};
+
+use super::{mqtt, ModuleContext};
+
+use exec::Exec;
+use gpio::Gpio;
+use http::Http;
+
+/// Delay before the first retry of a failed command, doubling after every further failure
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// What an actor has to do for a machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MachineCommand {
+    PowerOn,
+    PowerOff,
+}
+
+impl MachineCommand {
+    /// The command bringing a device in line with the machine being in `status`
+    pub fn for_status(status: machine::Status) -> Self {
+        match status {
+            machine::Status::Occupied => MachineCommand::PowerOn,
+            machine::Status::Free | machine::Status::Blocked => MachineCommand::PowerOff,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MachineCommand::PowerOn => "power on",
+            MachineCommand::PowerOff => "power off",
+        }
+    }
+}
+
+/// Fill in the placeholders `{uuid}`, `{name}`, `{state}`, `{occupant}` and `{power}` in
+/// `template` with what they are for the machine
+///
+/// With `json` the values are escaped to go into a JSON string.
+pub fn expand(template: &str, state: &StateChange, json: bool) -> String {
+    let power = match MachineCommand::for_status(state.status) {
+        MachineCommand::PowerOn => "on",
+        MachineCommand::PowerOff => "off",
+    };
+    let values = [
+        ("{uuid}", state.uuid.to_hyphenated().to_string()),
+        ("{name}", state.name.clone()),
+        ("{state}", state.status.as_str().to_string()),
+        ("{occupant}", state.occupant.clone().unwrap_or_default()),
+        ("{power}", power.to_string()),
+    ];
+
+    let mut s = template.to_string();
+    for (placeholder, value) in values.iter() {
+        let value = if json {
+            // Serializing a string gives it quotes we don't want
+            let quoted = serde_json::to_string(value).unwrap();
+            quoted[1..quoted.len() - 1].to_string()
+        } else {
+            value.clone()
+        };
+        s = s.replace(placeholder, &value);
+    }
+    s
+}
+
+/// Body of a webhook request about the state of a machine, `template` filled in or everything we
+/// know if there's none
+pub fn json_body(template: Option<&str>, state: &StateChange) -> Vec<u8> {
+    match template {
+        Some(t) => expand(t, state, true).into_bytes(),
+        None => {
+            let power = MachineCommand::for_status(state.status) == MachineCommand::PowerOn;
+            let body = serde_json::json!({
+                "machine": state.uuid.to_hyphenated().to_string(),
+                "name": state.name,
+                "state": state.status.as_str(),
+                "occupant": state.occupant,
+                "power": power,
+            });
+            body.to_string().into_bytes()
+        },
+    }
+}
+
+/// A device carrying out commands for a machine
+pub trait Actor {
+    /// Carry out `command`, which brings the device in line with `state`, resolving once the
+    /// device confirmed it did
+    ///
+    /// Calls for the same machine never overlap. Errors should say what went wrong in words a
+    /// trainer understands, they're shown to them.
+    fn apply(&mut self, state: &StateChange, command: MachineCommand)
+        -> LocalBoxFuture<'_, io::Result<()>>;
+}
+
+/// An actor that only logs what it's told to do
+pub struct Dummy {
+    log: Logger,
+}
+
+impl Dummy {
+    pub fn new(log: Logger) -> Self {
+        Self { log }
+    }
+}
+
+impl Actor for Dummy {
+    fn apply(&mut self, state: &StateChange, command: MachineCommand)
+        -> LocalBoxFuture<'_, io::Result<()>>
+    {
+        info!(self.log, "Machine {} would {} now", state.uuid, command.as_str());
+        future::ready(Ok(())).boxed_local()
+    }
+}
+
+/// An actor along with how hard to try with it
+struct Binding {
+    actor: Box<dyn Actor>,
+    retries: u32,
+    /// Block the machine once we gave up on the actor, see `MachinesProvider::actor_failed`
+    block_offline: bool,
+}
+
+/// The actors of all machines
+pub struct Registry {
+    actors: BTreeMap<Uuid, Binding>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self { actors: BTreeMap::new() }
+    }
+
+    /// Build the actors configured in `machines.actors`
+    ///
+    /// Actors switched over MQTT need the `bridge`. Config validation made sure there is one if
+    /// any of them are configured. Commands of exec actors are waited for on `pool`, and GPIO
+    /// lines are switched there.
+    pub fn from_config(log: &Logger, actors: &BTreeMap<Uuid, config::Actor>, client_id: &str,
+        bridge: Option<&mqtt::Handle>, pool: &ThreadPool) -> Self
+    {
+        let mut registry = Self::new();
+        for (uuid, config) in actors.iter() {
+            let actor: Box<dyn Actor> = match (config.kind, bridge) {
+                (ActorKind::Dummy, _) => Box::new(Dummy::new(log.clone())),
+                (ActorKind::Exec, _) => Box::new(Exec::new(
+                    log.new(o!("machine" => uuid.to_string())),
+                    config.command.clone(),
+                    Duration::from_secs(config.timeout),
+                    pool.clone())),
+                (ActorKind::Http, _) => match Http::from_config(config) {
+                    Ok(a) => Box::new(a),
+                    Err(e) => {
+                        error!(log, "Could not set up actor of machine {}: {}", uuid, e);
+                        continue;
+                    },
+                },
+                (ActorKind::Gpio, _) => match Gpio::from_config(
+                    log.new(o!("machine" => uuid.to_string())), config, pool.clone())
+                {
+                    Ok(a) => Box::new(a),
+                    Err(e) => {
+                        error!(log, "Could not set up actor of machine {}: {}", uuid, e);
+                        continue;
+                    },
+                },
+                (_, Some(bridge)) => match mqtt::actor(config, client_id, bridge) {
+                    Some(a) => a,
+                    None => continue,
+                },
+                (_, None) => continue,
+            };
+            registry.register(uuid.clone(), actor, config.retries, config.block_offline);
+        }
+        registry
+    }
+
+    /// A `Dummy` for every actor configured in `machines.actors`, for dry runs
+    pub fn dummies(log: &Logger, actors: &BTreeMap<Uuid, config::Actor>) -> Self {
+        let mut registry = Self::new();
+        for (uuid, config) in actors.iter() {
+            registry.register(uuid.clone(), Box::new(Dummy::new(log.clone())), config.retries,
+                config.block_offline);
+        }
+        registry
+    }
+
+    pub fn register(&mut self, machine: Uuid, actor: Box<dyn Actor>, retries: u32,
+        block_offline: bool)
+    {
+        self.actors.insert(machine, Binding { actor, retries, block_offline });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actors.is_empty()
+    }
+
+    /// Tell actors about every change of state of their machine for as long as we run
+    ///
+    /// All actors are brought in line with the current state of their machine first.
+    pub async fn run(self, log: Logger, mach: Arc<RwLock<MachinesProvider>>,
+        background: lock::Background, audit: Audit)
+    {
+        let (mut changes, states) = {
+            let mut m = background.write(&mach, "start the actors").await;
+            for uuid in self.actors.keys() {
+                m.set_actor_status(uuid, ActorStatus::default());
+            }
+            (m.subscribe(), m.states())
+        };
+
+        // Every machine gets its own worker so a slow device doesn't hold up the others
+        let mut queues = HashMap::new();
+        let mut workers = Vec::new();
+        for (uuid, binding) in self.actors.into_iter() {
+            let (tx, rx) = mpsc::unbounded();
+            let (override_tx, overrides) = mpsc::unbounded();
+            background.write(&mach, "start the actors").await.set_actor_control(&uuid, override_tx);
+            let worker = Worker {
+                log: log.new(o!("machine" => uuid.to_string())),
+                uuid: uuid.clone(),
+                binding,
+                mach: mach.clone(),
+                background: background.clone(),
+                audit: audit.clone(),
+                status: ActorStatus::default(),
+            };
+            let initial = states.iter().find(|s| s.uuid == uuid).cloned();
+            workers.push(worker.run(initial, rx, overrides).boxed_local());
+            queues.insert(uuid, tx);
+        }
+
+        let dispatch = async move {
+            while let Some(change) = changes.next().await {
+                if let Some(tx) = queues.get(&change.uuid) {
+                    let _ = tx.unbounded_send(change);
+                }
+            }
+        };
+
+        future::join(dispatch, future::join_all(workers)).await;
+    }
+}
+
+/// The actors of all machines as a module
+pub struct Module {
+    bridge: Option<mqtt::Handle>,
+    task: Option<RemoteHandle<()>>,
+}
+
+impl Module {
+    /// Actors switched over MQTT go through `bridge`
+    pub fn new(bridge: Option<mqtt::Handle>) -> Self {
+        Self { bridge, task: None }
+    }
+}
+
+impl super::Module for Module {
+    fn name(&self) -> &'static str {
+        "actors"
+    }
+
+    fn init<'a>(&'a mut self, ctx: ModuleContext<'a>) -> LocalBoxFuture<'a, Result<()>> {
+        async move {
+            let client_id = ctx.config.mqtt.as_ref().map(|m| m.client_id.as_str()).unwrap_or("");
+            let actors = if ctx.config.dry_run.is_some() {
+                Registry::dummies(&ctx.log, &ctx.config.machines.actors)
+            } else {
+                Registry::from_config(&ctx.log, &ctx.config.machines.actors, client_id,
+                    self.bridge.as_ref(), &ctx.pool)
+            };
+            if actors.is_empty() {
+                return Ok(());
+            }
+
+            let f = actors.run(ctx.log.clone(), ctx.mach.clone(), ctx.background(),
+                ctx.audit.clone());
+            let task = ctx.spawner.spawn_local_with_handle(f)
+                .map_err(|e| Error::Boxed(Box::new(e)))?;
+            self.task = Some(task);
+            Ok(())
+        }.boxed_local()
+    }
+
+    /// Devices are left as they are, they're brought in line with their machine on the next start
+    fn shutdown(&mut self) -> LocalBoxFuture<'_, ()> {
+        self.task = None;
+        future::ready(()).boxed_local()
+    }
+}
+
+/// Drives the actor of a single machine
+struct Worker {
+    log: Logger,
+    uuid: Uuid,
+    binding: Binding,
+    mach: Arc<RwLock<MachinesProvider>>,
+    background: lock::Background,
+    audit: Audit,
+    status: ActorStatus,
+}
+
+impl Worker {
+    async fn run(mut self, initial: Option<StateChange>,
+        mut changes: mpsc::UnboundedReceiver<StateChange>,
+        mut overrides: mpsc::UnboundedReceiver<ActorOverride>)
+    {
+        // Nothing changed at startup, so there's nothing to undo if the device can't be brought
+        // in line. It's only recorded.
+        if let Some(state) = initial {
+            let mut next = None;
+            if let Err(e) = self.apply(&state, &mut changes, &mut next).await {
+                warn!(self.log, "Could not bring actor in line with machine state: {}", e);
+            }
+            if let Some(next) = next {
+                self.handle(next, &mut changes).await;
+            }
+        }
+
+        loop {
+            let next = match future::select(changes.next(), overrides.next()).await {
+                future::Either::Left((change, _)) => future::Either::Left(change),
+                future::Either::Right((o, _)) => future::Either::Right(o),
+            };
+            match next {
+                future::Either::Left(Some(change)) => self.handle(change, &mut changes).await,
+                future::Either::Right(Some(o)) => self.force(o).await,
+                // We're shutting down
+                future::Either::Left(None) => return,
+                // Nobody can override us anymore, but changes still have to be carried out
+                future::Either::Right(None) => break,
+            }
+        }
+        while let Some(change) = changes.next().await {
+            self.handle(change, &mut changes).await;
+        }
+    }
+
+    /// Carry out a state change, and whatever came in after it while we were busy
+    async fn handle(&mut self, change: StateChange,
+        changes: &mut mpsc::UnboundedReceiver<StateChange>)
+    {
+        let mut change = change;
+        loop {
+            // Only the latest state matters, everything before it is superseded
+            while let Ok(Some(c)) = changes.try_next() {
+                change = c;
+            }
+
+            let mut next = None;
+            if let Err(e) = self.apply(&change, changes, &mut next).await {
+                self.failed(&change, e).await;
+            }
+            match next {
+                Some(c) => change = c,
+                None => return,
+            }
+        }
+    }
+
+    /// Apply a change, retrying with backoff until we run out of retries
+    ///
+    /// Gives up early if the machine changes state again in the meantime, putting the new state
+    /// into `next`.
+    async fn apply(&mut self, change: &StateChange,
+        changes: &mut mpsc::UnboundedReceiver<StateChange>, next: &mut Option<StateChange>)
+        -> io::Result<()>
+    {
+        let command = MachineCommand::for_status(change.status);
+        let mut delay = MIN_RETRY_DELAY;
+        let mut attempt = 0;
+        loop {
+            match self.command(change, command).await {
+                Ok(()) => {
+                    debug!(self.log, "Actor carried out {}", command.as_str());
+                    return Ok(());
+                },
+                Err(e) => {
+                    if attempt >= self.binding.retries {
+                        return Err(e);
+                    }
+                    attempt += 1;
+                    warn!(self.log, "Actor failed to {}, retrying in {}s: {}", command.as_str(),
+                        delay.as_secs(), e);
+                },
+            }
+
+            // A new state makes trying to reach the old one pointless
+            match future::select(task::sleep(delay).boxed_local(), changes.next()).await {
+                future::Either::Left(_) => {},
+                future::Either::Right((Some(c), _)) => {
+                    *next = Some(c);
+                    return Ok(());
+                },
+                // We're shutting down
+                future::Either::Right((None, _)) => return Ok(()),
+            }
+            delay = std::cmp::min(delay * 2, MAX_RETRY_DELAY);
+        }
+    }
+
+    /// Tell the actor to carry out `command` once, keeping track of how it did
+    async fn command(&mut self, state: &StateChange, command: MachineCommand) -> io::Result<()> {
+        let now = self.background.read(&self.mach, "note the time").await.now();
+        self.status.last_command = Some((now, command == MachineCommand::PowerOn));
+        let r = self.binding.actor.apply(state, command).await;
+        let now = self.background.read(&self.mach, "note the time").await.now();
+        match r {
+            Ok(()) => {
+                self.status.last_success = Some(now);
+                self.status.last_error = None;
+            },
+            Err(ref e) => self.status.last_error = Some((now, e.to_string())),
+        }
+        self.report().await;
+        r
+    }
+
+    /// Switch the device as a trainer asked for, leaving the machine as it is
+    ///
+    /// Tried only once, whoever asked is right there to try again.
+    async fn force(&mut self, o: ActorOverride) {
+        let command = if o.power { MachineCommand::PowerOn } else { MachineCommand::PowerOff };
+        let state = self.background.read(&self.mach, "override an actor").await.state(&self.uuid);
+        let r = match state {
+            // Actors are told about a state the command fits, since that's what templates of
+            // exec and http actors are filled in with
+            Some(mut state) => {
+                state.status = match command {
+                    MachineCommand::PowerOn => machine::Status::Occupied,
+                    MachineCommand::PowerOff => machine::Status::Free,
+                };
+                self.command(&state, command).await
+            },
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "the machine is gone")),
+        };
+        let _ = o.reply.send(r);
+    }
+
+    /// Put the machine into a safe state after its actor gave up on `change`
+    async fn failed(&self, change: &StateChange, e: io::Error) {
+        let command = MachineCommand::for_status(change.status);
+        let power = command == MachineCommand::PowerOn;
+        let reason = Some(format!("The device powering the machine is offline: {}", e))
+            .filter(|_| self.binding.block_offline);
+        let r = self.background.write(&self.mach, "handle a failed actor").await
+            .actor_failed(&self.log, change, reason);
+        match r {
+            Ok(Some(status)) => {
+                error!(self.log, "Actor failed to {}, machine is now {}: {}", command.as_str(),
+                    status.as_str(), e);
+                self.audit.record(AuditEvent::ActorFailed {
+                    machine: &self.uuid, power, status: status.as_str(),
+                });
+            },
+            // The machine moved on already, whatever it's switched to next is what matters
+            Ok(None) => warn!(self.log, "Actor failed to {}: {}", command.as_str(), e),
+            Err(e) => error!(self.log, "Could not handle failed actor: {}", e),
+        }
+    }
+
+    /// Make our status visible to the manage capability
+    async fn report(&self) {
+        self.background.write(&self.mach, "report the actor status").await
+            .set_actor_status(&self.uuid, self.status.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use futures::executor::LocalPool;
+
+    use crate::config::Config;
+    use crate::testing::{logger, machines, TempDir, TestClock, LASER};
+
+    /// A relay keeping track of what it was told, which can be made unreachable for a while
+    #[derive(Clone)]
+    struct Relay {
+        commands: Arc<Mutex<Vec<MachineCommand>>>,
+        failing: Arc<AtomicU32>,
+    }
+
+    impl Relay {
+        fn new() -> Self {
+            Relay { commands: Arc::new(Mutex::new(Vec::new())), failing: Arc::new(0.into()) }
+        }
+
+        /// Fail the next `times` commands
+        fn fail(&self, times: u32) {
+            self.failing.store(times, Ordering::SeqCst);
+        }
+
+        fn commands(&self) -> Vec<MachineCommand> {
+            self.commands.lock().unwrap().clone()
+        }
+    }
+
+    impl Actor for Relay {
+        fn apply(&mut self, _state: &StateChange, command: MachineCommand)
+            -> LocalBoxFuture<'_, io::Result<()>>
+        {
+            self.commands.lock().unwrap().push(command);
+            let fail = self.failing
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+            let r = if fail {
+                Err(io::Error::new(io::ErrorKind::Other, "relay unreachable"))
+            } else {
+                Ok(())
+            };
+            future::ready(r).boxed_local()
+        }
+    }
+
+    /// Run the laser's actor `relay` in `pool`, returning the machines it acts for
+    fn run(pool: &LocalPool, dir: &TempDir, relay: &Relay, retries: u32, block_offline: bool)
+        -> Arc<RwLock<MachinesProvider>>
+    {
+        let mach = Arc::new(RwLock::new(machines(dir, &TestClock::at(1_000_000))));
+        let mut registry = Registry::new();
+        registry.register(LASER, Box::new(relay.clone()), retries, block_offline);
+        let audit = Audit::open(&Config::default()).unwrap();
+        let background = lock::Background::new(logger(), lock::DEFAULT_TIMEOUT);
+        pool.spawner().spawn_local(registry.run(logger(), mach.clone(), background, audit))
+            .unwrap();
+        mach
+    }
+
+    /// Wait for the machines to get where `f` wants them
+    async fn until(mach: &RwLock<MachinesProvider>, f: impl Fn(&MachinesProvider) -> bool) {
+        for _ in 0..500 {
+            if f(&*mach.read().await) {
+                return;
+            }
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the machines never got there");
+    }
+
+    /// Wait for the relay to have been told `n` commands, and for how that went to be reported
+    async fn commands(mach: &RwLock<MachinesProvider>, relay: &Relay, n: usize) {
+        until(mach, |m| {
+            let reported = m.actor_status(&LASER).and_then(|s| s.last_command).is_some();
+            relay.commands().len() == n && reported
+        }).await;
+        // The worker may still be putting the machine into a safe state
+        task::sleep(Duration::from_millis(50)).await;
+    }
+
+    fn status(m: &MachinesProvider) -> machine::Status {
+        m.get(&LASER).unwrap().status
+    }
+
+    fn last_error(m: &MachinesProvider) -> Option<String> {
+        m.actor_status(&LASER).and_then(|s| s.last_error.clone()).map(|(_, e)| e)
+    }
+
+    #[test]
+    fn brought_in_line_at_start() {
+        let mut pool = LocalPool::new();
+        let dir = TempDir::new();
+        let relay = Relay::new();
+        let mach = run(&pool, &dir, &relay, 0, false);
+
+        pool.run_until(commands(&mach, &relay, 1));
+        assert_eq!(relay.commands(), vec![MachineCommand::PowerOff]);
+        let m = pool.run_until(mach.read());
+        let reported = m.actor_status(&LASER).unwrap();
+        assert_eq!(reported.last_command, Some((1_000_000, false)));
+        assert_eq!(reported.last_success, Some(1_000_000));
+        assert_eq!(reported.last_error, None);
+    }
+
+    #[test]
+    fn errors_are_reported() {
+        let mut pool = LocalPool::new();
+        let dir = TempDir::new();
+        let relay = Relay::new();
+        relay.fail(1);
+        let mach = run(&pool, &dir, &relay, 0, false);
+
+        pool.run_until(commands(&mach, &relay, 1));
+        let m = pool.run_until(mach.read());
+        assert_eq!(last_error(&m).as_deref(), Some("relay unreachable"));
+        // There was nothing to undo
+        assert_eq!(status(&m), machine::Status::Free);
+    }
+
+    #[test]
+    fn retries_until_it_works() {
+        let mut pool = LocalPool::new();
+        let dir = TempDir::new();
+        let relay = Relay::new();
+        let mach = run(&pool, &dir, &relay, 1, false);
+
+        pool.run_until(async {
+            commands(&mach, &relay, 1).await;
+            relay.fail(1);
+            mach.write().await.use_(&logger(), &LASER, "alice", false, None).unwrap();
+            commands(&mach, &relay, 3).await;
+        });
+        assert_eq!(relay.commands(), vec![MachineCommand::PowerOff, MachineCommand::PowerOn,
+            MachineCommand::PowerOn]);
+        let m = pool.run_until(mach.read());
+        assert_eq!(status(&m), machine::Status::Occupied);
+        assert_eq!(last_error(&m), None);
+    }
+
+    #[test]
+    fn gives_back_once_it_gives_up() {
+        let mut pool = LocalPool::new();
+        let dir = TempDir::new();
+        let relay = Relay::new();
+        let mach = run(&pool, &dir, &relay, 1, false);
+
+        pool.run_until(async {
+            commands(&mach, &relay, 1).await;
+            relay.fail(2);
+            mach.write().await.use_(&logger(), &LASER, "alice", false, None).unwrap();
+            commands(&mach, &relay, 4).await;
+        });
+        // Giving the machine back is a change of its own, switching the relay off again
+        assert_eq!(relay.commands(), vec![MachineCommand::PowerOff, MachineCommand::PowerOn,
+            MachineCommand::PowerOn, MachineCommand::PowerOff]);
+        assert_eq!(status(&pool.run_until(mach.read())), machine::Status::Free);
+    }
+
+    #[test]
+    fn blocks_offline_devices() {
+        let mut pool = LocalPool::new();
+        let dir = TempDir::new();
+        let relay = Relay::new();
+        let mach = run(&pool, &dir, &relay, 0, true);
+
+        pool.run_until(async {
+            commands(&mach, &relay, 1).await;
+            relay.fail(1);
+            mach.write().await.use_(&logger(), &LASER, "alice", false, None).unwrap();
+            commands(&mach, &relay, 3).await;
+        });
+        let m = pool.run_until(mach.read());
+        assert_eq!(status(&m), machine::Status::Blocked);
+        assert!(m.get(&LASER).unwrap().block_reason.unwrap().contains("relay unreachable"));
+    }
+
+    #[test]
+    fn new_state_cuts_retries_short() {
+        let mut pool = LocalPool::new();
+        let dir = TempDir::new();
+        let relay = Relay::new();
+        let mach = run(&pool, &dir, &relay, 3, false);
+
+        pool.run_until(async {
+            commands(&mach, &relay, 1).await;
+            relay.fail(1);
+            mach.write().await.use_(&logger(), &LASER, "alice", false, None).unwrap();
+            commands(&mach, &relay, 2).await;
+            // Still waiting to retry switching on
+            mach.write().await.give_back(&logger(), &LASER).unwrap();
+            commands(&mach, &relay, 3).await;
+        });
+        assert_eq!(relay.commands(), vec![MachineCommand::PowerOff, MachineCommand::PowerOn,
+            MachineCommand::PowerOff]);
+        let m = pool.run_until(mach.read());
+        assert_eq!(status(&m), machine::Status::Free);
+        assert_eq!(last_error(&m), None);
+    }
+
+    #[test]
+    fn overrides_leave_the_machine_alone() {
+        let mut pool = LocalPool::new();
+        let dir = TempDir::new();
+        let relay = Relay::new();
+        let mach = run(&pool, &dir, &relay, 3, true);
+
+        pool.run_until(async {
+            commands(&mach, &relay, 1).await;
+            let reply = mach.read().await.override_actor(&LASER, true).unwrap();
+            reply.await.unwrap().unwrap();
+            let m = mach.read().await;
+            assert_eq!(status(&m), machine::Status::Free);
+            assert_eq!(m.actor_status(&LASER).unwrap().last_command, Some((1_000_000, true)));
+            drop(m);
+
+            // Tried only once, and a failure doesn't put the machine anywhere
+            relay.fail(1);
+            let reply = mach.read().await.override_actor(&LASER, false).unwrap();
+            let e = reply.await.unwrap().unwrap_err();
+            assert_eq!(e.to_string(), "relay unreachable");
+            let m = mach.read().await;
+            assert_eq!(status(&m), machine::Status::Free);
+            assert!(!m.actor_status(&LASER).unwrap().online());
+            drop(m);
+
+            // The next change switches the device as usual, which brings it back online
+            mach.write().await.use_(&logger(), &LASER, "alice", false, None).unwrap();
+            commands(&mach, &relay, 4).await;
+        });
+        assert_eq!(relay.commands(), vec![MachineCommand::PowerOff, MachineCommand::PowerOn,
+            MachineCommand::PowerOff, MachineCommand::PowerOn]);
+        let m = pool.run_until(mach.read());
+        assert_eq!(status(&m), machine::Status::Occupied);
+        assert!(m.actor_status(&LASER).unwrap().online());
+    }
+
+    #[test]
+    fn no_overrides_without_an_actor() {
+        let dir = TempDir::new();
+        let mach = machines(&dir, &TestClock::at(1_000_000));
+        assert!(mach.override_actor(&LASER, true).is_none());
+    }
+}
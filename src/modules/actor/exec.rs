@@ -0,0 +1,88 @@
+//! Actor running a command on every change of machine state
+
+use std::io;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use futures::executor::ThreadPool;
+use futures::future::{FutureExt, LocalBoxFuture};
+use futures::task::SpawnExt;
+
+use async_std::future::timeout;
+
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+
+use slog::Logger;
+
+use crate::machine::StateChange;
+
+use super::{expand, Actor, MachineCommand};
+
+/// Runs a command for every change of its machine, e.g. a vendor tool switching it
+///
+/// The command failing or taking too long counts as the device failing.
+pub struct Exec {
+    log: Logger,
+    /// Program and arguments, with placeholders not filled in yet
+    command: Vec<String>,
+    timeout: Duration,
+    /// Waiting for a command to finish blocks, so it's done on here instead of the executor
+    pool: ThreadPool,
+}
+
+impl Exec {
+    pub fn new(log: Logger, command: Vec<String>, timeout: Duration, pool: ThreadPool) -> Self {
+        Self { log, command, timeout, pool }
+    }
+
+}
+
+impl Actor for Exec {
+    fn apply(&mut self, state: &StateChange, _command: MachineCommand)
+        -> LocalBoxFuture<'_, io::Result<()>>
+    {
+        let args: Vec<String> = self.command.iter().map(|arg| expand(arg, state, false)).collect();
+        async move {
+            let program = args[0].clone();
+
+            // Starting the command is quick, only waiting for it has to happen elsewhere
+            let child = Command::new(&program)
+                .args(&args[1..])
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .map_err(|e|
+                    io::Error::new(e.kind(), format!("could not run {}: {}", program, e)))?;
+            let pid = Pid::from_raw(child.id() as i32);
+            let output = self.pool.spawn_with_handle(async move { child.wait_with_output() })
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+            let output = match timeout(self.timeout, output).await {
+                Ok(output) => output?,
+                Err(_) => {
+                    // This also ends the wait on the pool
+                    let _ = kill(pid, Signal::SIGKILL);
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, format!(
+                        "{} did not finish within {}s", program, self.timeout.as_secs())));
+                },
+            };
+
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if output.status.success() {
+                info!(self.log, "{} finished", program;
+                    "stdout" => stdout.trim(), "stderr" => stderr.trim());
+                Ok(())
+            } else {
+                warn!(self.log, "{} failed with {}", program, output.status;
+                    "stdout" => stdout.trim(), "stderr" => stderr.trim());
+                // The last thing a tool says before failing is usually why
+                let why = stderr.trim().lines().last().unwrap_or("no output");
+                Err(io::Error::new(io::ErrorKind::Other,
+                    format!("{} failed with {}: {}", program, output.status, why)))
+            }
+        }.boxed_local()
+    }
+}
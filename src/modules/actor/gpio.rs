@@ -0,0 +1,282 @@
+//! Actor switching a relay on a GPIO line of the machine we run on, e.g. a Raspberry Pi
+//!
+//! Lines are driven through the Linux GPIO character device, which is only compiled in with the
+//! `gpio` feature. The simulation keeping the line level in a file is always available.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::executor::ThreadPool;
+use futures::future::{FutureExt, LocalBoxFuture};
+use futures::task::SpawnExt;
+
+use async_std::task;
+
+use slog::Logger;
+
+use crate::config;
+use crate::machine::StateChange;
+
+use super::{Actor, MachineCommand};
+
+/// How often the feedback line is read while waiting for the relay to follow
+const FEEDBACK_POLL: Duration = Duration::from_millis(100);
+
+/// Level of a line for the machine having `power` or not
+///
+/// Most relay boards switch on when their input is pulled low, which is what `active_low` is for.
+fn level(power: bool, active_low: bool) -> bool {
+    power != active_low
+}
+
+/// The lines of one relay
+trait Lines: Send {
+    /// Drive the output line to `high`
+    fn set(&mut self, high: bool) -> io::Result<()>;
+
+    /// Read the level of the feedback line, `None` if there is none
+    fn feedback(&mut self) -> io::Result<Option<bool>>;
+}
+
+/// Keeps the level of the output line in a file instead, with the feedback line following it
+struct Simulated {
+    path: PathBuf,
+    feedback: bool,
+}
+
+impl Lines for Simulated {
+    fn set(&mut self, high: bool) -> io::Result<()> {
+        fs::write(&self.path, if high { "1\n" } else { "0\n" })
+    }
+
+    fn feedback(&mut self) -> io::Result<Option<bool>> {
+        if !self.feedback {
+            return Ok(None);
+        }
+        match fs::read_to_string(&self.path)?.trim() {
+            "1" => Ok(Some(true)),
+            "0" => Ok(Some(false)),
+            s => Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("{} contains {} instead of a level", self.path.display(), s))),
+        }
+    }
+}
+
+#[cfg(feature = "gpio")]
+struct Chip {
+    output: gpio_cdev::LineHandle,
+    feedback: Option<gpio_cdev::LineHandle>,
+}
+
+#[cfg(feature = "gpio")]
+impl Lines for Chip {
+    fn set(&mut self, high: bool) -> io::Result<()> {
+        self.output.set_value(high as u8).map_err(cdev)
+    }
+
+    fn feedback(&mut self) -> io::Result<Option<bool>> {
+        match self.feedback {
+            Some(ref line) => line.get_value().map(|v| Some(v != 0)).map_err(cdev),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Request the lines of the relay from the chip
+#[cfg(feature = "gpio")]
+fn open_chip(config: &config::Actor, line: u32) -> io::Result<Box<dyn Lines>> {
+    use gpio_cdev::LineRequestFlags;
+
+    // Shows up as the user of the lines in gpioinfo
+    const CONSUMER: &str = "diflouroborane";
+
+    let mut chip = gpio_cdev::Chip::new(&config.chip).map_err(|e| io::Error::new(
+        io::ErrorKind::Other, format!("could not open {}: {}", config.chip.display(), e)))?;
+
+    // Until the actor is told otherwise the machine has no power
+    let off = level(false, config.active_low) as u8;
+    let output = chip.get_line(line)
+        .and_then(|l| l.request(LineRequestFlags::OUTPUT, off, CONSUMER))
+        .map_err(cdev)?;
+    let feedback = match config.feedback_line {
+        Some(f) => Some(chip.get_line(f)
+            .and_then(|l| l.request(LineRequestFlags::INPUT, 0, CONSUMER))
+            .map_err(cdev)?),
+        None => None,
+    };
+
+    Ok(Box::new(Chip { output, feedback }))
+}
+
+#[cfg(not(feature = "gpio"))]
+fn open_chip(_config: &config::Actor, _line: u32) -> io::Result<Box<dyn Lines>> {
+    Err(io::Error::new(io::ErrorKind::Other,
+        "built without the gpio feature, only simulated lines are available"))
+}
+
+#[cfg(feature = "gpio")]
+fn cdev<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+/// Switches a relay on a GPIO line, confirming it with the feedback line if there is one
+pub struct Gpio {
+    log: Logger,
+    lines: Arc<Mutex<Box<dyn Lines>>>,
+    active_low: bool,
+    timeout: Duration,
+    /// The ioctls driving the lines block, so they're done on here instead of the executor
+    pool: ThreadPool,
+}
+
+impl Gpio {
+    pub fn from_config(log: Logger, config: &config::Actor, pool: ThreadPool)
+        -> io::Result<Self>
+    {
+        // Checked when loading the config
+        let line = config.line.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+            "no line set"))?;
+        let lines: Box<dyn Lines> = match config.simulate {
+            Some(ref path) => {
+                warn!(log, "Only simulating GPIO line {} in {}", line, path.display());
+                Box::new(Simulated { path: path.clone(), feedback: config.feedback_line.is_some() })
+            },
+            None => open_chip(config, line)?,
+        };
+
+        Ok(Self {
+            log,
+            lines: Arc::new(Mutex::new(lines)),
+            active_low: config.active_low,
+            timeout: Duration::from_secs(config.timeout),
+            pool,
+        })
+    }
+
+    /// Do something with the lines on the pool
+    async fn blocking<T, F>(&self, f: F) -> io::Result<T>
+        where F: FnOnce(&mut dyn Lines) -> io::Result<T> + Send + 'static,
+              T: Send + 'static,
+    {
+        let lines = self.lines.clone();
+        let handle = self.pool.spawn_with_handle(async move {
+            // A panic while switching leaves the lines as they were, they're still usable
+            let mut lines = lines.lock().unwrap_or_else(|e| e.into_inner());
+            f(&mut **lines)
+        }).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        handle.await
+    }
+}
+
+impl Actor for Gpio {
+    fn apply(&mut self, state: &StateChange, command: MachineCommand)
+        -> LocalBoxFuture<'_, io::Result<()>>
+    {
+        let high = level(command == MachineCommand::PowerOn, self.active_low);
+        let uuid = state.uuid;
+        async move {
+            self.blocking(move |l| l.set(high)).await?;
+
+            // Relays take a moment to pull in, so the feedback may lag behind a bit
+            let deadline = Instant::now() + self.timeout;
+            loop {
+                match self.blocking(|l| l.feedback()).await? {
+                    Some(l) if l != high && Instant::now() < deadline =>
+                        task::sleep(FEEDBACK_POLL).await,
+                    Some(l) if l != high => return Err(io::Error::new(io::ErrorKind::TimedOut,
+                        format!("feedback line did not follow within {}s, relay stuck?",
+                            self.timeout.as_secs()))),
+                    _ => break,
+                }
+            }
+
+            debug!(self.log, "Switched machine {} to {}", uuid, command.as_str());
+            Ok(())
+        }.boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor::block_on;
+
+    use crate::machine;
+    use crate::testing::{logger, TempDir, LASER};
+
+    #[test]
+    fn levels() {
+        assert!(level(true, false));
+        assert!(!level(false, false));
+        // Relay boards pulling in on low
+        assert!(!level(true, true));
+        assert!(level(false, true));
+    }
+
+    /// A simulated relay with its line kept in `dir`
+    fn simulated(dir: &TempDir, active_low: bool, feedback: bool) -> Gpio {
+        let mut toml = format!("type = \"gpio\"\nline = 17\nactive_low = {}\nsimulate = {:?}\n",
+            active_low, dir.join("line"));
+        if feedback {
+            toml.push_str("feedback_line = 27\n");
+        }
+        let config: config::Actor = toml::from_str(&toml).unwrap();
+        Gpio::from_config(logger(), &config, ThreadPool::new().unwrap()).unwrap()
+    }
+
+    fn laser() -> StateChange {
+        StateChange {
+            uuid: LASER,
+            name: "Laser".to_string(),
+            status: machine::Status::Occupied,
+            occupant: Some("alice".to_string()),
+            since: None,
+            note: None,
+        }
+    }
+
+    #[test]
+    fn drives_the_line() {
+        let dir = TempDir::new();
+        let mut gpio = simulated(&dir, false, false);
+        block_on(gpio.apply(&laser(), MachineCommand::PowerOn)).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("line")).unwrap(), "1\n");
+        block_on(gpio.apply(&laser(), MachineCommand::PowerOff)).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("line")).unwrap(), "0\n");
+    }
+
+    #[test]
+    fn active_low_inverts() {
+        let dir = TempDir::new();
+        let mut gpio = simulated(&dir, true, true);
+        block_on(gpio.apply(&laser(), MachineCommand::PowerOn)).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("line")).unwrap(), "0\n");
+        block_on(gpio.apply(&laser(), MachineCommand::PowerOff)).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("line")).unwrap(), "1\n");
+    }
+
+    #[test]
+    fn garbled_feedback_fails() {
+        let dir = TempDir::new();
+        let mut lines = Simulated { path: dir.join("line"), feedback: true };
+        lines.set(true).unwrap();
+        assert_eq!(lines.feedback().unwrap(), Some(true));
+        fs::write(dir.join("line"), "maybe\n").unwrap();
+        assert_eq!(lines.feedback().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut without = Simulated { path: dir.join("line"), feedback: false };
+        assert_eq!(without.feedback().unwrap(), None);
+    }
+
+    #[test]
+    fn needs_a_line() {
+        let config: config::Actor = toml::from_str("type = \"gpio\"\nsimulate = \"/dev/null\"")
+            .unwrap();
+        let e = Gpio::from_config(logger(), &config, ThreadPool::new().unwrap()).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,45 @@
+//! Actor calling a webhook on every change of machine state
+
+use std::io;
+use std::time::Duration;
+
+use futures::future::{FutureExt, LocalBoxFuture};
+
+use crate::config;
+use crate::machine::StateChange;
+use crate::modules::http::Endpoint;
+
+use super::{json_body, Actor, MachineCommand};
+
+/// Calls a webhook, e.g. of a door controller, for every change of its machine
+///
+/// Anything but a 2xx answer counts as the device failing.
+pub struct Http {
+    endpoint: Endpoint,
+    /// Body template, everything we know about the machine if not set
+    body: Option<String>,
+}
+
+impl Http {
+    pub fn from_config(config: &config::Actor) -> io::Result<Self> {
+        let url = config.url.as_deref().unwrap_or_default();
+        let endpoint = Endpoint::new(url, &config.method, config.headers(), config.verify_tls,
+            Duration::from_secs(config.timeout))?;
+        Ok(Self { endpoint, body: config.body.clone() })
+    }
+}
+
+impl Actor for Http {
+    fn apply(&mut self, state: &StateChange, _command: MachineCommand)
+        -> LocalBoxFuture<'_, io::Result<()>>
+    {
+        let body = json_body(self.body.as_deref(), state);
+        async move {
+            match self.endpoint.send(&body).await? {
+                200..=299 => Ok(()),
+                status => Err(io::Error::new(io::ErrorKind::Other,
+                    format!("{} answered with status {}", self.endpoint.url(), status))),
+            }
+        }.boxed_local()
+    }
+}
@@ -0,0 +1,467 @@
+//! Sending mails about machines and logins, e.g. "Laser cutter was blocked by alice"
+//!
+//! Only compiled with the `email` feature. Mails about events go to the admins in `to` and are
+//! queued, so a slow relay never holds up whatever caused them. Like notifiers this is best
+//! effort, failing to send is logged and has no other effect. Nobody gets more than
+//! `per_recipient` mails an hour, anything beyond is dropped.
+//!
+//! It's also the `email` channel for telling users about machines they wait for, using the
+//! address in their contact details. Those mails are sent right away so we know whether they
+//! made it.
+
+mod smtp;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::channel::mpsc;
+use futures::future::{self, FutureExt, LocalBoxFuture, RemoteHandle};
+use futures::stream::StreamExt;
+use futures::task::LocalSpawnExt;
+
+use async_std::sync::RwLock;
+use async_std::task;
+
+use serde::Deserialize;
+
+use slog::Logger;
+
+use uuid::Uuid;
+
+use crate::audit::{Event, Recorded};
+use crate::config::{Config, Secret};
+use crate::error::{Error, Result};
+use crate::machine::{lock, MachinesProvider};
+use crate::machine::watch::{Channel, Notice, What};
+
+use self::smtp::{Mail, Relay};
+use super::ModuleContext;
+
+/// Delay before the first retry of a failed mail, doubling after every further failure
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// What we can send mails about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Kind {
+    Blocked,
+    Unblocked,
+    /// A machine was given back for somebody who didn't do so themselves
+    ForceFreed,
+    /// The device of a machine didn't switch, so the machine was put into a safe state
+    ActorFailed,
+    /// Somebody failed to log in `auth_failures` times in a row
+    AuthFailures,
+    /// A machine a user waits for became free
+    Free,
+    /// An admin tests whether mails arrive
+    Test,
+}
+
+#[derive(Deserialize)]
+struct Settings {
+    smtp_host: String,
+    #[serde(default = "default_port")]
+    port: u16,
+    /// Encrypt the connection before logging in. Without it `username` may not be set, so the
+    /// password never goes out in the clear.
+    #[serde(default = "default_starttls")]
+    starttls: bool,
+    username: Option<String>,
+    password: Option<Secret>,
+    /// Address the mails are from
+    from: String,
+    /// Who gets mails about `events`
+    #[serde(default)]
+    to: Vec<String>,
+    #[serde(default = "default_events")]
+    events: Vec<Kind>,
+    #[serde(default)]
+    templates: Templates,
+    /// Most mails to one address within an hour
+    #[serde(default = "default_per_recipient")]
+    per_recipient: usize,
+    /// Most mails waiting to be sent, the oldest ones are dropped beyond that
+    #[serde(default = "default_queue")]
+    queue: usize,
+    #[serde(default = "default_auth_failures")]
+    auth_failures: u32,
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    #[serde(default = "default_retries")]
+    retries: u32,
+}
+
+fn default_port() -> u16 {
+    587
+}
+
+fn default_starttls() -> bool {
+    true
+}
+
+fn default_events() -> Vec<Kind> {
+    vec![Kind::ActorFailed, Kind::AuthFailures]
+}
+
+fn default_per_recipient() -> usize {
+    10
+}
+
+fn default_queue() -> usize {
+    100
+}
+
+fn default_auth_failures() -> u32 {
+    5
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+impl Settings {
+    fn relay(&self) -> io::Result<Relay> {
+        let credentials = match (&self.username, &self.password) {
+            (Some(user), Some(password)) => Some((user.clone(), password.expose().to_string())),
+            (None, None) => None,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "username and password have to be set together")),
+        };
+        if credentials.is_some() && !self.starttls {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "refusing to log in without starttls, the password would be sent in the clear"));
+        }
+        Ok(Relay {
+            host: self.smtp_host.clone(),
+            port: self.port,
+            starttls: self.starttls,
+            credentials,
+            timeout: Duration::from_secs(self.timeout),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct Template {
+    subject: String,
+    body: String,
+}
+
+/// Subject and body for each kind of mail. `{name}`, `{uuid}`, `{user}`, `{status}`, `{count}`
+/// and `{peer}` are replaced in both, as far as they apply.
+#[derive(Default, Deserialize)]
+struct Templates {
+    blocked: Option<Template>,
+    unblocked: Option<Template>,
+    force_freed: Option<Template>,
+    actor_failed: Option<Template>,
+    auth_failures: Option<Template>,
+    free: Option<Template>,
+    test: Option<Template>,
+}
+
+impl Templates {
+    fn get(&self, kind: Kind) -> (&str, &str) {
+        let template = match kind {
+            Kind::Blocked => &self.blocked,
+            Kind::Unblocked => &self.unblocked,
+            Kind::ForceFreed => &self.force_freed,
+            Kind::ActorFailed => &self.actor_failed,
+            Kind::AuthFailures => &self.auth_failures,
+            Kind::Free => &self.free,
+            Kind::Test => &self.test,
+        };
+        if let Some(t) = template {
+            return (&t.subject, &t.body);
+        }
+        match kind {
+            Kind::Blocked => ("{name} was blocked", "{name} was blocked by {user}."),
+            Kind::Unblocked => ("{name} was unblocked", "{name} was unblocked by {user}."),
+            Kind::ForceFreed => ("{name} was given back",
+                "{name} was given back for {user}, whose connection went idle."),
+            Kind::ActorFailed => ("{name} could not be switched",
+                "The device of {name} didn't switch, so the machine is now {status}."),
+            Kind::AuthFailures => ("Failed logins as {user}",
+                "There were {count} failed logins as {user} in a row, the last from {peer}."),
+            Kind::Free => ("{name} is free", "Hello {user},\n\n{name} is free now."),
+            Kind::Test => ("Test mail",
+                "Hello {user},\n\nthis is a test, mails from FabAccess reach you."),
+        }
+    }
+}
+
+/// Everything there is to fill into a template
+#[derive(Default)]
+struct Values {
+    machine: Option<Uuid>,
+    name: String,
+    user: String,
+    status: String,
+    count: u32,
+    peer: String,
+}
+
+impl Values {
+    fn fill(&self, template: &str) -> String {
+        template
+            .replace("{name}", &self.name)
+            .replace("{uuid}", &self.machine.map(|u| u.to_string()).unwrap_or_default())
+            .replace("{user}", &self.user)
+            .replace("{status}", &self.status)
+            .replace("{count}", &self.count.to_string())
+            .replace("{peer}", &self.peer)
+    }
+}
+
+struct Email {
+    log: Logger,
+    settings: Settings,
+    relay: Relay,
+    queue: RefCell<VecDeque<Mail>>,
+    /// When mails went to an address within the last hour, by address
+    sent: RefCell<HashMap<String, VecDeque<u64>>>,
+    /// Failed logins in a row, by user
+    failures: RefCell<HashMap<String, u32>>,
+}
+
+impl Email {
+    fn new(log: Logger, settings: Settings) -> io::Result<Self> {
+        let relay = settings.relay()?;
+        Ok(Self {
+            log,
+            settings,
+            relay,
+            queue: RefCell::new(VecDeque::new()),
+            sent: RefCell::new(HashMap::new()),
+            failures: RefCell::new(HashMap::new()),
+        })
+    }
+
+    fn mail(&self, kind: Kind, values: &Values, to: &str) -> Mail {
+        let (subject, body) = self.settings.templates.get(kind);
+        Mail {
+            from: self.settings.from.clone(),
+            to: to.to_string(),
+            subject: values.fill(subject),
+            body: values.fill(body),
+        }
+    }
+
+    /// Whether another mail may go to `to` now, counting it if so
+    fn allow(&self, to: &str) -> bool {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut sent = self.sent.borrow_mut();
+        // Forget about addresses that had their last mail over an hour ago
+        sent.retain(|_, times| times.back().map_or(false, |t| t + 3600 > now));
+        let times = sent.entry(to.to_string()).or_insert_with(VecDeque::new);
+        while times.front().map_or(false, |t| t + 3600 <= now) {
+            times.pop_front();
+        }
+        if times.len() >= self.settings.per_recipient {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+
+    /// Queue mails for the events we're interested in
+    async fn collect(&self, mut events: mpsc::UnboundedReceiver<Recorded>,
+        mach: Arc<RwLock<MachinesProvider>>, background: lock::Background,
+        wake: mpsc::UnboundedSender<()>)
+    {
+        while let Some(recorded) = events.next().await {
+            let (kind, mut values) = match self.classify(recorded) {
+                Some(k) => k,
+                None => continue,
+            };
+            if !self.settings.events.contains(&kind) {
+                continue;
+            }
+            if let Some(ref uuid) = values.machine {
+                values.name = background.read(&mach, "look up a machine").await.get(uuid)
+                    .map(|m| m.name).unwrap_or_else(|| uuid.to_string());
+            }
+
+            let mut queue = self.queue.borrow_mut();
+            for to in self.settings.to.iter() {
+                if !self.allow(to) {
+                    warn!(self.log, "Too many mails to {} within the last hour, dropping one", to);
+                    continue;
+                }
+                if queue.len() >= self.settings.queue {
+                    warn!(self.log, "Too many mails waiting, dropping the oldest");
+                    queue.pop_front();
+                }
+                queue.push_back(self.mail(kind, &values, to));
+            }
+            let _ = wake.unbounded_send(());
+        }
+    }
+
+    /// What kind of event `recorded` is, if we can send mails about it
+    fn classify(&self, recorded: Recorded) -> Option<(Kind, Values)> {
+        let peer = recorded.peer.unwrap_or_default();
+        match recorded.event {
+            Event::MachineBlocked { authzid, machine, blocked } => {
+                let kind = if blocked { Kind::Blocked } else { Kind::Unblocked };
+                Some((kind, Values { machine: Some(machine), user: authzid, peer,
+                    ..Default::default() }))
+            },
+            Event::MachineReleased { authzid, machine } => Some((Kind::ForceFreed,
+                Values { machine: Some(machine), user: authzid, peer, ..Default::default() })),
+            Event::ActorFailed { machine, status, .. } => Some((Kind::ActorFailed,
+                Values { machine: Some(machine), status, peer, ..Default::default() })),
+            Event::Authentication { authzid, granted: true } => {
+                self.failures.borrow_mut().remove(&authzid);
+                None
+            },
+            Event::Authentication { authzid, granted: false } => {
+                let mut failures = self.failures.borrow_mut();
+                let count = failures.entry(authzid.clone()).or_insert(0);
+                *count += 1;
+                // Only once per streak, not for every failure after
+                if *count != self.settings.auth_failures {
+                    return None;
+                }
+                Some((Kind::AuthFailures,
+                    Values { user: authzid, count: *count, peer, ..Default::default() }))
+            },
+            _ => None,
+        }
+    }
+
+    /// Send queued mails whenever there are some
+    async fn deliver(&self, mut wake: mpsc::UnboundedReceiver<()>) {
+        while wake.next().await.is_some() {
+            loop {
+                let mail = match self.queue.borrow_mut().pop_front() {
+                    Some(m) => m,
+                    None => break,
+                };
+                // Failures were logged already
+                let _ = self.send_mail(&mail).await;
+            }
+        }
+    }
+
+    async fn send_mail(&self, mail: &Mail) -> std::result::Result<(), String> {
+        let mut delay = MIN_RETRY_DELAY;
+        let mut attempt = 0;
+        loop {
+            let why = match self.relay.send(mail).await {
+                Ok(()) => return Ok(()),
+                Err(e) => e.to_string(),
+            };
+            if attempt >= self.settings.retries {
+                error!(self.log, "Could not send mail to {}: {}", mail.to, why;
+                    "subject" => &mail.subject);
+                return Err(why);
+            }
+            attempt += 1;
+            warn!(self.log, "Sending mail to {} failed, retrying in {}s: {}", mail.to,
+                delay.as_secs(), why);
+            task::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+}
+
+impl Channel for Email {
+    fn name(&self) -> &'static str {
+        "email"
+    }
+
+    fn send<'a>(&'a self, notice: &'a Notice<'a>)
+        -> LocalBoxFuture<'a, std::result::Result<(), String>>
+    {
+        async move {
+            let to = notice.contact.email.as_deref()
+                .ok_or_else(|| "no email address known".to_string())?;
+            let (kind, values) = match notice.what {
+                // Being told it's free is all the head of the queue needs to go and get it
+                What::Free { machine, name } | What::Ready { machine, name, .. } =>
+                    (Kind::Free, Values {
+                        machine: Some(machine), name: name.to_string(),
+                        user: notice.user.to_string(), ..Default::default()
+                    }),
+                What::Test => (Kind::Test,
+                    Values { user: notice.user.to_string(), ..Default::default() }),
+            };
+            if !self.allow(to) {
+                return Err(format!("too many mails to {} within the last hour", to));
+            }
+            self.send_mail(&self.mail(kind, &values, to)).await
+        }.boxed_local()
+    }
+}
+
+/// Send a test mail to `to` with the settings in `[modules.email]`, without retrying
+///
+/// For checking the settings from the command line, no server has to run for it.
+pub async fn send_test(config: &Config, to: &str) -> Result<()> {
+    let section = config.modules.sections.get("email").cloned()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "[modules.email] is not set"))?;
+    let settings: Settings = section.try_into()?;
+    let values = Values { user: to.to_string(), ..Default::default() };
+    let (subject, body) = settings.templates.get(Kind::Test);
+    let mail = Mail {
+        from: settings.from.clone(),
+        to: to.to_string(),
+        subject: values.fill(subject),
+        body: values.fill(body),
+    };
+    settings.relay()?.send(&mail).await?;
+    Ok(())
+}
+
+/// Sending mails as a module, configured in `[modules.email]`
+pub struct Module {
+    task: Option<RemoteHandle<()>>,
+}
+
+impl Module {
+    pub fn new() -> Self {
+        Self { task: None }
+    }
+}
+
+impl super::Module for Module {
+    fn name(&self) -> &'static str {
+        "email"
+    }
+
+    fn init<'a>(&'a mut self, ctx: ModuleContext<'a>) -> LocalBoxFuture<'a, Result<()>> {
+        async move {
+            let email = Rc::new(Email::new(ctx.log.clone(), ctx.settings()?)?);
+            ctx.channels.register(email.clone());
+            let events = ctx.audit.subscribe();
+            let mach = ctx.mach.clone();
+            let background = ctx.background();
+
+            let f = async move {
+                let (wake_tx, wake_rx) = mpsc::unbounded();
+                let collect = email.collect(events, mach, background, wake_tx);
+                future::join(collect, email.deliver(wake_rx)).await;
+            };
+            let task = ctx.spawner.spawn_local_with_handle(f)
+                .map_err(|e| Error::Boxed(Box::new(e)))?;
+            self.task = Some(task);
+            Ok(())
+        }.boxed_local()
+    }
+
+    /// Mails still waiting are dropped, they're only best effort anyway
+    fn shutdown(&mut self) -> LocalBoxFuture<'_, ()> {
+        self.task = None;
+        future::ready(()).boxed_local()
+    }
+}
@@ -0,0 +1,217 @@
+//! Just enough of an SMTP client to hand mails to a relay
+//!
+//! Every mail gets a connection of its own. With `starttls` the connection is encrypted before
+//! anything but the greeting and EHLO is exchanged, and a relay not offering that is given up on
+//! instead of sending the password in the clear.
+
+use std::io;
+use std::time::Duration;
+
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use async_std::future::timeout;
+use async_std::net::TcpStream;
+
+use async_tls::TlsConnector;
+
+/// Longest reply line we accept, RFC 5321 allows 512 bytes
+const MAX_REPLY_LINE: usize = 1024;
+
+/// Longest line of the base64 encoded body
+const BODY_LINE: usize = 76;
+
+/// Where and how to send mails
+pub struct Relay {
+    pub host: String,
+    pub port: u16,
+    pub starttls: bool,
+    /// Username and password for AUTH PLAIN, if the relay wants us to log in
+    pub credentials: Option<(String, String)>,
+    pub timeout: Duration,
+}
+
+pub struct Mail {
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Mail {
+    /// Fails if an address could smuggle in commands or headers
+    pub fn check(&self) -> io::Result<()> {
+        for address in &[&self.from, &self.to] {
+            if address.is_empty() || !address.contains('@')
+                || address.contains(|c: char| c.is_control() || c == '<' || c == '>' || c == ' ')
+            {
+                return Err(invalid(format!("invalid address {}", address)));
+            }
+        }
+        Ok(())
+    }
+
+    /// The mail as it goes after DATA, including the terminating dot
+    ///
+    /// The body is sent base64 encoded so we neither need 8BITMIME nor dot-stuffing.
+    fn message(&self) -> String {
+        let mut message = format!("From: <{}>\r\nTo: <{}>\r\nSubject: {}\r\nDate: {}\r\n\
+            MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\
+            Content-Transfer-Encoding: base64\r\n\r\n",
+            self.from, self.to, header(&self.subject), chrono::Utc::now().to_rfc2822());
+        let body = base64(self.body.replace("\r\n", "\n").replace('\n', "\r\n").as_bytes());
+        // base64 is plain ASCII, so splitting at any byte is fine
+        for line in body.as_bytes().chunks(BODY_LINE) {
+            message.push_str(&String::from_utf8_lossy(line));
+            message.push_str("\r\n");
+        }
+        message.push_str(".\r\n");
+        message
+    }
+}
+
+impl Relay {
+    /// Hand `mail` to the relay, failing with what went wrong if it didn't take it
+    pub async fn send(&self, mail: &Mail) -> io::Result<()> {
+        mail.check()?;
+        let exchange = async {
+            let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+            let mut conn = Conn::new(stream);
+            conn.reply(2).await?;
+            let extensions = conn.command(&self.ehlo(mail), 2).await?;
+            if !self.starttls {
+                return self.deliver(conn, mail).await;
+            }
+
+            if !extensions.iter().any(|e| e.eq_ignore_ascii_case("STARTTLS")) {
+                return Err(invalid(format!("{} doesn't offer STARTTLS", self.host)));
+            }
+            conn.command("STARTTLS", 2).await?;
+            let stream = TlsConnector::default().connect(&self.host, conn.stream).await?;
+            // Whatever we learned before encrypting is to be forgotten
+            let mut conn = Conn::new(stream);
+            conn.command(&self.ehlo(mail), 2).await?;
+            self.deliver(conn, mail).await
+        };
+
+        timeout(self.timeout, exchange).await.map_err(|_| io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{} did not take the mail within {}s", self.host, self.timeout.as_secs())))?
+    }
+
+    /// We greet with the domain we send from, having no better name for ourselves
+    fn ehlo(&self, mail: &Mail) -> String {
+        let domain = mail.from.rsplit('@').next().unwrap_or("localhost");
+        format!("EHLO {}", domain)
+    }
+
+    async fn deliver<S: AsyncRead + AsyncWrite + Unpin>(&self, mut conn: Conn<S>, mail: &Mail)
+        -> io::Result<()>
+    {
+        if let Some((ref user, ref password)) = self.credentials {
+            let token = base64(format!("\0{}\0{}", user, password).as_bytes());
+            conn.command(&format!("AUTH PLAIN {}", token), 2).await?;
+        }
+        conn.command(&format!("MAIL FROM:<{}>", mail.from), 2).await?;
+        conn.command(&format!("RCPT TO:<{}>", mail.to), 2).await?;
+        conn.command("DATA", 3).await?;
+        conn.stream.write_all(mail.message().as_bytes()).await?;
+        conn.stream.flush().await?;
+        conn.reply(2).await?;
+        // The mail is taken, how the relay says goodbye doesn't matter anymore
+        let _ = conn.command("QUIT", 2).await;
+        Ok(())
+    }
+}
+
+struct Conn<S> {
+    stream: S,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Conn<S> {
+    fn new(stream: S) -> Self {
+        Self { stream }
+    }
+
+    /// Send `line` and read the reply, which has to be of `class`, e.g. 2 for 250
+    async fn command(&mut self, line: &str, class: u16) -> io::Result<Vec<String>> {
+        self.stream.write_all(line.as_bytes()).await?;
+        self.stream.write_all(b"\r\n").await?;
+        self.stream.flush().await?;
+        self.reply(class).await.map_err(|e| {
+            // Never put credentials into an error message
+            let command = line.split_whitespace().take(2).collect::<Vec<_>>().join(" ");
+            io::Error::new(e.kind(), format!("{}: {}", command, e))
+        })
+    }
+
+    /// Read a reply, failing unless its code is of `class`, and return the text of its lines
+    async fn reply(&mut self, class: u16) -> io::Result<Vec<String>> {
+        let mut lines = Vec::new();
+        loop {
+            let line = self.line().await?;
+            let code = line.get(..3).and_then(|c| c.parse::<u16>().ok())
+                .ok_or_else(|| invalid(format!("invalid reply {}", line)))?;
+            let last = line.as_bytes().get(3) != Some(&b'-');
+            lines.push(line.get(4..).unwrap_or_default().to_string());
+            if last {
+                if code / 100 != class {
+                    return Err(io::Error::new(io::ErrorKind::Other,
+                        format!("relay answered {} {}", code, lines.join(" "))));
+                }
+                return Ok(lines);
+            }
+        }
+    }
+
+    async fn line(&mut self) -> io::Result<String> {
+        let mut line = Vec::new();
+        let mut byte = [0u8];
+        while !line.ends_with(b"\r\n") {
+            if line.len() > MAX_REPLY_LINE {
+                return Err(invalid("reply line too long".to_string()));
+            }
+            if self.stream.read(&mut byte).await? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                    "connection closed before answering"));
+            }
+            line.push(byte[0]);
+        }
+        line.truncate(line.len() - 2);
+        Ok(String::from_utf8_lossy(&line).into_owned())
+    }
+}
+
+/// `value` fit for a header, encoded if it isn't plain ASCII
+///
+/// Line breaks are dropped so templates filled with e.g. machine names can't add headers.
+fn header(value: &str) -> String {
+    let value: String = value.chars().filter(|c| !c.is_control()).collect();
+    if value.is_ascii() {
+        value
+    } else {
+        format!("=?utf-8?b?{}?=", base64(value.as_bytes()))
+    }
+}
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64 with padding
+fn base64(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,151 @@
+//! Just enough of an HTTP/1.1 client to call webhooks
+//!
+//! Every request gets a connection of its own which the server closes after answering. We only
+//! look at the status of the answer.
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use async_std::future::timeout;
+use async_std::net::TcpStream;
+
+use async_tls::TlsConnector;
+
+use url::Url;
+
+/// Longest status line we accept, anything longer isn't HTTP
+const MAX_STATUS_LINE: usize = 1024;
+
+/// Where and how to send requests
+pub struct Endpoint {
+    url: Url,
+    method: String,
+    headers: Vec<(String, String)>,
+    /// `None` for plain HTTP
+    tls: Option<TlsConnector>,
+    timeout: Duration,
+}
+
+impl Endpoint {
+    /// Fails if `url` isn't a valid http or https URL. Certificates of https endpoints are only
+    /// checked if `verify_tls` is set.
+    pub fn new(url: &str, method: &str, headers: Vec<(String, String)>, verify_tls: bool,
+        timeout: Duration) -> io::Result<Self>
+    {
+        let url = Url::parse(url).map_err(|e| invalid(format!("invalid URL {}: {}", url, e)))?;
+        let tls = match url.scheme() {
+            "http" => None,
+            "https" if verify_tls => Some(TlsConnector::default()),
+            "https" => Some(unverified()),
+            s => return Err(invalid(format!("unsupported URL scheme {}", s))),
+        };
+        if url.host_str().is_none() {
+            return Err(invalid(format!("URL {} has no host", url)));
+        }
+
+        Ok(Self { url, method: method.to_uppercase(), headers, tls, timeout })
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Send a request with `body` as JSON, returning the status of the answer
+    ///
+    /// Only fails if there is no answer within the timeout, not for error statuses.
+    pub async fn send(&self, body: &[u8]) -> io::Result<u16> {
+        self.send_with(body, &[]).await
+    }
+
+    /// Like `send`, with `extra` headers on top of the configured ones, e.g. a signature of `body`
+    pub async fn send_with(&self, body: &[u8], extra: &[(String, String)]) -> io::Result<u16> {
+        let exchange = async {
+            // Checked when creating the endpoint. IPv6 addresses come in brackets.
+            let host = self.url.host_str().unwrap_or_default()
+                .trim_start_matches('[').trim_end_matches(']');
+            let port = self.url.port_or_known_default().unwrap_or(80);
+            let stream = TcpStream::connect((host, port)).await?;
+            match self.tls {
+                Some(ref tls) =>
+                    self.exchange(tls.connect(host, stream).await?, body, extra).await,
+                None => self.exchange(stream, body, extra).await,
+            }
+        };
+
+        timeout(self.timeout, exchange).await.map_err(|_| io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{} did not answer within {}s", self.url, self.timeout.as_secs())))?
+    }
+
+    async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(&self, mut stream: S, body: &[u8],
+        extra: &[(String, String)]) -> io::Result<u16>
+    {
+        let mut path = self.url.path().to_string();
+        if let Some(query) = self.url.query() {
+            path.push('?');
+            path.push_str(query);
+        }
+        let host = match self.url.port() {
+            Some(port) => format!("{}:{}", self.url.host_str().unwrap_or_default(), port),
+            None => self.url.host_str().unwrap_or_default().to_string(),
+        };
+
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: diflouroborane/{}\r\n\
+            Connection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+            self.method, path, host, clap::crate_version!(), body.len());
+        for (name, value) in self.headers.iter().chain(extra) {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+
+        stream.write_all(request.as_bytes()).await?;
+        stream.write_all(body).await?;
+        stream.flush().await?;
+
+        // Only the status line is of interest, e.g. `HTTP/1.1 204 No Content`
+        let mut line = Vec::new();
+        let mut byte = [0u8];
+        while !line.ends_with(b"\r\n") {
+            if line.len() > MAX_STATUS_LINE {
+                return Err(invalid("status line too long".to_string()));
+            }
+            if stream.read(&mut byte).await? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                    "connection closed before answering"));
+            }
+            line.push(byte[0]);
+        }
+
+        let line = String::from_utf8_lossy(&line);
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next().and_then(|s| s.parse().ok())) {
+            (Some(v), Some(status)) if v.starts_with("HTTP/") => Ok(status),
+            _ => Err(invalid(format!("invalid status line {}", line.trim()))),
+        }
+    }
+}
+
+/// Connector that accepts any certificate, for endpoints that don't have a valid one
+fn unverified() -> TlsConnector {
+    let mut config = rustls::ClientConfig::new();
+    config.dangerous().set_certificate_verifier(Arc::new(NoVerification));
+    TlsConnector::from(Arc::new(config))
+}
+
+struct NoVerification;
+
+impl rustls::ServerCertVerifier for NoVerification {
+    fn verify_server_cert(&self, _roots: &rustls::RootCertStore,
+        _presented_certs: &[rustls::Certificate], _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8]) -> Result<rustls::ServerCertVerified, rustls::TLSError>
+    {
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
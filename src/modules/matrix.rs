@@ -0,0 +1,388 @@
+//! Posting about machines and logins to a Matrix room, e.g. "Laser cutter was blocked by alice"
+//!
+//! Only compiled with the `matrix` feature. Messages are queued and sent one after another with a
+//! pause in between, so a burst of events doesn't get us rate limited by the homeserver. Like
+//! notifiers this is best effort, failing to post is logged and has no other effect.
+//!
+//! It's also the `matrix` channel for telling users about machines they wait for, by mentioning
+//! them in the room. Those messages are posted right away so we know whether they made it.
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::channel::mpsc;
+use futures::future::{self, FutureExt, LocalBoxFuture, RemoteHandle};
+use futures::stream::StreamExt;
+use futures::task::LocalSpawnExt;
+
+use async_std::sync::RwLock;
+use async_std::task;
+
+use serde::Deserialize;
+
+use slog::Logger;
+
+use url::Url;
+
+use uuid::Uuid;
+
+use crate::audit::{Event, Recorded};
+use crate::config::Secret;
+use crate::error::{Error, Result};
+use crate::machine::{lock, MachinesProvider};
+use crate::machine::watch::{Channel, Notice, What};
+
+use super::http::Endpoint;
+use super::ModuleContext;
+
+/// Delay before the first retry of a failed message, doubling after every further failure
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// What we can post about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Kind {
+    Blocked,
+    Unblocked,
+    /// A machine was given back for somebody who didn't do so themselves
+    ForceFreed,
+    /// The device of a machine didn't switch, so the machine was put into a safe state
+    ActorFailed,
+    /// Somebody failed to log in `auth_failures` times in a row
+    AuthFailures,
+}
+
+#[derive(Deserialize)]
+struct Settings {
+    /// Base URL of the homeserver, e.g. `https://matrix.example.org`
+    homeserver: String,
+    access_token: Secret,
+    /// Id of the room to post in, like `!abcdef:example.org`. The account has to be in it.
+    room: String,
+    #[serde(default = "default_events")]
+    events: Vec<Kind>,
+    #[serde(default)]
+    templates: Templates,
+    /// Seconds to wait between two messages
+    #[serde(default = "default_interval")]
+    interval: u64,
+    /// Most messages waiting to be sent, the oldest ones are dropped beyond that
+    #[serde(default = "default_queue")]
+    queue: usize,
+    #[serde(default = "default_auth_failures")]
+    auth_failures: u32,
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    #[serde(default = "default_retries")]
+    retries: u32,
+}
+
+fn default_events() -> Vec<Kind> {
+    vec![Kind::Blocked, Kind::ForceFreed, Kind::AuthFailures]
+}
+
+fn default_interval() -> u64 {
+    2
+}
+
+fn default_queue() -> usize {
+    100
+}
+
+fn default_auth_failures() -> u32 {
+    5
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+/// Message text for each kind of event. `{name}`, `{uuid}`, `{user}`, `{status}`, `{count}`,
+/// `{peer}` and `{mention}` are replaced, as far as they apply.
+#[derive(Default, Deserialize)]
+struct Templates {
+    blocked: Option<String>,
+    unblocked: Option<String>,
+    force_freed: Option<String>,
+    actor_failed: Option<String>,
+    auth_failures: Option<String>,
+    /// For users waiting for a machine, who are mentioned with `{mention}`
+    free: Option<String>,
+    /// Sent when an admin tests whether users can be reached
+    test: Option<String>,
+}
+
+impl Templates {
+    fn get(&self, kind: Kind) -> &str {
+        let (template, default) = match kind {
+            Kind::Blocked => (&self.blocked, "{name} was blocked by {user}"),
+            Kind::Unblocked => (&self.unblocked, "{name} was unblocked by {user}"),
+            Kind::ForceFreed => (&self.force_freed, "{name} was given back for {user}"),
+            Kind::ActorFailed =>
+                (&self.actor_failed, "{name} could not be switched and is now {status}"),
+            Kind::AuthFailures =>
+                (&self.auth_failures, "{count} failed logins as {user} in a row from {peer}"),
+        };
+        template.as_deref().unwrap_or(default)
+    }
+
+    fn free(&self) -> &str {
+        self.free.as_deref().unwrap_or("{mention}: {name} is free now")
+    }
+
+    fn test(&self) -> &str {
+        self.test.as_deref().unwrap_or("{mention}: this is a test, you can be reached here")
+    }
+}
+
+/// Everything there is to fill into a template
+#[derive(Default)]
+struct Values {
+    machine: Option<Uuid>,
+    user: String,
+    status: String,
+    count: u32,
+    peer: String,
+}
+
+struct Matrix {
+    log: Logger,
+    settings: Settings,
+    homeserver: Url,
+    queue: RefCell<VecDeque<String>>,
+    /// Failed logins in a row, by user
+    failures: RefCell<HashMap<String, u32>>,
+    /// Transaction ids have to be unique for the access token, so they start with when we did
+    started: u64,
+    sent: Cell<u64>,
+}
+
+impl Matrix {
+    fn new(log: Logger, settings: Settings) -> io::Result<Self> {
+        let homeserver = Url::parse(&settings.homeserver).map_err(|e| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid homeserver {}: {}", settings.homeserver, e)))?;
+        if homeserver.cannot_be_a_base() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("invalid homeserver {}", settings.homeserver)));
+        }
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+        Ok(Self {
+            log,
+            settings,
+            homeserver,
+            queue: RefCell::new(VecDeque::new()),
+            failures: RefCell::new(HashMap::new()),
+            started,
+            sent: Cell::new(0),
+        })
+    }
+
+    /// Queue messages for the events we're interested in
+    async fn collect(&self, mut events: mpsc::UnboundedReceiver<Recorded>,
+        mach: Arc<RwLock<MachinesProvider>>, background: lock::Background,
+        wake: mpsc::UnboundedSender<()>)
+    {
+        while let Some(recorded) = events.next().await {
+            let (kind, values) = match self.classify(recorded) {
+                Some(k) => k,
+                None => continue,
+            };
+            if !self.settings.events.contains(&kind) {
+                continue;
+            }
+
+            let name = match values.machine {
+                Some(ref uuid) => background.read(&mach, "look up a machine").await.get(uuid)
+                    .map(|m| m.name).unwrap_or_else(|| uuid.to_string()),
+                None => String::new(),
+            };
+            let text = self.settings.templates.get(kind)
+                .replace("{name}", &name)
+                .replace("{uuid}", &values.machine.map(|u| u.to_string()).unwrap_or_default())
+                .replace("{user}", &values.user)
+                .replace("{status}", &values.status)
+                .replace("{count}", &values.count.to_string())
+                .replace("{peer}", &values.peer);
+
+            let mut queue = self.queue.borrow_mut();
+            if queue.len() >= self.settings.queue {
+                warn!(self.log, "Too many messages waiting, dropping the oldest");
+                queue.pop_front();
+            }
+            queue.push_back(text);
+            let _ = wake.unbounded_send(());
+        }
+    }
+
+    /// What kind of event `recorded` is, if we can post about it
+    fn classify(&self, recorded: Recorded) -> Option<(Kind, Values)> {
+        let peer = recorded.peer.unwrap_or_default();
+        match recorded.event {
+            Event::MachineBlocked { authzid, machine, blocked } => {
+                let kind = if blocked { Kind::Blocked } else { Kind::Unblocked };
+                Some((kind, Values { machine: Some(machine), user: authzid, peer,
+                    ..Default::default() }))
+            },
+            Event::MachineReleased { authzid, machine } => Some((Kind::ForceFreed,
+                Values { machine: Some(machine), user: authzid, peer, ..Default::default() })),
+            Event::ActorFailed { machine, status, .. } => Some((Kind::ActorFailed,
+                Values { machine: Some(machine), status, peer, ..Default::default() })),
+            Event::Authentication { authzid, granted: true } => {
+                self.failures.borrow_mut().remove(&authzid);
+                None
+            },
+            Event::Authentication { authzid, granted: false } => {
+                let mut failures = self.failures.borrow_mut();
+                let count = failures.entry(authzid.clone()).or_insert(0);
+                *count += 1;
+                // Only once per streak, not for every failure after
+                if *count != self.settings.auth_failures {
+                    return None;
+                }
+                Some((Kind::AuthFailures,
+                    Values { user: authzid, count: *count, peer, ..Default::default() }))
+            },
+            _ => None,
+        }
+    }
+
+    /// Send queued messages whenever there are some, pausing between them
+    async fn deliver(&self, mut wake: mpsc::UnboundedReceiver<()>) {
+        let interval = Duration::from_secs(self.settings.interval);
+        while wake.next().await.is_some() {
+            loop {
+                let text = match self.queue.borrow_mut().pop_front() {
+                    Some(t) => t,
+                    None => break,
+                };
+                // Failures were logged already
+                let _ = self.post(&text).await;
+                task::sleep(interval).await;
+            }
+        }
+    }
+
+    async fn post(&self, text: &str) -> std::result::Result<(), String> {
+        // Retries use the same transaction id so the homeserver can tell they're the same
+        let txn = format!("fabaccess-{}-{}", self.started, self.sent.get());
+        self.sent.set(self.sent.get() + 1);
+
+        let mut url = self.homeserver.clone();
+        // Checked when starting that it can be a base
+        if let Ok(mut path) = url.path_segments_mut() {
+            path.pop_if_empty().extend(&["_matrix", "client", "r0", "rooms",
+                self.settings.room.as_str(), "send", "m.room.message", txn.as_str()]);
+        }
+        let auth = format!("Bearer {}", self.settings.access_token.expose());
+        let endpoint = match Endpoint::new(url.as_str(), "PUT",
+            vec![("Authorization".to_string(), auth)], true,
+            Duration::from_secs(self.settings.timeout))
+        {
+            Ok(e) => e,
+            Err(e) => {
+                error!(self.log, "Could not post to {}: {}", self.settings.room, e);
+                return Err(e.to_string());
+            },
+        };
+        let body = serde_json::json!({ "msgtype": "m.text", "body": text }).to_string();
+
+        let mut delay = MIN_RETRY_DELAY;
+        let mut attempt = 0;
+        loop {
+            let why = match endpoint.send(body.as_bytes()).await {
+                Ok(200..=299) => return Ok(()),
+                Ok(429) => "rate limited".to_string(),
+                Ok(status) => format!("homeserver answered with status {}", status),
+                Err(e) => e.to_string(),
+            };
+            if attempt >= self.settings.retries {
+                error!(self.log, "Could not post to {}: {}", self.settings.room, why;
+                    "message" => text);
+                return Err(why);
+            }
+            attempt += 1;
+            warn!(self.log, "Posting to {} failed, retrying in {}s: {}", self.settings.room,
+                delay.as_secs(), why);
+            task::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+}
+
+impl Channel for Matrix {
+    fn name(&self) -> &'static str {
+        "matrix"
+    }
+
+    fn send<'a>(&'a self, notice: &'a Notice<'a>)
+        -> LocalBoxFuture<'a, std::result::Result<(), String>>
+    {
+        async move {
+            let mention = notice.contact.matrix.as_deref()
+                .ok_or_else(|| "no Matrix id known".to_string())?;
+            let text = match notice.what {
+                // Being told it's free is all the head of the queue needs to go and get it
+                What::Free { machine, name } | What::Ready { machine, name, .. } =>
+                    self.settings.templates.free()
+                        .replace("{name}", name)
+                        .replace("{uuid}", &machine.to_string()),
+                What::Test => self.settings.templates.test().to_string(),
+            };
+            let text = text.replace("{mention}", mention).replace("{user}", notice.user);
+            self.post(&text).await
+        }.boxed_local()
+    }
+}
+
+/// Posting to Matrix as a module, configured in `[modules.matrix]`
+pub struct Module {
+    task: Option<RemoteHandle<()>>,
+}
+
+impl Module {
+    pub fn new() -> Self {
+        Self { task: None }
+    }
+}
+
+impl super::Module for Module {
+    fn name(&self) -> &'static str {
+        "matrix"
+    }
+
+    fn init<'a>(&'a mut self, ctx: ModuleContext<'a>) -> LocalBoxFuture<'a, Result<()>> {
+        async move {
+            let matrix = Rc::new(Matrix::new(ctx.log.clone(), ctx.settings()?)?);
+            ctx.channels.register(matrix.clone());
+            let events = ctx.audit.subscribe();
+            let mach = ctx.mach.clone();
+            let background = ctx.background();
+
+            let f = async move {
+                let (wake_tx, wake_rx) = mpsc::unbounded();
+                let collect = matrix.collect(events, mach, background, wake_tx);
+                future::join(collect, matrix.deliver(wake_rx)).await;
+            };
+            let task = ctx.spawner.spawn_local_with_handle(f)
+                .map_err(|e| Error::Boxed(Box::new(e)))?;
+            self.task = Some(task);
+            Ok(())
+        }.boxed_local()
+    }
+
+    /// Messages still waiting are dropped, they're only best effort anyway
+    fn shutdown(&mut self) -> LocalBoxFuture<'_, ()> {
+        self.task = None;
+        future::ready(()).boxed_local()
+    }
+}
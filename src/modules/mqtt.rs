@@ -1,9 +1,611 @@
-//! Mock impl of MQTT as transport.
+//! Bridge to an MQTT broker
 //!
 //! Specific Protocol implementations (Sonoff/Card2Go/...) would be located here
+//!
+//! While we're connected `availability_topic` holds a retained "online". The broker replaces it
+//! with "offline" if we vanish, so whoever reads the retained machine states knows not to trust
+//! them then. After every connect we subscribe again and publish "online" before any state.
+
+mod device;
+mod hass;
+mod packet;
+mod reader;
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc;
+use futures::future::{self, FutureExt, LocalBoxFuture, RemoteHandle};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use futures::stream::{self, StreamExt};
+use futures::task::LocalSpawnExt;
+
+use async_std::future::timeout;
+use async_std::net::TcpStream;
+use async_std::sync::RwLock;
+use async_std::task;
+
+use serde::Serialize;
 
 use slog::Logger;
 
-pub fn init(log: Logger) {
-    info!(log, "MQTT Module initialized.")
+use crate::cards::Cards;
+use crate::config::{self, Mqtt, Publication};
+use crate::error::{Error, Result};
+use crate::machine::{lock, MachinesProvider, StateChange};
+use crate::status::{Status, Bridge};
+
+use super::actor::{Actor, MachineCommand};
+use super::ModuleContext;
+use device::Device;
+use hass::Discovery;
+use packet::Packet;
+
+pub use reader::Readers;
+
+/// Delay before the first reconnect attempt, doubling after every failed one
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// How long the broker has to answer our CONNECT
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often we check whether the broker is still there
+const TICK: Duration = Duration::from_secs(1);
+
+/// How long saying goodbye to the broker may take when shutting down
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Machine state as published to MQTT
+#[derive(Serialize)]
+struct State<'a> {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    occupant: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    since: Option<u64>,
+    /// Only if `publish_notes` is on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<&'a str>,
+}
+
+/// What the bridge has to do, besides reading from the broker
+enum Event {
+    Tick,
+    /// All changes that were waiting, see `batches`
+    Publish(Vec<StateChange>),
+    /// A packet from somebody holding a `Handle`
+    Send(Vec<u8>),
+}
+
+/// Lets other modules talk to devices through the bridge
+#[derive(Clone)]
+pub struct Handle {
+    inner: Rc<Shared>,
+}
+
+struct Shared {
+    up: Cell<bool>,
+    /// Set when shutting down, so we don't reconnect
+    stopping: Cell<bool>,
+    outgoing: mpsc::UnboundedSender<Vec<u8>>,
+    /// Last packet id handed out
+    packet_id: Cell<u16>,
+    /// Who wants to hear about messages on which topic
+    watches: RefCell<HashMap<String, Vec<mpsc::UnboundedSender<Vec<u8>>>>>,
+}
+
+impl Handle {
+    /// A handle along with the packets sent through it, for `run`
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<Vec<u8>>) {
+        let (tx, rx) = mpsc::unbounded();
+        let inner = Shared {
+            up: Cell::new(false),
+            stopping: Cell::new(false),
+            outgoing: tx,
+            packet_id: Cell::new(0),
+            watches: RefCell::new(HashMap::new()),
+        };
+        (Self { inner: Rc::new(inner) }, rx)
+    }
+
+    /// Publish a message if we're connected to the broker
+    fn publish(&self, topic: &str, payload: &[u8]) -> io::Result<()> {
+        if !self.inner.up.get() {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "MQTT broker not connected"));
+        }
+        self.inner.outgoing.unbounded_send(packet::publish(topic, payload, false))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "MQTT bridge stopped"))
+    }
+
+    /// Get every message published on `topic` from now on
+    ///
+    /// Topics are subscribed to when connecting, so watches have to be set up before `run`.
+    fn watch(&self, topic: String) -> mpsc::UnboundedReceiver<Vec<u8>> {
+        let (tx, rx) = mpsc::unbounded();
+        self.inner.watches.borrow_mut().entry(topic).or_default().push(tx);
+        rx
+    }
+
+    fn topics(&self) -> Vec<String> {
+        self.inner.watches.borrow().keys().cloned().collect()
+    }
+
+    /// Disconnect from the broker for good, marking us offline on the availability topic first
+    ///
+    /// Returns whether there was a connection to close.
+    fn close(&self, config: &Mqtt) -> bool {
+        self.inner.stopping.set(true);
+        if !self.inner.up.get() {
+            return false;
+        }
+        // Disconnecting cleanly makes the broker drop our will, so we have to publish it ourselves
+        self.send(self.availability(config, false));
+        self.send(packet::disconnect());
+        true
+    }
+
+    /// A packet id that isn't used by any other packet still waiting for an answer
+    ///
+    /// Ids wrap around long after the broker answered the packets that had them before.
+    fn packet_id(&self) -> u16 {
+        // 0 isn't a valid id
+        let id = self.inner.packet_id.get().checked_add(1).unwrap_or(1);
+        self.inner.packet_id.set(id);
+        id
+    }
+
+    /// A PUBLISH of `payload` to `topic` with the flags of `publication`
+    fn publication(&self, publication: Publication, topic: &str, payload: &[u8]) -> Vec<u8> {
+        match publication.qos {
+            0 => packet::publish(topic, payload, publication.retain),
+            _ => packet::publish_acked(self.packet_id(), topic, payload, publication.retain),
+        }
+    }
+
+    /// Publish whether we're online. That has to be retained, no matter how states are.
+    fn availability(&self, config: &Mqtt, online: bool) -> Vec<u8> {
+        let payload: &[u8] = if online { b"online" } else { b"offline" };
+        let publication = Publication { retain: true, ..config.state };
+        self.publication(publication, &config.availability_topic, payload)
+    }
+
+    /// Queue a packet to be sent, whether we're connected or not
+    fn send(&self, packet: Vec<u8>) {
+        // The receiver only goes away when we shut down
+        let _ = self.inner.outgoing.unbounded_send(packet);
+    }
+
+    /// Hand a message to everybody watching its topic. Returns whether there was anybody.
+    fn deliver(&self, topic: &str, payload: &[u8]) -> bool {
+        match self.inner.watches.borrow_mut().get_mut(topic) {
+            Some(watches) => {
+                watches.retain(|w| w.unbounded_send(payload.to_vec()).is_ok());
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+/// The actor switching a device over MQTT, if the actor config of a machine describes one
+pub fn actor(config: &config::Actor, client_id: &str, bridge: &Handle) -> Option<Box<dyn Actor>> {
+    let device = device::from_config(config, client_id)?;
+    let reports = bridge.watch(device.stat_topic());
+    Some(Box::new(Relay {
+        device,
+        bridge: bridge.clone(),
+        reports,
+        timeout: Duration::from_secs(config.timeout),
+    }))
+}
+
+/// Switches a device and waits for it to report it did
+struct Relay {
+    device: Box<dyn Device>,
+    bridge: Handle,
+    reports: mpsc::UnboundedReceiver<Vec<u8>>,
+    /// How long the device has to confirm
+    timeout: Duration,
+}
+
+impl Actor for Relay {
+    fn apply(&mut self, _state: &StateChange, command: MachineCommand)
+        -> LocalBoxFuture<'_, io::Result<()>>
+    {
+        let Relay { device, bridge, reports, timeout: limit } = self;
+        let power = command == MachineCommand::PowerOn;
+        async move {
+            // The device may have reported before it got our command, which tells us nothing
+            while let Ok(Some(_)) = reports.try_next() {}
+
+            let (topic, payload) = device.command(power);
+            bridge.publish(&topic, &payload)?;
+
+            let confirmed = async {
+                while let Some(report) = reports.next().await {
+                    if device.parse(&report) == Some(power) {
+                        return true;
+                    }
+                }
+                false
+            };
+            match timeout(*limit, confirmed).await {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "MQTT bridge stopped")),
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!(
+                    "device did not confirm within {}s, relay unreachable?", limit.as_secs()))),
+            }
+        }.boxed_local()
+    }
+}
+
+/// The bridge as a module, along with the card readers talking through it
+pub struct Module {
+    handle: Handle,
+    /// Taken by the bridge once it starts
+    outgoing: Option<mpsc::UnboundedReceiver<Vec<u8>>>,
+    /// Set once the bridge starts
+    config: Option<Mqtt>,
+    task: Option<RemoteHandle<()>>,
+}
+
+impl Module {
+    pub fn new() -> Self {
+        let (handle, outgoing) = Handle::new();
+        Self { handle, outgoing: Some(outgoing), config: None, task: None }
+    }
+
+    /// For other modules to talk to devices through the bridge
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+}
+
+impl super::Module for Module {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    /// The bridge is configured in `[mqtt]`, which predates modules
+    fn init<'a>(&'a mut self, ctx: ModuleContext<'a>) -> LocalBoxFuture<'a, Result<()>> {
+        async move {
+            let config = ctx.config.mqtt.clone()
+                .ok_or_else(|| Error::Config("[mqtt] is not set".to_string()))?;
+            let outgoing = self.outgoing.take()
+                .ok_or_else(|| Error::Config("MQTT bridge started twice".to_string()))?;
+
+            self.config = Some(config.clone());
+            let readers = readers(&ctx, &config);
+            let discovery = Discovery::new(ctx.config);
+            let f = run(ctx.log.clone(), config, self.handle.clone(), outgoing,
+                ctx.status.clone(), ctx.mach.clone(), ctx.background(), readers, discovery);
+            let task = ctx.spawner.spawn_local_with_handle(f)
+                .map_err(|e| Error::Boxed(Box::new(e)))?;
+            self.task = Some(task);
+            Ok(())
+        }.boxed_local()
+    }
+
+    fn shutdown(&mut self) -> LocalBoxFuture<'_, ()> {
+        async move {
+            if let (Some(task), Some(config)) = (self.task.take(), self.config.as_ref()) {
+                // Dropping the task stops it, connected or not
+                if self.handle.close(config) {
+                    let _ = timeout(CLOSE_TIMEOUT, task).await;
+                }
+            }
+        }.boxed_local()
+    }
+}
+
+/// Card readers, if any are configured and the cards they need could be loaded
+fn readers(ctx: &ModuleContext, mqtt: &Mqtt) -> Option<Readers> {
+    if mqtt.readers.is_empty() {
+        return None;
+    }
+    // Checked when loading the config
+    let path = ctx.config.cards.as_ref()?;
+    match Cards::open(path) {
+        Ok(cards) => {
+            info!(ctx.log, "Loaded {} cards for {} readers", cards.len(), mqtt.readers.len());
+            Some(Readers::new(ctx.log.clone(), &mqtt.reader_prefix, mqtt.readers.clone(), cards,
+                ctx.perm.clone(), ctx.mach.clone(),
+                Duration::from_secs(ctx.config.machines.lock_timeout), ctx.audit.clone()))
+        },
+        Err(e) => {
+            error!(ctx.log, "Could not load cards from {}, readers are disabled: {}",
+                path.display(), e);
+            None
+        },
+    }
+}
+
+/// Stay connected to the broker for as long as we run, publishing every change of machine state
+///
+/// Packets sent through the `Handle` come in on `outgoing`. Swipes of card `readers` are handled
+/// as they come in. With `discovery` machines are announced to Home Assistant on every connect.
+pub async fn run(log: Logger, config: Mqtt, handle: Handle,
+    mut outgoing: mpsc::UnboundedReceiver<Vec<u8>>, status: Arc<Status>,
+    mach: Arc<RwLock<MachinesProvider>>, background: lock::Background, readers: Option<Readers>,
+    discovery: Option<Discovery>)
+{
+    // Changes keep queuing up while we're disconnected, they're sorted out on reconnect
+    let mut changes = background.write(&mach, "subscribe to changes").await.subscribe();
+
+    let mut delay = MIN_RETRY_DELAY;
+    loop {
+        status.set_mqtt(Bridge::Down);
+
+        let mut connected = false;
+        let s = Session {
+            log: &log, config: &config, handle: &handle, status: &status, mach: &mach,
+            background: &background, readers: readers.as_ref(), discovery: discovery.as_ref(),
+        };
+        let r = s.run(&mut changes, &mut outgoing, &mut connected).await;
+        handle.inner.up.set(false);
+        status.set_mqtt(Bridge::Down);
+        if handle.inner.stopping.get() {
+            info!(log, "Disconnected from broker");
+            return;
+        }
+        match r {
+            Ok(()) => warn!(log, "Broker closed the connection"),
+            Err(e) =>
+                warn!(log, "Connection to broker {}:{} failed: {}", config.host, config.port, e),
+        }
+
+        // Only back off further if we couldn't get a working connection at all
+        if connected {
+            delay = MIN_RETRY_DELAY;
+        }
+        info!(log, "Reconnecting to broker in {}s", delay.as_secs());
+        task::sleep(delay).await;
+        delay = std::cmp::min(delay * 2, MAX_RETRY_DELAY);
+    }
+}
+
+/// Everything a single connection to the broker needs
+struct Session<'a> {
+    log: &'a Logger,
+    config: &'a Mqtt,
+    handle: &'a Handle,
+    status: &'a Status,
+    mach: &'a Arc<RwLock<MachinesProvider>>,
+    background: &'a lock::Background,
+    readers: Option<&'a Readers>,
+    discovery: Option<&'a Discovery>,
+}
+
+impl<'a> Session<'a> {
+    /// Connect to the broker and serve it until the connection breaks
+    ///
+    /// `connected` is set once the broker accepted us.
+    async fn run(&self, changes: &mut mpsc::UnboundedReceiver<StateChange>,
+        outgoing: &mut mpsc::UnboundedReceiver<Vec<u8>>, connected: &mut bool) -> io::Result<()>
+    {
+        let config = self.config;
+        let stream = TcpStream::connect((config.host.as_str(), config.port)).await?;
+        if config.tls {
+            let stream = async_tls::TlsConnector::default().connect(&config.host, stream).await?;
+            self.talk(stream, changes, outgoing, connected).await
+        } else {
+            self.talk(stream, changes, outgoing, connected).await
+        }
+    }
+
+    async fn talk<S>(&self, stream: S, changes: &mut mpsc::UnboundedReceiver<StateChange>,
+        outgoing: &mut mpsc::UnboundedReceiver<Vec<u8>>, connected: &mut bool) -> io::Result<()>
+        where S: AsyncRead + AsyncWrite + Unpin
+    {
+        let config = self.config;
+        let (mut rd, mut wr) = stream.split();
+
+        let connect = packet::Connect {
+            client_id: &config.client_id,
+            keep_alive: config.keep_alive,
+            username: config.username.as_deref(),
+            password: config.password.as_ref().map(|p| p.expose()),
+            will: Some((config.availability_topic.as_str(), &b"offline"[..])),
+            will_qos: config.state.qos,
+        };
+        wr.write_all(&packet::connect(&connect)).await?;
+
+        let answer = timeout(CONNECT_TIMEOUT, packet::read(&mut rd)).await.map_err(|_|
+            io::Error::new(io::ErrorKind::TimedOut, "broker didn't answer CONNECT"))??;
+        match answer {
+            Packet::Connack { code: 0 } => {},
+            Packet::Connack { code } => return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("broker refused connection: {}", refused_reason(code)))),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                "broker didn't answer CONNECT with CONNACK")),
+        }
+
+        wr.write_all(&self.handle.availability(config, true)).await?;
+        let mut topics = vec![format!("{}/#", config.command_prefix.trim_end_matches('/'))];
+        topics.extend(self.handle.topics());
+        if let Some(readers) = self.readers {
+            topics.extend(readers.topics());
+        }
+        if let Some(discovery) = self.discovery {
+            topics.extend(discovery.topics());
+        }
+        for topic in topics.iter() {
+            wr.write_all(&packet::subscribe(self.handle.packet_id(), topic)).await?;
+        }
+
+        info!(self.log, "Connected to broker {}:{}", config.host, config.port);
+        self.status.set_mqtt(Bridge::Up);
+        *connected = true;
+
+        // Whatever changed while we were away is superseded by the current state. Anything that
+        // changes after we took it is still in the queue afterwards. Packets queued up while we
+        // were away are stale, whoever sent them gave up on them already.
+        while let Ok(Some(_)) = changes.try_next() {}
+        while let Ok(Some(_)) = outgoing.try_next() {}
+        self.handle.inner.up.set(true);
+        let states = self.background.read(self.mach, "publish machine states").await.states();
+        for state in states.iter() {
+            wr.write_all(&self.publish_state(state)).await?;
+        }
+        // Configs are retained, but the broker may have lost them or the machines changed since
+        if let Some(discovery) = self.discovery {
+            let machines = self.background.read(self.mach, "announce machines").await.list();
+            let publication = Publication { retain: true, ..config.state };
+            for (topic, payload) in discovery.announce(config, &machines) {
+                wr.write_all(&self.handle.publication(publication, &topic, &payload)).await?;
+            }
+        }
+
+        let last_seen = Cell::new(Instant::now());
+        match future::select(self.receive(&mut rd, &last_seen).boxed_local(),
+            self.send(&mut wr, &last_seen, changes, outgoing).boxed_local()).await
+        {
+            future::Either::Left((r, _)) => r,
+            future::Either::Right((r, _)) => r,
+        }
+    }
+
+    /// Handle everything the broker sends us until the connection breaks
+    async fn receive<S: AsyncRead>(&self, rd: &mut ReadHalf<S>, last_seen: &Cell<Instant>)
+        -> io::Result<()>
+    {
+        loop {
+            let p = packet::read(rd).await?;
+            last_seen.set(Instant::now());
+
+            match p {
+                Packet::Publish { topic, payload } => {
+                    if self.handle.deliver(&topic, &payload) {
+                        continue;
+                    }
+                    if let Some(uuid) = self.discovery.and_then(|d| d.machine(&topic, &payload)) {
+                        // Retained configs of machines that are gone come in after subscribing
+                        let gone = self.background.read(self.mach, "look up a machine").await
+                            .get(&uuid).is_none();
+                        if gone {
+                            info!(self.log, "Removing machine {} from Home Assistant", uuid);
+                            let publication = Publication { retain: true, ..self.config.state };
+                            self.handle.send(self.handle.publication(publication, &topic, b""));
+                        }
+                        continue;
+                    }
+                    let result = match self.readers {
+                        Some(readers) => readers.handle(&topic, &payload).await,
+                        None => None,
+                    };
+                    match result {
+                        Some((topic, payload)) => self.handle.send(
+                            self.handle.publication(self.config.events, &topic, &payload)),
+                        // Nothing acts on commands yet
+                        None => debug!(self.log, "Received command on {}", topic;
+                            "bytes" => payload.len()),
+                    }
+                },
+                Packet::Puback { packet_id } =>
+                    trace!(self.log, "Broker acknowledged message"; "packet_id" => packet_id),
+                Packet::Suback { codes } if codes.iter().any(|c| *c == 0x80) =>
+                    warn!(self.log, "Broker refused some of our subscriptions"),
+                Packet::Other(t) => trace!(self.log, "Ignoring packet of type {}", t),
+                _ => {},
+            }
+        }
+    }
+
+    /// Publish machine state changes as they come in, pass on packets sent through the handle
+    /// and keep the connection alive
+    async fn send<S: AsyncWrite>(&self, wr: &mut WriteHalf<S>, last_seen: &Cell<Instant>,
+        changes: &mut mpsc::UnboundedReceiver<StateChange>,
+        outgoing: &mut mpsc::UnboundedReceiver<Vec<u8>>) -> io::Result<()>
+    {
+        let keep_alive = Duration::from_secs(self.config.keep_alive as u64);
+        let ticks = stream::unfold((), |()| async {
+            task::sleep(TICK).await;
+            Some((Event::Tick, ()))
+        }).boxed_local();
+        let mut events = stream::select(ticks,
+            stream::select(batches(changes).map(Event::Publish), outgoing.map(Event::Send)));
+
+        let mut last_ping = Instant::now();
+        while let Some(event) = events.next().await {
+            match event {
+                Event::Tick => {
+                    if last_ping.elapsed() >= keep_alive / 2 {
+                        // The broker answers every ping, so not hearing from it for this long
+                        // means it's gone
+                        if last_seen.get().elapsed() > keep_alive * 3 / 2 {
+                            return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                "broker stopped responding"));
+                        }
+                        wr.write_all(&packet::pingreq()).await?;
+                        last_ping = Instant::now();
+                    }
+                },
+                Event::Publish(states) => {
+                    // One write for the whole batch instead of one per machine
+                    let packets: Vec<u8> = states.iter()
+                        .flat_map(|state| self.publish_state(state))
+                        .collect();
+                    wr.write_all(&packets).await?
+                },
+                Event::Send(p) => wr.write_all(&p).await?,
+            }
+        }
+        Ok(())
+    }
+
+    fn publish_state(&self, state: &StateChange) -> Vec<u8> {
+        let topic = format!("{}/{}/state", self.config.machine_prefix.trim_end_matches('/'),
+            state.uuid.to_hyphenated());
+        let payload = State {
+            status: state.status.as_str(),
+            occupant: state.occupant.as_deref(),
+            since: state.since,
+            note: state.note.as_deref().filter(|_| self.config.publish_notes),
+        };
+        // Serializing a struct of strings and numbers can't fail
+        let payload = serde_json::to_vec(&payload).unwrap();
+        self.handle.publication(self.config.state, &topic, &payload)
+    }
+}
+
+/// The changes from `changes` in batches of everything that's waiting
+///
+/// Changing many machines at once, e.g. blocking the whole space, would otherwise make us publish
+/// them one after another. Only the latest state of a machine is kept since the earlier ones would
+/// be replaced right away anyway.
+fn batches(changes: &mut mpsc::UnboundedReceiver<StateChange>)
+    -> impl stream::Stream<Item=Vec<StateChange>> + '_
+{
+    stream::poll_fn(move |cx| {
+        let mut batch: Vec<StateChange> = Vec::new();
+        loop {
+            match changes.poll_next_unpin(cx) {
+                Poll::Ready(Some(change)) => {
+                    batch.retain(|c| c.uuid != change.uuid);
+                    batch.push(change);
+                },
+                Poll::Ready(None) if batch.is_empty() => return Poll::Ready(None),
+                Poll::Pending if batch.is_empty() => return Poll::Pending,
+                _ => return Poll::Ready(Some(batch)),
+            }
+        }
+    })
+}
+
+fn refused_reason(code: u8) -> &'static str {
+    match code {
+        1 => "unsupported protocol version",
+        2 => "client id rejected",
+        3 => "server unavailable",
+        4 => "bad user name or password",
+        5 => "not authorized",
+        _ => "unknown reason",
+    }
 }
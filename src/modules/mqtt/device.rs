@@ -0,0 +1,236 @@
+//! Devices switched over MQTT, one implementation per kind of device
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::{self, ActorKind};
+
+/// How to talk to a kind of device over MQTT
+pub trait Device {
+    /// Topic and payload of the command switching the device on or off
+    fn command(&self, power: bool) -> (String, Vec<u8>);
+
+    /// Topic the device reports its state on
+    fn stat_topic(&self) -> String;
+
+    /// Whether the device says it's on or off, if `payload` says anything about it at all
+    fn parse(&self, payload: &[u8]) -> Option<bool>;
+}
+
+/// The device for the actor config of a machine
+///
+/// `None` if it isn't switched over MQTT or the config is missing something validation should
+/// have caught. `client_id` is what we call ourselves on the broker, which some devices want to
+/// know.
+pub fn from_config(config: &config::Actor, client_id: &str) -> Option<Box<dyn Device>> {
+    match config.kind {
+        ActorKind::Tasmota => {
+            let topic = config.topic.clone()?;
+            let stat_topic = match config.stat_topic {
+                Some(ref t) => t.clone(),
+                None if topic.starts_with("cmnd/") =>
+                    format!("stat/{}", &topic["cmnd/".len()..]),
+                None => return None,
+            };
+            Some(Box::new(Tasmota { topic, stat_topic }))
+        },
+        ActorKind::Shelly => {
+            let device = config.device.clone()?;
+            Some(Box::new(Shelly { device, channel: config.channel, src: client_id.to_string() }))
+        },
+        ActorKind::Dummy => None,
+    }
+}
+
+/// A relay running Tasmota, switched with ON and OFF on its POWER topic
+pub struct Tasmota {
+    topic: String,
+    stat_topic: String,
+}
+
+impl Device for Tasmota {
+    fn command(&self, power: bool) -> (String, Vec<u8>) {
+        let payload: &[u8] = if power { b"ON" } else { b"OFF" };
+        (self.topic.clone(), payload.to_vec())
+    }
+
+    fn stat_topic(&self) -> String {
+        self.stat_topic.clone()
+    }
+
+    fn parse(&self, payload: &[u8]) -> Option<bool> {
+        match payload {
+            b"ON" => Some(true),
+            b"OFF" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+/// A Shelly Gen2 device, switched with RPC calls on `<device>/rpc`
+///
+/// Devices with several relays are one actor per channel, all of them reporting on the same
+/// topic.
+pub struct Shelly {
+    /// Topic prefix of the device, its device id unless changed in its MQTT settings
+    device: String,
+    /// Which of its switches powers the machine
+    channel: u8,
+    /// Where the device sends its answers to. We don't read them, the notification that the
+    /// switch changed is what counts.
+    src: String,
+}
+
+#[derive(Serialize)]
+struct Rpc<'a> {
+    id: u32,
+    src: &'a str,
+    method: &'static str,
+    params: SwitchSet,
+}
+
+#[derive(Serialize)]
+struct SwitchSet {
+    id: u8,
+    on: bool,
+}
+
+impl Device for Shelly {
+    fn command(&self, power: bool) -> (String, Vec<u8>) {
+        let rpc = Rpc {
+            id: 1,
+            src: &self.src,
+            method: "Switch.Set",
+            params: SwitchSet { id: self.channel, on: power },
+        };
+        // Serializing a struct of strings and numbers can't fail
+        let payload = serde_json::to_vec(&rpc).unwrap();
+        (format!("{}/rpc", self.device), payload)
+    }
+
+    fn stat_topic(&self) -> String {
+        format!("{}/events/rpc", self.device)
+    }
+
+    /// Notifications look like `{"src":"...","dst":".../events","method":"NotifyStatus",
+    /// "params":{"ts":...,"switch:0":{"id":0,"output":true,"source":"MQTT"}}}`. Changes of other
+    /// switches or of anything else the device measures come in on the same topic and are ignored.
+    fn parse(&self, payload: &[u8]) -> Option<bool> {
+        let msg: Value = serde_json::from_slice(payload).ok()?;
+        match msg.get("method")?.as_str()? {
+            "NotifyStatus" | "NotifyFullStatus" => {},
+            _ => return None,
+        }
+        msg.get("params")?
+            .get(format!("switch:{}", self.channel))?
+            .get("output")?
+            .as_bool()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(toml: &str) -> Box<dyn Device> {
+        let config: config::Actor = toml::from_str(toml).unwrap();
+        from_config(&config, "bffh-lab").unwrap()
+    }
+
+    fn shelly(channel: u8) -> Box<dyn Device> {
+        device(&format!("type = \"shelly\"\ndevice = \"shellyplus2pm-a8032ab1c2d4\"\nchannel = {}",
+            channel))
+    }
+
+    #[test]
+    fn shelly_commands() {
+        let (topic, payload) = shelly(1).command(true);
+        assert_eq!(topic, "shellyplus2pm-a8032ab1c2d4/rpc");
+        let payload: Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(payload, serde_json::json!({
+            "id": 1,
+            "src": "bffh-lab",
+            "method": "Switch.Set",
+            "params": { "id": 1, "on": true },
+        }));
+
+        let (_, payload) = shelly(0).command(false);
+        let payload: Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(payload["params"], serde_json::json!({ "id": 0, "on": false }));
+    }
+
+    // Captured from a Shelly Plus 2PM with firmware 0.14
+    const SWITCHED_ON: &str = concat!(
+        r#"{"src":"shellyplus2pm-a8032ab1c2d4","dst":"shellyplus2pm-a8032ab1c2d4/events","#,
+        r#""method":"NotifyStatus","params":{"ts":1680000000.12,"#,
+        r#""switch:1":{"id":1,"output":true,"source":"MQTT"}}}"#);
+    const SWITCHED_OFF: &str = concat!(
+        r#"{"src":"shellyplus2pm-a8032ab1c2d4","dst":"shellyplus2pm-a8032ab1c2d4/events","#,
+        r#""method":"NotifyStatus","params":{"ts":1680000042.73,"#,
+        r#""switch:1":{"id":1,"output":false,"source":"button"}}}"#);
+    const FULL_STATUS: &str = concat!(
+        r#"{"src":"shellyplus2pm-a8032ab1c2d4","dst":"shellyplus2pm-a8032ab1c2d4/events","#,
+        r#""method":"NotifyFullStatus","params":{"ts":1680000001.01,"#,
+        r#""switch:0":{"id":0,"source":"init","output":false,"apower":0.0,"voltage":231.4},"#,
+        r#""switch:1":{"id":1,"source":"init","output":true,"apower":42.3,"voltage":231.4},"#,
+        r#""sys":{"uptime":12}}}"#);
+    const POWER: &str = concat!(
+        r#"{"src":"shellyplus2pm-a8032ab1c2d4","dst":"shellyplus2pm-a8032ab1c2d4/events","#,
+        r#""method":"NotifyStatus","params":{"ts":1680000005.50,"#,
+        r#""switch:1":{"id":1,"apower":41.9,"current":0.181}}}"#);
+    const ANSWER: &str = concat!(
+        r#"{"id":1,"src":"shellyplus2pm-a8032ab1c2d4","dst":"bffh-lab","#,
+        r#""result":{"was_on":false}}"#);
+    const INPUT: &str = concat!(
+        r#"{"src":"shellyplus2pm-a8032ab1c2d4","dst":"shellyplus2pm-a8032ab1c2d4/events","#,
+        r#""method":"NotifyEvent","params":{"ts":1680000007.00,"events":[{"component":"input:1","#,
+        r#""id":1,"event":"single_push","ts":1680000007.00}]}}"#);
+
+    #[test]
+    fn shelly_confirmations() {
+        let device = shelly(1);
+        assert_eq!(device.stat_topic(), "shellyplus2pm-a8032ab1c2d4/events/rpc");
+        assert_eq!(device.parse(SWITCHED_ON.as_bytes()), Some(true));
+        assert_eq!(device.parse(SWITCHED_OFF.as_bytes()), Some(false));
+        assert_eq!(device.parse(FULL_STATUS.as_bytes()), Some(true));
+
+        // Only the switch of the machine counts
+        let other = shelly(0);
+        assert_eq!(other.parse(SWITCHED_ON.as_bytes()), None);
+        assert_eq!(other.parse(FULL_STATUS.as_bytes()), Some(false));
+    }
+
+    #[test]
+    fn shelly_ignores_the_rest() {
+        let device = shelly(1);
+        for payload in &[POWER, ANSWER, INPUT, "ON", "", "{}"] {
+            assert_eq!(device.parse(payload.as_bytes()), None, "{}", payload);
+        }
+    }
+
+    #[test]
+    fn tasmota() {
+        let device = device("type = \"tasmota\"\ntopic = \"cmnd/laser/POWER\"");
+        assert_eq!(device.command(true), ("cmnd/laser/POWER".to_string(), b"ON".to_vec()));
+        assert_eq!(device.command(false), ("cmnd/laser/POWER".to_string(), b"OFF".to_vec()));
+        assert_eq!(device.stat_topic(), "stat/laser/POWER");
+        assert_eq!(device.parse(b"ON"), Some(true));
+        assert_eq!(device.parse(b"OFF"), Some(false));
+        assert_eq!(device.parse(b"TOGGLE"), None);
+    }
+
+    #[test]
+    fn incomplete_configs() {
+        let incomplete = &[
+            "type = \"shelly\"",
+            "type = \"tasmota\"",
+            // Not a Tasmota command topic, so we can't tell where it reports
+            "type = \"tasmota\"\ntopic = \"laser\"",
+            "type = \"dummy\"",
+        ];
+        for toml in incomplete {
+            let config: config::Actor = toml::from_str(toml).unwrap();
+            assert!(from_config(&config, "bffh-lab").is_none(), "{}", toml);
+        }
+    }
+}
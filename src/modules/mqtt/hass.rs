@@ -0,0 +1,108 @@
+//! Home Assistant MQTT discovery, so machines show up there without configuring each by hand
+//!
+//! See <https://www.home-assistant.io/docs/mqtt/discovery/>. Every machine gets a binary sensor
+//! that's on while it's occupied, machines with an actor optionally a switch showing their power.
+//! Both read the state we publish anyway.
+
+use std::collections::BTreeSet;
+
+use serde_json::json;
+
+use uuid::Uuid;
+
+use crate::config::{Config, Mqtt};
+use crate::machine::Machine;
+
+/// Object ids of our entities start with this, so we can tell them from others
+const OBJECT_PREFIX: &str = "fabaccess_";
+
+pub struct Discovery {
+    prefix: String,
+    /// Machines that get a switch
+    switches: BTreeSet<Uuid>,
+}
+
+impl Discovery {
+    /// Discovery as configured in `[mqtt]`, if it's enabled
+    pub fn new(config: &Config) -> Option<Self> {
+        let mqtt = config.mqtt.as_ref().filter(|m| m.hass_discovery)?;
+        let switches = if mqtt.hass_switches {
+            config.machines.actors.keys().cloned().collect()
+        } else {
+            BTreeSet::new()
+        };
+        Some(Self { prefix: mqtt.hass_prefix.trim_end_matches('/').to_string(), switches })
+    }
+
+    /// Where configs of our entities may be retained, to find those of machines that are gone
+    pub fn topics(&self) -> Vec<String> {
+        vec![
+            format!("{}/binary_sensor/+/config", self.prefix),
+            format!("{}/switch/+/config", self.prefix),
+        ]
+    }
+
+    /// Topics and payloads of the configs announcing all `machines`, to be retained
+    pub fn announce(&self, mqtt: &Mqtt, machines: &[(Uuid, Machine)]) -> Vec<(String, Vec<u8>)> {
+        let mut packets = Vec::new();
+        for (uuid, machine) in machines.iter() {
+            packets.push(self.config(mqtt, "binary_sensor", uuid, machine));
+            if self.switches.contains(uuid) {
+                packets.push(self.config(mqtt, "switch", uuid, machine));
+            }
+        }
+        packets
+    }
+
+    fn config(&self, mqtt: &Mqtt, component: &str, uuid: &Uuid, machine: &Machine)
+        -> (String, Vec<u8>)
+    {
+        let id = format!("{}{}", OBJECT_PREFIX, uuid.to_hyphenated());
+        let state_topic = format!("{}/{}/state", mqtt.machine_prefix.trim_end_matches('/'),
+            uuid.to_hyphenated());
+
+        let mut config = json!({
+            "name": machine.name,
+            "unique_id": format!("{}_{}", id, component),
+            "object_id": id,
+            "state_topic": state_topic,
+            // The occupant and since when show up as attributes
+            "json_attributes_topic": state_topic,
+            "value_template": "{{ 'ON' if value_json.status == 'occupied' else 'OFF' }}",
+            "availability_topic": mqtt.availability_topic,
+            "payload_available": "online",
+            "payload_not_available": "offline",
+            "device": {
+                "identifiers": [id],
+                "name": machine.name,
+                "manufacturer": "FabAccess",
+                "model": "Machine",
+                "suggested_area": machine.location,
+            },
+        });
+        if component == "binary_sensor" {
+            config["device_class"] = json!("occupancy");
+        } else {
+            // Only shows the power, machines are switched by using them
+            config["icon"] = json!("mdi:power-plug");
+        }
+
+        let topic = format!("{}/{}/{}/config", self.prefix, component, id);
+        (topic, config.to_string().into_bytes())
+    }
+
+    /// If `topic` holds the config of one of our entities, the machine it is for
+    ///
+    /// Configs without a payload are ones we removed already.
+    pub fn machine(&self, topic: &str, payload: &[u8]) -> Option<Uuid> {
+        if payload.is_empty() {
+            return None;
+        }
+        let mut parts = topic.strip_prefix(&self.prefix)?.strip_prefix('/')?.split('/');
+        let (_component, id) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(c), Some(id), Some("config"), None) => (c, id),
+            _ => return None,
+        };
+        Uuid::parse_str(id.strip_prefix(OBJECT_PREFIX)?).ok()
+    }
+}
@@ -0,0 +1,214 @@
+//! The few MQTT 3.1.1 packets the bridge needs
+//!
+//! See <http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html> for the format.
+
+use std::io;
+
+use futures::io::{AsyncRead, AsyncReadExt};
+
+/// Largest packet we accept from the broker. Commands are small, anything bigger is a mistake.
+const MAX_PACKET: usize = 64 * 1024;
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+/// A packet sent by the broker
+pub enum Packet {
+    Connack { code: u8 },
+    Publish { topic: String, payload: Vec<u8> },
+    /// The broker got one of our QoS 1 messages
+    Puback { packet_id: u16 },
+    Suback { codes: Vec<u8> },
+    Pingresp,
+    /// Anything we don't need to look at, by type
+    Other(u8),
+}
+
+/// Contents of a CONNECT packet
+pub struct Connect<'a> {
+    pub client_id: &'a str,
+    pub keep_alive: u16,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a str>,
+    /// Topic and message the broker publishes, retained, if we go away without saying goodbye
+    pub will: Option<(&'a str, &'a [u8])>,
+    pub will_qos: u8,
+}
+
+pub fn connect(c: &Connect) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_str(&mut body, "MQTT");
+    // Protocol level 4 is 3.1.1
+    body.push(4);
+
+    // Always start with a clean session, we subscribe again on every connect anyway
+    let mut flags = 0b0000_0010;
+    if c.will.is_some() {
+        // Will flag and will retain
+        flags |= 0b0010_0100 | (c.will_qos & 0b11) << 3;
+    }
+    if c.password.is_some() {
+        flags |= 0b0100_0000;
+    }
+    if c.username.is_some() {
+        flags |= 0b1000_0000;
+    }
+    body.push(flags);
+    body.extend_from_slice(&c.keep_alive.to_be_bytes());
+
+    put_str(&mut body, c.client_id);
+    if let Some((topic, message)) = c.will {
+        put_str(&mut body, topic);
+        put_bytes(&mut body, message);
+    }
+    if let Some(username) = c.username {
+        put_str(&mut body, username);
+    }
+    if let Some(password) = c.password {
+        put_str(&mut body, password);
+    }
+
+    packet(CONNECT << 4, body)
+}
+
+/// Publish with QoS 0
+pub fn publish(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_str(&mut body, topic);
+    body.extend_from_slice(payload);
+
+    packet((PUBLISH << 4) | retain as u8, body)
+}
+
+/// Publish with QoS 1, the broker acknowledges it with a PUBACK carrying `packet_id`
+///
+/// We always start with a clean session, so messages that weren't acknowledged before the
+/// connection broke are gone. Machine states are published again after reconnecting anyway.
+pub fn publish_acked(packet_id: u16, topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_str(&mut body, topic);
+    body.extend_from_slice(&packet_id.to_be_bytes());
+    body.extend_from_slice(payload);
+
+    packet((PUBLISH << 4) | 0b0010 | retain as u8, body)
+}
+
+/// Subscribe to `filter` with QoS 0
+pub fn subscribe(packet_id: u16, filter: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&packet_id.to_be_bytes());
+    put_str(&mut body, filter);
+    body.push(0);
+
+    // The flags of SUBSCRIBE are fixed by the spec
+    packet((SUBSCRIBE << 4) | 0b0010, body)
+}
+
+pub fn pingreq() -> Vec<u8> {
+    packet(PINGREQ << 4, Vec::new())
+}
+
+pub fn disconnect() -> Vec<u8> {
+    packet(DISCONNECT << 4, Vec::new())
+}
+
+/// Read the next packet from the broker
+pub async fn read<R: AsyncRead + Unpin>(r: &mut R) -> io::Result<Packet> {
+    let mut byte = [0u8];
+    r.read_exact(&mut byte).await?;
+    let header = byte[0];
+
+    // The remaining length is encoded in 7 bits per byte, least significant first, at most 4 bytes
+    let mut len = 0usize;
+    for i in 0..4 {
+        r.read_exact(&mut byte).await?;
+        len |= ((byte[0] & 0x7f) as usize) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            break;
+        } else if i == 3 {
+            return Err(invalid("malformed remaining length"));
+        }
+    }
+    if len > MAX_PACKET {
+        return Err(invalid("packet too large"));
+    }
+
+    let mut body = vec![0u8; len];
+    r.read_exact(&mut body).await?;
+
+    match header >> 4 {
+        CONNACK if body.len() == 2 => Ok(Packet::Connack { code: body[1] }),
+        PUBLISH => {
+            let (topic, mut rest) = take_str(&body)?;
+            // QoS 1 and 2 messages carry a packet id. We only subscribe with QoS 0 so the broker
+            // shouldn't send those, but skip it to be safe.
+            if (header >> 1) & 0b11 != 0 {
+                if rest.len() < 2 {
+                    return Err(invalid("truncated PUBLISH"));
+                }
+                rest = &rest[2..];
+            }
+            Ok(Packet::Publish { topic, payload: rest.to_vec() })
+        },
+        PUBACK if body.len() == 2 =>
+            Ok(Packet::Puback { packet_id: u16::from_be_bytes([body[0], body[1]]) }),
+        SUBACK if body.len() >= 2 => Ok(Packet::Suback { codes: body[2..].to_vec() }),
+        PINGRESP => Ok(Packet::Pingresp),
+        CONNACK | PUBACK | SUBACK => Err(invalid("truncated packet")),
+        t => Ok(Packet::Other(t)),
+    }
+}
+
+fn packet(header: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut p = vec![header];
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        p.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    p.extend(body);
+    p
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    put_bytes(buf, s.as_bytes())
+}
+
+/// Strings and binary data are prefixed with their length as u16. Everything we send is either
+/// short or from the config, so truncating is fine.
+fn put_bytes(buf: &mut Vec<u8>, b: &[u8]) {
+    let b = &b[..std::cmp::min(b.len(), u16::MAX as usize)];
+    buf.extend_from_slice(&(b.len() as u16).to_be_bytes());
+    buf.extend_from_slice(b);
+}
+
+fn take_str(buf: &[u8]) -> io::Result<(String, &[u8])> {
+    if buf.len() < 2 {
+        return Err(invalid("truncated string"));
+    }
+    let len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+    if buf.len() < 2 + len {
+        return Err(invalid("truncated string"));
+    }
+    let s = String::from_utf8(buf[2..2 + len].to_vec())
+        .map_err(|_| invalid("string is not UTF-8"))?;
+    Ok((s, &buf[2 + len..]))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,190 @@
+//! Card readers letting members use machines by swiping their card
+//!
+//! A swipe on a free machine uses it just like the `use` call of the API would, a swipe by whoever
+//! is using the machine gives it back.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_std::sync::RwLock;
+
+use serde::{Serialize, Deserialize};
+
+use slog::Logger;
+
+use crate::access::PermissionsProvider;
+use crate::audit::{Audit, AuditEvent};
+use crate::cards::Cards;
+use crate::config::Reader;
+use crate::machine::{lock, MachinesProvider};
+
+/// What a reader sends when a card is swiped
+#[derive(Deserialize)]
+struct Swipe {
+    uid: String,
+    #[serde(default)]
+    secret: Option<String>,
+}
+
+/// What we send back, so the reader can show it
+#[derive(Serialize)]
+struct Outcome {
+    result: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+impl Outcome {
+    fn granted() -> Self {
+        Self { result: "granted", reason: None }
+    }
+
+    fn returned() -> Self {
+        Self { result: "returned", reason: None }
+    }
+
+    fn denied<R: Into<String>>(reason: R) -> Self {
+        Self { result: "denied", reason: Some(reason.into()) }
+    }
+}
+
+/// All configured readers
+pub struct Readers {
+    log: Logger,
+    prefix: String,
+    readers: BTreeMap<String, Reader>,
+    cards: Cards,
+    perm: Arc<RwLock<PermissionsProvider>>,
+    mach: Arc<RwLock<MachinesProvider>>,
+    /// How long a swipe waits for the lock on `mach` before it's turned down
+    lock_timeout: Duration,
+    audit: Audit,
+}
+
+impl Readers {
+    pub fn new(log: Logger, prefix: &str, readers: BTreeMap<String, Reader>, cards: Cards,
+        perm: Arc<RwLock<PermissionsProvider>>, mach: Arc<RwLock<MachinesProvider>>,
+        lock_timeout: Duration, audit: Audit) -> Self
+    {
+        for (id, reader) in readers.iter() {
+            if reader.secret.is_none() {
+                warn!(log, "Reader {} has no secret, make sure only it may publish swipes", id);
+            }
+        }
+
+        let prefix = prefix.trim_end_matches('/').to_string();
+        Self { log, prefix, readers, cards, perm, mach, lock_timeout, audit }
+    }
+
+    /// Topics swipes come in on
+    pub fn topics(&self) -> Vec<String> {
+        self.readers.keys().map(|id| format!("{}/{}/swipe", self.prefix, id)).collect()
+    }
+
+    /// Handle a message if it's a swipe, returning the topic and payload of the result
+    pub async fn handle(&self, topic: &str, payload: &[u8]) -> Option<(String, Vec<u8>)> {
+        let id = topic.strip_prefix(&self.prefix)?
+            .strip_prefix('/')?
+            .strip_suffix("/swipe")?;
+        let reader = self.readers.get(id)?;
+
+        let outcome = self.swipe(id, reader, payload).await;
+        // Serializing a struct of strings can't fail
+        let outcome = serde_json::to_vec(&outcome).unwrap();
+        Some((format!("{}/{}/result", self.prefix, id), outcome))
+    }
+
+    async fn swipe(&self, id: &str, reader: &Reader, payload: &[u8]) -> Outcome {
+        let log = self.log.new(o!("reader" => id.to_string()));
+
+        let swipe: Swipe = match serde_json::from_slice(payload) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!(log, "Ignoring invalid swipe: {}", e);
+                return Outcome::denied("invalid");
+            },
+        };
+        if let Some(ref secret) = reader.secret {
+            if swipe.secret.as_deref() != Some(secret.expose()) {
+                warn!(log, "Swipe with wrong secret, ignoring it");
+                return Outcome::denied("bad-secret");
+            }
+        }
+
+        let user = match self.cards.lookup(&swipe.uid) {
+            Some(u) => u.to_string(),
+            None => {
+                info!(log, "Unknown card {} swiped", swipe.uid);
+                return Outcome::denied("unknown-card");
+            },
+        };
+        let audit = self.audit.for_peer(format!("reader {}", id));
+        let machine = &reader.machine;
+
+        // Whoever swiped is standing right there, they're better off trying again than waiting
+        // on a busy database
+        let timeout = self.lock_timeout;
+
+        // Swiping again gives the machine back
+        {
+            let mut mach = match lock::write(&self.mach, timeout).await {
+                Ok(mach) => mach,
+                Err(e) => return Outcome::denied(code(&e)),
+            };
+            if mach.occupant(machine) == Some(user.as_str()) {
+                return match mach.give_back(&log, machine) {
+                    Ok(()) => {
+                        audit.record(AuditEvent::MachineGiveBack { authzid: &user, machine });
+                        Outcome::returned()
+                    },
+                    Err(e) => Outcome::denied(code(&e)),
+                };
+            }
+        }
+
+        // The same checks as the `use` call of the API
+        let perm = match lock::read(&self.mach, timeout).await.map(|m| m.get_perm_req(machine)) {
+            Ok(Some(p)) => p,
+            Ok(None) => return Outcome::denied("no-such-machine"),
+            Err(e) => return Outcome::denied(code(&e)),
+        };
+        match self.perm.read().await.enforce(&log, &user, &perm, "write") {
+            Ok(true) => {},
+            Ok(false) => {
+                audit.record(AuditEvent::PermissionDenied {
+                    authzid: &user, object: &perm, action: "write",
+                });
+                return Outcome::denied("unauthorized");
+            },
+            Err(e) => {
+                error!(log, "Failed to check permission write on {}: {}", perm, e);
+                return Outcome::denied("internal");
+            },
+        }
+
+        // Managers may use machines outside of their opening hours
+        let hours = match lock::read(&self.mach, timeout).await {
+            Ok(mach) => mach.has_hours(machine),
+            Err(e) => return Outcome::denied(code(&e)),
+        };
+        let anytime = hours
+            && self.perm.read().await.enforce(&log, &user, &perm, "manage").unwrap_or(false);
+        let mut mach = match lock::write(&self.mach, timeout).await {
+            Ok(mach) => mach,
+            Err(e) => return Outcome::denied(code(&e)),
+        };
+        match mach.use_(&log, machine, &user, anytime, None) {
+            Ok(_) => {
+                audit.record(AuditEvent::MachineUse { authzid: &user, machine, note: None });
+                Outcome::granted()
+            },
+            Err(r) => Outcome::denied(r.code().as_str()),
+        }
+    }
+}
+
+/// The code of an API error, e.g. `occupied`
+fn code(e: &capnp::Error) -> String {
+    e.description.split(':').next().unwrap_or("internal").to_string()
+}
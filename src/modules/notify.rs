@@ -0,0 +1,147 @@
+//! Telling others about machines changing their state, e.g. a chat about machines being blocked
+//!
+//! Notifications are best effort. Failing to deliver them is logged and has no other effect.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::{self, FutureExt, LocalBoxFuture, RemoteHandle};
+use futures::stream::StreamExt;
+use futures::task::LocalSpawnExt;
+
+use async_std::sync::RwLock;
+use async_std::task;
+
+use slog::Logger;
+
+use uuid::Uuid;
+
+use crate::config::{self, MachineEvent, NotifierKind};
+use crate::error::{Error, Result};
+use crate::machine::{self, lock, MachinesProvider, StateChange};
+
+use super::actor::json_body;
+use super::http::Endpoint;
+use super::ModuleContext;
+
+/// Delay before the first retry of a failed request, doubling after every further failure
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+struct Notifier {
+    log: Logger,
+    endpoint: Endpoint,
+    body: Option<String>,
+    retries: u32,
+    events: Vec<MachineEvent>,
+    machines: Vec<Uuid>,
+}
+
+impl Notifier {
+    fn from_config(log: Logger, config: &config::Notifier) -> std::io::Result<Self> {
+        let endpoint = match config.kind {
+            NotifierKind::Http => Endpoint::new(&config.url, &config.method, config.headers(),
+                config.verify_tls, Duration::from_secs(config.timeout))?,
+        };
+        Ok(Self {
+            log,
+            endpoint,
+            body: config.body.clone(),
+            retries: config.retries,
+            events: config.events.clone(),
+            machines: config.machines.clone(),
+        })
+    }
+
+    fn wants(&self, change: &StateChange) -> bool {
+        let event = match change.status {
+            machine::Status::Free => MachineEvent::Free,
+            machine::Status::Occupied => MachineEvent::Occupied,
+            machine::Status::Blocked => MachineEvent::Blocked,
+        };
+        (self.events.is_empty() || self.events.contains(&event))
+            && (self.machines.is_empty() || self.machines.contains(&change.uuid))
+    }
+
+    async fn notify(&self, change: &StateChange) {
+        let body = json_body(self.body.as_deref(), change);
+        let mut delay = MIN_RETRY_DELAY;
+        let mut attempt = 0;
+        loop {
+            let why = match self.endpoint.send(&body).await {
+                Ok(200..=299) => return,
+                Ok(status) => format!("answered with status {}", status),
+                Err(e) => e.to_string(),
+            };
+            if attempt >= self.retries {
+                error!(self.log, "Could not tell {} that machine {} is {}: {}",
+                    self.endpoint.url(), change.uuid, change.status.as_str(), why);
+                return;
+            }
+            attempt += 1;
+            warn!(self.log, "Notifying {} failed, retrying in {}s: {}", self.endpoint.url(),
+                delay.as_secs(), why);
+            task::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+
+    /// Deliver notifications one after another so they arrive in order
+    async fn run(self, mach: Arc<RwLock<MachinesProvider>>, background: lock::Background) {
+        let mut changes = background.write(&mach, "subscribe to changes").await.subscribe();
+        while let Some(change) = changes.next().await {
+            if self.wants(&change) {
+                self.notify(&change).await;
+            }
+        }
+    }
+}
+
+/// Tell every configured notifier about the changes it cares about for as long as we run
+///
+/// Each notifier gets its own queue so one that's down doesn't hold up the others.
+pub async fn run(log: Logger, notifiers: Vec<config::Notifier>,
+    mach: Arc<RwLock<MachinesProvider>>, background: lock::Background)
+{
+    let mut tasks = Vec::new();
+    for (i, config) in notifiers.iter().enumerate() {
+        match Notifier::from_config(log.new(o!("notifier" => i)), config) {
+            Ok(n) => tasks.push(n.run(mach.clone(), background.clone())),
+            Err(e) => error!(log, "Could not set up notifier {}: {}", i, e),
+        }
+    }
+    future::join_all(tasks).await;
+}
+
+/// The notifiers as a module
+pub struct Module {
+    task: Option<RemoteHandle<()>>,
+}
+
+impl Module {
+    pub fn new() -> Self {
+        Self { task: None }
+    }
+}
+
+impl super::Module for Module {
+    fn name(&self) -> &'static str {
+        "notify"
+    }
+
+    fn init<'a>(&'a mut self, ctx: ModuleContext<'a>) -> LocalBoxFuture<'a, Result<()>> {
+        async move {
+            let f = run(ctx.log.clone(), ctx.config.notifiers.clone(), ctx.mach.clone(),
+                ctx.background());
+            let task = ctx.spawner.spawn_local_with_handle(f)
+                .map_err(|e| Error::Boxed(Box::new(e)))?;
+            self.task = Some(task);
+            Ok(())
+        }.boxed_local()
+    }
+
+    /// Notifications still being sent are dropped, they're only best effort anyway
+    fn shutdown(&mut self) -> LocalBoxFuture<'_, ()> {
+        self.task = None;
+        future::ready(()).boxed_local()
+    }
+}
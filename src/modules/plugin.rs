@@ -0,0 +1,310 @@
+//! Modules loaded at runtime from shared objects
+//!
+//! Only compiled with the `plugins` feature. A plugin exports two symbols with C linkage:
+//!
+//! - `FABACCESS_MODULE_ABI`, a `u32` holding the `ABI_VERSION` it was built against
+//! - `fabaccess_module_init`, a function without arguments returning a pointer to its `Vtable`
+//!
+//! The version is checked before anything in the plugin is called. Strings crossing the boundary
+//! are NUL-terminated UTF-8 and only valid for the duration of the call. Plugins are called on
+//! the same thread as all other modules, so they have to return quickly.
+//!
+//! `plugins/example` is a plugin that only logs what it's told, to start your own from.
+
+use std::cell::Cell;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use futures::future::{self, FutureExt, LocalBoxFuture, RemoteHandle};
+use futures::stream::StreamExt;
+use futures::task::LocalSpawnExt;
+
+use libloading::Library;
+
+use slog::Logger;
+
+use crate::error::{Error, Result};
+use crate::machine::StateChange;
+
+use super::{Module, ModuleContext};
+
+/// Version of the interface below. Plugins built against any other one are refused.
+pub const ABI_VERSION: u32 = 1;
+
+const ABI_SYMBOL: &[u8] = b"FABACCESS_MODULE_ABI\0";
+const INIT_SYMBOL: &[u8] = b"fabaccess_module_init\0";
+
+/// What a plugin hands us. Every function gets `state` passed back.
+#[repr(C)]
+pub struct Vtable {
+    /// Name of the module, its settings are taken from `[modules.<name>]`
+    pub name: *const c_char,
+    /// Whatever the plugin needs to keep around
+    pub state: *mut c_void,
+    /// Start with the settings as JSON, `null` if there are none. Anything but 0 is a failure.
+    pub init: extern "C" fn(state: *mut c_void, settings: *const c_char) -> c_int,
+    /// Stop. Nothing else is called afterwards.
+    pub shutdown: extern "C" fn(state: *mut c_void),
+    /// Something happened, as JSON like `{"type": "machine", "machine": "<uuid>", ...}`
+    pub handle_event: extern "C" fn(state: *mut c_void, event: *const c_char),
+}
+
+type InitFn = unsafe extern "C" fn() -> *const Vtable;
+
+/// The shared objects in `dir`, in order of their names
+pub fn find(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map(|e| e == std::env::consts::DLL_EXTENSION).unwrap_or(false) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+struct Inner {
+    log: Logger,
+    name: &'static str,
+    vtable: *const Vtable,
+    /// Set once the plugin panicked, it's not called anymore after that
+    broken: Cell<bool>,
+    /// Has to stay loaded for as long as we call into it, so it's dropped last
+    _lib: Library,
+}
+
+impl Inner {
+    /// Call into the plugin unless it panicked before
+    ///
+    /// Panics are caught as long as they make it back to us. A plugin aborting or unwinding with
+    /// something else than a Rust panic takes us down with it, there's no helping that.
+    fn call<T, F: FnOnce(&Vtable) -> T>(&self, what: &str, f: F) -> Option<T> {
+        if self.broken.get() {
+            return None;
+        }
+        // The vtable stays valid for as long as the library is loaded
+        let vtable = unsafe { &*self.vtable };
+        match panic::catch_unwind(AssertUnwindSafe(|| f(vtable))) {
+            Ok(r) => Some(r),
+            Err(_) => {
+                error!(self.log, "Plugin panicked in {}, not calling it anymore", what);
+                self.broken.set(true);
+                None
+            },
+        }
+    }
+}
+
+/// A module loaded from a shared object
+pub struct Plugin {
+    inner: Rc<Inner>,
+    events: Option<RemoteHandle<()>>,
+}
+
+impl Plugin {
+    /// Load the plugin at `path`, refusing it if it was built for another ABI version
+    pub fn load(log: &Logger, path: &Path) -> io::Result<Self> {
+        let lib = Library::new(path).map_err(other)?;
+
+        // Only as safe as the plugin is honest about the types of its symbols, which is all we
+        // can go on
+        let vtable = unsafe {
+            let version = lib.get::<*const u32>(ABI_SYMBOL).map_err(other)?;
+            let version = **version;
+            if version != ABI_VERSION {
+                return Err(invalid(format!("built for module ABI {}, we have {}", version,
+                    ABI_VERSION)));
+            }
+
+            let init = lib.get::<InitFn>(INIT_SYMBOL).map_err(other)?;
+            panic::catch_unwind(AssertUnwindSafe(|| init()))
+                .map_err(|_| invalid("panicked while loading".to_string()))?
+        };
+        if vtable.is_null() {
+            return Err(invalid("returned no vtable".to_string()));
+        }
+
+        let name = unsafe { (*vtable).name };
+        if name.is_null() {
+            return Err(invalid("has no name".to_string()));
+        }
+        let name = unsafe { CStr::from_ptr(name) }.to_str()
+            .map_err(|_| invalid("has a name that isn't UTF-8".to_string()))?;
+        // Plugins stay loaded for as long as we run anyway
+        let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+
+        let inner = Inner {
+            log: log.new(o!("plugin" => name)),
+            name,
+            vtable,
+            broken: Cell::new(false),
+            _lib: lib,
+        };
+        Ok(Self { inner: Rc::new(inner), events: None })
+    }
+}
+
+impl Module for Plugin {
+    fn name(&self) -> &'static str {
+        self.inner.name
+    }
+
+    fn init<'a>(&'a mut self, ctx: ModuleContext<'a>) -> LocalBoxFuture<'a, Result<()>> {
+        async move {
+            let settings = CString::new(serde_json::to_string(&ctx.section)?).map_err(other)?;
+            match self.inner.call("init", |v| (v.init)(v.state, settings.as_ptr())) {
+                Some(0) => {},
+                Some(code) => return Err(other(format!("failed with code {}", code)).into()),
+                None => return Err(other("panicked".to_string()).into()),
+            }
+
+            let inner = self.inner.clone();
+            let mut changes = ctx.background().write(&ctx.mach, "subscribe to changes").await
+                .subscribe();
+            let f = async move {
+                while let Some(change) = changes.next().await {
+                    let event = match event(&change) {
+                        Ok(e) => e,
+                        Err(e) => {
+                            warn!(inner.log, "Could not pass on change of machine {}: {}",
+                                change.uuid, e);
+                            continue;
+                        },
+                    };
+                    if inner.call("handle_event", |v| (v.handle_event)(v.state, event.as_ptr()))
+                        .is_none()
+                    {
+                        return;
+                    }
+                }
+            };
+            let task = ctx.spawner.spawn_local_with_handle(f)
+                .map_err(|e| Error::Boxed(Box::new(e)))?;
+            self.events = Some(task);
+            Ok(())
+        }.boxed_local()
+    }
+
+    fn shutdown(&mut self) -> LocalBoxFuture<'_, ()> {
+        self.events = None;
+        self.inner.call("shutdown", |v| (v.shutdown)(v.state));
+        future::ready(()).boxed_local()
+    }
+}
+
+fn event(change: &StateChange) -> io::Result<CString> {
+    let event = serde_json::json!({
+        "type": "machine",
+        "machine": change.uuid.to_hyphenated().to_string(),
+        "name": change.name,
+        "state": change.status.as_str(),
+        "occupant": change.occupant,
+        "since": change.since,
+    });
+    CString::new(event.to_string()).map_err(other)
+}
+
+fn other<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process::Command;
+
+    use crate::machine;
+    use crate::testing::{logger, TempDir, LASER};
+
+    /// Build `plugins/example` into `dir`, returning where the shared object ended up
+    fn example(dir: &TempDir) -> PathBuf {
+        let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("plugins/example/Cargo.toml");
+        let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+        let status = Command::new(cargo)
+            .arg("build")
+            .arg("--quiet")
+            .arg("--manifest-path").arg(&manifest)
+            .arg("--target-dir").arg(dir.join("target"))
+            .status()
+            .unwrap();
+        assert!(status.success(), "building the example plugin failed");
+
+        let name = format!("{}fabaccess_example_plugin.{}", env::consts::DLL_PREFIX,
+            env::consts::DLL_EXTENSION);
+        let plugins = dir.join("plugins");
+        fs::create_dir(&plugins).unwrap();
+        fs::copy(dir.join("target/debug").join(&name), plugins.join(&name)).unwrap();
+        plugins.join(name)
+    }
+
+    #[test]
+    fn loads_the_example() {
+        let dir = TempDir::new();
+        let path = example(&dir);
+        // Other files lying around are left alone
+        fs::write(dir.join("plugins/README"), "not a plugin").unwrap();
+        assert_eq!(find(&dir.join("plugins")).unwrap(), vec![path.clone()]);
+
+        let plugin = Plugin::load(&logger(), &path).unwrap();
+        assert_eq!(plugin.name(), "example");
+
+        let settings = CString::new("{\"greeting\":\"hello\"}").unwrap();
+        let state = StateChange {
+            uuid: LASER,
+            name: "Laser".to_string(),
+            status: machine::Status::Occupied,
+            occupant: Some("alice".to_string()),
+            since: Some(1_000_000),
+            note: None,
+        };
+        let event = event(&state).unwrap();
+        let inner = &plugin.inner;
+        assert_eq!(inner.call("init", |v| (v.init)(v.state, settings.as_ptr())), Some(0));
+        assert_eq!(inner.call("handle_event", |v| (v.handle_event)(v.state, event.as_ptr())),
+            Some(()));
+        assert_eq!(inner.call("shutdown", |v| (v.shutdown)(v.state)), Some(()));
+    }
+
+    #[test]
+    fn refuses_other_files() {
+        let dir = TempDir::new();
+        let path = dir.join(format!("garbage.{}", env::consts::DLL_EXTENSION));
+        fs::write(&path, "not a shared object").unwrap();
+        assert!(Plugin::load(&logger(), &path).is_err());
+    }
+
+    #[test]
+    fn events() {
+        let state = StateChange {
+            uuid: LASER,
+            // Makes it through escaped
+            name: "Laser\0cutter".to_string(),
+            status: machine::Status::Free,
+            occupant: None,
+            since: None,
+            note: Some("not passed on".to_string()),
+        };
+        let event = event(&state).unwrap();
+        let json: serde_json::Value = serde_json::from_str(event.to_str().unwrap()).unwrap();
+        assert_eq!(json, serde_json::json!({
+            "type": "machine",
+            "machine": LASER.to_hyphenated().to_string(),
+            "name": "Laser\0cutter",
+            "state": "free",
+            "occupant": null,
+            "since": null,
+        }));
+    }
+}
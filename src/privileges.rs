@@ -0,0 +1,257 @@
+//! Dropping root privileges after startup
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use nix::unistd::{self, AccessFlags, Gid, Uid};
+
+use slog::Logger;
+
+use crate::config::{ActorKind, Config};
+
+fn denied(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, msg)
+}
+
+/// Switch to the user and group configured in `[daemon]`
+///
+/// This has to happen after all privileged setup like binding to low ports is done. Afterwards
+/// the files we still need to access are checked so that a misconfiguration shows up now instead
+/// of as an obscure error the first time the machine database is saved.
+pub fn drop(log: &Logger, config: &Config) -> io::Result<()> {
+    let daemon = &config.daemon;
+    if daemon.user.is_none() && daemon.group.is_none() {
+        return Ok(());
+    }
+
+    let user = match daemon.user {
+        Some(ref name) => Some(users::get_user_by_name(name)
+            .ok_or_else(|| denied(format!("Can not drop privileges: no such user {}", name)))?),
+        None => None,
+    };
+
+    // Without an explicit group use the primary group of the user
+    let gid = match daemon.group {
+        Some(ref name) => Some(users::get_group_by_name(name)
+            .ok_or_else(|| denied(format!("Can not drop privileges: no such group {}", name)))?
+            .gid()),
+        None => user.as_ref().map(|u| u.primary_group_id()),
+    };
+
+    let to_io = |what: &str, e: nix::Error| denied(format!("Can not drop privileges: {} failed: {}",
+        what, e));
+
+    // Dry runs write into a directory that belongs to whoever started us
+    if let Some(ref dir) = config.dry_run {
+        unistd::chown(dir.as_path(), user.as_ref().map(|u| Uid::from_raw(u.uid())),
+            gid.map(Gid::from_raw)).map_err(|e| to_io("chown", e))?;
+    }
+
+    // Groups have to be changed first since we lose the permission to do so with the user switch
+    if let Some(gid) = gid {
+        let gid = Gid::from_raw(gid);
+        unistd::setgroups(&[gid]).map_err(|e| to_io("setgroups", e))?;
+        unistd::setgid(gid).map_err(|e| to_io("setgid", e))?;
+    }
+
+    if let Some(ref user) = user {
+        let uid = Uid::from_raw(user.uid());
+        unistd::setuid(uid).map_err(|e| to_io("setuid", e))?;
+
+        // If we were supposed to become somebody else than root but could get root back, something
+        // went very wrong.
+        if !uid.is_root() && (unistd::geteuid().is_root() || unistd::setuid(Uid::from_raw(0)).is_ok()) {
+            return Err(denied("Can not drop privileges: process is still able to regain root"
+                .to_string()));
+        }
+    }
+
+    info!(log, "Dropped privileges";
+        "uid" => unistd::getuid().as_raw(), "gid" => unistd::getgid().as_raw());
+
+    check_access(config)
+}
+
+/// Make sure the unprivileged process can still use all files it needs
+fn check_access(config: &Config) -> io::Result<()> {
+    // Saving the machine database creates a new file next to it so the directory has to be
+    // writable as well. Dry runs only read it and save into their own directory.
+    // The same goes for its journal, which is only read in dry runs.
+    let journal = crate::machine::journal::path(&config.machinedb);
+    match config.dry_run {
+        Some(ref dir) => {
+            need(&config.machinedb, AccessFlags::R_OK)?;
+            need(&journal, AccessFlags::R_OK)?;
+            need(dir, AccessFlags::W_OK | AccessFlags::X_OK)?;
+        },
+        None => {
+            need(&config.machinedb, AccessFlags::R_OK | AccessFlags::W_OK)?;
+            need(&journal, AccessFlags::R_OK | AccessFlags::W_OK)?;
+            if let Some(dir) = config.machinedb.parent() {
+                need(dir, AccessFlags::W_OK | AccessFlags::X_OK)?;
+            }
+        },
+    }
+
+    need(&config.passdb, AccessFlags::R_OK)?;
+    if let Some(ref cards) = config.cards {
+        need(cards, AccessFlags::R_OK)?;
+    }
+    need(&config.access.model, AccessFlags::R_OK)?;
+    need(&config.access.policy, AccessFlags::R_OK)?;
+    // Grants are saved the same way as the machine database
+    if let Some(ref grants) = config.access.grants {
+        need(grants, AccessFlags::R_OK | AccessFlags::W_OK)?;
+        if let Some(dir) = grants.parent() {
+            need(dir, AccessFlags::W_OK | AccessFlags::X_OK)?;
+        }
+    }
+    // Redeeming a password reset token writes the password database and the tokens
+    if let Some(ref resets) = config.auth.resets {
+        need(resets, AccessFlags::R_OK | AccessFlags::W_OK)?;
+        need(&config.passdb, AccessFlags::W_OK)?;
+        for path in [resets, &config.passdb].iter() {
+            if let Some(dir) = path.parent() {
+                need(dir, AccessFlags::W_OK | AccessFlags::X_OK)?;
+            }
+        }
+    }
+
+    // Relays on GPIOs are switched through the chip device for as long as we run
+    for actor in config.machines.actors.values() {
+        if actor.kind == ActorKind::Gpio && actor.simulate.is_none() {
+            need(&actor.chip, AccessFlags::R_OK | AccessFlags::W_OK)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A file deciding who may do what that somebody else than us and root could change
+pub struct Exposed {
+    pub path: PathBuf,
+    what: String,
+    /// Whether it's bad enough to not start at all, e.g. anybody could grant themselves everything
+    pub fatal: bool,
+}
+
+impl fmt::Display for Exposed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.path.display(), self.what)
+    }
+}
+
+/// Look for files among the config at `configpath`, the password database, the policy and model,
+/// the temporary grants, the machine database and the password reset tokens that others than us
+/// and root could write to
+///
+/// Only the files themselves are looked at, not the directories they're in. Files that don't
+/// exist yet are fine, we create them with the right permissions.
+pub fn check_files(config: &Config, configpath: &Path) -> Vec<Exposed> {
+    // Who we will be running as, which is who may own the files besides root
+    let us = match config.daemon.user {
+        Some(ref name) => users::get_user_by_name(name).map(|u| u.uid()),
+        None => Some(unistd::geteuid().as_raw()),
+    };
+
+    let mut files: Vec<&Path> = vec![configpath, &config.passdb, &config.access.policy,
+        &config.access.model, &config.machinedb];
+    // Whoever can add a grant there can do anything, same as with the policy
+    if let Some(ref grants) = config.access.grants {
+        files.push(grants);
+    }
+    // Whoever can add a token there can set anybody's password
+    if let Some(ref resets) = config.auth.resets {
+        files.push(resets);
+    }
+    let mut exposed = Vec::new();
+    for path in files.iter() {
+        let meta = match fs::metadata(path) {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+        let mut found = |what: String, fatal: bool| exposed.push(Exposed {
+            path: path.to_path_buf(), what, fatal,
+        });
+
+        let mode = meta.mode();
+        if mode & 0o002 != 0 {
+            found("is writable by everybody".to_string(), true);
+        } else if mode & 0o020 != 0 {
+            found("is writable by its group".to_string(), false);
+        }
+        let owner = meta.uid();
+        if owner != 0 && Some(owner) != us {
+            let name = users::get_user_by_uid(owner)
+                .map(|u| u.name().to_string_lossy().into_owned())
+                .unwrap_or_else(|| owner.to_string());
+            found(format!("belongs to {}, who is neither root nor who we run as", name), true);
+        }
+    }
+    exposed
+}
+
+fn need(path: &Path, mode: AccessFlags) -> io::Result<()> {
+    // A file that doesn't exist yet will be created, which is covered by checking its directory
+    if !path.exists() {
+        return Ok(());
+    }
+
+    unistd::access(path, mode).map_err(|e| {
+        denied(format!("{} is not accessible after dropping privileges: {}", path.display(), e))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// What `check_files` finds with the files in `dir`, as the file names and whether it's fatal
+    fn exposed(config: &Config, dir: &TempDir) -> Vec<(String, bool)> {
+        check_files(config, &dir.join("bffh.toml")).into_iter()
+            .map(|e| (e.path.file_name().unwrap().to_string_lossy().into_owned(), e.fatal))
+            .collect()
+    }
+
+    fn chmod(path: &Path, mode: u32) {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn writable_files() {
+        let dir = TempDir::new();
+        let mut config = Config::default();
+        config.passdb = dir.join("passwd.toml");
+        config.access.model = dir.join("model.conf");
+        config.access.policy = dir.join("policy.csv");
+        config.access.grants = Some(dir.join("grants.toml"));
+        config.auth.resets = Some(dir.join("resets.toml"));
+        config.machinedb = dir.join("machines.toml");
+        // Files that aren't there yet are fine
+        assert_eq!(exposed(&config, &dir), vec![]);
+
+        let names = ["bffh.toml", "passwd.toml", "model.conf", "policy.csv", "grants.toml",
+            "resets.toml", "machines.toml"];
+        for name in names.iter() {
+            fs::write(dir.join(name), "").unwrap();
+            chmod(&dir.join(name), 0o644);
+        }
+        assert_eq!(exposed(&config, &dir), vec![]);
+
+        chmod(&dir.join("policy.csv"), 0o664);
+        assert_eq!(exposed(&config, &dir), vec![("policy.csv".to_string(), false)]);
+
+        for name in names.iter() {
+            chmod(&dir.join(name), 0o646);
+            assert_eq!(exposed(&config, &dir).into_iter().filter(|(_, fatal)| *fatal)
+                .map(|(n, _)| n).collect::<Vec<_>>(), vec![name.to_string()]);
+            chmod(&dir.join(name), 0o644);
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! What to do when we stop, registered by whatever needs it while starting up
+//!
+//! Hooks run one after the other, lowest priority first and in the order they were registered
+//! within a priority. Each gets a timeout: a hook taking longer is dropped halfway and reported,
+//! so one stuck disk or broker can't keep the rest from running.
+//!
+//! Hooks below `DRAIN` run as soon as we decide to stop, the others once open connections had
+//! their grace period to finish.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::future::{FutureExt, LocalBoxFuture};
+use futures::Future;
+
+use async_std::future::timeout;
+
+use slog::Logger;
+
+/// Stop taking new connections
+pub const LISTENERS: u32 = 100;
+/// Not a hook, where waiting for open connections to finish happens
+pub const DRAIN: u32 = 200;
+/// Write out state nobody can change anymore
+pub const SAVE: u32 = 300;
+/// Make sure what was written is on disk
+pub const SYNC: u32 = 400;
+
+type Hook = Box<dyn FnOnce() -> LocalBoxFuture<'static, Result<(), String>>>;
+
+struct Entry {
+    name: &'static str,
+    priority: u32,
+    timeout: Duration,
+    hook: Hook,
+}
+
+/// All hooks registered so far, shared by whoever registers them
+#[derive(Clone)]
+pub struct Registry {
+    hooks: Rc<RefCell<Vec<Entry>>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self { hooks: Rc::new(RefCell::new(Vec::new())) }
+    }
+
+    /// Run `hook` during shutdown, see the module docs for when. `name` is what the log calls it.
+    pub fn register<F, Fut>(&self, name: &'static str, priority: u32, timeout: Duration, hook: F)
+        where F: FnOnce() -> Fut + 'static,
+              Fut: Future<Output = Result<(), String>> + 'static,
+    {
+        let hook: Hook = Box::new(move || hook().boxed_local());
+        self.hooks.borrow_mut().push(Entry { name, priority, timeout, hook });
+    }
+
+    /// Run the hooks with a priority below `priority` that didn't run yet
+    pub async fn run_below(&self, log: &Logger, priority: u32) {
+        self.run_where(log, |p| p < priority).await
+    }
+
+    /// Run all hooks that didn't run yet
+    pub async fn run(&self, log: &Logger) {
+        self.run_where(log, |_| true).await
+    }
+
+    async fn run_where<P: Fn(u32) -> bool>(&self, log: &Logger, due: P) {
+        let due = {
+            let mut hooks = self.hooks.borrow_mut();
+            let (mut due, later): (Vec<Entry>, Vec<Entry>) = hooks.drain(..)
+                .partition(|e| due(e.priority));
+            *hooks = later;
+            // Stable, so registration order holds within a priority
+            due.sort_by_key(|e| e.priority);
+            due
+        };
+
+        for entry in due {
+            let started = Instant::now();
+            let r = timeout(entry.timeout, (entry.hook)()).await;
+            let ms = started.elapsed().as_millis() as u64;
+            match r {
+                Ok(Ok(())) => info!(log, "Shutdown hook {} done", entry.name; "ms" => ms),
+                Ok(Err(e)) => error!(log, "Shutdown hook {} failed: {}", entry.name, e;
+                    "ms" => ms),
+                Err(_) => error!(log, "Shutdown hook {} did not finish within {}s, gave up on it",
+                    entry.name, entry.timeout.as_secs(); "ms" => ms),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::LocalPool;
+    use futures::future;
+
+    use super::*;
+    use crate::testing::logger;
+
+    #[test]
+    fn order_and_timeouts() {
+        let ran = Rc::new(RefCell::new(Vec::new()));
+        let hooks = Registry::new();
+        let register = |name: &'static str, priority: u32, result: Option<Result<(), String>>| {
+            let ran = ran.clone();
+            hooks.register(name, priority, Duration::from_millis(50), move || async move {
+                ran.borrow_mut().push(name);
+                match result {
+                    Some(r) => r,
+                    // Stuck for good
+                    None => future::pending().await,
+                }
+            });
+        };
+        register("sync", SYNC, Some(Ok(())));
+        register("save", SAVE, None);
+        register("listeners", LISTENERS, Some(Ok(())));
+        register("outbox", SAVE, Some(Err("disk full".to_string())));
+        register("mqtt", SAVE, Some(Ok(())));
+
+        let log = logger();
+        LocalPool::new().run_until(async {
+            hooks.run_below(&log, DRAIN).await;
+            assert_eq!(*ran.borrow(), vec!["listeners"]);
+
+            // Neither being stuck nor failing keeps the others from running
+            hooks.run(&log).await;
+            assert_eq!(*ran.borrow(), vec!["listeners", "save", "outbox", "mqtt", "sync"]);
+
+            // Every hook only ever runs once
+            hooks.run(&log).await;
+            assert_eq!(ran.borrow().len(), 5);
+        });
+    }
+}
@@ -0,0 +1,273 @@
+//! Counting what happens over time for reports, e.g. how many members used machines this year
+//!
+//! Counts are kept per day, in the timezone of `machines.timezone`, in the file at `stats.path`.
+//! They're collected in memory and only written out every `stats.flush_interval` seconds and
+//! during shutdown, so a crash loses at most the counts since the last write. The file is replaced
+//! as a whole on every write, so it's never left half written.
+//!
+//! Time a machine was occupied is counted on the day it was given back, even if it was taken into
+//! use the day before.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+
+use futures::channel::mpsc;
+use futures::future;
+use futures::stream::StreamExt;
+
+use async_std::sync::{Arc, RwLock};
+use async_std::task;
+
+use chrono::{NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+
+use serde::{Serialize, Deserialize};
+
+use slog::Logger;
+
+use uuid::Uuid;
+
+use crate::audit::{Event, Recorded};
+use crate::config::Config;
+use crate::error::{Result, WithPath};
+use crate::machine::{lock, unix_secs, MachinesProvider, StateChange, Status};
+
+/// How days are written, which also sorts them in order
+const DAY_FORMAT: &str = "%Y-%m-%d";
+
+/// Everything counted on one day
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Day {
+    #[serde(default)]
+    pub authentications: u64,
+    #[serde(default)]
+    pub failed_authentications: u64,
+    /// Everybody who took a machine into use
+    #[serde(default)]
+    pub users: BTreeSet<String>,
+    #[serde(default)]
+    pub machines: BTreeMap<Uuid, MachineDay>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MachineDay {
+    /// How often it was taken into use
+    #[serde(default)]
+    pub sessions: u64,
+    #[serde(default)]
+    pub occupied_secs: u64,
+}
+
+/// The days from `from` to `to` added up
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Totals {
+    /// First and last day that had anything counted, `None` if none had
+    pub first: Option<String>,
+    pub last: Option<String>,
+    pub authentications: u64,
+    pub failed_authentications: u64,
+    /// Users who took a machine into use at least once
+    pub unique_users: usize,
+    /// Busiest first, by the time they were occupied
+    pub machines: Vec<(Uuid, MachineDay)>,
+}
+
+/// The counts of all days, as they are on disk
+pub struct Store {
+    path: PathBuf,
+    /// By day as in `DAY_FORMAT`
+    days: BTreeMap<String, Day>,
+    /// Whether something was counted since the last write
+    dirty: bool,
+}
+
+impl Store {
+    /// Read the counts at `path`, which is created on the first write if it doesn't exist
+    pub fn open(path: &Path) -> Result<Self> {
+        let days = if path.is_file() {
+            serde_json::from_str(&fs::read_to_string(path).with_path(path)?)?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self { path: path.to_path_buf(), days, dirty: false })
+    }
+
+    fn day_mut(&mut self, day: String) -> &mut Day {
+        self.dirty = true;
+        self.days.entry(day).or_default()
+    }
+
+    /// Add up the days from `from` to `to`, both included and both open if `None`
+    pub fn totals(&self, from: Option<&NaiveDate>, to: Option<&NaiveDate>) -> Totals {
+        let from = from.map(|d| d.format(DAY_FORMAT).to_string()).unwrap_or_default();
+        let to = to.map(|d| d.format(DAY_FORMAT).to_string());
+
+        let mut totals = Totals::default();
+        let mut users = BTreeSet::new();
+        let mut machines: BTreeMap<Uuid, MachineDay> = BTreeMap::new();
+        let days = self.days.range(from..)
+            .take_while(|(day, _)| to.as_ref().map_or(true, |to| day.as_str() <= to.as_str()));
+        for (day, counts) in days {
+            if totals.first.is_none() {
+                totals.first = Some(day.clone());
+            }
+            totals.last = Some(day.clone());
+            totals.authentications += counts.authentications;
+            totals.failed_authentications += counts.failed_authentications;
+            users.extend(counts.users.iter());
+            for (uuid, m) in counts.machines.iter() {
+                let sum = machines.entry(*uuid).or_default();
+                sum.sessions += m.sessions;
+                sum.occupied_secs += m.occupied_secs;
+            }
+        }
+        totals.unique_users = users.len();
+        totals.machines = machines.into_iter().collect();
+        totals.machines.sort_by(|a, b| b.1.occupied_secs.cmp(&a.1.occupied_secs)
+            .then(b.1.sessions.cmp(&a.1.sessions)));
+        totals
+    }
+
+    /// Write the counts out if anything changed since the last time
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut fp = File::create(&tmp).with_path(&tmp)?;
+            fp.write_all(&serde_json::to_vec(&self.days)?).with_path(&tmp)?;
+            fp.sync_all().with_path(&tmp)?;
+        }
+        fs::rename(&tmp, &self.path).with_path(&self.path)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+/// Open the counts configured in `[stats]`, if any
+pub fn open(config: &Config) -> Result<Option<Stats>> {
+    let path = match config.stats.path {
+        Some(ref p) => p,
+        None => return Ok(None),
+    };
+    let timezone = config.machines.timezone.parse().unwrap_or(Tz::UTC);
+    Ok(Some(Stats::new(Store::open(path)?, timezone)))
+}
+
+/// Handle to the counts, for counting and for reading them back while we run
+#[derive(Clone)]
+pub struct Stats {
+    store: Rc<RefCell<Store>>,
+    timezone: Tz,
+}
+
+impl Stats {
+    pub fn new(store: Store, timezone: Tz) -> Self {
+        Self { store: Rc::new(RefCell::new(store)), timezone }
+    }
+
+    /// The day `at` falls on, in UNIX seconds
+    fn day(&self, at: u64) -> String {
+        self.timezone.from_utc_datetime(&Utc.timestamp(at as i64, 0).naive_utc())
+            .format(DAY_FORMAT).to_string()
+    }
+
+    /// See `Store::totals`, including what wasn't written out yet
+    pub fn totals(&self, from: Option<&NaiveDate>, to: Option<&NaiveDate>) -> Totals {
+        self.store.borrow().totals(from, to)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.store.borrow_mut().flush()
+    }
+
+    /// Count logins and machine use for as long as we run, writing the counts out every
+    /// `interval`
+    pub async fn run(self, log: Logger, events: mpsc::UnboundedReceiver<Recorded>,
+        mach: Arc<RwLock<MachinesProvider>>, background: lock::Background, interval: Duration)
+    {
+        let (changes, states) = {
+            let mut mach = background.write(&mach, "count machine use").await;
+            (mach.subscribe(), mach.states())
+        };
+        future::join3(self.count_logins(events), self.count_use(changes, states),
+            self.write_out(log, interval)).await;
+    }
+
+    async fn count_logins(&self, mut events: mpsc::UnboundedReceiver<Recorded>) {
+        while let Some(recorded) = events.next().await {
+            if let Event::Authentication { granted, .. } = recorded.event {
+                let day = self.day(unix_secs(SystemTime::now()));
+                let mut store = self.store.borrow_mut();
+                let counts = store.day_mut(day);
+                if granted {
+                    counts.authentications += 1;
+                } else {
+                    counts.failed_authentications += 1;
+                }
+            }
+        }
+    }
+
+    /// Machines in use when we started count from then on, their session was counted already
+    async fn count_use(&self, mut changes: mpsc::UnboundedReceiver<StateChange>,
+        states: Vec<StateChange>)
+    {
+        // When each machine in use was taken into use
+        let mut occupied: HashMap<Uuid, u64> = states.into_iter()
+            .filter(|s| s.status == Status::Occupied)
+            .filter_map(|s| s.since.map(|since| (s.uuid, since)))
+            .collect();
+
+        while let Some(change) = changes.next().await {
+            let now = unix_secs(SystemTime::now());
+            let since = match change.status {
+                Status::Occupied => change.since,
+                _ => None,
+            };
+            // Same session as before, e.g. only the queue changed
+            if since.is_some() && occupied.get(&change.uuid) == since.as_ref() {
+                continue;
+            }
+
+            let day = self.day(now);
+            let mut store = self.store.borrow_mut();
+            if let Some(start) = occupied.remove(&change.uuid) {
+                let counts = store.day_mut(day.clone()).machines.entry(change.uuid).or_default();
+                counts.occupied_secs += now.saturating_sub(start);
+            }
+            if let Some(since) = since {
+                occupied.insert(change.uuid, since);
+                let counts = store.day_mut(day);
+                if let Some(ref user) = change.occupant {
+                    counts.users.insert(user.clone());
+                }
+                counts.machines.entry(change.uuid).or_default().sessions += 1;
+            }
+        }
+    }
+
+    async fn write_out(&self, log: Logger, interval: Duration) {
+        loop {
+            task::sleep(interval).await;
+            if let Err(e) = self.flush() {
+                error!(log, "Failed to write statistics: {}", e);
+            }
+        }
+    }
+}
+
+/// Parse a day as `stats` and `getStatistics` take it, empty meaning no bound
+pub fn parse_day(s: &str) -> std::result::Result<Option<NaiveDate>, String> {
+    if s.is_empty() {
+        return Ok(None);
+    }
+    NaiveDate::parse_from_str(s, DAY_FORMAT).map(Some)
+        .map_err(|e| format!("invalid day {}, expected YYYY-MM-DD: {}", s, e))
+}
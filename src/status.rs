@@ -0,0 +1,193 @@
+//! Server status as reported to monitoring
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// How many status queries are answered per `QUERY_WINDOW`, shared by all clients
+const QUERY_LIMIT: u32 = 10;
+const QUERY_WINDOW: Duration = Duration::from_secs(1);
+
+/// Whether we're connected to the MQTT broker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bridge {
+    /// No broker configured
+    Disabled,
+    Down,
+    Up,
+}
+
+/// Health information subsystems keep up to date as they go
+pub struct Status {
+    started: Instant,
+    machines: AtomicUsize,
+    connections: AtomicUsize,
+    last_save_ok: AtomicBool,
+    /// A `Bridge` as number
+    mqtt: AtomicU8,
+    dry_run: AtomicBool,
+    /// Milliseconds spent not accepting connections since we were at the limit
+    saturated: AtomicU64,
+    /// Whether permission checks are given up on, see `access::breaker`
+    authz_breaker: AtomicBool,
+    /// Seconds clients turned away for overload are told to wait at least, 0 if we aren't
+    retry_after: AtomicU64,
+    /// Saves of the machine database, failed ones included
+    writes: AtomicU64,
+    /// Changes to the machine database that waited for a later save instead of saving on their own
+    coalesced: AtomicU64,
+    /// Whether changes are refused, see `MachinesProvider::set_read_only`
+    read_only: AtomicBool,
+    /// Entries in each registry as of the last look, see `gc`
+    registries: Mutex<BTreeMap<&'static str, usize>>,
+
+    /// Start of the current rate limiting window and the queries answered in it
+    queries: Mutex<(Instant, u32)>,
+}
+
+impl Status {
+    pub fn new() -> Arc<Self> {
+        let now = Instant::now();
+        Arc::new(Self {
+            started: now,
+            machines: AtomicUsize::new(0),
+            connections: AtomicUsize::new(0),
+            last_save_ok: AtomicBool::new(true),
+            mqtt: AtomicU8::new(Bridge::Disabled as u8),
+            dry_run: AtomicBool::new(false),
+            saturated: AtomicU64::new(0),
+            authz_breaker: AtomicBool::new(false),
+            retry_after: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+            read_only: AtomicBool::new(false),
+            registries: Mutex::new(BTreeMap::new()),
+            queries: Mutex::new((now, 0)),
+        })
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn machines(&self) -> usize {
+        self.machines.load(Ordering::Relaxed)
+    }
+
+    pub fn set_machines(&self, n: usize) {
+        self.machines.store(n, Ordering::Relaxed)
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn set_connections(&self, n: usize) {
+        self.connections.store(n, Ordering::Relaxed)
+    }
+
+    /// Whether the most recent attempt to save the machine database worked
+    pub fn last_save_ok(&self) -> bool {
+        self.last_save_ok.load(Ordering::Relaxed)
+    }
+
+    pub fn saved(&self, ok: bool) {
+        self.last_save_ok.store(ok, Ordering::Relaxed);
+        self.writes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn writes(&self) -> u64 {
+        self.writes.load(Ordering::Relaxed)
+    }
+
+    pub fn coalesced(&self) -> u64 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+
+    pub fn add_coalesced(&self) {
+        self.coalesced.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    pub fn set_read_only(&self, on: bool) {
+        self.read_only.store(on, Ordering::Relaxed)
+    }
+
+    /// Entries in each registry, by name
+    pub fn registries(&self) -> Vec<(&'static str, usize)> {
+        self.registries.lock().unwrap().iter().map(|(name, n)| (*name, *n)).collect()
+    }
+
+    pub fn set_registry(&self, name: &'static str, n: usize) {
+        self.registries.lock().unwrap().insert(name, n);
+    }
+
+    pub fn mqtt(&self) -> Bridge {
+        match self.mqtt.load(Ordering::Relaxed) {
+            x if x == Bridge::Up as u8 => Bridge::Up,
+            x if x == Bridge::Down as u8 => Bridge::Down,
+            _ => Bridge::Disabled,
+        }
+    }
+
+    pub fn set_mqtt(&self, state: Bridge) {
+        self.mqtt.store(state as u8, Ordering::Relaxed)
+    }
+
+    /// Whether this is a dry run, where nothing is switched or saved
+    pub fn dry_run(&self) -> bool {
+        self.dry_run.load(Ordering::Relaxed)
+    }
+
+    pub fn set_dry_run(&self, dry_run: bool) {
+        self.dry_run.store(dry_run, Ordering::Relaxed)
+    }
+
+    /// How long we didn't accept connections because we were at the limit, in total
+    pub fn saturated(&self) -> Duration {
+        Duration::from_millis(self.saturated.load(Ordering::Relaxed))
+    }
+
+    pub fn add_saturated(&self, d: Duration) {
+        self.saturated.fetch_add(d.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn authz_breaker(&self) -> bool {
+        self.authz_breaker.load(Ordering::Relaxed)
+    }
+
+    pub fn set_authz_breaker(&self, open: bool) {
+        self.authz_breaker.store(open, Ordering::Relaxed)
+    }
+
+    pub fn retry_after(&self) -> u64 {
+        self.retry_after.load(Ordering::Relaxed)
+    }
+
+    pub fn set_retry_after(&self, secs: u64) {
+        self.retry_after.store(secs, Ordering::Relaxed)
+    }
+
+    /// Account for a status query, returning false if too many were made recently
+    ///
+    /// Status queries don't need authentication so this keeps them from being a cheap way to
+    /// keep the server busy.
+    pub fn allow_query(&self) -> bool {
+        let mut queries = self.queries.lock().unwrap();
+        if queries.0.elapsed() >= QUERY_WINDOW {
+            *queries = (Instant::now(), 0);
+        }
+
+        if queries.1 >= QUERY_LIMIT {
+            false
+        } else {
+            queries.1 += 1;
+            true
+        }
+    }
+}
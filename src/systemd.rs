@@ -0,0 +1,100 @@
+//! Integration with systemd: socket activation and readiness notification
+//!
+//! Only compiled with the `systemd` feature.
+
+use std::env;
+use std::io;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::rc::Rc;
+use std::time::Duration;
+
+use nix::sys::socket::{self, AddressFamily, MsgFlags, SockAddr, SockFlag, SockType, UnixAddr};
+use nix::unistd::close;
+
+use crate::listen::{Listener, UnixSocket};
+use crate::listen::filter::PeerFilter;
+
+/// The first file descriptor passed by systemd, see sd_listen_fds(3)
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Take over the listening sockets systemd passed to us, if any
+///
+/// The environment variables are unset afterwards so child processes don't inherit them.
+pub fn listeners() -> io::Result<Vec<Listener>> {
+    let pid = env::var("LISTEN_PID").ok().and_then(|p| p.parse::<u32>().ok());
+    let fds = env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<RawFd>().ok());
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    // The variables are meant for a specific process; if that's not us they aren't ours to use.
+    let n = match (pid, fds) {
+        (Some(pid), Some(n)) if pid == std::process::id() => n,
+        _ => return Ok(Vec::new()),
+    };
+
+    (SD_LISTEN_FDS_START .. SD_LISTEN_FDS_START + n).map(adopt).collect()
+}
+
+/// Turn a passed file descriptor into a listener depending on its address family
+fn adopt(fd: RawFd) -> io::Result<Listener> {
+    let addr = socket::getsockname(fd).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    // Safe because systemd passed ownership of these descriptors to us and we take each one exactly
+    // once.
+    match addr {
+        SockAddr::Inet(_) => {
+            let l = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            l.set_nonblocking(true)?;
+            // There's no `[[listen]]` entry to take `allow` and `deny` from, the service manager
+            // has its own settings for that
+            Ok(Listener::Tcp(l.into(), Rc::new(PeerFilter::default())))
+        },
+        SockAddr::Unix(_) => {
+            let l = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            l.set_nonblocking(true)?;
+            Ok(Listener::Unix(UnixSocket::adopted(l.into())))
+        },
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput,
+            format!("Passed file descriptor {} is not a TCP or Unix socket", fd))),
+    }
+}
+
+/// Send a state update to the service manager, see sd_notify(3)
+///
+/// Does nothing if we're not running under a service manager that wants notifications.
+pub fn notify(state: &str) -> io::Result<()> {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(p) => p,
+        Err(_) => return Ok(()),
+    };
+
+    let to_io = |e: nix::Error| io::Error::new(io::ErrorKind::Other, e);
+
+    // A leading '@' denotes a socket in the abstract namespace
+    let addr = if path.starts_with('@') {
+        UnixAddr::new_abstract(path[1..].as_bytes())
+    } else {
+        UnixAddr::new(path.as_str())
+    }.map_err(to_io)?;
+
+    let fd = socket::socket(AddressFamily::Unix, SockType::Datagram, SockFlag::SOCK_CLOEXEC, None)
+        .map_err(to_io)?;
+    let r = socket::sendto(fd, state.as_bytes(), &SockAddr::Unix(addr), MsgFlags::empty());
+    let _ = close(fd);
+    r.map(|_| ()).map_err(to_io)
+}
+
+/// Interval in which the service manager expects watchdog pings, if it wants them at all
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = env::var("WATCHDOG_PID").ok().and_then(|p| p.parse::<u32>().ok()) {
+        if pid != std::process::id() {
+            return None;
+        }
+    }
+
+    env::var("WATCHDOG_USEC").ok()
+        .and_then(|u| u.parse::<u64>().ok())
+        .filter(|&u| u > 0)
+        .map(Duration::from_micros)
+}
@@ -0,0 +1,103 @@
+//! Helpers shared by the tests
+//!
+//! Tests that need files get a directory of their own with `TempDir`. Those that need the whole
+//! server get an in-process one to connect to, see `rpc`. Tests of the machine state get away with
+//! `machines` and a `TestClock`, which only moves when they tell it to.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_std::sync::Arc;
+
+use chrono_tz::Tz;
+
+use slog::{Discard, Logger};
+
+use uuid::Uuid;
+
+use crate::machine::{self, store, Clock, Machine, MachineDB, MachinesProvider};
+use crate::machine::location::Locations;
+use crate::status::Status;
+
+pub mod rpc;
+
+/// Directories made by this process so far, so parallel tests never share one
+static DIRS: AtomicUsize = AtomicUsize::new(0);
+
+/// A fresh directory that is removed again with everything in it when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let n = DIRS.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir()
+            .join(format!("diflouroborane-test-{}-{}", std::process::id(), n));
+        // Left over from a process that had our pid before and didn't get to clean up
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A logger throwing everything away
+pub fn logger() -> Logger {
+    Logger::root(Discard, o!())
+}
+
+/// The only machine `machines` knows about
+pub const LASER: Uuid = Uuid::from_u128(0x1a5e_0001);
+
+/// A clock that only moves when told to
+#[derive(Clone)]
+pub struct TestClock(Arc<AtomicU64>);
+
+impl TestClock {
+    /// A clock standing at `secs` seconds since the UNIX epoch
+    pub fn at(secs: u64) -> Self {
+        TestClock(Arc::new(AtomicU64::new(secs)))
+    }
+
+    pub fn advance(&self, secs: u64) {
+        self.0.fetch_add(secs, Ordering::SeqCst);
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.0.load(Ordering::SeqCst))
+    }
+}
+
+/// Machines with only the laser cutter, keeping their database in `dir`
+///
+/// The head of a queue gets the machine held for five minutes.
+pub fn machines(dir: &TempDir, clock: &TestClock) -> MachinesProvider {
+    let mut mdb = MachineDB::new();
+    mdb.insert(LASER, Machine::new("Laser".to_string(), String::new(), "lab.laser".to_string()));
+    machine::save(&dir.join("machines.toml"), &mdb, &Locations::new()).unwrap();
+    reopen(dir, clock)
+}
+
+/// The machines `machines` made in `dir` as they were saved, like after a restart
+pub fn reopen(dir: &TempDir, clock: &TestClock) -> MachinesProvider {
+    let store = store::FileStore::open(&dir.join("machines.toml")).unwrap();
+    MachinesProvider::with_clock(logger(), Box::new(store), 300, None, Tz::UTC, Status::new(),
+        Box::new(clock.clone()))
+}
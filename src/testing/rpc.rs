@@ -0,0 +1,302 @@
+//! An in-process server to test the API against
+//!
+//! `Server::start` sets up everything `main` does with files in a temporary directory: the users
+//! in `USERS`, the machines `PRINTER` and `SAW` and a policy letting members use the printer but
+//! only look at the saw, and alice act as bob. The API is served on a loopback port and clients
+//! connect to it with `Client::connect`. Both sides run on the same `LocalPool`, so a test drives
+//! everything by running the pool until its checks are done. Connections are accepted and counted
+//! against `api.max_connections` like `main` does it.
+
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::FutureExt;
+use futures::future::{self, AbortHandle};
+use futures::executor::{LocalSpawner, ThreadPool};
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::stream::StreamExt;
+use futures::task::LocalSpawnExt;
+
+use async_std::net::{TcpListener, TcpStream};
+use async_std::os::unix::net::UnixStream;
+
+use capnp::Error;
+use capnp_rpc::RpcSystem;
+use capnp_rpc::twoparty::VatNetwork;
+use capnp_rpc::rpc_twoparty_capnp::Side;
+
+use uuid::Uuid;
+
+use crate::access;
+use crate::api::{self, API};
+use crate::api::api::{admin, authentication, diflouroborane, machines};
+use crate::audit::Audit;
+use crate::auth::{self, PassDB};
+use crate::auth::setup::Setup;
+use crate::config::Config;
+use crate::connection::{Connections, Refused};
+use crate::machine::{self, api_from_uuid, uuid_from_api, Machine, MachineDB};
+use crate::machine::location::Locations;
+use crate::machine::watch::Channels;
+use crate::status::Status;
+
+use super::{logger, TempDir};
+
+/// Users and their passwords
+pub const USERS: &[(&str, &str)] = &[
+    ("admin", "correct horse battery staple"),
+    ("alice", "alice's password"),
+    ("bob", "bob's password"),
+];
+
+pub const PRINTER: Uuid = Uuid::from_u128(0x5c1d_0001);
+pub const SAW: Uuid = Uuid::from_u128(0x5c1d_0002);
+
+const MODEL: &str = "\
+[request_definition]
+r = sub, obj, act
+
+[policy_definition]
+p = sub, obj, act
+
+[role_definition]
+g = _, _
+
+[policy_effect]
+e = some(where (p.eft == allow))
+
+[matchers]
+m = g(r.sub, p.sub) && r.obj == p.obj && r.act == p.act
+";
+
+const POLICY: &str = "\
+p, role:admin, server, admin
+p, role:admin, machines, admin
+p, role:admin, lab.printer, read
+p, role:admin, lab.printer, write
+p, role:admin, lab.printer, manage
+p, role:admin, lab.saw, read
+p, role:admin, lab.saw, write
+p, role:admin, lab.saw, manage
+p, role:member, lab.printer, read
+p, role:member, lab.printer, write
+p, role:member, lab.saw, read
+p, alice, bob, su
+g, admin, role:admin
+g, alice, role:member
+g, bob, role:member
+";
+
+/// A server serving the API on a loopback port
+pub struct Server {
+    pub config: Config,
+    pub api: API<ThreadPool>,
+    pub addr: SocketAddr,
+    pub connections: Arc<Connections>,
+    pub audit: Audit,
+    /// Offers the server to be claimed, which it doesn't on its own
+    pub setup: Setup,
+    /// Where all the files are, removed with the server
+    pub dir: TempDir,
+}
+
+impl Server {
+    pub async fn start(spawner: &LocalSpawner) -> Self {
+        Self::with_config(spawner, |_| {}).await
+    }
+
+    /// Start a server with the config changed by `configure` first
+    ///
+    /// The paths in it point into the server's directory already. Changes are saved right away and
+    /// idle connections are never closed unless `configure` says otherwise.
+    pub async fn with_config(spawner: &LocalSpawner, configure: impl FnOnce(&mut Config))
+        -> Self
+    {
+        let dir = TempDir::new();
+        let mut config = Config::default();
+        config.machinedb = dir.join("machines.toml");
+        config.passdb = dir.join("passwd.toml");
+        config.access.model = dir.join("model.conf");
+        config.access.policy = dir.join("policy.csv");
+        config.machines.write_interval = 0;
+        config.api.idle_timeout = 0;
+        configure(&mut config);
+
+        fs::write(&config.access.model, MODEL).unwrap();
+        fs::write(&config.access.policy, POLICY).unwrap();
+        let passdb: PassDB = USERS.iter()
+            .map(|(user, password)| (user.to_string(), password.to_string()))
+            .collect();
+        auth::save_passdb(&config.passdb, &passdb).unwrap();
+        let mut mdb = MachineDB::new();
+        mdb.insert(PRINTER, Machine::new("Printer".to_string(), String::new(),
+            "lab.printer".to_string()));
+        mdb.insert(SAW, Machine::new("Saw".to_string(), String::new(), "lab.saw".to_string()));
+        machine::save(&config.machinedb, &mdb, &Locations::new()).unwrap();
+
+        let log = logger();
+        let status = Status::new();
+        let mach = machine::init(log.clone(), &config, status.clone()).await.unwrap();
+        let perm = access::init(log.clone(), &config, status.clone()).await.unwrap();
+        let auth = auth::init(log.clone(), config.clone()).await.unwrap();
+        let audit = Audit::open(&config).unwrap();
+        let setup = Setup::new(&config);
+        // Enough threads for a check stuck in the enforcer not to hold up the others, even on a
+        // single core
+        let pool = ThreadPool::builder().pool_size(4).create().unwrap();
+        let api = API::new(auth, perm, mach, config.api.clone(), status.clone(), audit.clone(),
+            None, Channels::new(None), None, setup.clone(), pool);
+        let connections = Connections::new(logger(), config.api.max_connections,
+            config.api.max_connections_per_peer, status);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = api.clone();
+        let counter = connections.clone();
+        let options = api::reader_options(&config.api);
+        let conns = spawner.clone();
+        spawner.spawn_local(async move {
+            let mut id = 0;
+            let mut incoming = listener.incoming();
+            while let Some(Ok(stream)) = incoming.next().await {
+                id += 1;
+                let peer = stream.peer_addr().ok();
+                let served = match counter.try_acquire(peer.map(|a| a.ip())) {
+                    Ok(guard) => api::handle_connection(serving.clone(), logger(), stream,
+                        peer.map(|a| a.to_string()).unwrap_or_default(), false, id)
+                        .map(move |_| drop(guard))
+                        .boxed_local(),
+                    Err(Refused::Overloaded) => match counter.try_turn_away() {
+                        Some(guard) => {
+                            let retry = guard.retry_after();
+                            api::handle_overloaded(logger(), stream, options, retry)
+                                .map(move |_| drop(guard))
+                                .boxed_local()
+                        },
+                        None => continue,
+                    },
+                    Err(Refused::PeerLimit) => continue,
+                };
+                conns.spawn_local(served).unwrap();
+                counter.capacity().await;
+            }
+        }).unwrap();
+
+        Server { config, api, addr, connections, audit, setup, dir }
+    }
+
+    /// A new connection to the server
+    pub async fn connect(&self, spawner: &LocalSpawner) -> Client {
+        Client::connect(self.addr, spawner).await
+    }
+
+    /// A new connection over a socket pair instead of the loopback port
+    ///
+    /// It's served as a local connection, like one over a Unix socket.
+    pub fn connect_pair(&self, spawner: &LocalSpawner) -> Client {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let served = api::handle_connection(self.api.clone(), logger(), theirs,
+            "socket pair".to_string(), true, 0);
+        spawner.spawn_local(served.map(|_| ())).unwrap();
+        Client::over(ours, spawner)
+    }
+}
+
+/// One connection to a `Server`, with shortcuts for the calls tests make all the time
+pub struct Client {
+    pub bootstrap: diflouroborane::Client,
+    rpc: AbortHandle,
+}
+
+impl Client {
+    pub async fn connect(addr: SocketAddr, spawner: &LocalSpawner) -> Self {
+        Self::over(TcpStream::connect(addr).await.unwrap(), spawner)
+    }
+
+    /// Talk to the server on the other end of `stream`
+    pub fn over<T>(stream: T, spawner: &LocalSpawner) -> Self
+        where T: AsyncRead + AsyncWrite + Clone + Unpin + 'static
+    {
+        let netw = VatNetwork::new(stream.clone(), stream, Side::Client, Default::default());
+        let mut rpc = RpcSystem::new(Box::new(netw), None);
+        let bootstrap: diflouroborane::Client = rpc.bootstrap(Side::Server);
+        let (rpc, abort) = future::abortable(rpc);
+        spawner.spawn_local(rpc.map(|_| ())).unwrap();
+        Client { bootstrap, rpc: abort }
+    }
+
+    /// Hang up. Just dropping the client leaves the connection open until the test is over.
+    pub fn close(self) {
+        self.rpc.abort();
+    }
+
+    /// Authenticate with PLAIN, returning whether it was granted
+    pub async fn login(&self, user: &str, password: &str) -> Result<bool, Error> {
+        let auth = self.bootstrap.authentication_request().send().pipeline.get_auth();
+        let mut req = auth.initialize_authentication_request();
+        req.get().set_mechanism("PLAIN");
+        let data = format!("\0{}\0{}", user, password);
+        req.get().init_initial_data().set_some(data.as_bytes());
+        let reply = req.send().promise.await?;
+
+        let outcome = match reply.get()?.get_response()?.which()? {
+            authentication::step_result::Outcome(o) => o?,
+            authentication::step_result::Challenge(_) =>
+                return Err(Error::failed("PLAIN wants more than one step".to_string())),
+        };
+        Ok(outcome.value_request().send().promise.await?.get()?.get_granted())
+    }
+
+    /// Authenticate as one of `USERS`, failing the test if that doesn't work
+    pub async fn login_as(&self, user: &str) {
+        let password = USERS.iter().find(|(u, _)| *u == user).unwrap().1;
+        assert!(self.login(user, password).await.unwrap(), "logging in as {} failed", user);
+    }
+
+    /// Administration of the server, calls on it fail unless logged in as `admin`
+    pub fn admin(&self) -> admin::Client {
+        self.bootstrap.admin_request().send().pipeline.get_admin()
+    }
+
+    pub fn machines(&self) -> machines::Client {
+        self.bootstrap.machines_request().send().pipeline.get_mach()
+    }
+
+    /// The machines listed to the connection and their status
+    pub async fn list(&self) -> Result<Vec<(Uuid, machines::Status)>, Error> {
+        let reply = self.machines().list_request().send().promise.await?;
+        let mut listed = Vec::new();
+        for m in reply.get()?.get_machines()?.iter() {
+            listed.push((uuid_from_api(m.get_uuid()?), m.get_status()?));
+        }
+        listed.sort_by_key(|(uuid, _)| *uuid);
+        Ok(listed)
+    }
+
+    pub async fn status(&self, uuid: Uuid) -> Result<machines::Status, Error> {
+        let mut req = self.machines().get_info_request();
+        api_from_uuid(uuid, req.get().init_uuid());
+        let reply = req.send().promise.await?;
+        Ok(reply.get()?.get_info()?.get_status()?)
+    }
+
+    pub async fn use_machine(&self, uuid: Uuid) -> Result<machines::give_back::Client, Error> {
+        let mut req = self.machines().use_request();
+        api_from_uuid(uuid, req.get().init_uuid());
+        let reply = req.send().promise.await?;
+        reply.get()?.get_giveback()
+    }
+}
+
+pub async fn give_back(giveback: &machines::give_back::Client) -> Result<(), Error> {
+    giveback.giveback_request().send().promise.await?;
+    Ok(())
+}
+
+/// The code of an error a call failed with, e.g. `unauthorized`, see `api::codes`
+pub fn code(e: &Error) -> &str {
+    // Errors from the server come with a prefix saying so
+    let description = e.description.trim_start_matches("remote exception: ");
+    description.split(':').next().unwrap_or("")
+}
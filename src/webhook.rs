@@ -0,0 +1,272 @@
+//! HTTP callbacks about machines being used and given back, e.g. for a booking system
+//!
+//! Unlike notifiers these must not miss anything. Events are taken from the audit trail as they're
+//! recorded and put into an outbox on disk, so whatever changed a machine never waits for a hook.
+//! A delivery leaves the outbox once its hook answered with a 2xx status. Until then it's retried
+//! with growing delays, also after a restart. Every request is signed so the receiving end can
+//! tell it's from us.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use futures::channel::mpsc;
+use futures::future;
+use futures::stream::StreamExt;
+
+use async_std::future::timeout;
+use async_std::task;
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+use slog::Logger;
+
+use uuid::Uuid;
+
+use crate::audit::{Audit, Event};
+use crate::config::Config;
+use crate::error::{Error, Result, WithPath};
+use crate::machine::unix_secs;
+use crate::modules::http::Endpoint;
+
+pub mod outbox;
+use outbox::{Delivery, Outbox};
+
+/// Delay before the first retry of a failed delivery, doubling after every further failure
+const MIN_RETRY_DELAY: u64 = 5;
+/// Longest delay between two attempts of the same delivery
+const MAX_RETRY_DELAY: u64 = 3600;
+/// How long to wait for new events if nothing is pending, in seconds
+const IDLE_WAIT: u64 = 3600;
+
+/// Header carrying `sha256=` followed by the HMAC-SHA256 of the body in hex
+const SIGNATURE_HEADER: &str = "X-Signature-256";
+/// Header carrying the id of the delivery, which stays the same across retries
+const ID_HEADER: &str = "X-Delivery-Id";
+
+struct Hook {
+    url: String,
+    endpoint: Endpoint,
+    secret: String,
+    /// Machines the hook wants to hear about, all of them if empty
+    machines: Vec<Uuid>,
+}
+
+/// Handle to the outbox, for looking at deliveries that didn't go through
+#[derive(Clone)]
+pub struct Deliveries(Arc<Mutex<Outbox>>);
+
+impl Deliveries {
+    /// All deliveries that weren't made yet or were given up on, oldest first
+    pub fn list(&self) -> Vec<Delivery> {
+        self.0.lock().unwrap().list().to_vec()
+    }
+}
+
+pub struct Webhooks {
+    log: Logger,
+    hooks: Vec<Hook>,
+    outbox: Deliveries,
+    max_attempts: u32,
+}
+
+/// Set up the hooks in `[webhooks]`, if there are any
+///
+/// Nothing is sent during dry runs, nobody may notice them.
+pub fn open(log: Logger, config: &Config) -> Result<Option<Webhooks>> {
+    let wh = &config.webhooks;
+    if wh.hooks.is_empty() || config.dry_run.is_some() {
+        return Ok(None);
+    }
+    let path = wh.outbox.as_ref()
+        .ok_or_else(|| Error::Config("webhooks.outbox must be set".to_string()))?;
+
+    let mut hooks = Vec::with_capacity(wh.hooks.len());
+    for hook in wh.hooks.iter() {
+        let secret = hook.secret.as_ref().or(wh.secret.as_ref())
+            .ok_or_else(|| Error::Config(format!("webhook {} has no secret", hook.url)))?;
+        hooks.push(Hook {
+            url: hook.url.clone(),
+            endpoint: Endpoint::new(&hook.url, "POST", Vec::new(), hook.verify_tls,
+                Duration::from_secs(hook.timeout))?,
+            secret: secret.expose().to_string(),
+            machines: hook.machines.clone(),
+        });
+    }
+
+    let mut outbox = Outbox::open(path).with_path(path)?;
+    let urls: Vec<&str> = hooks.iter().map(|h| h.url.as_str()).collect();
+    let dropped = outbox.retain_urls(&urls).with_path(path)?;
+    if dropped > 0 {
+        warn!(log, "Dropped {} deliveries to webhooks that aren't configured anymore", dropped);
+    }
+
+    Ok(Some(Webhooks {
+        log,
+        hooks,
+        outbox: Deliveries(Arc::new(Mutex::new(outbox))),
+        max_attempts: wh.max_attempts,
+    }))
+}
+
+impl Webhooks {
+    pub fn deliveries(&self) -> Deliveries {
+        self.outbox.clone()
+    }
+
+    /// Put deliveries for `event` into the outbox, returning whether there were any
+    fn enqueue(&self, event: &Event) -> bool {
+        let (name, machine, user) = match *event {
+            // Notes stay out of webhooks, they're for those managing the machine only
+            Event::MachineUse { ref authzid, machine, .. } => ("use", machine, authzid),
+            Event::MachineGiveBack { ref authzid, machine } => ("giveback", machine, authzid),
+            Event::MachineReleased { ref authzid, machine } => ("release", machine, authzid),
+            _ => return false,
+        };
+
+        let at = now();
+        let mut outbox = self.outbox.0.lock().unwrap();
+        let mut any = false;
+        for hook in self.hooks.iter() {
+            if !hook.machines.is_empty() && !hook.machines.contains(&machine) {
+                continue;
+            }
+            let delivery = Delivery {
+                id: 0,
+                url: hook.url.clone(),
+                event: name.to_string(),
+                machine,
+                user: user.clone(),
+                at,
+                attempts: 0,
+                next_attempt: at,
+                last_error: None,
+                failed: false,
+            };
+            // It's still in memory and delivered if we keep running
+            if let Err(e) = outbox.push(delivery) {
+                error!(self.log, "Failed to save webhook outbox: {}", e);
+            }
+            any = true;
+        }
+        any
+    }
+
+    /// Try every delivery that's due
+    ///
+    /// After a delivery failed the later ones to the same hook wait for its retry, so a hook gets
+    /// to hear about a machine in the order things happened.
+    async fn deliver_due(&self) {
+        let due = self.outbox.0.lock().unwrap().due(now());
+        for d in due {
+            // A failure earlier in this round may have postponed it
+            if !self.outbox.0.lock().unwrap().is_due(d.id, now()) {
+                continue;
+            }
+            let hook = match self.hooks.iter().find(|h| h.url == d.url) {
+                Some(h) => h,
+                None => continue,
+            };
+
+            let body = body(&d);
+            let headers = vec![
+                (SIGNATURE_HEADER.to_string(), sign(&hook.secret, body.as_bytes())),
+                (ID_HEADER.to_string(), d.id.to_string()),
+            ];
+            let r = match hook.endpoint.send_with(body.as_bytes(), &headers).await {
+                Ok(200..=299) => Ok(()),
+                Ok(status) => Err(format!("answered with status {}", status)),
+                Err(e) => Err(e.to_string()),
+            };
+
+            let mut outbox = self.outbox.0.lock().unwrap();
+            let saved = match r {
+                Ok(()) => {
+                    debug!(self.log, "Delivered {} of machine {} to {}", d.event, d.machine,
+                        d.url; "id" => d.id);
+                    outbox.delivered(d.id)
+                },
+                Err(why) if d.attempts + 1 >= self.max_attempts => {
+                    error!(self.log, "Giving up on telling {} about {} of machine {}: {}", d.url,
+                        d.event, d.machine, why; "id" => d.id, "attempts" => d.attempts + 1);
+                    outbox.attempt_failed(d.id, why, None)
+                },
+                Err(why) => {
+                    let delay = retry_delay(d.attempts + 1);
+                    warn!(self.log, "Delivery to {} failed, retrying in {}s: {}", d.url, delay,
+                        why; "id" => d.id);
+                    outbox.attempt_failed(d.id, why, Some(now() + delay))
+                },
+            };
+            if let Err(e) = saved {
+                error!(self.log, "Failed to save webhook outbox: {}", e);
+            }
+        }
+    }
+
+    /// Deliver events recorded in `audit` for as long as we run
+    pub async fn run(self, audit: Audit) {
+        let pending = self.outbox.0.lock().unwrap().list().iter().filter(|d| !d.failed).count();
+        if pending > 0 {
+            info!(self.log, "{} webhook deliveries pending from before", pending);
+        }
+
+        let mut events = audit.subscribe();
+        let (wake, mut woken) = mpsc::unbounded();
+
+        let collect = async {
+            while let Some(recorded) = events.next().await {
+                if self.enqueue(&recorded.event) {
+                    let _ = wake.unbounded_send(());
+                }
+            }
+        };
+
+        let deliver = async {
+            loop {
+                self.deliver_due().await;
+                let next = self.outbox.0.lock().unwrap().next_due();
+                let wait = next.map(|t| t.saturating_sub(now())).unwrap_or(IDLE_WAIT);
+                if wait == 0 {
+                    continue;
+                }
+                let wait = Duration::from_secs(wait);
+                // Nothing is recorded anymore once the collector is gone, but retries are still due
+                if let Ok(None) = timeout(wait, woken.next()).await {
+                    task::sleep(wait).await;
+                }
+            }
+        };
+
+        future::join(collect, deliver).await;
+    }
+}
+
+fn now() -> u64 {
+    unix_secs(SystemTime::now())
+}
+
+/// Seconds to wait before attempt number `attempts + 1`
+fn retry_delay(attempts: u32) -> u64 {
+    let factor = 1u64 << attempts.saturating_sub(1).min(32);
+    MIN_RETRY_DELAY.saturating_mul(factor).min(MAX_RETRY_DELAY)
+}
+
+fn body(d: &Delivery) -> String {
+    serde_json::json!({
+        "id": d.id,
+        "event": d.event,
+        "machine": d.machine.to_string(),
+        "user": d.user,
+        "at": d.at,
+    }).to_string()
+}
+
+/// Value of the signature header for `body`
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes())
+        .expect("HMAC takes keys of any length");
+    mac.update(body);
+    let hex: String = mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256={}", hex)
+}
@@ -0,0 +1,157 @@
+//! Deliveries of webhooks that weren't made yet, kept on disk so they survive restarts
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+use uuid::Uuid;
+
+use crate::error::{Result, WithPath};
+
+/// How many deliveries that were given up on are kept around to be looked at
+const MAX_FAILED: usize = 100;
+
+/// A single event to tell a single hook about
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delivery {
+    pub id: u64,
+    /// URL of the hook, which is how hooks are told apart across restarts
+    pub url: String,
+    /// `use`, `giveback` or `release`
+    pub event: String,
+    pub machine: Uuid,
+    pub user: String,
+    /// When it happened, in UNIX seconds
+    pub at: u64,
+    pub attempts: u32,
+    /// When to try again, in UNIX seconds
+    pub next_attempt: u64,
+    /// Why the last attempt failed
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// Given up on after too many attempts, only kept to be looked at
+    #[serde(default)]
+    pub failed: bool,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Saved {
+    next_id: u64,
+    deliveries: Vec<Delivery>,
+}
+
+/// Every change is written out right away. Failing to do so is returned but leaves the outbox in
+/// memory intact, the next successful write contains the change.
+pub struct Outbox {
+    path: PathBuf,
+    next_id: u64,
+    /// Oldest first
+    deliveries: Vec<Delivery>,
+}
+
+impl Outbox {
+    /// Read the outbox at `path`, which is created once there is something to save
+    pub fn open(path: &Path) -> Result<Self> {
+        let saved = if path.is_file() {
+            serde_json::from_str(&fs::read_to_string(path)?)?
+        } else {
+            Saved::default()
+        };
+        Ok(Self { path: path.to_path_buf(), next_id: saved.next_id, deliveries: saved.deliveries })
+    }
+
+    fn save(&self) -> Result<()> {
+        let saved = Saved { next_id: self.next_id, deliveries: self.deliveries.clone() };
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut fp = File::create(&tmp).with_path(&tmp)?;
+            fp.write_all(&serde_json::to_vec(&saved)?).with_path(&tmp)?;
+            fp.sync_all().with_path(&tmp)?;
+        }
+        fs::rename(&tmp, &self.path).with_path(&self.path)?;
+        Ok(())
+    }
+
+    /// All deliveries, pending and failed, oldest first
+    pub fn list(&self) -> &[Delivery] {
+        &self.deliveries
+    }
+
+    /// Add a delivery that's due right away, with its id filled in
+    pub fn push(&mut self, mut delivery: Delivery) -> Result<()> {
+        delivery.id = self.next_id;
+        self.next_id += 1;
+        self.deliveries.push(delivery);
+        self.save()
+    }
+
+    /// Deliveries to try now, oldest first
+    pub fn due(&self, now: u64) -> Vec<Delivery> {
+        self.deliveries.iter().filter(|d| !d.failed && d.next_attempt <= now).cloned().collect()
+    }
+
+    pub fn is_due(&self, id: u64, now: u64) -> bool {
+        self.deliveries.iter().any(|d| d.id == id && !d.failed && d.next_attempt <= now)
+    }
+
+    /// When the next delivery is due, in UNIX seconds
+    pub fn next_due(&self) -> Option<u64> {
+        self.deliveries.iter().filter(|d| !d.failed).map(|d| d.next_attempt).min()
+    }
+
+    pub fn delivered(&mut self, id: u64) -> Result<()> {
+        self.deliveries.retain(|d| d.id != id);
+        self.save()
+    }
+
+    /// Record a failed attempt, to be tried again at `retry_at` or given up on if `None`
+    ///
+    /// Later deliveries to the same hook aren't tried before the retry, so they don't overtake it.
+    pub fn attempt_failed(&mut self, id: u64, error: String, retry_at: Option<u64>) -> Result<()> {
+        let url = match self.deliveries.iter_mut().find(|d| d.id == id) {
+            Some(d) => {
+                d.attempts += 1;
+                d.last_error = Some(error);
+                match retry_at {
+                    Some(t) => d.next_attempt = t,
+                    None => d.failed = true,
+                }
+                d.url.clone()
+            },
+            None => return Ok(()),
+        };
+        if let Some(t) = retry_at {
+            for d in self.deliveries.iter_mut() {
+                if d.url == url && d.id > id && !d.failed && d.next_attempt < t {
+                    d.next_attempt = t;
+                }
+            }
+        }
+
+        let failed = self.deliveries.iter().filter(|d| d.failed).count();
+        if failed > MAX_FAILED {
+            let mut excess = failed - MAX_FAILED;
+            self.deliveries.retain(|d| {
+                let drop = d.failed && excess > 0;
+                if drop {
+                    excess -= 1;
+                }
+                !drop
+            });
+        }
+        self.save()
+    }
+
+    /// Drop deliveries for hooks that aren't configured anymore, returning how many
+    pub fn retain_urls(&mut self, urls: &[&str]) -> Result<usize> {
+        let before = self.deliveries.len();
+        self.deliveries.retain(|d| urls.contains(&d.url.as_str()));
+        let dropped = before - self.deliveries.len();
+        if dropped > 0 {
+            self.save()?;
+        }
+        Ok(dropped)
+    }
+}
@@ -0,0 +1,224 @@
+//! The API over WebSocket, for clients running in a browser
+//!
+//! capnp messages are sent as binary WebSocket messages. Message boundaries carry no meaning, a
+//! capnp message may be split over several WebSocket messages and the other way around.
+
+use std::cell::RefCell;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::sink::Sink;
+use futures::stream::Stream;
+
+use async_std::net::TcpStream;
+
+use async_tungstenite::WebSocketStream;
+use async_tungstenite::tungstenite::{self, Message};
+use async_tungstenite::tungstenite::handshake::server::{Request, Response, ErrorResponse};
+use async_tungstenite::tungstenite::http::StatusCode;
+
+/// Perform the WebSocket handshake on a freshly accepted connection
+///
+/// Upgrade requests from a browser must come from one of `origins`, otherwise any web page could
+/// talk to the API in the name of whoever visits it. Requests without an `Origin` header don't
+/// come from a browser and are always accepted.
+pub async fn accept(stream: TcpStream, origins: &[String]) -> io::Result<WsStream> {
+    let check_origin = |req: &Request, resp: Response| -> Result<Response, ErrorResponse> {
+        let origin = match req.headers().get("origin") {
+            Some(o) => o.to_str().unwrap_or(""),
+            None => return Ok(resp),
+        };
+        if origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)) {
+            Ok(resp)
+        } else {
+            let mut refusal = ErrorResponse::new(Some(format!("Origin {} not allowed", origin)));
+            *refusal.status_mut() = StatusCode::FORBIDDEN;
+            Err(refusal)
+        }
+    };
+
+    let ws = async_tungstenite::accept_hdr_async(stream, check_origin).await
+        .map_err(into_io)?;
+    Ok(WsStream::new(ws))
+}
+
+/// Byte stream over a WebSocket connection
+///
+/// Cloning gives another handle to the same connection, so it can be split into a reading and a
+/// writing half like a `TcpStream`.
+#[derive(Clone)]
+pub struct WsStream {
+    inner: Rc<RefCell<Inner>>,
+}
+
+struct Inner {
+    ws: WebSocketStream<TcpStream>,
+    /// The message we're currently reading from and how far we've got
+    pending: Vec<u8>,
+    pos: usize,
+}
+
+impl WsStream {
+    fn new(ws: WebSocketStream<TcpStream>) -> Self {
+        let inner = Inner { ws, pending: Vec::new(), pos: 0 };
+        Self { inner: Rc::new(RefCell::new(inner)) }
+    }
+}
+
+impl AsyncRead for WsStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+        -> Poll<io::Result<usize>>
+    {
+        let mut inner = self.inner.borrow_mut();
+        let inner = &mut *inner;
+
+        loop {
+            if inner.pos < inner.pending.len() {
+                let n = std::cmp::min(buf.len(), inner.pending.len() - inner.pos);
+                buf[..n].copy_from_slice(&inner.pending[inner.pos..inner.pos + n]);
+                inner.pos += n;
+                return Poll::Ready(Ok(n));
+            }
+
+            match Pin::new(&mut inner.ws).poll_next(cx) {
+                Poll::Ready(Some(Ok(Message::Binary(data)))) => {
+                    inner.pending = data;
+                    inner.pos = 0;
+                },
+                // Either way the other side is done, which to a reader looks like EOF
+                Poll::Ready(Some(Ok(Message::Close(_)))) | Poll::Ready(None) =>
+                    return Poll::Ready(Ok(0)),
+                Poll::Ready(Some(Ok(Message::Text(_)))) =>
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData,
+                        "capnp messages must be sent as binary WebSocket messages"))),
+                // Pings are answered by tungstenite itself
+                Poll::Ready(Some(Ok(_))) => {},
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(into_io(e))),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for WsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        let mut inner = self.inner.borrow_mut();
+        let mut ws = Pin::new(&mut inner.ws);
+
+        match ws.as_mut().poll_ready(cx) {
+            Poll::Ready(Ok(())) => {},
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(into_io(e))),
+            Poll::Pending => return Poll::Pending,
+        }
+        ws.start_send(Message::Binary(buf.to_vec())).map_err(into_io)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let mut inner = self.inner.borrow_mut();
+        Pin::new(&mut inner.ws).poll_flush(cx).map_err(into_io)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let mut inner = self.inner.borrow_mut();
+        Pin::new(&mut inner.ws).poll_close(cx).map_err(into_io)
+    }
+}
+
+fn into_io(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed =>
+            io::Error::new(io::ErrorKind::BrokenPipe, e),
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+
+    use futures::FutureExt;
+    use futures::executor::{LocalPool, LocalSpawner};
+    use futures::task::LocalSpawnExt;
+
+    use async_std::net::TcpListener;
+
+    use crate::api;
+    use crate::testing::logger;
+    use crate::testing::rpc::{self, Client, Server, PRINTER};
+
+    const ORIGIN: &str = "https://fabaccess.example.org";
+
+    /// Serve the API of `server` over WebSocket on a port of its own
+    async fn listen(server: &Server, spawner: &LocalSpawner) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let api = server.api.clone();
+        let conns = spawner.clone();
+        spawner.spawn_local(async move {
+            let origins = vec![ORIGIN.to_string()];
+            while let Ok((stream, _)) = listener.accept().await {
+                // Refused handshakes are what the tests look at from the client's side
+                if let Ok(ws) = accept(stream, &origins).await {
+                    let served = api::handle_connection(api.clone(), logger(), ws,
+                        "websocket".to_string(), false, 0);
+                    conns.spawn_local(served.map(|_| ())).unwrap();
+                }
+            }
+        }).unwrap();
+        addr
+    }
+
+    async fn connect(addr: SocketAddr, origin: Option<&str>)
+        -> Result<WsStream, tungstenite::Error>
+    {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut req = tungstenite::http::Request::builder().uri(format!("ws://{}/", addr));
+        if let Some(origin) = origin {
+            req = req.header("Origin", origin);
+        }
+        let (ws, _) = async_tungstenite::client_async(req.body(()).unwrap(), stream).await?;
+        Ok(WsStream::new(ws))
+    }
+
+    #[test]
+    fn api_over_websocket() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let addr = listen(&server, &spawner).await;
+
+            // Both from a browser on an allowed page and from something that isn't a browser
+            for origin in &[Some(ORIGIN), None] {
+                let client = Client::over(connect(addr, *origin).await.unwrap(), &spawner);
+                client.login_as("alice").await;
+                let giveback = client.use_machine(PRINTER).await.unwrap();
+                rpc::give_back(&giveback).await.unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn other_origins_refused() {
+        let mut exec = LocalPool::new();
+        let spawner = exec.spawner();
+        exec.run_until(async {
+            let server = Server::start(&spawner).await;
+            let addr = listen(&server, &spawner).await;
+            match connect(addr, Some("https://evil.example.com")).await {
+                Err(tungstenite::Error::Http(status)) => assert_eq!(status, StatusCode::FORBIDDEN),
+                Err(e) => panic!("expected the upgrade to be forbidden, got {}", e),
+                Ok(_) => panic!("upgrade from a foreign origin was accepted"),
+            }
+        });
+    }
+}